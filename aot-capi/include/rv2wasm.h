@@ -0,0 +1,60 @@
+/* rv2wasm.h - C API for the rv2wasm RISC-V to WebAssembly compiler
+ *
+ * Link against librv2wasm_capi (cdylib or staticlib, built from aot-capi).
+ * This header is stable: entries are only ever added, and existing
+ * signatures, constants and flag bits keep their meaning.
+ *
+ * Every function returning int32_t returns a status code. Modules are
+ * returned in buffers owned by the library; release them with
+ * rv2wasm_free(). Errors are described by rv2wasm_last_error(), per thread.
+ */
+
+#ifndef RV2WASM_H
+#define RV2WASM_H
+
+#include <stddef.h>
+#include <stdint.h>
+
+#ifdef __cplusplus
+extern "C" {
+#endif
+
+/* Status codes */
+#define RV2WASM_OK                0
+#define RV2WASM_ERROR            -1 /* compilation failed */
+#define RV2WASM_INVALID_ARGUMENT -2 /* null pointer, unknown flag, bad option */
+#define RV2WASM_PANIC            -3 /* internal compiler bug */
+
+/* rv2wasm_compile flags */
+#define RV2WASM_DEBUG   (1u << 0) /* emit debug info */
+#define RV2WASM_THREADS (1u << 1) /* shared memory and atomics for threaded guests */
+#define RV2WASM_SIZE    (1u << 2) /* optimize for size (-Os) */
+
+/* ABI version of the emitted modules: machine-state layout, return
+ * protocol and exports, as described by rv2wasm's <output>.abi.json. */
+uint32_t rv2wasm_abi_version(void);
+
+/* Compile a RISC-V ELF image to a Wasm module.
+ * opt_level is 0-3; flags is a mask of RV2WASM_* flags above. On success
+ * *out and *out_len hold the module; on failure *out is NULL. */
+int32_t rv2wasm_compile(const uint8_t *elf, size_t elf_len, uint32_t opt_level, uint32_t flags,
+                        uint8_t **out, size_t *out_len);
+
+/* Compile raw RISC-V machine code loaded at base_addr to a JIT module
+ * entered at its first instruction. The module imports env.memory (shared)
+ * and exports one function per block. */
+int32_t rv2wasm_compile_region(const uint8_t *code, size_t code_len, uint64_t base_addr,
+                               uint8_t **out, size_t *out_len);
+
+/* Release a buffer returned by rv2wasm_compile*. NULL is ignored. */
+void rv2wasm_free(uint8_t *buf, size_t len);
+
+/* Message for the last failed call on this thread, or NULL after a
+ * success. Valid until the next rv2wasm call on this thread. */
+const char *rv2wasm_last_error(void);
+
+#ifdef __cplusplus
+}
+#endif
+
+#endif /* RV2WASM_H */
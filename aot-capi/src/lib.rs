@@ -0,0 +1,193 @@
+// rv2wasm-capi: C API for embedding the compiler
+//
+// Exposes rv2wasm to non-Rust hosts (a C++ engine running RISC-V mods, say)
+// as a cdylib/staticlib with the stable header in `include/rv2wasm.h`.
+// Keep the two in sync: the header is the contract, and entries are only
+// ever added to it.
+//
+// Every call returns a status code. Output buffers are allocated here and
+// must be released with `rv2wasm_free`; on failure the message is kept per
+// thread for `rv2wasm_last_error`. Panics are caught at the boundary and
+// reported as `RV2WASM_PANIC` instead of unwinding into C.
+
+use rv2wasm::CompileOptions;
+use std::cell::RefCell;
+use std::ffi::{c_char, CString};
+use std::panic::{catch_unwind, AssertUnwindSafe};
+
+/// Success
+pub const RV2WASM_OK: i32 = 0;
+/// Compilation failed; see `rv2wasm_last_error`
+pub const RV2WASM_ERROR: i32 = -1;
+/// A null pointer, unknown flag or out-of-range option
+pub const RV2WASM_INVALID_ARGUMENT: i32 = -2;
+/// The compiler panicked (a bug); see `rv2wasm_last_error`
+pub const RV2WASM_PANIC: i32 = -3;
+
+/// Flag: emit debug info
+pub const RV2WASM_DEBUG: u32 = 1 << 0;
+/// Flag: shared memory and atomics for multi-threaded guests
+pub const RV2WASM_THREADS: u32 = 1 << 1;
+/// Flag: optimize for size (-Os)
+pub const RV2WASM_SIZE: u32 = 1 << 2;
+
+const KNOWN_FLAGS: u32 = RV2WASM_DEBUG | RV2WASM_THREADS | RV2WASM_SIZE;
+
+thread_local! {
+    static LAST_ERROR: RefCell<Option<CString>> = const { RefCell::new(None) };
+}
+
+/// ABI version of the modules this library emits (see rv2wasm's abi.rs)
+#[no_mangle]
+pub extern "C" fn rv2wasm_abi_version() -> u32 {
+    rv2wasm::abi::ABI_VERSION
+}
+
+/// Compile the ELF image `elf[..elf_len]` to a Wasm module in `*out`/`*out_len`.
+///
+/// # Safety
+///
+/// `elf` must point to `elf_len` readable bytes (or be null with `elf_len`
+/// 0), and `out`/`out_len` must be valid for writes.
+#[no_mangle]
+pub unsafe extern "C" fn rv2wasm_compile(
+    elf: *const u8,
+    elf_len: usize,
+    opt_level: u32,
+    flags: u32,
+    out: *mut *mut u8,
+    out_len: *mut usize,
+) -> i32 {
+    let Some(elf) = input(elf, elf_len) else {
+        return invalid("elf is null");
+    };
+    if opt_level > 3 {
+        return invalid("opt_level must be 0-3");
+    }
+    if flags & !KNOWN_FLAGS != 0 {
+        return invalid("unknown flags");
+    }
+    let options = CompileOptions {
+        opt_level: opt_level as u8,
+        debug: flags & RV2WASM_DEBUG != 0,
+        threads: flags & RV2WASM_THREADS != 0,
+        size: flags & RV2WASM_SIZE != 0,
+        ..Default::default()
+    };
+    output(|| rv2wasm::compile_with_options(elf, &options), out, out_len)
+}
+
+/// Compile raw machine code `code[..code_len]` loaded at `base_addr` to a
+/// JIT module (entered at its first instruction) in `*out`/`*out_len`.
+///
+/// # Safety
+///
+/// As for `rv2wasm_compile`.
+#[no_mangle]
+pub unsafe extern "C" fn rv2wasm_compile_region(
+    code: *const u8,
+    code_len: usize,
+    base_addr: u64,
+    out: *mut *mut u8,
+    out_len: *mut usize,
+) -> i32 {
+    let Some(code) = input(code, code_len) else {
+        return invalid("code is null");
+    };
+    output(|| rv2wasm::compile_region(code, base_addr), out, out_len)
+}
+
+/// Release a buffer returned by `rv2wasm_compile*`. Null is ignored.
+///
+/// # Safety
+///
+/// `buf`/`len` must be exactly as returned, and freed only once.
+#[no_mangle]
+pub unsafe extern "C" fn rv2wasm_free(buf: *mut u8, len: usize) {
+    if !buf.is_null() {
+        drop(Box::from_raw(std::ptr::slice_from_raw_parts_mut(buf, len)));
+    }
+}
+
+/// The message of the last failed call on this thread, or null. Valid
+/// until the next call on this thread.
+#[no_mangle]
+pub extern "C" fn rv2wasm_last_error() -> *const c_char {
+    LAST_ERROR.with(|e| e.borrow().as_ref().map_or(std::ptr::null(), |s| s.as_ptr()))
+}
+
+unsafe fn input<'a>(ptr: *const u8, len: usize) -> Option<&'a [u8]> {
+    match (ptr.is_null(), len) {
+        (true, 0) => Some(&[]),
+        (true, _) => None,
+        (false, _) => Some(std::slice::from_raw_parts(ptr, len)),
+    }
+}
+
+/// Run `compile` and hand its module to the caller
+unsafe fn output(
+    compile: impl FnOnce() -> anyhow::Result<Vec<u8>>,
+    out: *mut *mut u8,
+    out_len: *mut usize,
+) -> i32 {
+    if out.is_null() || out_len.is_null() {
+        return invalid("out is null");
+    }
+    *out = std::ptr::null_mut();
+    *out_len = 0;
+    let (status, message) = match catch_unwind(AssertUnwindSafe(compile)) {
+        Ok(Ok(wasm)) => {
+            *out_len = wasm.len();
+            *out = Box::into_raw(wasm.into_boxed_slice()) as *mut u8;
+            (RV2WASM_OK, None)
+        }
+        Ok(Err(e)) => (RV2WASM_ERROR, Some(format!("{:#}", e))),
+        Err(panic) => {
+            let what = panic
+                .downcast_ref::<&str>()
+                .map(|s| s.to_string())
+                .or_else(|| panic.downcast_ref::<String>().cloned())
+                .unwrap_or_default();
+            (RV2WASM_PANIC, Some(format!("compiler panicked: {}", what)))
+        }
+    };
+    set_error(message);
+    status
+}
+
+fn invalid(message: &str) -> i32 {
+    set_error(Some(message.to_string()));
+    RV2WASM_INVALID_ARGUMENT
+}
+
+fn set_error(message: Option<String>) {
+    let message = message.map(|m| CString::new(m.replace('\0', " ")).unwrap_or_default());
+    LAST_ERROR.with(|e| *e.borrow_mut() = message);
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::ffi::CStr;
+
+    #[test]
+    fn test_compile_region_and_errors() {
+        // addi a0, zero, 0 ; ecall
+        let code = [0x13, 0x05, 0x00, 0x00, 0x73, 0x00, 0x00, 0x00];
+        let (mut out, mut len) = (std::ptr::null_mut(), 0);
+        unsafe {
+            assert_eq!(rv2wasm_compile_region(code.as_ptr(), code.len(), 0x1000, &mut out, &mut len), RV2WASM_OK);
+            assert_eq!(std::slice::from_raw_parts(out, 4), b"\0asm");
+            assert!(rv2wasm_last_error().is_null());
+            rv2wasm_free(out, len);
+
+            let junk = [0u8; 16];
+            assert_eq!(rv2wasm_compile(junk.as_ptr(), junk.len(), 2, 0, &mut out, &mut len), RV2WASM_ERROR);
+            assert!(out.is_null());
+            assert!(!CStr::from_ptr(rv2wasm_last_error()).to_bytes().is_empty());
+            let status = rv2wasm_compile(junk.as_ptr(), junk.len(), 2, 1 << 31, &mut out, &mut len);
+            assert_eq!(status, RV2WASM_INVALID_ARGUMENT);
+            assert_eq!(rv2wasm_compile(std::ptr::null(), 1, 2, 0, &mut out, &mut len), RV2WASM_INVALID_ARGUMENT);
+        }
+    }
+}
@@ -0,0 +1,19 @@
+// diff_block - Fuzz the translator against the reference interpreter
+//
+//   cd aot-isa-tests && cargo fuzz run diff_block
+
+#![no_main]
+
+use libfuzzer_sys::fuzz_target;
+use rv2wasm_isa_tests::diff::{Case, Differ};
+use std::sync::OnceLock;
+
+static DIFFER: OnceLock<Differ> = OnceLock::new();
+
+fuzz_target!(|data: &[u8]| {
+    let differ = DIFFER.get_or_init(|| Differ::new().expect("Failed to create engine"));
+    let case = Case::from_bytes(data);
+    if let Err(e) = differ.check(&case) {
+        panic!("{:#}\n{:?}", e, case);
+    }
+});
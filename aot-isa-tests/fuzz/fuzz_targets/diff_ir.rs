@@ -0,0 +1,16 @@
+// diff_ir - Fuzz the translated IR (run by rv2wasm::eval) against the
+// reference interpreter; no Wasm engine, so many more cases per second
+//
+//   cd aot-isa-tests && cargo fuzz run diff_ir
+
+#![no_main]
+
+use libfuzzer_sys::fuzz_target;
+use rv2wasm_isa_tests::diff::{check_ir, Case};
+
+fuzz_target!(|data: &[u8]| {
+    let case = Case::from_bytes(data);
+    if let Err(e) = check_ir(&case) {
+        panic!("{:#}\n{:?}", e, case);
+    }
+});
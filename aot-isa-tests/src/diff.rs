@@ -0,0 +1,336 @@
+// diff.rs - Differential testing of the translator against the interpreter
+//
+// Builds blocks of random RV64IM instructions (ALU, shifts, multiply/divide,
+// loads, stores and byte/halfword AMOs on a scratch buffer), some ending in a conditional
+// branch past the block, then runs each block twice: through translate_jit → build_jit under wasmtime, and
+// through rv2wasm's reference interpreter. Registers, scratch memory and
+// the returned PC must match. `check_ir` compares the interpreter with the
+// translated IR run by `rv2wasm::eval` instead, which needs no Wasm engine
+// and is far cheaper per case.
+//
+// Cases are derived from a byte string, so the same generator drives the
+// seeded cargo test and the cargo-fuzz target (fuzz/fuzz_targets/diff_block.rs).
+
+use anyhow::{bail, Context, Result};
+use rv2wasm::interp::{self, Hart};
+use rv2wasm::{cfg, disasm, encode, eval, translate, wasm_builder, CodeSection, Opcode};
+use wasmtime::{Config, Engine, Func, Instance, MemoryType, Module, SharedMemory, Store};
+
+/// Guest address of the generated code
+const CODE: u64 = 0x10000;
+/// Machine state block for the Wasm side
+const STATE_ADDR: u64 = 0x1000;
+/// Scratch buffer for loads and stores, addressed through x5
+const SCRATCH: u64 = 0x8000;
+const SCRATCH_LEN: usize = 512;
+/// Base register for memory operations; never written by generated code
+const BASE_REG: u8 = 5;
+/// Longest generated block
+pub const MAX_INSTRUCTIONS: usize = 64;
+
+/// Values that tend to expose overflow and sign-extension bugs
+const SPECIAL: [u64; 8] = [
+    0,
+    1,
+    u64::MAX,
+    i64::MIN as u64,
+    i64::MAX as u64,
+    0x8000_0000,
+    0xffff_ffff,
+    0xffff_ffff_8000_0000,
+];
+
+/// One differential test input
+#[derive(Debug, Clone)]
+pub struct Case {
+    pub code: Vec<u32>,
+    pub regs: [u64; 32],
+    pub scratch: Vec<u8>,
+}
+
+/// Deterministic generator state (xorshift64*)
+struct Rng(u64);
+
+impl Rng {
+    fn next(&mut self) -> u64 {
+        self.0 ^= self.0 >> 12;
+        self.0 ^= self.0 << 25;
+        self.0 ^= self.0 >> 27;
+        self.0.wrapping_mul(0x2545_f491_4f6c_dd1d)
+    }
+}
+
+impl Case {
+    /// Derive a case from arbitrary bytes: the first 8 seed register and
+    /// scratch contents, every following 4 bytes select one instruction.
+    pub fn from_bytes(data: &[u8]) -> Case {
+        let (seed, body) = data.split_at(data.len().min(8));
+        let mut seed_bytes = [0u8; 8];
+        seed_bytes[..seed.len()].copy_from_slice(seed);
+        let mut rng = Rng(u64::from_le_bytes(seed_bytes) | 1);
+
+        let mut regs = [0u64; 32];
+        for r in regs.iter_mut().skip(1) {
+            let v = rng.next();
+            *r = if v & 3 == 0 { SPECIAL[(v >> 2) as usize % SPECIAL.len()] } else { rng.next() };
+        }
+        regs[BASE_REG as usize] = SCRATCH;
+        let scratch = (0..SCRATCH_LEN).map(|_| rng.next() as u8).collect();
+
+        let mut code: Vec<u32> = body
+            .chunks_exact(4)
+            .take(MAX_INSTRUCTIONS)
+            .map(|c| instruction(u32::from_le_bytes(c.try_into().unwrap())))
+            .collect();
+        if rng.next().is_multiple_of(4) {
+            code.extend(branch(rng.next()));
+        }
+        Case { code, regs, scratch }
+    }
+
+    /// A pseudo-random case of `len` instructions
+    pub fn random(seed: u64, len: usize) -> Case {
+        let mut rng = Rng(seed | 1);
+        let data: Vec<u8> = (0..8 + len * 4).map(|_| rng.next() as u8).collect();
+        Case::from_bytes(&data)
+    }
+}
+
+/// Map 32 random bits onto a supported, straight-line instruction
+fn instruction(bits: u32) -> u32 {
+    use Opcode::*;
+    const OP: [Opcode; 28] = [
+        ADD, SUB, SLL, SLT, SLTU, XOR, SRL, SRA, OR, AND, MUL, MULH, MULHSU, MULHU, DIV, DIVU, REM, REMU,
+        ADDW, SUBW, SLLW, SRLW, SRAW, MULW, DIVW, DIVUW, REMW, REMUW,
+    ];
+    const OP_IMM: [Opcode; 10] = [ADDI, SLTI, SLTIU, XORI, ORI, ANDI, ADDIW, LUI, AUIPC, ADDI];
+    const SHIFT: [(Opcode, i64); 6] = [(SLLI, 64), (SRLI, 64), (SRAI, 64), (SLLIW, 32), (SRLIW, 32), (SRAIW, 32)];
+    const LOAD: [Opcode; 7] = [LB, LH, LW, LD, LBU, LHU, LWU];
+    // cbo.zero ignores the offset and clears the first block of scratch
+    const STORE: [Opcode; 5] = [SB, SH, SW, SD, CBO_ZERO];
+    // Zabha AMOs on the (aligned) start of scratch
+    const AMO: [Opcode; 10] =
+        [AMOSWAP_B, AMOADD_H, AMOXOR_B, AMOAND_H, AMOOR_B, AMOMIN_H, AMOMAX_B, AMOMINU_H, AMOMAXU_B, AMOCAS_H];
+
+    let mut rd = ((bits >> 8) & 0x1f) as u8;
+    if rd == BASE_REG {
+        rd = 6;
+    }
+    let rs1 = ((bits >> 13) & 0x1f) as u8;
+    let rs2 = ((bits >> 18) & 0x1f) as u8;
+    let pick = (bits >> 23) as usize;
+    let imm = (bits >> 20) as i64 - 2048;
+    let offset = (bits >> 20) as i64 % (SCRATCH_LEN as i64 - 8);
+    let (opcode, rs1, imm) = match bits & 0xff {
+        0..=119 => (OP[pick % OP.len()], rs1, 0),
+        120..=169 => match OP_IMM[pick % OP_IMM.len()] {
+            op @ (LUI | AUIPC) => (op, rs1, (bits & 0xffff_f000) as i32 as i64),
+            op => (op, rs1, imm),
+        },
+        170..=199 => {
+            let (op, width) = SHIFT[pick % SHIFT.len()];
+            (op, rs1, imm.rem_euclid(width))
+        }
+        200..=229 => (LOAD[pick % LOAD.len()], BASE_REG, offset),
+        230..=247 => (STORE[pick % STORE.len()], BASE_REG, offset),
+        _ => (AMO[pick % AMO.len()], BASE_REG, 0),
+    };
+    encode::assemble(opcode, rd, rs1, rs2, imm).expect("generator produced an unencodable instruction")
+}
+
+/// A conditional branch past the end of the block, usually testing the
+/// result of a set-less-than just before it (the translator fuses those)
+fn branch(bits: u64) -> Vec<u32> {
+    use Opcode::*;
+    const SET: [Opcode; 4] = [SLT, SLTU, SLTI, SLTIU];
+    const BRANCH: [Opcode; 6] = [BEQ, BNE, BLT, BGE, BLTU, BGEU];
+
+    let mut rd = ((bits >> 8) & 0x1f) as u8;
+    if rd == BASE_REG || rd == 0 {
+        rd = 6;
+    }
+    let rs1 = ((bits >> 13) & 0x1f) as u8;
+    let rs2 = ((bits >> 18) & 0x1f) as u8;
+    let imm = ((bits >> 23) & 0xfff) as i64 - 2048;
+    let offset = 4 * (1 + ((bits >> 35) & 7) as i64);
+    let assemble = |op, rd, rs1, rs2, imm| encode::assemble(op, rd, rs1, rs2, imm).expect("unencodable branch");
+    if bits & 3 == 0 {
+        let op = BRANCH[(bits >> 40) as usize % BRANCH.len()];
+        return vec![assemble(op, 0, rs1, rs2, offset)];
+    }
+    let set = assemble(SET[(bits >> 2) as usize % SET.len()], rd, rs1, rs2, imm);
+    let (a, b) = if bits & 4 == 0 { (rd, 0) } else { (0, rd) };
+    let op = if bits & 8 == 0 { BEQ } else { BNE };
+    vec![set, assemble(op, 0, a, b, offset + 4)]
+}
+
+/// Runs cases through both engines
+pub struct Differ {
+    engine: Engine,
+    memory: SharedMemory,
+}
+
+impl Differ {
+    pub fn new() -> Result<Self> {
+        let mut config = Config::new();
+        config.wasm_threads(true);
+        let engine = Engine::new(&config)?;
+        // build_jit modules import a shared memory (see rv2wasm::link)
+        let memory = SharedMemory::new(&engine, MemoryType::shared(256, 65536))?;
+        Ok(Self { engine, memory })
+    }
+
+    /// Execute `case` both ways; an error describes the first difference
+    pub fn check(&self, case: &Case) -> Result<()> {
+        if case.code.is_empty() {
+            return Ok(());
+        }
+        let (instructions, hart, mem) = reference(case)?;
+
+        // Translated
+        let graph = cfg::build(&instructions, CODE)?;
+        let wasm = wasm_builder::build_jit(&translate::translate_jit(&graph, CODE)?)?;
+        let module = Module::new(&self.engine, &wasm).context("Generated module failed to compile")?;
+        let mut store = Store::new(&self.engine, ());
+        let syscall = Func::wrap(&mut store, |_: i32, _: i32| -> i32 { -1 });
+        let yield_hint = Func::wrap(&mut store, || {});
+        let crypto = Func::wrap(&mut store, |op: i32, rs1: i64, rs2: i64| {
+            rv2wasm::crypto::crypto(op, rs1 as u64, rs2 as u64) as i64
+        });
+        let imports = [self.memory.clone().into(), syscall.into(), yield_hint.into(), crypto.into()];
+        let instance = Instance::new(&mut store, &module, &imports)?;
+        let regs: Vec<u8> = case.regs.iter().flat_map(|r| r.to_le_bytes()).collect();
+        self.write(STATE_ADDR, &regs);
+        self.write(SCRATCH, &case.scratch);
+        let block = instance.get_typed_func::<i32, i32>(&mut store, &format!("block_{:x}", CODE))?;
+        let next_pc = block.call(&mut store, STATE_ADDR as i32)? as u32 as u64;
+
+        let state = Outcome { regs: self.read(STATE_ADDR, 256), scratch: self.read(SCRATCH, SCRATCH_LEN), next_pc };
+        state.compare("wasm", &hart, &mem, &instructions)
+    }
+
+    fn write(&self, addr: u64, data: &[u8]) {
+        let cells = &self.memory.data()[addr as usize..addr as usize + data.len()];
+        for (cell, &b) in cells.iter().zip(data) {
+            // Safety: no guest code runs while the host touches memory
+            unsafe { *cell.get() = b };
+        }
+    }
+
+    fn read(&self, addr: u64, len: usize) -> Vec<u8> {
+        self.memory.data()[addr as usize..addr as usize + len]
+            .iter()
+            .map(|cell| unsafe { *cell.get() })
+            .collect()
+    }
+}
+
+/// Execute `case` with the translated IR under `rv2wasm::eval` and with
+/// the interpreter; an error describes the first difference
+pub fn check_ir(case: &Case) -> Result<()> {
+    if case.code.is_empty() {
+        return Ok(());
+    }
+    let (instructions, hart, mem) = reference(case)?;
+
+    let graph = cfg::build(&instructions, CODE)?;
+    let module = translate::translate_jit(&graph, CODE)?;
+    let func = module.functions.iter().find(|f| f.block_addr == CODE).context("No function for the block")?;
+    let mut memory = vec![0u8; SCRATCH as usize + SCRATCH_LEN];
+    for (r, value) in case.regs.iter().enumerate() {
+        memory[STATE_ADDR as usize + r * 8..][..8].copy_from_slice(&value.to_le_bytes());
+    }
+    memory[SCRATCH as usize..].copy_from_slice(&case.scratch);
+    let next_pc = eval::eval(func, &mut memory, STATE_ADDR as u32)? as u32 as u64;
+
+    let state = Outcome {
+        regs: memory[STATE_ADDR as usize..][..256].to_vec(),
+        scratch: memory[SCRATCH as usize..].to_vec(),
+        next_pc,
+    };
+    state.compare("IR", &hart, &mem, &instructions)
+}
+
+/// Run `case` through the reference interpreter: the decoded block, the
+/// final hart and memory
+fn reference(case: &Case) -> Result<(Vec<disasm::Instruction>, Hart, Vec<u8>)> {
+    let section = CodeSection {
+        vaddr: CODE,
+        data: case.code.iter().flat_map(|w| w.to_le_bytes()).collect(),
+        name: "diff".into(),
+    };
+    let instructions = disasm::disassemble(&section)?;
+    let mut hart = Hart::new(CODE);
+    hart.x = case.regs;
+    let mut mem = vec![0u8; SCRATCH as usize + SCRATCH_LEN];
+    mem[SCRATCH as usize..].copy_from_slice(&case.scratch);
+    interp::run_block(&mut hart, &mut mem, &instructions)?;
+    Ok((instructions, hart, mem))
+}
+
+/// Machine state after running a translated block
+struct Outcome {
+    regs: Vec<u8>,
+    scratch: Vec<u8>,
+    next_pc: u64,
+}
+
+impl Outcome {
+    /// Compare with the interpreter's result; `what` names the translated side
+    fn compare(&self, what: &str, hart: &Hart, mem: &[u8], instructions: &[disasm::Instruction]) -> Result<()> {
+        for r in 1..32 {
+            let got = u64::from_le_bytes(self.regs[r * 8..r * 8 + 8].try_into().unwrap());
+            if got != hart.x[r] {
+                bail!(
+                    "x{} differs: {} 0x{:x}, interpreter 0x{:x}\n{}",
+                    r,
+                    what,
+                    got,
+                    hart.x[r],
+                    listing(instructions)
+                );
+            }
+        }
+        if self.scratch != mem[SCRATCH as usize..] {
+            bail!("Scratch memory differs\n{}", listing(instructions));
+        }
+        if self.next_pc != hart.pc {
+            bail!("Next PC differs: {} 0x{:x}, interpreter 0x{:x}", what, self.next_pc, hart.pc);
+        }
+        Ok(())
+    }
+}
+
+fn listing(instructions: &[disasm::Instruction]) -> String {
+    instructions
+        .iter()
+        .map(|i| format!("  {:x}: {:08x} {:?}", i.addr, i.bytes, i.opcode))
+        .collect::<Vec<_>>()
+        .join("\n")
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_random_blocks_match_interpreter() {
+        let differ = Differ::new().unwrap();
+        for seed in 0..300 {
+            let case = Case::random(seed, 1 + seed as usize % MAX_INSTRUCTIONS);
+            if let Err(e) = differ.check(&case) {
+                panic!("seed {}: {:#}", seed, e);
+            }
+        }
+    }
+
+    #[test]
+    fn test_random_blocks_ir_match_interpreter() {
+        for seed in 0..5000 {
+            let case = Case::random(seed, 1 + seed as usize % MAX_INSTRUCTIONS);
+            if let Err(e) = check_ir(&case) {
+                panic!("seed {}: {:#}", seed, e);
+            }
+        }
+    }
+}
@@ -0,0 +1,352 @@
+// rv2wasm-isa-tests: riscv-tests conformance harness
+//
+// Runs the official riscv-tests ISA binaries (rv64ui/um/ua/uf/ud, "p"
+// environment) through the AOT path: each ELF is compiled with rv2wasm and
+// executed under wasmtime, and the result is read back through the
+// tohost convention (1 = pass, (n << 1) | 1 = test case n failed).
+//
+// The p environment boots in machine mode, which rv2wasm does not model.
+// The harness therefore starts each test right after the `mret` that ends
+// the boot code in RVTEST_CODE_BEGIN, and treats the ECALL in RVTEST_PASS /
+// RVTEST_FAIL (a7 = 93, a0 = result) as the trap handler's tohost write.
+// Tests that store to `tohost` directly are picked up from memory.
+//
+// riscv-tests link at 0x80000000, above the PCs the block return encoding
+// can carry, so images are rebased into low memory first. The tests only
+// use PC-relative addressing (`la`), so moving the whole image is safe.
+//
+//   scripts/fetch-riscv-tests.sh
+//   cargo run -p rv2wasm-isa-tests -- /path/to/riscv-tests/isa
+//
+// `diff` cross-checks random blocks against rv2wasm's reference
+// interpreter (see also fuzz/).
+
+pub mod diff;
+
+use anyhow::{bail, Context, Result};
+use goblin::elf::{program_header, section_header, Elf};
+use std::collections::HashSet;
+use std::fmt;
+use std::path::{Path, PathBuf};
+use wasmtime::{Caller, Config, Engine, Func, Instance, Memory, Module, Store};
+
+/// Test binary name prefixes making up the gate
+pub const SUITES: &[&str] = &["rv64ui-p-", "rv64um-p-", "rv64ua-p-", "rv64uf-p-", "rv64ud-p-"];
+
+/// Fuel (roughly Wasm instructions) before a test is declared hung
+pub const DEFAULT_FUEL: u64 = 100_000_000;
+
+/// Where rebased images are placed
+const LOAD_BASE: u64 = 0x10000;
+
+/// Machine state block (`$m`), below the image
+const STATE_ADDR: u64 = 0x1000;
+
+/// First PC the block return encoding cannot represent
+const PC_LIMIT: u64 = 0x4000_0000;
+
+const MRET: u32 = 0x3020_0073;
+const SYS_EXIT: u64 = 93;
+
+/// Tests known to fail, one name per line (`#` comments)
+const XFAIL: &str = include_str!("../xfail.txt");
+
+/// Result of one test binary
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Outcome {
+    Pass,
+    /// The numbered test case failed
+    Fail(u64),
+    /// Execution ended without reporting through tohost
+    NoResult(String),
+    /// Ran out of fuel (infinite loop or very slow code)
+    Timeout,
+    /// rv2wasm or wasmtime rejected the binary
+    Error(String),
+}
+
+impl Outcome {
+    pub fn passed(&self) -> bool {
+        *self == Outcome::Pass
+    }
+}
+
+impl fmt::Display for Outcome {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Outcome::Pass => write!(f, "PASS"),
+            Outcome::Fail(n) => write!(f, "FAIL (test case {})", n),
+            Outcome::NoResult(why) => write!(f, "FAIL (no result: {})", why),
+            Outcome::Timeout => write!(f, "FAIL (timeout)"),
+            Outcome::Error(e) => write!(f, "ERROR ({})", e),
+        }
+    }
+}
+
+/// Tests listed in xfail.txt
+pub fn expected_failures() -> HashSet<&'static str> {
+    XFAIL
+        .lines()
+        .map(str::trim)
+        .filter(|l| !l.is_empty() && !l.starts_with('#'))
+        .collect()
+}
+
+/// Test binaries in `dir` belonging to SUITES, sorted by name
+pub fn discover(dir: &Path) -> Result<Vec<PathBuf>> {
+    let mut tests = Vec::new();
+    for entry in std::fs::read_dir(dir).with_context(|| format!("Cannot read {}", dir.display()))? {
+        let path = entry?.path();
+        let Some(name) = path.file_name().and_then(|n| n.to_str()) else { continue };
+        // Skip the .dump listings built next to each binary
+        if path.extension().is_none() && SUITES.iter().any(|s| name.starts_with(s)) {
+            tests.push(path);
+        }
+    }
+    tests.sort();
+    Ok(tests)
+}
+
+/// Compile and run one riscv-tests ELF
+pub fn run_elf(elf: &[u8], fuel: u64) -> Outcome {
+    match try_run(elf, fuel) {
+        Ok(outcome) => outcome,
+        Err(e) => Outcome::Error(format!("{:#}", e)),
+    }
+}
+
+/// Host state seen by the syscall import
+struct Host {
+    memory: Option<Memory>,
+    tohost: Option<u64>,
+    unexpected: Option<String>,
+}
+
+fn try_run(elf: &[u8], fuel: u64) -> Result<Outcome> {
+    let image = prepare(elf)?;
+    let wasm = rv2wasm::compile(&image.elf, 2, false).context("rv2wasm failed")?;
+
+    let mut config = Config::new();
+    config.consume_fuel(true);
+    let engine = Engine::new(&config)?;
+    let module = Module::new(&engine, &wasm).context("Generated module failed to compile")?;
+    let mut store = Store::new(&engine, Host { memory: None, tohost: None, unexpected: None });
+    store.set_fuel(fuel)?;
+
+    let memory_ty = module
+        .imports()
+        .find_map(|i| i.ty().memory().cloned())
+        .context("Module does not import memory")?;
+    let memory = Memory::new(&mut store, memory_ty)?;
+    store.data_mut().memory = Some(memory);
+    for (vaddr, data) in &image.segments {
+        memory.write(&mut store, *vaddr as usize, data).context("Segment outside memory")?;
+    }
+    // No LR reservation
+    let reservation = (STATE_ADDR + rv2wasm::translate::RESERVATION_ADDR_OFFSET as u64) as usize;
+    memory.write(&mut store, reservation, &u64::MAX.to_le_bytes())?;
+
+    let syscall = Func::wrap(&mut store, |mut caller: Caller<'_, Host>, m: i32, pc: i32| -> i32 {
+        let memory = caller.data().memory.unwrap();
+        let reg = |caller: &Caller<'_, Host>, i: usize| {
+            let mut b = [0u8; 8];
+            let _ = memory.read(caller, m as u32 as usize + i * 8, &mut b);
+            u64::from_le_bytes(b)
+        };
+        let (a7, a0) = (reg(&caller, 17), reg(&caller, 10));
+        let trap_pc = reg(&caller, rv2wasm::translate::TRAP_PC_OFFSET as usize / 8);
+        let trap_value = reg(&caller, rv2wasm::translate::TRAP_VALUE_OFFSET as usize / 8);
+        let host = caller.data_mut();
+        if pc as u32 == rv2wasm::abi::ILLEGAL_TRAP {
+            host.unexpected = Some(format!("illegal instruction at 0x{:x}", trap_pc));
+        } else if pc as u32 == rv2wasm::abi::MISALIGNED_TRAP {
+            host.unexpected = Some(format!("misaligned atomic access to 0x{:x} at 0x{:x}", trap_value, trap_pc));
+        } else if a7 == SYS_EXIT {
+            // RVTEST_PASS leaves a0 = 0; RVTEST_FAIL a0 = (n << 1) | 1
+            host.tohost = Some(if a0 == 0 { 1 } else { a0 });
+        } else {
+            host.unexpected = Some(format!("ecall a7={} at 0x{:x}", a7, pc & 0x7fff_ffff));
+        }
+        -1
+    });
+
+    let yield_hint = Func::wrap(&mut store, || {});
+    let crypto = Func::wrap(&mut store, |op: i32, rs1: i64, rs2: i64| {
+        rv2wasm::crypto::crypto(op, rs1 as u64, rs2 as u64) as i64
+    });
+    let imports = [memory.into(), syscall.into(), yield_hint.into(), crypto.into()];
+    let instance = Instance::new(&mut store, &module, &imports)?;
+    let run = instance.get_typed_func::<(i32, i32), i32>(&mut store, "run")?;
+    let result = run.call(&mut store, (STATE_ADDR as i32, image.entry as i32));
+
+    let mut tohost = store.data().tohost;
+    if tohost.is_none() {
+        if let Some(addr) = image.tohost {
+            let mut b = [0u8; 8];
+            memory.read(&store, addr as usize, &mut b)?;
+            tohost = Some(u64::from_le_bytes(b)).filter(|&v| v != 0);
+        }
+    }
+
+    Ok(match (tohost, result) {
+        (Some(1), _) => Outcome::Pass,
+        (Some(v), _) => Outcome::Fail(v >> 1),
+        (None, Err(_)) if store.get_fuel().unwrap_or(1) == 0 => Outcome::Timeout,
+        (None, Err(e)) => Outcome::NoResult(format!("trap: {:#}", e)),
+        (None, Ok(_)) => Outcome::NoResult(
+            store
+                .data()
+                .unexpected
+                .clone()
+                .unwrap_or_else(|| "halted on an unsupported instruction".into()),
+        ),
+    })
+}
+
+/// A test image ready to compile and load
+struct Prepared {
+    /// ELF with headers rebased below PC_LIMIT and entry moved past the boot code
+    elf: Vec<u8>,
+    entry: u64,
+    /// Loadable contents (vaddr, file bytes); bss is the zeroed fresh memory
+    segments: Vec<(u64, Vec<u8>)>,
+    tohost: Option<u64>,
+}
+
+fn prepare(data: &[u8]) -> Result<Prepared> {
+    let elf = Elf::parse(data).context("Invalid ELF")?;
+    if !elf.is_64 {
+        bail!("Only RV64 tests are supported");
+    }
+    let loads: Vec<_> = elf
+        .program_headers
+        .iter()
+        .filter(|ph| ph.p_type == program_header::PT_LOAD)
+        .collect();
+    let low = loads.iter().map(|ph| ph.p_vaddr).min().context("No PT_LOAD segments")?;
+    let high = loads.iter().map(|ph| ph.p_vaddr + ph.p_memsz).max().unwrap_or(low);
+    let delta = if high > PC_LIMIT { LOAD_BASE.wrapping_sub(low) } else { 0 };
+
+    // Boot code ends with mret; the test body starts at the next instruction
+    let mut entry = elf.entry;
+    for ph in loads.iter().filter(|ph| ph.p_flags & program_header::PF_X != 0) {
+        let code = data
+            .get(ph.p_offset as usize..(ph.p_offset + ph.p_filesz) as usize)
+            .context("Segment outside file")?;
+        if let Some(i) = code.chunks_exact(4).position(|w| w == MRET.to_le_bytes()) {
+            entry = ph.p_vaddr + (i as u64 + 1) * 4;
+            break;
+        }
+    }
+
+    let tohost = elf
+        .syms
+        .iter()
+        .find(|s| elf.strtab.get_at(s.st_name) == Some("tohost"))
+        .map(|s| s.st_value.wrapping_add(delta));
+
+    let segments = loads
+        .iter()
+        .map(|ph| {
+            let bytes = data
+                .get(ph.p_offset as usize..(ph.p_offset + ph.p_filesz) as usize)
+                .context("Segment outside file")?;
+            Ok((ph.p_vaddr.wrapping_add(delta), bytes.to_vec()))
+        })
+        .collect::<Result<_>>()?;
+
+    let mut out = data.to_vec();
+    rebase(&mut out, &elf, delta, entry.wrapping_add(delta))?;
+
+    Ok(Prepared {
+        elf: out,
+        entry: entry.wrapping_add(delta),
+        segments,
+        tohost,
+    })
+}
+
+/// Patch an ELF64 in place: move every address by `delta` and set e_entry
+fn rebase(out: &mut [u8], elf: &Elf, delta: u64, entry: u64) -> Result<()> {
+    fn patch(out: &mut [u8], at: usize, delta: u64) -> Result<()> {
+        let field = out.get_mut(at..at + 8).context("Header outside file")?;
+        let v = u64::from_le_bytes(field.try_into().unwrap()).wrapping_add(delta);
+        field.copy_from_slice(&v.to_le_bytes());
+        Ok(())
+    }
+
+    out[24..32].copy_from_slice(&entry.to_le_bytes());
+    if delta == 0 {
+        return Ok(());
+    }
+
+    let phoff = elf.header.e_phoff as usize;
+    let phentsize = elf.header.e_phentsize as usize;
+    for i in 0..elf.program_headers.len() {
+        patch(out, phoff + i * phentsize + 16, delta)?; // p_vaddr
+        patch(out, phoff + i * phentsize + 24, delta)?; // p_paddr
+    }
+
+    let shoff = elf.header.e_shoff as usize;
+    let shentsize = elf.header.e_shentsize as usize;
+    for (i, sh) in elf.section_headers.iter().enumerate() {
+        if sh.sh_addr != 0 {
+            patch(out, shoff + i * shentsize + 16, delta)?; // sh_addr
+        }
+        if sh.sh_type == section_header::SHT_SYMTAB {
+            let count = sh.sh_size / 24;
+            for j in 0..count as usize {
+                let sym = sh.sh_offset as usize + j * 24;
+                let shndx = u16::from_le_bytes([out[sym + 6], out[sym + 7]]);
+                // Skip undefined and absolute symbols
+                if shndx != 0 && shndx != 0xfff1 {
+                    patch(out, sym + 8, delta)?; // st_value
+                }
+            }
+        }
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// ELF linked at 0x80000000 like riscv-tests: boot code ending in mret,
+    /// then a test body ending with an exit ECALL carrying `a0`
+    fn fake_test(a0_result: i32) -> Vec<u8> {
+        let code = [
+            0xf140_2573,                                   // csrr a0, mhartid
+            MRET,                                          // mret
+            0x0010_0193,                                   // li gp, 1
+            0x05d0_0893,                                   // li a7, 93
+            0x0000_0513 | ((a0_result as u32) << 20),      // li a0, result
+            0x0000_0073,                                   // ecall
+        ];
+        rv2wasm::elf::tiny_elf_at(goblin::elf::header::ET_EXEC, 0x8000_0000, &code)
+    }
+
+    #[test]
+    fn test_pass_and_fail_reporting() {
+        assert_eq!(run_elf(&fake_test(0), DEFAULT_FUEL), Outcome::Pass);
+        assert_eq!(run_elf(&fake_test((7 << 1) | 1), DEFAULT_FUEL), Outcome::Fail(7));
+    }
+
+    /// The conformance gate: set RISCV_TESTS_DIR to riscv-tests' isa/ build
+    /// (see scripts/fetch-riscv-tests.sh) and run with --ignored
+    #[test]
+    #[ignore = "needs the riscv-tests binaries in RISCV_TESTS_DIR"]
+    fn test_riscv_tests_suite() {
+        let dir = std::env::var_os("RISCV_TESTS_DIR").expect("RISCV_TESTS_DIR must name riscv-tests' isa/ build");
+        let xfail = expected_failures();
+        let mut unexpected = Vec::new();
+        for path in discover(Path::new(&dir)).unwrap() {
+            let name = path.file_name().unwrap().to_string_lossy().into_owned();
+            let outcome = run_elf(&std::fs::read(&path).unwrap(), DEFAULT_FUEL);
+            if outcome.passed() == xfail.contains(name.as_str()) {
+                unexpected.push(format!("{}: {}", name, outcome));
+            }
+        }
+        assert!(unexpected.is_empty(), "Unexpected results:\n{}", unexpected.join("\n"));
+    }
+}
@@ -0,0 +1,70 @@
+// rv2wasm-isa-tests - Run riscv-tests binaries through rv2wasm
+//
+// Usage:
+//   rv2wasm-isa-tests /path/to/riscv-tests/isa
+//   rv2wasm-isa-tests isa/rv64ui-p-add isa/rv64um-p-mulh
+//
+// Exits non-zero if any test's result disagrees with xfail.txt.
+
+use anyhow::Result;
+use clap::Parser;
+use rv2wasm_isa_tests::{discover, expected_failures, run_elf, DEFAULT_FUEL};
+use std::path::PathBuf;
+
+#[derive(Parser, Debug)]
+#[command(name = "rv2wasm-isa-tests")]
+#[command(about = "riscv-tests ISA conformance harness for rv2wasm")]
+#[command(version)]
+struct Args {
+    /// riscv-tests isa/ directory, or individual test binaries
+    paths: Vec<PathBuf>,
+
+    /// Fuel per test before it is reported as a timeout
+    #[arg(long, default_value_t = DEFAULT_FUEL)]
+    fuel: u64,
+
+    /// Only run tests whose name contains this string
+    #[arg(short, long)]
+    filter: Option<String>,
+}
+
+fn main() -> Result<()> {
+    let args = Args::parse();
+
+    let mut tests = Vec::new();
+    for path in &args.paths {
+        if path.is_dir() {
+            tests.extend(discover(path)?);
+        } else {
+            tests.push(path.clone());
+        }
+    }
+
+    let xfail = expected_failures();
+    let (mut passed, mut failed, mut unexpected) = (0, 0, 0);
+    for path in &tests {
+        let name = path.file_name().unwrap_or_default().to_string_lossy();
+        if args.filter.as_ref().is_some_and(|f| !name.contains(f.as_str())) {
+            continue;
+        }
+        let outcome = run_elf(&std::fs::read(path)?, args.fuel);
+        let expected_fail = xfail.contains(name.as_ref());
+        let note = match (outcome.passed(), expected_fail) {
+            (true, true) => " [unexpected pass]",
+            (false, true) => " [expected]",
+            _ => "",
+        };
+        if outcome.passed() == expected_fail {
+            unexpected += 1;
+        }
+        if outcome.passed() {
+            passed += 1;
+        } else {
+            failed += 1;
+        }
+        println!("{:<28} {}{}", name, outcome, note);
+    }
+
+    println!("\n{} passed, {} failed, {} unexpected", passed, failed, unexpected);
+    std::process::exit(if unexpected == 0 { 0 } else { 1 });
+}
@@ -0,0 +1,180 @@
+// idb.rs - IndexedDB persistence for the guest filesystem
+//
+// The guest's writes live in the VFS upper layer, which the runtime hands
+// out as a layer tar (`Vfs::upper_layer`) and takes back at startup
+// (`Vfs::apply_layer`). A `VfsStore` keeps that tar in IndexedDB under a
+// key per container, so files created in the shell survive a page reload.
+//
+// `persist` returns at once and the write happens in the background. Only
+// the newest layer matters: layers persisted while a write is in flight
+// replace each other, and the next write stores the last of them. `sync`
+// settles once everything persisted so far is committed, or fails with
+// the error of the last write; a page calls it before it goes away
+// (`visibilitychange`), since a write still queued then is lost.
+
+use js_sys::{Function, Promise, Uint8Array};
+use std::cell::RefCell;
+use std::rc::Rc;
+use wasm_bindgen::prelude::*;
+use wasm_bindgen::JsCast;
+use wasm_bindgen_futures::{future_to_promise, spawn_local, JsFuture};
+use web_sys::{Event, IdbDatabase, IdbFactory, IdbRequest, IdbTransaction, IdbTransactionMode};
+
+/// The database and object store layers are kept in
+const DB_NAME: &str = "friscy-vfs";
+const DB_VERSION: u32 = 1;
+const STORE: &str = "layers";
+
+/// Layers waiting to be written, and who is waiting for them
+#[derive(Default)]
+struct Queue {
+    /// The newest layer not yet handed to IndexedDB
+    pending: Option<Vec<u8>>,
+    /// A write is in flight
+    writing: bool,
+    /// Why the last write failed
+    error: Option<JsValue>,
+    /// `sync` promises to settle once the queue is empty
+    waiters: Vec<(Function, Function)>,
+}
+
+/// Where a container's upper layer is kept between page loads
+#[wasm_bindgen]
+pub struct VfsStore {
+    db: IdbDatabase,
+    key: String,
+    queue: Rc<RefCell<Queue>>,
+}
+
+#[wasm_bindgen]
+impl VfsStore {
+    /// Open the store for the container called `key`, creating the
+    /// database on first use
+    pub async fn open(key: String) -> Result<VfsStore, JsValue> {
+        let factory: IdbFactory = js_sys::Reflect::get(&js_sys::global(), &"indexedDB".into())?
+            .dyn_into()
+            .map_err(|_| JsValue::from_str("IndexedDB is not available"))?;
+        let request = factory.open_with_u32(DB_NAME, DB_VERSION)?;
+        let upgrade = request.clone();
+        let create = Closure::once_into_js(move || {
+            if let Ok(db) = upgrade.result() {
+                let _ = db.unchecked_into::<IdbDatabase>().create_object_store(STORE);
+            }
+        });
+        request.set_onupgradeneeded(Some(create.unchecked_ref()));
+        let db = settle(&request).await?.unchecked_into();
+        Ok(VfsStore { db, key, queue: Rc::default() })
+    }
+
+    /// The layer stored for this container, or undefined if there is none
+    pub fn load(&self) -> Promise {
+        let (db, key) = (self.db.clone(), self.key.clone());
+        future_to_promise(async move {
+            let tx = db.transaction_with_str(STORE)?;
+            settle(&tx.object_store(STORE)?.get(&key.into())?).await
+        })
+    }
+
+    /// Store `layer` in the background, replacing anything persisted
+    /// before it
+    pub fn persist(&self, layer: Vec<u8>) {
+        let idle = {
+            let mut queue = self.queue.borrow_mut();
+            queue.pending = Some(layer);
+            !std::mem::replace(&mut queue.writing, true)
+        };
+        if idle {
+            spawn_local(write_back(self.db.clone(), self.key.clone(), self.queue.clone()));
+        }
+    }
+
+    /// Settles once every layer persisted so far is committed; rejects
+    /// with the error of the last write if it failed
+    pub fn sync(&self) -> Promise {
+        let mut queue = self.queue.borrow_mut();
+        if !queue.writing {
+            return match queue.error.take() {
+                Some(error) => Promise::reject(&error),
+                None => Promise::resolve(&JsValue::UNDEFINED),
+            };
+        }
+        Promise::new(&mut |resolve, reject| queue.waiters.push((resolve, reject)))
+    }
+
+    /// Forget the stored layer, as for a factory reset of the container
+    pub fn clear(&self) -> Promise {
+        self.queue.borrow_mut().pending = None;
+        let (db, key) = (self.db.clone(), self.key.clone());
+        future_to_promise(async move {
+            let tx = db.transaction_with_str_and_mode(STORE, IdbTransactionMode::Readwrite)?;
+            tx.object_store(STORE)?.delete(&key.into())?;
+            commit(&tx).await
+        })
+    }
+}
+
+/// Write queued layers until none is left, then settle `sync` callers
+async fn write_back(db: IdbDatabase, key: String, queue: Rc<RefCell<Queue>>) {
+    loop {
+        let next = queue.borrow_mut().pending.take();
+        let Some(layer) = next else {
+            break;
+        };
+        let result = put(&db, &key, &layer).await;
+        queue.borrow_mut().error = result.err();
+    }
+    let (waiters, error) = {
+        let mut queue = queue.borrow_mut();
+        queue.writing = false;
+        (std::mem::take(&mut queue.waiters), queue.error.take())
+    };
+    for (resolve, reject) in waiters {
+        let _ = match &error {
+            Some(error) => reject.call1(&JsValue::UNDEFINED, error),
+            None => resolve.call0(&JsValue::UNDEFINED),
+        };
+    }
+}
+
+/// Store `layer` under `key` and wait for the transaction to commit
+async fn put(db: &IdbDatabase, key: &str, layer: &[u8]) -> Result<JsValue, JsValue> {
+    let tx = db.transaction_with_str_and_mode(STORE, IdbTransactionMode::Readwrite)?;
+    tx.object_store(STORE)?.put_with_key(&Uint8Array::from(layer), &key.into())?;
+    commit(&tx).await
+}
+
+/// The result of `request`, once it has one
+async fn settle(request: &IdbRequest) -> Result<JsValue, JsValue> {
+    let promise = Promise::new(&mut |resolve, reject| {
+        let done = request.clone();
+        // Only one of success and error fires, and the closure frees itself
+        let handler = Closure::once_into_js(move || {
+            let _ = match done.error() {
+                Ok(None) => resolve.call1(&JsValue::UNDEFINED, &done.result().unwrap_or_default()),
+                Ok(Some(error)) => reject.call1(&JsValue::UNDEFINED, &error),
+                Err(error) => reject.call1(&JsValue::UNDEFINED, &error),
+            };
+        });
+        request.set_onsuccess(Some(handler.unchecked_ref()));
+        request.set_onerror(Some(handler.unchecked_ref()));
+    });
+    JsFuture::from(promise).await
+}
+
+/// Wait for `tx` to commit; a failed request aborts it
+async fn commit(tx: &IdbTransaction) -> Result<JsValue, JsValue> {
+    let promise = Promise::new(&mut |resolve, reject| {
+        let done = tx.clone();
+        let handler = Closure::once_into_js(move |event: Event| {
+            let _ = if event.type_() == "complete" {
+                resolve.call0(&JsValue::UNDEFINED)
+            } else {
+                let error = done.error().map_or_else(|| JsValue::from_str("IndexedDB transaction aborted"), Into::into);
+                reject.call1(&JsValue::UNDEFINED, &error)
+            };
+        });
+        tx.set_oncomplete(Some(handler.unchecked_ref()));
+        tx.set_onabort(Some(handler.unchecked_ref()));
+    });
+    JsFuture::from(promise).await
+}
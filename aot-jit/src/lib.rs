@@ -3,10 +3,20 @@
 // This crate wraps the rv2wasm AOT compiler for use as a JIT compiler
 // inside a WebAssembly environment. It compiles to wasm32-unknown-unknown
 // via wasm-bindgen, allowing the browser to compile RISC-V code regions
-// to Wasm at runtime.
+// to Wasm at runtime. `queue.rs` runs those compilations in a worker,
+// off the thread running the guest. With the wasm32 feature it also keeps
+// the guest filesystem's writes in IndexedDB (`idb.rs`).
 
 use wasm_bindgen::prelude::*;
 
+mod queue;
+pub use queue::{CompileQueue, CompiledRegion};
+
+#[cfg(feature = "wasm32")]
+mod idb;
+#[cfg(feature = "wasm32")]
+pub use idb::VfsStore;
+
 /// Compile a region of RISC-V machine code to a WebAssembly module.
 ///
 /// Takes raw RISC-V bytes and their virtual address, returns a Wasm module
@@ -16,39 +26,297 @@ use wasm_bindgen::prelude::*;
 /// exports block functions that read/write registers via linear memory.
 #[wasm_bindgen]
 pub fn compile_region(code: &[u8], base_addr: u32) -> Result<Vec<u8>, JsValue> {
-    compile_region_inner(code, base_addr)
+    rv2wasm::compile_region(code, base_addr as u64)
         .map_err(|e| JsValue::from_str(&format!("{:#}", e)))
 }
 
-fn compile_region_inner(code: &[u8], base_addr: u32) -> anyhow::Result<Vec<u8>> {
-    use rv2wasm::{disasm, cfg, translate, wasm_builder};
-
-    // Create a CodeSection from the raw bytes
-    let section = rv2wasm::CodeSection {
-        vaddr: base_addr as u64,
-        data: code.to_vec(),
-        name: format!("jit_0x{:08x}", base_addr),
+/// `compile_region` with a call to the imported `env.breakpoint(pc)` before
+/// each instruction in `breakpoints`; the block stops there (returning as
+/// EBREAK at that PC) when it returns nonzero. The module also imports
+/// `env.watch`. Replacing a block's function with this module's export sets
+/// a breakpoint without writing guest memory.
+#[wasm_bindgen(js_name = compileRegionWithBreakpoints)]
+pub fn compile_region_with_breakpoints(code: &[u8], base_addr: u32, breakpoints: Vec<u32>) -> Result<Vec<u8>, JsValue> {
+    let options = rv2wasm::CompileOptions {
+        breakpoints: breakpoints.into_iter().map(u64::from).collect(),
+        ..Default::default()
     };
+    rv2wasm::compile_region_with_options(code, base_addr as u64, &options)
+        .map_err(|e| JsValue::from_str(&format!("{:#}", e)))
+}
+
+/// Get version string
+#[wasm_bindgen]
+pub fn version() -> String {
+    format!("rv2wasm-jit {}", env!("CARGO_PKG_VERSION"))
+}
+
+/// A guest snapshot in the format shared with the native runtime
+/// (rv2wasm::snapshot). JS reads regions out to restore guest memory, or
+/// builds one from its own state and serializes it with `toBytes()`.
+#[wasm_bindgen]
+pub struct GuestSnapshot(rv2wasm::Snapshot);
+
+#[wasm_bindgen]
+impl GuestSnapshot {
+    #[wasm_bindgen(constructor)]
+    pub fn new(pc: u64, state: &[u8]) -> GuestSnapshot {
+        GuestSnapshot(rv2wasm::Snapshot {
+            pc,
+            state: state.to_vec(),
+            ..Default::default()
+        })
+    }
+
+    #[wasm_bindgen(js_name = fromBytes)]
+    pub fn from_bytes(bytes: &[u8]) -> Result<GuestSnapshot, JsValue> {
+        rv2wasm::Snapshot::decode(bytes)
+            .map(GuestSnapshot)
+            .map_err(|e| JsValue::from_str(&format!("{:#}", e)))
+    }
+
+    #[wasm_bindgen(js_name = toBytes)]
+    pub fn to_bytes(&self) -> Vec<u8> {
+        self.0.encode()
+    }
+
+    #[wasm_bindgen(getter)]
+    pub fn pc(&self) -> u64 {
+        self.0.pc
+    }
+
+    /// Hart state block (registers, FP registers, fcsr, reservation)
+    #[wasm_bindgen(getter)]
+    pub fn state(&self) -> Vec<u8> {
+        self.0.state.clone()
+    }
+
+    #[wasm_bindgen(getter)]
+    pub fn brk(&self) -> u64 {
+        self.0.brk
+    }
+
+    /// Set the heap: `[brk_base, brk_base + contents.length)`
+    #[wasm_bindgen(js_name = setHeap)]
+    pub fn set_heap(&mut self, brk_base: u64, brk_max: u64, contents: &[u8]) {
+        self.0.layout.brk_base = brk_base;
+        self.0.layout.brk_max = brk_max;
+        self.0.brk = brk_base + contents.len() as u64;
+        self.0.heap = contents.to_vec();
+    }
 
-    // Disassemble
-    let instructions = disasm::disassemble(&section)?;
-    if instructions.is_empty() {
-        anyhow::bail!("No instructions decoded in region 0x{:08x}", base_addr);
+    #[wasm_bindgen(js_name = heapBase)]
+    pub fn heap_base(&self) -> u64 {
+        self.0.layout.brk_base
     }
 
-    // Build CFG
-    let entry = instructions[0].addr;
-    let cfg = cfg::build(&instructions, entry)?;
+    pub fn heap(&self) -> Vec<u8> {
+        self.0.heap.clone()
+    }
 
-    // Translate to Wasm IR (JIT mode: shared memory import)
-    let wasm_module = translate::translate_jit(&cfg, base_addr as u64)?;
+    #[wasm_bindgen(js_name = addRegion)]
+    pub fn add_region(&mut self, start: u64, prot: u32, flags: u32, data: &[u8]) {
+        self.0.regions.push(rv2wasm::snapshot::Region {
+            start,
+            prot,
+            flags,
+            file: None,
+            data: data.to_vec(),
+        });
+    }
+
+    #[wasm_bindgen(js_name = regionCount)]
+    pub fn region_count(&self) -> usize {
+        self.0.regions.len()
+    }
+
+    #[wasm_bindgen(js_name = regionStart)]
+    pub fn region_start(&self, i: usize) -> u64 {
+        self.0.regions.get(i).map_or(0, |r| r.start)
+    }
+
+    #[wasm_bindgen(js_name = regionProt)]
+    pub fn region_prot(&self, i: usize) -> u32 {
+        self.0.regions.get(i).map_or(0, |r| r.prot)
+    }
+
+    #[wasm_bindgen(js_name = regionData)]
+    pub fn region_data(&self, i: usize) -> Vec<u8> {
+        self.0.regions.get(i).map(|r| r.data.clone()).unwrap_or_default()
+    }
 
-    // Generate Wasm binary
-    wasm_builder::build_jit(&wasm_module)
+    /// Record an open file: descriptor, path, openat flags and offset
+    #[wasm_bindgen(js_name = addFd)]
+    pub fn add_fd(&mut self, fd: i32, path: &str, flags: u32, offset: u64) {
+        self.0.fds.push(rv2wasm::snapshot::FdEntry { fd, path: path.into(), flags, offset });
+    }
+
+    #[wasm_bindgen(js_name = fdCount)]
+    pub fn fd_count(&self) -> usize {
+        self.0.fds.len()
+    }
+
+    #[wasm_bindgen(js_name = fdNumber)]
+    pub fn fd_number(&self, i: usize) -> i32 {
+        self.0.fds.get(i).map_or(-1, |f| f.fd)
+    }
+
+    #[wasm_bindgen(js_name = fdPath)]
+    pub fn fd_path(&self, i: usize) -> String {
+        self.0.fds.get(i).map(|f| f.path.clone()).unwrap_or_default()
+    }
+
+    #[wasm_bindgen(js_name = fdFlags)]
+    pub fn fd_flags(&self, i: usize) -> u32 {
+        self.0.fds.get(i).map_or(0, |f| f.flags)
+    }
+
+    #[wasm_bindgen(js_name = fdOffset)]
+    pub fn fd_offset(&self, i: usize) -> u64 {
+        self.0.fds.get(i).map_or(0, |f| f.offset)
+    }
 }
 
-/// Get version string
+/// What a guest program starts with: argv, environment and working
+/// directory. `load` lays a program out for them the way the native
+/// runtime does (rv2wasm::loader), so JS copies the result into guest
+/// memory instead of building the initial stack itself.
 #[wasm_bindgen]
-pub fn version() -> String {
-    format!("rv2wasm-jit {}", env!("CARGO_PKG_VERSION"))
+pub struct GuestLaunch {
+    opts: rv2wasm::loader::LoadOptions,
+    cwd: String,
+}
+
+#[wasm_bindgen]
+impl GuestLaunch {
+    #[wasm_bindgen(constructor)]
+    pub fn new(argv0: &str) -> GuestLaunch {
+        GuestLaunch {
+            opts: rv2wasm::loader::LoadOptions {
+                argv: vec![argv0.to_string()],
+                ..Default::default()
+            },
+            cwd: "/".to_string(),
+        }
+    }
+
+    #[wasm_bindgen(js_name = addArg)]
+    pub fn add_arg(&mut self, arg: &str) {
+        self.opts.argv.push(arg.to_string());
+    }
+
+    /// Set `key` to `value`, replacing any earlier value
+    #[wasm_bindgen(js_name = setEnv)]
+    pub fn set_env(&mut self, key: &str, value: &str) {
+        let entry = format!("{}={}", key, value);
+        let prefix = format!("{}=", key);
+        match self.opts.envp.iter_mut().find(|e| e.starts_with(&prefix)) {
+            Some(existing) => *existing = entry,
+            None => self.opts.envp.push(entry),
+        }
+    }
+
+    /// The initial working directory, an absolute path
+    #[wasm_bindgen(js_name = setCwd)]
+    pub fn set_cwd(&mut self, path: &str) -> Result<(), JsValue> {
+        if !path.starts_with('/') {
+            return Err(JsValue::from_str(&format!("Working directory {:?} is not absolute", path)));
+        }
+        self.cwd = path.to_string();
+        Ok(())
+    }
+
+    #[wasm_bindgen(getter)]
+    pub fn cwd(&self) -> String {
+        self.cwd.clone()
+    }
+
+    /// Lay out `program` (and its dynamic interpreter, if any)
+    pub fn load(&self, program: &[u8], interp: Option<Vec<u8>>) -> Result<GuestImage, JsValue> {
+        rv2wasm::loader::load(program, interp.as_deref(), &self.opts)
+            .map(GuestImage)
+            .map_err(|e| JsValue::from_str(&format!("{:#}", e)))
+    }
+}
+
+/// A loaded program: segments and the initial stack to copy into guest
+/// memory, and where to start
+#[wasm_bindgen]
+pub struct GuestImage(rv2wasm::loader::InitialImage);
+
+#[wasm_bindgen]
+impl GuestImage {
+    #[wasm_bindgen(getter)]
+    pub fn entry(&self) -> u64 {
+        self.0.entry
+    }
+
+    #[wasm_bindgen(getter)]
+    pub fn sp(&self) -> u64 {
+        self.0.stack.sp
+    }
+
+    #[wasm_bindgen(getter, js_name = brkBase)]
+    pub fn brk_base(&self) -> u64 {
+        self.0.brk_base
+    }
+
+    /// Memory to fill, the initial stack last
+    #[wasm_bindgen(js_name = dataCount)]
+    pub fn data_count(&self) -> usize {
+        self.0.data_segments().count()
+    }
+
+    #[wasm_bindgen(js_name = dataAddr)]
+    pub fn data_addr(&self, i: usize) -> u64 {
+        self.0.data_segments().nth(i).map_or(0, |(addr, _)| addr)
+    }
+
+    #[wasm_bindgen(js_name = dataBytes)]
+    pub fn data_bytes(&self, i: usize) -> Vec<u8> {
+        self.0.data_segments().nth(i).map(|(_, data)| data.to_vec()).unwrap_or_default()
+    }
+}
+
+/// Names guest PCs (`main+0x14`) from a program's symbol table and the
+/// blocks JS has compiled, for trap messages and profiles
+#[wasm_bindgen]
+pub struct GuestSymbolizer(rv2wasm::Symbolizer);
+
+#[wasm_bindgen]
+impl GuestSymbolizer {
+    #[wasm_bindgen(constructor)]
+    pub fn new() -> GuestSymbolizer {
+        GuestSymbolizer(rv2wasm::Symbolizer::default())
+    }
+
+    /// Add the function symbols of an ELF object loaded at `bias`
+    #[wasm_bindgen(js_name = addElf)]
+    pub fn add_elf(&mut self, elf: &[u8], bias: u64) -> Result<(), JsValue> {
+        let file = rv2wasm::ElfFile::parse(elf).map_err(|e| JsValue::from_str(&format!("{:#}", e)))?;
+        self.0.add_symbols(file.symbols(), bias);
+        Ok(())
+    }
+
+    /// Record a compiled block `[start, end)`
+    #[wasm_bindgen(js_name = addBlock)]
+    pub fn add_block(&mut self, start: u64, end: u64) {
+        self.0.add_block(start, end);
+    }
+
+    /// `symbol+offset` for `pc`, or undefined outside any function
+    pub fn lookup(&self, pc: u64) -> Option<String> {
+        self.0.lookup(pc).map(|loc| loc.to_string())
+    }
+
+    /// `pc` for messages: `0x<pc> <main+0x14>`
+    pub fn describe(&self, pc: u64) -> String {
+        self.0.describe(pc)
+    }
+}
+
+impl Default for GuestSymbolizer {
+    fn default() -> Self {
+        Self::new()
+    }
 }
@@ -0,0 +1,249 @@
+// queue.rs - Background compilation queue
+//
+// Compiling a region takes milliseconds, too long for the thread running
+// the guest. A `CompileQueue` lives in a dedicated worker instead: the
+// runtime posts regions to it and keeps interpreting, and each finished
+// module comes back through the callback it was submitted with, along
+// with what it was compiled from.
+//
+// Requests run highest priority first, in submission order among equals.
+// Asking again for a region already queued (same code, address and
+// breakpoints) does not compile it twice: the request joins the queued
+// one, which takes the higher of the two priorities, and every caller is
+// called back with the one module. `runNext` compiles a single region, so
+// a worker interleaves it with its message handling and submissions and
+// cancellations take effect between compilations:
+//
+//     onmessage = ({ data }) => { ...submit or cancel...; schedule(); };
+//     const schedule = () => setTimeout(() => queue.runNext() && schedule());
+
+use js_sys::Function;
+use std::collections::{BTreeMap, BTreeSet};
+use wasm_bindgen::prelude::*;
+
+/// What a region is compiled from; equal requests are compiled once
+#[derive(Clone, PartialEq, Eq)]
+pub struct Request {
+    pub code: Vec<u8>,
+    pub base_addr: u64,
+    pub breakpoints: BTreeSet<u64>,
+}
+
+/// A queued request and everyone waiting for it
+pub struct Job<C> {
+    pub request: Request,
+    pub priority: i32,
+    pub callbacks: Vec<C>,
+    seq: u64,
+}
+
+/// Pending requests by id, independent of how callers are called back
+pub struct Queue<C> {
+    jobs: BTreeMap<u32, Job<C>>,
+    next_id: u32,
+    next_seq: u64,
+}
+
+impl<C> Default for Queue<C> {
+    fn default() -> Self {
+        Self { jobs: BTreeMap::new(), next_id: 1, next_seq: 0 }
+    }
+}
+
+impl<C> Queue<C> {
+    /// Queue `request`, or join an equal one already queued; the job's id
+    pub fn submit(&mut self, request: Request, priority: i32, callback: C) -> u32 {
+        if let Some((&id, job)) = self.jobs.iter_mut().find(|(_, job)| job.request == request) {
+            job.priority = job.priority.max(priority);
+            job.callbacks.push(callback);
+            return id;
+        }
+        let id = self.next_id;
+        self.next_id += 1;
+        self.jobs.insert(id, Job { request, priority, callbacks: vec![callback], seq: self.next_seq });
+        self.next_seq += 1;
+        id
+    }
+
+    /// Drop job `id` and its callbacks; whether it was still queued
+    pub fn cancel(&mut self, id: u32) -> bool {
+        self.jobs.remove(&id).is_some()
+    }
+
+    /// The job to run next: highest priority, then oldest
+    pub fn pop(&mut self) -> Option<(u32, Job<C>)> {
+        let id = *self.jobs.iter().max_by_key(|(_, job)| (job.priority, std::cmp::Reverse(job.seq)))?.0;
+        self.jobs.remove(&id).map(|job| (id, job))
+    }
+
+    pub fn len(&self) -> usize {
+        self.jobs.len()
+    }
+}
+
+/// A finished compilation, as passed to submitters' callbacks
+#[wasm_bindgen]
+#[derive(Clone)]
+pub struct CompiledRegion {
+    id: u32,
+    base_addr: u64,
+    code_size: usize,
+    priority: i32,
+    requests: usize,
+    module: Option<Vec<u8>>,
+    error: Option<String>,
+}
+
+#[wasm_bindgen]
+impl CompiledRegion {
+    /// The id `submit` returned
+    #[wasm_bindgen(getter)]
+    pub fn id(&self) -> u32 {
+        self.id
+    }
+
+    #[wasm_bindgen(getter, js_name = baseAddr)]
+    pub fn base_addr(&self) -> u64 {
+        self.base_addr
+    }
+
+    /// Bytes of RISC-V code compiled
+    #[wasm_bindgen(getter, js_name = codeSize)]
+    pub fn code_size(&self) -> usize {
+        self.code_size
+    }
+
+    /// The priority it ran at, the highest any submitter asked for
+    #[wasm_bindgen(getter)]
+    pub fn priority(&self) -> i32 {
+        self.priority
+    }
+
+    /// How many submissions this compilation answered
+    #[wasm_bindgen(getter)]
+    pub fn requests(&self) -> usize {
+        self.requests
+    }
+
+    /// The JIT module (rv2wasm link.rs imports), or undefined if the
+    /// region failed to compile
+    #[wasm_bindgen(getter)]
+    pub fn module(&self) -> Option<Vec<u8>> {
+        self.module.clone()
+    }
+
+    /// Why the region failed to compile
+    #[wasm_bindgen(getter)]
+    pub fn error(&self) -> Option<String> {
+        self.error.clone()
+    }
+}
+
+/// Regions waiting to be compiled in a worker
+#[wasm_bindgen]
+#[derive(Default)]
+pub struct CompileQueue(Queue<Function>);
+
+#[wasm_bindgen]
+impl CompileQueue {
+    #[wasm_bindgen(constructor)]
+    pub fn new() -> CompileQueue {
+        CompileQueue::default()
+    }
+
+    /// Queue `code` loaded at `base_addr` for compilation, with hooks at
+    /// `breakpoints` as `compileRegionWithBreakpoints` does; `callback`
+    /// gets a `CompiledRegion`. Returns an id for `cancel`, shared with an
+    /// equal request already queued.
+    pub fn submit(
+        &mut self,
+        code: &[u8],
+        base_addr: u32,
+        priority: i32,
+        callback: Function,
+        breakpoints: Option<Vec<u32>>,
+    ) -> u32 {
+        let request = Request {
+            code: code.to_vec(),
+            base_addr: base_addr as u64,
+            breakpoints: breakpoints.unwrap_or_default().into_iter().map(u64::from).collect(),
+        };
+        self.0.submit(request, priority, callback)
+    }
+
+    /// Drop a queued request and every callback waiting on it; false if it
+    /// already ran or was never queued
+    pub fn cancel(&mut self, id: u32) -> bool {
+        self.0.cancel(id)
+    }
+
+    /// Requests still queued
+    #[wasm_bindgen(getter)]
+    pub fn pending(&self) -> usize {
+        self.0.len()
+    }
+
+    /// Compile the next region and call back everyone who asked for it;
+    /// false if the queue was empty
+    #[wasm_bindgen(js_name = runNext)]
+    pub fn run_next(&mut self) -> bool {
+        let Some((id, job)) = self.0.pop() else {
+            return false;
+        };
+        let options = rv2wasm::CompileOptions {
+            breakpoints: job.request.breakpoints.clone(),
+            ..Default::default()
+        };
+        let result = rv2wasm::compile_region_with_options(&job.request.code, job.request.base_addr, &options);
+        let region = CompiledRegion {
+            id,
+            base_addr: job.request.base_addr,
+            code_size: job.request.code.len(),
+            priority: job.priority,
+            requests: job.callbacks.len(),
+            error: result.as_ref().err().map(|e| format!("{:#}", e)),
+            module: result.ok(),
+        };
+        for callback in job.callbacks {
+            // A callback that throws does not keep the others from running
+            let _ = callback.call1(&JsValue::NULL, &region.clone().into());
+        }
+        true
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn request(base_addr: u64) -> Request {
+        Request { code: vec![0x13, 0, 0, 0], base_addr, breakpoints: BTreeSet::new() }
+    }
+
+    #[test]
+    fn test_queue_order_and_coalescing() {
+        let mut queue = Queue::default();
+        let low = queue.submit(request(0x1000), 0, "a");
+        let high = queue.submit(request(0x2000), 5, "b");
+        let later = queue.submit(request(0x3000), 5, "c");
+
+        // The same region again joins the queued job and raises it
+        assert_eq!(queue.submit(request(0x1000), 9, "d"), low);
+        let mut with_breakpoint = request(0x1000);
+        with_breakpoint.breakpoints.insert(0x1000);
+        let other = queue.submit(with_breakpoint, 0, "e");
+        assert_ne!(other, low);
+        assert_eq!(queue.len(), 4);
+
+        let (id, job) = queue.pop().unwrap();
+        assert_eq!((id, job.priority, job.callbacks), (low, 9, vec!["a", "d"]));
+        assert!(queue.cancel(later));
+        assert!(!queue.cancel(later));
+        assert_eq!(queue.pop().unwrap().0, high);
+        assert_eq!(queue.pop().unwrap().0, other);
+        assert!(queue.pop().is_none());
+
+        // A region compiled already is queued anew
+        assert_ne!(queue.submit(request(0x1000), 0, "f"), low);
+    }
+}
@@ -0,0 +1,140 @@
+// backtrace.rs - Guest stack backtraces
+//
+// Walks the guest stack from a hart's registers. Where an .eh_frame FDE
+// covers the PC (`rv2wasm::unwind`) its rules give the caller's sp, ra and
+// saved registers; elsewhere the frame-pointer convention does: s0 is the
+// frame's CFA, with ra saved at s0-8 and the caller's s0 at s0-16. A leaf
+// that saves only s0 leaves the caller's s0 at s0-8 instead, which shows as
+// a value above the frame, and its caller is still in ra.
+//
+// Code without either (no CFI, built with -fomit-frame-pointer) ends the
+// walk early or skips frames; the trace is a debugging aid, not an
+// unwinder exceptions can rely on.
+
+use crate::mm::GuestMemory;
+use rv2wasm::unwind::{EhFrame, Row, Rule, RA, SP};
+use rv2wasm::Symbolizer;
+
+/// Frame pointer (s0)
+const FP: usize = 8;
+
+/// Deepest trace walked
+pub const MAX_FRAMES: usize = 64;
+
+/// One frame: where it is executing, and its sp
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Frame {
+    pub pc: u64,
+    pub sp: u64,
+}
+
+fn read_u64(mem: &impl GuestMemory, addr: u64) -> Option<u64> {
+    let mut word = [0u8; 8];
+    mem.read(addr, &mut word).ok()?;
+    Some(u64::from_le_bytes(word))
+}
+
+/// The caller's PC and registers by the CFI row for the current PC
+fn unwind_cfi(mem: &impl GuestMemory, row: &Row, regs: &[u64; 32]) -> Option<(u64, [u64; 32])> {
+    let cfa = regs.get(row.cfa_reg as usize)?.wrapping_add(row.cfa_offset as u64);
+    if matches!(row.rule(row.ra_reg), Rule::Undefined | Rule::Unsupported) {
+        // The outermost frame (_start marks ra undefined)
+        return None;
+    }
+    let mut caller = *regs;
+    for (reg, value) in caller.iter_mut().enumerate().skip(1) {
+        *value = match row.rule(reg as u16) {
+            Rule::Offset(offset) => read_u64(mem, cfa.wrapping_add(offset as u64))?,
+            Rule::ValOffset(offset) => cfa.wrapping_add(offset as u64),
+            Rule::Register(from) => *regs.get(from as usize)?,
+            Rule::SameValue | Rule::Undefined | Rule::Unsupported => regs[reg],
+        };
+    }
+    let pc = *caller.get(row.ra_reg as usize)?;
+    caller[SP as usize] = cfa;
+    Some((pc, caller))
+}
+
+/// The caller's PC and registers by frame pointer
+fn unwind_fp(mem: &impl GuestMemory, regs: &[u64; 32], innermost: bool) -> Option<(u64, [u64; 32])> {
+    let (sp, fp) = (regs[SP as usize], regs[FP]);
+    if fp <= sp || !fp.is_multiple_of(8) {
+        return None;
+    }
+    let saved = read_u64(mem, fp - 8)?;
+    let mut caller = *regs;
+    caller[SP as usize] = fp;
+    let pc = if innermost && saved > fp {
+        caller[FP] = saved;
+        regs[RA as usize]
+    } else {
+        caller[FP] = read_u64(mem, fp - 16)?;
+        saved
+    };
+    Some((pc, caller))
+}
+
+/// Walk the stack of a hart stopped at `pc` with registers `regs`
+pub fn walk(mem: &impl GuestMemory, unwind: &[EhFrame], pc: u64, regs: &[u64; 32]) -> Vec<Frame> {
+    let (mut pc, mut regs) = (pc, *regs);
+    let mut frames = Vec::new();
+    while frames.len() < MAX_FRAMES {
+        let sp = regs[SP as usize];
+        frames.push(Frame { pc, sp });
+        // Return addresses point past the call; look up the call itself
+        let at = if frames.len() == 1 { pc } else { pc.wrapping_sub(1) };
+        let caller = match unwind.iter().find(|t| t.covers(at)).and_then(|t| t.row(at)) {
+            Some(row) => unwind_cfi(mem, &row, &regs),
+            None => unwind_fp(mem, &regs, frames.len() == 1),
+        };
+        let Some((next_pc, next_regs)) = caller else {
+            break;
+        };
+        // Stacks grow down: a caller's frame is never below its callee's
+        let next_sp = next_regs[SP as usize];
+        if next_pc == 0 || next_sp < sp || (next_sp == sp && next_pc == pc) {
+            break;
+        }
+        (pc, regs) = (next_pc, next_regs);
+    }
+    frames
+}
+
+/// One line per frame, innermost first: `#1  0x10234 <main+0x14>`
+pub fn render(frames: &[Frame], symbols: &Symbolizer) -> String {
+    frames.iter().enumerate().map(|(i, f)| format!("#{:<2} {}\n", i, symbols.describe(f.pc))).collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use rv2wasm::Symbol;
+
+    #[test]
+    fn test_frame_pointer_walk() {
+        // main (0x1000) calls f (0x1100), which calls the leaf g (0x1200).
+        // main's frame is 0x7f00-0x8000, f's 0x7e00-0x7f00, g's (s0 only)
+        // 0x7df0-0x7e00.
+        let mut mem = vec![0u8; 0x8000];
+        let mut put = |addr: u64, value: u64| GuestMemory::write(&mut mem, addr, &value.to_le_bytes()).unwrap();
+        put(0x7ff8, 0);
+        put(0x7ff0, 0);
+        put(0x7ef8, 0x1010);
+        put(0x7ef0, 0x8000);
+        put(0x7df8, 0x7f00);
+
+        let mut regs = [0u64; 32];
+        (regs[RA as usize], regs[SP as usize], regs[FP]) = (0x1120, 0x7df0, 0x7e00);
+        let frames = walk(&mem, &[], 0x1208, &regs);
+        let pcs: Vec<u64> = frames.iter().map(|f| f.pc).collect();
+        assert_eq!(pcs, [0x1208, 0x1120, 0x1010]);
+        assert_eq!(frames[2].sp, 0x7f00);
+
+        let symbols = Symbolizer::new(vec![
+            Symbol { name: "main".into(), addr: 0x1000, size: 0x100 },
+            Symbol { name: "f".into(), addr: 0x1100, size: 0x100 },
+            Symbol { name: "g".into(), addr: 0x1200, size: 0x10 },
+        ]);
+        assert_eq!(render(&frames, &symbols), "#0  0x1208 <g+0x8>\n#1  0x1120 <f+0x20>\n#2  0x1010 <main+0x10>\n");
+    }
+}
@@ -0,0 +1,135 @@
+// friscy-trace - Compare two register traces
+//
+// Reads two `friscy-run --reg-trace` files and reports the first block
+// boundary where they disagree: the block that ran just before it (the one
+// that computed the differing registers), disassembled from the program,
+// and the registers that differ.
+//
+// Usage:
+//   friscy-trace golden.rtr jit.rtr --program prog
+//   friscy-trace golden.rtr jit.rtr --program prog --interp ld-linux-riscv64-lp64d.so.1
+//
+// Exits 0 if the traces are identical and 1 if they diverge.
+
+use anyhow::{Context, Result};
+use clap::Parser;
+use friscy_runtime::launch::Launch;
+use friscy_runtime::regtrace::{self, RegTraceReader};
+use rv2wasm::disasm::{self, Instruction};
+use rv2wasm::loader::InitialImage;
+use rv2wasm::{CodeSection, ElfFile};
+use std::io::BufReader;
+use std::path::{Path, PathBuf};
+
+/// Bytes of code disassembled looking for the end of a block
+const MAX_BLOCK: u64 = 1024;
+
+#[derive(Parser, Debug)]
+#[command(name = "friscy-trace")]
+#[command(about = "Find where two friscy-run register traces diverge")]
+#[command(version)]
+struct Args {
+    /// Reference trace (e.g. from friscy-run --interpret)
+    a: PathBuf,
+
+    /// Trace to check against it
+    b: PathBuf,
+
+    /// The traced program, to disassemble the divergent block
+    #[arg(long)]
+    program: Option<PathBuf>,
+
+    /// Dynamic interpreter the program ran with
+    #[arg(long, requires = "program")]
+    interp: Option<PathBuf>,
+}
+
+fn open(path: &Path) -> Result<RegTraceReader<BufReader<std::fs::File>>> {
+    let file = std::fs::File::open(path).with_context(|| format!("Failed to open {}", path.display()))?;
+    RegTraceReader::new(BufReader::new(file)).with_context(|| format!("Failed to read {}", path.display()))
+}
+
+/// Load the program as friscy-run does, for the code at its runtime addresses
+fn load(program: &Path, interp: Option<&Path>) -> Result<InitialImage> {
+    let program_data = std::fs::read(program).context("Failed to read program")?;
+    let interp_data = interp.map(std::fs::read).transpose().context("Failed to read interpreter")?;
+    let program_elf = ElfFile::parse(&program_data)?;
+    let interp_elf = interp_data.as_deref().map(ElfFile::parse).transpose().context("Invalid interpreter ELF")?;
+    Launch::new(program.display().to_string()).load(&program_elf, interp_elf.as_ref())
+}
+
+/// The instructions of the block at `pc`, through its terminator
+fn block_at(image: &InitialImage, pc: u64) -> Vec<Instruction> {
+    let Some(seg) = image.segments.iter().find(|s| pc >= s.vaddr && pc < s.vaddr + s.data.len() as u64) else {
+        return Vec::new();
+    };
+    let start = (pc - seg.vaddr) as usize;
+    let end = seg.data.len().min(start + MAX_BLOCK as usize);
+    let section = CodeSection { vaddr: pc, data: seg.data[start..end].to_vec(), name: String::new() };
+    let mut block = Vec::new();
+    for inst in disasm::disassemble(&section).unwrap_or_default() {
+        let last = inst.opcode.is_terminator();
+        block.push(inst);
+        if last {
+            break;
+        }
+    }
+    block
+}
+
+/// One line of disassembly: address, encoding, opcode and operand fields
+fn describe(inst: &Instruction) -> String {
+    let bytes = if inst.len == 2 { format!("{:04x}    ", inst.bytes) } else { format!("{:08x}", inst.bytes) };
+    let mut line = format!("0x{:x}  {}  {:?}", inst.addr, bytes, inst.opcode);
+    let fields = [("rd", inst.rd), ("rs1", inst.rs1), ("rs2", inst.rs2)];
+    for (name, reg) in fields.iter().filter_map(|&(name, reg)| Some((name, reg?))) {
+        line += &format!(" {}={}", name, reg);
+    }
+    if let Some(imm) = inst.imm {
+        line += &format!(" imm={}", imm);
+    }
+    line
+}
+
+fn main() -> Result<()> {
+    let args = Args::parse();
+    let Some(d) = regtrace::diff(open(&args.a)?, open(&args.b)?)? else {
+        println!("Traces are identical");
+        return Ok(());
+    };
+
+    println!("Traces agree for {} blocks", d.index);
+    match &d.last {
+        Some(last) => {
+            println!("First divergence after the block at 0x{:x}:", last.pc);
+            if let Some(program) = &args.program {
+                let image = load(program, args.interp.as_deref())?;
+                let block = block_at(&image, last.pc);
+                if block.is_empty() {
+                    println!("  (no code at 0x{:x} in {})", last.pc, program.display());
+                }
+                for inst in &block {
+                    println!("  {}", describe(inst));
+                }
+            }
+        }
+        None => println!("First divergence at the start"),
+    }
+
+    let (a, b) = (args.a.display(), args.b.display());
+    match (&d.a, &d.b) {
+        (Some(ea), Some(eb)) => {
+            if ea.pc != eb.pc {
+                println!("Next block: 0x{:x} ({}) vs 0x{:x} ({})", ea.pc, a, eb.pc, b);
+            }
+            for slot in d.registers() {
+                let name = regtrace::slot_name(slot);
+                println!("  {:>6}: 0x{:x} ({}) vs 0x{:x} ({})", name, ea.regs[slot], a, eb.regs[slot], b);
+            }
+        }
+        (Some(ea), None) => println!("{} ends; {} goes on to 0x{:x}", b, a, ea.pc),
+        (None, Some(eb)) => println!("{} ends; {} goes on to 0x{:x}", a, b, eb.pc),
+        (None, None) => unreachable!(),
+    }
+    std::process::exit(1);
+}
@@ -0,0 +1,346 @@
+// gdb.rs - GDB remote serial protocol stub
+//
+// Lets `gdb-multiarch` debug a guest running in a Machine:
+//
+//   friscy-run --gdb 127.0.0.1:1234 prog
+//   gdb-multiarch prog -ex 'set arch riscv:rv64' -ex 'target remote :1234'
+//
+// Supports register and memory access, software/hardware breakpoints
+// (Z0/Z1, implemented by recompiling the block holding the address with a
+// breakpoint hook, so guest memory is left alone) and
+// single-step (one-instruction blocks). The transport is any byte stream:
+// a TCP connection, or a WebSocket (`websocket.rs`, `--gdb-ws`) for remote
+// browser sessions.
+// Execution is synchronous: Ctrl-C is only seen between continue requests.
+
+use crate::machine::{Exit, Machine};
+use crate::mm::GuestMemory;
+use anyhow::Result;
+use std::io::{Read, Write};
+
+/// Register numbers in gdb's RISC-V layout
+const REG_PC: usize = 32;
+const REG_F0: usize = 33;
+const REG_F31: usize = 64;
+
+/// Largest packet we accept, as qSupported advertises it
+const PACKET_SIZE: usize = 0x4000;
+
+const SIGTRAP: u8 = 5;
+const SIGILL: u8 = 4;
+const SIGBUS: u8 = 7;
+const SIGSEGV: u8 = 11;
+
+/// A GDB session over a byte stream
+pub struct GdbStub<S: Read + Write> {
+    stream: S,
+    /// Set when the guest has exited; the next stop reply is a W packet
+    exited: Option<i32>,
+}
+
+impl<S: Read + Write> GdbStub<S> {
+    pub fn new(stream: S) -> Self {
+        Self { stream, exited: None }
+    }
+
+    /// Serve requests until gdb detaches or kills the session.
+    /// Returns the guest exit status if it exited.
+    pub fn serve(&mut self, machine: &mut Machine) -> Result<Option<i32>> {
+        while let Some(packet) = self.recv()? {
+            let reply = match packet.as_bytes().first() {
+                Some(b'k') => return Ok(self.exited),
+                Some(b'D') => {
+                    self.send("OK")?;
+                    return Ok(self.exited);
+                }
+                _ => self.handle(machine, &packet)?,
+            };
+            self.send(&reply)?;
+        }
+        Ok(self.exited)
+    }
+
+    fn handle(&mut self, machine: &mut Machine, packet: &str) -> Result<String> {
+        let Some(cmd) = packet.get(..1) else { return Ok(String::new()) };
+        let args = &packet[1..];
+        Ok(match cmd {
+            "?" => stop_reply(&Exit::Stepped, self.exited),
+            "g" => {
+                let mut out = String::new();
+                for r in 0..=REG_PC {
+                    out.push_str(&hex_u64(read_reg(machine, r)));
+                }
+                out
+            }
+            "G" => {
+                for (r, chunk) in args.as_bytes().chunks(16).enumerate().take(REG_PC + 1) {
+                    if let Some(v) = parse_le_hex(chunk) {
+                        write_reg(machine, r, v);
+                    }
+                }
+                "OK".into()
+            }
+            "p" => match usize::from_str_radix(args, 16) {
+                Ok(r) if r <= REG_F31 => hex_u64(read_reg(machine, r)),
+                _ => "E01".into(),
+            },
+            "P" => {
+                let parsed = args.split_once('=').and_then(|(r, v)| {
+                    Some((usize::from_str_radix(r, 16).ok()?, parse_le_hex(v.as_bytes())?))
+                });
+                match parsed {
+                    Some((r, v)) if r <= REG_F31 => {
+                        write_reg(machine, r, v);
+                        "OK".into()
+                    }
+                    _ => "E01".into(),
+                }
+            }
+            "m" => {
+                let Some((addr, len)) = parse_addr_len(args) else { return Ok("E01".into()) };
+                // A shorter read is allowed; the hex reply must fit a packet
+                let mut buf = vec![0u8; len.min(PACKET_SIZE as u64 / 2) as usize];
+                match machine.memory().read(addr, &mut buf) {
+                    Ok(()) => buf.iter().map(|b| format!("{b:02x}")).collect(),
+                    Err(_) => "E14".into(),
+                }
+            }
+            "M" => {
+                let Some((range, data)) = args.split_once(':') else { return Ok("E01".into()) };
+                let (Some((addr, _)), Some(bytes)) = (parse_addr_len(range), decode_hex(data.as_bytes())) else {
+                    return Ok("E01".into());
+                };
+                match machine.memory().write(addr, &bytes) {
+                    Ok(()) => {
+                        machine.invalidate(addr, bytes.len() as u64);
+                        "OK".into()
+                    }
+                    Err(_) => "E14".into(),
+                }
+            }
+            "c" | "s" => {
+                if let Ok(addr) = u64::from_str_radix(args, 16) {
+                    machine.pc = addr;
+                }
+                if self.exited.is_some() {
+                    return Ok(stop_reply(&Exit::Stepped, self.exited));
+                }
+                let exit = if cmd == "c" { machine.run()? } else { machine.step()? };
+                if let Exit::Exited(code) = exit {
+                    self.exited = Some(code);
+                }
+                stop_reply(&exit, self.exited)
+            }
+            "Z" | "z" => {
+                let mut parts = args.split(',');
+                let kind = parts.next();
+                let addr = parts.next().and_then(|a| u64::from_str_radix(a, 16).ok());
+                match (kind, addr) {
+                    (Some("0") | Some("1"), Some(addr)) => {
+                        let done = match cmd {
+                            "Z" => machine.insert_breakpoint(addr),
+                            _ => machine.remove_breakpoint(addr).map(|_| ()),
+                        };
+                        match done {
+                            Ok(()) => "OK".into(),
+                            Err(_) => "E01".into(),
+                        }
+                    }
+                    _ => String::new(), // watchpoints unsupported
+                }
+            }
+            "H" | "T" => "OK".into(),
+            "q" => query(args),
+            _ => String::new(),
+        })
+    }
+
+    /// Read one packet, acknowledging it and skipping any that fail their
+    /// checksum. None on EOF.
+    fn recv(&mut self) -> Result<Option<String>> {
+        let mut byte = [0u8; 1];
+        loop {
+            // Skip acks and anything before the start of a packet
+            loop {
+                if self.stream.read(&mut byte)? == 0 {
+                    return Ok(None);
+                }
+                if byte[0] == b'$' {
+                    break;
+                }
+            }
+            let mut body = Vec::new();
+            loop {
+                if self.stream.read(&mut byte)? == 0 {
+                    return Ok(None);
+                }
+                if byte[0] == b'#' {
+                    break;
+                }
+                body.push(byte[0]);
+            }
+            let mut checksum = [0u8; 2];
+            self.stream.read_exact(&mut checksum)?;
+            let ok = decode_hex(&checksum) == Some(vec![body.iter().fold(0u8, |a, &b| a.wrapping_add(b))]);
+            self.stream.write_all(if ok { b"+" } else { b"-" })?;
+            self.stream.flush()?;
+            if ok {
+                return Ok(Some(String::from_utf8_lossy(&body).into_owned()));
+            }
+        }
+    }
+
+    fn send(&mut self, data: &str) -> Result<()> {
+        let sum = data.bytes().fold(0u8, |a, b| a.wrapping_add(b));
+        write!(self.stream, "${data}#{sum:02x}")?;
+        self.stream.flush()?;
+        Ok(())
+    }
+}
+
+fn query(args: &str) -> String {
+    if args.starts_with("Supported") {
+        format!("PacketSize={PACKET_SIZE:x}")
+    } else if args == "Attached" {
+        "1".into()
+    } else if args == "C" {
+        "QC1".into()
+    } else if args == "fThreadInfo" {
+        "m1".into()
+    } else if args == "sThreadInfo" {
+        "l".into()
+    } else {
+        String::new()
+    }
+}
+
+fn stop_reply(exit: &Exit, exited: Option<i32>) -> String {
+    if let Some(code) = exited {
+        return format!("W{:02x}", code as u8);
+    }
+    if let Exit::Signaled(sig) = exit {
+        return format!("X{:02x}", *sig as u8);
+    }
+    let signal = match exit {
+        Exit::Halted | Exit::IllegalInstruction(_) => SIGILL,
+        Exit::MisalignedAccess(..) => SIGBUS,
+        Exit::Trap(_) => SIGSEGV,
+        Exit::Stopped(sig) => *sig as u8,
+        _ => SIGTRAP,
+    };
+    format!("S{signal:02x}")
+}
+
+fn read_reg(machine: &Machine, r: usize) -> u64 {
+    match r {
+        0..=31 => machine.reg(r),
+        REG_PC => machine.pc,
+        _ => machine.fpr(r - REG_F0),
+    }
+}
+
+fn write_reg(machine: &mut Machine, r: usize, v: u64) {
+    match r {
+        0..=31 => machine.set_reg(r, v),
+        REG_PC => machine.pc = v,
+        _ => machine.set_fpr(r - REG_F0, v),
+    }
+}
+
+/// Registers travel as little-endian byte sequences
+fn hex_u64(v: u64) -> String {
+    v.to_le_bytes().iter().map(|b| format!("{b:02x}")).collect()
+}
+
+fn parse_le_hex(hex: &[u8]) -> Option<u64> {
+    let bytes = decode_hex(hex)?;
+    let mut buf = [0u8; 8];
+    buf.get_mut(..bytes.len())?.copy_from_slice(&bytes);
+    Some(u64::from_le_bytes(buf))
+}
+
+/// Hex digit pairs to bytes, from the packet's raw bytes so that a
+/// non-ASCII byte is a bad digit rather than a split character
+fn decode_hex(hex: &[u8]) -> Option<Vec<u8>> {
+    if !hex.len().is_multiple_of(2) {
+        return None;
+    }
+    let digit = |b: u8| (b as char).to_digit(16).map(|d| d as u8);
+    hex.chunks(2).map(|pair| Some(digit(pair[0])? << 4 | digit(pair[1])?)).collect()
+}
+
+fn parse_addr_len(s: &str) -> Option<(u64, u64)> {
+    let (a, l) = s.split_once(',')?;
+    Some((u64::from_str_radix(a, 16).ok()?, u64::from_str_radix(l, 16).ok()?))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::Layout;
+    use std::io::{self, Cursor};
+
+    /// Input from a buffer, output to another
+    struct Duplex {
+        input: Cursor<Vec<u8>>,
+        output: Vec<u8>,
+    }
+
+    impl Read for Duplex {
+        fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+            self.input.read(buf)
+        }
+    }
+
+    impl Write for Duplex {
+        fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+            Write::write(&mut self.output, buf)
+        }
+
+        fn flush(&mut self) -> io::Result<()> {
+            Ok(())
+        }
+    }
+
+    fn packet(body: &[u8]) -> Vec<u8> {
+        let mut out = vec![b'$'];
+        out.extend_from_slice(body);
+        out.extend(format!("#{:02x}", body.iter().fold(0u8, |a, &b| a.wrapping_add(b))).bytes());
+        out
+    }
+
+    #[test]
+    fn test_register_hex_is_little_endian() {
+        assert_eq!(hex_u64(0x10078), "7800010000000000");
+        assert_eq!(parse_le_hex(b"7800010000000000"), Some(0x10078));
+        assert_eq!(stop_reply(&Exit::Exited(3), Some(3)), "W03");
+        assert_eq!(stop_reply(&Exit::Breakpoint(0), None), "S05");
+    }
+
+    #[test]
+    fn test_hostile_packets() {
+        // Bad checksums are refused one after another without recursing
+        let mut input = b"$g#00".repeat(100_000);
+        input.extend(packet(b"?"));
+        let mut stub = GdbStub::new(Duplex { input: Cursor::new(input), output: Vec::new() });
+        assert_eq!(stub.recv().unwrap().as_deref(), Some("?"));
+        assert_eq!(stub.stream.output.len(), 100_001);
+        assert_eq!(stub.stream.output.last(), Some(&b'+'));
+
+        // Non-ASCII hex is a bad digit, not a panic
+        assert_eq!(decode_hex("0é".as_bytes()), None);
+        assert_eq!(decode_hex(b"+f"), None);
+        assert_eq!(decode_hex(b"00fF"), Some(vec![0, 0xff]));
+
+        // A read is cut to what a reply packet holds, and a bad write is refused
+        let layout = Layout { brk_base: 0x10000, brk_max: 0x10000, mmap_base: 0x20000, limit: 0x40000 };
+        let mut machine = Machine::new(layout).unwrap();
+        let mut input = packet(b"m20000,ffffffffffff");
+        input.extend(packet("M20000,2:0é".as_bytes()));
+        let mut stub = GdbStub::new(Duplex { input: Cursor::new(input), output: Vec::new() });
+        stub.serve(&mut machine).unwrap();
+        let out = String::from_utf8_lossy(&stub.stream.output).into_owned();
+        assert!(out.starts_with(&format!("+${}#", "0".repeat(PACKET_SIZE))), "{}", &out[..32]);
+        assert!(out.ends_with("+$E01#a6"), "{}", &out[out.len() - 16..]);
+    }
+}
@@ -0,0 +1,843 @@
+// hostfs.rs - Host directory mounts
+//
+// Mounts a host directory at a guest path, so a guest can work on real
+// project files next to its rootfs. A *at syscall whose path lands under a
+// mount point comes here instead of going to `vfs.rs`, and so does any
+// call on a descriptor opened here. Paths are made absolute against the
+// cwd or the directory descriptor, and "." and ".." are resolved
+// lexically first, so a mount only ever sees paths inside itself. What
+// backs a mount is up to a `Directory`:
+//
+//   HostDirectory  std::fs under a host path: the native runtime, or a
+//                  WASI build, where std::fs reaches the preopened dirs
+//   (browser)      a page embedding the runtime implements `Directory`
+//                  over calls back into the page
+//
+// HostDirectory jails every path: the host resolves symlinks on the way,
+// and the result must still be under the mounted directory, or the call
+// fails with EACCES. Symlinks in the rootfs are not followed into a
+// mount. A mount can be read-only, which makes writes EROFS. Descriptors
+// are shared with the VFS and sockets, so the syscall layer picks the
+// number a new file gets.
+
+use crate::mm::{FileSource, GuestMemory};
+use crate::syscalls::{
+    Errno, EACCES, EBADF, EBUSY, EEXIST, EFBIG, EINVAL, EIO, EISDIR, ELOOP, ENAMETOOLONG, ENOENT, ENOSPC, ENOTDIR,
+    ENOTEMPTY, ENXIO, EROFS, EXDEV,
+};
+use crate::vfs::{
+    self, push_dirent, read_path, Stat, AT_EMPTY_PATH, AT_REMOVEDIR, AT_SYMLINK_NOFOLLOW, O_ACCMODE, O_APPEND,
+    O_CREAT, O_DIRECTORY, O_EXCL, O_NOFOLLOW, O_PATH, O_RDONLY, O_TRUNC, O_WRONLY, RENAME_NOREPLACE, UMASK, W_OK,
+    X_OK,
+};
+use std::collections::BTreeMap;
+use std::fs::{self, File, OpenOptions};
+use std::io::{self, Read, Seek, SeekFrom, Write};
+use std::path::{Path, PathBuf};
+
+/// Largest transfer made in one call; the guest sees a short read or write
+const MAX_IO: usize = 1 << 20;
+
+/// st_dev of files in a mount; the rootfs is device 1
+const MOUNT_DEV: u64 = 2;
+
+/// A directory tree to mount. Paths are relative to its root,
+/// '/'-separated, without "." or ".." components; "" is the root itself.
+pub trait Directory: Send {
+    /// Open a file; `flags` are openat's, `mode` is for a created file
+    fn open(&mut self, path: &str, flags: u64, mode: u32) -> Result<Box<dyn Handle>, Errno>;
+    /// stat, or lstat when `follow` is unset
+    fn metadata(&mut self, path: &str, follow: bool) -> Result<Stat, Errno>;
+    /// A directory's entries, without "." and ".."
+    fn read_dir(&mut self, path: &str) -> Result<Vec<(String, Stat)>, Errno>;
+    fn read_link(&mut self, path: &str) -> Result<String, Errno>;
+    fn create_dir(&mut self, path: &str, mode: u32) -> Result<(), Errno>;
+    fn symlink(&mut self, target: &str, path: &str) -> Result<(), Errno>;
+    /// unlink, or rmdir when `dir` is set
+    fn remove(&mut self, path: &str, dir: bool) -> Result<(), Errno>;
+    /// rename, replacing what `to` names
+    fn rename(&mut self, from: &str, to: &str) -> Result<(), Errno>;
+    fn set_mode(&mut self, path: &str, mode: u32) -> Result<(), Errno>;
+}
+
+/// A file opened in a `Directory`
+pub trait Handle: Send {
+    fn read_at(&self, offset: u64, buf: &mut [u8]) -> Result<usize, Errno>;
+    fn write_at(&mut self, offset: u64, buf: &[u8]) -> Result<usize, Errno>;
+    fn metadata(&self) -> Result<Stat, Errno>;
+    fn set_len(&mut self, len: u64) -> Result<(), Errno>;
+    fn set_mode(&mut self, mode: u32) -> Result<(), Errno>;
+}
+
+/// `Directory` on a host directory through std::fs
+pub struct HostDirectory {
+    /// Canonical, so jailed paths can be checked by prefix
+    root: PathBuf,
+}
+
+impl HostDirectory {
+    pub fn new(root: impl AsRef<Path>) -> io::Result<Self> {
+        let root = root.as_ref().canonicalize()?;
+        if !root.is_dir() {
+            return Err(io::Error::new(io::ErrorKind::NotADirectory, format!("{} is not a directory", root.display())));
+        }
+        Ok(Self { root })
+    }
+
+    /// The host path for `path`. Symlinks are resolved in its directory,
+    /// and in its last component when `follow` is set; wherever they lead
+    /// must stay inside the root.
+    fn host_path(&self, path: &str, follow: bool) -> Result<PathBuf, Errno> {
+        let (dir, name) = path.rsplit_once('/').unwrap_or(("", path));
+        let dir = self.root.join(dir).canonicalize().map_err(errno)?;
+        if !dir.starts_with(&self.root) {
+            return Err(EACCES);
+        }
+        if name.is_empty() {
+            return Ok(dir);
+        }
+        let full = dir.join(name);
+        if !follow {
+            return Ok(full);
+        }
+        match full.canonicalize() {
+            Ok(real) if real.starts_with(&self.root) => Ok(real),
+            Ok(_) => Err(EACCES),
+            // Not there yet, so it can be created; a dangling symlink
+            // could point anywhere, so it is not followed
+            Err(e) if e.kind() == io::ErrorKind::NotFound => match full.symlink_metadata() {
+                Ok(_) => Err(EACCES),
+                Err(_) => Ok(full),
+            },
+            Err(e) => Err(errno(e)),
+        }
+    }
+}
+
+impl Directory for HostDirectory {
+    fn open(&mut self, path: &str, flags: u64, mode: u32) -> Result<Box<dyn Handle>, Errno> {
+        let host = self.host_path(path, flags & O_NOFOLLOW == 0)?;
+        let mut options = OpenOptions::new();
+        options
+            .read(flags & O_ACCMODE != O_WRONLY)
+            // std only creates through a writable file; read-only guest
+            // descriptors are refused writes before they get here
+            .write(flags & O_ACCMODE != O_RDONLY || flags & O_CREAT != 0)
+            .truncate(flags & O_TRUNC != 0 && flags & O_ACCMODE != O_RDONLY)
+            .create(flags & O_CREAT != 0)
+            .create_new(flags & (O_CREAT | O_EXCL) == O_CREAT | O_EXCL);
+        #[cfg(unix)]
+        std::os::unix::fs::OpenOptionsExt::mode(&mut options, mode);
+        #[cfg(not(unix))]
+        let _ = mode;
+        Ok(Box::new(options.open(host).map_err(errno)?))
+    }
+
+    fn metadata(&mut self, path: &str, follow: bool) -> Result<Stat, Errno> {
+        let host = self.host_path(path, follow)?;
+        Ok(stat(&fs::symlink_metadata(host).map_err(errno)?))
+    }
+
+    fn read_dir(&mut self, path: &str) -> Result<Vec<(String, Stat)>, Errno> {
+        let mut entries = Vec::new();
+        for entry in fs::read_dir(self.host_path(path, true)?).map_err(errno)? {
+            let entry = entry.map_err(errno)?;
+            // Names the guest cannot spell are left out
+            if let Ok(name) = entry.file_name().into_string() {
+                entries.push((name, stat(&entry.metadata().map_err(errno)?)));
+            }
+        }
+        entries.sort_by(|a, b| a.0.cmp(&b.0));
+        Ok(entries)
+    }
+
+    fn read_link(&mut self, path: &str) -> Result<String, Errno> {
+        let target = fs::read_link(self.host_path(path, false)?).map_err(errno)?;
+        target.into_os_string().into_string().map_err(|_| EINVAL)
+    }
+
+    fn create_dir(&mut self, path: &str, mode: u32) -> Result<(), Errno> {
+        let mut builder = fs::DirBuilder::new();
+        #[cfg(unix)]
+        std::os::unix::fs::DirBuilderExt::mode(&mut builder, mode);
+        #[cfg(not(unix))]
+        let _ = mode;
+        builder.create(self.host_path(path, false)?).map_err(errno)
+    }
+
+    fn symlink(&mut self, target: &str, path: &str) -> Result<(), Errno> {
+        symlink(target, &self.host_path(path, false)?)
+    }
+
+    fn remove(&mut self, path: &str, dir: bool) -> Result<(), Errno> {
+        let host = self.host_path(path, false)?;
+        if dir {
+            fs::remove_dir(host).map_err(errno)
+        } else {
+            fs::remove_file(host).map_err(errno)
+        }
+    }
+
+    fn rename(&mut self, from: &str, to: &str) -> Result<(), Errno> {
+        fs::rename(self.host_path(from, false)?, self.host_path(to, false)?).map_err(errno)
+    }
+
+    fn set_mode(&mut self, path: &str, mode: u32) -> Result<(), Errno> {
+        let host = self.host_path(path, true)?;
+        let mut permissions = fs::metadata(&host).map_err(errno)?.permissions();
+        set_permissions(&mut permissions, mode);
+        fs::set_permissions(host, permissions).map_err(errno)
+    }
+}
+
+impl Handle for File {
+    fn read_at(&self, offset: u64, buf: &mut [u8]) -> Result<usize, Errno> {
+        let mut file = self;
+        file.seek(SeekFrom::Start(offset)).map_err(errno)?;
+        file.read(buf).map_err(errno)
+    }
+
+    fn write_at(&mut self, offset: u64, buf: &[u8]) -> Result<usize, Errno> {
+        self.seek(SeekFrom::Start(offset)).map_err(errno)?;
+        self.write(buf).map_err(errno)
+    }
+
+    fn metadata(&self) -> Result<Stat, Errno> {
+        Ok(stat(&File::metadata(self).map_err(errno)?))
+    }
+
+    fn set_len(&mut self, len: u64) -> Result<(), Errno> {
+        File::set_len(self, len).map_err(errno)
+    }
+
+    fn set_mode(&mut self, mode: u32) -> Result<(), Errno> {
+        let mut permissions = File::metadata(self).map_err(errno)?.permissions();
+        set_permissions(&mut permissions, mode);
+        self.set_permissions(permissions).map_err(errno)
+    }
+}
+
+#[cfg(unix)]
+fn stat(meta: &fs::Metadata) -> Stat {
+    use std::os::unix::fs::MetadataExt;
+    Stat {
+        dev: MOUNT_DEV,
+        ino: meta.ino(),
+        mode: meta.mode(),
+        nlink: meta.nlink() as u32,
+        uid: meta.uid(),
+        gid: meta.gid(),
+        rdev: meta.rdev(),
+        size: meta.size(),
+        mtime: meta.mtime().max(0) as u64,
+    }
+}
+
+/// Without Unix modes, only whether the owner may write
+#[cfg(not(unix))]
+fn stat(meta: &fs::Metadata) -> Stat {
+    let write = if meta.permissions().readonly() { 0 } else { 0o200 };
+    let mode = match meta.file_type() {
+        t if t.is_dir() => Stat::S_IFDIR | 0o555 | write,
+        t if t.is_symlink() => Stat::S_IFLNK | 0o777,
+        _ => Stat::S_IFREG | 0o444 | write,
+    };
+    let mtime = meta.modified().ok().and_then(|t| t.duration_since(std::time::UNIX_EPOCH).ok());
+    Stat {
+        dev: MOUNT_DEV,
+        ino: 0,
+        mode,
+        nlink: 1,
+        uid: 0,
+        gid: 0,
+        rdev: 0,
+        size: meta.len(),
+        mtime: mtime.map_or(0, |d| d.as_secs()),
+    }
+}
+
+#[cfg(unix)]
+fn symlink(target: &str, host: &Path) -> Result<(), Errno> {
+    std::os::unix::fs::symlink(target, host).map_err(errno)
+}
+
+#[cfg(not(unix))]
+fn symlink(_target: &str, _host: &Path) -> Result<(), Errno> {
+    Err(crate::syscalls::EPERM)
+}
+
+fn set_permissions(permissions: &mut fs::Permissions, mode: u32) {
+    #[cfg(unix)]
+    std::os::unix::fs::PermissionsExt::set_mode(permissions, mode & 0o7777);
+    #[cfg(not(unix))]
+    permissions.set_readonly(mode & 0o200 == 0);
+}
+
+fn errno(e: io::Error) -> Errno {
+    match e.kind() {
+        io::ErrorKind::NotFound => ENOENT,
+        io::ErrorKind::PermissionDenied => EACCES,
+        io::ErrorKind::AlreadyExists => EEXIST,
+        io::ErrorKind::NotADirectory => ENOTDIR,
+        io::ErrorKind::IsADirectory => EISDIR,
+        io::ErrorKind::DirectoryNotEmpty => ENOTEMPTY,
+        io::ErrorKind::ReadOnlyFilesystem => EROFS,
+        io::ErrorKind::CrossesDevices => EXDEV,
+        io::ErrorKind::StorageFull => ENOSPC,
+        io::ErrorKind::FileTooLarge => EFBIG,
+        io::ErrorKind::InvalidFilename => ENAMETOOLONG,
+        io::ErrorKind::InvalidInput => EINVAL,
+        _ => EIO,
+    }
+}
+
+/// A directory mounted at a guest path
+struct Mount {
+    /// The mount point, without the leading slash
+    path: String,
+    dir: Box<dyn Directory>,
+    read_only: bool,
+}
+
+/// An open file description in a mount
+struct OpenFile {
+    mount: usize,
+    path: String,
+    /// None for a directory or an O_PATH descriptor
+    handle: Option<Box<dyn Handle>>,
+    flags: u64,
+    /// Byte offset, or for a directory the index of the next entry
+    offset: u64,
+    /// A directory's entries, read when getdents64 starts from the top
+    entries: Vec<(String, Stat)>,
+}
+
+impl OpenFile {
+    fn readable(&self) -> bool {
+        self.flags & O_PATH == 0 && self.flags & O_ACCMODE != O_WRONLY
+    }
+
+    fn writable(&self) -> bool {
+        self.flags & O_PATH == 0 && self.flags & O_ACCMODE != O_RDONLY
+    }
+}
+
+/// The process's mounts and the files open in them
+#[derive(Default)]
+pub struct Mounts {
+    mounts: Vec<Mount>,
+    files: BTreeMap<i32, OpenFile>,
+}
+
+impl Mounts {
+    /// Mount `dir` at the absolute guest path `path`, over whatever the
+    /// rootfs has there
+    pub fn mount(&mut self, path: &str, dir: Box<dyn Directory>, read_only: bool) {
+        let path = normalize("/", path).unwrap_or_default();
+        self.mounts.retain(|m| m.path != path);
+        self.mounts.push(Mount { path, dir, read_only });
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.mounts.is_empty()
+    }
+
+    /// Whether `fd` is a file open in a mount
+    pub fn owns(&self, fd: u64) -> bool {
+        self.files.contains_key(&(fd as i32))
+    }
+
+    /// The absolute guest path of the directory open as `fd`
+    pub fn fd_path(&self, fd: u64) -> Option<String> {
+        let file = self.files.get(&(fd as i32))?;
+        file.handle.is_none().then(|| format!("/{}", join(&self.mounts[file.mount].path, &file.path)))
+    }
+
+    /// The mount `path` lands in and the path inside it; `base` is the
+    /// absolute directory a relative path starts from ("" if unknown)
+    pub fn find(&self, base: &str, path: &str) -> Option<(usize, String)> {
+        let full = normalize(base, path)?;
+        let (i, mount) = self
+            .mounts
+            .iter()
+            .enumerate()
+            .filter(|(_, m)| m.path.is_empty() || full == m.path || full.starts_with(&format!("{}/", m.path)))
+            .max_by_key(|(_, m)| m.path.len())?;
+        Some((i, full[mount.path.len()..].trim_start_matches('/').to_string()))
+    }
+
+    /// Resolve a guest path for a *at syscall
+    fn resolve(&self, mem: &dyn GuestMemory, base: &str, path: u64) -> Result<(usize, String), Errno> {
+        let path = read_path(mem, path)?;
+        if path.is_empty() {
+            return Err(ENOENT);
+        }
+        self.find(base, &path).ok_or(ENOENT)
+    }
+
+    /// Resolve a path that names an entry to create, remove or rename
+    fn resolve_entry(&self, mem: &dyn GuestMemory, base: &str, path: u64) -> Result<(usize, String), Errno> {
+        let (mount, rel) = self.resolve(mem, base, path)?;
+        if rel.is_empty() {
+            return Err(EBUSY);
+        }
+        Ok((mount, rel))
+    }
+
+    fn writable_mount(&self, mount: usize) -> Result<(), Errno> {
+        if self.mounts[mount].read_only {
+            return Err(EROFS);
+        }
+        Ok(())
+    }
+
+    fn file(&self, fd: u64) -> Result<&OpenFile, Errno> {
+        self.files.get(&(fd as i32)).ok_or(EBADF)
+    }
+
+    fn file_mut(&mut self, fd: u64) -> Result<&mut OpenFile, Errno> {
+        self.files.get_mut(&(fd as i32)).ok_or(EBADF)
+    }
+
+    /// openat, as descriptor `new_fd`
+    pub fn openat(
+        &mut self,
+        mem: &dyn GuestMemory,
+        base: &str,
+        path: u64,
+        flags: u64,
+        mode: u64,
+        new_fd: i32,
+    ) -> Result<u64, Errno> {
+        let (mount, rel) = self.resolve(mem, base, path)?;
+        let writable = flags & O_PATH == 0 && flags & O_ACCMODE != O_RDONLY;
+        let dir = &mut self.mounts[mount].dir;
+        let handle = match dir.metadata(&rel, flags & O_NOFOLLOW == 0) {
+            Err(e) if e == ENOENT && flags & O_CREAT != 0 => {
+                self.writable_mount(mount)?;
+                let dir = &mut self.mounts[mount].dir;
+                Some(dir.open(&rel, flags, (mode & !UMASK) as u32)?)
+            }
+            Err(e) => return Err(e),
+            Ok(_) if flags & (O_CREAT | O_EXCL) == O_CREAT | O_EXCL => return Err(EEXIST),
+            Ok(_) if flags & O_PATH != 0 => None,
+            Ok(st) if st.mode & Stat::S_IFMT == Stat::S_IFLNK => return Err(ELOOP),
+            Ok(st) if st.is_dir() && writable => return Err(EISDIR),
+            Ok(st) if st.is_dir() => None,
+            Ok(_) if flags & O_DIRECTORY != 0 => return Err(ENOTDIR),
+            // Host devices, fifos and sockets are not passed through
+            Ok(st) if st.mode & Stat::S_IFMT != Stat::S_IFREG => return Err(ENXIO),
+            Ok(_) => {
+                if writable || flags & O_TRUNC != 0 {
+                    self.writable_mount(mount)?;
+                }
+                Some(self.mounts[mount].dir.open(&rel, flags & !(O_CREAT | O_EXCL), 0)?)
+            }
+        };
+        self.files.insert(new_fd, OpenFile { mount, path: rel, handle, flags, offset: 0, entries: Vec::new() });
+        Ok(new_fd as u64)
+    }
+
+    pub fn close(&mut self, fd: u64) -> Result<u64, Errno> {
+        self.files.remove(&(fd as i32)).map(|_| 0).ok_or(EBADF)
+    }
+
+    /// Copy the file's bytes at `offset` to guest memory
+    fn read_at(&self, mem: &mut dyn GuestMemory, fd: u64, offset: u64, buf: u64, count: u64) -> Result<u64, Errno> {
+        let file = self.file(fd)?;
+        if !file.readable() {
+            return Err(EBADF);
+        }
+        let handle = file.handle.as_ref().ok_or(EISDIR)?;
+        let mut data = vec![0u8; (count as usize).min(MAX_IO)];
+        let n = handle.read_at(offset, &mut data)?;
+        mem.write(buf, &data[..n])?;
+        Ok(n as u64)
+    }
+
+    pub fn read(&mut self, mem: &mut dyn GuestMemory, fd: u64, buf: u64, count: u64) -> Result<u64, Errno> {
+        let n = self.read_at(mem, fd, self.file(fd)?.offset, buf, count)?;
+        self.file_mut(fd)?.offset += n;
+        Ok(n)
+    }
+
+    pub fn pread(&self, mem: &mut dyn GuestMemory, fd: u64, buf: u64, count: u64, offset: u64) -> Result<u64, Errno> {
+        self.read_at(mem, fd, offset, buf, count)
+    }
+
+    /// Copy guest memory into the file at `offset`, or at its end for
+    /// O_APPEND; returns the bytes written and where they ended
+    fn write_at(&mut self, mem: &dyn GuestMemory, fd: u64, offset: u64, buf: u64, count: u64) -> Result<(u64, u64), Errno> {
+        let file = self.file_mut(fd)?;
+        if !file.writable() {
+            return Err(EBADF);
+        }
+        let handle = file.handle.as_mut().ok_or(EISDIR)?;
+        let offset = if file.flags & O_APPEND != 0 { handle.metadata()?.size } else { offset };
+        let mut data = vec![0u8; (count as usize).min(MAX_IO)];
+        mem.read(buf, &mut data)?;
+        let n = handle.write_at(offset, &data)? as u64;
+        Ok((n, offset + n))
+    }
+
+    pub fn write(&mut self, mem: &dyn GuestMemory, fd: u64, buf: u64, count: u64) -> Result<u64, Errno> {
+        let (n, end) = self.write_at(mem, fd, self.file(fd)?.offset, buf, count)?;
+        self.file_mut(fd)?.offset = end;
+        Ok(n)
+    }
+
+    pub fn pwrite(&mut self, mem: &dyn GuestMemory, fd: u64, buf: u64, count: u64, offset: u64) -> Result<u64, Errno> {
+        Ok(self.write_at(mem, fd, offset, buf, count)?.0)
+    }
+
+    pub fn ftruncate(&mut self, fd: u64, length: u64) -> Result<u64, Errno> {
+        let file = self.file_mut(fd)?;
+        match &mut file.handle {
+            Some(handle) if file.flags & O_PATH == 0 && file.flags & O_ACCMODE != O_RDONLY => handle.set_len(length)?,
+            _ => return Err(EINVAL),
+        }
+        Ok(0)
+    }
+
+    pub fn lseek(&mut self, fd: u64, offset: u64, whence: u64) -> Result<u64, Errno> {
+        let file = self.file(fd)?;
+        let base = match whence {
+            vfs::SEEK_SET => 0,
+            vfs::SEEK_CUR => file.offset,
+            vfs::SEEK_END => match &file.handle {
+                Some(handle) => handle.metadata()?.size,
+                None => file.entries.len() as u64 + 2,
+            },
+            _ => return Err(EINVAL),
+        };
+        let offset = base.checked_add_signed(offset as i64).ok_or(EINVAL)?;
+        self.file_mut(fd)?.offset = offset;
+        Ok(offset)
+    }
+
+    /// Directory entries as struct linux_dirent64 records, "." and ".."
+    /// first; the offset counts entries
+    pub fn getdents64(&mut self, mem: &mut dyn GuestMemory, fd: u64, buf: u64, count: u64) -> Result<u64, Errno> {
+        let file = self.files.get_mut(&(fd as i32)).ok_or(EBADF)?;
+        if file.handle.is_some() || file.flags & O_PATH != 0 {
+            return Err(ENOTDIR);
+        }
+        if file.offset == 0 {
+            file.entries = self.mounts[file.mount].dir.read_dir(&file.path)?;
+        }
+        let dots = [(".", 4), ("..", 4)].into_iter();
+        let entries = dots.chain(file.entries.iter().map(|(name, st)| (name.as_str(), st.d_type())));
+        let mut out = Vec::new();
+        let mut next = file.offset;
+        for (i, (name, d_type)) in entries.enumerate().skip(file.offset as usize) {
+            if !push_dirent(&mut out, count, i as u64 + 1, next + 1, d_type, name) {
+                break;
+            }
+            next += 1;
+        }
+        if out.is_empty() && next < 2 + file.entries.len() as u64 {
+            return Err(EINVAL);
+        }
+        mem.write(buf, &out)?;
+        file.offset = next;
+        Ok(out.len() as u64)
+    }
+
+    fn stat_file(&mut self, fd: u64) -> Result<Stat, Errno> {
+        let file = self.files.get(&(fd as i32)).ok_or(EBADF)?;
+        match &file.handle {
+            Some(handle) => handle.metadata(),
+            None => self.mounts[file.mount].dir.metadata(&file.path, file.flags & O_NOFOLLOW == 0),
+        }
+    }
+
+    pub fn fstat(&mut self, mem: &mut dyn GuestMemory, fd: u64, statbuf: u64) -> Result<u64, Errno> {
+        let st = Stat { dev: MOUNT_DEV, ..self.stat_file(fd)? };
+        mem.write(statbuf, &st.to_bytes())?;
+        Ok(0)
+    }
+
+    pub fn newfstatat(
+        &mut self,
+        mem: &mut dyn GuestMemory,
+        base: &str,
+        path: u64,
+        statbuf: u64,
+        flags: u64,
+    ) -> Result<u64, Errno> {
+        let (mount, rel) = if flags & AT_EMPTY_PATH != 0 && read_path(mem, path)?.is_empty() {
+            self.find(base, ".").ok_or(ENOENT)?
+        } else {
+            self.resolve(mem, base, path)?
+        };
+        let st = self.mounts[mount].dir.metadata(&rel, flags & AT_SYMLINK_NOFOLLOW == 0)?;
+        mem.write(statbuf, &Stat { dev: MOUNT_DEV, ..st }.to_bytes())?;
+        Ok(0)
+    }
+
+    pub fn readlinkat(&mut self, mem: &mut dyn GuestMemory, base: &str, path: u64, buf: u64, size: u64) -> Result<u64, Errno> {
+        let (mount, rel) = self.resolve(mem, base, path)?;
+        let dir = &mut self.mounts[mount].dir;
+        if dir.metadata(&rel, false)?.mode & Stat::S_IFMT != Stat::S_IFLNK {
+            return Err(EINVAL);
+        }
+        let target = dir.read_link(&rel)?;
+        let target = &target.as_bytes()[..target.len().min(size as usize)];
+        mem.write(buf, target)?;
+        Ok(target.len() as u64)
+    }
+
+    /// faccessat as root, as in the VFS; writing to a read-only mount is
+    /// EROFS
+    pub fn faccessat(&mut self, mem: &dyn GuestMemory, base: &str, path: u64, mode: u64) -> Result<u64, Errno> {
+        let (mount, rel) = self.resolve(mem, base, path)?;
+        let st = self.mounts[mount].dir.metadata(&rel, true)?;
+        if mode & X_OK != 0 && st.mode & 0o111 == 0 {
+            return Err(EACCES);
+        }
+        if mode & W_OK != 0 {
+            self.writable_mount(mount)?;
+        }
+        Ok(0)
+    }
+
+    pub fn mkdirat(&mut self, mem: &dyn GuestMemory, base: &str, path: u64, mode: u64) -> Result<u64, Errno> {
+        let (mount, rel) = self.resolve(mem, base, path)?;
+        if rel.is_empty() {
+            return Err(EEXIST);
+        }
+        self.writable_mount(mount)?;
+        self.mounts[mount].dir.create_dir(&rel, (mode & !UMASK) as u32)?;
+        Ok(0)
+    }
+
+    pub fn symlinkat(&mut self, mem: &dyn GuestMemory, target: u64, base: &str, path: u64) -> Result<u64, Errno> {
+        let target = read_path(mem, target)?;
+        if target.is_empty() {
+            return Err(ENOENT);
+        }
+        let (mount, rel) = self.resolve(mem, base, path)?;
+        if rel.is_empty() {
+            return Err(EEXIST);
+        }
+        self.writable_mount(mount)?;
+        self.mounts[mount].dir.symlink(&target, &rel)?;
+        Ok(0)
+    }
+
+    pub fn unlinkat(&mut self, mem: &dyn GuestMemory, base: &str, path: u64, flags: u64) -> Result<u64, Errno> {
+        let (mount, rel) = self.resolve_entry(mem, base, path)?;
+        self.writable_mount(mount)?;
+        self.mounts[mount].dir.remove(&rel, flags & AT_REMOVEDIR != 0)?;
+        Ok(0)
+    }
+
+    /// renameat2 within one mount; anything else is EXDEV, as between
+    /// filesystems
+    pub fn renameat2(
+        &mut self,
+        mem: &dyn GuestMemory,
+        old_base: &str,
+        old_path: u64,
+        new_base: &str,
+        new_path: u64,
+        flags: u64,
+    ) -> Result<u64, Errno> {
+        if flags & !RENAME_NOREPLACE != 0 {
+            return Err(EINVAL);
+        }
+        // A path that is in no mount is in the rootfs
+        let elsewhere = |e| if e == ENOENT { EXDEV } else { e };
+        let (mount, from) = self.resolve_entry(mem, old_base, old_path).map_err(elsewhere)?;
+        let (new_mount, to) = self.resolve_entry(mem, new_base, new_path).map_err(elsewhere)?;
+        if mount != new_mount {
+            return Err(EXDEV);
+        }
+        self.writable_mount(mount)?;
+        let dir = &mut self.mounts[mount].dir;
+        if flags & RENAME_NOREPLACE != 0 && dir.metadata(&to, false).is_ok() {
+            return Err(EEXIST);
+        }
+        dir.rename(&from, &to)?;
+        Ok(0)
+    }
+
+    pub fn fchmod(&mut self, fd: u64, mode: u64) -> Result<u64, Errno> {
+        let file = self.files.get_mut(&(fd as i32)).ok_or(EBADF)?;
+        if self.mounts[file.mount].read_only {
+            return Err(EROFS);
+        }
+        match &mut file.handle {
+            Some(handle) => handle.set_mode(mode as u32)?,
+            None => self.mounts[file.mount].dir.set_mode(&file.path, mode as u32)?,
+        }
+        Ok(0)
+    }
+
+    pub fn fchmodat(&mut self, mem: &dyn GuestMemory, base: &str, path: u64, mode: u64) -> Result<u64, Errno> {
+        let (mount, rel) = self.resolve(mem, base, path)?;
+        self.writable_mount(mount)?;
+        self.mounts[mount].dir.set_mode(&rel, mode as u32)?;
+        Ok(0)
+    }
+}
+
+impl FileSource for Mounts {
+    fn read_at(&self, fd: i32, offset: u64, buf: &mut [u8]) -> Result<usize, Errno> {
+        let file = self.files.get(&fd).ok_or(EBADF)?;
+        file.handle.as_ref().ok_or(EISDIR)?.read_at(offset, buf)
+    }
+}
+
+/// `path` made absolute against `base`, with "." and ".." resolved and
+/// without the leading slash; None for a relative path with no base
+fn normalize(base: &str, path: &str) -> Option<String> {
+    let full = match path.starts_with('/') {
+        true => path.to_string(),
+        false if base.starts_with('/') => format!("{}/{}", base, path),
+        false => return None,
+    };
+    let mut components = Vec::new();
+    for component in full.split('/') {
+        match component {
+            "" | "." => {}
+            ".." => {
+                components.pop();
+            }
+            _ => components.push(component),
+        }
+    }
+    Some(components.join("/"))
+}
+
+/// `name` inside the directory at `dir` ("" for the root)
+fn join(dir: &str, name: &str) -> String {
+    match (dir.is_empty(), name.is_empty()) {
+        (true, _) => name.to_string(),
+        (_, true) => dir.to_string(),
+        _ => format!("{}/{}", dir, name),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::syscalls::{nr, Kernel};
+    use crate::vfs::AT_FDCWD;
+    use crate::Layout;
+
+    /// Put a NUL-terminated path at `addr`
+    fn path_at(mem: &mut [u8], addr: usize, s: &str) {
+        mem[addr..addr + s.len() + 1].copy_from_slice(format!("{}\0", s).as_bytes());
+    }
+
+    /// The names a getdents64 result lists
+    fn dirent_names(mem: &[u8], mut pos: usize, end: usize) -> Vec<String> {
+        let mut names = Vec::new();
+        while pos < end {
+            let reclen = u16::from_le_bytes([mem[pos + 16], mem[pos + 17]]) as usize;
+            let name = &mem[pos + 19..pos + reclen];
+            names.push(String::from_utf8(name[..name.iter().position(|&b| b == 0).unwrap()].to_vec()).unwrap());
+            pos += reclen;
+        }
+        names
+    }
+
+    #[test]
+    fn test_host_mount() {
+        let tmp = std::env::temp_dir().join(format!("friscy-hostfs-test-{}", std::process::id()));
+        let _ = fs::remove_dir_all(&tmp);
+        let root = tmp.join("project");
+        fs::create_dir_all(root.join("src")).unwrap();
+        fs::write(root.join("src/main.c"), "int main;\n").unwrap();
+        fs::write(tmp.join("secret"), "outside").unwrap();
+        #[cfg(unix)]
+        std::os::unix::fs::symlink("../secret", root.join("escape")).unwrap();
+
+        let mut kernel = Kernel::new(Layout { brk_base: 0x10000, brk_max: 0x10000, mmap_base: 0x20000, limit: 0x100000 });
+        kernel.mount("/work", Box::new(HostDirectory::new(&root).unwrap()), false).unwrap();
+        kernel.mount("/ro", Box::new(HostDirectory::new(&root).unwrap()), true).unwrap();
+        kernel.process.cwd = "/work".into();
+        let mut mem = vec![0u8; 0x4000];
+        let path = |mem: &mut Vec<u8>, s: &str| path_at(mem, 0x100, s);
+        let cwd = AT_FDCWD as u64;
+
+        // Host files read through relative and absolute paths
+        path(&mut mem, "src/main.c");
+        assert_eq!(kernel.dispatch(&mut mem, nr::OPENAT, [cwd, 0x100, 0, 0, 0, 0]), 3);
+        assert_eq!(kernel.dispatch(&mut mem, nr::READ, [3, 0x200, 100, 0, 0, 0]), 10);
+        assert_eq!(&mem[0x200..0x20a], b"int main;\n");
+        assert_eq!(kernel.dispatch(&mut mem, nr::CLOSE, [3, 0, 0, 0, 0, 0]), 0);
+        path(&mut mem, "/work/src/../src/main.c");
+        assert_eq!(kernel.dispatch(&mut mem, nr::NEWFSTATAT, [cwd, 0x100, 0x400, 0, 0, 0]), 0);
+        assert_eq!(u64::from_le_bytes(mem[0x400..0x408].try_into().unwrap()), MOUNT_DEV);
+        assert_eq!(u64::from_le_bytes(mem[0x430..0x438].try_into().unwrap()), 10);
+
+        // Writes land on the host
+        path(&mut mem, "out.txt");
+        let fd = kernel.dispatch(&mut mem, nr::OPENAT, [cwd, 0x100, O_CREAT | O_WRONLY, 0o666, 0, 0]);
+        assert_eq!(fd, 3);
+        mem[0x200..0x203].copy_from_slice(b"hi\n");
+        assert_eq!(kernel.dispatch(&mut mem, nr::WRITE, [3, 0x200, 3, 0, 0, 0]), 3);
+        assert_eq!(kernel.dispatch(&mut mem, nr::READ, [3, 0x200, 3, 0, 0, 0]), EBADF.as_ret());
+        assert_eq!(kernel.dispatch(&mut mem, nr::CLOSE, [3, 0, 0, 0, 0, 0]), 0);
+        assert_eq!(fs::read(root.join("out.txt")).unwrap(), b"hi\n");
+
+        // The mount lists the host directory; relative paths work from it
+        path(&mut mem, "/work");
+        assert_eq!(kernel.dispatch(&mut mem, nr::OPENAT, [cwd, 0x100, O_DIRECTORY, 0, 0, 0]), 3);
+        let n = kernel.dispatch(&mut mem, nr::GETDENTS64, [3, 0x1000, 0x400, 0, 0, 0]);
+        let mut names = dirent_names(&mem, 0x1000, 0x1000 + n as usize);
+        names.retain(|name| name != "escape");
+        assert_eq!(names, [".", "..", "out.txt", "src"]);
+        path(&mut mem, "src");
+        assert_eq!(kernel.dispatch(&mut mem, nr::MKDIRAT, [3, 0x100, 0o755, 0, 0, 0]), EEXIST.as_ret());
+        path(&mut mem, "build");
+        assert_eq!(kernel.dispatch(&mut mem, nr::MKDIRAT, [3, 0x100, 0o755, 0, 0, 0]), 0);
+        assert!(root.join("build").is_dir());
+
+        // Renames stay inside one mount
+        path(&mut mem, "out.txt");
+        path_at(&mut mem, 0x180, "build/out.txt");
+        assert_eq!(kernel.dispatch(&mut mem, nr::RENAMEAT2, [cwd, 0x100, cwd, 0x180, 0, 0]), 0);
+        assert_eq!(fs::read(root.join("build/out.txt")).unwrap(), b"hi\n");
+        path_at(&mut mem, 0x180, "/tmp-out.txt");
+        path(&mut mem, "build/out.txt");
+        assert_eq!(kernel.dispatch(&mut mem, nr::RENAMEAT2, [cwd, 0x100, cwd, 0x180, 0, 0]), EXDEV.as_ret());
+        assert_eq!(kernel.dispatch(&mut mem, nr::UNLINKAT, [cwd, 0x100, 0, 0, 0, 0]), 0);
+        path(&mut mem, "build");
+        assert_eq!(kernel.dispatch(&mut mem, nr::UNLINKAT, [cwd, 0x100, 0, 0, 0, 0]), EISDIR.as_ret());
+        assert_eq!(kernel.dispatch(&mut mem, nr::UNLINKAT, [cwd, 0x100, AT_REMOVEDIR, 0, 0, 0]), 0);
+        path(&mut mem, "/work");
+        assert_eq!(kernel.dispatch(&mut mem, nr::UNLINKAT, [cwd, 0x100, AT_REMOVEDIR, 0, 0, 0]), EBUSY.as_ret());
+
+        // Nothing reaches out of the mount: ".." leaves it for the rootfs,
+        // and a host symlink out of it is refused
+        path(&mut mem, "../../secret");
+        assert_eq!(kernel.dispatch(&mut mem, nr::OPENAT, [cwd, 0x100, 0, 0, 0, 0]), ENOENT.as_ret());
+        #[cfg(unix)]
+        {
+            path(&mut mem, "escape");
+            assert_eq!(kernel.dispatch(&mut mem, nr::OPENAT, [cwd, 0x100, 0, 0, 0, 0]), EACCES.as_ret());
+            assert_eq!(kernel.dispatch(&mut mem, nr::READLINKAT, [cwd, 0x100, 0x200, 64, 0, 0]), 9);
+            assert_eq!(&mem[0x200..0x209], b"../secret");
+        }
+
+        // The mount points are in the rootfs's root directory, beside the
+        // kernel's /dev and /proc
+        path(&mut mem, "/");
+        assert_eq!(kernel.dispatch(&mut mem, nr::OPENAT, [cwd, 0x100, O_DIRECTORY, 0, 0, 0]), 4);
+        let n = kernel.dispatch(&mut mem, nr::GETDENTS64, [4, 0x1000, 0x400, 0, 0, 0]);
+        assert_eq!(dirent_names(&mem, 0x1000, 0x1000 + n as usize), [".", "..", "dev", "proc", "ro", "work"]);
+
+        // A read-only mount refuses writes
+        path(&mut mem, "/ro/src/main.c");
+        assert_eq!(kernel.dispatch(&mut mem, nr::OPENAT, [cwd, 0x100, 0, 0, 0, 0]), 5);
+        assert_eq!(kernel.dispatch(&mut mem, nr::OPENAT, [cwd, 0x100, O_WRONLY, 0, 0, 0]), EROFS.as_ret());
+        path(&mut mem, "/ro/new");
+        assert_eq!(kernel.dispatch(&mut mem, nr::OPENAT, [cwd, 0x100, O_CREAT | O_WRONLY, 0o644, 0, 0]), EROFS.as_ret());
+        assert!(!root.join("new").exists());
+
+        fs::remove_dir_all(&tmp).unwrap();
+    }
+}
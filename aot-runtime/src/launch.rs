@@ -0,0 +1,125 @@
+// launch.rs - What a program starts with
+//
+// `Launch` collects the argument list, environment and working directory
+// a guest starts with, in the manner of `std::process::Command`, and turns
+// them into the loader's `LoadOptions` (argv and envp go onto the initial
+// stack, argv[0] into AT_EXECFN) and the new process's state (the cwd,
+// which getcwd reports, and the argv and program path /proc/self shows).
+// Hosts set these here rather than assembling the stack themselves.
+
+use crate::machine::Machine;
+use anyhow::{Context, Result};
+use rv2wasm::loader::{self, InitialImage, LoadOptions};
+use rv2wasm::ElfFile;
+
+/// A guest program's argv, environment and cwd
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Launch {
+    argv: Vec<String>,
+    /// KEY=VALUE, in the order first set
+    env: Vec<String>,
+    cwd: String,
+}
+
+impl Launch {
+    /// Start `argv0` with no further arguments, an empty environment and
+    /// `/` as the working directory
+    pub fn new(argv0: impl Into<String>) -> Self {
+        Self {
+            argv: vec![argv0.into()],
+            env: Vec::new(),
+            cwd: "/".into(),
+        }
+    }
+
+    pub fn arg(mut self, arg: impl Into<String>) -> Self {
+        self.argv.push(arg.into());
+        self
+    }
+
+    pub fn args<I: IntoIterator<Item = S>, S: Into<String>>(mut self, args: I) -> Self {
+        self.argv.extend(args.into_iter().map(Into::into));
+        self
+    }
+
+    /// Set `key` to `value`, replacing any earlier value
+    pub fn env(mut self, key: &str, value: &str) -> Self {
+        let entry = format!("{}={}", key, value);
+        match self.env.iter_mut().find(|e| e.split_once('=').map(|(k, _)| k) == Some(key)) {
+            Some(existing) => *existing = entry,
+            None => self.env.push(entry),
+        }
+        self
+    }
+
+    /// Set a KEY=VALUE string, as given on a command line
+    pub fn env_pair(self, pair: &str) -> Result<Self> {
+        let (key, value) = pair.split_once('=').with_context(|| format!("Expected KEY=VALUE, got {:?}", pair))?;
+        Ok(self.env(key, value))
+    }
+
+    /// The initial working directory, an absolute path
+    pub fn cwd(mut self, path: impl Into<String>) -> Result<Self> {
+        let path = path.into();
+        anyhow::ensure!(path.starts_with('/'), "Working directory {:?} is not absolute", path);
+        self.cwd = path;
+        Ok(self)
+    }
+
+    pub fn argv(&self) -> &[String] {
+        &self.argv
+    }
+
+    pub fn envp(&self) -> &[String] {
+        &self.env
+    }
+
+    pub fn load_options(&self) -> LoadOptions {
+        LoadOptions {
+            argv: self.argv.clone(),
+            envp: self.env.clone(),
+            ..Default::default()
+        }
+    }
+
+    /// Lay out `program` (and its interpreter) for these arguments
+    pub fn load(&self, program: &ElfFile, interp: Option<&ElfFile>) -> Result<InitialImage> {
+        loader::load_parsed(program, interp, &self.load_options())
+    }
+
+    /// A machine about to run `image`, a program loaded by `load`
+    pub fn start(&self, image: &InitialImage) -> Result<Machine> {
+        let mut machine = Machine::from_image(image)?;
+        let process = &mut machine.kernel.process;
+        process.cwd = self.cwd.clone();
+        process.argv = self.argv.clone();
+        process.exe = if self.argv[0].starts_with('/') {
+            self.argv[0].clone()
+        } else {
+            format!("{}/{}", self.cwd.trim_end_matches('/'), self.argv[0])
+        };
+        Ok(machine)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_launch_options() {
+        let launch = Launch::new("/bin/sh")
+            .args(["-c", "echo $HOME"])
+            .env("HOME", "/root")
+            .env_pair("TERM=xterm")
+            .unwrap()
+            .env("HOME", "/home/guest")
+            .cwd("/tmp")
+            .unwrap();
+        let opts = launch.load_options();
+        assert_eq!(opts.argv, ["/bin/sh", "-c", "echo $HOME"]);
+        assert_eq!(opts.envp, ["HOME=/home/guest", "TERM=xterm"]);
+        assert!(launch.clone().cwd("tmp").is_err());
+        assert!(launch.env_pair("TERM").is_err());
+    }
+}
@@ -0,0 +1,101 @@
+// friscy-runtime: Native host runtime for rv2wasm-compiled programs
+//
+// rv2wasm output imports `env.memory` and `env.syscall` and leaves the
+// operating system to the host. This crate is that host's kernel side:
+// the Linux syscall layer and the per-process state behind it.
+//
+// # Memory
+//
+// Guest addresses are linear-memory offsets. The memory manager owns the
+// layout of that space (image, brk heap, mmap area) and asks the host to
+// grow linear memory when a mapping or the heap reaches past its end.
+//
+// # Time
+//
+// `time.rs` serves the clock syscalls from one host clock and, for code
+// compiled with a time page, keeps that page current. `random.rs` serves
+// getrandom. For reproducible runs (`Machine::deterministic`) the clocks
+// count retired instructions instead and getrandom is seeded.
+//
+// # Files
+//
+// `vfs.rs` serves the file syscalls from the container rootfs, packed by
+// rv2wasm with every file compressed on its own and decompressed when the
+// guest first opens it. The rootfs stays read-only; the guest's writes go
+// to an overlay upper layer a host can save and reapply as a layer tar.
+// `hostfs.rs` mounts host directories over it, jailed to the directory.
+// `procfs.rs` renders /proc/self and /proc/meminfo from the kernel's
+// state; /dev/null, /dev/zero and /dev/urandom are device nodes the VFS
+// always has.
+//
+// # Networking
+//
+// `net.rs` implements the socket syscalls over a pluggable `Network`:
+// host sockets, or whatever transport an embedder provides. Browser guests
+// use friscy.wasm's own socket bridge instead (runtime/network.hpp).
+//
+// # Terminal
+//
+// `pty.rs` makes descriptors 0-2 a terminal: a termios line discipline
+// (line editing, echo, keyboard signals) between the guest and the host,
+// which pushes keystrokes into one bounded `Pipe` and shows what comes
+// out of another on a pluggable `Terminal`. `pipe.rs` also backs the
+// guest's own pipe2 pipes. `process.rs` holds the process's ids and run
+// state, so the terminal can do job control: a stopped process returns
+// from `Machine::run` until it gets SIGCONT.
+//
+// # Execution
+//
+// `launch.rs` sets up what a program starts with: argv, environment, cwd.
+// `machine.rs` runs guest code natively: blocks are compiled on demand with
+// rv2wasm's JIT path and executed under wasmtime. `gdb.rs` exposes a
+// Machine to gdb over the remote serial protocol, on TCP or on a WebSocket
+// (`websocket.rs`) for a browser page, and `trace.rs` records where its
+// time goes as a Chrome/Perfetto trace. `profile.rs` samples the
+// running block and reports hot functions as flamegraph input.
+// `record.rs` logs syscall results so a run can be replayed exactly.
+// `backtrace.rs` walks the guest stack (CFI or frame pointers) for a
+// symbolized backtrace when the guest crashes.
+// `regtrace.rs` logs the register file at every block, for diffing a run
+// against the reference interpreter's (friscy-trace). `telemetry.rs`
+// counts blocks, compilations and syscalls for a host's dashboards.
+
+pub mod backtrace;
+pub mod gdb;
+pub mod hostfs;
+pub mod launch;
+pub mod machine;
+pub mod mm;
+pub mod net;
+pub mod pipe;
+pub mod poll;
+pub mod process;
+pub mod procfs;
+pub mod profile;
+pub mod pty;
+pub mod random;
+pub mod record;
+pub mod regtrace;
+pub mod syscalls;
+pub mod telemetry;
+pub mod time;
+pub mod trace;
+pub mod vfs;
+pub mod websocket;
+
+pub use hostfs::{Directory, HostDirectory};
+pub use launch::Launch;
+pub use machine::{Exit, Machine};
+pub use mm::{GuestMemory, Layout, MemoryManager};
+pub use net::{HostNetwork, Network};
+pub use pipe::Pipe;
+pub use process::Process;
+pub use profile::Profiler;
+pub use pty::{HostTerminal, Terminal};
+pub use random::Random;
+pub use record::SyscallLog;
+pub use syscalls::{Errno, Kernel};
+pub use telemetry::{Counts, Telemetry};
+pub use time::{Clock, Time};
+pub use trace::Tracer;
+pub use vfs::Vfs;
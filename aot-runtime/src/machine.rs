@@ -0,0 +1,1188 @@
+// machine.rs - Native execution of guest code under wasmtime
+//
+// Mirrors the browser JIT tier (jit_manager.js): guest code is compiled on
+// demand with rv2wasm's JIT path, one window of instructions at a time. Block
+// functions run against a shared linear memory and return the next PC; this
+// loop dispatches between them and the syscall layer.
+//
+// Guest addresses are linear-memory offsets, so PCs must stay below
+// 0x40000000 to survive the block return encoding (bit 31 = ECALL,
+// bits 31:30 = EBREAK).
+//
+// `call` runs a single guest function for the host, FFI style: arguments in
+// a0-a7, ra = `translate::RETURN_ADDR` and a private stack. Returning to
+// that address ends the run, and the caller's hart state is put back.
+//
+// With `interpret` set, blocks run on rv2wasm's reference interpreter
+// instead of their compiled code (same blocks, same syscall layer), which
+// is the golden side of a register-trace comparison (`regtrace.rs`).
+//
+// Breakpoints are compiled in: a block holding one is recompiled with a call
+// to `env.breakpoint` before the instruction and swapped into the block map,
+// so neither guest memory nor block boundaries change. The interpreter has
+// no hooks, so with `interpret` blocks are split at breakpoints instead.
+//
+// `deterministic` takes the host out of everything the guest observes:
+// blocks are compiled metered (`CompileOptions::meter`) against a budget
+// that never runs out, so what was spent is the count of retired
+// instructions, and the kernel's clocks run on that count alone.
+
+use crate::backtrace::{self, Frame};
+use crate::mm::{self, Backing, GuestMemory, Layout, Mapping, MemoryManager};
+use crate::process::{Process, SIGBUS, SIGILL, SIGSEGV};
+use crate::profile::Profiler;
+use crate::random::Random;
+use crate::record::SyscallLog;
+use crate::regtrace::{self, RegTraceWriter};
+use crate::syscalls::{Errno, Kernel, EFAULT, ERESTARTSYS};
+use crate::telemetry::Telemetry;
+use crate::time::Time;
+use crate::trace::{EventKind, Tracer};
+use anyhow::{Context, Result};
+use rv2wasm::loader::InitialImage;
+use rv2wasm::snapshot::{Region, Snapshot, SnapshotLayout};
+use rv2wasm::disasm::Instruction;
+use rv2wasm::interp::{self, Hart, Step};
+use rv2wasm::unwind::EhFrame;
+use rv2wasm::{abi, cfg, disasm, link, translate, wasm_builder, CodeSection, CompileOptions, Symbol, Symbolizer};
+use std::collections::{BTreeSet, HashMap};
+use std::io::Write;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::Arc;
+use std::time::Instant;
+use wasmtime::{Config, Engine, Func, Instance, MemoryType, Module, SharedMemory, Store, TypedFunc};
+
+/// Linear-memory address of the machine state block (`$m`)
+pub const STATE_ADDR: u64 = 0x1000;
+
+/// Heap reserved above the image for brk
+pub const BRK_MAX: u64 = 64 << 20;
+
+/// Bytes of guest code disassembled per compilation
+const WINDOW: u64 = 4096;
+
+/// Stack mapped for guest functions run by `Machine::call`
+const CALL_STACK_SIZE: u64 = 256 << 10;
+
+/// Initial / maximum linear memory, in Wasm pages
+const MIN_PAGES: u32 = 256;
+const MAX_PAGES: u32 = link::MAX_PAGES as u32;
+
+const SYS_EXIT: u64 = 93;
+const SYS_EXIT_GROUP: u64 = 94;
+
+/// Why execution stopped
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Exit {
+    /// exit / exit_group with this status
+    Exited(i32),
+    /// Killed by this signal (e.g. SIGINT from the terminal)
+    Signaled(i32),
+    /// Stopped by this signal (e.g. SIGTSTP); runs again after SIGCONT
+    Stopped(i32),
+    /// A block returned the halt marker (e.g. unsupported instruction)
+    Halted,
+    /// EBREAK executed at this PC
+    Ebreak(u64),
+    /// Privileged instruction or CSR at this PC (SIGILL in user mode)
+    IllegalInstruction(u64),
+    /// Misaligned atomic access (PC, address); SIGBUS in user mode
+    MisalignedAccess(u64, u64),
+    /// About to execute a breakpoint address
+    Breakpoint(u64),
+    /// One instruction executed (single-step)
+    Stepped,
+    /// The Wasm code trapped (e.g. out-of-bounds guest access)
+    Trap(String),
+    /// The function started by `Machine::call` returned
+    Returned,
+}
+
+impl Exit {
+    /// How the process ended, as a module's dispatch loop would report it;
+    /// None if it has not (stopped at a breakpoint, say)
+    pub fn status(&self) -> Option<abi::RunStatus> {
+        use abi::RunStatus;
+        Some(match self {
+            Exit::Exited(code) => RunStatus::Exited(*code as u8),
+            Exit::Signaled(sig) => RunStatus::Signaled(*sig as u8),
+            Exit::IllegalInstruction(_) => RunStatus::Trapped(SIGILL as u8),
+            Exit::MisalignedAccess(..) => RunStatus::Trapped(SIGBUS as u8),
+            Exit::Trap(_) => RunStatus::Trapped(SIGSEGV as u8),
+            _ => return None,
+        })
+    }
+}
+
+/// Breakpoint hook state shared with `env.breakpoint`
+#[derive(Default)]
+struct Stops {
+    /// PC whose breakpoint the run is continuing from (not hit again)
+    resume: AtomicU64,
+    /// PC of the breakpoint that stopped the last block
+    hit: AtomicU64,
+}
+
+/// No PC in `Stops`
+const NO_PC: u64 = u64::MAX;
+
+/// A compiled block and the guest range it covers
+struct Block {
+    func: TypedFunc<i32, i32>,
+    end: u64,
+    /// The decoded block, kept for `Machine::interpret`
+    instructions: Option<Arc<[Instruction]>>,
+}
+
+/// Linear memory shared by every compiled module
+#[derive(Clone)]
+pub struct SharedGuestMemory(SharedMemory);
+
+impl SharedGuestMemory {
+    fn range(&self, addr: u64, len: usize) -> Result<*mut u8, Errno> {
+        let size = self.0.data_size() as u64;
+        if addr.checked_add(len as u64).is_none_or(|end| end > size) {
+            return Err(EFAULT);
+        }
+        Ok(unsafe { (self.0.data().as_ptr() as *mut u8).add(addr as usize) })
+    }
+}
+
+impl GuestMemory for SharedGuestMemory {
+    fn size(&self) -> u64 {
+        self.0.data_size() as u64
+    }
+
+    fn grow(&mut self, new_size: u64) -> bool {
+        let delta = new_size.saturating_sub(self.size()).div_ceil(mm::WASM_PAGE_SIZE);
+        delta == 0 || self.0.grow(delta).is_ok()
+    }
+
+    fn read(&self, addr: u64, buf: &mut [u8]) -> Result<(), Errno> {
+        let src = self.range(addr, buf.len())?;
+        // Safety: bounds checked above; guest code is not running concurrently
+        unsafe { std::ptr::copy_nonoverlapping(src, buf.as_mut_ptr(), buf.len()) };
+        Ok(())
+    }
+
+    fn write(&mut self, addr: u64, data: &[u8]) -> Result<(), Errno> {
+        let dst = self.range(addr, data.len())?;
+        unsafe { std::ptr::copy_nonoverlapping(data.as_ptr(), dst, data.len()) };
+        Ok(())
+    }
+
+    fn fill(&mut self, addr: u64, len: u64, byte: u8) -> Result<(), Errno> {
+        let dst = self.range(addr, len as usize)?;
+        unsafe { std::ptr::write_bytes(dst, byte, len as usize) };
+        Ok(())
+    }
+}
+
+/// A guest hart plus its process, executing rv2wasm-compiled blocks
+pub struct Machine {
+    engine: Engine,
+    store: Store<()>,
+    memory: SharedGuestMemory,
+    /// `env.syscall` for JIT modules; their blocks return ECALL instead
+    syscall: Func,
+    /// `env.yield`, called by PAUSE and WRS.* outside threads mode
+    yield_hint: Func,
+    /// `env.crypto`, the scalar AES instructions
+    crypto: Func,
+    /// `env.watch`, for debug builds; the machine sets no watchpoints
+    watch: Func,
+    /// `env.breakpoint`, called by blocks recompiled with breakpoints
+    breakpoint: Func,
+    stops: Arc<Stops>,
+    pub kernel: Kernel,
+    pub pc: u64,
+    blocks: HashMap<u64, Block>,
+    /// One-instruction blocks used for single-stepping
+    steps: HashMap<u64, TypedFunc<i32, i32>>,
+    breakpoints: BTreeSet<u64>,
+    /// Records blocks, syscalls and compilations when set
+    pub tracer: Option<Tracer>,
+    /// Samples the running block when set
+    pub profiler: Option<Profiler>,
+    /// Records or replays syscall results when set
+    pub syscall_log: Option<SyscallLog>,
+    /// Logs the register file at every block when set
+    pub reg_trace: Option<RegTraceWriter<Box<dyn Write + Send>>>,
+    /// Run blocks on the reference interpreter instead of compiled code
+    pub interpret: bool,
+    /// Guest function addresses by name, for `call`
+    pub symbols: HashMap<String, u64>,
+    /// The same functions with their extents, for naming PCs
+    symbol_table: Symbolizer,
+    /// Call frame information of the loaded objects, for `backtrace`
+    unwind: Vec<EhFrame>,
+    /// Top of the stack mapped for `call`, once one has run
+    call_stack: Option<u64>,
+    /// Instructions retired, as the deterministic clocks read them
+    retired: Option<Arc<AtomicU64>>,
+    telemetry: Arc<Telemetry>,
+}
+
+impl Machine {
+    /// Create an empty machine whose process uses `layout`
+    pub fn new(layout: Layout) -> Result<Self> {
+        let mut config = Config::new();
+        config.wasm_threads(true);
+        let engine = Engine::new(&config)?;
+        let memory = SharedMemory::new(&engine, MemoryType::shared(MIN_PAGES, MAX_PAGES))?;
+        let mut store = Store::new(&engine, ());
+        let syscall = Func::wrap(&mut store, |_: i32, pc: i32| -> Result<i32> {
+            anyhow::bail!("Block at 0x{:x} called env.syscall directly", pc as u32)
+        });
+        let yield_hint = Func::wrap(&mut store, std::thread::yield_now);
+        let crypto = Func::wrap(&mut store, |op: i32, rs1: i64, rs2: i64| {
+            rv2wasm::crypto::crypto(op, rs1 as u64, rs2 as u64) as i64
+        });
+        let watch = Func::wrap(&mut store, |_: i32, _: i32, _: i32, _: i32| {});
+        let stops = Arc::new(Stops { resume: AtomicU64::new(NO_PC), hit: AtomicU64::new(NO_PC) });
+        let hook = stops.clone();
+        let breakpoint = Func::wrap(&mut store, move |pc: i32| -> i32 {
+            let pc = pc as u32 as u64;
+            if hook.resume.swap(NO_PC, Ordering::Relaxed) == pc {
+                return 0;
+            }
+            hook.hit.store(pc, Ordering::Relaxed);
+            1
+        });
+        let mut machine = Self {
+            store,
+            engine,
+            memory: SharedGuestMemory(memory),
+            syscall,
+            yield_hint,
+            crypto,
+            watch,
+            breakpoint,
+            stops,
+            kernel: Kernel::new(layout),
+            pc: 0,
+            blocks: HashMap::new(),
+            steps: HashMap::new(),
+            breakpoints: BTreeSet::new(),
+            tracer: None,
+            profiler: None,
+            syscall_log: None,
+            reg_trace: None,
+            interpret: false,
+            symbols: HashMap::new(),
+            symbol_table: Symbolizer::default(),
+            unwind: Vec::new(),
+            call_stack: None,
+            retired: None,
+            telemetry: Arc::default(),
+        };
+        // No LR reservation
+        machine.memory.write(STATE_ADDR + translate::RESERVATION_ADDR_OFFSET as u64, &u64::MAX.to_le_bytes())?;
+        Ok(machine)
+    }
+
+    /// Create a machine running a loaded program
+    pub fn from_image(image: &InitialImage) -> Result<Self> {
+        let mut machine = Self::new(Layout {
+            brk_base: image.brk_base,
+            brk_max: BRK_MAX,
+            mmap_base: image.brk_base + BRK_MAX,
+            limit: MAX_PAGES as u64 * mm::WASM_PAGE_SIZE,
+        })?;
+        machine.kernel = Kernel::exec(&mut machine.memory, image, BRK_MAX, machine.kernel.mm.layout().limit)
+            .context("Failed to map program image")?;
+        for (i, &v) in image.regs.iter().enumerate() {
+            machine.set_reg(i, v);
+        }
+        machine.pc = image.entry;
+        Ok(machine)
+    }
+
+    /// Capture the hart, memory map and memory contents
+    pub fn snapshot(&self) -> Snapshot {
+        let read = |start: u64, len: u64| {
+            // Mapped but not yet backed by linear memory reads as zero
+            let mut buf = vec![0u8; len as usize];
+            let avail = self.memory.size().saturating_sub(start).min(len) as usize;
+            let _ = self.memory.read(start, &mut buf[..avail]);
+            buf
+        };
+        let mm = &self.kernel.mm;
+        let layout = mm.layout();
+        Snapshot {
+            pc: self.pc,
+            state: read(STATE_ADDR, translate::MACHINE_STATE_SIZE as u64),
+            layout: SnapshotLayout {
+                brk_base: layout.brk_base,
+                brk_max: layout.brk_max,
+                mmap_base: layout.mmap_base,
+                limit: layout.limit,
+            },
+            brk: mm.current_brk(),
+            heap: read(layout.brk_base, mm.current_brk() - layout.brk_base),
+            regions: mm
+                .mappings()
+                .map(|m| Region {
+                    start: m.start,
+                    prot: m.prot,
+                    flags: m.flags,
+                    file: match m.backing {
+                        Backing::File { fd, offset } => Some((fd, offset)),
+                        Backing::Anonymous => None,
+                    },
+                    data: read(m.start, m.len),
+                })
+                .collect(),
+            fds: self.kernel.vfs.open_files(),
+        }
+    }
+
+    /// Create a machine resuming from a snapshot. Its open files are not
+    /// reopened: that is `Vfs::reopen`'s job, once the filesystem they were
+    /// open in is in place.
+    pub fn restore(snapshot: &Snapshot) -> Result<Self> {
+        let l = snapshot.layout;
+        let layout = Layout {
+            brk_base: l.brk_base,
+            brk_max: l.brk_max,
+            mmap_base: l.mmap_base,
+            limit: l.limit,
+        };
+        let mut machine = Self::new(layout)?;
+        let mut write = |addr: u64, data: &[u8]| -> Result<()> {
+            anyhow::ensure!(
+                mm::ensure_backed(&mut machine.memory, addr + data.len() as u64),
+                "Snapshot does not fit in guest memory"
+            );
+            machine.memory.write(addr, data)?;
+            Ok(())
+        };
+        write(STATE_ADDR, &snapshot.state)?;
+        write(l.brk_base, &snapshot.heap)?;
+        for r in &snapshot.regions {
+            write(r.start, &r.data)?;
+        }
+
+        let mappings = snapshot.regions.iter().map(|r| Mapping {
+            start: r.start,
+            len: r.data.len() as u64,
+            prot: r.prot,
+            flags: r.flags,
+            backing: match r.file {
+                Some((fd, offset)) => Backing::File { fd, offset },
+                None => Backing::Anonymous,
+            },
+        });
+        machine.kernel.mm = MemoryManager::restore(layout, snapshot.brk, mappings);
+        machine.pc = snapshot.pc;
+        Ok(machine)
+    }
+
+    /// Make runs reproducible: the clocks advance only with retired
+    /// instructions, getrandom is seeded with `seed`, and the process has
+    /// its boot ids. Code compiled before is dropped, to be compiled again
+    /// with instruction counting.
+    pub fn deterministic(&mut self, seed: u64) -> Result<()> {
+        let retired = Arc::new(AtomicU64::new(0));
+        let page = self.kernel.time.page;
+        self.kernel.time = Time::deterministic(retired.clone());
+        self.kernel.time.page = page;
+        self.kernel.random = Random::Seeded(seed);
+        let Process { cwd, argv, exe, .. } = std::mem::take(&mut self.kernel.process);
+        self.kernel.process = Process { cwd, argv, exe, ..Default::default() };
+        self.memory.write(STATE_ADDR + translate::BUDGET_OFFSET as u64, &u64::MAX.to_le_bytes())?;
+        self.retired = Some(retired);
+        self.blocks.clear();
+        self.steps.clear();
+        Ok(())
+    }
+
+    /// Instructions retired since `deterministic`, counted a block at a time
+    pub fn retired(&self) -> Option<u64> {
+        self.retired.as_ref()?;
+        let mut budget = [0u8; 8];
+        self.memory.read(STATE_ADDR + translate::BUDGET_OFFSET as u64, &mut budget).ok()?;
+        Some(u64::MAX - u64::from_le_bytes(budget))
+    }
+
+    /// Charge `count` instructions run outside compiled code to the budget
+    fn charge(&mut self, count: u64) -> Result<()> {
+        if let Some(retired) = self.retired() {
+            let budget = u64::MAX - (retired + count);
+            self.memory.write(STATE_ADDR + translate::BUDGET_OFFSET as u64, &budget.to_le_bytes())?;
+        }
+        Ok(())
+    }
+
+    /// The counters of what this machine does, readable while it runs
+    pub fn telemetry(&self) -> Arc<Telemetry> {
+        self.telemetry.clone()
+    }
+
+    /// Guest memory handle
+    pub fn memory(&self) -> SharedGuestMemory {
+        self.memory.clone()
+    }
+
+    /// Integer register x`i`
+    pub fn reg(&self, i: usize) -> u64 {
+        let mut b = [0u8; 8];
+        let _ = self.memory.read(STATE_ADDR + i as u64 * 8, &mut b);
+        u64::from_le_bytes(b)
+    }
+
+    pub fn set_reg(&mut self, i: usize, value: u64) {
+        if i != 0 {
+            let _ = self.memory.write(STATE_ADDR + i as u64 * 8, &value.to_le_bytes());
+        }
+    }
+
+    /// Raw bits of FP register f`i` (the f64 view of the register file)
+    pub fn fpr(&self, i: usize) -> u64 {
+        let mut b = [0u8; 8];
+        let _ = self.memory.read(STATE_ADDR + translate::F64_REGS_OFFSET as u64 + i as u64 * 8, &mut b);
+        u64::from_le_bytes(b)
+    }
+
+    pub fn set_fpr(&mut self, i: usize, bits: u64) {
+        let _ = self.memory.write(STATE_ADDR + translate::F64_REGS_OFFSET as u64 + i as u64 * 8, &bits.to_le_bytes());
+    }
+
+    /// Stop before executing `addr`, recompiling the block that holds it
+    pub fn insert_breakpoint(&mut self, addr: u64) -> Result<()> {
+        if self.breakpoints.insert(addr) {
+            self.rebuild_block_at(addr)?;
+        }
+        Ok(())
+    }
+
+    pub fn remove_breakpoint(&mut self, addr: u64) -> Result<bool> {
+        let removed = self.breakpoints.remove(&addr);
+        if removed {
+            self.rebuild_block_at(addr)?;
+        }
+        Ok(removed)
+    }
+
+    /// Bring the compiled blocks holding `addr` up to date with the
+    /// breakpoints: recompile them in place, or (interpreting) drop them so
+    /// blocks are split at `addr`
+    fn rebuild_block_at(&mut self, addr: u64) -> Result<()> {
+        if self.interpret {
+            self.blocks.retain(|&start, b| !(start < addr && addr < b.end));
+            return Ok(());
+        }
+        let holding: Vec<(u64, u64)> =
+            self.blocks.iter().filter(|(&start, b)| start <= addr && addr < b.end).map(|(&s, b)| (s, b.end)).collect();
+        for (start, end) in holding {
+            self.compile_range(start, end)?;
+        }
+        Ok(())
+    }
+
+    /// Drop compiled code overlapping `[start, start + len)` (code was written)
+    pub fn invalidate(&mut self, start: u64, len: u64) {
+        let end = start.saturating_add(len);
+        self.blocks.retain(|&s, b| b.end <= start || s >= end);
+        self.steps.retain(|&pc, _| pc + 4 <= start || pc >= end);
+    }
+
+    /// Run until exit, a signal, a breakpoint, EBREAK, halt or trap. A
+    /// breakpoint at the current PC does not stop the first block, so
+    /// continuing from a breakpoint makes progress.
+    pub fn run(&mut self) -> Result<Exit> {
+        if let Some(exit) = self.signal_exit() {
+            return Ok(exit);
+        }
+        let mut first = true;
+        loop {
+            if !first && self.breakpoints.contains(&self.pc) {
+                return Ok(Exit::Breakpoint(self.pc));
+            }
+            // Continuing from a breakpoint: its hook lets the first block pass
+            let resume = if first { self.pc } else { NO_PC };
+            self.stops.resume.store(resume, Ordering::Relaxed);
+            first = false;
+            self.telemetry.dispatch();
+            if let Some(profiler) = &mut self.profiler {
+                profiler.poll(self.pc);
+            }
+            if self.reg_trace.is_some() {
+                self.trace_regs()?;
+            }
+            if !self.blocks.contains_key(&self.pc) {
+                self.compile(self.pc)?;
+            }
+            let block = &self.blocks[&self.pc];
+            let exit = match &block.instructions {
+                Some(instructions) if self.interpret => self.interpret_block(&instructions.clone())?,
+                _ => self.execute(&block.func.clone())?,
+            };
+            if let Some(exit) = exit {
+                return Ok(exit);
+            }
+        }
+    }
+
+    /// Make `symbols` (link-time addresses of an object loaded at `bias`)
+    /// callable by name
+    pub fn add_symbols(&mut self, symbols: &[Symbol], bias: u64) {
+        self.symbols.extend(symbols.iter().map(|s| (s.name.clone(), s.addr + bias)));
+        self.symbol_table.add_symbols(symbols.iter().cloned(), bias);
+    }
+
+    /// Unwind through `eh_frame` (an object's .eh_frame, at its link-time
+    /// addresses) for an object loaded at `bias`
+    pub fn add_unwind_info(&mut self, eh_frame: EhFrame, bias: u64) {
+        self.unwind.push(eh_frame.with_bias(bias));
+    }
+
+    /// The guest call stack at the current PC, innermost frame first
+    pub fn backtrace(&self) -> Vec<Frame> {
+        let regs = std::array::from_fn(|i| self.reg(i));
+        backtrace::walk(&self.memory, &self.unwind, self.pc, &regs)
+    }
+
+    /// The symbol table plus the blocks compiled so far, for naming guest
+    /// PCs in messages and profiles
+    pub fn symbolizer(&self) -> Symbolizer {
+        let mut symbolizer = self.symbol_table.clone();
+        for (&start, block) in &self.blocks {
+            symbolizer.add_block(start, block.end);
+        }
+        symbolizer
+    }
+
+    /// Call the guest function `symbol` with integer arguments and return a0
+    pub fn call(&mut self, symbol: &str, args: &[u64]) -> Result<u64> {
+        let addr = *self
+            .symbols
+            .get(symbol)
+            .with_context(|| format!("No guest function named {}", symbol))?;
+        self.call_addr(addr, args)
+    }
+
+    /// Call the guest function at `addr` with the standard calling
+    /// convention, servicing its syscalls, then restore the hart (PC and
+    /// register file) as it was. The instruction budget is not restored:
+    /// the call's instructions stay spent, so the retired count (and the
+    /// deterministic clock) keeps moving forward. gp and tp are inherited,
+    /// so the program should have initialized its runtime first.
+    pub fn call_addr(&mut self, addr: u64, args: &[u64]) -> Result<u64> {
+        anyhow::ensure!(args.len() <= 8, "At most 8 register arguments, got {}", args.len());
+        let stack_top = match self.call_stack {
+            Some(top) => top,
+            None => {
+                let prot = mm::PROT_READ | mm::PROT_WRITE;
+                let flags = mm::MAP_PRIVATE | mm::MAP_ANONYMOUS;
+                let base = self
+                    .kernel
+                    .mm
+                    .mmap(&mut self.memory, None, 0, CALL_STACK_SIZE, prot, flags, -1, 0)
+                    .context("Failed to map call stack")?;
+                *self.call_stack.insert(base + CALL_STACK_SIZE)
+            }
+        };
+
+        let mut saved = vec![0u8; translate::MACHINE_STATE_SIZE as usize];
+        self.memory.read(STATE_ADDR, &mut saved)?;
+        let saved_pc = self.pc;
+
+        for (i, &arg) in args.iter().enumerate() {
+            self.set_reg(10 + i, arg);
+        }
+        self.set_reg(1, translate::RETURN_ADDR);
+        self.set_reg(2, stack_top);
+        self.pc = addr;
+        let exit = self.run();
+        let result = self.reg(10);
+
+        let budget = translate::BUDGET_OFFSET as usize..translate::BUDGET_OFFSET as usize + 8;
+        self.memory.read(STATE_ADDR + budget.start as u64, &mut saved[budget])?;
+        self.memory.write(STATE_ADDR, &saved)?;
+        self.pc = saved_pc;
+        match exit? {
+            Exit::Returned => Ok(result),
+            other => anyhow::bail!("Guest function 0x{:x} did not return: {:?}", addr, other),
+        }
+    }
+
+    /// Execute exactly one instruction
+    pub fn step(&mut self) -> Result<Exit> {
+        let func = match self.steps.get(&self.pc) {
+            Some(f) => f.clone(),
+            None => {
+                let f = self.compile_single(self.pc)?;
+                self.steps.insert(self.pc, f.clone());
+                f
+            }
+        };
+        Ok(self.execute(&func)?.unwrap_or(Exit::Stepped))
+    }
+
+    /// Call a block function and interpret its return value
+    fn execute(&mut self, func: &TypedFunc<i32, i32>) -> Result<Option<Exit>> {
+        let started = self.tracer.as_ref().map(|_| Instant::now());
+        self.telemetry.block(false);
+        let result = func.call(&mut self.store, STATE_ADDR as i32);
+        if let (Some(tracer), Some(started)) = (&mut self.tracer, started) {
+            tracer.record(EventKind::Block { pc: self.pc }, started);
+        }
+        let ret = match result {
+            Ok(r) => r as u32,
+            Err(e) => {
+                let at = self.symbolizer().describe(self.pc);
+                return Ok(Some(Exit::Trap(format!("{e:#} in the block at {at}"))));
+            }
+        };
+
+        if ret == u32::MAX {
+            return Ok(Some(Exit::Halted));
+        }
+        if ret == translate::RETURN_ADDR as u32 {
+            self.pc = translate::RETURN_ADDR;
+            return Ok(Some(Exit::Returned));
+        }
+        if ret == abi::ILLEGAL_TRAP {
+            let mut pc = [0u8; 8];
+            self.memory.read(STATE_ADDR + translate::TRAP_PC_OFFSET as u64, &mut pc)?;
+            self.pc = u64::from_le_bytes(pc);
+            return Ok(Some(Exit::IllegalInstruction(self.pc)));
+        }
+        if ret == abi::MISALIGNED_TRAP {
+            let mut words = [0u8; 16];
+            self.memory.read(STATE_ADDR + translate::TRAP_PC_OFFSET as u64, &mut words)?;
+            let (pc, addr) = words.split_at(8);
+            self.pc = u64::from_le_bytes(pc.try_into().unwrap());
+            return Ok(Some(Exit::MisalignedAccess(self.pc, u64::from_le_bytes(addr.try_into().unwrap()))));
+        }
+        if ret & 0xC000_0000 == 0xC000_0000 {
+            let pc = (ret & 0x3fff_ffff) as u64;
+            self.pc = pc;
+            if self.stops.hit.swap(NO_PC, Ordering::Relaxed) == pc {
+                return Ok(Some(Exit::Breakpoint(pc)));
+            }
+            return Ok(Some(Exit::Ebreak(pc)));
+        }
+        if ret & 0x8000_0000 != 0 {
+            let pc = (ret & 0x7fff_ffff) as u64;
+            self.pc = pc + 4;
+            return self.syscall();
+        }
+        self.pc = ret as u64;
+        Ok(None)
+    }
+
+    /// Run a block's instructions on the reference interpreter, against the
+    /// same machine state and memory as its compiled code
+    fn interpret_block(&mut self, instructions: &[Instruction]) -> Result<Option<Exit>> {
+        self.charge(instructions.len() as u64)?;
+        self.telemetry.block(true);
+        let reservation_addr = STATE_ADDR + translate::RESERVATION_ADDR_OFFSET as u64;
+        let mut hart = Hart::new(self.pc);
+        for (i, x) in hart.x.iter_mut().enumerate() {
+            *x = self.reg(i);
+        }
+        let mut word = [0u8; 8];
+        self.memory.read(reservation_addr, &mut word)?;
+        hart.reservation = Some(u64::from_le_bytes(word)).filter(|&addr| addr != u64::MAX);
+
+        let size = self.memory.size() as usize;
+        // Safety: the whole of linear memory; no guest code runs meanwhile
+        let mem = unsafe { std::slice::from_raw_parts_mut(self.memory.range(0, size)?, size) };
+        let step = interp::run_block(&mut hart, mem, instructions);
+
+        for (i, &x) in hart.x.iter().enumerate() {
+            self.set_reg(i, x);
+        }
+        self.memory.write(reservation_addr, &hart.reservation.unwrap_or(u64::MAX).to_le_bytes())?;
+        self.pc = hart.pc;
+        match step {
+            Err(e) => Ok(Some(Exit::Trap(format!("{e:#} at {}", self.symbolizer().describe(hart.pc))))),
+            Ok(Step::Next) if hart.pc == translate::RETURN_ADDR => Ok(Some(Exit::Returned)),
+            Ok(Step::Next) => Ok(None),
+            Ok(Step::Ecall) => {
+                self.pc += 4;
+                self.syscall()
+            }
+            Ok(Step::Ebreak) => Ok(Some(Exit::Ebreak(hart.pc))),
+        }
+    }
+
+    /// Log the register file on entry to the block at the PC
+    fn trace_regs(&mut self) -> Result<()> {
+        let mut state = [0u8; translate::RESERVATION_ADDR_OFFSET as usize];
+        self.memory.read(STATE_ADDR, &mut state)?;
+        if let Some(trace) = &mut self.reg_trace {
+            trace.record(self.pc, &regtrace::regs_from_state(&state)).context("Failed to write register trace")?;
+        }
+        Ok(())
+    }
+
+    /// Service an ECALL: a7 = number, a0-a5 = arguments, result in a0
+    fn syscall(&mut self) -> Result<Option<Exit>> {
+        let nr = self.reg(17);
+        self.telemetry.syscall(nr);
+        if nr == SYS_EXIT || nr == SYS_EXIT_GROUP {
+            return Ok(Some(Exit::Exited(self.reg(10) as i32)));
+        }
+        let args = std::array::from_fn(|i| self.reg(10 + i));
+        if let (Some(clock), Some(retired)) = (&self.retired, self.retired()) {
+            clock.store(retired, Ordering::Relaxed);
+        }
+        let started = Instant::now();
+        let ret = match &mut self.syscall_log {
+            Some(log) => log.dispatch(&mut self.kernel, &mut self.memory, nr, args)?,
+            None => self.kernel.dispatch(&mut self.memory, nr, args),
+        };
+        if let Some(tracer) = &mut self.tracer {
+            tracer.record(EventKind::Syscall { nr, ret }, started);
+        }
+        if ret == ERESTARTSYS.as_ret() {
+            self.pc -= 4;
+        } else {
+            self.set_reg(10, ret as u64);
+        }
+        Ok(self.signal_exit())
+    }
+
+    /// The exit for a killed or stopped process
+    fn signal_exit(&self) -> Option<Exit> {
+        let process = &self.kernel.process;
+        match (process.killed, process.stopped) {
+            (Some(sig), _) => Some(Exit::Signaled(sig)),
+            (None, Some(sig)) => Some(Exit::Stopped(sig)),
+            (None, None) => None,
+        }
+    }
+
+    /// Read up to WINDOW bytes of code at `pc`, bounded by its mapping
+    fn fetch(&self, pc: u64) -> Result<Vec<u8>> {
+        let limit = self
+            .kernel
+            .mm
+            .find(pc)
+            .map(|m| m.end())
+            .unwrap_or(pc + WINDOW)
+            .min(self.memory.size());
+        let end = (pc + WINDOW).min(limit);
+        anyhow::ensure!(end > pc, "PC 0x{:x} is outside guest memory", pc);
+        let mut data = vec![0u8; (end - pc) as usize];
+        self.memory.read(pc, &mut data)?;
+        Ok(data)
+    }
+
+    /// Compile the code window at `pc`
+    fn compile(&mut self, pc: u64) -> Result<()> {
+        self.compile_range(pc, u64::MAX)
+    }
+
+    /// Compile the code window at `pc`, up to `end`, with hooks at the
+    /// breakpoints in it (or, interpreting, ending blocks at them)
+    fn compile_range(&mut self, pc: u64, end: u64) -> Result<()> {
+        let started = Instant::now();
+        let data = self.fetch(pc)?;
+        let section = CodeSection {
+            vaddr: pc,
+            data,
+            name: format!("jit_0x{:x}", pc),
+        };
+        let mut instructions = disasm::disassemble(&section)?;
+        let mut end = end;
+        if self.interpret {
+            end = end.min(self.breakpoints.range(pc + 1..).next().copied().unwrap_or(u64::MAX));
+        }
+        instructions.retain(|i| i.addr < end);
+        anyhow::ensure!(!instructions.is_empty(), "No instructions at 0x{:x}", pc);
+
+        let graph = cfg::build(&instructions, pc)?;
+        let options = CompileOptions {
+            breakpoints: self.breakpoints.range(pc..end).copied().collect(),
+            meter: self.retired.is_some(),
+            ..Default::default()
+        };
+        let module = translate::translate_jit_with(&graph, pc, &options)?;
+        let wasm = wasm_builder::build_jit(&module)?;
+        let instance = self.instantiate(&wasm)?;
+        self.telemetry.compile();
+
+        for addr in link::block_map(&wasm)? {
+            let func = instance.get_typed_func::<i32, i32>(&mut self.store, &translate::block_name(addr))?;
+            let block = &graph.blocks[&addr];
+            let instructions = self.interpret.then(|| block.instructions.into());
+            self.blocks.insert(addr, Block { func, end: block.end_addr, instructions });
+        }
+        if let Some(tracer) = &mut self.tracer {
+            tracer.record(EventKind::Compile { pc, blocks: graph.blocks.len() }, started);
+        }
+        Ok(())
+    }
+
+    /// Compile a block holding only the instruction at `pc`
+    fn compile_single(&mut self, pc: u64) -> Result<TypedFunc<i32, i32>> {
+        let data = self.fetch(pc)?;
+        let inst = match *data.as_slice() {
+            [b0, b1, ..] if b0 & 0x3 != 0x3 => disasm::decode16(pc, u16::from_le_bytes([b0, b1])),
+            [b0, b1, b2, b3, ..] => disasm::decode32(pc, u32::from_le_bytes([b0, b1, b2, b3])),
+            _ => anyhow::bail!("No instruction at 0x{:x}", pc),
+        };
+        let inst = [inst.with_context(|| format!("Cannot step at 0x{:x}", pc))?];
+
+        let graph = cfg::build(&inst, pc)?;
+        let options = CompileOptions { meter: self.retired.is_some(), ..Default::default() };
+        let module = translate::translate_jit_with(&graph, pc, &options)?;
+        let instance = self.instantiate(&wasm_builder::build_jit(&module)?)?;
+        self.telemetry.compile();
+        instance.get_typed_func::<i32, i32>(&mut self.store, &format!("block_{:x}", pc))
+    }
+
+    fn instantiate(&mut self, wasm: &[u8]) -> Result<Instance> {
+        let module = Module::new(&self.engine, wasm).context("Generated module failed to compile")?;
+        let imports = module
+            .imports()
+            .map(|import| match import.name() {
+                link::MEMORY => Ok(self.memory.0.clone().into()),
+                link::SYSCALL => Ok(self.syscall.into()),
+                link::YIELD => Ok(self.yield_hint.into()),
+                link::CRYPTO => Ok(self.crypto.into()),
+                link::WATCH => Ok(self.watch.into()),
+                link::BREAKPOINT => Ok(self.breakpoint.into()),
+                name => anyhow::bail!("Unexpected import env.{}", name),
+            })
+            .collect::<Result<Vec<wasmtime::Extern>>>()?;
+        Instance::new(&mut self.store, &module, &imports)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const CODE: u64 = 0x10000;
+
+    /// Machine with `code` mapped executable at CODE
+    fn machine(code: &[u32]) -> Machine {
+        let mut m = Machine::new(Layout {
+            brk_base: 0x100000,
+            brk_max: 0x100000,
+            mmap_base: 0x200000,
+            limit: 0x1000000,
+        })
+        .unwrap();
+        let bytes: Vec<u8> = code.iter().flat_map(|i| i.to_le_bytes()).collect();
+        m.memory().write(CODE, &bytes).unwrap();
+        m.kernel.mm.reserve(CODE, bytes.len() as u64, mm::PROT_READ | mm::PROT_EXEC);
+        m.pc = CODE;
+        m
+    }
+
+    // addi a0, zero, 40 ; addi a0, a0, 2 ; addi a7, zero, 93 ; ecall
+    const EXIT_42: [u32; 4] = [0x02800513, 0x00250513, 0x05d00893, 0x00000073];
+
+    #[test]
+    fn test_run_to_exit() {
+        let mut m = machine(&EXIT_42);
+        let exit = m.run().unwrap();
+        assert_eq!(exit, Exit::Exited(42));
+        assert_eq!(exit.status(), Some(abi::RunStatus::Exited(42)));
+    }
+
+    #[test]
+    fn test_deterministic_clock() {
+        // addi a0, zero, 1 ; lui a1, 0x100 ; addi a7, zero, 113 ; ecall (clock_gettime)
+        // addi a7, zero, 93 ; ecall
+        let code = [0x00100513, 0x001005b7, 0x07100893, 0x00000073, 0x05d00893, 0x00000073];
+        let run = || {
+            let mut m = machine(&code);
+            m.deterministic(7).unwrap();
+            assert_eq!(m.run().unwrap(), Exit::Exited(0));
+            let mut ts = [0u8; 16];
+            m.memory().read(0x100000, &mut ts).unwrap();
+            (ts, m.retired())
+        };
+        // The clock read the four instructions before the ECALL, every run
+        let (ts, retired) = run();
+        assert_eq!(u64::from_le_bytes(ts[8..].try_into().unwrap()), 4);
+        assert_eq!(retired, Some(6));
+        assert_eq!(run(), (ts, retired));
+    }
+
+    #[test]
+    fn test_breakpoint_and_step() {
+        let mut m = machine(&EXIT_42);
+        m.insert_breakpoint(CODE + 4).unwrap();
+        assert_eq!(m.run().unwrap(), Exit::Breakpoint(CODE + 4));
+        assert_eq!(m.reg(10), 40);
+
+        assert_eq!(m.step().unwrap(), Exit::Stepped);
+        assert_eq!((m.pc, m.reg(10)), (CODE + 8, 42));
+
+        assert_eq!(m.run().unwrap(), Exit::Exited(42));
+    }
+
+    #[test]
+    fn test_breakpoint_recompiles_block() {
+        let mut m = machine(&EXIT_42);
+        m.insert_breakpoint(CODE + 8).unwrap();
+        assert_eq!(m.run().unwrap(), Exit::Breakpoint(CODE + 8));
+        // One block with a hook, not a block split at the breakpoint
+        assert_eq!(m.symbolizer().block(CODE + 8), Some(CODE));
+
+        // A breakpoint in the compiled block swaps in a recompiled one
+        m.insert_breakpoint(CODE + 4).unwrap();
+        m.pc = CODE;
+        assert_eq!(m.run().unwrap(), Exit::Breakpoint(CODE + 4));
+        assert_eq!(m.reg(10), 40);
+        // Continuing compiles a block at CODE + 4, with the hook at CODE + 8
+        assert_eq!(m.run().unwrap(), Exit::Breakpoint(CODE + 8));
+        assert!(m.remove_breakpoint(CODE + 8).unwrap());
+        assert_eq!(m.run().unwrap(), Exit::Exited(42));
+    }
+
+    /// A writer whose bytes stay readable after it is boxed away
+    #[derive(Clone, Default)]
+    struct Shared(Arc<std::sync::Mutex<Vec<u8>>>);
+
+    impl Write for Shared {
+        fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+            self.0.lock().unwrap().extend_from_slice(buf);
+            Ok(buf.len())
+        }
+
+        fn flush(&mut self) -> std::io::Result<()> {
+            Ok(())
+        }
+    }
+
+    #[test]
+    fn test_interpreter_matches_register_trace() {
+        // addi a0, zero, 0 ; addi a1, zero, 5
+        // loop: add a0, a0, a1 ; addi a1, a1, -1 ; bnez a1, loop
+        // addi a7, zero, 93 ; ecall
+        let code = [0x00000513, 0x00500593, 0x00b50533, 0xfff58593, 0xfe059ce3, 0x05d00893, 0x00000073];
+        let traces: Vec<Vec<u8>> = [false, true]
+            .into_iter()
+            .map(|interpret| {
+                let out = Shared::default();
+                let mut m = machine(&code);
+                m.interpret = interpret;
+                m.reg_trace = Some(RegTraceWriter::new(Box::new(out.clone()) as Box<dyn Write + Send>).unwrap());
+                assert_eq!(m.run().unwrap(), Exit::Exited(15));
+                let bytes = out.0.lock().unwrap().clone();
+                bytes
+            })
+            .collect();
+
+        let read = |t| regtrace::RegTraceReader::new(std::io::Cursor::new(t)).unwrap();
+        let mut golden = read(&traces[1][..]);
+        let mut pcs = Vec::new();
+        while let Some(entry) = golden.next_entry().unwrap() {
+            pcs.push(entry.pc - CODE);
+        }
+        assert_eq!(pcs, [0, 8, 8, 8, 8, 8, 20]);
+        assert_eq!(regtrace::diff(read(&traces[0][..]), read(&traces[1][..])).unwrap(), None);
+    }
+
+    #[test]
+    fn test_telemetry() {
+        // The loop of test_interpreter_matches_register_trace, interpreted
+        let code = [0x00000513, 0x00500593, 0x00b50533, 0xfff58593, 0xfe059ce3, 0x05d00893, 0x00000073];
+        let mut m = machine(&code);
+        m.interpret = true;
+        let telemetry = m.telemetry();
+        assert_eq!(m.run().unwrap(), Exit::Exited(15));
+        let counts = telemetry.read();
+        assert_eq!((counts.dispatches, counts.blocks, counts.interpreted), (7, 7, 7));
+        assert_eq!(counts.compilations, 1);
+        assert_eq!(counts.syscalls, [(93, 1)].into());
+    }
+
+    #[test]
+    fn test_call_guest_function() {
+        // square_sum: add a0, a0, a1 ; mul a0, a0, a0 ; ret
+        let mut m = machine(&[0x00b50533, 0x02a50533, 0x00008067]);
+        m.add_symbols(&[Symbol { name: "square_sum".into(), addr: 0, size: 12 }], CODE);
+        m.set_reg(10, 7);
+
+        assert_eq!(m.call("square_sum", &[2, 3]).unwrap(), 25);
+        assert_eq!((m.pc, m.reg(10), m.reg(1)), (CODE, 7, 0));
+        assert!(m.call("missing", &[]).is_err());
+    }
+
+    #[test]
+    fn test_call_keeps_deterministic_clock_moving() {
+        // clock_gettime into 0x100000, then again into 0x100010, then exit
+        // with the square_sum of test_call_guest_function after them
+        let code = [
+            0x00100513, 0x001005b7, 0x07100893, 0x00000073,
+            0x00100513, 0x001005b7, 0x01058593, 0x07100893, 0x00000073,
+            0x05d00893, 0x00000073,
+            0x00b50533, 0x02a50533, 0x00008067,
+        ];
+        let mut m = machine(&code);
+        m.deterministic(7).unwrap();
+        m.insert_breakpoint(CODE + 16).unwrap();
+        assert_eq!(m.run().unwrap(), Exit::Breakpoint(CODE + 16));
+        assert_eq!(m.call_addr(CODE + 44, &[2, 3]).unwrap(), 25);
+        assert_eq!(m.retired(), Some(7));
+        assert!(m.remove_breakpoint(CODE + 16).unwrap());
+        assert_eq!(m.run().unwrap(), Exit::Exited(0));
+
+        // The second read counts the call's three instructions too
+        let mut ts = [0u8; 32];
+        m.memory().read(0x100000, &mut ts).unwrap();
+        let nsec = |at: usize| u64::from_le_bytes(ts[at + 8..at + 16].try_into().unwrap());
+        assert_eq!((nsec(0), nsec(16)), (4, 12));
+    }
+
+    #[test]
+    fn test_privileged_instruction_traps() {
+        // addi a0, zero, 1 ; csrrw sp, mscratch, sp
+        let mut m = machine(&[0x00100513, 0x34011173]);
+        assert_eq!(m.run().unwrap(), Exit::IllegalInstruction(CODE + 4));
+        assert_eq!((m.pc, m.reg(10)), (CODE + 4, 1));
+    }
+
+    #[test]
+    fn test_misaligned_atomic_traps() {
+        // addi a0, zero, 0x402 ; amoadd.w a1, a2, (a0)
+        let mut m = machine(&[0x40200513, 0x00c525af]);
+        assert_eq!(m.run().unwrap(), Exit::MisalignedAccess(CODE + 4, 0x402));
+        assert_eq!(m.reg(11), 0);
+    }
+
+    #[test]
+    fn test_amocas_swaps_on_match() {
+        // addi a0, zero, 0x400 ; addi a1, zero, 7
+        // amocas.w t1, a1, (a0) (0 == 0: stores 7) ; amocas.w t2, a1, (a0) (fails)
+        // add a0, t1, t2 ; addi a7, zero, 93 ; ecall
+        let mut m = machine(&[
+            0x40000513, 0x00700593, 0x28b5232f, 0x28b523af, 0x00730533, 0x05d00893, 0x00000073,
+        ]);
+        assert_eq!(m.run().unwrap(), Exit::Exited(7));
+        assert_eq!((m.reg(6), m.reg(7)), (0, 7));
+    }
+
+    #[test]
+    fn test_scalar_crypto() {
+        // addi a0, zero, 0x123 ; addi a1, zero, -5
+        // sha256sig0 t1, a0 ; aes64es t2, a0, a1 ; aes64ks2 t3, a0, a1 ; sha512sum1 t4, a1
+        // addi a0, zero, 0 ; addi a7, zero, 93 ; ecall
+        let mut m = machine(&[
+            0x12300513, 0xffb00593, 0x10251313, 0x32b503b3, 0x7eb50e33, 0x10559e93, 0x00000513, 0x05d00893,
+            0x00000073,
+        ]);
+        assert_eq!(m.run().unwrap(), Exit::Exited(0));
+        let (a, b) = (0x123u64, -5i64 as u64);
+        let sig0 = 0x123u32.rotate_right(7) ^ 0x123u32.rotate_right(18) ^ (0x123 >> 3);
+        assert_eq!(m.reg(6), sig0 as i32 as u64);
+        assert_eq!(m.reg(7), rv2wasm::crypto::crypto(rv2wasm::crypto::AES64ES, a, b));
+        assert_eq!(m.reg(28), rv2wasm::crypto::aes64ks2(a, b));
+        assert_eq!(m.reg(29), b.rotate_right(14) ^ b.rotate_right(18) ^ b.rotate_right(41));
+    }
+
+    #[test]
+    fn test_syscall_clears_reservation() {
+        // lui a2, 0x10 ; addi a2, a2, 0x100 ; lr.w t0, (a2)
+        // addi a0, zero, 0 ; addi a7, zero, 214 ; ecall (brk)
+        // sc.w t1, a1, (a2) ; lr.w t0, (a2) ; sc.w t2, a1, (a2) ; sc.w t3, a1, (a2)
+        // addi a0, zero, 0 ; addi a7, zero, 93 ; ecall
+        let mut m = machine(&[
+            0x00010637, 0x10060613, 0x100622af, 0x00000513, 0x0d600893, 0x00000073, 0x18b6232f, 0x100622af,
+            0x18b623af, 0x18b62e2f, 0x00000513, 0x05d00893, 0x00000073,
+        ]);
+        assert_eq!(m.run().unwrap(), Exit::Exited(0));
+        // Failed after the syscall, succeeded after a fresh LR, failed once used
+        assert_eq!((m.reg(6), m.reg(7), m.reg(28)), (1, 0, 1));
+    }
+
+    #[test]
+    fn test_snapshot_restore_resumes() {
+        let mut m = machine(&EXIT_42);
+        m.insert_breakpoint(CODE + 8).unwrap();
+        assert_eq!(m.run().unwrap(), Exit::Breakpoint(CODE + 8));
+
+        let bytes = m.snapshot().encode();
+        let mut resumed = Machine::restore(&Snapshot::decode(&bytes).unwrap()).unwrap();
+        assert_eq!((resumed.pc, resumed.reg(10)), (CODE + 8, 42));
+        assert_eq!(resumed.run().unwrap(), Exit::Exited(42));
+    }
+
+    #[test]
+    fn test_snapshot_keeps_files_and_fcsr() {
+        use crate::vfs::{Vfs, AT_FDCWD, SEEK_SET};
+        use rv2wasm::rootfs;
+
+        let tar = rootfs::build_tar(&[("etc/", b'5', "", b""), ("etc/motd", b'0', "", b"hello from the rootfs\n")]);
+        let packed = rootfs::pack(&rootfs::read_tar(&tar).unwrap());
+        let vfs = || Vfs::new(rootfs::Image::decode(&packed).unwrap());
+        let mut scratch = vec![0u8; 0x100];
+        scratch[..10].copy_from_slice(b"/etc/motd\0");
+
+        // fsrmi 2 ; csrsi fflags, 1 ; frcsr a0 ; addi a7, zero, 93 ; ecall
+        let mut m = machine(&[0x00215073, 0x0010e073, 0x00302573, 0x05d00893, 0x00000073]);
+        m.kernel.vfs = vfs();
+        assert_eq!(m.kernel.vfs.openat(&scratch, "/", AT_FDCWD as u64, 0, 0, 0, 3, 0), Ok(3));
+        assert_eq!(m.kernel.vfs.lseek(3, 6, SEEK_SET), Ok(6));
+        m.insert_breakpoint(CODE + 8).unwrap();
+        assert_eq!(m.run().unwrap(), Exit::Breakpoint(CODE + 8));
+
+        let snapshot = Snapshot::decode(&m.snapshot().encode()).unwrap();
+        assert_eq!(snapshot.fds.len(), 1);
+        let mut resumed = Machine::restore(&snapshot).unwrap();
+        resumed.kernel.vfs = vfs();
+        resumed.kernel.vfs.reopen(&snapshot.fds).unwrap();
+        assert_eq!(resumed.kernel.vfs.read(&mut scratch, 3, 0x80, 4), Ok(4));
+        assert_eq!(&scratch[0x80..0x84], b"from");
+        // Round toward -inf, and the inexact flag
+        assert_eq!(resumed.run().unwrap(), Exit::Exited(2 << 5 | 1));
+    }
+
+    /// Run the test rootfs's busybox (dynamically linked against musl) with
+    /// `args` and `stdin` piped in; how it exited and what it wrote to the
+    /// terminal
+    fn busybox(args: &[&str], stdin: &[u8]) -> (Exit, Vec<u8>) {
+        use crate::launch::Launch;
+        use crate::pty::{Pty, OUTPUT_CAPACITY};
+        use crate::vfs::Vfs;
+        use rv2wasm::{rootfs, ElfFile};
+
+        let tar = std::fs::read(concat!(env!("CARGO_MANIFEST_DIR"), "/../tests/rootfs.tar")).unwrap();
+        let entries = rootfs::read_tar(&tar).unwrap();
+        let program = ElfFile::parse(rootfs::find_file(&entries, "bin/busybox").unwrap()).unwrap();
+        let interp = ElfFile::parse(rootfs::find_file(&entries, "lib/ld-musl-riscv64.so.1").unwrap()).unwrap();
+        let launch = Launch::new("/bin/busybox").args(args.iter().copied());
+        let mut m = launch.start(&launch.load(&program, Some(&interp)).unwrap()).unwrap();
+        m.kernel.vfs = Vfs::new(rootfs::Image::decode(&rootfs::pack(&entries)).unwrap());
+        m.kernel.pty = Pty::detached();
+        m.kernel.pty.piped = true;
+        m.kernel.pty.input.write(stdin, false).unwrap();
+        m.kernel.pty.input.close_write();
+        let exit = m.run().unwrap();
+        let mut out = vec![0u8; OUTPUT_CAPACITY];
+        let n = m.kernel.pty.output.read(&mut out, false).unwrap_or(0);
+        out.truncate(n);
+        (exit, out)
+    }
+
+    #[test]
+    fn test_musl_stdio() {
+        // musl's stdio writes with writev; piped input is read as it came,
+        // without being echoed
+        assert_eq!(busybox(&["wc", "-c"], b"hi there\n"), (Exit::Exited(0), b"9\r\n".to_vec()));
+    }
+
+    #[test]
+    fn test_busybox_shell() {
+        // The shell's setjmp saves fs0 and fs1 with compressed stores (c.fsd)
+        assert_eq!(busybox(&["sh", "-c", "echo a"], b""), (Exit::Exited(0), b"a\r\n".to_vec()));
+    }
+
+    #[test]
+    fn test_busybox_proc_and_devices() {
+        let (exit, out) = busybox(&["head", "-n", "2", "/proc/self/status"], b"");
+        assert_eq!((exit, out), (Exit::Exited(0), b"Name:\tbusybox\r\nUmask:\t0022\r\n".to_vec()));
+        assert_eq!(busybox(&["head", "-c", "5", "/dev/zero"], b""), (Exit::Exited(0), vec![0; 5]));
+        assert_eq!(busybox(&["cat", "/dev/null"], b""), (Exit::Exited(0), Vec::new()));
+    }
+}
@@ -0,0 +1,353 @@
+// friscy-run - Run a RISC-V Linux program natively
+//
+// Loads the program (and its interpreter, if given) with the rv2wasm
+// loader and executes it through the JIT path under wasmtime.
+//
+// Usage:
+//   friscy-run prog arg1 arg2
+//   friscy-run --cwd /tmp -e HOME=/root -e TERM=xterm prog
+//   friscy-run --rootfs rootfs.tar --cwd /root busybox sh
+//   friscy-run --rootfs rootfs.tar --overlay changes.tar busybox sh
+//   friscy-run --rootfs rootfs.tar --mount .:/work --cwd /work busybox sh
+//   friscy-run --gdb 127.0.0.1:1234 prog
+//   friscy-run --gdb-ws 127.0.0.1:1234 prog
+//   friscy-run --trace trace.json prog
+//   friscy-run --profile prog.folded prog
+//   friscy-run --block-profile prog.blocks prog && rv2wasm --block-profile prog.blocks prog
+//   friscy-run --record run.log prog && friscy-run --replay run.log prog
+//   friscy-run --deterministic --seed 7 --reg-trace run.rtr prog
+//   friscy-run --interpret --reg-trace golden.rtr prog && friscy-trace golden.rtr other.rtr
+//   friscy-run --snapshot-at 0x10400 --snapshot booted.snap prog
+//   friscy-run --restore booted.snap prog
+
+use anyhow::{ensure, Context, Result};
+use clap::Parser;
+use friscy_runtime::backtrace;
+use friscy_runtime::gdb::GdbStub;
+use friscy_runtime::hostfs::HostDirectory;
+use friscy_runtime::launch::Launch;
+use friscy_runtime::machine::{Exit, Machine};
+use friscy_runtime::profile::{self, Profiler};
+use friscy_runtime::pty;
+use friscy_runtime::record::SyscallLog;
+use friscy_runtime::regtrace::RegTraceWriter;
+use friscy_runtime::trace::Tracer;
+use friscy_runtime::vfs::Vfs;
+use friscy_runtime::websocket::WebSocket;
+use rv2wasm::unwind::EhFrame;
+use rv2wasm::{loader, rootfs};
+use rv2wasm::{ElfFile, Snapshot, Symbol};
+use std::io::IsTerminal;
+use std::path::PathBuf;
+
+#[derive(Parser, Debug)]
+#[command(name = "friscy-run")]
+#[command(about = "Run a RISC-V Linux program under the friscy runtime")]
+#[command(version)]
+struct Args {
+    /// RISC-V ELF program
+    program: PathBuf,
+
+    /// Arguments passed to the program
+    #[arg(trailing_var_arg = true)]
+    args: Vec<String>,
+
+    /// Dynamic interpreter (host path) for dynamically linked programs
+    #[arg(long)]
+    interp: Option<PathBuf>,
+
+    /// Environment variables (KEY=VALUE), repeatable
+    #[arg(short, long)]
+    env: Vec<String>,
+
+    /// Working directory the program starts in
+    #[arg(long, default_value = "/")]
+    cwd: String,
+
+    /// The guest's filesystem: a rootfs tar, a packed rootfs, or a module
+    /// built with rv2wasm --embed-rootfs
+    #[arg(long)]
+    rootfs: Option<PathBuf>,
+
+    /// Keep the guest's filesystem changes in this layer tar (whiteouts as
+    /// .wh. entries): applied at start if it exists, rewritten at exit
+    #[arg(long)]
+    overlay: Option<PathBuf>,
+
+    /// Mount a host directory in the guest (HOST:GUEST, or HOST:GUEST:ro
+    /// read-only), repeatable
+    #[arg(long)]
+    mount: Vec<String>,
+
+    /// Wait for a GDB connection on this address before running
+    #[arg(long)]
+    gdb: Option<String>,
+
+    /// Like --gdb, but take the connection as a WebSocket (a browser page
+    /// bridging gdb's protocol)
+    #[arg(long, conflicts_with = "gdb")]
+    gdb_ws: Option<String>,
+
+    /// Write a Chrome trace (chrome://tracing, ui.perfetto.dev) to this file
+    #[arg(long)]
+    trace: Option<PathBuf>,
+
+    /// Sample the guest and write collapsed stacks (flamegraph input) here
+    #[arg(long)]
+    profile: Option<PathBuf>,
+
+    /// Sample the guest and write per-block counts (rv2wasm --block-profile input) here
+    #[arg(long)]
+    block_profile: Option<PathBuf>,
+
+    /// Write runtime counters (blocks, compilations, syscalls by number) to this file as JSON
+    #[arg(long)]
+    counters: Option<PathBuf>,
+
+    /// Profiler sampling rate
+    #[arg(long, default_value_t = profile::DEFAULT_HZ)]
+    profile_hz: u32,
+
+    /// Log every syscall result to this file
+    #[arg(long, conflicts_with = "replay")]
+    record: Option<PathBuf>,
+
+    /// Feed syscall results back from a --record log
+    #[arg(long)]
+    replay: Option<PathBuf>,
+
+    /// Make the run reproducible: time advances with retired instructions,
+    /// getrandom is seeded and process ids are fixed
+    #[arg(long)]
+    deterministic: bool,
+
+    /// Seed for getrandom in --deterministic runs
+    #[arg(long, default_value_t = 0, requires = "deterministic")]
+    seed: u64,
+
+    /// Log the register file at every block to this file (friscy-trace input)
+    #[arg(long)]
+    reg_trace: Option<PathBuf>,
+
+    /// Run blocks on the reference interpreter instead of compiled code
+    /// (RV64IMAC only), for a golden --reg-trace
+    #[arg(long)]
+    interpret: bool,
+
+    /// Resume from a snapshot instead of starting the program. Its open
+    /// files are reopened in the filesystem --rootfs, --overlay and --mount
+    /// set up, so pass the same ones it was taken with.
+    #[arg(long)]
+    restore: Option<PathBuf>,
+
+    /// Write a snapshot when execution reaches --snapshot-at
+    #[arg(long, requires = "snapshot_at")]
+    snapshot: Option<PathBuf>,
+
+    /// Guest address (hex) at which to take the snapshot and stop
+    #[arg(long, value_parser = parse_hex, requires = "snapshot")]
+    snapshot_at: Option<u64>,
+}
+
+fn parse_hex(s: &str) -> Result<u64, std::num::ParseIntError> {
+    u64::from_str_radix(s.trim_start_matches("0x"), 16)
+}
+
+/// Function symbols of the program and interpreter at their load addresses
+fn load_symbols(program: &ElfFile, interp: Option<&ElfFile>, image: &loader::InitialImage) -> Vec<Symbol> {
+    let objects = [(Some(program), image.main_bias), (interp, image.interp_bias.unwrap_or(0))];
+    objects
+        .into_iter()
+        .filter_map(|(file, bias)| Some((file?.symbols(), bias)))
+        .flat_map(|(syms, bias)| {
+            syms.into_iter().map(move |s| Symbol { addr: s.addr + bias, ..s })
+        })
+        .collect()
+}
+
+/// Add the .eh_frame of the program and interpreter for backtraces; a
+/// malformed one only costs the frame-pointer fallback
+fn load_unwind_info(machine: &mut Machine, program: &ElfFile, interp: Option<&ElfFile>, image: &loader::InitialImage) {
+    let objects = [(Some(program), image.main_bias), (interp, image.interp_bias.unwrap_or(0))];
+    for (file, bias) in objects {
+        if let Some(Ok(Some(eh_frame))) = file.map(EhFrame::from_elf) {
+            machine.add_unwind_info(eh_frame, bias);
+        }
+    }
+}
+
+/// The packed rootfs in `data`, whichever form it came in
+fn load_rootfs(data: &[u8]) -> Result<rootfs::Image> {
+    if let Ok(image) = rootfs::Image::decode(data) {
+        return Ok(image);
+    }
+    if data.starts_with(b"\0asm") {
+        let packed = rootfs::from_module(data)?.context("Module has no embedded rootfs")?;
+        return rootfs::Image::decode(packed);
+    }
+    rootfs::Image::decode(&rootfs::pack(&rootfs::read_tar(data)?))
+}
+
+/// The guest stack, after a crash report
+fn print_backtrace(machine: &Machine) {
+    eprint!("{}", backtrace::render(&machine.backtrace(), &machine.symbolizer()));
+}
+
+fn main() -> Result<()> {
+    let args = Args::parse();
+
+    let program = std::fs::read(&args.program).context("Failed to read program")?;
+    let interp = args
+        .interp
+        .as_ref()
+        .map(std::fs::read)
+        .transpose()
+        .context("Failed to read interpreter")?;
+
+    let mut launch = Launch::new(args.program.display().to_string()).args(&args.args).cwd(&args.cwd)?;
+    for pair in &args.env {
+        launch = launch.env_pair(pair)?;
+    }
+    let program = ElfFile::parse(&program)?;
+    let interp = interp.as_deref().map(ElfFile::parse).transpose().context("Invalid interpreter ELF")?;
+    let image = launch.load(&program, interp.as_ref())?;
+    let snapshot = match &args.restore {
+        Some(path) => Some(Snapshot::decode(&std::fs::read(path).context("Failed to read snapshot")?)?),
+        None => None,
+    };
+    let mut machine = match &snapshot {
+        Some(snapshot) => Machine::restore(snapshot)?,
+        None => launch.start(&image)?,
+    };
+    if let Some(path) = &args.rootfs {
+        let data = std::fs::read(path).context("Failed to read rootfs")?;
+        let image = load_rootfs(&data).with_context(|| format!("Bad rootfs {}", path.display()))?;
+        machine.kernel.vfs = Vfs::new(image);
+    }
+    if let Some(path) = &args.overlay {
+        match std::fs::read(path) {
+            Ok(layer) => machine.kernel.vfs.apply_layer(&layer).with_context(|| format!("Bad overlay {}", path.display()))?,
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => {}
+            Err(e) => return Err(e).context("Failed to read overlay"),
+        }
+    }
+    for spec in &args.mount {
+        let (spec_paths, read_only) = match spec.strip_suffix(":ro") {
+            Some(paths) => (paths, true),
+            None => (spec.as_str(), false),
+        };
+        let (host, guest) =
+            spec_paths.rsplit_once(':').with_context(|| format!("Bad --mount {}: expected HOST:GUEST[:ro]", spec))?;
+        ensure!(guest.starts_with('/'), "Bad --mount {}: the guest path must be absolute", spec);
+        let dir = HostDirectory::new(host).with_context(|| format!("Failed to mount {}", host))?;
+        machine.kernel.mount(guest, Box::new(dir), read_only).with_context(|| format!("Failed to mount at {}", guest))?;
+    }
+    if let Some(snapshot) = &snapshot {
+        machine.kernel.vfs.reopen(&snapshot.fds)?;
+    }
+    machine.kernel.pty.piped = !std::io::stdin().is_terminal();
+    pty::pump_stdin(machine.kernel.pty.input.clone());
+    if let Some(addr) = args.snapshot_at {
+        machine.insert_breakpoint(addr)?;
+    }
+    if args.trace.is_some() {
+        machine.tracer = Some(Tracer::new());
+    }
+    if args.record.is_some() {
+        machine.syscall_log = Some(SyscallLog::recorder());
+    }
+    if let Some(path) = &args.replay {
+        let file = std::fs::File::open(path).context("Failed to open replay log")?;
+        machine.syscall_log = Some(SyscallLog::load(std::io::BufReader::new(file))?);
+    }
+    if let Some(path) = &args.reg_trace {
+        let file = std::fs::File::create(path).context("Failed to create register trace")?;
+        let out: Box<dyn std::io::Write + Send> = Box::new(std::io::BufWriter::new(file));
+        machine.reg_trace = Some(RegTraceWriter::new(out)?);
+    }
+    machine.interpret = args.interpret;
+    if args.deterministic {
+        machine.deterministic(args.seed)?;
+    }
+    machine.add_symbols(&load_symbols(&program, interp.as_ref(), &image), 0);
+    load_unwind_info(&mut machine, &program, interp.as_ref(), &image);
+    if args.profile.is_some() || args.block_profile.is_some() {
+        machine.profiler = Some(Profiler::start(args.profile_hz));
+    }
+
+    let status = if let Some(addr) = &args.gdb {
+        let listener = std::net::TcpListener::bind(addr).context("Failed to bind GDB port")?;
+        eprintln!("friscy-run: waiting for gdb on {}", addr);
+        let (stream, _) = listener.accept()?;
+        GdbStub::new(stream).serve(&mut machine)?.unwrap_or(0)
+    } else if let Some(addr) = &args.gdb_ws {
+        let listener = std::net::TcpListener::bind(addr).context("Failed to bind GDB port")?;
+        eprintln!("friscy-run: waiting for a gdb WebSocket on {}", addr);
+        let (stream, _) = listener.accept()?;
+        GdbStub::new(WebSocket::accept(stream)?).serve(&mut machine)?.unwrap_or(0)
+    } else {
+        let exit = machine.run()?;
+        // The guest's last output comes before anything said about its exit
+        machine.kernel.pty.flush();
+        match exit {
+            Exit::Exited(_) | Exit::Signaled(_) => exit.status().unwrap().shell_status(),
+            Exit::Stopped(sig) => {
+                eprintln!("friscy-run: stopped by signal {}", sig);
+                128 + sig
+            }
+            Exit::Breakpoint(pc) if Some(pc) == args.snapshot_at => {
+                let path = args.snapshot.as_ref().unwrap();
+                std::fs::write(path, machine.snapshot().encode()).context("Failed to write snapshot")?;
+                eprintln!("friscy-run: snapshot at 0x{:x} written to {}", pc, path.display());
+                0
+            }
+            Exit::IllegalInstruction(pc) => {
+                eprintln!("friscy-run: illegal instruction at {}", machine.symbolizer().describe(pc));
+                print_backtrace(&machine);
+                exit.status().unwrap().shell_status()
+            }
+            Exit::MisalignedAccess(pc, addr) => {
+                let at = machine.symbolizer().describe(pc);
+                eprintln!("friscy-run: misaligned atomic access to 0x{:x} at {}", addr, at);
+                print_backtrace(&machine);
+                exit.status().unwrap().shell_status()
+            }
+            other => {
+                eprintln!("friscy-run: stopped at {}: {:?}", machine.symbolizer().describe(machine.pc), other);
+                if matches!(other, Exit::Trap(_)) {
+                    print_backtrace(&machine);
+                }
+                128 + 5
+            }
+        }
+    };
+    machine.kernel.pty.flush();
+
+    if let (Some(path), Some(tracer)) = (&args.trace, &machine.tracer) {
+        let file = std::fs::File::create(path).context("Failed to create trace file")?;
+        tracer.write_chrome_json(std::io::BufWriter::new(file))?;
+    }
+    if let (Some(path), Some(log)) = (&args.record, &machine.syscall_log) {
+        let file = std::fs::File::create(path).context("Failed to create record log")?;
+        log.save(std::io::BufWriter::new(file))?;
+    }
+    if let Some(path) = &args.overlay {
+        std::fs::write(path, machine.kernel.vfs.upper_layer()?).context("Failed to write overlay")?;
+    }
+    if let Some(trace) = machine.reg_trace.take() {
+        trace.finish().context("Failed to write register trace")?;
+    }
+    if let (Some(path), Some(profiler)) = (&args.profile, &machine.profiler) {
+        let file = std::fs::File::create(path).context("Failed to create profile file")?;
+        profiler.write_collapsed(&machine.symbolizer(), std::io::BufWriter::new(file))?;
+    }
+    if let (Some(path), Some(profiler)) = (&args.block_profile, &machine.profiler) {
+        let file = std::fs::File::create(path).context("Failed to create block profile")?;
+        profiler.block_profile().write(std::io::BufWriter::new(file))?;
+    }
+    if let Some(path) = &args.counters {
+        std::fs::write(path, machine.telemetry().read().to_json()).context("Failed to write counters")?;
+    }
+
+    std::process::exit(status);
+}
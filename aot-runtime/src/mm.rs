@@ -0,0 +1,529 @@
+// mm.rs - Guest memory manager
+//
+// Tracks the guest address space the way the kernel's mm does for a single
+// process: the brk heap, a page-granular map of mmap regions with their
+// protection, and the size of the host linear memory backing it all.
+// Wasm linear memory can only grow, so unmapped pages are recycled (and
+// zeroed when handed out again) rather than returned to the host.
+
+use crate::syscalls::{Errno, EBADF, EEXIST, EFAULT, EINVAL, ENOMEM};
+use std::collections::BTreeMap;
+
+/// Guest page size
+pub const PAGE_SIZE: u64 = 4096;
+
+/// Granularity of linear-memory growth requests (one Wasm page)
+pub const WASM_PAGE_SIZE: u64 = 65536;
+
+pub const PROT_NONE: u32 = 0;
+pub const PROT_READ: u32 = 1;
+pub const PROT_WRITE: u32 = 2;
+pub const PROT_EXEC: u32 = 4;
+
+pub const MAP_SHARED: u32 = 0x01;
+pub const MAP_PRIVATE: u32 = 0x02;
+pub const MAP_FIXED: u32 = 0x10;
+pub const MAP_ANONYMOUS: u32 = 0x20;
+pub const MAP_FIXED_NOREPLACE: u32 = 0x100000;
+
+/// Host linear memory holding the guest address space
+pub trait GuestMemory {
+    /// Current size in bytes
+    fn size(&self) -> u64;
+    /// Grow to at least `new_size` bytes; false if the host refuses
+    fn grow(&mut self, new_size: u64) -> bool;
+    /// Copy guest memory at `addr` into `buf`
+    fn read(&self, addr: u64, buf: &mut [u8]) -> Result<(), Errno>;
+    /// Copy `data` into guest memory at `addr`
+    fn write(&mut self, addr: u64, data: &[u8]) -> Result<(), Errno>;
+    /// Set `len` bytes at `addr` to `byte`
+    fn fill(&mut self, addr: u64, len: u64, byte: u8) -> Result<(), Errno>;
+}
+
+/// The indices of `len` bytes at `addr`, or EFAULT if they wrap
+fn span(addr: u64, len: u64) -> Result<std::ops::Range<usize>, Errno> {
+    let start = usize::try_from(addr).map_err(|_| EFAULT)?;
+    let len = usize::try_from(len).map_err(|_| EFAULT)?;
+    Ok(start..start.checked_add(len).ok_or(EFAULT)?)
+}
+
+impl GuestMemory for Vec<u8> {
+    fn size(&self) -> u64 {
+        self.len() as u64
+    }
+
+    fn grow(&mut self, new_size: u64) -> bool {
+        if new_size > self.len() as u64 {
+            self.resize(new_size as usize, 0);
+        }
+        true
+    }
+
+    fn read(&self, addr: u64, buf: &mut [u8]) -> Result<(), Errno> {
+        let src = self.get(span(addr, buf.len() as u64)?).ok_or(EFAULT)?;
+        buf.copy_from_slice(src);
+        Ok(())
+    }
+
+    fn write(&mut self, addr: u64, data: &[u8]) -> Result<(), Errno> {
+        let dst = self.get_mut(span(addr, data.len() as u64)?).ok_or(EFAULT)?;
+        dst.copy_from_slice(data);
+        Ok(())
+    }
+
+    fn fill(&mut self, addr: u64, len: u64, byte: u8) -> Result<(), Errno> {
+        let dst = self.get_mut(span(addr, len)?).ok_or(EFAULT)?;
+        dst.fill(byte);
+        Ok(())
+    }
+}
+
+/// Source of file contents for file-backed mappings (implemented by the VFS)
+pub trait FileSource {
+    /// Read up to `buf.len()` bytes of `fd` at `offset`; returns bytes read
+    fn read_at(&self, fd: i32, offset: u64, buf: &mut [u8]) -> Result<usize, Errno>;
+}
+
+/// What a mapping's pages were populated from
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Backing {
+    Anonymous,
+    File { fd: i32, offset: u64 },
+}
+
+/// A contiguous, page-aligned range with uniform protection
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Mapping {
+    pub start: u64,
+    pub len: u64,
+    pub prot: u32,
+    pub flags: u32,
+    pub backing: Backing,
+}
+
+impl Mapping {
+    /// Exclusive end address
+    pub fn end(&self) -> u64 {
+        self.start + self.len
+    }
+}
+
+/// Address space layout handed to the manager at exec time.
+/// The heap `[brk_base, brk_base + brk_max)` must lie below `mmap_base`.
+#[derive(Debug, Clone, Copy)]
+pub struct Layout {
+    /// End of the loaded image (initial program break)
+    pub brk_base: u64,
+    /// Maximum heap size reachable through brk
+    pub brk_max: u64,
+    /// Lowest address handed out by non-fixed mmap
+    pub mmap_base: u64,
+    /// Exclusive upper bound of the guest address space
+    pub limit: u64,
+}
+
+/// Per-process guest memory bookkeeping
+#[derive(Debug, Clone)]
+pub struct MemoryManager {
+    layout: Layout,
+    brk: u64,
+    regions: BTreeMap<u64, Mapping>,
+}
+
+fn page_align_up(v: u64) -> Option<u64> {
+    v.checked_add(PAGE_SIZE - 1).map(|v| v & !(PAGE_SIZE - 1))
+}
+
+impl MemoryManager {
+    pub fn new(layout: Layout) -> Self {
+        debug_assert!(layout.brk_base + layout.brk_max <= layout.mmap_base);
+        Self {
+            brk: layout.brk_base,
+            layout,
+            regions: BTreeMap::new(),
+        }
+    }
+
+    /// Rebuild a manager from saved state (snapshot restore)
+    pub fn restore(layout: Layout, brk: u64, mappings: impl IntoIterator<Item = Mapping>) -> Self {
+        Self {
+            layout,
+            brk,
+            regions: mappings.into_iter().map(|m| (m.start, m)).collect(),
+        }
+    }
+
+    pub fn layout(&self) -> &Layout {
+        &self.layout
+    }
+
+    /// Current program break
+    pub fn current_brk(&self) -> u64 {
+        self.brk
+    }
+
+    /// brk(2): move the program break. Like Linux, returns the new break on
+    /// success and the unchanged break on failure (including `addr == 0`).
+    pub fn brk(&mut self, mem: &mut dyn GuestMemory, addr: u64) -> u64 {
+        let heap_end = self.layout.brk_base + self.layout.brk_max;
+        if addr < self.layout.brk_base || addr > heap_end {
+            return self.brk;
+        }
+        if addr > self.brk
+            && (!ensure_backed(mem, addr) || mem.fill(self.brk, addr - self.brk, 0).is_err())
+        {
+            return self.brk;
+        }
+        self.brk = addr;
+        self.brk
+    }
+
+    /// Register a region set up outside mmap (loaded segments, initial stack)
+    /// so mprotect and munmap can act on it.
+    pub fn reserve(&mut self, start: u64, len: u64, prot: u32) {
+        let start = start & !(PAGE_SIZE - 1);
+        let len = page_align_up(len).unwrap_or(len);
+        self.unmap_range(start, len);
+        self.regions.insert(
+            start,
+            Mapping {
+                start,
+                len,
+                prot,
+                flags: MAP_PRIVATE | MAP_FIXED,
+                backing: Backing::Anonymous,
+            },
+        );
+    }
+
+    /// mmap(2). `files` resolves `fd` for file-backed mappings.
+    #[allow(clippy::too_many_arguments)]
+    pub fn mmap(
+        &mut self,
+        mem: &mut dyn GuestMemory,
+        files: Option<&dyn FileSource>,
+        addr: u64,
+        len: u64,
+        prot: u32,
+        flags: u32,
+        fd: i32,
+        offset: u64,
+    ) -> Result<u64, Errno> {
+        if len == 0 || !offset.is_multiple_of(PAGE_SIZE) {
+            return Err(EINVAL);
+        }
+        let len = page_align_up(len).ok_or(ENOMEM)?;
+        let anonymous = flags & MAP_ANONYMOUS != 0;
+        if !anonymous && files.is_none() {
+            return Err(EBADF);
+        }
+
+        let start = if flags & (MAP_FIXED | MAP_FIXED_NOREPLACE) != 0 {
+            if !addr.is_multiple_of(PAGE_SIZE) {
+                return Err(EINVAL);
+            }
+            if addr.checked_add(len).is_none_or(|end| end > self.layout.limit) {
+                return Err(ENOMEM);
+            }
+            if flags & MAP_FIXED_NOREPLACE != 0 && !self.is_free(addr, len) {
+                return Err(EEXIST);
+            }
+            addr
+        } else {
+            let hint = addr & !(PAGE_SIZE - 1);
+            if hint >= self.layout.mmap_base
+                && hint.checked_add(len).is_some_and(|end| end <= self.layout.limit)
+                && self.is_free(hint, len)
+            {
+                hint
+            } else {
+                self.find_free(len).ok_or(ENOMEM)?
+            }
+        };
+
+        if !ensure_backed(mem, start + len) {
+            return Err(ENOMEM);
+        }
+
+        // Only now is the call known to succeed: replace what was there
+        self.unmap_range(start, len);
+        mem.fill(start, len, 0)?;
+        let backing = if anonymous {
+            Backing::Anonymous
+        } else {
+            populate_from_file(mem, files.unwrap(), fd, offset, start, len)?;
+            Backing::File { fd, offset }
+        };
+
+        self.regions.insert(
+            start,
+            Mapping {
+                start,
+                len,
+                prot,
+                flags,
+                backing,
+            },
+        );
+        Ok(start)
+    }
+
+    /// munmap(2). Unmapping pages that are not mapped is not an error.
+    pub fn munmap(&mut self, addr: u64, len: u64) -> Result<(), Errno> {
+        if !addr.is_multiple_of(PAGE_SIZE) || len == 0 {
+            return Err(EINVAL);
+        }
+        let len = page_align_up(len).ok_or(EINVAL)?;
+        self.unmap_range(addr, len);
+        Ok(())
+    }
+
+    /// mprotect(2). The whole range must be mapped (heap or regions).
+    pub fn mprotect(&mut self, addr: u64, len: u64, prot: u32) -> Result<(), Errno> {
+        if !addr.is_multiple_of(PAGE_SIZE) {
+            return Err(EINVAL);
+        }
+        let len = page_align_up(len).ok_or(ENOMEM)?;
+        if len == 0 {
+            return Ok(());
+        }
+        let end = addr + len;
+        if !self.is_mapped(addr, end) {
+            return Err(ENOMEM);
+        }
+
+        self.split_at(addr);
+        self.split_at(end);
+        for m in self.regions.range_mut(addr..end).map(|(_, m)| m) {
+            m.prot = prot;
+        }
+        Ok(())
+    }
+
+    /// The mapping containing `addr`, if any
+    pub fn find(&self, addr: u64) -> Option<&Mapping> {
+        self.regions
+            .range(..=addr)
+            .next_back()
+            .map(|(_, m)| m)
+            .filter(|m| addr < m.end())
+    }
+
+    /// All mappings in address order
+    pub fn mappings(&self) -> impl Iterator<Item = &Mapping> {
+        self.regions.values()
+    }
+
+    /// Pages currently mapped, heap included
+    pub fn mapped_pages(&self) -> u64 {
+        let heap = page_align_up(self.brk).unwrap_or(self.brk) - self.layout.brk_base;
+        let mapped: u64 = self.regions.values().map(|m| m.len).sum();
+        (heap + mapped) / PAGE_SIZE
+    }
+
+    fn is_free(&self, start: u64, len: u64) -> bool {
+        let end = start + len;
+        let heap_end = self.layout.brk_base + self.layout.brk_max;
+        if start < heap_end && end > self.layout.brk_base {
+            return false;
+        }
+        self.regions
+            .range(..end)
+            .next_back()
+            .is_none_or(|(_, m)| m.end() <= start)
+    }
+
+    fn is_mapped(&self, start: u64, end: u64) -> bool {
+        let heap_end = page_align_up(self.brk).unwrap_or(self.brk);
+        let mut cursor = start;
+        while cursor < end {
+            if cursor >= self.layout.brk_base && cursor < heap_end {
+                cursor = heap_end;
+            } else if let Some(m) = self.find(cursor) {
+                cursor = m.end();
+            } else {
+                return false;
+            }
+        }
+        true
+    }
+
+    /// First-fit search above `mmap_base`, reusing holes left by munmap
+    fn find_free(&self, len: u64) -> Option<u64> {
+        let mut cursor = self.layout.mmap_base;
+        if let Some(m) = self.find(cursor) {
+            cursor = m.end();
+        }
+        for m in self.regions.range(cursor..).map(|(_, m)| m) {
+            if m.start >= cursor + len {
+                break;
+            }
+            cursor = m.end();
+        }
+        cursor
+            .checked_add(len)
+            .filter(|&end| end <= self.layout.limit)
+            .map(|_| cursor)
+    }
+
+    /// Split the mapping containing `addr` so a mapping starts exactly there
+    fn split_at(&mut self, addr: u64) {
+        let Some(m) = self.find(addr).cloned() else { return };
+        if m.start == addr {
+            return;
+        }
+        let head_len = addr - m.start;
+        let backing = match m.backing {
+            Backing::File { fd, offset } => Backing::File {
+                fd,
+                offset: offset + head_len,
+            },
+            Backing::Anonymous => Backing::Anonymous,
+        };
+        self.regions.get_mut(&m.start).unwrap().len = head_len;
+        self.regions.insert(
+            addr,
+            Mapping {
+                start: addr,
+                len: m.len - head_len,
+                prot: m.prot,
+                flags: m.flags,
+                backing,
+            },
+        );
+    }
+
+    fn unmap_range(&mut self, start: u64, len: u64) {
+        let end = start.saturating_add(len);
+        self.split_at(start);
+        self.split_at(end);
+        let doomed: Vec<u64> = self.regions.range(start..end).map(|(&a, _)| a).collect();
+        for a in doomed {
+            self.regions.remove(&a);
+        }
+    }
+}
+
+/// Request linear-memory growth so `[0, end)` is addressable
+pub(crate) fn ensure_backed(mem: &mut dyn GuestMemory, end: u64) -> bool {
+    if end <= mem.size() {
+        return true;
+    }
+    let wanted = end.div_ceil(WASM_PAGE_SIZE) * WASM_PAGE_SIZE;
+    mem.grow(wanted) && mem.size() >= end
+}
+
+fn populate_from_file(
+    mem: &mut dyn GuestMemory,
+    files: &dyn FileSource,
+    fd: i32,
+    offset: u64,
+    start: u64,
+    len: u64,
+) -> Result<(), Errno> {
+    let mut buf = vec![0u8; PAGE_SIZE as usize * 16];
+    let mut done = 0u64;
+    while done < len {
+        let chunk = (len - done).min(buf.len() as u64) as usize;
+        let n = files.read_at(fd, offset + done, &mut buf[..chunk])?;
+        if n == 0 {
+            break; // past EOF: the rest stays zero
+        }
+        mem.write(start + done, &buf[..n])?;
+        done += n as u64;
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn manager() -> MemoryManager {
+        MemoryManager::new(Layout {
+            brk_base: 0x10000,
+            brk_max: 0x10000,
+            mmap_base: 0x40000,
+            limit: 0x100000,
+        })
+    }
+
+    #[test]
+    fn test_mmap_reuses_unmapped_hole() {
+        let mut mm = manager();
+        let mut mem = Vec::new();
+        let anon = MAP_PRIVATE | MAP_ANONYMOUS;
+        let a = mm.mmap(&mut mem, None, 0, 0x2000, PROT_READ, anon, -1, 0).unwrap();
+        let b = mm.mmap(&mut mem, None, 0, 0x1000, PROT_READ, anon, -1, 0).unwrap();
+        assert_eq!((a, b), (0x40000, 0x42000));
+        assert!(mem.size() >= 0x43000, "linear memory grew to cover mappings");
+
+        mem.write(a, &[0xaa; 16]).unwrap();
+        mm.munmap(a, 0x2000).unwrap();
+        let c = mm.mmap(&mut mem, None, 0, 0x1000, PROT_READ, anon, -1, 0).unwrap();
+        assert_eq!(c, a);
+        let mut buf = [0xffu8; 16];
+        mem.read(c, &mut buf).unwrap();
+        assert_eq!(buf, [0; 16], "recycled pages are zeroed");
+    }
+
+    #[test]
+    fn test_vec_memory_rejects_wrapping_ranges() {
+        let mut mem = vec![0u8; 0x1000];
+        let mut buf = [0u8; 16];
+        assert_eq!(mem.read(u64::MAX - 7, &mut buf), Err(EFAULT));
+        assert_eq!(mem.write(u64::MAX - 7, &buf), Err(EFAULT));
+        assert_eq!(mem.fill(0x10, u64::MAX, 0), Err(EFAULT));
+        assert_eq!(mem.read(0xff8, &mut buf), Err(EFAULT));
+        mem.write(0xff0, &[1; 16]).unwrap();
+    }
+
+    #[test]
+    fn test_mprotect_splits_and_requires_mapping() {
+        let mut mm = manager();
+        let mut mem = Vec::new();
+        let a = mm
+            .mmap(&mut mem, None, 0, 0x3000, PROT_NONE, MAP_PRIVATE | MAP_ANONYMOUS, -1, 0)
+            .unwrap();
+        mm.mprotect(a + 0x1000, 0x1000, PROT_READ | PROT_WRITE).unwrap();
+        let prots: Vec<u32> = mm.mappings().map(|m| m.prot).collect();
+        assert_eq!(prots, vec![PROT_NONE, PROT_READ | PROT_WRITE, PROT_NONE]);
+        assert_eq!(mm.mprotect(a + 0x3000, 0x1000, PROT_READ), Err(ENOMEM));
+    }
+
+    #[test]
+    fn test_brk_grows_within_limits() {
+        let mut mm = manager();
+        let mut mem = Vec::new();
+        assert_eq!(mm.brk(&mut mem, 0), 0x10000);
+        assert_eq!(mm.brk(&mut mem, 0x12345), 0x12345);
+        assert!(mem.size() >= 0x12345);
+        assert_eq!(mm.brk(&mut mem, 0x30000), 0x12345, "beyond brk_max fails");
+        assert_eq!(mm.mapped_pages(), 3);
+    }
+
+    #[test]
+    fn test_file_backed_mmap() {
+        struct OneFile(Vec<u8>);
+        impl FileSource for OneFile {
+            fn read_at(&self, _fd: i32, offset: u64, buf: &mut [u8]) -> Result<usize, Errno> {
+                let data = self.0.get(offset as usize..).unwrap_or(&[]);
+                let n = data.len().min(buf.len());
+                buf[..n].copy_from_slice(&data[..n]);
+                Ok(n)
+            }
+        }
+        let file = OneFile((0..0x1800u32).map(|i| i as u8).collect());
+        let mut mm = manager();
+        let mut mem = Vec::new();
+        let a = mm
+            .mmap(&mut mem, Some(&file), 0, 0x2000, PROT_READ, MAP_PRIVATE, 3, 0x1000)
+            .unwrap();
+        let mut buf = [0u8; 4];
+        mem.read(a, &mut buf).unwrap();
+        assert_eq!(buf, [0, 1, 2, 3]);
+        mem.read(a + 0x900, &mut buf).unwrap();
+        assert_eq!(buf, [0; 4], "bytes past EOF are zero");
+        assert_eq!(mm.find(a).unwrap().backing, Backing::File { fd: 3, offset: 0x1000 });
+    }
+}
@@ -0,0 +1,601 @@
+// net.rs - Socket syscalls
+//
+// socket, bind, listen, accept, connect, sendto/recvfrom and shutdown for
+// AF_INET and AF_INET6 stream and datagram sockets, plus read, write and
+// close on the descriptors they return. The guest-facing state machine
+// (unopened -> stream, datagram or listener) lives here; moving bytes is
+// left to a `Network` backend; this crate ships `HostNetwork`, on std::net
+// sockets. It runs guests on wasmtime, so it is native only and has no
+// browser backend: in the browser, guests run in friscy.wasm and its socket
+// syscalls (runtime/network.hpp) reach the network through
+// network_bridge.js, over WebTransport to the proxy in proxy/. Another
+// transport, such as a WebSocket tunnel, plugs in as a `Network`.
+//
+// A stream socket only reaches the backend at connect or listen, so a
+// backend never sees a half-configured socket. Datagram sockets open at
+// bind, connect or their first sendto. Descriptors other than sockets are
+// not this module's: `owns` tells the syscall layer which ones are, and
+// the syscall layer picks the descriptor a new socket gets.
+//
+// For poll, `Net::poll` asks the backend whether a socket has something to
+// read (`Socket::readable`) or a listener a connection to accept
+// (`Listener::pending`). Connected sockets always count as writable: the
+// backend has no way to tell when a send would wait.
+
+use crate::mm::GuestMemory;
+use crate::poll::{POLLERR, POLLHUP, POLLIN, POLLOUT};
+use crate::syscalls::{
+    Errno, EACCES, EADDRINUSE, EADDRNOTAVAIL, EAFNOSUPPORT, EAGAIN, EBADF, ECONNREFUSED, ECONNRESET,
+    EDESTADDRREQ, EINTR, EINVAL, EIO, EISCONN, ENOTCONN, EOPNOTSUPP, EPIPE, EPROTONOSUPPORT, ETIMEDOUT,
+};
+use std::collections::BTreeMap;
+use std::io::{self, Read, Write};
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr, Shutdown, SocketAddr, TcpListener, TcpStream, UdpSocket};
+
+pub const AF_INET: u16 = 2;
+pub const AF_INET6: u16 = 10;
+pub const SOCK_STREAM: u64 = 1;
+pub const SOCK_DGRAM: u64 = 2;
+pub const SOCK_NONBLOCK: u64 = 0o4000;
+
+/// Largest transfer made in one call; the guest sees a short read or write
+const MAX_IO: usize = 1 << 20;
+
+/// A way to reach the network
+pub trait Network: Send {
+    /// Open a stream connection to `addr`
+    fn connect(&mut self, addr: SocketAddr) -> Result<Box<dyn Socket>, Errno>;
+    /// Open a datagram socket bound to `addr`
+    fn bind_datagram(&mut self, addr: SocketAddr) -> Result<Box<dyn Socket>, Errno>;
+    /// Accept stream connections on `addr`
+    fn listen(&mut self, addr: SocketAddr, backlog: u32) -> Result<Box<dyn Listener>, Errno>;
+}
+
+/// A connected stream or a datagram socket
+pub trait Socket: Send {
+    /// Send `buf`; datagram sockets always get a destination
+    fn send(&mut self, buf: &[u8], to: Option<SocketAddr>) -> Result<usize, Errno>;
+    /// Receive into `buf`; datagram sockets also return the sender
+    fn recv(&mut self, buf: &mut [u8]) -> Result<(usize, Option<SocketAddr>), Errno>;
+    fn local_addr(&self) -> Result<SocketAddr, Errno>;
+    /// The peer of a stream; datagram peers are tracked by the caller
+    fn peer_addr(&self) -> Result<SocketAddr, Errno>;
+    fn shutdown(&mut self, how: Shutdown) -> Result<(), Errno>;
+    /// Make calls that would block fail with EAGAIN instead
+    fn set_nonblocking(&mut self, nonblocking: bool) -> Result<(), Errno>;
+    /// Whether a recv would not wait: data or the end of the stream has
+    /// come. Called with the socket non-blocking.
+    fn readable(&mut self) -> Result<bool, Errno>;
+}
+
+/// A listening stream socket
+pub trait Listener: Send {
+    /// The next connection and its peer
+    fn accept(&mut self) -> Result<(Box<dyn Socket>, SocketAddr), Errno>;
+    fn local_addr(&self) -> Result<SocketAddr, Errno>;
+    fn set_nonblocking(&mut self, nonblocking: bool) -> Result<(), Errno>;
+    /// Whether a connection is waiting to be accepted. Called with the
+    /// listener non-blocking.
+    fn pending(&mut self) -> Result<bool, Errno>;
+}
+
+/// `Network` on the host's own sockets. The listen backlog is the host's.
+pub struct HostNetwork;
+
+impl Network for HostNetwork {
+    fn connect(&mut self, addr: SocketAddr) -> Result<Box<dyn Socket>, Errno> {
+        Ok(Box::new(TcpStream::connect(addr).map_err(errno)?))
+    }
+
+    fn bind_datagram(&mut self, addr: SocketAddr) -> Result<Box<dyn Socket>, Errno> {
+        Ok(Box::new(UdpSocket::bind(addr).map_err(errno)?))
+    }
+
+    fn listen(&mut self, addr: SocketAddr, _backlog: u32) -> Result<Box<dyn Listener>, Errno> {
+        Ok(Box::new(HostListener { listener: TcpListener::bind(addr).map_err(errno)?, pending: None }))
+    }
+}
+
+impl Socket for TcpStream {
+    fn send(&mut self, buf: &[u8], _to: Option<SocketAddr>) -> Result<usize, Errno> {
+        self.write(buf).map_err(errno)
+    }
+
+    fn recv(&mut self, buf: &mut [u8]) -> Result<(usize, Option<SocketAddr>), Errno> {
+        Ok((self.read(buf).map_err(errno)?, None))
+    }
+
+    fn local_addr(&self) -> Result<SocketAddr, Errno> {
+        TcpStream::local_addr(self).map_err(errno)
+    }
+
+    fn peer_addr(&self) -> Result<SocketAddr, Errno> {
+        TcpStream::peer_addr(self).map_err(errno)
+    }
+
+    fn shutdown(&mut self, how: Shutdown) -> Result<(), Errno> {
+        TcpStream::shutdown(self, how).map_err(errno)
+    }
+
+    fn set_nonblocking(&mut self, nonblocking: bool) -> Result<(), Errno> {
+        TcpStream::set_nonblocking(self, nonblocking).map_err(errno)
+    }
+
+    fn readable(&mut self) -> Result<bool, Errno> {
+        peeked(self.peek(&mut [0]))
+    }
+}
+
+impl Socket for UdpSocket {
+    fn send(&mut self, buf: &[u8], to: Option<SocketAddr>) -> Result<usize, Errno> {
+        self.send_to(buf, to.ok_or(EDESTADDRREQ)?).map_err(errno)
+    }
+
+    fn recv(&mut self, buf: &mut [u8]) -> Result<(usize, Option<SocketAddr>), Errno> {
+        let (n, from) = self.recv_from(buf).map_err(errno)?;
+        Ok((n, Some(from)))
+    }
+
+    fn local_addr(&self) -> Result<SocketAddr, Errno> {
+        UdpSocket::local_addr(self).map_err(errno)
+    }
+
+    fn peer_addr(&self) -> Result<SocketAddr, Errno> {
+        Err(ENOTCONN)
+    }
+
+    fn shutdown(&mut self, _how: Shutdown) -> Result<(), Errno> {
+        Ok(())
+    }
+
+    fn set_nonblocking(&mut self, nonblocking: bool) -> Result<(), Errno> {
+        UdpSocket::set_nonblocking(self, nonblocking).map_err(errno)
+    }
+
+    fn readable(&mut self) -> Result<bool, Errno> {
+        peeked(self.peek_from(&mut [0]))
+    }
+}
+
+/// A host listener. std cannot ask whether a connection is waiting
+/// without accepting it, so `pending` accepts it early and keeps it for
+/// the next `accept`.
+struct HostListener {
+    listener: TcpListener,
+    pending: Option<(TcpStream, SocketAddr)>,
+}
+
+impl Listener for HostListener {
+    fn accept(&mut self) -> Result<(Box<dyn Socket>, SocketAddr), Errno> {
+        let (stream, peer) = match self.pending.take() {
+            Some(pending) => pending,
+            None => self.listener.accept().map_err(errno)?,
+        };
+        // Accepted sockets start blocking, as on Linux
+        stream.set_nonblocking(false).map_err(errno)?;
+        Ok((Box::new(stream), peer))
+    }
+
+    fn local_addr(&self) -> Result<SocketAddr, Errno> {
+        self.listener.local_addr().map_err(errno)
+    }
+
+    fn set_nonblocking(&mut self, nonblocking: bool) -> Result<(), Errno> {
+        self.listener.set_nonblocking(nonblocking).map_err(errno)
+    }
+
+    fn pending(&mut self) -> Result<bool, Errno> {
+        if self.pending.is_none() {
+            match self.listener.accept() {
+                Ok(pending) => self.pending = Some(pending),
+                Err(e) if e.kind() == io::ErrorKind::WouldBlock => return Ok(false),
+                Err(e) => return Err(errno(e)),
+            }
+        }
+        Ok(true)
+    }
+}
+
+/// Whether a non-blocking peek found something rather than nothing yet
+fn peeked<T>(peek: io::Result<T>) -> Result<bool, Errno> {
+    match peek {
+        Ok(_) => Ok(true),
+        Err(e) if e.kind() == io::ErrorKind::WouldBlock => Ok(false),
+        Err(e) => Err(errno(e)),
+    }
+}
+
+/// Host I/O errors as Linux errnos (by kind: host errno numbers vary)
+fn errno(e: io::Error) -> Errno {
+    match e.kind() {
+        io::ErrorKind::WouldBlock => EAGAIN,
+        io::ErrorKind::Interrupted => EINTR,
+        io::ErrorKind::PermissionDenied => EACCES,
+        io::ErrorKind::BrokenPipe => EPIPE,
+        io::ErrorKind::AddrInUse => EADDRINUSE,
+        io::ErrorKind::AddrNotAvailable => EADDRNOTAVAIL,
+        io::ErrorKind::ConnectionReset | io::ErrorKind::ConnectionAborted => ECONNRESET,
+        io::ErrorKind::ConnectionRefused => ECONNREFUSED,
+        io::ErrorKind::NotConnected => ENOTCONN,
+        io::ErrorKind::TimedOut => ETIMEDOUT,
+        io::ErrorKind::InvalidInput => EINVAL,
+        _ => EIO,
+    }
+}
+
+/// Where a guest socket is in its life
+enum State {
+    /// Nothing on the backend yet; `bind` only records the address
+    Unopened { bound: Option<SocketAddr> },
+    Stream(Box<dyn Socket>),
+    Datagram { socket: Box<dyn Socket>, peer: Option<SocketAddr> },
+    Listener(Box<dyn Listener>),
+}
+
+struct GuestSocket {
+    family: u16,
+    stream: bool,
+    nonblocking: bool,
+    state: State,
+}
+
+impl GuestSocket {
+    /// The unspecified address of this socket's family, port 0
+    fn any_addr(&self) -> SocketAddr {
+        match self.family {
+            AF_INET6 => SocketAddr::new(IpAddr::V6(Ipv6Addr::UNSPECIFIED), 0),
+            _ => SocketAddr::new(IpAddr::V4(Ipv4Addr::UNSPECIFIED), 0),
+        }
+    }
+}
+
+/// The process's sockets
+pub struct Net {
+    backend: Box<dyn Network>,
+    sockets: BTreeMap<i32, GuestSocket>,
+}
+
+impl Net {
+    pub fn new(backend: Box<dyn Network>) -> Self {
+        Self {
+            backend,
+            sockets: BTreeMap::new(),
+        }
+    }
+
+    /// Whether `fd` is a socket
+    pub fn owns(&self, fd: u64) -> bool {
+        self.sockets.contains_key(&(fd as i32))
+    }
+
+    fn get(&mut self, fd: u64) -> Result<&mut GuestSocket, Errno> {
+        self.sockets.get_mut(&(fd as i32)).ok_or(EBADF)
+    }
+
+    fn insert(&mut self, fd: i32, socket: GuestSocket) -> u64 {
+        self.sockets.insert(fd, socket);
+        fd as u64
+    }
+
+    /// Open a socket as descriptor `new_fd`, which the caller found free
+    pub fn socket(&mut self, new_fd: i32, domain: u64, kind: u64, _protocol: u64) -> Result<u64, Errno> {
+        let family = match domain as u16 {
+            AF_INET => AF_INET,
+            AF_INET6 => AF_INET6,
+            _ => return Err(EAFNOSUPPORT),
+        };
+        let stream = match kind & 0xf {
+            SOCK_STREAM => true,
+            SOCK_DGRAM => false,
+            _ => return Err(EPROTONOSUPPORT),
+        };
+        Ok(self.insert(new_fd, GuestSocket {
+            family,
+            stream,
+            nonblocking: kind & SOCK_NONBLOCK != 0,
+            state: State::Unopened { bound: None },
+        }))
+    }
+
+    pub fn bind(&mut self, mem: &dyn GuestMemory, fd: u64, addr: u64, len: u64) -> Result<u64, Errno> {
+        let addr = read_sockaddr(mem, addr, len)?;
+        let backend = &mut self.backend;
+        let sock = self.sockets.get_mut(&(fd as i32)).ok_or(EBADF)?;
+        if !matches!(sock.state, State::Unopened { bound: None }) {
+            return Err(EINVAL);
+        }
+        sock.state = if sock.stream {
+            State::Unopened { bound: Some(addr) }
+        } else {
+            let mut socket = backend.bind_datagram(addr)?;
+            socket.set_nonblocking(sock.nonblocking)?;
+            State::Datagram { socket, peer: None }
+        };
+        Ok(0)
+    }
+
+    pub fn listen(&mut self, fd: u64, backlog: u64) -> Result<u64, Errno> {
+        let backend = &mut self.backend;
+        let sock = self.sockets.get_mut(&(fd as i32)).ok_or(EBADF)?;
+        if !sock.stream {
+            return Err(EOPNOTSUPP);
+        }
+        let State::Unopened { bound } = sock.state else {
+            return Err(EINVAL);
+        };
+        let mut listener = backend.listen(bound.unwrap_or(sock.any_addr()), backlog as u32)?;
+        listener.set_nonblocking(sock.nonblocking)?;
+        sock.state = State::Listener(listener);
+        Ok(0)
+    }
+
+    /// accept and accept4: SOCK_NONBLOCK in `flags` applies to the new
+    /// socket, descriptor `new_fd`
+    pub fn accept(
+        &mut self,
+        mem: &mut dyn GuestMemory,
+        fd: u64,
+        addr: u64,
+        len: u64,
+        flags: u64,
+        new_fd: i32,
+    ) -> Result<u64, Errno> {
+        let sock = self.get(fd)?;
+        let State::Listener(listener) = &mut sock.state else {
+            return Err(EINVAL);
+        };
+        let (mut socket, peer) = listener.accept()?;
+        let nonblocking = flags & SOCK_NONBLOCK != 0;
+        socket.set_nonblocking(nonblocking)?;
+        let accepted = GuestSocket {
+            family: sock.family,
+            stream: true,
+            nonblocking,
+            state: State::Stream(socket),
+        };
+        write_sockaddr(mem, addr, len, peer)?;
+        Ok(self.insert(new_fd, accepted))
+    }
+
+    pub fn connect(&mut self, mem: &dyn GuestMemory, fd: u64, addr: u64, len: u64) -> Result<u64, Errno> {
+        let addr = read_sockaddr(mem, addr, len)?;
+        let backend = &mut self.backend;
+        let sock = self.sockets.get_mut(&(fd as i32)).ok_or(EBADF)?;
+        if sock.stream {
+            if !matches!(sock.state, State::Unopened { .. }) {
+                return Err(EISCONN);
+            }
+            let mut socket = backend.connect(addr)?;
+            socket.set_nonblocking(sock.nonblocking)?;
+            sock.state = State::Stream(socket);
+            return Ok(0);
+        }
+        // Datagrams: connect sets the default destination
+        open_datagram(backend.as_mut(), sock)?;
+        if let State::Datagram { peer, .. } = &mut sock.state {
+            *peer = Some(addr);
+        }
+        Ok(0)
+    }
+
+    pub fn getsockname(&mut self, mem: &mut dyn GuestMemory, fd: u64, addr: u64, len: u64) -> Result<u64, Errno> {
+        let sock = self.get(fd)?;
+        let local = match &sock.state {
+            State::Unopened { bound } => bound.unwrap_or(sock.any_addr()),
+            State::Stream(socket) | State::Datagram { socket, .. } => socket.local_addr()?,
+            State::Listener(listener) => listener.local_addr()?,
+        };
+        write_sockaddr(mem, addr, len, local)?;
+        Ok(0)
+    }
+
+    pub fn getpeername(&mut self, mem: &mut dyn GuestMemory, fd: u64, addr: u64, len: u64) -> Result<u64, Errno> {
+        let peer = match &self.get(fd)?.state {
+            State::Stream(socket) => socket.peer_addr()?,
+            State::Datagram { peer: Some(peer), .. } => *peer,
+            _ => return Err(ENOTCONN),
+        };
+        write_sockaddr(mem, addr, len, peer)?;
+        Ok(0)
+    }
+
+    /// sendto, and write on a socket (`dest` = 0)
+    pub fn sendto(
+        &mut self,
+        mem: &dyn GuestMemory,
+        fd: u64,
+        buf: u64,
+        len: u64,
+        dest: u64,
+        dest_len: u64,
+    ) -> Result<u64, Errno> {
+        let to = if dest != 0 { Some(read_sockaddr(mem, dest, dest_len)?) } else { None };
+        let mut data = vec![0u8; (len as usize).min(MAX_IO)];
+        mem.read(buf, &mut data)?;
+        let backend = &mut self.backend;
+        let sock = self.sockets.get_mut(&(fd as i32)).ok_or(EBADF)?;
+        if !sock.stream {
+            open_datagram(backend.as_mut(), sock)?;
+        }
+        let sent = match &mut sock.state {
+            State::Stream(socket) => socket.send(&data, None)?,
+            State::Datagram { socket, peer } => socket.send(&data, Some(to.or(*peer).ok_or(EDESTADDRREQ)?))?,
+            _ => return Err(ENOTCONN),
+        };
+        Ok(sent as u64)
+    }
+
+    /// recvfrom, and read on a socket (`src` = 0)
+    pub fn recvfrom(
+        &mut self,
+        mem: &mut dyn GuestMemory,
+        fd: u64,
+        buf: u64,
+        len: u64,
+        src: u64,
+        src_len: u64,
+    ) -> Result<u64, Errno> {
+        let mut data = vec![0u8; (len as usize).min(MAX_IO)];
+        let (n, from) = match &mut self.get(fd)?.state {
+            State::Stream(socket) | State::Datagram { socket, .. } => socket.recv(&mut data)?,
+            _ => return Err(ENOTCONN),
+        };
+        mem.write(buf, &data[..n])?;
+        if let Some(from) = from {
+            write_sockaddr(mem, src, src_len, from)?;
+        }
+        Ok(n as u64)
+    }
+
+    pub fn shutdown(&mut self, fd: u64, how: u64) -> Result<u64, Errno> {
+        let how = match how {
+            0 => Shutdown::Read,
+            1 => Shutdown::Write,
+            2 => Shutdown::Both,
+            _ => return Err(EINVAL),
+        };
+        match &mut self.get(fd)?.state {
+            State::Stream(socket) => socket.shutdown(how)?,
+            _ => return Err(ENOTCONN),
+        }
+        Ok(0)
+    }
+
+    /// The poll conditions of socket `fd`. A stream socket that is not
+    /// connected is hung up, as on Linux; a backend error is POLLERR.
+    pub fn poll(&mut self, fd: u64) -> Result<u32, Errno> {
+        let sock = self.get(fd)?;
+        let nonblocking = sock.nonblocking;
+        let events = match &mut sock.state {
+            State::Unopened { .. } => return Ok(if sock.stream { POLLOUT | POLLHUP } else { POLLOUT }),
+            State::Stream(socket) | State::Datagram { socket, .. } => {
+                socket.set_nonblocking(true)?;
+                let readable = socket.readable();
+                socket.set_nonblocking(nonblocking)?;
+                readable.map(|readable| if readable { POLLIN } else { 0 } | POLLOUT)
+            }
+            State::Listener(listener) => {
+                listener.set_nonblocking(true)?;
+                let pending = listener.pending();
+                listener.set_nonblocking(nonblocking)?;
+                pending.map(|pending| if pending { POLLIN } else { 0 })
+            }
+        };
+        Ok(events.unwrap_or(POLLIN | POLLERR))
+    }
+
+    pub fn close(&mut self, fd: u64) -> Result<u64, Errno> {
+        self.sockets.remove(&(fd as i32)).ok_or(EBADF)?;
+        Ok(0)
+    }
+}
+
+/// Give an unopened datagram socket a backend socket on the wildcard address
+fn open_datagram(backend: &mut dyn Network, sock: &mut GuestSocket) -> Result<(), Errno> {
+    if let State::Unopened { .. } = sock.state {
+        let mut socket = backend.bind_datagram(sock.any_addr())?;
+        socket.set_nonblocking(sock.nonblocking)?;
+        sock.state = State::Datagram { socket, peer: None };
+    }
+    Ok(())
+}
+
+/// A guest sockaddr_in or sockaddr_in6
+fn read_sockaddr(mem: &dyn GuestMemory, addr: u64, len: u64) -> Result<SocketAddr, Errno> {
+    let mut buf = [0u8; 28];
+    let len = (len as usize).min(buf.len());
+    if len < 2 {
+        return Err(EINVAL);
+    }
+    mem.read(addr, &mut buf[..len])?;
+    let port = u16::from_be_bytes([buf[2], buf[3]]);
+    match u16::from_le_bytes([buf[0], buf[1]]) {
+        AF_INET if len >= 16 => {
+            let ip: [u8; 4] = buf[4..8].try_into().unwrap();
+            Ok(SocketAddr::new(IpAddr::from(ip), port))
+        }
+        AF_INET6 if len >= 28 => {
+            let ip: [u8; 16] = buf[8..24].try_into().unwrap();
+            Ok(SocketAddr::new(IpAddr::from(ip), port))
+        }
+        AF_INET | AF_INET6 => Err(EINVAL),
+        _ => Err(EAFNOSUPPORT),
+    }
+}
+
+/// Store `value` at a guest (sockaddr, socklen_t *) pair, truncating to the
+/// buffer and reporting the full length; nothing if `addr` is null
+fn write_sockaddr(mem: &mut dyn GuestMemory, addr: u64, len_ptr: u64, value: SocketAddr) -> Result<(), Errno> {
+    if addr == 0 {
+        return Ok(());
+    }
+    let mut out = Vec::with_capacity(28);
+    match value {
+        SocketAddr::V4(v4) => {
+            out.extend(AF_INET.to_le_bytes());
+            out.extend(v4.port().to_be_bytes());
+            out.extend(v4.ip().octets());
+            out.extend([0u8; 8]);
+        }
+        SocketAddr::V6(v6) => {
+            out.extend(AF_INET6.to_le_bytes());
+            out.extend(v6.port().to_be_bytes());
+            out.extend(v6.flowinfo().to_be_bytes());
+            out.extend(v6.ip().octets());
+            out.extend(v6.scope_id().to_le_bytes());
+        }
+    }
+    let mut len = [0u8; 4];
+    mem.read(len_ptr, &mut len)?;
+    let room = u32::from_le_bytes(len) as usize;
+    mem.write(addr, &out[..room.min(out.len())])?;
+    mem.write(len_ptr, &(out.len() as u32).to_le_bytes())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::syscalls::{nr, Kernel};
+    use crate::Layout;
+
+    #[test]
+    fn test_tcp_loopback() {
+        let mut kernel = Kernel::new(Layout {
+            brk_base: 0x10000,
+            brk_max: 0x10000,
+            mmap_base: 0x20000,
+            limit: 0x100000,
+        });
+        let mut mem = vec![0u8; 0x4000];
+        let mut call = |mem: &mut Vec<u8>, nr: u64, args: [u64; 6]| kernel.dispatch(mem, nr, args);
+
+        // Listen on 127.0.0.1, port chosen by the host
+        let server = call(&mut mem, nr::SOCKET, [AF_INET as u64, SOCK_STREAM, 0, 0, 0, 0]);
+        assert_eq!(server, 3);
+        mem[0x100..0x110].copy_from_slice(&[2, 0, 0, 0, 127, 0, 0, 1, 0, 0, 0, 0, 0, 0, 0, 0]);
+        assert_eq!(call(&mut mem, nr::BIND, [3, 0x100, 16, 0, 0, 0]), 0);
+        assert_eq!(call(&mut mem, nr::LISTEN, [3, 8, 0, 0, 0, 0]), 0);
+        mem[0x200..0x204].copy_from_slice(&16u32.to_le_bytes());
+        assert_eq!(call(&mut mem, nr::GETSOCKNAME, [3, 0x100, 0x200, 0, 0, 0]), 0);
+        assert_ne!(&mem[0x102..0x104], &[0, 0]);
+
+        // Connect, accept, and pass bytes both ways
+        assert_eq!(call(&mut mem, nr::SOCKET, [AF_INET as u64, SOCK_STREAM, 0, 0, 0, 0]), 4);
+        assert_eq!(call(&mut mem, nr::CONNECT, [4, 0x100, 16, 0, 0, 0]), 0);
+        assert_eq!(call(&mut mem, nr::ACCEPT4, [3, 0x300, 0x200, 0, 0, 0]), 5);
+        assert_eq!(&mem[0x304..0x308], &[127, 0, 0, 1]);
+
+        mem[0x400..0x405].copy_from_slice(b"hello");
+        assert_eq!(call(&mut mem, nr::WRITE, [4, 0x400, 5, 0, 0, 0]), 5);
+        assert_eq!(call(&mut mem, nr::RECVFROM, [5, 0x500, 16, 0, 0, 0]), 5);
+        assert_eq!(&mem[0x500..0x505], b"hello");
+        assert_eq!(call(&mut mem, nr::SENDTO, [5, 0x400, 2, 0, 0, 0]), 2);
+        assert_eq!(call(&mut mem, nr::READ, [4, 0x600, 16, 0, 0, 0]), 2);
+
+        // EOF after the peer closes; the descriptor is gone after close
+        assert_eq!(call(&mut mem, nr::CLOSE, [5, 0, 0, 0, 0, 0]), 0);
+        assert_eq!(call(&mut mem, nr::READ, [4, 0x600, 16, 0, 0, 0]), 0);
+        assert_eq!(call(&mut mem, nr::SOCKET, [1, SOCK_STREAM, 0, 0, 0, 0]), EAFNOSUPPORT.as_ret());
+        assert!(!kernel.net.owns(5));
+    }
+}
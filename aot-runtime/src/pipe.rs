@@ -0,0 +1,433 @@
+// pipe.rs - Flow-controlled byte pipes
+//
+// A `Pipe` is a bounded ring of bytes between a writer and a reader, which
+// may be on different threads: the host and the guest, or two guest
+// descriptors. Neither side can get further ahead of the other than the
+// ring holds. A write into a full pipe, or a read from an empty one,
+// either waits for the other side or, when the caller cannot block
+// (O_NONBLOCK, or a host that cannot, such as a browser's main thread),
+// takes what it can and fails with EAGAIN if that is nothing. `readable`
+// and `writable` are the conditions ppoll, pselect6 and epoll report
+// (`poll.rs`), and a host can use them to decide when to resume the guest.
+//
+// The terminal (`pty.rs`) reads keyboard input from one pipe and writes
+// output to another, which a console thread drains into the host's
+// display, so a guest printing faster than the display keeps up is held
+// back instead of queueing without bound. pipe2 makes one between two
+// guest descriptors (`Pipes`). The ends close on their own: the reader
+// sees end of file once the writer has closed and the ring is empty, and
+// writing with no reader left is EPIPE, and SIGPIPE for the guest.
+
+use crate::mm::GuestMemory;
+use crate::poll::{POLLERR, POLLHUP, POLLIN, POLLOUT};
+use crate::process::{Process, SIGPIPE};
+use crate::pty::FIONREAD;
+use crate::syscalls::{Errno, EAGAIN, EBADF, EINVAL, ENOTTY, EPIPE};
+use crate::vfs::{Stat, O_RDONLY, O_WRONLY};
+use std::collections::{BTreeMap, VecDeque};
+use std::sync::{Arc, Condvar, Mutex};
+use std::time::Duration;
+
+/// Bytes a pipe2 pipe holds, as on Linux
+pub const PIPE_CAPACITY: usize = 64 * 1024;
+
+/// fcntl commands, and the flags pipe2 and F_SETFL take
+pub(crate) const F_GETFD: u64 = 1;
+pub(crate) const F_SETFD: u64 = 2;
+pub(crate) const F_GETFL: u64 = 3;
+pub(crate) const F_SETFL: u64 = 4;
+pub(crate) const O_NONBLOCK: u64 = 0o4000;
+pub(crate) const O_CLOEXEC: u64 = 0o2000000;
+
+/// st_mode of a pipe: a FIFO only its owner uses
+const S_IFIFO_MODE: u32 = 0o010600;
+
+struct Ring {
+    bytes: VecDeque<u8>,
+    capacity: usize,
+    /// The writer is done: the reader gets end of file after the bytes
+    writer_closed: bool,
+    /// Nobody will read: writes fail
+    reader_closed: bool,
+}
+
+/// A bounded byte pipe; clones share it
+#[derive(Clone)]
+pub struct Pipe(Arc<(Mutex<Ring>, Condvar)>);
+
+impl Pipe {
+    pub fn new(capacity: usize) -> Self {
+        let ring = Ring { bytes: VecDeque::new(), capacity, writer_closed: false, reader_closed: false };
+        Self(Arc::new((Mutex::new(ring), Condvar::new())))
+    }
+
+    /// Queue as much of `data` as fits, or with `block` all of it, waiting
+    /// for the reader to make room. EAGAIN if nothing fit and it may not
+    /// wait; EPIPE if the reader has closed before any of it was queued.
+    pub fn write(&self, mut data: &[u8], block: bool) -> Result<usize, Errno> {
+        let (ring, cond) = &*self.0;
+        let mut guard = ring.lock().unwrap();
+        let mut done = 0;
+        loop {
+            if guard.reader_closed {
+                return if done > 0 { Ok(done) } else { Err(EPIPE) };
+            }
+            let n = data.len().min(guard.capacity - guard.bytes.len());
+            guard.bytes.extend(&data[..n]);
+            data = &data[n..];
+            done += n;
+            cond.notify_all();
+            if data.is_empty() || !block {
+                return if done > 0 || data.is_empty() { Ok(done) } else { Err(EAGAIN) };
+            }
+            guard = cond.wait(guard).unwrap();
+        }
+    }
+
+    /// Take up to `buf.len()` bytes; 0 at end of file. With `block`, wait
+    /// for some to come, else EAGAIN if there are none.
+    pub fn read(&self, buf: &mut [u8], block: bool) -> Result<usize, Errno> {
+        let (ring, cond) = &*self.0;
+        let mut guard = ring.lock().unwrap();
+        while guard.bytes.is_empty() && !guard.writer_closed && !buf.is_empty() {
+            if !block {
+                return Err(EAGAIN);
+            }
+            guard = cond.wait(guard).unwrap();
+        }
+        let n = buf.len().min(guard.bytes.len());
+        for (dst, src) in buf.iter_mut().zip(guard.bytes.drain(..n)) {
+            *dst = src;
+        }
+        cond.notify_all();
+        Ok(n)
+    }
+
+    /// Copy queued bytes without taking them, so a consumer can `consume`
+    /// them once it has passed them on; 0 only at end of file
+    pub fn peek(&self, buf: &mut [u8]) -> usize {
+        let (ring, _) = &*self.0;
+        let ring = ring.lock().unwrap();
+        let n = buf.len().min(ring.bytes.len());
+        for (dst, src) in buf.iter_mut().zip(ring.bytes.iter()) {
+            *dst = *src;
+        }
+        n
+    }
+
+    /// Take `n` bytes that `peek` returned
+    pub fn consume(&self, n: usize) {
+        let (ring, cond) = &*self.0;
+        let mut ring = ring.lock().unwrap();
+        let n = n.min(ring.bytes.len());
+        ring.bytes.drain(..n);
+        cond.notify_all();
+    }
+
+    /// Everything queued, and whether the writer has closed
+    pub fn drain(&self) -> (Vec<u8>, bool) {
+        let (ring, cond) = &*self.0;
+        let mut ring = ring.lock().unwrap();
+        let bytes = ring.bytes.drain(..).collect();
+        cond.notify_all();
+        (bytes, ring.writer_closed)
+    }
+
+    /// Discard what is queued
+    pub fn clear(&self) {
+        let (ring, cond) = &*self.0;
+        ring.lock().unwrap().bytes.clear();
+        cond.notify_all();
+    }
+
+    /// End the writes: once the reader has what is queued, it sees EOF
+    pub fn close_write(&self) {
+        let (ring, cond) = &*self.0;
+        ring.lock().unwrap().writer_closed = true;
+        cond.notify_all();
+    }
+
+    /// Stop reading: queued bytes are dropped and writes fail
+    pub fn close_read(&self) {
+        let (ring, cond) = &*self.0;
+        let mut ring = ring.lock().unwrap();
+        ring.reader_closed = true;
+        ring.bytes.clear();
+        cond.notify_all();
+    }
+
+    /// Bytes queued
+    pub fn len(&self) -> usize {
+        self.0 .0.lock().unwrap().bytes.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    /// Room for more bytes
+    pub fn space(&self) -> usize {
+        let ring = self.0 .0.lock().unwrap();
+        ring.capacity - ring.bytes.len()
+    }
+
+    /// A read would not wait: there are bytes, or the writer has closed
+    pub fn readable(&self) -> bool {
+        let ring = self.0 .0.lock().unwrap();
+        !ring.bytes.is_empty() || ring.writer_closed
+    }
+
+    /// A write would not wait: there is room, or the reader has closed
+    pub fn writable(&self) -> bool {
+        let ring = self.0 .0.lock().unwrap();
+        ring.bytes.len() < ring.capacity || ring.reader_closed
+    }
+
+    /// The writer has closed
+    pub fn writer_closed(&self) -> bool {
+        self.0 .0.lock().unwrap().writer_closed
+    }
+
+    /// The reader has closed
+    pub fn reader_closed(&self) -> bool {
+        self.0 .0.lock().unwrap().reader_closed
+    }
+
+    /// Wait until the pipe is readable, for at most `timeout`; false if
+    /// the time ran out
+    pub fn wait_readable(&self, timeout: Option<Duration>) -> bool {
+        let (ring, cond) = &*self.0;
+        let ring = ring.lock().unwrap();
+        let idle = |ring: &mut Ring| ring.bytes.is_empty() && !ring.writer_closed;
+        match timeout {
+            None => drop(cond.wait_while(ring, idle).unwrap()),
+            Some(timeout) => return !cond.wait_timeout_while(ring, timeout, idle).unwrap().1.timed_out(),
+        }
+        true
+    }
+
+    /// Wait until the reader has taken everything, or closed
+    pub fn wait_empty(&self) {
+        let (ring, cond) = &*self.0;
+        let ring = ring.lock().unwrap();
+        drop(cond.wait_while(ring, |ring| !ring.bytes.is_empty() && !ring.reader_closed).unwrap());
+    }
+}
+
+/// One end of a guest pipe
+struct End {
+    pipe: Pipe,
+    write: bool,
+    nonblocking: bool,
+}
+
+/// Guest descriptors on pipes made by pipe2
+#[derive(Default)]
+pub struct Pipes {
+    ends: BTreeMap<i32, End>,
+}
+
+impl Pipes {
+    /// Whether `fd` is a pipe end
+    pub fn owns(&self, fd: u64) -> bool {
+        self.ends.contains_key(&(fd as i32))
+    }
+
+    fn end(&self, fd: u64) -> Result<&End, Errno> {
+        self.ends.get(&(fd as i32)).ok_or(EBADF)
+    }
+
+    /// pipe2: a new pipe read through `read_fd` and written through
+    /// `write_fd`, stored to the int[2] at `fds`
+    pub fn pipe2(&mut self, mem: &mut dyn GuestMemory, fds: u64, flags: u64, read_fd: i32, write_fd: i32) -> Result<u64, Errno> {
+        if flags & !(O_NONBLOCK | O_CLOEXEC) != 0 {
+            return Err(EINVAL);
+        }
+        let mut out = [0u8; 8];
+        out[..4].copy_from_slice(&read_fd.to_le_bytes());
+        out[4..].copy_from_slice(&write_fd.to_le_bytes());
+        mem.write(fds, &out)?;
+        let pipe = Pipe::new(PIPE_CAPACITY);
+        let nonblocking = flags & O_NONBLOCK != 0;
+        self.ends.insert(read_fd, End { pipe: pipe.clone(), write: false, nonblocking });
+        self.ends.insert(write_fd, End { pipe, write: true, nonblocking });
+        Ok(0)
+    }
+
+    /// read; waits for a writer only if the descriptor and the host allow
+    pub fn read(&mut self, mem: &mut dyn GuestMemory, fd: u64, buf: u64, count: u64, blocking: bool) -> Result<u64, Errno> {
+        let end = self.end(fd)?;
+        if end.write {
+            return Err(EBADF);
+        }
+        let mut data = vec![0u8; (count as usize).min(PIPE_CAPACITY)];
+        let n = end.pipe.read(&mut data, blocking && !end.nonblocking)?;
+        mem.write(buf, &data[..n])?;
+        Ok(n as u64)
+    }
+
+    /// write; with no reader left, EPIPE and SIGPIPE for `process`
+    pub fn write(
+        &mut self,
+        mem: &mut dyn GuestMemory,
+        process: &mut Process,
+        fd: u64,
+        buf: u64,
+        count: u64,
+        blocking: bool,
+    ) -> Result<u64, Errno> {
+        let end = self.end(fd)?;
+        if !end.write {
+            return Err(EBADF);
+        }
+        let mut data = vec![0u8; (count as usize).min(PIPE_CAPACITY)];
+        mem.read(buf, &mut data)?;
+        match end.pipe.write(&data, blocking && !end.nonblocking) {
+            Err(EPIPE) => {
+                process.signal(SIGPIPE);
+                Err(EPIPE)
+            }
+            result => Ok(result? as u64),
+        }
+    }
+
+    /// The poll conditions of `fd`: POLLHUP on the read end once the
+    /// writer has closed, POLLERR on the write end once the reader has
+    pub fn poll(&self, fd: u64) -> Result<u32, Errno> {
+        let end = self.end(fd)?;
+        let pipe = &end.pipe;
+        let events = if end.write {
+            (if pipe.writable() { POLLOUT } else { 0 }) | if pipe.reader_closed() { POLLERR } else { 0 }
+        } else {
+            (if pipe.readable() { POLLIN } else { 0 }) | if pipe.writer_closed() { POLLHUP } else { 0 }
+        };
+        Ok(events)
+    }
+
+    pub fn close(&mut self, fd: u64) -> Result<u64, Errno> {
+        let end = self.ends.remove(&(fd as i32)).ok_or(EBADF)?;
+        if end.write {
+            end.pipe.close_write();
+        } else {
+            end.pipe.close_read();
+        }
+        Ok(0)
+    }
+
+    pub fn fcntl(&mut self, fd: u64, cmd: u64, arg: u64) -> Result<u64, Errno> {
+        let end = self.ends.get_mut(&(fd as i32)).ok_or(EBADF)?;
+        match cmd {
+            F_GETFD | F_SETFD => Ok(0),
+            F_GETFL => Ok(if end.write { O_WRONLY } else { O_RDONLY } | if end.nonblocking { O_NONBLOCK } else { 0 }),
+            F_SETFL => {
+                end.nonblocking = arg & O_NONBLOCK != 0;
+                Ok(0)
+            }
+            _ => Err(EINVAL),
+        }
+    }
+
+    pub fn ioctl(&self, mem: &mut dyn GuestMemory, fd: u64, request: u64, arg: u64) -> Result<u64, Errno> {
+        let end = self.end(fd)?;
+        match request {
+            FIONREAD => mem.write(arg, &(end.pipe.len() as i32).to_le_bytes())?,
+            _ => return Err(ENOTTY),
+        }
+        Ok(0)
+    }
+
+    pub fn fstat(&self, mem: &mut dyn GuestMemory, fd: u64, statbuf: u64) -> Result<u64, Errno> {
+        self.end(fd)?;
+        let st = Stat { dev: 0, ino: fd, mode: S_IFIFO_MODE, nlink: 1, uid: 0, gid: 0, rdev: 0, size: 0, mtime: 0 };
+        mem.write(statbuf, &st.to_bytes())?;
+        Ok(0)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::syscalls::{nr, Kernel, ESPIPE};
+    use crate::Layout;
+
+    #[test]
+    fn test_backpressure() {
+        let pipe = Pipe::new(8);
+        assert_eq!(pipe.write(b"0123456789", false), Ok(8));
+        assert_eq!(pipe.write(b"x", false), Err(EAGAIN));
+        assert!(!pipe.writable());
+
+        // A blocking write finishes as the reader makes room
+        let reader = pipe.clone();
+        let drain = std::thread::spawn(move || {
+            let mut got = Vec::new();
+            let mut buf = [0u8; 3];
+            loop {
+                match reader.read(&mut buf, true) {
+                    Ok(0) => return got,
+                    Ok(n) => got.extend_from_slice(&buf[..n]),
+                    Err(e) => panic!("{e:?}"),
+                }
+            }
+        });
+        assert_eq!(pipe.write(b"89abcdef", true), Ok(8));
+        pipe.close_write();
+        assert_eq!(drain.join().unwrap(), b"0123456789abcdef");
+
+        let pipe = Pipe::new(8);
+        let mut buf = [0u8; 4];
+        assert_eq!(pipe.read(&mut buf, false), Err(EAGAIN));
+        pipe.close_read();
+        assert_eq!(pipe.write(b"x", true), Err(EPIPE));
+    }
+
+    #[test]
+    fn test_pipe2() {
+        let mut kernel = Kernel::new(Layout {
+            brk_base: 0x10000,
+            brk_max: 0x10000,
+            mmap_base: 0x20000,
+            limit: 0x100000,
+        });
+        let mut mem = vec![0u8; 0x1000];
+        assert_eq!(kernel.dispatch(&mut mem, nr::PIPE2, [0x100, O_NONBLOCK, 0, 0, 0, 0]), 0);
+        assert_eq!(&mem[0x100..0x108], &[3, 0, 0, 0, 4, 0, 0, 0]);
+
+        mem[0x200..0x205].copy_from_slice(b"hello");
+        assert_eq!(kernel.dispatch(&mut mem, nr::WRITE, [4, 0x200, 5, 0, 0, 0]), 5);
+        assert_eq!(kernel.dispatch(&mut mem, nr::IOCTL, [3, FIONREAD, 0x300, 0, 0, 0]), 0);
+        assert_eq!(mem[0x300], 5);
+        assert_eq!(kernel.dispatch(&mut mem, nr::READ, [4, 0x400, 8, 0, 0, 0]), EBADF.as_ret());
+        assert_eq!(kernel.dispatch(&mut mem, nr::READ, [3, 0x400, 8, 0, 0, 0]), 5);
+        assert_eq!(&mem[0x400..0x405], b"hello");
+        assert_eq!(kernel.dispatch(&mut mem, nr::READ, [3, 0x400, 8, 0, 0, 0]), EAGAIN.as_ret());
+        assert_eq!(kernel.dispatch(&mut mem, nr::LSEEK, [3, 0, 0, 0, 0, 0]), ESPIPE.as_ret());
+
+        // writev gathers its buffers, readv fills its own in turn
+        let mut iovec = |at: usize, bufs: &[(u64, u64)]| {
+            for (i, &(base, len)) in bufs.iter().enumerate() {
+                mem[at + i * 16..at + i * 16 + 8].copy_from_slice(&base.to_le_bytes());
+                mem[at + i * 16 + 8..at + i * 16 + 16].copy_from_slice(&len.to_le_bytes());
+            }
+        };
+        iovec(0x500, &[(0x200, 2), (0x202, 0), (0x202, 3)]);
+        iovec(0x600, &[(0x400, 2), (0x410, 8), (0x420, 8)]);
+        assert_eq!(kernel.dispatch(&mut mem, nr::WRITEV, [4, 0x500, 3, 0, 0, 0]), 5);
+        assert_eq!(kernel.dispatch(&mut mem, nr::READV, [3, 0x600, 3, 0, 0, 0]), 5);
+        assert_eq!((&mem[0x400..0x402], &mem[0x410..0x413]), (&b"he"[..], &b"llo"[..]));
+        assert_eq!(kernel.dispatch(&mut mem, nr::READV, [3, 0x600, 3, 0, 0, 0]), EAGAIN.as_ret());
+        assert_eq!(kernel.dispatch(&mut mem, nr::WRITEV, [4, 0x500, 1025, 0, 0, 0]), EINVAL.as_ret());
+
+        // The writer closes: end of file
+        assert_eq!(kernel.dispatch(&mut mem, nr::CLOSE, [4, 0, 0, 0, 0, 0]), 0);
+        assert_eq!(kernel.dispatch(&mut mem, nr::READ, [3, 0x400, 8, 0, 0, 0]), 0);
+        assert_eq!(kernel.free_fd(), 4);
+
+        // The reader closes: EPIPE, and SIGPIPE kills the writer
+        assert_eq!(kernel.dispatch(&mut mem, nr::PIPE2, [0x100, 0, 0, 0, 0, 0]), 0);
+        assert_eq!(&mem[0x100..0x108], &[4, 0, 0, 0, 5, 0, 0, 0]);
+        assert_eq!(kernel.dispatch(&mut mem, nr::CLOSE, [4, 0, 0, 0, 0, 0]), 0);
+        assert_eq!(kernel.dispatch(&mut mem, nr::WRITE, [5, 0x200, 5, 0, 0, 0]), EPIPE.as_ret());
+        assert_eq!(kernel.process.killed, Some(SIGPIPE));
+    }
+}
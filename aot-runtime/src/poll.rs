@@ -0,0 +1,548 @@
+// poll.rs - ppoll, pselect6 and epoll
+//
+// All three wait on the same thing: the readiness of a set of descriptors,
+// as each subsystem reports it (`Pty::poll`, `Pipes::poll`, `Net::poll`;
+// files in the VFS or a host mount are always ready, and an epoll
+// descriptor never is). `wait` looks at the descriptors and, while none is
+// ready, sleeps on the guest clock (`Time::sleep_ns`) a little at a time
+// until one is or the timeout has passed, so in deterministic mode a
+// timeout skips guest time forward like any sleep. A host that cannot
+// block (`Pty::blocking` off) gets EAGAIN instead of a wait, as a read
+// does, and resumes the guest once it has pushed input. Terminal input
+// that raises a signal cuts a wait short with ERESTARTSYS, so the call is
+// made again once the process continues.
+//
+// Timeouts are checked (non-negative, normalized) before they are scaled
+// to nanoseconds, and saturate rather than overflow. The signal masks the
+// calls take are ignored: there are no guest signal handlers to hold off.
+// The time left is not written back.
+//
+// An epoll instance is a descriptor holding an interest list: descriptor,
+// events and the caller's data, kept until EPOLL_CTL_DEL or until the
+// descriptor is closed. Readiness is level-triggered. EPOLLET is accepted
+// and treated the same, which can report a descriptor more often than
+// Linux would but never misses one. EPOLLONESHOT disarms an entry once it
+// has fired, until EPOLL_CTL_MOD arms it again.
+
+use crate::mm::GuestMemory;
+use crate::pipe::O_CLOEXEC;
+use crate::syscalls::{Errno, Kernel, EAGAIN, EBADF, EEXIST, EINVAL, ENOENT, EPERM, ERESTARTSYS};
+use crate::time::read_timespec;
+use std::collections::BTreeMap;
+
+/// poll conditions, and the epoll events that share their bits
+pub const POLLIN: u32 = 0x1;
+pub const POLLPRI: u32 = 0x2;
+pub const POLLOUT: u32 = 0x4;
+pub const POLLERR: u32 = 0x8;
+pub const POLLHUP: u32 = 0x10;
+pub const POLLNVAL: u32 = 0x20;
+pub const POLLRDNORM: u32 = 0x40;
+pub const POLLWRNORM: u32 = 0x100;
+
+/// epoll_ctl operations
+pub const EPOLL_CTL_ADD: u64 = 1;
+pub const EPOLL_CTL_DEL: u64 = 2;
+pub const EPOLL_CTL_MOD: u64 = 3;
+/// epoll flags above the event bits
+pub const EPOLLONESHOT: u32 = 1 << 30;
+pub const EPOLLET: u32 = 1 << 31;
+
+/// Most descriptors ppoll or pselect6 looks at (RLIMIT_NOFILE)
+const MAX_FDS: u64 = 1024;
+/// Bytes in a struct epoll_event: events, padding, then the data
+const EVENT_SIZE: u64 = 16;
+/// Most events one epoll_pwait returns (EP_MAX_EVENTS)
+const MAX_EVENTS: u64 = i32::MAX as u64 / EVENT_SIZE;
+/// How long a wait sleeps between looks at its descriptors
+const POLL_INTERVAL_NS: u64 = 1_000_000;
+const CLOCK_MONOTONIC: u64 = 1;
+
+/// The conditions `fd` is in, POLLRDNORM and POLLWRNORM included; None if
+/// it is not open
+fn readiness(kernel: &mut Kernel, fd: u64) -> Option<u32> {
+    let events = if kernel.pty.owns(fd) {
+        kernel.pty.poll()
+    } else if kernel.pipes.owns(fd) {
+        kernel.pipes.poll(fd).unwrap_or(POLLERR)
+    } else if kernel.net.owns(fd) {
+        kernel.net.poll(fd).unwrap_or(POLLERR)
+    } else if kernel.vfs.owns(fd) || kernel.mounts.owns(fd) {
+        POLLIN | POLLOUT
+    } else if kernel.epolls.owns(fd) {
+        0
+    } else {
+        return None;
+    };
+    let read = if events & POLLIN != 0 { POLLRDNORM } else { 0 };
+    let write = if events & POLLOUT != 0 { POLLWRNORM } else { 0 };
+    Some(events | read | write)
+}
+
+/// Run `scan` until it counts something ready, or `timeout` ns (None: no
+/// limit) have passed and it has counted nothing
+fn wait(
+    kernel: &mut Kernel,
+    timeout: Option<u64>,
+    mut scan: impl FnMut(&mut Kernel) -> Result<u64, Errno>,
+) -> Result<u64, Errno> {
+    let start = kernel.time.now(CLOCK_MONOTONIC)?;
+    loop {
+        let ready = scan(kernel)?;
+        if ready > 0 {
+            return Ok(ready);
+        }
+        if kernel.pty.signalled() {
+            return Err(ERESTARTSYS);
+        }
+        let waited = kernel.time.now(CLOCK_MONOTONIC)?.saturating_sub(start);
+        let left = timeout.map_or(u64::MAX, |timeout| timeout.saturating_sub(waited));
+        if left == 0 {
+            return Ok(0);
+        }
+        if !kernel.pty.blocking {
+            return Err(EAGAIN);
+        }
+        kernel.time.sleep_ns(left.min(POLL_INTERVAL_NS));
+    }
+}
+
+/// The timespec at `addr` in ns; None (no limit) for a null pointer
+fn read_timeout(mem: &dyn GuestMemory, addr: u64) -> Result<Option<u64>, Errno> {
+    if addr == 0 {
+        return Ok(None);
+    }
+    read_timespec(mem, addr).map(Some)
+}
+
+/// ppoll: each of the `nfds` struct pollfd at `fds` gets the conditions it
+/// asked for, POLLERR and POLLHUP, or POLLNVAL if its descriptor is not
+/// open, in revents (negative descriptors are skipped); the count of those
+/// with any
+pub fn ppoll(kernel: &mut Kernel, mem: &mut dyn GuestMemory, fds: u64, nfds: u64, timeout: u64) -> Result<u64, Errno> {
+    if nfds > MAX_FDS {
+        return Err(EINVAL);
+    }
+    let timeout = read_timeout(mem, timeout)?;
+    let mut pollfds = vec![0u8; nfds as usize * 8];
+    mem.read(fds, &mut pollfds)?;
+    let ready = wait(kernel, timeout, |kernel| {
+        let mut ready = 0;
+        for pollfd in pollfds.chunks_exact_mut(8) {
+            let fd = i32::from_le_bytes(pollfd[..4].try_into().unwrap());
+            let events = u16::from_le_bytes(pollfd[4..6].try_into().unwrap()) as u32;
+            let revents = if fd < 0 {
+                0
+            } else {
+                readiness(kernel, fd as u64).map_or(POLLNVAL, |ready| ready & (events | POLLERR | POLLHUP))
+            };
+            pollfd[6..].copy_from_slice(&(revents as u16).to_le_bytes());
+            ready += (revents != 0) as u64;
+        }
+        Ok(ready)
+    })?;
+    mem.write(fds, &pollfds)?;
+    Ok(ready)
+}
+
+/// pselect6: the descriptors below `nfds` set in the read, write and
+/// except fd_sets at `sets` (any may be null) stay set only if ready that
+/// way; the count of those left set. EBADF if one is not open.
+pub fn pselect6(
+    kernel: &mut Kernel,
+    mem: &mut dyn GuestMemory,
+    nfds: u64,
+    sets: [u64; 3],
+    timeout: u64,
+) -> Result<u64, Errno> {
+    if (nfds as i32) < 0 {
+        return Err(EINVAL);
+    }
+    let nfds = nfds.min(MAX_FDS);
+    let timeout = read_timeout(mem, timeout)?;
+    let bytes = nfds.div_ceil(64) as usize * 8;
+    let mut wanted = [vec![0u8; bytes], vec![0u8; bytes], vec![0u8; bytes]];
+    for (set, &addr) in wanted.iter_mut().zip(&sets) {
+        if addr != 0 {
+            mem.read(addr, set)?;
+        }
+    }
+    let bit = |set: &[u8], fd: u64| set[fd as usize / 8] & 1 << (fd % 8) != 0;
+    // What counts as ready for reading, writing and an exception
+    let conditions = [POLLIN | POLLHUP | POLLERR, POLLOUT | POLLERR, POLLPRI];
+    let mut found = wanted.clone();
+    let ready = wait(kernel, timeout, |kernel| {
+        let mut ready = 0;
+        for set in &mut found {
+            set.as_mut_slice().fill(0);
+        }
+        for fd in (0..nfds).filter(|&fd| wanted.iter().any(|set| bit(set, fd))) {
+            let events = readiness(kernel, fd).ok_or(EBADF)?;
+            for ((wanted, found), condition) in wanted.iter().zip(&mut found).zip(conditions) {
+                if bit(wanted, fd) && events & condition != 0 {
+                    found[fd as usize / 8] |= 1 << (fd % 8);
+                    ready += 1;
+                }
+            }
+        }
+        Ok(ready)
+    })?;
+    for (set, &addr) in found.iter().zip(&sets) {
+        if addr != 0 {
+            mem.write(addr, set)?;
+        }
+    }
+    Ok(ready)
+}
+
+/// An epoll_ctl registration
+#[derive(Debug, Clone, Copy)]
+struct Interest {
+    /// What to report: the events asked for, POLLERR and POLLHUP, and the
+    /// flags; none once an EPOLLONESHOT entry has fired
+    events: u32,
+    data: u64,
+}
+
+/// The process's epoll instances
+#[derive(Default)]
+pub struct Epolls {
+    /// Interest lists by instance, each by descriptor
+    instances: BTreeMap<i32, BTreeMap<i32, Interest>>,
+}
+
+impl Epolls {
+    /// Whether `fd` is an epoll instance
+    pub fn owns(&self, fd: u64) -> bool {
+        self.instances.contains_key(&(fd as i32))
+    }
+
+    /// epoll_create1: a new instance as descriptor `new_fd`, which the
+    /// caller found free
+    pub fn create(&mut self, new_fd: i32, flags: u64) -> Result<u64, Errno> {
+        if flags & !O_CLOEXEC != 0 {
+            return Err(EINVAL);
+        }
+        self.instances.insert(new_fd, BTreeMap::new());
+        Ok(new_fd as u64)
+    }
+
+    pub fn close(&mut self, fd: u64) -> Result<u64, Errno> {
+        self.instances.remove(&(fd as i32)).ok_or(EBADF)?;
+        Ok(0)
+    }
+
+    /// Drop `fd`, now closed, from every interest list
+    pub fn forget(&mut self, fd: u64) {
+        for list in self.instances.values_mut() {
+            list.remove(&(fd as i32));
+        }
+    }
+}
+
+/// epoll_ctl: add `fd` to the interest list of `epfd` with the struct
+/// epoll_event at `event`, change its entry, or remove it
+pub fn epoll_ctl(
+    kernel: &mut Kernel,
+    mem: &mut dyn GuestMemory,
+    epfd: u64,
+    op: u64,
+    fd: u64,
+    event: u64,
+) -> Result<u64, Errno> {
+    let open = |fd: u64| kernel.pty.owns(fd) || kernel.in_use(fd as i32);
+    if !open(epfd) || !open(fd) {
+        return Err(EBADF);
+    }
+    // Files are always ready, so Linux will not watch them
+    if kernel.vfs.owns(fd) || kernel.mounts.owns(fd) {
+        return Err(EPERM);
+    }
+    let list = kernel.epolls.instances.get_mut(&(epfd as i32)).filter(|_| epfd != fd).ok_or(EINVAL)?;
+    let fd = fd as i32;
+    match op {
+        EPOLL_CTL_DEL => list.remove(&fd).map(|_| 0).ok_or(ENOENT),
+        EPOLL_CTL_ADD | EPOLL_CTL_MOD => {
+            match (op, list.contains_key(&fd)) {
+                (EPOLL_CTL_ADD, true) => return Err(EEXIST),
+                (EPOLL_CTL_MOD, false) => return Err(ENOENT),
+                _ => {}
+            }
+            let mut buf = [0u8; EVENT_SIZE as usize];
+            mem.read(event, &mut buf)?;
+            let events = u32::from_le_bytes(buf[..4].try_into().unwrap()) | POLLERR | POLLHUP;
+            let data = u64::from_le_bytes(buf[8..].try_into().unwrap());
+            list.insert(fd, Interest { events, data });
+            Ok(0)
+        }
+        _ => Err(EINVAL),
+    }
+}
+
+/// epoll_pwait: up to `max` struct epoll_event for the ready descriptors
+/// of `epfd` to `events`, waiting up to `timeout` ms (negative: no limit);
+/// the count written
+pub fn epoll_pwait(
+    kernel: &mut Kernel,
+    mem: &mut dyn GuestMemory,
+    epfd: u64,
+    events: u64,
+    max: u64,
+    timeout: u64,
+) -> Result<u64, Errno> {
+    if (max as i32) <= 0 || max > MAX_EVENTS {
+        return Err(EINVAL);
+    }
+    if !kernel.epolls.owns(epfd) {
+        return Err(if kernel.pty.owns(epfd) || kernel.in_use(epfd as i32) { EINVAL } else { EBADF });
+    }
+    let timeout = (timeout as i32 >= 0).then(|| timeout as i32 as u64 * 1_000_000);
+    let epfd = epfd as i32;
+    let mut out = Vec::new();
+    let ready = wait(kernel, timeout, |kernel| {
+        out.clear();
+        let list: Vec<_> = kernel.epolls.instances[&epfd].iter().map(|(&fd, &interest)| (fd, interest)).collect();
+        for (fd, interest) in list {
+            if out.len() as u64 == max * EVENT_SIZE {
+                break;
+            }
+            let Some(ready) = readiness(kernel, fd as u64) else {
+                continue;
+            };
+            let ready = ready & interest.events & !(EPOLLONESHOT | EPOLLET);
+            if ready == 0 {
+                continue;
+            }
+            out.extend(ready.to_le_bytes());
+            out.extend([0; 4]);
+            out.extend(interest.data.to_le_bytes());
+            if interest.events & EPOLLONESHOT != 0 {
+                if let Some(entry) = kernel.epolls.instances.get_mut(&epfd).and_then(|list| list.get_mut(&fd)) {
+                    entry.events &= EPOLLONESHOT | EPOLLET;
+                }
+            }
+        }
+        Ok(out.len() as u64 / EVENT_SIZE)
+    })?;
+    mem.write(events, &out)?;
+    Ok(ready)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::net::{AF_INET, SOCK_STREAM};
+    use crate::pty::Pty;
+    use crate::syscalls::nr;
+    use crate::time::Time;
+    use crate::vfs::AT_FDCWD;
+    use crate::Layout;
+    use std::sync::atomic::AtomicU64;
+    use std::sync::Arc;
+
+    /// A kernel on a detached terminal, with time that only moves when slept on
+    fn kernel() -> Kernel {
+        let mut kernel = Kernel::new(Layout {
+            brk_base: 0x10000,
+            brk_max: 0x10000,
+            mmap_base: 0x20000,
+            limit: 0x100000,
+        });
+        kernel.pty = Pty::detached();
+        kernel.time = Time::deterministic(Arc::new(AtomicU64::new(0)));
+        kernel
+    }
+
+    /// Write struct pollfds for `fds` to `at`
+    fn pollfds(mem: &mut [u8], at: usize, fds: &[(i32, u32)]) {
+        for (i, &(fd, events)) in fds.iter().enumerate() {
+            mem[at + i * 8..at + i * 8 + 4].copy_from_slice(&fd.to_le_bytes());
+            mem[at + i * 8 + 4..at + i * 8 + 6].copy_from_slice(&(events as u16).to_le_bytes());
+        }
+    }
+
+    fn revents(mem: &[u8], at: usize, i: usize) -> u32 {
+        u16::from_le_bytes(mem[at + i * 8 + 6..at + i * 8 + 8].try_into().unwrap()) as u32
+    }
+
+    fn timespec(mem: &mut [u8], at: usize, sec: i64, nsec: i64) {
+        mem[at..at + 8].copy_from_slice(&sec.to_le_bytes());
+        mem[at + 8..at + 16].copy_from_slice(&nsec.to_le_bytes());
+    }
+
+    #[test]
+    fn test_ppoll() {
+        let mut kernel = kernel();
+        let mut mem = vec![0u8; 0x1000];
+        assert_eq!(kernel.dispatch(&mut mem, nr::PIPE2, [0x80, 0, 0, 0, 0, 0]), 0);
+        timespec(&mut mem, 0x100, 0, 0);
+
+        // Nothing typed or piped yet: only the write end and the closed
+        // descriptor have anything to report
+        pollfds(&mut mem, 0x200, &[(0, POLLIN), (3, POLLIN), (4, POLLOUT), (9, POLLIN), (-1, POLLIN)]);
+        assert_eq!(kernel.dispatch(&mut mem, nr::PPOLL, [0x200, 5, 0x100, 0, 0, 0]), 2);
+        let all = |mem: &Vec<u8>| (0..5).map(|i| revents(mem, 0x200, i)).collect::<Vec<_>>();
+        assert_eq!(all(&mem), [0, 0, POLLOUT, POLLNVAL, 0]);
+
+        // The terminal is readable once a whole line is in, the pipe once
+        // something is written to it
+        kernel.pty.input.write(b"ls", false).unwrap();
+        assert_eq!(kernel.dispatch(&mut mem, nr::PPOLL, [0x200, 1, 0x100, 0, 0, 0]), 0);
+        kernel.pty.input.write(b"\n", false).unwrap();
+        assert_eq!(kernel.dispatch(&mut mem, nr::WRITE, [4, 0x80, 1, 0, 0, 0]), 1);
+        assert_eq!(kernel.dispatch(&mut mem, nr::PPOLL, [0x200, 5, 0x100, 0, 0, 0]), 4);
+        assert_eq!(all(&mem), [POLLIN, POLLIN, POLLOUT, POLLNVAL, 0]);
+
+        // A timeout too large for nanoseconds is fine when something is ready
+        timespec(&mut mem, 0x180, i64::MAX, 999_999_999);
+        assert_eq!(kernel.dispatch(&mut mem, nr::PPOLL, [0x208, 1, 0x180, 0, 0, 0]), 1);
+        timespec(&mut mem, 0x180, 1, 1_000_000_000);
+        assert_eq!(kernel.dispatch(&mut mem, nr::PPOLL, [0x208, 1, 0x180, 0, 0, 0]), EINVAL.as_ret());
+        assert_eq!(kernel.dispatch(&mut mem, nr::PPOLL, [0x200, 1025, 0, 0, 0, 0]), EINVAL.as_ret());
+
+        // With nothing ready the whole timeout passes, skipped in guest time
+        assert_eq!(kernel.dispatch(&mut mem, nr::READ, [0, 0x300, 16, 0, 0, 0]), 3);
+        assert_eq!(kernel.dispatch(&mut mem, nr::READ, [3, 0x300, 16, 0, 0, 0]), 1);
+        timespec(&mut mem, 0x180, 1, 500_000_000);
+        assert_eq!(kernel.dispatch(&mut mem, nr::PPOLL, [0x200, 2, 0x180, 0, 0, 0]), 0);
+        assert_eq!(kernel.time.now(1), Ok(1_500_000_000));
+
+        // A host that cannot block gets EAGAIN rather than a wait
+        kernel.pty.blocking = false;
+        assert_eq!(kernel.dispatch(&mut mem, nr::PPOLL, [0x200, 2, 0, 0, 0, 0]), EAGAIN.as_ret());
+        kernel.pty.blocking = true;
+
+        // The writer closes: the reader hangs up, with end of file to read
+        assert_eq!(kernel.dispatch(&mut mem, nr::CLOSE, [4, 0, 0, 0, 0, 0]), 0);
+        assert_eq!(kernel.dispatch(&mut mem, nr::PPOLL, [0x208, 1, 0, 0, 0, 0]), 1);
+        assert_eq!(revents(&mem, 0x208, 0), POLLIN | POLLHUP);
+    }
+
+    #[test]
+    fn test_pselect6() {
+        let mut kernel = kernel();
+        let mut mem = vec![0u8; 0x1000];
+        assert_eq!(kernel.dispatch(&mut mem, nr::PIPE2, [0x80, 0, 0, 0, 0, 0]), 0);
+        timespec(&mut mem, 0x100, 0, 0);
+
+        // Read {3}, write {3, 4}: only the write end is ready, for writing
+        mem[0x200] = 1 << 3;
+        mem[0x208] = 1 << 3 | 1 << 4;
+        assert_eq!(kernel.dispatch(&mut mem, nr::PSELECT6, [5, 0x200, 0x208, 0, 0x100, 0]), 1);
+        assert_eq!((mem[0x200], mem[0x208]), (0, 1 << 4));
+
+        assert_eq!(kernel.dispatch(&mut mem, nr::WRITE, [4, 0x80, 1, 0, 0, 0]), 1);
+        mem[0x200] = 1 << 3;
+        mem[0x208] = 1 << 4;
+        mem[0x210] = 1 << 3;
+        assert_eq!(kernel.dispatch(&mut mem, nr::PSELECT6, [5, 0x200, 0x208, 0x210, 0x100, 0]), 2);
+        assert_eq!((mem[0x200], mem[0x208], mem[0x210]), (1 << 3, 1 << 4, 0));
+
+        // Descriptors at or above nfds are not looked at; closed ones below are EBADF
+        mem[0x200] = 1 << 3 | 1 << 7;
+        assert_eq!(kernel.dispatch(&mut mem, nr::PSELECT6, [5, 0x200, 0, 0, 0x100, 0]), 1);
+        mem[0x200] = 1 << 3 | 1 << 7;
+        assert_eq!(kernel.dispatch(&mut mem, nr::PSELECT6, [8, 0x200, 0, 0, 0x100, 0]), EBADF.as_ret());
+        assert_eq!(kernel.dispatch(&mut mem, nr::PSELECT6, [u64::MAX, 0, 0, 0, 0x100, 0]), EINVAL.as_ret());
+    }
+
+    #[test]
+    fn test_epoll() {
+        let mut kernel = kernel();
+        let mut mem = vec![0u8; 0x1000];
+        let event = |mem: &mut Vec<u8>, at: usize, events: u32, data: u64| {
+            mem[at..at + 4].copy_from_slice(&events.to_le_bytes());
+            mem[at + 8..at + 16].copy_from_slice(&data.to_le_bytes());
+        };
+        let word = |mem: &Vec<u8>, at: usize| u64::from_le_bytes(mem[at..at + 8].try_into().unwrap());
+
+        assert_eq!(kernel.dispatch(&mut mem, nr::EPOLL_CREATE1, [1, 0, 0, 0, 0, 0]), EINVAL.as_ret());
+        assert_eq!(kernel.dispatch(&mut mem, nr::EPOLL_CREATE1, [O_CLOEXEC, 0, 0, 0, 0, 0]), 3);
+        assert_eq!(kernel.dispatch(&mut mem, nr::PIPE2, [0x80, 0, 0, 0, 0, 0]), 0);
+        mem[0x90..0x9a].copy_from_slice(b"/dev/null\0");
+        assert_eq!(kernel.dispatch(&mut mem, nr::OPENAT, [AT_FDCWD as u64, 0x90, 0, 0, 0, 0]), 6);
+
+        event(&mut mem, 0x100, POLLIN, 0xfeed);
+        let ctl = |kernel: &mut Kernel, mem: &mut Vec<u8>, op: u64, fd: u64| {
+            kernel.dispatch(mem, nr::EPOLL_CTL, [3, op, fd, 0x100, 0, 0])
+        };
+        assert_eq!(ctl(&mut kernel, &mut mem, EPOLL_CTL_ADD, 4), 0);
+        assert_eq!(ctl(&mut kernel, &mut mem, EPOLL_CTL_ADD, 4), EEXIST.as_ret());
+        assert_eq!(ctl(&mut kernel, &mut mem, EPOLL_CTL_MOD, 5), ENOENT.as_ret());
+        assert_eq!(ctl(&mut kernel, &mut mem, EPOLL_CTL_ADD, 6), EPERM.as_ret());
+        assert_eq!(ctl(&mut kernel, &mut mem, EPOLL_CTL_ADD, 3), EINVAL.as_ret());
+        assert_eq!(ctl(&mut kernel, &mut mem, EPOLL_CTL_ADD, 42), EBADF.as_ret());
+        assert_eq!(ctl(&mut kernel, &mut mem, 9, 4), EINVAL.as_ret());
+
+        // Nothing yet; a 20 ms wait passes in guest time
+        let pwait = |kernel: &mut Kernel, mem: &mut Vec<u8>, timeout: i32| {
+            kernel.dispatch(mem, nr::EPOLL_PWAIT, [3, 0x200, 8, timeout as u64, 0, 0])
+        };
+        assert_eq!(pwait(&mut kernel, &mut mem, 0), 0);
+        assert_eq!(pwait(&mut kernel, &mut mem, 20), 0);
+        assert_eq!(kernel.time.now(1), Ok(20_000_000));
+
+        // Level-triggered: reported until read
+        assert_eq!(kernel.dispatch(&mut mem, nr::WRITE, [5, 0x80, 1, 0, 0, 0]), 1);
+        assert_eq!(pwait(&mut kernel, &mut mem, -1), 1);
+        assert_eq!((word(&mem, 0x200), word(&mem, 0x208)), (POLLIN as u64, 0xfeed));
+        assert_eq!(pwait(&mut kernel, &mut mem, 0), 1);
+
+        // A one-shot entry fires once, until rearmed
+        event(&mut mem, 0x100, POLLIN | EPOLLONESHOT, 7);
+        assert_eq!(ctl(&mut kernel, &mut mem, EPOLL_CTL_MOD, 4), 0);
+        assert_eq!(pwait(&mut kernel, &mut mem, 0), 1);
+        assert_eq!(word(&mem, 0x208), 7);
+        assert_eq!(pwait(&mut kernel, &mut mem, 0), 0);
+        assert_eq!(ctl(&mut kernel, &mut mem, EPOLL_CTL_MOD, 4), 0);
+        assert_eq!(pwait(&mut kernel, &mut mem, 0), 1);
+
+        // Closing a descriptor takes it off the list
+        assert_eq!(kernel.dispatch(&mut mem, nr::CLOSE, [4, 0, 0, 0, 0, 0]), 0);
+        assert_eq!(pwait(&mut kernel, &mut mem, 0), 0);
+        assert_eq!(ctl(&mut kernel, &mut mem, EPOLL_CTL_DEL, 4), EBADF.as_ret());
+
+        assert_eq!(kernel.dispatch(&mut mem, nr::EPOLL_PWAIT, [5, 0x200, 8, 0, 0, 0]), EINVAL.as_ret());
+        assert_eq!(kernel.dispatch(&mut mem, nr::EPOLL_PWAIT, [42, 0x200, 8, 0, 0, 0]), EBADF.as_ret());
+        assert_eq!(kernel.dispatch(&mut mem, nr::EPOLL_PWAIT, [3, 0x200, 0, 0, 0, 0]), EINVAL.as_ret());
+        assert_eq!(kernel.dispatch(&mut mem, nr::CLOSE, [3, 0, 0, 0, 0, 0]), 0);
+        assert!(!kernel.epolls.owns(3));
+    }
+
+    #[test]
+    fn test_socket_readiness() {
+        let mut kernel = kernel();
+        kernel.time = Time::host();
+        let mut mem = vec![0u8; 0x1000];
+        timespec(&mut mem, 0x100, 0, 0);
+        timespec(&mut mem, 0x110, 5, 0);
+
+        // An unconnected stream socket is hung up; a listener is readable
+        // once a connection waits, which accept then takes
+        assert_eq!(kernel.dispatch(&mut mem, nr::SOCKET, [AF_INET as u64, SOCK_STREAM, 0, 0, 0, 0]), 3);
+        pollfds(&mut mem, 0x200, &[(3, POLLIN | POLLOUT)]);
+        assert_eq!(kernel.dispatch(&mut mem, nr::PPOLL, [0x200, 1, 0x100, 0, 0, 0]), 1);
+        assert_eq!(revents(&mem, 0x200, 0), POLLOUT | POLLHUP);
+        mem[0x300..0x310].copy_from_slice(&[2, 0, 0, 0, 127, 0, 0, 1, 0, 0, 0, 0, 0, 0, 0, 0]);
+        assert_eq!(kernel.dispatch(&mut mem, nr::BIND, [3, 0x300, 16, 0, 0, 0]), 0);
+        assert_eq!(kernel.dispatch(&mut mem, nr::LISTEN, [3, 8, 0, 0, 0, 0]), 0);
+        assert_eq!(kernel.dispatch(&mut mem, nr::PPOLL, [0x200, 1, 0x100, 0, 0, 0]), 0);
+        mem[0x310..0x314].copy_from_slice(&16u32.to_le_bytes());
+        assert_eq!(kernel.dispatch(&mut mem, nr::GETSOCKNAME, [3, 0x300, 0x310, 0, 0, 0]), 0);
+
+        assert_eq!(kernel.dispatch(&mut mem, nr::SOCKET, [AF_INET as u64, SOCK_STREAM, 0, 0, 0, 0]), 4);
+        assert_eq!(kernel.dispatch(&mut mem, nr::CONNECT, [4, 0x300, 16, 0, 0, 0]), 0);
+        assert_eq!(kernel.dispatch(&mut mem, nr::PPOLL, [0x200, 1, 0x110, 0, 0, 0]), 1);
+        assert_eq!(revents(&mem, 0x200, 0), POLLIN);
+        assert_eq!(kernel.dispatch(&mut mem, nr::ACCEPT, [3, 0, 0, 0, 0, 0]), 5);
+
+        // The accepted end is readable once the client has written
+        pollfds(&mut mem, 0x200, &[(5, POLLIN), (4, POLLIN | POLLOUT)]);
+        assert_eq!(kernel.dispatch(&mut mem, nr::PPOLL, [0x200, 2, 0x100, 0, 0, 0]), 1);
+        assert_eq!((revents(&mem, 0x200, 0), revents(&mem, 0x200, 1)), (0, POLLOUT));
+        mem[0x400..0x402].copy_from_slice(b"hi");
+        assert_eq!(kernel.dispatch(&mut mem, nr::WRITE, [4, 0x400, 2, 0, 0, 0]), 2);
+        assert_eq!(kernel.dispatch(&mut mem, nr::PPOLL, [0x200, 1, 0x110, 0, 0, 0]), 1);
+        assert_eq!(kernel.dispatch(&mut mem, nr::READ, [5, 0x500, 16, 0, 0, 0]), 2);
+    }
+}
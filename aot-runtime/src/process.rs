@@ -0,0 +1,227 @@
+// process.rs - Process identity and job control
+//
+// The runtime runs one process, so its ids are few: a pid, the process
+// group it is in and the session that group belongs to, all 1 at boot,
+// and a user and group, root's (0) unless the host says otherwise.
+// setpgid, getpgid, setsid and getsid follow Linux's rules for a process
+// without children; kill reaches this process (by pid, 0 or its group)
+// and nothing else.
+//
+// Signals have no guest handlers here and take their default action:
+// SIGCONT resumes, SIGTSTP/SIGTTIN/SIGTTOU/SIGSTOP stop, SIGCHLD, SIGURG
+// and SIGWINCH are discarded, anything else kills. A stopped or killed
+// process makes `Machine::run` return, which is where a host acting as
+// the job-control shell takes over: `fg` is making the guest's group the
+// terminal's foreground (`Pty::foreground`) and sending SIGCONT, `bg`
+// is sending SIGCONT alone.
+//
+// The working directory is whatever the host started the program in
+// (`Launch::cwd`); there is no filesystem here to chdir around.
+
+use crate::mm::GuestMemory;
+use crate::syscalls::{Errno, EINVAL, EPERM, ERANGE, ESRCH};
+
+pub const SIGINT: i32 = 2;
+pub const SIGQUIT: i32 = 3;
+pub const SIGILL: i32 = 4;
+pub const SIGBUS: i32 = 7;
+pub const SIGKILL: i32 = 9;
+pub const SIGSEGV: i32 = 11;
+pub const SIGPIPE: i32 = 13;
+pub const SIGCHLD: i32 = 17;
+pub const SIGCONT: i32 = 18;
+pub const SIGSTOP: i32 = 19;
+pub const SIGTSTP: i32 = 20;
+pub const SIGTTIN: i32 = 21;
+pub const SIGTTOU: i32 = 22;
+pub const SIGURG: i32 = 23;
+pub const SIGWINCH: i32 = 28;
+/// Highest signal number
+const NSIG: i32 = 64;
+
+/// The process's ids and run state
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Process {
+    pub pid: i32,
+    pub ppid: i32,
+    pub pgid: i32,
+    pub sid: i32,
+    pub uid: u32,
+    pub gid: u32,
+    /// Working directory, as getcwd reports it
+    pub cwd: String,
+    /// The arguments the program started with, for /proc/self/cmdline
+    pub argv: Vec<String>,
+    /// The program's path, for /proc/self/exe
+    pub exe: String,
+    /// The signal that stopped the process, until SIGCONT
+    pub stopped: Option<i32>,
+    /// The signal that killed the process
+    pub killed: Option<i32>,
+}
+
+impl Default for Process {
+    fn default() -> Self {
+        Self {
+            pid: 1,
+            ppid: 0,
+            pgid: 1,
+            sid: 1,
+            uid: 0,
+            gid: 0,
+            cwd: "/".into(),
+            argv: Vec::new(),
+            exe: String::new(),
+            stopped: None,
+            killed: None,
+        }
+    }
+}
+
+impl Process {
+    /// Deliver `sig` with its default action
+    pub fn signal(&mut self, sig: i32) {
+        match sig {
+            SIGCONT => self.stopped = None,
+            SIGSTOP | SIGTSTP | SIGTTIN | SIGTTOU => self.stopped = Some(sig),
+            SIGCHLD | SIGURG | SIGWINCH => {}
+            _ => self.killed = self.killed.or(Some(sig)),
+        }
+    }
+
+    /// Whether `pid` (kill's argument) names this process or its group
+    fn targets(&self, pid: i32) -> bool {
+        pid == 0 || pid == self.pid || pid == -self.pgid
+    }
+
+    pub fn kill(&mut self, pid: u64, sig: u64) -> Result<u64, Errno> {
+        let (pid, sig) = (pid as i32, sig as i32);
+        if !(0..=NSIG).contains(&sig) {
+            return Err(EINVAL);
+        }
+        if !self.targets(pid) {
+            return Err(ESRCH);
+        }
+        if sig != 0 {
+            self.signal(sig);
+        }
+        Ok(0)
+    }
+
+    pub fn getpgid(&self, pid: u64) -> Result<u64, Errno> {
+        match pid as i32 {
+            0 => Ok(self.pgid as u64),
+            pid if pid == self.pid => Ok(self.pgid as u64),
+            _ => Err(ESRCH),
+        }
+    }
+
+    pub fn getsid(&self, pid: u64) -> Result<u64, Errno> {
+        match pid as i32 {
+            0 => Ok(self.sid as u64),
+            pid if pid == self.pid => Ok(self.sid as u64),
+            _ => Err(ESRCH),
+        }
+    }
+
+    /// Move into group `pgid` (0: the pid), which must be this process's
+    /// own or the one it is already in; a session leader cannot move
+    pub fn setpgid(&mut self, pid: u64, pgid: u64) -> Result<u64, Errno> {
+        let (pid, pgid) = (pid as i32, pgid as i32);
+        if pid != 0 && pid != self.pid {
+            return Err(ESRCH);
+        }
+        if pgid < 0 {
+            return Err(EINVAL);
+        }
+        let pgid = if pgid == 0 { self.pid } else { pgid };
+        if self.sid == self.pid {
+            return Err(EPERM);
+        }
+        if pgid != self.pid && pgid != self.pgid {
+            return Err(EPERM);
+        }
+        self.pgid = pgid;
+        Ok(0)
+    }
+
+    pub fn getcwd(&self, mem: &mut dyn GuestMemory, buf: u64, size: u64) -> Result<u64, Errno> {
+        let mut path = self.cwd.clone().into_bytes();
+        path.push(0);
+        if (size as usize) < path.len() {
+            return Err(ERANGE);
+        }
+        mem.write(buf, &path)?;
+        Ok(path.len() as u64)
+    }
+
+    /// Start a session and group led by this process, which must not
+    /// already lead a group
+    pub fn setsid(&mut self) -> Result<u64, Errno> {
+        if self.pgid == self.pid {
+            return Err(EPERM);
+        }
+        self.sid = self.pid;
+        self.pgid = self.pid;
+        Ok(self.sid as u64)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::pty::{Pty, Terminal, TIOCGPGRP, TIOCSPGRP};
+    use crate::syscalls::{nr, Kernel, ERESTARTSYS};
+    use crate::Layout;
+
+    struct Mute;
+
+    impl Terminal for Mute {
+        fn write(&mut self, _data: &[u8]) {}
+    }
+
+    #[test]
+    fn test_job_control() {
+        let mut kernel = Kernel::new(Layout {
+            brk_base: 0x10000,
+            brk_max: 0x10000,
+            mmap_base: 0x20000,
+            limit: 0x100000,
+        });
+        kernel.pty = Pty::new(Box::new(Mute));
+        kernel.pty.input.write(b"\x1als\r", false).unwrap();
+        let mut mem = vec![0u8; 0x4000];
+
+        // A shell in init's session moves into a group of its own and makes
+        // that the foreground
+        kernel.process = Process { pid: 7, ppid: 1, pgid: 1, sid: 1, ..Default::default() };
+        assert_eq!(kernel.dispatch(&mut mem, nr::SETPGID, [0, 9, 0, 0, 0, 0]), EPERM.as_ret());
+        assert_eq!(kernel.dispatch(&mut mem, nr::SETPGID, [0, 0, 0, 0, 0, 0]), 0);
+        assert_eq!(kernel.dispatch(&mut mem, nr::GETPGID, [7, 0, 0, 0, 0, 0]), 7);
+        assert_eq!(kernel.dispatch(&mut mem, nr::SETSID, [0; 6]), EPERM.as_ret());
+        mem[0x100..0x104].copy_from_slice(&7i32.to_le_bytes());
+        assert_eq!(kernel.dispatch(&mut mem, nr::IOCTL, [0, TIOCSPGRP, 0x100, 0, 0, 0]), 0);
+        mem[0x100..0x104].copy_from_slice(&3i32.to_le_bytes());
+        assert_eq!(kernel.dispatch(&mut mem, nr::IOCTL, [0, TIOCSPGRP, 0x100, 0, 0, 0]), EPERM.as_ret());
+        assert_eq!(kernel.dispatch(&mut mem, nr::IOCTL, [0, TIOCGPGRP, 0x100, 0, 0, 0]), 0);
+        assert_eq!(&mem[0x100..0x104], &7i32.to_le_bytes());
+
+        // ^Z stops the foreground group; SIGCONT resumes it
+        assert_eq!(kernel.dispatch(&mut mem, nr::READ, [0, 0x200, 64, 0, 0, 0]), ERESTARTSYS.as_ret());
+        assert_eq!(kernel.process.stopped, Some(SIGTSTP));
+        assert_eq!(kernel.dispatch(&mut mem, nr::KILL, [0, SIGCONT as u64, 0, 0, 0, 0]), 0);
+        assert_eq!(kernel.process.stopped, None);
+
+        // In the background, reading the terminal stops it with SIGTTIN
+        kernel.pty.foreground = 1;
+        assert_eq!(kernel.dispatch(&mut mem, nr::READ, [0, 0x200, 64, 0, 0, 0]), ERESTARTSYS.as_ret());
+        assert_eq!(kernel.process.stopped, Some(SIGTTIN));
+        kernel.pty.foreground = 7;
+        kernel.process.signal(SIGCONT);
+        assert_eq!(kernel.dispatch(&mut mem, nr::READ, [0, 0x200, 64, 0, 0, 0]), 3);
+
+        assert_eq!(kernel.dispatch(&mut mem, nr::KILL, [42, SIGINT as u64, 0, 0, 0, 0]), ESRCH.as_ret());
+        assert_eq!(kernel.dispatch(&mut mem, nr::KILL, [(-7i64) as u64, SIGINT as u64, 0, 0, 0, 0]), 0);
+        assert_eq!(kernel.process.killed, Some(SIGINT));
+    }
+}
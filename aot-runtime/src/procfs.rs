@@ -0,0 +1,153 @@
+// procfs.rs - /proc files made from the kernel's state
+//
+// No rootfs carries /proc; Linux makes its files up from the kernel's own
+// bookkeeping, and so does this. The VFS holds their names (under
+// /proc/self, plus /proc/meminfo) as files with no contents; when one is
+// opened, the syscall layer renders its text from the `Kernel` (the
+// process's ids and argv from `process.rs`, its mappings and heap from
+// `mm.rs`) and the open file reads from that. A reader sees the snapshot
+// taken at open however it splits its reads, as with Linux's seq files.
+// /proc/self/exe and /proc/self/cwd are symlinks the VFS keeps pointing at
+// the process's program and working directory.
+
+use crate::mm::{self, Backing, PAGE_SIZE};
+use crate::process::Process;
+use crate::syscalls::Kernel;
+use crate::vfs::UMASK;
+use std::fmt::Write;
+
+/// A file whose contents the kernel renders
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ProcFile {
+    Status,
+    Stat,
+    Cmdline,
+    Comm,
+    Maps,
+    Meminfo,
+}
+
+/// Where each file appears
+pub const FILES: [(&str, ProcFile); 6] = [
+    ("/proc/self/status", ProcFile::Status),
+    ("/proc/self/stat", ProcFile::Stat),
+    ("/proc/self/cmdline", ProcFile::Cmdline),
+    ("/proc/self/comm", ProcFile::Comm),
+    ("/proc/self/maps", ProcFile::Maps),
+    ("/proc/meminfo", ProcFile::Meminfo),
+];
+
+/// Longest comm, as TASK_COMM_LEN (16) less its NUL
+const COMM_LEN: usize = 15;
+
+/// The process's name: the last component of argv[0], cut to comm's length
+fn comm(process: &Process) -> &str {
+    let argv0 = process.argv.first().map_or("", |a| a.rsplit('/').next().unwrap_or(a));
+    let mut end = argv0.len().min(COMM_LEN);
+    while !argv0.is_char_boundary(end) {
+        end -= 1;
+    }
+    &argv0[..end]
+}
+
+/// The state letter and word of status, as ps shows them
+fn state(process: &Process) -> (char, &'static str) {
+    match process.stopped {
+        Some(_) => ('T', "stopped"),
+        None => ('R', "running"),
+    }
+}
+
+/// `file`'s contents, as read from `kernel` now
+pub fn render(file: ProcFile, kernel: &Kernel) -> Vec<u8> {
+    let process = &kernel.process;
+    let vm_kb = kernel.mm.mapped_pages() * PAGE_SIZE / 1024;
+    let mut out = String::new();
+    match file {
+        ProcFile::Status => {
+            let (letter, word) = state(process);
+            let _ = write!(
+                out,
+                "Name:\t{}\nUmask:\t{:04o}\nState:\t{} ({})\nTgid:\t{pid}\nNgid:\t0\nPid:\t{pid}\nPPid:\t{}\n\
+                 TracerPid:\t0\nUid:\t{uid}\t{uid}\t{uid}\t{uid}\nGid:\t{gid}\t{gid}\t{gid}\t{gid}\nFDSize:\t64\n\
+                 VmPeak:\t{vm_kb:8} kB\nVmSize:\t{vm_kb:8} kB\nVmRSS:\t{vm_kb:8} kB\nThreads:\t1\n",
+                comm(process),
+                UMASK,
+                letter,
+                word,
+                process.ppid,
+                pid = process.pid,
+                uid = process.uid,
+                gid = process.gid,
+            );
+        }
+        ProcFile::Stat => {
+            // pid (comm) state ppid pgrp session tty_nr tpgid, then flags,
+            // faults and times (none kept), priority, nice, threads,
+            // itrealvalue, starttime, vsize and rss; the rest are zero
+            let (letter, _) = state(process);
+            let _ = write!(
+                out,
+                "{} ({}) {} {} {} {} 0 {} 0 0 0 0 0 0 0 0 0 20 0 1 0 0 {} {}",
+                process.pid,
+                comm(process),
+                letter,
+                process.ppid,
+                process.pgid,
+                process.sid,
+                kernel.pty.foreground,
+                vm_kb * 1024,
+                vm_kb * 1024 / PAGE_SIZE,
+            );
+            out.push_str(&" 0".repeat(28));
+            out.push('\n');
+        }
+        ProcFile::Cmdline => {
+            for arg in &process.argv {
+                out.push_str(arg);
+                out.push('\0');
+            }
+        }
+        ProcFile::Comm => {
+            out.push_str(comm(process));
+            out.push('\n');
+        }
+        ProcFile::Maps => {
+            let layout = kernel.mm.layout();
+            let heap_end = kernel.mm.current_brk().next_multiple_of(PAGE_SIZE);
+            let heap = (heap_end > layout.brk_base).then_some((layout.brk_base, heap_end));
+            let mut lines: Vec<(u64, String)> = kernel
+                .mm
+                .mappings()
+                .map(|m| {
+                    let offset = match m.backing {
+                        Backing::File { offset, .. } => offset,
+                        Backing::Anonymous => 0,
+                    };
+                    let perms = [(mm::PROT_READ, 'r'), (mm::PROT_WRITE, 'w'), (mm::PROT_EXEC, 'x')]
+                        .iter()
+                        .map(|&(bit, c)| if m.prot & bit != 0 { c } else { '-' })
+                        .chain([if m.flags & mm::MAP_SHARED != 0 { 's' } else { 'p' }])
+                        .collect::<String>();
+                    (m.start, format!("{:08x}-{:08x} {} {:08x} 00:00 0\n", m.start, m.end(), perms, offset))
+                })
+                .collect();
+            if let Some((start, end)) = heap {
+                lines.push((start, format!("{:08x}-{:08x} rw-p 00000000 00:00 0 [heap]\n", start, end)));
+            }
+            lines.sort();
+            lines.into_iter().for_each(|(_, line)| out.push_str(&line));
+        }
+        ProcFile::Meminfo => {
+            let total_kb = kernel.mm.layout().limit / 1024;
+            let free_kb = total_kb.saturating_sub(vm_kb);
+            let _ = write!(
+                out,
+                "MemTotal:       {:8} kB\nMemFree:        {:8} kB\nMemAvailable:   {:8} kB\nBuffers:        {:8} kB\n\
+                 Cached:         {:8} kB\nSwapTotal:      {:8} kB\nSwapFree:       {:8} kB\n",
+                total_kb, free_kb, free_kb, 0, 0, 0, 0,
+            );
+        }
+    }
+    out.into_bytes()
+}
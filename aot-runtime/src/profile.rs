@@ -0,0 +1,143 @@
+// profile.rs - Sampling profiler for guest code
+//
+// A timer thread raises a flag every interval; the machine checks it
+// between blocks and charges the sample to the block about to run. Samples
+// are keyed by block start (the machine's block map), then aggregated by
+// the enclosing ELF function symbol (`Machine::symbolizer`) when written
+// out:
+//
+//   friscy-run --profile prog.folded prog
+//   flamegraph.pl prog.folded > prog.svg      (or inferno-flamegraph)
+//
+// Time spent in syscalls and compilation is charged to the next block.
+//
+// The raw per-block counts can also be written as a block profile, which
+// `rv2wasm --block-profile` uses to lay hot blocks out first.
+
+use rv2wasm::{BlockProfile, Symbolizer};
+use std::collections::HashMap;
+use std::io::{self, Write};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::Arc;
+use std::thread::JoinHandle;
+use std::time::Duration;
+
+/// Default sampling rate; prime so it does not beat with periodic guest work
+pub const DEFAULT_HZ: u32 = 997;
+
+pub struct Profiler {
+    samples: HashMap<u64, u64>,
+    pending: Arc<AtomicBool>,
+    stop: Arc<AtomicBool>,
+    timer: Option<JoinHandle<()>>,
+}
+
+impl Profiler {
+    /// Start sampling `hz` times per second
+    pub fn start(hz: u32) -> Self {
+        let pending = Arc::new(AtomicBool::new(false));
+        let stop = Arc::new(AtomicBool::new(false));
+        let interval = Duration::from_secs(1) / hz.max(1);
+        let timer = {
+            let (pending, stop) = (pending.clone(), stop.clone());
+            std::thread::spawn(move || {
+                while !stop.load(Ordering::Relaxed) {
+                    std::thread::sleep(interval);
+                    pending.store(true, Ordering::Relaxed);
+                }
+            })
+        };
+        Self {
+            samples: HashMap::new(),
+            pending,
+            stop,
+            timer: Some(timer),
+        }
+    }
+
+    /// Called before each block; takes a sample if the timer fired
+    #[inline]
+    pub fn poll(&mut self, pc: u64) {
+        if self.pending.load(Ordering::Relaxed) {
+            self.pending.store(false, Ordering::Relaxed);
+            self.record(pc);
+        }
+    }
+
+    pub fn record(&mut self, pc: u64) {
+        *self.samples.entry(pc).or_default() += 1;
+    }
+
+    /// Total samples taken
+    pub fn total(&self) -> u64 {
+        self.samples.values().sum()
+    }
+
+    /// Sample counts per function, hottest first
+    pub fn by_symbol(&self, symbols: &Symbolizer) -> Vec<(String, u64)> {
+        let mut totals: HashMap<String, u64> = HashMap::new();
+        for (&pc, &count) in &self.samples {
+            *totals.entry(symbols.name(pc)).or_default() += count;
+        }
+        let mut totals: Vec<_> = totals.into_iter().collect();
+        totals.sort_by(|a, b| b.1.cmp(&a.1).then_with(|| a.0.cmp(&b.0)));
+        totals
+    }
+
+    /// Sample counts per block start. Addresses carry the load bias, so
+    /// they line up with the ELF only for non-PIE programs.
+    pub fn block_profile(&self) -> BlockProfile {
+        BlockProfile::from_counts(self.samples.iter().map(|(&pc, &count)| (pc, count)))
+    }
+
+    /// Write collapsed stacks (`frame;frame count`), the flamegraph input format
+    pub fn write_collapsed<W: Write>(&self, symbols: &Symbolizer, mut out: W) -> io::Result<()> {
+        for (name, count) in self.by_symbol(symbols) {
+            writeln!(out, "guest;{} {}", name, count)?;
+        }
+        Ok(())
+    }
+}
+
+impl Drop for Profiler {
+    fn drop(&mut self) {
+        self.stop.store(true, Ordering::Relaxed);
+        if let Some(timer) = self.timer.take() {
+            let _ = timer.join();
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use rv2wasm::Symbol;
+
+    fn sym(name: &str, addr: u64, size: u64) -> Symbol {
+        Symbol { name: name.into(), addr, size }
+    }
+
+    #[test]
+    fn test_aggregates_by_symbol() {
+        let mut symbols = Symbolizer::new(vec![sym("main", 0x1000, 0x100), sym("loop", 0x1100, 0x40)]);
+        symbols.add_block(0x6000, 0x6010);
+        let mut p = Profiler::start(1000);
+        p.record(0x1000);
+        p.record(0x1080);
+        p.record(0x1110);
+        p.record(0x5000);
+        p.record(0x6000);
+
+        assert_eq!(symbols.name(0x1140), "0x1140");
+        assert_eq!(p.by_symbol(&symbols)[0], ("main".to_string(), 2));
+
+        let mut out = Vec::new();
+        p.write_collapsed(&symbols, &mut out).unwrap();
+        let text = String::from_utf8(out).unwrap();
+        assert!(text.contains("guest;loop 1\n"));
+        assert!(text.contains("guest;0x5000 1\n"));
+        // Stripped code is named by its block
+        assert!(text.contains("guest;block_0x6000 1\n"));
+        assert_eq!(p.block_profile().range(0x1000, 0x1100), 2);
+    }
+}
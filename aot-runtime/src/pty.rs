@@ -0,0 +1,896 @@
+// pty.rs - The guest's controlling terminal
+//
+// Descriptors 0-2 are the slave side of a pseudo-terminal whose master is
+// the host: what the user types is pushed into an input pipe, and what the
+// guest writes goes out through an output pipe to a `Terminal`. In between
+// sits the line discipline, configured with termios (TCGETS,
+// TCSETS/TCSETSW/TCSETSF, TCFLSH) like a Linux tty:
+//
+//   ICANON  input is edited a line at a time (erase, word erase, kill, EOF)
+//           and a read returns at most one line
+//   ECHO    input is echoed back, control characters as ^X with ECHOCTL
+//   ISIG    the INTR, QUIT and SUSP characters raise signals instead of
+//           being read; `take_signals` hands them to the process layer
+//
+// plus ICRNL/INLCR/IGNCR on input and OPOST/ONLCR on output. With ICANON
+// off, reads follow VMIN and VTIME: wait for VMIN bytes, for VTIME tenths
+// of a second between them once the first has come, or (VMIN = 0) for at
+// most VTIME for any byte at all.
+//
+// Both pipes are bounded (`pipe.rs`). Output is drained into the
+// `Terminal` by a console thread (`Pty::new`), or by the host itself
+// (`Pty::detached`), and a guest writing faster than that waits for room,
+// or writes what fits and gets EAGAIN when it cannot wait. TCSETSW and
+// tcdrain (TCSBRK) wait for the output to be shown; TCOFLUSH drops it.
+//
+// For job control the terminal has a session and a foreground process
+// group (TIOCGPGRP/TIOCSPGRP, TIOCGSID). Keyboard signals go to the
+// foreground group; a background group reading the terminal gets SIGTTIN,
+// and writing it with TOSTOP set, SIGTTOU. Reads and writes cut short by a
+// signal return ERESTARTSYS, so they are made again once the process
+// continues. tcsetpgrp from the background is allowed: shells block
+// SIGTTOU around it anyway.
+//
+// The window size (TIOCGWINSZ/TIOCSWINSZ) starts at 24x80. The host
+// reports a resized window with `resize`; like any change of size, that
+// sends SIGWINCH to the foreground group.
+//
+// Input only reaches the guest through its pipe, which holds up to a
+// Linux tty's 4 KiB. The native runtime pumps its stdin into it from the
+// start (`pump_stdin`), so replies the host terminal sends to the guest's
+// queries (a DSR cursor report, say) go to the guest rather than being
+// left behind for the shell friscy-run was started from. A read that
+// would wait fails with EAGAIN when the descriptor is O_NONBLOCK, or when
+// the host cannot block (`Pty::blocking`, off in a browser's main thread);
+// the host resumes the guest once it has pushed input. For poll, the
+// terminal is readable once a read would return at once: a whole line in
+// canonical mode, any byte otherwise, or the end of the host's input.
+//
+// When the host's stdin is not a terminal (`Pty::piped`: a pipe or a file)
+// there is no line discipline on input: it reaches the guest as it came,
+// read like a pipe, with nothing echoed, edited or turned into signals.
+
+use crate::mm::GuestMemory;
+use crate::pipe::{Pipe, F_GETFD, F_GETFL, F_SETFD, F_SETFL, O_NONBLOCK};
+use crate::poll::{POLLHUP, POLLIN, POLLOUT};
+use crate::process::{Process, SIGINT, SIGQUIT, SIGTSTP, SIGTTIN, SIGTTOU, SIGWINCH};
+use crate::syscalls::{Errno, EAGAIN, EINVAL, ENOTTY, EPERM, ERESTARTSYS};
+use std::collections::VecDeque;
+use std::io::{Read, Write};
+use std::time::Duration;
+
+/// Largest write copied out of the guest in one call
+const MAX_IO: usize = 1 << 20;
+
+/// Bytes the input pipe holds
+pub const INPUT_CAPACITY: usize = 4096;
+
+/// Bytes of output the host may fall behind by
+pub const OUTPUT_CAPACITY: usize = 64 * 1024;
+
+/// The terminal's access mode, for F_GETFL
+const O_RDWR: u64 = 2;
+
+/// ioctl requests
+pub const TCGETS: u64 = 0x5401;
+pub const TCSETS: u64 = 0x5402;
+pub const TCSETSW: u64 = 0x5403;
+pub const TCSETSF: u64 = 0x5404;
+pub const TCSBRK: u64 = 0x5409;
+pub const TCFLSH: u64 = 0x540b;
+pub const TIOCGPGRP: u64 = 0x540f;
+pub const TIOCSPGRP: u64 = 0x5410;
+pub const TIOCGWINSZ: u64 = 0x5413;
+pub const FIONREAD: u64 = 0x541b;
+pub const TIOCSWINSZ: u64 = 0x5414;
+pub const TIOCGSID: u64 = 0x5429;
+
+/// TCFLSH queue selectors
+const TCIFLUSH: u64 = 0;
+const TCOFLUSH: u64 = 1;
+const TCIOFLUSH: u64 = 2;
+
+/// c_iflag
+pub const INLCR: u32 = 0o100;
+pub const IGNCR: u32 = 0o200;
+pub const ICRNL: u32 = 0o400;
+pub const IXON: u32 = 0o2000;
+/// c_oflag
+pub const OPOST: u32 = 0o1;
+pub const ONLCR: u32 = 0o4;
+/// c_lflag
+pub const ISIG: u32 = 0o1;
+pub const ICANON: u32 = 0o2;
+pub const ECHO: u32 = 0o10;
+pub const ECHOE: u32 = 0o20;
+pub const ECHOK: u32 = 0o40;
+pub const ECHONL: u32 = 0o100;
+pub const NOFLSH: u32 = 0o200;
+pub const TOSTOP: u32 = 0o400;
+pub const ECHOCTL: u32 = 0o1000;
+pub const IEXTEN: u32 = 0o100000;
+
+/// c_cc indices
+pub const VINTR: usize = 0;
+pub const VQUIT: usize = 1;
+pub const VERASE: usize = 2;
+pub const VKILL: usize = 3;
+pub const VEOF: usize = 4;
+pub const VTIME: usize = 5;
+pub const VMIN: usize = 6;
+pub const VSUSP: usize = 10;
+pub const VEOL: usize = 11;
+pub const VWERASE: usize = 14;
+pub const VEOL2: usize = 16;
+pub const NCCS: usize = 19;
+
+/// The kernel's struct termios (not glibc's, which adds speeds)
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Termios {
+    pub iflag: u32,
+    pub oflag: u32,
+    pub cflag: u32,
+    pub lflag: u32,
+    pub line: u8,
+    pub cc: [u8; NCCS],
+}
+
+impl Termios {
+    pub const SIZE: usize = 17 + NCCS;
+
+    pub fn encode(&self) -> [u8; Self::SIZE] {
+        let mut out = [0u8; Self::SIZE];
+        for (i, flag) in [self.iflag, self.oflag, self.cflag, self.lflag].into_iter().enumerate() {
+            out[i * 4..i * 4 + 4].copy_from_slice(&flag.to_le_bytes());
+        }
+        out[16] = self.line;
+        out[17..].copy_from_slice(&self.cc);
+        out
+    }
+
+    pub fn decode(buf: &[u8; Self::SIZE]) -> Self {
+        let flag = |i: usize| u32::from_le_bytes(buf[i * 4..i * 4 + 4].try_into().unwrap());
+        Self {
+            iflag: flag(0),
+            oflag: flag(1),
+            cflag: flag(2),
+            lflag: flag(3),
+            line: buf[16],
+            cc: buf[17..].try_into().unwrap(),
+        }
+    }
+}
+
+/// `stty sane`: cooked mode, 38400 8N1
+impl Default for Termios {
+    fn default() -> Self {
+        let mut cc = [0u8; NCCS];
+        for (i, c) in [(VINTR, 0x03), (VQUIT, 0x1c), (VERASE, 0x7f), (VKILL, 0x15), (VEOF, 0x04)] {
+            cc[i] = c;
+        }
+        cc[VMIN] = 1;
+        cc[VSUSP] = 0x1a;
+        cc[VWERASE] = 0x17;
+        Self {
+            iflag: ICRNL | IXON,
+            oflag: OPOST | ONLCR,
+            cflag: 0o17 | 0o60 | 0o200,
+            lflag: ISIG | ICANON | ECHO | ECHOE | ECHOK | ECHOCTL | IEXTEN,
+            line: 0,
+            cc,
+        }
+    }
+}
+
+/// struct winsize
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct WinSize {
+    pub rows: u16,
+    pub cols: u16,
+    pub xpixel: u16,
+    pub ypixel: u16,
+}
+
+impl WinSize {
+    pub const SIZE: usize = 8;
+
+    pub fn encode(&self) -> [u8; Self::SIZE] {
+        let mut out = [0u8; Self::SIZE];
+        for (i, v) in [self.rows, self.cols, self.xpixel, self.ypixel].into_iter().enumerate() {
+            out[i * 2..i * 2 + 2].copy_from_slice(&v.to_le_bytes());
+        }
+        out
+    }
+
+    pub fn decode(buf: &[u8; Self::SIZE]) -> Self {
+        let field = |i: usize| u16::from_le_bytes([buf[i * 2], buf[i * 2 + 1]]);
+        Self {
+            rows: field(0),
+            cols: field(1),
+            xpixel: field(2),
+            ypixel: field(3),
+        }
+    }
+}
+
+impl Default for WinSize {
+    fn default() -> Self {
+        Self {
+            rows: 24,
+            cols: 80,
+            xpixel: 0,
+            ypixel: 0,
+        }
+    }
+}
+
+/// The host end of the terminal's output
+pub trait Terminal: Send {
+    /// Display guest output
+    fn write(&mut self, data: &[u8]);
+}
+
+/// `Terminal` on the host's stdout
+pub struct HostTerminal;
+
+impl Terminal for HostTerminal {
+    fn write(&mut self, data: &[u8]) {
+        let mut out = std::io::stdout().lock();
+        let _ = out.write_all(data).and_then(|_| out.flush());
+    }
+}
+
+/// Feed the host's stdin to `input` from a background thread, closing it
+/// at end of file. When stdin is itself a terminal its own line discipline
+/// runs first; `stty raw -echo` hands editing and echo over to the guest's.
+pub fn pump_stdin(input: Pipe) {
+    std::thread::spawn(move || {
+        let mut buf = [0u8; 4096];
+        loop {
+            match std::io::stdin().read(&mut buf) {
+                Ok(0) | Err(_) => break,
+                Ok(n) if input.write(&buf[..n], true).is_err() => break,
+                Ok(_) => {}
+            }
+        }
+        input.close_write();
+    });
+}
+
+/// Show what reaches `output` on `terminal`, from a background thread,
+/// until the terminal goes away. Bytes leave the pipe only once shown, so
+/// an empty pipe means the host has all of the output.
+pub fn pump_output(output: Pipe, mut terminal: Box<dyn Terminal>) {
+    std::thread::spawn(move || {
+        let mut buf = vec![0u8; OUTPUT_CAPACITY];
+        while output.wait_readable(None) {
+            let n = output.peek(&mut buf);
+            if n == 0 {
+                break;
+            }
+            terminal.write(&buf[..n]);
+            output.consume(n);
+        }
+    });
+}
+
+/// The terminal and its line discipline
+pub struct Pty {
+    /// Where the host pushes keyboard input
+    pub input: Pipe,
+    /// Where the host takes output from
+    pub output: Pipe,
+    /// Whether reads and writes may wait for the host
+    pub blocking: bool,
+    /// Input comes from a pipe or file, not a keyboard, and skips the line
+    /// discipline
+    pub piped: bool,
+    /// O_NONBLOCK, shared by descriptors 0-2
+    nonblocking: bool,
+    pub termios: Termios,
+    pub winsize: WinSize,
+    /// The canonical-mode line being edited
+    line: Vec<u8>,
+    /// Input the guest can read: whole lines in canonical mode, where an
+    /// empty one is an EOF character
+    ready: VecDeque<Vec<u8>>,
+    /// The host's input has ended
+    hangup: bool,
+    /// The session the terminal controls
+    pub session: i32,
+    /// The process group in the foreground
+    pub foreground: i32,
+    /// Signals raised for process groups, not yet taken
+    signals: Vec<(i32, i32)>,
+}
+
+impl Pty {
+    /// A terminal shown on `host` by a console thread
+    pub fn new(host: Box<dyn Terminal>) -> Self {
+        let pty = Self::detached();
+        pump_output(pty.output.clone(), host);
+        pty
+    }
+
+    /// A terminal whose output the host drains from `output` itself, as a
+    /// page without threads does between runs of the guest
+    pub fn detached() -> Self {
+        Self {
+            input: Pipe::new(INPUT_CAPACITY),
+            output: Pipe::new(OUTPUT_CAPACITY),
+            blocking: true,
+            piped: false,
+            nonblocking: false,
+            termios: Termios::default(),
+            winsize: WinSize::default(),
+            line: Vec::new(),
+            ready: VecDeque::new(),
+            hangup: false,
+            session: 1,
+            foreground: 1,
+            signals: Vec::new(),
+        }
+    }
+
+    /// Wait until the host has taken all output so far, as tcdrain does
+    pub fn flush(&self) {
+        if self.blocking {
+            self.output.wait_empty();
+        }
+    }
+
+    /// Whether `fd` is the terminal
+    pub fn owns(&self, fd: u64) -> bool {
+        fd <= 2
+    }
+
+    /// Signals raised since the last call, as (process group, signal)
+    pub fn take_signals(&mut self) -> Vec<(i32, i32)> {
+        std::mem::take(&mut self.signals)
+    }
+
+    /// Whether signals are waiting for `take_signals`
+    pub fn signalled(&self) -> bool {
+        !self.signals.is_empty()
+    }
+
+    /// The host's window is now `size`
+    pub fn resize(&mut self, size: WinSize) {
+        if size != self.winsize {
+            self.winsize = size;
+            self.signals.push((self.foreground, SIGWINCH));
+        }
+    }
+
+    fn lflag(&self, flag: u32) -> bool {
+        self.termios.lflag & flag != 0
+    }
+
+    /// Whether input is read a line at a time
+    fn canonical(&self) -> bool {
+        self.lflag(ICANON) && !self.piped
+    }
+
+    /// Whether `c` is the (enabled) control character `index`
+    fn is(&self, index: usize, c: u8) -> bool {
+        self.termios.cc[index] != 0 && self.termios.cc[index] == c
+    }
+
+    /// Run keyboard input through the line discipline
+    pub fn input(&mut self, data: &[u8]) {
+        if self.piped {
+            if !data.is_empty() {
+                self.ready.push_back(data.to_vec());
+            }
+            return;
+        }
+        let mut echo = Vec::new();
+        for &c in data {
+            let iflag = self.termios.iflag;
+            let c = match c {
+                b'\r' if iflag & IGNCR != 0 => continue,
+                b'\r' if iflag & ICRNL != 0 => b'\n',
+                b'\n' if iflag & INLCR != 0 => b'\r',
+                c => c,
+            };
+
+            if self.lflag(ISIG) {
+                let signal = [(VINTR, SIGINT), (VQUIT, SIGQUIT), (VSUSP, SIGTSTP)]
+                    .into_iter()
+                    .find(|&(index, _)| self.is(index, c));
+                if let Some((_, signal)) = signal {
+                    if !self.lflag(NOFLSH) {
+                        self.flush_input();
+                    }
+                    self.echo(&mut echo, c);
+                    self.signals.push((self.foreground, signal));
+                    continue;
+                }
+            }
+
+            if !self.lflag(ICANON) {
+                self.echo(&mut echo, c);
+                match self.ready.back_mut() {
+                    Some(chunk) if !chunk.is_empty() => chunk.push(c),
+                    _ => self.ready.push_back(vec![c]),
+                }
+                continue;
+            }
+
+            if self.is(VERASE, c) {
+                self.erase(&mut echo, 1);
+            } else if self.is(VWERASE, c) && self.lflag(IEXTEN) {
+                let spaces = self.line.iter().rev().take_while(|c| c.is_ascii_whitespace()).count();
+                let word = self.line.iter().rev().skip(spaces).take_while(|c| !c.is_ascii_whitespace()).count();
+                self.erase(&mut echo, spaces + word);
+            } else if self.is(VKILL, c) {
+                if self.lflag(ECHO) && !self.lflag(ECHOE) && self.lflag(ECHOK) {
+                    self.echo(&mut echo, c);
+                    echo.push(b'\n');
+                }
+                self.erase(&mut echo, usize::MAX);
+            } else if self.is(VEOF, c) {
+                self.ready.push_back(std::mem::take(&mut self.line));
+            } else if c == b'\n' || self.is(VEOL, c) || self.is(VEOL2, c) {
+                if self.lflag(ECHO) || (c == b'\n' && self.lflag(ECHONL)) {
+                    echo.push(c);
+                }
+                self.line.push(c);
+                self.ready.push_back(std::mem::take(&mut self.line));
+            } else {
+                self.echo(&mut echo, c);
+                self.line.push(c);
+            }
+        }
+        if !echo.is_empty() {
+            self.send(&echo, self.blocking);
+        }
+    }
+
+    /// Echo `c` if ECHO is on, control characters as ^X with ECHOCTL
+    fn echo(&self, echo: &mut Vec<u8>, c: u8) {
+        if !self.lflag(ECHO) {
+            return;
+        }
+        if (c < 0x20 && c != b'\t' && c != b'\n' || c == 0x7f) && self.lflag(ECHOCTL) {
+            echo.extend([b'^', c ^ 0x40]);
+        } else {
+            echo.push(c);
+        }
+    }
+
+    /// Remove up to `chars` characters (not bytes: UTF-8 sequences go
+    /// whole) from the end of the line, rubbing them out with ECHOE
+    fn erase(&mut self, echo: &mut Vec<u8>, chars: usize) {
+        for _ in 0..chars {
+            let Some(mut c) = self.line.pop() else {
+                break;
+            };
+            while c & 0xc0 == 0x80 {
+                match self.line.pop() {
+                    Some(lead) => c = lead,
+                    None => break,
+                }
+            }
+            if self.lflag(ECHO) && self.lflag(ECHOE) {
+                let width = if (c < 0x20 && c != b'\t' || c == 0x7f) && self.lflag(ECHOCTL) { 2 } else { 1 };
+                for _ in 0..width {
+                    echo.extend(b"\x08 \x08");
+                }
+            }
+        }
+    }
+
+    /// Discard typed-ahead input (TCIFLUSH)
+    fn flush_input(&mut self) {
+        self.input.clear();
+        self.line.clear();
+        self.ready.clear();
+    }
+
+    /// Run what the host has pushed through the line discipline
+    fn pull(&mut self) {
+        let (data, closed) = self.input.drain();
+        self.input(&data);
+        if closed && !self.hangup {
+            self.hangup = true;
+            if !self.line.is_empty() {
+                self.ready.push_back(std::mem::take(&mut self.line));
+            }
+        }
+    }
+
+    fn onlcr(&self) -> bool {
+        self.termios.oflag & OPOST != 0 && self.termios.oflag & ONLCR != 0
+    }
+
+    /// Pass guest output (or echo) to the host, with OPOST processing
+    fn send(&mut self, data: &[u8], block: bool) {
+        if !self.onlcr() || !data.contains(&b'\n') {
+            let _ = self.output.write(data, block);
+            return;
+        }
+        let mut out = Vec::with_capacity(data.len() + 16);
+        for &c in data {
+            if c == b'\n' {
+                out.push(b'\r');
+            }
+            out.push(c);
+        }
+        let _ = self.output.write(&out, block);
+    }
+
+    /// How much of `data` fits in the output pipe after OPOST processing
+    fn fits(&self, data: &[u8]) -> usize {
+        let mut space = self.output.space();
+        for (i, &c) in data.iter().enumerate() {
+            let size = if c == b'\n' && self.onlcr() { 2 } else { 1 };
+            if size > space {
+                return i;
+            }
+            space -= size;
+        }
+        data.len()
+    }
+
+    /// Copy ready input to the guest; `None` if there is none
+    fn take_ready(&mut self, mem: &mut dyn GuestMemory, buf: u64, count: u64) -> Result<Option<u64>, Errno> {
+        let count = count as usize;
+        let mut data = Vec::new();
+        if self.canonical() {
+            let Some(line) = self.ready.front_mut() else {
+                return Ok(None);
+            };
+            let n = count.min(line.len());
+            data.extend(line.drain(..n));
+            if line.is_empty() {
+                self.ready.pop_front();
+            }
+        } else {
+            while data.len() < count {
+                let Some(chunk) = self.ready.front_mut() else {
+                    break;
+                };
+                let n = (count - data.len()).min(chunk.len());
+                data.extend(chunk.drain(..n));
+                if chunk.is_empty() {
+                    self.ready.pop_front();
+                }
+            }
+            if data.is_empty() {
+                return Ok(None);
+            }
+        }
+        mem.write(buf, &data)?;
+        Ok(Some(data.len() as u64))
+    }
+
+    /// Signal `process`'s group with `sig` if this is its controlling
+    /// terminal and the group is not in the foreground
+    fn background_check(&mut self, process: &Process, sig: i32) -> Result<(), Errno> {
+        if process.sid != self.session || process.pgid == self.foreground {
+            return Ok(());
+        }
+        self.signals.push((process.pgid, sig));
+        Err(ERESTARTSYS)
+    }
+
+    pub fn read(&mut self, mem: &mut dyn GuestMemory, process: &Process, buf: u64, count: u64) -> Result<u64, Errno> {
+        self.background_check(process, SIGTTIN)?;
+        if count == 0 {
+            return Ok(0);
+        }
+        // Piped input reads like a pipe: whatever is there, once there is any
+        let canonical = self.canonical();
+        let vmin = if self.piped { 1 } else { self.termios.cc[VMIN] as u64 };
+        let vtime = if self.piped { Duration::ZERO } else { Duration::from_millis(self.termios.cc[VTIME] as u64 * 100) };
+        let mut got = 0;
+        let mut timed_out = false;
+        loop {
+            self.pull();
+            if !self.signals.is_empty() {
+                return if got > 0 { Ok(got) } else { Err(ERESTARTSYS) };
+            }
+            if let Some(n) = self.take_ready(mem, buf + got, count - got)? {
+                got += n;
+                if canonical || got >= vmin.min(count) {
+                    return Ok(got);
+                }
+            }
+            if !canonical && ((vmin == 0 && (got > 0 || vtime.is_zero())) || timed_out) {
+                return Ok(got);
+            }
+            if self.hangup {
+                return Ok(got);
+            }
+            if self.nonblocking || !self.blocking {
+                return if got > 0 { Ok(got) } else { Err(EAGAIN) };
+            }
+            // VTIME times the whole read with VMIN = 0, else the gaps
+            // between bytes once one has come
+            let untimed = canonical || vtime.is_zero() || (vmin > 0 && got == 0);
+            let timeout = if untimed { None } else { Some(vtime) };
+            timed_out = !self.input.wait_readable(timeout);
+        }
+    }
+
+    pub fn write(&mut self, mem: &mut dyn GuestMemory, process: &Process, buf: u64, count: u64) -> Result<u64, Errno> {
+        if self.lflag(TOSTOP) {
+            self.background_check(process, SIGTTOU)?;
+        }
+        let mut data = vec![0u8; (count as usize).min(MAX_IO)];
+        mem.read(buf, &mut data)?;
+        // Without waiting for the host, only what fits goes out
+        let block = self.blocking && !self.nonblocking;
+        let n = if block { data.len() } else { self.fits(&data) };
+        if n == 0 && !data.is_empty() {
+            return Err(EAGAIN);
+        }
+        self.send(&data[..n], block);
+        Ok(n as u64)
+    }
+
+    /// The poll conditions of descriptors 0-2, after taking in what the
+    /// host has pushed
+    pub fn poll(&mut self) -> u32 {
+        self.pull();
+        let mut events = if self.output.writable() { POLLOUT } else { 0 };
+        if !self.ready.is_empty() || self.hangup {
+            events |= POLLIN;
+        }
+        if self.hangup {
+            events |= POLLHUP;
+        }
+        events
+    }
+
+    pub fn fcntl(&mut self, cmd: u64, arg: u64) -> Result<u64, Errno> {
+        match cmd {
+            F_GETFD | F_SETFD => Ok(0),
+            F_GETFL => Ok(O_RDWR | if self.nonblocking { O_NONBLOCK } else { 0 }),
+            F_SETFL => {
+                self.nonblocking = arg & O_NONBLOCK != 0;
+                Ok(0)
+            }
+            _ => Err(EINVAL),
+        }
+    }
+
+    /// An ioctl made by `process`
+    pub fn ioctl(
+        &mut self,
+        mem: &mut dyn GuestMemory,
+        process: &Process,
+        request: u64,
+        arg: u64,
+    ) -> Result<u64, Errno> {
+        match request {
+            TCGETS => mem.write(arg, &self.termios.encode())?,
+            TCSETS | TCSETSW | TCSETSF => {
+                let mut buf = [0u8; Termios::SIZE];
+                mem.read(arg, &mut buf)?;
+                if request == TCSETSF {
+                    self.flush_input();
+                }
+                if request != TCSETS {
+                    self.flush();
+                }
+                let was_canonical = self.lflag(ICANON);
+                self.termios = Termios::decode(&buf);
+                // Leaving canonical mode makes the partial line readable
+                if was_canonical && !self.lflag(ICANON) && !self.line.is_empty() {
+                    self.ready.push_back(std::mem::take(&mut self.line));
+                }
+            }
+            TCSBRK => self.flush(),
+            TCFLSH => match arg {
+                TCIFLUSH => self.flush_input(),
+                TCOFLUSH => self.output.clear(),
+                TCIOFLUSH => {
+                    self.flush_input();
+                    self.output.clear();
+                }
+                _ => return Err(EINVAL),
+            },
+            FIONREAD => {
+                self.pull();
+                let ready = if self.canonical() {
+                    self.ready.front().map_or(0, Vec::len)
+                } else {
+                    self.ready.iter().map(Vec::len).sum()
+                };
+                mem.write(arg, &(ready as i32).to_le_bytes())?;
+            }
+            TIOCGPGRP => mem.write(arg, &self.foreground.to_le_bytes())?,
+            TIOCGSID => mem.write(arg, &self.session.to_le_bytes())?,
+            TIOCGWINSZ => mem.write(arg, &self.winsize.encode())?,
+            TIOCSWINSZ => {
+                let mut buf = [0u8; WinSize::SIZE];
+                mem.read(arg, &mut buf)?;
+                self.resize(WinSize::decode(&buf));
+            }
+            TIOCSPGRP => {
+                let mut pgrp = [0u8; 4];
+                mem.read(arg, &mut pgrp)?;
+                let pgrp = i32::from_le_bytes(pgrp);
+                if pgrp < 0 {
+                    return Err(EINVAL);
+                }
+                // The only group in the session is the process's own
+                if process.sid != self.session || pgrp != process.pgid {
+                    return Err(EPERM);
+                }
+                self.foreground = pgrp;
+            }
+            _ => return Err(ENOTTY),
+        }
+        Ok(0)
+    }
+}
+
+impl Drop for Pty {
+    /// Let the console thread show what is left and finish, and the stdin
+    /// thread stop at its next read
+    fn drop(&mut self) {
+        self.output.close_write();
+        self.input.close_read();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::syscalls::{nr, Kernel};
+    use crate::Layout;
+    use std::sync::{Arc, Mutex};
+
+    /// Output collected for the test
+    #[derive(Clone, Default)]
+    struct FakeTerminal {
+        shown: Arc<Mutex<Vec<u8>>>,
+    }
+
+    impl Terminal for FakeTerminal {
+        fn write(&mut self, data: &[u8]) {
+            self.shown.lock().unwrap().extend_from_slice(data);
+        }
+    }
+
+    #[test]
+    fn test_line_discipline() {
+        let mut kernel = Kernel::new(Layout {
+            brk_base: 0x10000,
+            brk_max: 0x10000,
+            mmap_base: 0x20000,
+            limit: 0x100000,
+        });
+        let host = FakeTerminal::default();
+        kernel.pty = Pty::new(Box::new(host.clone()));
+        let mut mem = vec![0u8; 0x4000];
+        let input = kernel.pty.input.clone();
+        let type_keys = |keys: &[u8]| assert_eq!(input.write(keys, false), Ok(keys.len()));
+        let output = kernel.pty.output.clone();
+        let shown = || {
+            output.wait_empty();
+            std::mem::take(&mut *host.shown.lock().unwrap())
+        };
+
+        // Cooked: edited, echoed, and read a line at a time
+        type_keys(b"lx\x7fs -l\x17-a\rpwd\r");
+        assert_eq!(kernel.dispatch(&mut mem, nr::READ, [0, 0x100, 64, 0, 0, 0]), 6);
+        assert_eq!(&mem[0x100..0x106], b"ls -a\n");
+        assert_eq!(shown(), b"lx\x08 \x08s -l\x08 \x08\x08 \x08-a\r\npwd\r\n");
+        assert_eq!(kernel.dispatch(&mut mem, nr::READ, [0, 0x100, 64, 0, 0, 0]), 4);
+        assert_eq!(kernel.dispatch(&mut mem, nr::FCNTL, [0, F_SETFL, O_NONBLOCK, 0, 0, 0]), 0);
+        assert_eq!(kernel.dispatch(&mut mem, nr::READ, [0, 0x100, 64, 0, 0, 0]), EAGAIN.as_ret());
+
+        // A password prompt: echo off
+        assert_eq!(kernel.dispatch(&mut mem, nr::IOCTL, [0, TCGETS, 0x200, 0, 0, 0]), 0);
+        let mut termios = Termios::decode(mem[0x200..0x200 + Termios::SIZE].try_into().unwrap());
+        assert_eq!(termios, Termios::default());
+        termios.lflag &= !ECHO;
+        mem[0x200..0x200 + Termios::SIZE].copy_from_slice(&termios.encode());
+        assert_eq!(kernel.dispatch(&mut mem, nr::IOCTL, [0, TCSETSW, 0x200, 0, 0, 0]), 0);
+        type_keys(b"hunter2\r");
+        assert_eq!(kernel.dispatch(&mut mem, nr::READ, [0, 0x100, 64, 0, 0, 0]), 8);
+        assert_eq!(shown(), b"");
+
+        // ^C flushes typed-ahead input and kills the foreground process
+        type_keys(b"rm -rf \x03");
+        assert_eq!(kernel.dispatch(&mut mem, nr::READ, [0, 0x100, 64, 0, 0, 0]), ERESTARTSYS.as_ret());
+        assert_eq!(kernel.process.killed, Some(SIGINT));
+
+        // Raw: bytes as they come, nothing waits with VMIN = 0
+        termios.lflag &= !(ICANON | ISIG);
+        termios.cc[VMIN] = 0;
+        mem[0x200..0x200 + Termios::SIZE].copy_from_slice(&termios.encode());
+        assert_eq!(kernel.dispatch(&mut mem, nr::IOCTL, [0, TCSETS, 0x200, 0, 0, 0]), 0);
+        type_keys(b"\x03q");
+        assert_eq!(kernel.dispatch(&mut mem, nr::READ, [0, 0x100, 1, 0, 0, 0]), 1);
+        assert_eq!(mem[0x100], 0x03);
+        assert_eq!(kernel.dispatch(&mut mem, nr::IOCTL, [0, TCFLSH, TCIFLUSH, 0, 0, 0]), 0);
+        assert_eq!(kernel.dispatch(&mut mem, nr::READ, [0, 0x100, 1, 0, 0, 0]), 0);
+
+        mem[0x300..0x303].copy_from_slice(b"ok\n");
+        assert_eq!(kernel.dispatch(&mut mem, nr::WRITE, [1, 0x300, 3, 0, 0, 0]), 3);
+        assert_eq!(shown(), b"ok\r\n");
+        assert_eq!(kernel.dispatch(&mut mem, nr::IOCTL, [0, 0x5490, 0x200, 0, 0, 0]), ENOTTY.as_ret());
+    }
+
+    #[test]
+    fn test_window_size() {
+        let mut pty = Pty::new(Box::new(FakeTerminal::default()));
+        let process = Process::default();
+        let mut mem = vec![0u8; 0x100];
+        assert_eq!(pty.ioctl(&mut mem, &process, TIOCGWINSZ, 0x10), Ok(0));
+        assert_eq!(&mem[0x10..0x14], &[24, 0, 80, 0]);
+
+        // The host's window grows: SIGWINCH, and the guest sees the new size
+        pty.resize(WinSize { rows: 50, cols: 132, ..Default::default() });
+        assert_eq!(pty.take_signals(), [(1, SIGWINCH)]);
+        assert_eq!(pty.ioctl(&mut mem, &process, TIOCGWINSZ, 0x10), Ok(0));
+        assert_eq!(WinSize::decode(mem[0x10..0x18].try_into().unwrap()).cols, 132);
+
+        // Setting the size it already has is no change
+        assert_eq!(pty.ioctl(&mut mem, &process, TIOCSWINSZ, 0x10), Ok(0));
+        assert!(pty.take_signals().is_empty());
+    }
+
+    #[test]
+    fn test_raw_read_timing() {
+        let mut pty = Pty::new(Box::new(FakeTerminal::default()));
+        let process = Process::default();
+        let mut mem = vec![0u8; 0x2000];
+        pty.termios.lflag &= !(ICANON | ECHO);
+
+        // VMIN = 3: a read waits for three bytes, however they arrive
+        pty.termios.cc[VMIN] = 3;
+        let input = pty.input.clone();
+        let typist = std::thread::spawn(move || {
+            for key in [b"a", b"b", b"c"] {
+                std::thread::sleep(Duration::from_millis(5));
+                input.write(key, false).unwrap();
+            }
+        });
+        assert_eq!(pty.read(&mut mem, &process, 0x10, 8), Ok(3));
+        typist.join().unwrap();
+        assert_eq!(&mem[0x10..0x13], b"abc");
+
+        // VTIME = 1: a lone escape comes back after a tenth of a second
+        pty.termios.cc[VTIME] = 1;
+        pty.input.write(b"\x1b", false).unwrap();
+        assert_eq!(pty.read(&mut mem, &process, 0x10, 8), Ok(1));
+
+        // A full pipe takes no more, and reads as EOF once drained and closed
+        assert_eq!(pty.input.write(&[b'x'; INPUT_CAPACITY + 1], false), Ok(INPUT_CAPACITY));
+        assert_eq!(pty.input.write(b"x", false), Err(EAGAIN));
+        pty.input.close_write();
+        assert_eq!(pty.read(&mut mem, &process, 0x10, 0x1800), Ok(INPUT_CAPACITY as u64));
+        assert_eq!(pty.read(&mut mem, &process, 0x10, 8), Ok(0));
+    }
+
+    #[test]
+    fn test_output_flow_control() {
+        // Nothing drains the output: a guest that cannot wait fills it
+        let mut pty = Pty::detached();
+        pty.blocking = false;
+        let process = Process::default();
+        let mut mem = vec![b'\n'; 0x100];
+        let mut written = 0;
+        while let Ok(n) = pty.write(&mut mem, &process, 0, 0x100) {
+            written += n as usize;
+        }
+        assert_eq!(written, OUTPUT_CAPACITY / 2);
+        assert_eq!(pty.write(&mut mem, &process, 0, 0x100), Err(EAGAIN));
+
+        // Each newline became two bytes, and what the host takes makes room
+        let mut taken = [0u8; 4];
+        assert_eq!(pty.output.read(&mut taken, false), Ok(4));
+        assert_eq!(&taken, b"\r\n\r\n");
+        assert_eq!(pty.write(&mut mem, &process, 0, 0x100), Ok(2));
+
+        // TCOFLUSH drops the backlog
+        assert_eq!(pty.ioctl(&mut mem, &process, TCFLSH, TCOFLUSH), Ok(0));
+        assert!(pty.output.is_empty());
+    }
+}
@@ -0,0 +1,99 @@
+// random.rs - getrandom
+//
+// Guest entropy comes from the host's /dev/urandom, or, in deterministic
+// mode (`Machine::deterministic`), from a generator seeded by the host so
+// two runs see the same bytes. The seeded stream is SplitMix64: not for
+// keys, but the guest asked for reproducibility, not secrecy.
+
+use crate::mm::GuestMemory;
+use crate::syscalls::{Errno, EINVAL, EIO};
+use std::io::Read;
+
+/// GRND_NONBLOCK, GRND_RANDOM and GRND_INSECURE: accepted and ignored,
+/// neither source ever blocks
+const GRND_FLAGS: u64 = 0x7;
+
+/// Bytes returned by one getrandom, as Linux caps it
+const MAX_READ: u64 = (1 << 25) - 1;
+
+/// Where getrandom's bytes come from
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Random {
+    /// The host's entropy pool
+    Host,
+    /// SplitMix64 state
+    Seeded(u64),
+}
+
+impl Random {
+    /// Fill `buf` from the source
+    pub fn fill(&mut self, buf: &mut [u8]) -> Result<(), Errno> {
+        match self {
+            Random::Host => std::fs::File::open("/dev/urandom")
+                .and_then(|mut f| f.read_exact(buf))
+                .map_err(|_| EIO),
+            Random::Seeded(state) => {
+                for chunk in buf.chunks_mut(8) {
+                    *state = state.wrapping_add(0x9e37_79b9_7f4a_7c15);
+                    let mut z = *state;
+                    z = (z ^ (z >> 30)).wrapping_mul(0xbf58_476d_1ce4_e5b9);
+                    z = (z ^ (z >> 27)).wrapping_mul(0x94d0_49bb_1331_11eb);
+                    z ^= z >> 31;
+                    chunk.copy_from_slice(&z.to_le_bytes()[..chunk.len()]);
+                }
+                Ok(())
+            }
+        }
+    }
+
+    pub fn getrandom(&mut self, mem: &mut dyn GuestMemory, buf: u64, len: u64, flags: u64) -> Result<u64, Errno> {
+        if flags & !GRND_FLAGS != 0 {
+            return Err(EINVAL);
+        }
+        let len = len.min(MAX_READ);
+        let mut chunk = [0u8; 256];
+        let mut done = 0;
+        while done < len {
+            let n = (len - done).min(chunk.len() as u64) as usize;
+            self.fill(&mut chunk[..n])?;
+            mem.write(buf + done, &chunk[..n])?;
+            done += n as u64;
+        }
+        Ok(len)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::syscalls::{nr, Kernel};
+    use crate::Layout;
+
+    #[test]
+    fn test_seeded_getrandom() {
+        let kernel = || {
+            let mut kernel = Kernel::new(Layout {
+                brk_base: 0x10000,
+                brk_max: 0x10000,
+                mmap_base: 0x20000,
+                limit: 0x100000,
+            });
+            kernel.random = Random::Seeded(42);
+            kernel
+        };
+        let (mut a, mut b) = (kernel(), kernel());
+        let mut mem = vec![0u8; 0x4000];
+
+        // The same seed gives the same bytes, whatever the flags
+        assert_eq!(a.dispatch(&mut mem, nr::GETRANDOM, [0x100, 300, 0, 0, 0, 0]), 300);
+        let first = mem[0x100..0x100 + 300].to_vec();
+        assert_eq!(b.dispatch(&mut mem, nr::GETRANDOM, [0x1000, 300, 1, 0, 0, 0]), 300);
+        assert_eq!(&mem[0x1000..0x1000 + 300], &first[..]);
+        assert!(first.iter().any(|&byte| byte != 0));
+
+        // The stream moves on; unknown flags are refused
+        assert_eq!(a.dispatch(&mut mem, nr::GETRANDOM, [0x100, 8, 0, 0, 0, 0]), 8);
+        assert_ne!(&mem[0x100..0x108], &first[..8]);
+        assert_eq!(a.dispatch(&mut mem, nr::GETRANDOM, [0x100, 8, 0x10, 0, 0, 0]), EINVAL.as_ret());
+    }
+}
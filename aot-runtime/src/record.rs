@@ -0,0 +1,254 @@
+// record.rs - Syscall record/replay
+//
+// Guest code is deterministic given its syscall results, so logging every
+// syscall's return value and the guest memory it wrote (read buffers,
+// clock_gettime, getrandom, ...) is enough to reproduce a run exactly:
+//
+//   friscy-run --record run.log prog      (e.g. from a failing browser session)
+//   friscy-run --replay run.log prog      (bit-identical re-execution)
+//
+// Memory-management syscalls only touch state the runtime owns, so they
+// are re-executed during replay (keeping the memory manager in sync) and
+// their results checked against the log instead of being injected.
+//
+// File format (little-endian):
+//   magic "FRSCYREC", u32 version, u32 reserved,
+//   then per syscall: u64 nr, 6 x u64 args, i64 ret, u32 write count,
+//   and per write: u64 addr, u32 len, len bytes.
+
+use crate::mm::GuestMemory;
+use crate::syscalls::{nr, Errno, Kernel};
+use anyhow::{bail, Context, Result};
+use std::io::{self, Read, Write};
+
+const MAGIC: &[u8; 8] = b"FRSCYREC";
+const VERSION: u32 = 1;
+
+/// One logged syscall
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Entry {
+    pub nr: u64,
+    pub args: [u64; 6],
+    pub ret: i64,
+    /// Guest memory written by the syscall, in order
+    pub writes: Vec<(u64, Vec<u8>)>,
+}
+
+/// Syscall log being recorded or replayed
+pub enum SyscallLog {
+    Record(Vec<Entry>),
+    Replay { entries: Vec<Entry>, next: usize },
+}
+
+/// Syscalls whose effects live entirely in runtime state
+fn reexecuted(nr: u64) -> bool {
+    matches!(nr, nr::BRK | nr::MMAP | nr::MUNMAP | nr::MPROTECT)
+}
+
+impl SyscallLog {
+    pub fn recorder() -> Self {
+        SyscallLog::Record(Vec::new())
+    }
+
+    /// Read a log for replay
+    pub fn load<R: Read>(mut r: R) -> Result<Self> {
+        let mut header = [0u8; 16];
+        r.read_exact(&mut header).context("Truncated syscall log")?;
+        if &header[..8] != MAGIC {
+            bail!("Not a syscall log");
+        }
+        let version = u32::from_le_bytes(header[8..12].try_into().unwrap());
+        if version != VERSION {
+            bail!("Unsupported syscall log version {}", version);
+        }
+
+        let mut entries = Vec::new();
+        while let Some(nr) = read_u64_or_eof(&mut r)? {
+            let mut args = [0u64; 6];
+            for a in &mut args {
+                *a = read_u64(&mut r)?;
+            }
+            let ret = read_u64(&mut r)? as i64;
+            let count = read_u32(&mut r)?;
+            let mut writes = Vec::new();
+            for _ in 0..count {
+                let addr = read_u64(&mut r)?;
+                let mut data = vec![0u8; read_u32(&mut r)? as usize];
+                r.read_exact(&mut data)?;
+                writes.push((addr, data));
+            }
+            entries.push(Entry { nr, args, ret, writes });
+        }
+        Ok(SyscallLog::Replay { entries, next: 0 })
+    }
+
+    pub fn entries(&self) -> &[Entry] {
+        match self {
+            SyscallLog::Record(entries) | SyscallLog::Replay { entries, .. } => entries,
+        }
+    }
+
+    /// Write the log in the file format above
+    pub fn save<W: Write>(&self, mut w: W) -> io::Result<()> {
+        w.write_all(MAGIC)?;
+        w.write_all(&VERSION.to_le_bytes())?;
+        w.write_all(&0u32.to_le_bytes())?;
+        for e in self.entries() {
+            w.write_all(&e.nr.to_le_bytes())?;
+            for a in e.args {
+                w.write_all(&a.to_le_bytes())?;
+            }
+            w.write_all(&e.ret.to_le_bytes())?;
+            w.write_all(&(e.writes.len() as u32).to_le_bytes())?;
+            for (addr, data) in &e.writes {
+                w.write_all(&addr.to_le_bytes())?;
+                w.write_all(&(data.len() as u32).to_le_bytes())?;
+                w.write_all(data)?;
+            }
+        }
+        w.flush()
+    }
+
+    /// Service a syscall through the log. Fails if a replayed run
+    /// diverges from the recording.
+    pub fn dispatch(
+        &mut self,
+        kernel: &mut Kernel,
+        mem: &mut dyn GuestMemory,
+        nr: u64,
+        args: [u64; 6],
+    ) -> Result<i64> {
+        match self {
+            SyscallLog::Record(entries) => {
+                let (ret, writes) = if reexecuted(nr) {
+                    (kernel.dispatch(mem, nr, args), Vec::new())
+                } else {
+                    let mut recording = RecordingMemory { inner: mem, writes: Vec::new() };
+                    let ret = kernel.dispatch(&mut recording, nr, args);
+                    (ret, recording.writes)
+                };
+                entries.push(Entry { nr, args, ret, writes });
+                Ok(ret)
+            }
+            SyscallLog::Replay { entries, next } => {
+                let index = *next;
+                let Some(entry) = entries.get(index) else {
+                    bail!("Replay log exhausted at syscall #{} (nr {})", index, nr);
+                };
+                if entry.nr != nr || entry.args != args {
+                    bail!(
+                        "Replay diverged at syscall #{}: recorded nr {} {:x?}, got nr {} {:x?}",
+                        index,
+                        entry.nr,
+                        entry.args,
+                        nr,
+                        args
+                    );
+                }
+                *next += 1;
+                if reexecuted(nr) {
+                    let ret = kernel.dispatch(mem, nr, args);
+                    if ret != entry.ret {
+                        bail!("Replay diverged at syscall #{}: nr {} returned {}, recorded {}", index, nr, ret, entry.ret);
+                    }
+                    return Ok(ret);
+                }
+                for (addr, data) in &entry.writes {
+                    mem.write(*addr, data)
+                        .with_context(|| format!("Replaying syscall #{} write to 0x{:x}", index, addr))?;
+                }
+                Ok(entry.ret)
+            }
+        }
+    }
+}
+
+/// Forwards to guest memory, logging everything written
+struct RecordingMemory<'a> {
+    inner: &'a mut dyn GuestMemory,
+    writes: Vec<(u64, Vec<u8>)>,
+}
+
+impl GuestMemory for RecordingMemory<'_> {
+    fn size(&self) -> u64 {
+        self.inner.size()
+    }
+
+    fn grow(&mut self, new_size: u64) -> bool {
+        self.inner.grow(new_size)
+    }
+
+    fn read(&self, addr: u64, buf: &mut [u8]) -> Result<(), Errno> {
+        self.inner.read(addr, buf)
+    }
+
+    fn write(&mut self, addr: u64, data: &[u8]) -> Result<(), Errno> {
+        self.inner.write(addr, data)?;
+        self.writes.push((addr, data.to_vec()));
+        Ok(())
+    }
+
+    fn fill(&mut self, addr: u64, len: u64, byte: u8) -> Result<(), Errno> {
+        self.inner.fill(addr, len, byte)?;
+        self.writes.push((addr, vec![byte; len as usize]));
+        Ok(())
+    }
+}
+
+fn read_u64_or_eof<R: Read>(r: &mut R) -> Result<Option<u64>> {
+    let mut b = [0u8; 8];
+    match r.read_exact(&mut b) {
+        Ok(()) => Ok(Some(u64::from_le_bytes(b))),
+        Err(e) if e.kind() == io::ErrorKind::UnexpectedEof => Ok(None),
+        Err(e) => Err(e.into()),
+    }
+}
+
+fn read_u64<R: Read>(r: &mut R) -> Result<u64> {
+    read_u64_or_eof(r)?.context("Truncated syscall log")
+}
+
+fn read_u32<R: Read>(r: &mut R) -> Result<u32> {
+    let mut b = [0u8; 4];
+    r.read_exact(&mut b).context("Truncated syscall log")?;
+    Ok(u32::from_le_bytes(b))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::mm::Layout;
+
+    fn kernel() -> Kernel {
+        Kernel::new(Layout {
+            brk_base: 0x10000,
+            brk_max: 0x10000,
+            mmap_base: 0x20000,
+            limit: 0x100000,
+        })
+    }
+
+    #[test]
+    fn test_record_then_replay() {
+        let mut mem = vec![0u8; 0x20000];
+        let mut log = SyscallLog::recorder();
+        let brk = log.dispatch(&mut kernel(), &mut mem, nr::BRK, [0x11000, 0, 0, 0, 0, 0]).unwrap();
+        log.dispatch(&mut kernel(), &mut mem, 9999, [0; 6]).unwrap();
+        let SyscallLog::Record(entries) = &mut log else { unreachable!() };
+        // A syscall that wrote guest memory (e.g. getrandom)
+        entries.push(Entry { nr: 278, args: [0x100, 4, 0, 0, 0, 0], ret: 4, writes: vec![(0x100, vec![1, 2, 3, 4])] });
+
+        let mut file = Vec::new();
+        log.save(&mut file).unwrap();
+        let mut replay = SyscallLog::load(file.as_slice()).unwrap();
+        assert_eq!(replay.entries(), log.entries());
+
+        let (mut k, mut mem) = (kernel(), vec![0u8; 0x20000]);
+        assert_eq!(replay.dispatch(&mut k, &mut mem, nr::BRK, [0x11000, 0, 0, 0, 0, 0]).unwrap(), brk);
+        assert_eq!(k.mm.current_brk(), 0x11000);
+        assert_eq!(replay.dispatch(&mut k, &mut mem, 9999, [0; 6]).unwrap(), -38);
+        assert!(replay.dispatch(&mut k, &mut mem, 278, [0x200, 4, 0, 0, 0, 0]).is_err());
+        assert_eq!(replay.dispatch(&mut k, &mut mem, 278, [0x100, 4, 0, 0, 0, 0]).unwrap(), 4);
+        assert_eq!(&mem[0x100..0x104], &[1, 2, 3, 4]);
+    }
+}
@@ -0,0 +1,234 @@
+// regtrace.rs - Golden register traces
+//
+// A register trace logs the whole register file each time a block starts,
+// so two runs of one program can be compared block by block: the compiled
+// code against the reference interpreter (`Machine::interpret`), or one
+// rv2wasm against another. The first record whose registers differ points
+// at the block before it, the one that computed them:
+//
+//   friscy-run --record run.log --interpret --reg-trace golden.rtr prog
+//   friscy-run --replay run.log --reg-trace jit.rtr prog
+//   friscy-trace golden.rtr jit.rtr --program prog
+//
+// (Recording syscalls keeps clocks, random bytes and input identical
+// between the two runs.)
+//
+// File format (little-endian): magic "FRSCYRTR", u32 version, u32 slot
+// count, then per block: u64 pc, a u128 mask of the slots that changed
+// since the previous record (every slot starts at zero) and their new
+// values in slot order. The slots are x0-x31, f0-f31 as doubles and f0-f31
+// as singles, which the machine state keeps apart.
+
+use anyhow::{bail, Context, Result};
+use rv2wasm::translate::{F32_REGS_OFFSET, F64_REGS_OFFSET};
+use std::io::{self, Read, Write};
+
+const MAGIC: &[u8; 8] = b"FRSCYRTR";
+const VERSION: u32 = 1;
+
+/// Registers per record: x, f (double view), f (single view)
+pub const SLOTS: usize = 96;
+
+/// One register file, by slot
+pub type Regs = [u64; SLOTS];
+
+/// Integer register ABI names
+const X_NAMES: [&str; 32] = [
+    "zero", "ra", "sp", "gp", "tp", "t0", "t1", "t2", "s0", "s1", "a0", "a1", "a2", "a3", "a4", "a5", "a6", "a7", "s2",
+    "s3", "s4", "s5", "s6", "s7", "s8", "s9", "s10", "s11", "t3", "t4", "t5", "t6",
+];
+
+/// Name of register slot `slot`
+pub fn slot_name(slot: usize) -> String {
+    match slot {
+        0..32 => X_NAMES[slot].to_string(),
+        32..64 => format!("f{}", slot - 32),
+        _ => format!("f{}.s", slot - 64),
+    }
+}
+
+/// The registers held in a machine state block (`$m`)
+pub fn regs_from_state(state: &[u8]) -> Regs {
+    let word = |offset: usize, len: usize| {
+        let mut bytes = [0u8; 8];
+        bytes[..len].copy_from_slice(&state[offset..offset + len]);
+        u64::from_le_bytes(bytes)
+    };
+    std::array::from_fn(|slot| match slot {
+        0..32 => word(slot * 8, 8),
+        32..64 => word(F64_REGS_OFFSET as usize + (slot - 32) * 8, 8),
+        _ => word(F32_REGS_OFFSET as usize + (slot - 64) * 4, 4),
+    })
+}
+
+/// One record: the registers on entry to the block at `pc`
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Entry {
+    pub pc: u64,
+    pub regs: Regs,
+}
+
+/// Streams a register trace to `W`
+pub struct RegTraceWriter<W: Write> {
+    out: W,
+    prev: Regs,
+}
+
+impl<W: Write> RegTraceWriter<W> {
+    pub fn new(mut out: W) -> io::Result<Self> {
+        out.write_all(MAGIC)?;
+        out.write_all(&VERSION.to_le_bytes())?;
+        out.write_all(&(SLOTS as u32).to_le_bytes())?;
+        Ok(Self { out, prev: [0; SLOTS] })
+    }
+
+    /// Log entry to the block at `pc` with registers `regs`
+    pub fn record(&mut self, pc: u64, regs: &Regs) -> io::Result<()> {
+        let changed = (0..SLOTS).filter(|&i| regs[i] != self.prev[i]);
+        let mask = changed.clone().fold(0u128, |mask, i| mask | 1 << i);
+        self.out.write_all(&pc.to_le_bytes())?;
+        self.out.write_all(&mask.to_le_bytes())?;
+        for i in changed {
+            self.out.write_all(&regs[i].to_le_bytes())?;
+        }
+        self.prev = *regs;
+        Ok(())
+    }
+
+    /// Flush and return the output
+    pub fn finish(mut self) -> io::Result<W> {
+        self.out.flush()?;
+        Ok(self.out)
+    }
+}
+
+/// Reads a register trace record by record
+pub struct RegTraceReader<R: Read> {
+    input: R,
+    regs: Regs,
+}
+
+impl<R: Read> RegTraceReader<R> {
+    pub fn new(mut input: R) -> Result<Self> {
+        let mut header = [0u8; 16];
+        input.read_exact(&mut header).context("Truncated register trace")?;
+        if &header[..8] != MAGIC {
+            bail!("Not a register trace");
+        }
+        let version = u32::from_le_bytes(header[8..12].try_into().unwrap());
+        let slots = u32::from_le_bytes(header[12..16].try_into().unwrap());
+        if version != VERSION || slots as usize != SLOTS {
+            bail!("Unsupported register trace version {} ({} registers)", version, slots);
+        }
+        Ok(Self { input, regs: [0; SLOTS] })
+    }
+
+    /// The next record, or None at the end of the trace. A run killed
+    /// mid-write leaves a partial record, which also ends it.
+    pub fn next_entry(&mut self) -> Result<Option<Entry>> {
+        let mut head = [0u8; 24];
+        match self.input.read_exact(&mut head) {
+            Ok(()) => {}
+            Err(e) if e.kind() == io::ErrorKind::UnexpectedEof => return Ok(None),
+            Err(e) => return Err(e).context("Failed to read register trace"),
+        }
+        let pc = u64::from_le_bytes(head[..8].try_into().unwrap());
+        let mask = u128::from_le_bytes(head[8..].try_into().unwrap());
+        for i in (0..SLOTS).filter(|&i| mask & 1 << i != 0) {
+            let mut value = [0u8; 8];
+            match self.input.read_exact(&mut value) {
+                Ok(()) => self.regs[i] = u64::from_le_bytes(value),
+                Err(e) if e.kind() == io::ErrorKind::UnexpectedEof => return Ok(None),
+                Err(e) => return Err(e).context("Failed to read register trace"),
+            }
+        }
+        Ok(Some(Entry { pc, regs: self.regs }))
+    }
+}
+
+/// Where two traces first disagree
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Divergence {
+    /// Records both traces agree on
+    pub index: u64,
+    /// The last of those: the block that went wrong, unless the traces
+    /// differ from the start
+    pub last: Option<Entry>,
+    /// Each trace's record at `index`, None where it ended
+    pub a: Option<Entry>,
+    pub b: Option<Entry>,
+}
+
+impl Divergence {
+    /// Slots whose values differ between the two records
+    pub fn registers(&self) -> Vec<usize> {
+        match (&self.a, &self.b) {
+            (Some(a), Some(b)) => (0..SLOTS).filter(|&i| a.regs[i] != b.regs[i]).collect(),
+            _ => Vec::new(),
+        }
+    }
+}
+
+/// Compare two traces; None if they are identical
+pub fn diff<A: Read, B: Read>(mut a: RegTraceReader<A>, mut b: RegTraceReader<B>) -> Result<Option<Divergence>> {
+    let mut last = None;
+    let mut index = 0;
+    loop {
+        let (ea, eb) = (a.next_entry()?, b.next_entry()?);
+        if ea.is_none() && eb.is_none() {
+            return Ok(None);
+        }
+        if ea != eb {
+            return Ok(Some(Divergence { index, last, a: ea, b: eb }));
+        }
+        last = ea;
+        index += 1;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn trace(blocks: &[(u64, &[(usize, u64)])]) -> Vec<u8> {
+        let mut w = RegTraceWriter::new(Vec::new()).unwrap();
+        let mut regs = [0; SLOTS];
+        for &(pc, writes) in blocks {
+            for &(slot, value) in writes {
+                regs[slot] = value;
+            }
+            w.record(pc, &regs).unwrap();
+        }
+        w.finish().unwrap()
+    }
+
+    #[test]
+    fn test_trace_diff() {
+        let a = trace(&[(0x1000, &[(2, 0x7fff0000)]), (0x1010, &[(10, 1)]), (0x1020, &[(10, 2), (40, 7)])]);
+        // Only changed registers are stored: a header, then 24 bytes per
+        // record plus 8 per changed register
+        assert_eq!(a.len(), 16 + 3 * 24 + 4 * 8);
+
+        let mut r = RegTraceReader::new(&a[..]).unwrap();
+        let mut entries = Vec::new();
+        while let Some(e) = r.next_entry().unwrap() {
+            entries.push(e);
+        }
+        assert_eq!(entries.iter().map(|e| e.pc).collect::<Vec<_>>(), [0x1000, 0x1010, 0x1020]);
+        assert_eq!((entries[2].regs[2], entries[2].regs[10], entries[2].regs[40]), (0x7fff0000, 2, 7));
+        assert_eq!(slot_name(10) + &slot_name(40) + &slot_name(70), "a0f8f6.s");
+
+        let same = |x: &[u8], y: &[u8]| diff(RegTraceReader::new(x).unwrap(), RegTraceReader::new(y).unwrap());
+        assert_eq!(same(&a, &a).unwrap(), None);
+
+        // The block at 0x1010 left a0 wrong
+        let b = trace(&[(0x1000, &[(2, 0x7fff0000)]), (0x1010, &[(10, 1)]), (0x1020, &[(10, 3), (40, 7)])]);
+        let d = same(&a, &b).unwrap().unwrap();
+        assert_eq!((d.index, d.last.as_ref().unwrap().pc), (2, 0x1010));
+        assert_eq!(d.registers(), [10]);
+
+        // A truncated trace ends where its last whole record does
+        let d = same(&a, &a[..a.len() - 4]).unwrap().unwrap();
+        assert_eq!((d.index, d.b), (2, None));
+    }
+}
@@ -0,0 +1,487 @@
+// syscalls.rs - Linux syscall layer
+//
+// Decodes RISC-V Linux syscalls (a7 = number, a0-a5 = arguments) and
+// routes them to the kernel subsystems (memory in `mm.rs`, clocks in
+// `time.rs`, sockets in `net.rs`, the terminal in `pty.rs`, pipes in
+// `pipe.rs`, ppoll, pselect6 and epoll in `poll.rs`, process ids and
+// signals in `process.rs`, getrandom in `random.rs`, files in `vfs.rs`, /proc files in `procfs.rs` and host
+// directory mounts in `hostfs.rs`). Results follow the kernel ABI:
+// non-negative on success, -errno on failure.
+
+use crate::hostfs::{Directory, Mounts};
+use crate::mm::{self, FileSource, GuestMemory, Layout, MemoryManager};
+use crate::net::{HostNetwork, Net};
+use crate::pipe::Pipes;
+use crate::poll::{self, Epolls};
+use crate::process::Process;
+use crate::procfs;
+use crate::pty::{HostTerminal, Pty};
+use crate::random::Random;
+use crate::vfs::{read_path, Vfs, AT_FDCWD};
+use crate::time::Time;
+use rv2wasm::loader::InitialImage;
+use std::fmt;
+
+/// A Linux errno value
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Errno(pub i32);
+
+impl Errno {
+    /// The value returned to the guest in a0
+    pub fn as_ret(self) -> i64 {
+        -(self.0 as i64)
+    }
+}
+
+impl fmt::Display for Errno {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "errno {}", self.0)
+    }
+}
+
+impl std::error::Error for Errno {}
+
+pub const EPERM: Errno = Errno(1);
+pub const ENOENT: Errno = Errno(2);
+pub const ESRCH: Errno = Errno(3);
+pub const EINTR: Errno = Errno(4);
+pub const EIO: Errno = Errno(5);
+pub const ENXIO: Errno = Errno(6);
+pub const EBADF: Errno = Errno(9);
+pub const EAGAIN: Errno = Errno(11);
+pub const ENOMEM: Errno = Errno(12);
+pub const EACCES: Errno = Errno(13);
+pub const EFAULT: Errno = Errno(14);
+pub const EBUSY: Errno = Errno(16);
+pub const EEXIST: Errno = Errno(17);
+pub const EXDEV: Errno = Errno(18);
+pub const ENOTDIR: Errno = Errno(20);
+pub const EISDIR: Errno = Errno(21);
+pub const EINVAL: Errno = Errno(22);
+pub const ENOTTY: Errno = Errno(25);
+pub const EFBIG: Errno = Errno(27);
+pub const ENOSPC: Errno = Errno(28);
+pub const ESPIPE: Errno = Errno(29);
+pub const EROFS: Errno = Errno(30);
+pub const EPIPE: Errno = Errno(32);
+pub const ERANGE: Errno = Errno(34);
+pub const ENAMETOOLONG: Errno = Errno(36);
+pub const ENOSYS: Errno = Errno(38);
+pub const ENOTEMPTY: Errno = Errno(39);
+pub const ELOOP: Errno = Errno(40);
+pub const EDESTADDRREQ: Errno = Errno(89);
+pub const EPROTONOSUPPORT: Errno = Errno(93);
+pub const EOPNOTSUPP: Errno = Errno(95);
+pub const EAFNOSUPPORT: Errno = Errno(97);
+pub const EADDRINUSE: Errno = Errno(98);
+pub const EADDRNOTAVAIL: Errno = Errno(99);
+pub const ECONNRESET: Errno = Errno(104);
+pub const EISCONN: Errno = Errno(106);
+pub const ENOTCONN: Errno = Errno(107);
+pub const ETIMEDOUT: Errno = Errno(110);
+pub const ECONNREFUSED: Errno = Errno(111);
+/// Interrupted by a signal; never returned to the guest, which makes the
+/// syscall again (`Machine` rewinds to the ECALL)
+pub const ERESTARTSYS: Errno = Errno(512);
+
+/// RISC-V Linux syscall numbers (asm-generic table)
+pub mod nr {
+    pub const GETCWD: u64 = 17;
+    pub const EPOLL_CREATE1: u64 = 20;
+    pub const EPOLL_CTL: u64 = 21;
+    pub const EPOLL_PWAIT: u64 = 22;
+    pub const FCNTL: u64 = 25;
+    pub const IOCTL: u64 = 29;
+    pub const MKDIRAT: u64 = 34;
+    pub const UNLINKAT: u64 = 35;
+    pub const SYMLINKAT: u64 = 36;
+    pub const FTRUNCATE: u64 = 46;
+    pub const FACCESSAT: u64 = 48;
+    pub const FCHMOD: u64 = 52;
+    pub const FCHMODAT: u64 = 53;
+    pub const OPENAT: u64 = 56;
+    pub const CLOSE: u64 = 57;
+    pub const PIPE2: u64 = 59;
+    pub const GETDENTS64: u64 = 61;
+    pub const LSEEK: u64 = 62;
+    pub const READ: u64 = 63;
+    pub const WRITE: u64 = 64;
+    pub const READV: u64 = 65;
+    pub const WRITEV: u64 = 66;
+    pub const PREAD64: u64 = 67;
+    pub const PWRITE64: u64 = 68;
+    pub const PSELECT6: u64 = 72;
+    pub const PPOLL: u64 = 73;
+    pub const READLINKAT: u64 = 78;
+    pub const NEWFSTATAT: u64 = 79;
+    pub const FSTAT: u64 = 80;
+    pub const NANOSLEEP: u64 = 101;
+    pub const CLOCK_GETTIME: u64 = 113;
+    pub const CLOCK_GETRES: u64 = 114;
+    pub const CLOCK_NANOSLEEP: u64 = 115;
+    pub const KILL: u64 = 129;
+    pub const SETPGID: u64 = 154;
+    pub const GETPGID: u64 = 155;
+    pub const GETSID: u64 = 156;
+    pub const SETSID: u64 = 157;
+    pub const GETTIMEOFDAY: u64 = 169;
+    pub const GETPID: u64 = 172;
+    pub const GETPPID: u64 = 173;
+    pub const GETUID: u64 = 174;
+    pub const GETEUID: u64 = 175;
+    pub const GETGID: u64 = 176;
+    pub const GETEGID: u64 = 177;
+    pub const GETTID: u64 = 178;
+    pub const SOCKET: u64 = 198;
+    pub const BIND: u64 = 200;
+    pub const LISTEN: u64 = 201;
+    pub const ACCEPT: u64 = 202;
+    pub const CONNECT: u64 = 203;
+    pub const GETSOCKNAME: u64 = 204;
+    pub const GETPEERNAME: u64 = 205;
+    pub const SENDTO: u64 = 206;
+    pub const RECVFROM: u64 = 207;
+    pub const SHUTDOWN: u64 = 210;
+    pub const BRK: u64 = 214;
+    pub const MUNMAP: u64 = 215;
+    pub const MMAP: u64 = 222;
+    pub const MPROTECT: u64 = 226;
+    pub const ACCEPT4: u64 = 242;
+    pub const RENAMEAT2: u64 = 276;
+    pub const GETRANDOM: u64 = 278;
+}
+
+/// Most buffers a readv or writev takes (UIO_MAXIOV)
+const IOV_MAX: u64 = 1024;
+
+/// Per-process kernel state serviced by the syscall layer
+pub struct Kernel {
+    pub mm: MemoryManager,
+    /// Backing store for file-backed mmap, if not the VFS
+    pub files: Option<Box<dyn FileSource>>,
+    pub vfs: Vfs,
+    /// Host directories mounted over the VFS
+    pub mounts: Mounts,
+    pub time: Time,
+    pub net: Net,
+    /// Descriptors 0-2
+    pub pty: Pty,
+    /// Pipes the guest made with pipe2
+    pub pipes: Pipes,
+    pub epolls: Epolls,
+    pub process: Process,
+    pub random: Random,
+}
+
+impl Kernel {
+    pub fn new(layout: Layout) -> Self {
+        Self {
+            mm: MemoryManager::new(layout),
+            files: None,
+            vfs: Vfs::default(),
+            mounts: Mounts::default(),
+            time: Time::host(),
+            net: Net::new(Box::new(HostNetwork)),
+            pty: Pty::new(Box::new(HostTerminal)),
+            pipes: Pipes::default(),
+            epolls: Epolls::default(),
+            process: Process::default(),
+            random: Random::Host,
+        }
+    }
+
+    /// Start a process from a loader image: copy its segments and initial
+    /// stack into guest memory and register them with the memory manager.
+    /// The brk heap gets `brk_max` bytes above the image; mmap starts after it.
+    pub fn exec(
+        mem: &mut dyn GuestMemory,
+        image: &InitialImage,
+        brk_max: u64,
+        limit: u64,
+    ) -> Result<Self, Errno> {
+        let mut kernel = Kernel::new(Layout {
+            brk_base: image.brk_base,
+            brk_max,
+            mmap_base: image.brk_base + brk_max,
+            limit,
+        });
+
+        for seg in &image.segments {
+            if !mm::ensure_backed(mem, seg.vaddr + seg.memsz) {
+                return Err(ENOMEM);
+            }
+            mem.write(seg.vaddr, &seg.data)?;
+            let bss = seg.vaddr + seg.data.len() as u64;
+            mem.fill(bss, seg.memsz - seg.data.len() as u64, 0)?;
+
+            let page_off = seg.vaddr % mm::PAGE_SIZE;
+            kernel.mm.reserve(seg.vaddr, seg.memsz + page_off, segment_prot(seg.flags));
+        }
+
+        let stack = &image.stack;
+        if !mm::ensure_backed(mem, stack.top) {
+            return Err(ENOMEM);
+        }
+        kernel.mm.reserve(stack.top - stack.size, stack.size, mm::PROT_READ | mm::PROT_WRITE);
+        mem.write(stack.sp, &stack.data)?;
+
+        Ok(kernel)
+    }
+
+    /// Whether `fd` is open, other than as stdio
+    pub(crate) fn in_use(&self, fd: i32) -> bool {
+        let fd = fd as u64;
+        self.net.owns(fd) || self.vfs.owns(fd) || self.mounts.owns(fd) || self.pipes.owns(fd) || self.epolls.owns(fd)
+    }
+
+    /// The lowest descriptor above stdio that is free, for a new file or socket
+    pub fn free_fd(&self) -> i32 {
+        (3..).find(|&fd| !self.in_use(fd)).unwrap()
+    }
+
+    /// Mount `dir` at the absolute guest path `path`, making the mount point
+    /// in the VFS so it shows up in listings
+    pub fn mount(&mut self, path: &str, dir: Box<dyn Directory>, read_only: bool) -> Result<(), Errno> {
+        self.vfs.mount_point(path)?;
+        self.mounts.mount(path, dir, read_only);
+        Ok(())
+    }
+
+    /// The absolute directory a *at syscall's relative paths start from,
+    /// or "" if `dirfd` is not an open directory
+    fn base(&self, dirfd: u64) -> String {
+        match dirfd as i32 {
+            AT_FDCWD => Some(self.process.cwd.clone()),
+            _ if self.mounts.owns(dirfd) => self.mounts.fd_path(dirfd),
+            _ => self.vfs.fd_path(dirfd),
+        }
+        .unwrap_or_default()
+    }
+
+    /// Whether a *at syscall's path lands in a host mount
+    fn mounted(&self, mem: &dyn GuestMemory, dirfd: u64, path: u64) -> bool {
+        !self.mounts.is_empty()
+            && read_path(mem, path).is_ok_and(|path| self.mounts.find(&self.base(dirfd), &path).is_some())
+    }
+
+    /// openat in the VFS; a /proc file gets its text rendered now
+    fn openat(&mut self, mem: &dyn GuestMemory, args: [u64; 6]) -> Result<u64, Errno> {
+        let (new_fd, now) = (self.free_fd(), self.time.epoch_secs());
+        let fd = self.vfs.openat(mem, &self.process.cwd, args[0], args[1], args[2], args[3], new_fd, now)?;
+        if let Some(file) = self.vfs.proc_file(fd) {
+            let text = procfs::render(file, self);
+            self.vfs.fill(fd, text);
+        }
+        Ok(fd)
+    }
+
+    /// readv or writev: `nr` (READ or WRITE) on each of the `count` buffers
+    /// of the iovec array at `iov` in turn, routed like any read or write of
+    /// `fd`, until one comes up short. An error after some bytes moved
+    /// reports those bytes instead.
+    fn vectored(&mut self, mem: &mut dyn GuestMemory, nr: u64, fd: u64, iov: u64, count: u64) -> Result<u64, Errno> {
+        if count > IOV_MAX {
+            return Err(EINVAL);
+        }
+        let mut total = 0;
+        for i in 0..count {
+            let mut entry = [0u8; 16];
+            mem.read(iov + i * 16, &mut entry)?;
+            let base = u64::from_le_bytes(entry[..8].try_into().unwrap());
+            let len = u64::from_le_bytes(entry[8..].try_into().unwrap());
+            let ret = self.dispatch(mem, nr, [fd, base, len, 0, 0, 0]);
+            if ret < 0 {
+                return if total > 0 { Ok(total) } else { Err(Errno(-ret as i32)) };
+            }
+            total += ret as u64;
+            if (ret as u64) < len {
+                break;
+            }
+        }
+        Ok(total)
+    }
+
+    /// Handle one syscall; returns the value for a0
+    pub fn dispatch(&mut self, mem: &mut dyn GuestMemory, nr: u64, args: [u64; 6]) -> i64 {
+        if matches!(nr, nr::OPENAT | nr::NEWFSTATAT | nr::READLINKAT | nr::FACCESSAT) {
+            self.vfs.proc_links(&self.process.exe, &self.process.cwd);
+        }
+        let result = match nr {
+            nr::BRK => Ok(self.mm.brk(mem, args[0])),
+            nr::MMAP if self.mounts.owns(args[4]) => self.mm.mmap(
+                mem,
+                Some(&self.mounts),
+                args[0],
+                args[1],
+                args[2] as u32,
+                args[3] as u32,
+                args[4] as i32,
+                args[5],
+            ),
+            nr::MMAP => self.mm.mmap(
+                mem,
+                Some(self.files.as_deref().unwrap_or(&self.vfs)),
+                args[0],
+                args[1],
+                args[2] as u32,
+                args[3] as u32,
+                args[4] as i32,
+                args[5],
+            ),
+            nr::MUNMAP => self.mm.munmap(args[0], args[1]).map(|_| 0),
+            nr::MPROTECT => self.mm.mprotect(args[0], args[1], args[2] as u32).map(|_| 0),
+            nr::CLOCK_GETTIME => self.time.clock_gettime(mem, args[0], args[1]),
+            nr::CLOCK_GETRES => self.time.clock_getres(mem, args[0], args[1]),
+            nr::GETTIMEOFDAY => self.time.gettimeofday(mem, args[0], args[1]),
+            nr::NANOSLEEP => self.time.nanosleep(mem, args[0]),
+            nr::CLOCK_NANOSLEEP => self.time.clock_nanosleep(mem, args[0], args[1], args[2]),
+            nr::SOCKET => self.net.socket(self.free_fd(), args[0], args[1], args[2]),
+            nr::BIND => self.net.bind(mem, args[0], args[1], args[2]),
+            nr::LISTEN => self.net.listen(args[0], args[1]),
+            nr::ACCEPT => self.net.accept(mem, args[0], args[1], args[2], 0, self.free_fd()),
+            nr::ACCEPT4 => self.net.accept(mem, args[0], args[1], args[2], args[3], self.free_fd()),
+            nr::CONNECT => self.net.connect(mem, args[0], args[1], args[2]),
+            nr::GETSOCKNAME => self.net.getsockname(mem, args[0], args[1], args[2]),
+            nr::GETPEERNAME => self.net.getpeername(mem, args[0], args[1], args[2]),
+            nr::SENDTO => self.net.sendto(mem, args[0], args[1], args[2], args[4], args[5]),
+            nr::RECVFROM => self.net.recvfrom(mem, args[0], args[1], args[2], args[4], args[5]),
+            nr::SHUTDOWN => self.net.shutdown(args[0], args[1]),
+            nr::PPOLL => poll::ppoll(self, mem, args[0], args[1], args[2]),
+            nr::PSELECT6 => poll::pselect6(self, mem, args[0], [args[1], args[2], args[3]], args[4]),
+            nr::EPOLL_CREATE1 => self.epolls.create(self.free_fd(), args[0]),
+            nr::EPOLL_CTL => poll::epoll_ctl(self, mem, args[0], args[1], args[2], args[3]),
+            nr::EPOLL_PWAIT => poll::epoll_pwait(self, mem, args[0], args[1], args[2], args[3]),
+            nr::CLOSE if self.epolls.owns(args[0]) => self.epolls.close(args[0]),
+            nr::GETCWD => self.process.getcwd(mem, args[0], args[1]),
+            nr::GETPID => Ok(self.process.pid as u64),
+            nr::GETPPID => Ok(self.process.ppid as u64),
+            // One thread, so its tid is the pid
+            nr::GETTID => Ok(self.process.pid as u64),
+            nr::GETUID | nr::GETEUID => Ok(self.process.uid as u64),
+            nr::GETGID | nr::GETEGID => Ok(self.process.gid as u64),
+            nr::GETPGID => self.process.getpgid(args[0]),
+            nr::SETPGID => self.process.setpgid(args[0], args[1]),
+            nr::GETSID => self.process.getsid(args[0]),
+            nr::SETSID => self.process.setsid(),
+            nr::KILL => self.process.kill(args[0], args[1]),
+            nr::GETRANDOM => self.random.getrandom(mem, args[0], args[1], args[2]),
+            nr::READV => self.vectored(mem, nr::READ, args[0], args[1], args[2]),
+            nr::WRITEV => self.vectored(mem, nr::WRITE, args[0], args[1], args[2]),
+            // Paths in a host mount, and descriptors open there, go to the mount
+            nr::OPENAT if self.mounted(mem, args[0], args[1]) => {
+                let fd = self.free_fd();
+                self.mounts.openat(mem, &self.base(args[0]), args[1], args[2], args[3], fd)
+            }
+            nr::MKDIRAT if self.mounted(mem, args[0], args[1]) => {
+                self.mounts.mkdirat(mem, &self.base(args[0]), args[1], args[2])
+            }
+            nr::SYMLINKAT if self.mounted(mem, args[1], args[2]) => {
+                self.mounts.symlinkat(mem, args[0], &self.base(args[1]), args[2])
+            }
+            nr::UNLINKAT if self.mounted(mem, args[0], args[1]) => {
+                self.mounts.unlinkat(mem, &self.base(args[0]), args[1], args[2])
+            }
+            nr::RENAMEAT2 if self.mounted(mem, args[0], args[1]) || self.mounted(mem, args[2], args[3]) => {
+                let (old_base, new_base) = (self.base(args[0]), self.base(args[2]));
+                self.mounts.renameat2(mem, &old_base, args[1], &new_base, args[3], args[4])
+            }
+            nr::FCHMODAT if self.mounted(mem, args[0], args[1]) => {
+                self.mounts.fchmodat(mem, &self.base(args[0]), args[1], args[2])
+            }
+            nr::NEWFSTATAT if self.mounted(mem, args[0], args[1]) => {
+                self.mounts.newfstatat(mem, &self.base(args[0]), args[1], args[2], args[3])
+            }
+            nr::READLINKAT if self.mounted(mem, args[0], args[1]) => {
+                self.mounts.readlinkat(mem, &self.base(args[0]), args[1], args[2], args[3])
+            }
+            nr::FACCESSAT if self.mounted(mem, args[0], args[1]) => {
+                self.mounts.faccessat(mem, &self.base(args[0]), args[1], args[2])
+            }
+            nr::READ if self.mounts.owns(args[0]) => self.mounts.read(mem, args[0], args[1], args[2]),
+            nr::WRITE if self.mounts.owns(args[0]) => self.mounts.write(mem, args[0], args[1], args[2]),
+            nr::CLOSE if self.mounts.owns(args[0]) => self.mounts.close(args[0]),
+            nr::PREAD64 if self.mounts.owns(args[0]) => self.mounts.pread(mem, args[0], args[1], args[2], args[3]),
+            nr::PWRITE64 if self.mounts.owns(args[0]) => self.mounts.pwrite(mem, args[0], args[1], args[2], args[3]),
+            nr::FTRUNCATE if self.mounts.owns(args[0]) => self.mounts.ftruncate(args[0], args[1]),
+            nr::FCHMOD if self.mounts.owns(args[0]) => self.mounts.fchmod(args[0], args[1]),
+            nr::LSEEK if self.mounts.owns(args[0]) => self.mounts.lseek(args[0], args[1], args[2]),
+            nr::GETDENTS64 if self.mounts.owns(args[0]) => self.mounts.getdents64(mem, args[0], args[1], args[2]),
+            nr::FSTAT if self.mounts.owns(args[0]) => self.mounts.fstat(mem, args[0], args[1]),
+            nr::IOCTL if self.mounts.owns(args[0]) => Err(ENOTTY),
+            nr::OPENAT => self.openat(mem, args),
+            nr::MKDIRAT => self.vfs.mkdirat(mem, &self.process.cwd, args[0], args[1], args[2], self.time.epoch_secs()),
+            nr::SYMLINKAT => self.vfs.symlinkat(mem, &self.process.cwd, args[0], args[1], args[2], self.time.epoch_secs()),
+            nr::UNLINKAT => self.vfs.unlinkat(mem, &self.process.cwd, args[0], args[1], args[2]),
+            nr::RENAMEAT2 => self.vfs.renameat2(mem, &self.process.cwd, args[0], args[1], args[2], args[3], args[4]),
+            nr::FCHMODAT => self.vfs.fchmodat(mem, &self.process.cwd, args[0], args[1], args[2]),
+            nr::NEWFSTATAT => self.vfs.newfstatat(mem, &self.process.cwd, args[0], args[1], args[2], args[3]),
+            nr::READLINKAT => self.vfs.readlinkat(mem, &self.process.cwd, args[0], args[1], args[2], args[3]),
+            nr::FACCESSAT => self.vfs.faccessat(mem, &self.process.cwd, args[0], args[1], args[2]),
+            nr::WRITE if self.net.owns(args[0]) => self.net.sendto(mem, args[0], args[1], args[2], 0, 0),
+            nr::READ if self.net.owns(args[0]) => self.net.recvfrom(mem, args[0], args[1], args[2], 0, 0),
+            nr::CLOSE if self.net.owns(args[0]) => self.net.close(args[0]),
+            nr::WRITE if self.pty.owns(args[0]) => self.pty.write(mem, &self.process, args[1], args[2]),
+            nr::READ if self.pty.owns(args[0]) => self.pty.read(mem, &self.process, args[1], args[2]),
+            nr::FCNTL if self.pty.owns(args[0]) => self.pty.fcntl(args[1], args[2]),
+            nr::IOCTL if self.pty.owns(args[0]) => self.pty.ioctl(mem, &self.process, args[1], args[2]),
+            nr::IOCTL if self.net.owns(args[0]) => Err(ENOTTY),
+            nr::PIPE2 => {
+                let read_fd = self.free_fd();
+                let write_fd = (read_fd + 1..).find(|&fd| !self.in_use(fd)).unwrap();
+                self.pipes.pipe2(mem, args[0], args[1], read_fd, write_fd)
+            }
+            nr::READ if self.pipes.owns(args[0]) => self.pipes.read(mem, args[0], args[1], args[2], self.pty.blocking),
+            nr::WRITE if self.pipes.owns(args[0]) => {
+                self.pipes.write(mem, &mut self.process, args[0], args[1], args[2], self.pty.blocking)
+            }
+            nr::CLOSE if self.pipes.owns(args[0]) => self.pipes.close(args[0]),
+            nr::FCNTL if self.pipes.owns(args[0]) => self.pipes.fcntl(args[0], args[1], args[2]),
+            nr::IOCTL if self.pipes.owns(args[0]) => self.pipes.ioctl(mem, args[0], args[1], args[2]),
+            nr::FSTAT if self.pipes.owns(args[0]) => self.pipes.fstat(mem, args[0], args[1]),
+            nr::LSEEK | nr::PREAD64 | nr::PWRITE64 if self.pipes.owns(args[0]) => Err(ESPIPE),
+            nr::READ if self.vfs.is_random(args[0]) => self.random.getrandom(mem, args[1], args[2], 0),
+            nr::PREAD64 if self.vfs.is_random(args[0]) => self.random.getrandom(mem, args[1], args[2], 0),
+            // Any other descriptor is a file, or not open
+            nr::READ => self.vfs.read(mem, args[0], args[1], args[2]),
+            nr::WRITE => self.vfs.write(mem, args[0], args[1], args[2], self.time.epoch_secs()),
+            nr::CLOSE => self.vfs.close(args[0]),
+            nr::PREAD64 => self.vfs.pread(mem, args[0], args[1], args[2], args[3]),
+            nr::PWRITE64 => self.vfs.pwrite(mem, args[0], args[1], args[2], args[3], self.time.epoch_secs()),
+            nr::FTRUNCATE => self.vfs.ftruncate(args[0], args[1], self.time.epoch_secs()),
+            nr::FCHMOD => self.vfs.fchmod(args[0], args[1]),
+            nr::LSEEK => self.vfs.lseek(args[0], args[1], args[2]),
+            nr::GETDENTS64 => self.vfs.getdents64(mem, args[0], args[1], args[2]),
+            nr::FSTAT => self.vfs.fstat(mem, args[0], args[1]),
+            nr::IOCTL if self.vfs.owns(args[0]) => Err(ENOTTY),
+            _ => Err(ENOSYS),
+        };
+        // A closed descriptor leaves every epoll interest list
+        if nr == nr::CLOSE && result.is_ok() {
+            self.epolls.forget(args[0]);
+        }
+        for (pgid, sig) in self.pty.take_signals() {
+            if pgid == self.process.pgid {
+                self.process.signal(sig);
+            }
+        }
+        match result {
+            Ok(v) => v as i64,
+            Err(e) => e.as_ret(),
+        }
+    }
+}
+
+/// ELF PF_* flags to PROT_* bits
+fn segment_prot(flags: u32) -> u32 {
+    let mut prot = 0;
+    if flags & 4 != 0 {
+        prot |= mm::PROT_READ;
+    }
+    if flags & 2 != 0 {
+        prot |= mm::PROT_WRITE;
+    }
+    if flags & 1 != 0 {
+        prot |= mm::PROT_EXEC;
+    }
+    prot
+}
@@ -0,0 +1,168 @@
+// telemetry.rs - Runtime counters
+//
+// `Telemetry` counts what a machine spends its time on: trips around the
+// dispatch loop, blocks executed (compiled or on the interpreter), blocks
+// that ran on the interpreter, code windows compiled, and syscalls by
+// number. The counters are atomics the machine shares with its host
+// (`Machine::telemetry`), so a dashboard reads them from its own thread
+// while the guest runs, and charts the difference between two readings
+// (`Counts::since`). friscy-run --counters writes the final reading as JSON.
+
+use std::collections::BTreeMap;
+use std::fmt::Write;
+use std::sync::atomic::{AtomicU64, Ordering};
+
+/// Syscalls are counted by number below this; those at or above it are
+/// counted together under it
+pub const MAX_SYSCALL: usize = 512;
+
+/// Live counters, updated by the machine as it runs
+pub struct Telemetry {
+    dispatches: AtomicU64,
+    blocks: AtomicU64,
+    interpreted: AtomicU64,
+    compilations: AtomicU64,
+    syscalls: Box<[AtomicU64]>,
+}
+
+impl Default for Telemetry {
+    fn default() -> Self {
+        Self {
+            dispatches: AtomicU64::new(0),
+            blocks: AtomicU64::new(0),
+            interpreted: AtomicU64::new(0),
+            compilations: AtomicU64::new(0),
+            syscalls: (0..=MAX_SYSCALL).map(|_| AtomicU64::new(0)).collect(),
+        }
+    }
+}
+
+impl Telemetry {
+    /// One trip around the dispatch loop
+    pub(crate) fn dispatch(&self) {
+        self.dispatches.fetch_add(1, Ordering::Relaxed);
+    }
+
+    /// One block run, on the interpreter or as compiled code
+    pub(crate) fn block(&self, interpreted: bool) {
+        self.blocks.fetch_add(1, Ordering::Relaxed);
+        if interpreted {
+            self.interpreted.fetch_add(1, Ordering::Relaxed);
+        }
+    }
+
+    /// One code window (or single-step block) compiled
+    pub(crate) fn compile(&self) {
+        self.compilations.fetch_add(1, Ordering::Relaxed);
+    }
+
+    pub(crate) fn syscall(&self, nr: u64) {
+        let slot = (nr as usize).min(MAX_SYSCALL);
+        self.syscalls[slot].fetch_add(1, Ordering::Relaxed);
+    }
+
+    /// The counters as they stand
+    pub fn read(&self) -> Counts {
+        let syscalls = self
+            .syscalls
+            .iter()
+            .enumerate()
+            .map(|(nr, count)| (nr as u64, count.load(Ordering::Relaxed)))
+            .filter(|&(_, count)| count != 0)
+            .collect();
+        Counts {
+            dispatches: self.dispatches.load(Ordering::Relaxed),
+            blocks: self.blocks.load(Ordering::Relaxed),
+            interpreted: self.interpreted.load(Ordering::Relaxed),
+            compilations: self.compilations.load(Ordering::Relaxed),
+            syscalls,
+        }
+    }
+}
+
+/// A reading of the counters
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct Counts {
+    /// Trips around the dispatch loop (`Machine::run`)
+    pub dispatches: u64,
+    /// Blocks executed, including single steps and interpreted blocks
+    pub blocks: u64,
+    /// Blocks that ran on the reference interpreter instead of compiled code
+    pub interpreted: u64,
+    /// Code windows compiled
+    pub compilations: u64,
+    /// Syscalls made, by number (nonzero counts only)
+    pub syscalls: BTreeMap<u64, u64>,
+}
+
+impl Counts {
+    /// What was counted between `earlier` and this reading
+    pub fn since(&self, earlier: &Counts) -> Counts {
+        let syscalls = self
+            .syscalls
+            .iter()
+            .map(|(&nr, &count)| (nr, count - earlier.syscalls.get(&nr).copied().unwrap_or(0)))
+            .filter(|&(_, count)| count != 0)
+            .collect();
+        Counts {
+            dispatches: self.dispatches - earlier.dispatches,
+            blocks: self.blocks - earlier.blocks,
+            interpreted: self.interpreted - earlier.interpreted,
+            compilations: self.compilations - earlier.compilations,
+            syscalls,
+        }
+    }
+
+    /// Syscalls of any number
+    pub fn total_syscalls(&self) -> u64 {
+        self.syscalls.values().sum()
+    }
+
+    /// `{"dispatches": .., "blocks": .., "interpreted": .., "compilations":
+    /// .., "syscalls": {"<nr>": <count>, ..}}`
+    pub fn to_json(&self) -> String {
+        let mut out = format!(
+            "{{\"dispatches\":{},\"blocks\":{},\"interpreted\":{},\"compilations\":{},\"syscalls\":{{",
+            self.dispatches, self.blocks, self.interpreted, self.compilations
+        );
+        for (i, (nr, count)) in self.syscalls.iter().enumerate() {
+            let sep = if i == 0 { "" } else { "," };
+            write!(out, "{}\"{}\":{}", sep, nr, count).unwrap();
+        }
+        out.push_str("}}\n");
+        out
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_counts() {
+        let telemetry = Telemetry::default();
+        telemetry.dispatch();
+        telemetry.block(false);
+        telemetry.compile();
+        telemetry.syscall(64);
+        let earlier = telemetry.read();
+
+        telemetry.dispatch();
+        telemetry.block(true);
+        telemetry.syscall(64);
+        telemetry.syscall(63);
+        telemetry.syscall(100_000);
+        let now = telemetry.read();
+        assert_eq!(now.blocks, 2);
+        assert_eq!(now.interpreted, 1);
+        assert_eq!(now.syscalls, BTreeMap::from([(63, 1), (64, 2), (MAX_SYSCALL as u64, 1)]));
+        assert_eq!(now.total_syscalls(), 4);
+
+        let delta = now.since(&earlier);
+        assert_eq!((delta.dispatches, delta.blocks, delta.compilations), (1, 1, 0));
+        assert_eq!(
+            delta.to_json(),
+            "{\"dispatches\":1,\"blocks\":1,\"interpreted\":1,\"compilations\":0,\"syscalls\":{\"63\":1,\"64\":1,\"512\":1}}\n"
+        );
+    }
+}
@@ -0,0 +1,280 @@
+// time.rs - Guest clocks
+//
+// clock_gettime, gettimeofday and the sleeps are served from one host
+// clock, `Clock::monotonic_ns`: a single cheap import in a browser
+// (performance.now), `Instant` natively. CLOCK_REALTIME is the host's wall
+// time read once at boot plus the monotonic time since, so it advances with
+// CLOCK_MONOTONIC and never steps backwards under the guest. The CPU-time
+// clocks read as monotonic time: the guest has the hart to itself.
+//
+// Code compiled with `--time-page` answers clock_gettime and gettimeofday
+// from a page of guest memory instead of exiting (`rv2wasm::vdso`). The
+// host keeps that page current by calling `Time::publish` on a timer; the
+// guest sees time advance at that rate.
+//
+// In deterministic mode (`Machine::deterministic`) the clock is
+// `InstructionClock`: time is the count of retired instructions at one
+// nanosecond each, sleeps skip it forward instead of waiting, and
+// CLOCK_REALTIME starts at `DETERMINISTIC_EPOCH_NS`. The time page is
+// published whenever the host's timer fires, so it is not reproducible.
+
+use crate::mm::GuestMemory;
+use crate::syscalls::{Errno, EINVAL};
+use rv2wasm::abi::{TIME_MONOTONIC_OFFSET, TIME_REALTIME_OFFSET, TIME_SEQ_OFFSET};
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::Arc;
+use std::time::{Duration, Instant, SystemTime};
+
+const NS_PER_SEC: u64 = 1_000_000_000;
+
+/// TIMER_ABSTIME for clock_nanosleep
+const TIMER_ABSTIME: u64 = 1;
+
+/// The host's time source
+pub trait Clock: Send {
+    /// Nanoseconds since an arbitrary start; never decreases
+    fn monotonic_ns(&self) -> u64;
+
+    /// Block for `ns` nanoseconds
+    fn sleep_ns(&self, ns: u64) {
+        std::thread::sleep(Duration::from_nanos(ns));
+    }
+}
+
+/// `Clock` on the native monotonic clock
+pub struct HostClock(Instant);
+
+impl Clock for HostClock {
+    fn monotonic_ns(&self) -> u64 {
+        self.0.elapsed().as_nanos() as u64
+    }
+}
+
+/// CLOCK_REALTIME at boot in deterministic mode: 2000-01-01T00:00:00Z
+pub const DETERMINISTIC_EPOCH_NS: u64 = 946_684_800 * NS_PER_SEC;
+
+/// `Clock` on the guest's own progress: one nanosecond per retired
+/// instruction, counted by the machine into `retired`
+pub struct InstructionClock {
+    pub retired: Arc<AtomicU64>,
+    /// Nanoseconds slept so far
+    slept: AtomicU64,
+}
+
+impl InstructionClock {
+    pub fn new(retired: Arc<AtomicU64>) -> Self {
+        Self { retired, slept: AtomicU64::new(0) }
+    }
+}
+
+impl Clock for InstructionClock {
+    fn monotonic_ns(&self) -> u64 {
+        self.retired.load(Ordering::Relaxed) + self.slept.load(Ordering::Relaxed)
+    }
+
+    fn sleep_ns(&self, ns: u64) {
+        self.slept.fetch_add(ns, Ordering::Relaxed);
+    }
+}
+
+/// Which host reading a clock id maps to
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Source {
+    Realtime,
+    Monotonic,
+}
+
+/// Clock ids: REALTIME, REALTIME_COARSE, REALTIME_ALARM, TAI are wall time;
+/// MONOTONIC(_RAW, _COARSE), BOOTTIME(_ALARM) and the CPU-time clocks are not
+fn source(clock_id: u64) -> Result<Source, Errno> {
+    match clock_id {
+        0 | 5 | 8 | 11 => Ok(Source::Realtime),
+        1..=4 | 6 | 7 | 9 => Ok(Source::Monotonic),
+        _ => Err(EINVAL),
+    }
+}
+
+/// The process's clocks
+pub struct Time {
+    clock: Box<dyn Clock>,
+    /// CLOCK_REALTIME when the clock read 0
+    boot_realtime_ns: u64,
+    /// Guest address of the time page, if the program was compiled with one
+    pub page: Option<u64>,
+    /// Sequence count last published to the page
+    seq: u64,
+}
+
+impl Time {
+    pub fn new(clock: Box<dyn Clock>, boot_realtime_ns: u64) -> Self {
+        Self {
+            clock,
+            boot_realtime_ns,
+            page: None,
+            seq: 0,
+        }
+    }
+
+    /// Clocks on the host's own monotonic and wall clocks
+    pub fn host() -> Self {
+        let clock = HostClock(Instant::now());
+        let now = SystemTime::now().duration_since(SystemTime::UNIX_EPOCH).unwrap_or_default();
+        Self::new(Box::new(clock), now.as_nanos() as u64)
+    }
+
+    /// Clocks that advance with `retired` alone, starting from a fixed date
+    pub fn deterministic(retired: Arc<AtomicU64>) -> Self {
+        Self::new(Box::new(InstructionClock::new(retired)), DETERMINISTIC_EPOCH_NS)
+    }
+
+    /// The time on `clock_id`, in ns
+    pub fn now(&self, clock_id: u64) -> Result<u64, Errno> {
+        let monotonic = self.clock.monotonic_ns();
+        Ok(match source(clock_id)? {
+            Source::Realtime => self.boot_realtime_ns + monotonic,
+            Source::Monotonic => monotonic,
+        })
+    }
+
+    /// CLOCK_REALTIME in whole seconds, for file timestamps
+    pub fn epoch_secs(&self) -> u64 {
+        self.now(0).unwrap_or(0) / 1_000_000_000
+    }
+
+    pub fn clock_gettime(&self, mem: &mut dyn GuestMemory, clock_id: u64, tp: u64) -> Result<u64, Errno> {
+        let ns = self.now(clock_id)?;
+        mem.write(tp, &timespec(ns / NS_PER_SEC, ns % NS_PER_SEC))?;
+        Ok(0)
+    }
+
+    /// Every clock ticks in nanoseconds
+    pub fn clock_getres(&self, mem: &mut dyn GuestMemory, clock_id: u64, res: u64) -> Result<u64, Errno> {
+        source(clock_id)?;
+        if res != 0 {
+            mem.write(res, &timespec(0, 1))?;
+        }
+        Ok(0)
+    }
+
+    /// `tz` is always UTC
+    pub fn gettimeofday(&self, mem: &mut dyn GuestMemory, tv: u64, tz: u64) -> Result<u64, Errno> {
+        if tv != 0 {
+            let ns = self.now(0)?;
+            mem.write(tv, &timespec(ns / NS_PER_SEC, ns % NS_PER_SEC / 1000))?;
+        }
+        if tz != 0 {
+            mem.write(tz, &[0; 8])?;
+        }
+        Ok(0)
+    }
+
+    /// Wait `ns` nanoseconds, or skip them in deterministic mode
+    pub fn sleep_ns(&self, ns: u64) {
+        self.clock.sleep_ns(ns);
+    }
+
+    /// Sleeps are never interrupted, so `rem` is not written
+    pub fn nanosleep(&self, mem: &mut dyn GuestMemory, req: u64) -> Result<u64, Errno> {
+        self.clock.sleep_ns(read_timespec(mem, req)?);
+        Ok(0)
+    }
+
+    pub fn clock_nanosleep(
+        &self,
+        mem: &mut dyn GuestMemory,
+        clock_id: u64,
+        flags: u64,
+        req: u64,
+    ) -> Result<u64, Errno> {
+        source(clock_id)?;
+        let ns = read_timespec(mem, req)?;
+        let ns = if flags & TIMER_ABSTIME != 0 { ns.saturating_sub(self.now(clock_id)?) } else { ns };
+        self.clock.sleep_ns(ns);
+        Ok(0)
+    }
+
+    /// Write the current time to the time page, if there is one
+    pub fn publish(&mut self, mem: &mut dyn GuestMemory) -> Result<(), Errno> {
+        let Some(page) = self.page else {
+            return Ok(());
+        };
+        let monotonic = self.clock.monotonic_ns();
+        mem.write(page + TIME_SEQ_OFFSET as u64, &(self.seq + 1).to_le_bytes())?;
+        mem.write(page + TIME_MONOTONIC_OFFSET as u64, &monotonic.to_le_bytes())?;
+        mem.write(page + TIME_REALTIME_OFFSET as u64, &(self.boot_realtime_ns + monotonic).to_le_bytes())?;
+        self.seq += 2;
+        mem.write(page + TIME_SEQ_OFFSET as u64, &self.seq.to_le_bytes())
+    }
+}
+
+/// A struct timespec (or timeval): two i64s
+fn timespec(sec: u64, fraction: u64) -> [u8; 16] {
+    let mut out = [0u8; 16];
+    out[..8].copy_from_slice(&sec.to_le_bytes());
+    out[8..].copy_from_slice(&fraction.to_le_bytes());
+    out
+}
+
+/// A guest timespec as ns; EINVAL if negative or not normalized
+pub(crate) fn read_timespec(mem: &dyn GuestMemory, addr: u64) -> Result<u64, Errno> {
+    let mut buf = [0u8; 16];
+    mem.read(addr, &mut buf)?;
+    let sec = i64::from_le_bytes(buf[..8].try_into().unwrap());
+    let nsec = i64::from_le_bytes(buf[8..].try_into().unwrap());
+    if sec < 0 || !(0..NS_PER_SEC as i64).contains(&nsec) {
+        return Err(EINVAL);
+    }
+    Ok((sec as u64).saturating_mul(NS_PER_SEC).saturating_add(nsec as u64))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::syscalls::{nr, Kernel};
+    use crate::Layout;
+    use std::sync::atomic::{AtomicU64, Ordering};
+
+    /// A clock that only moves when slept on
+    struct FakeClock(AtomicU64);
+
+    impl Clock for FakeClock {
+        fn monotonic_ns(&self) -> u64 {
+            self.0.load(Ordering::Relaxed)
+        }
+
+        fn sleep_ns(&self, ns: u64) {
+            self.0.fetch_add(ns, Ordering::Relaxed);
+        }
+    }
+
+    #[test]
+    fn test_clock_syscalls() {
+        let mut kernel = Kernel::new(Layout {
+            brk_base: 0x10000,
+            brk_max: 0x10000,
+            mmap_base: 0x20000,
+            limit: 0x100000,
+        });
+        kernel.time = Time::new(Box::new(FakeClock(AtomicU64::new(5 * NS_PER_SEC))), 1_700_000_000 * NS_PER_SEC);
+        kernel.time.page = Some(0x2000);
+        let mut mem = vec![0u8; 0x4000];
+        let word = |mem: &Vec<u8>, addr: usize| u64::from_le_bytes(mem[addr..addr + 8].try_into().unwrap());
+
+        // nanosleep(1.5s), then both clocks have moved on
+        mem[0x100..0x110].copy_from_slice(&timespec(1, 500_000_000));
+        assert_eq!(kernel.dispatch(&mut mem, nr::NANOSLEEP, [0x100, 0, 0, 0, 0, 0]), 0);
+        assert_eq!(kernel.dispatch(&mut mem, nr::CLOCK_GETTIME, [1, 0x200, 0, 0, 0, 0]), 0);
+        assert_eq!((word(&mem, 0x200), word(&mem, 0x208)), (6, 500_000_000));
+        assert_eq!(kernel.dispatch(&mut mem, nr::GETTIMEOFDAY, [0x200, 0, 0, 0, 0, 0]), 0);
+        assert_eq!((word(&mem, 0x200), word(&mem, 0x208)), (1_700_000_006, 500_000));
+
+        // Sleeping until an absolute time already past returns at once
+        assert_eq!(kernel.dispatch(&mut mem, nr::CLOCK_NANOSLEEP, [1, TIMER_ABSTIME, 0x100, 0, 0, 0]), 0);
+        assert_eq!(kernel.time.now(1), Ok(6_500_000_000));
+        assert_eq!(kernel.dispatch(&mut mem, nr::CLOCK_GETTIME, [42, 0x200, 0, 0, 0, 0]), EINVAL.as_ret());
+
+        kernel.time.publish(&mut mem).unwrap();
+        assert_eq!((word(&mem, 0x2000), word(&mem, 0x2008)), (2, 6_500_000_000));
+        assert_eq!(word(&mem, 0x2010), 1_700_000_006_500_000_000);
+    }
+}
@@ -0,0 +1,160 @@
+// trace.rs - Execution tracing in Chrome trace format
+//
+// Records block executions, syscalls and JIT compilations with host
+// timestamps. The output is Chrome's JSON trace event format, which both
+// chrome://tracing and ui.perfetto.dev open directly:
+//
+//   friscy-run --trace trace.json prog
+//
+// Each event is a complete ("X") event on one of three tracks, so the
+// timeline shows where wall time goes between compiled guest code, the
+// syscall layer and the compiler.
+
+use std::io::{self, Write};
+use std::time::{Duration, Instant};
+
+/// Events kept by default before further events are dropped (~50 MB)
+pub const DEFAULT_LIMIT: usize = 1 << 20;
+
+/// What a trace event measured
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum EventKind {
+    /// A compiled block starting at `pc` ran
+    Block { pc: u64 },
+    /// Syscall `nr` was serviced and returned `ret`
+    Syscall { nr: u64, ret: i64 },
+    /// The code window at `pc` was compiled into `blocks` blocks
+    Compile { pc: u64, blocks: usize },
+}
+
+impl EventKind {
+    fn track(&self) -> (u32, &'static str) {
+        match self {
+            EventKind::Block { .. } => (1, "guest"),
+            EventKind::Syscall { .. } => (2, "syscall"),
+            EventKind::Compile { .. } => (3, "jit"),
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy)]
+pub struct TraceEvent {
+    pub kind: EventKind,
+    /// Offset from the start of the trace
+    pub start: Duration,
+    pub duration: Duration,
+}
+
+/// In-memory trace buffer
+pub struct Tracer {
+    origin: Instant,
+    events: Vec<TraceEvent>,
+    limit: usize,
+    dropped: u64,
+}
+
+impl Default for Tracer {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl Tracer {
+    pub fn new() -> Self {
+        Self::with_limit(DEFAULT_LIMIT)
+    }
+
+    /// Keep at most `limit` events; later ones are counted but dropped
+    pub fn with_limit(limit: usize) -> Self {
+        Self {
+            origin: Instant::now(),
+            events: Vec::new(),
+            limit,
+            dropped: 0,
+        }
+    }
+
+    /// Record an event that began at `started` and ends now
+    pub fn record(&mut self, kind: EventKind, started: Instant) {
+        if self.events.len() >= self.limit {
+            self.dropped += 1;
+            return;
+        }
+        self.events.push(TraceEvent {
+            kind,
+            start: started.saturating_duration_since(self.origin),
+            duration: started.elapsed(),
+        });
+    }
+
+    pub fn events(&self) -> &[TraceEvent] {
+        &self.events
+    }
+
+    /// Number of events dropped after the buffer filled
+    pub fn dropped(&self) -> u64 {
+        self.dropped
+    }
+
+    /// Write the trace as a Chrome trace JSON object
+    pub fn write_chrome_json<W: Write>(&self, mut out: W) -> io::Result<()> {
+        writeln!(out, "{{\"displayTimeUnit\":\"ns\",\"traceEvents\":[")?;
+        for (tid, name) in [(1, "guest"), (2, "syscall"), (3, "jit")] {
+            writeln!(
+                out,
+                "{{\"ph\":\"M\",\"pid\":1,\"tid\":{},\"name\":\"thread_name\",\"args\":{{\"name\":\"{}\"}}}},",
+                tid, name
+            )?;
+        }
+        for ev in &self.events {
+            let (tid, cat) = ev.kind.track();
+            let (name, args) = match ev.kind {
+                EventKind::Block { pc } => (format!("0x{:x}", pc), String::new()),
+                EventKind::Syscall { nr, ret } => (format!("syscall {}", nr), format!("\"ret\":{}", ret)),
+                EventKind::Compile { pc, blocks } => (format!("compile 0x{:x}", pc), format!("\"blocks\":{}", blocks)),
+            };
+            writeln!(
+                out,
+                "{{\"ph\":\"X\",\"pid\":1,\"tid\":{},\"cat\":\"{}\",\"name\":\"{}\",\"ts\":{:.3},\"dur\":{:.3},\"args\":{{{}}}}},",
+                tid,
+                cat,
+                name,
+                ev.start.as_secs_f64() * 1e6,
+                ev.duration.as_secs_f64() * 1e6,
+                args
+            )?;
+        }
+        // Trailing metadata event keeps the array free of a dangling comma
+        let process = match self.dropped {
+            0 => "friscy".to_string(),
+            n => format!("friscy ({} events dropped)", n),
+        };
+        writeln!(
+            out,
+            "{{\"ph\":\"M\",\"pid\":1,\"name\":\"process_name\",\"args\":{{\"name\":\"{}\"}}}}]}}",
+            process
+        )
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_chrome_json_output() {
+        let mut tracer = Tracer::with_limit(2);
+        let now = Instant::now();
+        tracer.record(EventKind::Compile { pc: 0x10000, blocks: 3 }, now);
+        tracer.record(EventKind::Syscall { nr: 64, ret: 5 }, now);
+        tracer.record(EventKind::Block { pc: 0x10000 }, now);
+        assert_eq!((tracer.events().len(), tracer.dropped()), (2, 1));
+
+        let mut out = Vec::new();
+        tracer.write_chrome_json(&mut out).unwrap();
+        let json = String::from_utf8(out).unwrap();
+        assert!(json.contains("\"name\":\"compile 0x10000\""));
+        assert!(json.contains("\"args\":{\"ret\":5}"));
+        assert!(json.trim_end().ends_with("]}"));
+    }
+}
@@ -0,0 +1,1403 @@
+// vfs.rs - Guest filesystem
+//
+// The process sees a tree built from a packed rootfs (`rv2wasm::rootfs::
+// Image`: embedded in the module by `rv2wasm --embed-rootfs`, or packed
+// from a tar at startup). The index is turned into inodes up front; a
+// regular file's contents are decompressed the first time it is opened and
+// kept from then on, so only the files the guest touches are ever resident
+// uncompressed.
+//
+// The image is the read-only lower layer of an overlay, as overlayfs
+// stacks a container's layers. Writes go to an in-memory upper layer: the
+// first write to a lower file copies it up, a created, changed or renamed
+// entry lives in the upper layer, and removing a name the image has leaves
+// a whiteout in its directory. `upper_layer` exports the upper layer as an
+// OCI-style layer tar (whiteouts as `.wh.` entries) and `apply_layer` loads
+// one back, so a host can persist a container's changes between runs.
+//
+// openat, read, write, pread64, pwrite64, lseek, getdents64, fstat,
+// newfstatat, readlinkat, faccessat, ftruncate, mkdirat, unlinkat,
+// symlinkat, renameat2, fchmod, fchmodat and close work on it. Paths
+// resolve against the process's cwd or a directory descriptor, following
+// symlinks up to Linux's limit. The tree always has /dev/null, /dev/zero,
+// /dev/random and /dev/urandom, which read and write as they do on Linux
+// (the random ones from the kernel's getrandom source); other devices are
+// ENXIO. It also has the /proc files `procfs.rs` renders, which an open
+// reads from the text the kernel gives it (`proc_file`, `fill`).
+// Descriptors are shared with sockets, so the syscall layer picks the
+// number a new file gets. Open files also back file mmaps (`FileSource`),
+// and `open_files` and `reopen` carry them through a machine snapshot.
+
+use anyhow::Context;
+use crate::mm::{FileSource, GuestMemory};
+use crate::procfs::{self, ProcFile};
+use crate::syscalls::{
+    Errno, EACCES, EBADF, EEXIST, EFBIG, EINVAL, EIO, EISDIR, ELOOP, ENAMETOOLONG, ENOENT, ENOTDIR, ENOTEMPTY, ENXIO,
+};
+use rv2wasm::rootfs::{self, Image, Kind, TarEntry};
+use rv2wasm::snapshot::FdEntry;
+use std::collections::{BTreeMap, BTreeSet, HashSet};
+use std::sync::{Arc, OnceLock};
+
+pub const AT_FDCWD: i32 = -100;
+pub const AT_SYMLINK_NOFOLLOW: u64 = 0x100;
+pub const AT_REMOVEDIR: u64 = 0x200;
+pub const AT_EMPTY_PATH: u64 = 0x1000;
+
+pub const O_ACCMODE: u64 = 3;
+pub const O_RDONLY: u64 = 0;
+pub const O_WRONLY: u64 = 1;
+pub const O_CREAT: u64 = 0o100;
+pub const O_EXCL: u64 = 0o200;
+pub const O_TRUNC: u64 = 0o1000;
+pub const O_APPEND: u64 = 0o2000;
+pub const O_DIRECTORY: u64 = 0o200000;
+pub const O_NOFOLLOW: u64 = 0o400000;
+pub const O_PATH: u64 = 0o10000000;
+
+/// renameat2's only supported flag
+pub const RENAME_NOREPLACE: u64 = 1;
+
+pub(crate) const SEEK_SET: u64 = 0;
+pub(crate) const SEEK_CUR: u64 = 1;
+pub(crate) const SEEK_END: u64 = 2;
+
+/// faccessat's X_OK and W_OK
+pub(crate) const X_OK: u64 = 1;
+pub(crate) const W_OK: u64 = 2;
+
+/// Longest path accepted from the guest, NUL included
+const PATH_MAX: usize = 4096;
+
+/// Symlinks followed in one lookup before ELOOP
+const MAX_SYMLINKS: usize = 40;
+
+/// st_rdev of /dev/null, /dev/zero, /dev/random and /dev/urandom (major
+/// 1, minors 3, 5, 8 and 9)
+const DEV_NULL: u64 = 0x103;
+const DEV_ZERO: u64 = 0x105;
+const DEV_RANDOM: u64 = 0x108;
+const DEV_URANDOM: u64 = 0x109;
+
+/// The device nodes every tree has
+const DEVICES: [(&str, u64); 4] =
+    [("/dev/null", DEV_NULL), ("/dev/zero", DEV_ZERO), ("/dev/random", DEV_RANDOM), ("/dev/urandom", DEV_URANDOM)];
+
+/// Size of the asm-generic struct stat
+pub const STAT_SIZE: usize = 128;
+
+/// Guest bytes copied per step of a write
+const WRITE_CHUNK: usize = 64 * 1024;
+
+/// The file creation mask: Linux's usual 022, as there is no umask(2) yet
+pub(crate) const UMASK: u64 = 0o022;
+
+/// Largest file the in-memory upper layer holds
+const MAX_FILE_SIZE: u64 = 1 << 32;
+
+/// Layer entries that hide a name, or a directory's lower contents
+const WHITEOUT: &str = ".wh.";
+const OPAQUE: &str = ".wh..wh..opq";
+
+/// The root directory's inode
+const ROOT: usize = 0;
+
+/// A file, directory, link or device in the tree
+struct Inode {
+    kind: Kind,
+    mode: u32,
+    uid: u32,
+    gid: u32,
+    mtime: u64,
+    rdev: u64,
+    size: u64,
+    /// Symlink target
+    link: String,
+    parent: usize,
+    children: BTreeMap<String, usize>,
+    /// Names removed from this directory that the image has
+    whiteouts: BTreeSet<String>,
+    /// Created, changed or moved since the image: part of the upper layer
+    upper: bool,
+    /// The file's node in the image, until it is decompressed into `data`
+    packed: Option<usize>,
+    data: OnceLock<Arc<[u8]>>,
+    /// The contents once copied up; `data` is dropped then
+    written: Option<Vec<u8>>,
+    /// The /proc file whose text an open of this one reads
+    proc: Option<ProcFile>,
+}
+
+impl Inode {
+    fn dir(parent: usize) -> Self {
+        Self {
+            kind: Kind::Dir,
+            mode: 0o755,
+            uid: 0,
+            gid: 0,
+            mtime: 0,
+            rdev: 0,
+            size: 0,
+            link: String::new(),
+            parent,
+            children: BTreeMap::new(),
+            whiteouts: BTreeSet::new(),
+            upper: false,
+            packed: None,
+            data: OnceLock::new(),
+            written: None,
+            proc: None,
+        }
+    }
+
+    /// A new upper-layer entry
+    fn created(kind: Kind, mode: u32, parent: usize, now: u64) -> Self {
+        Self {
+            kind,
+            mode: mode & 0o7777,
+            mtime: now,
+            upper: true,
+            written: (kind == Kind::File).then(Vec::new),
+            ..Self::dir(parent)
+        }
+    }
+
+    /// st_mode: file type bits and permissions
+    fn st_mode(&self) -> u32 {
+        let kind = match self.kind {
+            Kind::File | Kind::Hardlink => 0o100000,
+            Kind::Dir => 0o040000,
+            Kind::Symlink => 0o120000,
+            Kind::CharDev => 0o020000,
+            Kind::BlockDev => 0o060000,
+            Kind::Fifo => 0o010000,
+        };
+        kind | self.mode
+    }
+
+    /// getdents64's d_type
+    fn d_type(&self) -> u8 {
+        match self.kind {
+            Kind::File | Kind::Hardlink => 8,
+            Kind::Dir => 4,
+            Kind::Symlink => 10,
+            Kind::CharDev => 2,
+            Kind::BlockDev => 6,
+            Kind::Fifo => 1,
+        }
+    }
+
+    /// One of `DEVICES`
+    fn is_device(&self) -> bool {
+        self.kind == Kind::CharDev && DEVICES.iter().any(|&(_, rdev)| self.rdev == rdev)
+    }
+}
+
+/// An open file description
+struct OpenFile {
+    ino: usize,
+    /// openat's flags
+    flags: u64,
+    /// Byte offset, or for a directory the index of the next entry
+    offset: u64,
+    /// A /proc file's text, rendered when it was opened
+    text: Option<Vec<u8>>,
+}
+
+impl OpenFile {
+    fn readable(&self) -> bool {
+        self.flags & O_PATH == 0 && self.flags & O_ACCMODE != O_WRONLY
+    }
+
+    fn writable(&self) -> bool {
+        self.flags & O_PATH == 0 && self.flags & O_ACCMODE != O_RDONLY
+    }
+}
+
+/// The process's filesystem and open files
+pub struct Vfs {
+    image: Option<Image>,
+    /// Every path the image has, its implied directories included
+    lower: HashSet<String>,
+    inodes: Vec<Inode>,
+    files: BTreeMap<i32, OpenFile>,
+}
+
+impl Default for Vfs {
+    /// A root directory with only the device nodes and /proc files
+    fn default() -> Self {
+        let mut vfs =
+            Self { image: None, lower: HashSet::new(), inodes: vec![Inode::dir(ROOT)], files: BTreeMap::new() };
+        for (path, rdev) in DEVICES {
+            vfs.synthesize(path, |parent| Inode { kind: Kind::CharDev, mode: 0o666, rdev, ..Inode::dir(parent) });
+        }
+        for (path, file) in procfs::FILES {
+            vfs.synthesize(path, |parent| Inode { kind: Kind::File, mode: 0o444, proc: Some(file), ..Inode::dir(parent) });
+        }
+        for name in ["exe", "cwd"] {
+            vfs.synthesize(&format!("/proc/self/{}", name), |parent| Inode {
+                kind: Kind::Symlink,
+                mode: 0o777,
+                link: "/".into(),
+                ..Inode::dir(parent)
+            });
+        }
+        vfs
+    }
+}
+
+impl Vfs {
+    /// The tree of a packed rootfs; contents stay compressed until opened
+    pub fn new(image: Image) -> Self {
+        let mut vfs = Self::default();
+        for (i, node) in image.nodes.iter().enumerate() {
+            let mut parent = ROOT;
+            let mut components = node.path.split('/').peekable();
+            while let Some(name) = components.next() {
+                let existing = vfs.inodes[parent].children.get(name).copied();
+                if components.peek().is_some() {
+                    parent = match existing {
+                        Some(ino) if vfs.inodes[ino].kind == Kind::Dir => ino,
+                        _ => vfs.insert(parent, name, Inode::dir(parent)),
+                    };
+                    continue;
+                }
+                let mut inode = Inode {
+                    kind: node.kind,
+                    mode: node.mode,
+                    uid: node.uid,
+                    gid: node.gid,
+                    mtime: node.mtime,
+                    rdev: node.rdev,
+                    size: node.size,
+                    link: node.link.clone(),
+                    packed: (node.kind == Kind::File).then_some(i),
+                    ..Inode::dir(parent)
+                };
+                match existing {
+                    // A directory listed after its contents keeps them
+                    Some(ino) if node.kind == Kind::Dir && vfs.inodes[ino].kind == Kind::Dir => {
+                        inode.children = std::mem::take(&mut vfs.inodes[ino].children);
+                        vfs.inodes[ino] = inode;
+                    }
+                    _ => {
+                        vfs.insert(parent, name, inode);
+                    }
+                }
+            }
+        }
+        vfs.lower = vfs.paths(ROOT, "").into_iter().map(|(path, _)| path).collect();
+        vfs.image = Some(image);
+        vfs
+    }
+
+    /// Add the node at the absolute `path` the kernel provides, and the
+    /// directories above it, unless something is there already
+    fn synthesize(&mut self, path: &str, inode: impl FnOnce(usize) -> Inode) {
+        let (dir_path, name) = path.rsplit_once('/').unwrap();
+        let mut dir = ROOT;
+        for component in dir_path.split('/').filter(|c| !c.is_empty()) {
+            dir = match self.inodes[dir].children.get(component) {
+                Some(&ino) if self.inodes[ino].kind == Kind::Dir => ino,
+                Some(_) => return,
+                None => self.insert(dir, component, Inode::dir(dir)),
+            };
+        }
+        if !self.inodes[dir].children.contains_key(name) {
+            let inode = inode(dir);
+            self.insert(dir, name, inode);
+        }
+    }
+
+    /// Point /proc/self/exe and /proc/self/cwd at the process's program
+    /// and working directory
+    pub fn proc_links(&mut self, exe: &str, cwd: &str) {
+        for (name, target) in [("exe", exe), ("cwd", cwd)] {
+            if let Ok(ino) = self.lookup(ROOT, &format!("/proc/self/{}", name), false) {
+                let inode = &mut self.inodes[ino];
+                if inode.kind == Kind::Symlink && !inode.upper && inode.link != target {
+                    inode.link = target.to_string();
+                }
+            }
+        }
+    }
+
+    /// The /proc file open as `fd`, whose text the kernel has to `fill` in
+    pub fn proc_file(&self, fd: u64) -> Option<ProcFile> {
+        self.inodes[self.files.get(&(fd as i32))?.ino].proc
+    }
+
+    /// Give the /proc file open as `fd` its text
+    pub fn fill(&mut self, fd: u64, text: Vec<u8>) {
+        if let Some(file) = self.files.get_mut(&(fd as i32)) {
+            file.text = Some(text);
+        }
+    }
+
+    /// Whether `fd` is /dev/random or /dev/urandom open for reading
+    pub fn is_random(&self, fd: u64) -> bool {
+        self.files.get(&(fd as i32)).is_some_and(|file| {
+            let inode = &self.inodes[file.ino];
+            file.readable() && inode.kind == Kind::CharDev && (inode.rdev == DEV_RANDOM || inode.rdev == DEV_URANDOM)
+        })
+    }
+
+    fn insert(&mut self, parent: usize, name: &str, inode: Inode) -> usize {
+        self.inodes.push(inode);
+        let ino = self.inodes.len() - 1;
+        self.inodes[parent].children.insert(name.to_string(), ino);
+        ino
+    }
+
+    /// Every path under `dir` (at `path`) and its inode, parents first
+    fn paths(&self, dir: usize, path: &str) -> Vec<(String, usize)> {
+        let mut out = Vec::new();
+        for (name, &ino) in &self.inodes[dir].children {
+            let child = join(path, name);
+            out.push((child.clone(), ino));
+            if self.inodes[ino].kind == Kind::Dir {
+                out.extend(self.paths(ino, &child));
+            }
+        }
+        out
+    }
+
+    /// A directory's path, without the leading slash
+    fn dir_path(&self, dir: usize) -> String {
+        let mut names = Vec::new();
+        let mut cur = dir;
+        while cur != ROOT {
+            let parent = self.inodes[cur].parent;
+            let name = self.inodes[parent].children.iter().find(|(_, &ino)| ino == cur).map(|(name, _)| name);
+            names.push(name.cloned().unwrap_or_default());
+            cur = parent;
+        }
+        names.reverse();
+        names.join("/")
+    }
+
+    /// Take `name` out of `dir`, leaving a whiteout if the image has it
+    fn remove(&mut self, dir: usize, name: &str) -> Option<usize> {
+        let ino = self.inodes[dir].children.remove(name)?;
+        if self.lower.contains(&join(&self.dir_path(dir), name)) {
+            self.inodes[dir].whiteouts.insert(name.to_string());
+        }
+        Some(ino)
+    }
+
+    /// Put `ino` and everything under it in the upper layer
+    fn mark_upper(&mut self, ino: usize) {
+        self.inodes[ino].upper = true;
+        let children: Vec<usize> = self.inodes[ino].children.values().copied().collect();
+        for child in children {
+            self.mark_upper(child);
+        }
+    }
+
+    /// Whether `fd` is an open file
+    pub fn owns(&self, fd: u64) -> bool {
+        self.files.contains_key(&(fd as i32))
+    }
+
+    /// The absolute path of the directory open as `fd`
+    pub fn fd_path(&self, fd: u64) -> Option<String> {
+        let ino = self.files.get(&(fd as i32))?.ino;
+        (self.inodes[ino].kind == Kind::Dir).then(|| format!("/{}", self.dir_path(ino)))
+    }
+
+    /// The open files, by path, for a snapshot. Files with no name left
+    /// (unlinked while open) cannot be opened again and are skipped.
+    pub fn open_files(&self) -> Vec<FdEntry> {
+        self.files
+            .iter()
+            .filter_map(|(&fd, file)| {
+                let path = format!("/{}", self.dir_path(file.ino));
+                (self.lookup(ROOT, &path, false) == Ok(file.ino)).then_some(FdEntry {
+                    fd,
+                    path,
+                    flags: file.flags as u32,
+                    offset: file.offset,
+                })
+            })
+            .collect()
+    }
+
+    /// Open a snapshot's files again, at their descriptors and offsets
+    pub fn reopen(&mut self, fds: &[FdEntry]) -> anyhow::Result<()> {
+        for f in fds {
+            let ino =
+                self.lookup(ROOT, &f.path, false).with_context(|| format!("Cannot reopen {} as fd {}", f.path, f.fd))?;
+            self.files.insert(f.fd, OpenFile { ino, flags: f.flags as u64, offset: f.offset, text: None });
+        }
+        Ok(())
+    }
+
+    /// Make sure a directory is at `path` for a mount to cover. Directories
+    /// made for it are not part of the upper layer.
+    pub fn mount_point(&mut self, path: &str) -> Result<(), Errno> {
+        let mut dir = ROOT;
+        for name in path.split('/').filter(|c| !c.is_empty()) {
+            dir = match self.inodes[dir].children.get(name) {
+                Some(&ino) if self.inodes[ino].kind == Kind::Dir => ino,
+                Some(_) => return Err(ENOTDIR),
+                None => self.insert(dir, name, Inode::dir(dir)),
+            };
+        }
+        Ok(())
+    }
+
+    /// A file's contents, decompressed on first use
+    fn data(&self, ino: usize) -> Result<&[u8], Errno> {
+        let inode = &self.inodes[ino];
+        if let Some(written) = &inode.written {
+            return Ok(written);
+        }
+        if inode.data.get().is_none() {
+            let data: Arc<[u8]> = match (inode.packed, &self.image) {
+                (Some(i), Some(image)) => image.contents(&image.nodes[i]).map_err(|_| EIO)?.into(),
+                _ => Arc::from([]),
+            };
+            let _ = inode.data.set(data);
+        }
+        Ok(inode.data.get().unwrap())
+    }
+
+    /// A file's contents in the upper layer, copied up on first write
+    fn copy_up(&mut self, ino: usize) -> Result<&mut Vec<u8>, Errno> {
+        if self.inodes[ino].written.is_none() {
+            let data = self.data(ino)?.to_vec();
+            self.inodes[ino].written = Some(data);
+        }
+        let inode = &mut self.inodes[ino];
+        inode.data = OnceLock::new();
+        inode.upper = true;
+        Ok(inode.written.as_mut().unwrap())
+    }
+
+    /// Cut or extend a file to `length` bytes
+    fn truncate(&mut self, ino: usize, length: u64, now: u64) -> Result<(), Errno> {
+        if length > MAX_FILE_SIZE {
+            return Err(EFBIG);
+        }
+        // Nothing of the old contents survives, so skip decompressing them
+        if length == 0 && self.inodes[ino].written.is_none() {
+            self.inodes[ino].written = Some(Vec::new());
+        }
+        self.copy_up(ino)?.resize(length as usize, 0);
+        let inode = &mut self.inodes[ino];
+        inode.size = length;
+        inode.mtime = now;
+        Ok(())
+    }
+
+    /// Follow `path` from the directory `start`; a symlink as the last
+    /// component is followed when `follow` is set
+    fn lookup(&self, start: usize, path: &str, follow: bool) -> Result<usize, Errno> {
+        if path.is_empty() {
+            return Err(ENOENT);
+        }
+        let mut cur = if path.starts_with('/') { ROOT } else { start };
+        let mut pending: Vec<String> = path.split('/').filter(|c| !c.is_empty()).rev().map(String::from).collect();
+        let mut followed = 0;
+        while let Some(name) = pending.pop() {
+            let dir = &self.inodes[cur];
+            if dir.kind != Kind::Dir {
+                return Err(ENOTDIR);
+            }
+            let next = match name.as_str() {
+                "." => cur,
+                ".." => dir.parent,
+                _ => *dir.children.get(&name).ok_or(ENOENT)?,
+            };
+            let inode = &self.inodes[next];
+            if inode.kind == Kind::Symlink && (follow || !pending.is_empty()) {
+                followed += 1;
+                if followed > MAX_SYMLINKS {
+                    return Err(ELOOP);
+                }
+                if inode.link.starts_with('/') {
+                    cur = ROOT;
+                }
+                pending.extend(inode.link.split('/').filter(|c| !c.is_empty()).rev().map(String::from));
+                continue;
+            }
+            cur = next;
+        }
+        Ok(cur)
+    }
+
+    /// The directory a *at syscall's relative paths start from
+    fn start(&self, cwd: &str, dirfd: u64) -> Result<usize, Errno> {
+        match dirfd as i32 {
+            AT_FDCWD => Ok(self.lookup(ROOT, cwd, true).unwrap_or(ROOT)),
+            fd => {
+                let ino = self.files.get(&fd).ok_or(EBADF)?.ino;
+                Ok(ino)
+            }
+        }
+    }
+
+    /// Resolve a guest path for a *at syscall
+    fn resolve(&self, mem: &dyn GuestMemory, cwd: &str, dirfd: u64, path: u64, follow: bool) -> Result<usize, Errno> {
+        let path = read_path(mem, path)?;
+        if path.starts_with('/') {
+            return self.lookup(ROOT, &path, follow);
+        }
+        let start = self.start(cwd, dirfd)?;
+        if self.inodes[start].kind != Kind::Dir {
+            return Err(ENOTDIR);
+        }
+        self.lookup(start, &path, follow)
+    }
+
+    /// The directory a guest path names an entry in, and the entry's name
+    fn resolve_parent(&self, mem: &dyn GuestMemory, cwd: &str, dirfd: u64, path: u64) -> Result<(usize, String), Errno> {
+        let path = read_path(mem, path)?;
+        let trimmed = path.trim_end_matches('/');
+        let (dir_path, name) = match trimmed.rsplit_once('/') {
+            Some(("", name)) => ("/", name),
+            Some((dir, name)) => (dir, name),
+            None => (".", trimmed),
+        };
+        if path.is_empty() {
+            return Err(ENOENT);
+        }
+        if name.is_empty() || name == "." || name == ".." {
+            return Err(EINVAL);
+        }
+        let start = if path.starts_with('/') { ROOT } else { self.start(cwd, dirfd)? };
+        let dir = self.lookup(start, dir_path, true)?;
+        if self.inodes[dir].kind != Kind::Dir {
+            return Err(ENOTDIR);
+        }
+        Ok((dir, name.to_string()))
+    }
+
+    /// Add a new entry to the upper layer
+    fn create(&mut self, dir: usize, name: &str, inode: Inode) -> Result<usize, Errno> {
+        if self.inodes[dir].children.contains_key(name) {
+            return Err(EEXIST);
+        }
+        self.inodes[dir].upper = true;
+        Ok(self.insert(dir, name, inode))
+    }
+
+    /// openat, as descriptor `new_fd`; `now` stamps a created or truncated file
+    #[allow(clippy::too_many_arguments)]
+    pub fn openat(
+        &mut self,
+        mem: &dyn GuestMemory,
+        cwd: &str,
+        dirfd: u64,
+        path: u64,
+        flags: u64,
+        mode: u64,
+        new_fd: i32,
+        now: u64,
+    ) -> Result<u64, Errno> {
+        let ino = match self.resolve(mem, cwd, dirfd, path, flags & O_NOFOLLOW == 0) {
+            Err(e) if e == ENOENT && flags & O_CREAT != 0 => {
+                let (dir, name) = self.resolve_parent(mem, cwd, dirfd, path)?;
+                self.create(dir, &name, Inode::created(Kind::File, (mode & !UMASK) as u32, dir, now))?
+            }
+            Ok(_) if flags & (O_CREAT | O_EXCL) == O_CREAT | O_EXCL => return Err(EEXIST),
+            result => result?,
+        };
+        let file = OpenFile { ino, flags, offset: 0, text: None };
+        let inode = &self.inodes[ino];
+        if flags & O_PATH == 0 {
+            match inode.kind {
+                Kind::Symlink => return Err(ELOOP),
+                Kind::Dir if file.writable() => return Err(EISDIR),
+                _ if flags & O_DIRECTORY != 0 && inode.kind != Kind::Dir => return Err(ENOTDIR),
+                Kind::CharDev | Kind::BlockDev | Kind::Fifo if !inode.is_device() => return Err(ENXIO),
+                Kind::File if inode.proc.is_some() && file.writable() => return Err(EACCES),
+                Kind::File | Kind::Hardlink if file.writable() && flags & O_TRUNC != 0 => self.truncate(ino, 0, now)?,
+                Kind::File | Kind::Hardlink => {
+                    self.data(ino)?;
+                }
+                _ => {}
+            }
+        }
+        self.files.insert(new_fd, file);
+        Ok(new_fd as u64)
+    }
+
+    pub fn close(&mut self, fd: u64) -> Result<u64, Errno> {
+        self.files.remove(&(fd as i32)).map(|_| 0).ok_or(EBADF)
+    }
+
+    fn file(&self, fd: u64) -> Result<&OpenFile, Errno> {
+        self.files.get(&(fd as i32)).ok_or(EBADF)
+    }
+
+    /// What an open file reads: a /proc file's text, or the file's contents
+    fn contents<'a>(&'a self, file: &'a OpenFile) -> Result<&'a [u8], Errno> {
+        match &file.text {
+            Some(text) => Ok(text),
+            None => self.data(file.ino),
+        }
+    }
+
+    /// Copy the file's bytes at `offset` to guest memory
+    fn read_at(&self, mem: &mut dyn GuestMemory, file: &OpenFile, offset: u64, buf: u64, count: u64) -> Result<u64, Errno> {
+        let inode = &self.inodes[file.ino];
+        match inode.kind {
+            Kind::Dir => Err(EISDIR),
+            Kind::CharDev if inode.rdev == DEV_ZERO => {
+                mem.fill(buf, count, 0)?;
+                Ok(count)
+            }
+            Kind::CharDev => Ok(0),
+            _ => {
+                let data = self.contents(file)?;
+                let start = (offset as usize).min(data.len());
+                let end = start + (count as usize).min(data.len() - start);
+                mem.write(buf, &data[start..end])?;
+                Ok((end - start) as u64)
+            }
+        }
+    }
+
+    pub fn read(&mut self, mem: &mut dyn GuestMemory, fd: u64, buf: u64, count: u64) -> Result<u64, Errno> {
+        let file = self.file(fd)?;
+        if !file.readable() {
+            return Err(EBADF);
+        }
+        let n = self.read_at(mem, file, file.offset, buf, count)?;
+        self.files.get_mut(&(fd as i32)).unwrap().offset += n;
+        Ok(n)
+    }
+
+    pub fn pread(&self, mem: &mut dyn GuestMemory, fd: u64, buf: u64, count: u64, offset: u64) -> Result<u64, Errno> {
+        let file = self.file(fd)?;
+        if !file.readable() {
+            return Err(EBADF);
+        }
+        self.read_at(mem, file, offset, buf, count)
+    }
+
+    /// Copy guest memory into the file at `offset`, copying it up first
+    fn write_at(&mut self, mem: &dyn GuestMemory, ino: usize, offset: u64, buf: u64, count: u64, now: u64) -> Result<u64, Errno> {
+        match self.inodes[ino].kind {
+            Kind::Dir => return Err(EISDIR),
+            Kind::CharDev => return Ok(count),
+            _ => {}
+        }
+        if offset.checked_add(count).is_none_or(|end| end > MAX_FILE_SIZE) {
+            return Err(EFBIG);
+        }
+        let data = self.copy_up(ino)?;
+        let mut chunk = vec![0u8; WRITE_CHUNK.min(count as usize)];
+        let mut done = 0;
+        while done < count {
+            let n = (count - done).min(chunk.len() as u64) as usize;
+            mem.read(buf + done, &mut chunk[..n])?;
+            let at = (offset + done) as usize;
+            if data.len() < at + n {
+                data.resize(at + n, 0);
+            }
+            data[at..at + n].copy_from_slice(&chunk[..n]);
+            done += n as u64;
+        }
+        let size = data.len() as u64;
+        let inode = &mut self.inodes[ino];
+        inode.size = size;
+        inode.mtime = now;
+        Ok(count)
+    }
+
+    pub fn write(&mut self, mem: &dyn GuestMemory, fd: u64, buf: u64, count: u64, now: u64) -> Result<u64, Errno> {
+        let file = self.file(fd)?;
+        if !file.writable() {
+            return Err(EBADF);
+        }
+        let ino = file.ino;
+        let offset = if file.flags & O_APPEND != 0 { self.inodes[ino].size } else { file.offset };
+        let n = self.write_at(mem, ino, offset, buf, count, now)?;
+        self.files.get_mut(&(fd as i32)).unwrap().offset = offset + n;
+        Ok(n)
+    }
+
+    pub fn pwrite(&mut self, mem: &dyn GuestMemory, fd: u64, buf: u64, count: u64, offset: u64, now: u64) -> Result<u64, Errno> {
+        let file = self.file(fd)?;
+        if !file.writable() {
+            return Err(EBADF);
+        }
+        self.write_at(mem, file.ino, offset, buf, count, now)
+    }
+
+    pub fn ftruncate(&mut self, fd: u64, length: u64, now: u64) -> Result<u64, Errno> {
+        let file = self.file(fd)?;
+        let ino = file.ino;
+        if !file.writable() || !matches!(self.inodes[ino].kind, Kind::File | Kind::Hardlink) {
+            return Err(EINVAL);
+        }
+        self.truncate(ino, length, now)?;
+        Ok(0)
+    }
+
+    pub fn lseek(&mut self, fd: u64, offset: u64, whence: u64) -> Result<u64, Errno> {
+        let file = self.file(fd)?;
+        let base = match whence {
+            SEEK_SET => 0,
+            SEEK_CUR => file.offset,
+            SEEK_END => self.inodes[file.ino].size,
+            _ => return Err(EINVAL),
+        };
+        let offset = base.checked_add_signed(offset as i64).ok_or(EINVAL)?;
+        self.files.get_mut(&(fd as i32)).unwrap().offset = offset;
+        Ok(offset)
+    }
+
+    /// Directory entries as struct linux_dirent64 records, "." and ".."
+    /// first; the offset counts entries
+    pub fn getdents64(&mut self, mem: &mut dyn GuestMemory, fd: u64, buf: u64, count: u64) -> Result<u64, Errno> {
+        let file = self.file(fd)?;
+        let dir = &self.inodes[file.ino];
+        if dir.kind != Kind::Dir {
+            return Err(ENOTDIR);
+        }
+        let entries = [(".", file.ino), ("..", dir.parent)]
+            .into_iter()
+            .chain(dir.children.iter().map(|(name, &ino)| (name.as_str(), ino)));
+        let mut out = Vec::new();
+        let mut next = file.offset;
+        for (name, ino) in entries.skip(file.offset as usize) {
+            if !push_dirent(&mut out, count, ino as u64 + 1, next + 1, self.inodes[ino].d_type(), name) {
+                break;
+            }
+            next += 1;
+        }
+        if out.is_empty() && next < 2 + dir.children.len() as u64 {
+            return Err(EINVAL);
+        }
+        mem.write(buf, &out)?;
+        self.files.get_mut(&(fd as i32)).unwrap().offset = next;
+        Ok(out.len() as u64)
+    }
+
+    /// struct stat for `ino`
+    fn stat(&self, ino: usize) -> [u8; STAT_SIZE] {
+        let inode = &self.inodes[ino];
+        Stat {
+            dev: 1,
+            ino: ino as u64 + 1,
+            mode: inode.st_mode(),
+            nlink: if inode.kind == Kind::Dir { 2 } else { 1 },
+            uid: inode.uid,
+            gid: inode.gid,
+            rdev: inode.rdev,
+            size: if inode.kind == Kind::Symlink { inode.link.len() as u64 } else { inode.size },
+            mtime: inode.mtime,
+        }
+        .to_bytes()
+    }
+
+    pub fn fstat(&self, mem: &mut dyn GuestMemory, fd: u64, statbuf: u64) -> Result<u64, Errno> {
+        mem.write(statbuf, &self.stat(self.file(fd)?.ino))?;
+        Ok(0)
+    }
+
+    pub fn newfstatat(
+        &self,
+        mem: &mut dyn GuestMemory,
+        cwd: &str,
+        dirfd: u64,
+        path: u64,
+        statbuf: u64,
+        flags: u64,
+    ) -> Result<u64, Errno> {
+        let ino = if flags & AT_EMPTY_PATH != 0 && read_path(mem, path)?.is_empty() {
+            self.start(cwd, dirfd)?
+        } else {
+            self.resolve(mem, cwd, dirfd, path, flags & AT_SYMLINK_NOFOLLOW == 0)?
+        };
+        mem.write(statbuf, &self.stat(ino))?;
+        Ok(0)
+    }
+
+    pub fn readlinkat(
+        &self,
+        mem: &mut dyn GuestMemory,
+        cwd: &str,
+        dirfd: u64,
+        path: u64,
+        buf: u64,
+        size: u64,
+    ) -> Result<u64, Errno> {
+        let inode = &self.inodes[self.resolve(mem, cwd, dirfd, path, false)?];
+        if inode.kind != Kind::Symlink {
+            return Err(EINVAL);
+        }
+        let target = &inode.link.as_bytes()[..inode.link.len().min(size as usize)];
+        mem.write(buf, target)?;
+        Ok(target.len() as u64)
+    }
+
+    /// faccessat as root: anything that exists is readable and writable,
+    /// and only files with an execute bit are executable
+    pub fn faccessat(&self, mem: &dyn GuestMemory, cwd: &str, dirfd: u64, path: u64, mode: u64) -> Result<u64, Errno> {
+        let inode = &self.inodes[self.resolve(mem, cwd, dirfd, path, true)?];
+        if mode & X_OK != 0 && inode.mode & 0o111 == 0 {
+            return Err(EACCES);
+        }
+        Ok(0)
+    }
+
+    pub fn mkdirat(&mut self, mem: &dyn GuestMemory, cwd: &str, dirfd: u64, path: u64, mode: u64, now: u64) -> Result<u64, Errno> {
+        let (dir, name) = self.resolve_parent(mem, cwd, dirfd, path)?;
+        self.create(dir, &name, Inode::created(Kind::Dir, (mode & !UMASK) as u32, dir, now))?;
+        Ok(0)
+    }
+
+    pub fn symlinkat(&mut self, mem: &dyn GuestMemory, cwd: &str, target: u64, dirfd: u64, path: u64, now: u64) -> Result<u64, Errno> {
+        let target = read_path(mem, target)?;
+        if target.is_empty() {
+            return Err(ENOENT);
+        }
+        let (dir, name) = self.resolve_parent(mem, cwd, dirfd, path)?;
+        let inode = Inode { link: target, ..Inode::created(Kind::Symlink, 0o777, dir, now) };
+        self.create(dir, &name, inode)?;
+        Ok(0)
+    }
+
+    pub fn unlinkat(&mut self, mem: &dyn GuestMemory, cwd: &str, dirfd: u64, path: u64, flags: u64) -> Result<u64, Errno> {
+        let (dir, name) = self.resolve_parent(mem, cwd, dirfd, path)?;
+        let inode = &self.inodes[*self.inodes[dir].children.get(&name).ok_or(ENOENT)?];
+        match (inode.kind == Kind::Dir, flags & AT_REMOVEDIR != 0) {
+            (true, true) if !inode.children.is_empty() => return Err(ENOTEMPTY),
+            (false, true) => return Err(ENOTDIR),
+            (true, false) => return Err(EISDIR),
+            _ => {}
+        }
+        self.remove(dir, &name);
+        Ok(0)
+    }
+
+    /// renameat2; the moved entry (a whole directory tree) is copied up,
+    /// and its old name whited out if the image has it
+    #[allow(clippy::too_many_arguments)]
+    pub fn renameat2(
+        &mut self,
+        mem: &dyn GuestMemory,
+        cwd: &str,
+        old_dirfd: u64,
+        old_path: u64,
+        new_dirfd: u64,
+        new_path: u64,
+        flags: u64,
+    ) -> Result<u64, Errno> {
+        if flags & !RENAME_NOREPLACE != 0 {
+            return Err(EINVAL);
+        }
+        let (old_dir, old_name) = self.resolve_parent(mem, cwd, old_dirfd, old_path)?;
+        let (new_dir, new_name) = self.resolve_parent(mem, cwd, new_dirfd, new_path)?;
+        let ino = *self.inodes[old_dir].children.get(&old_name).ok_or(ENOENT)?;
+        if old_dir == new_dir && old_name == new_name {
+            return Ok(0);
+        }
+        let is_dir = self.inodes[ino].kind == Kind::Dir;
+        // A directory cannot move inside itself
+        let mut cur = new_dir;
+        while is_dir && cur != ROOT {
+            if cur == ino {
+                return Err(EINVAL);
+            }
+            cur = self.inodes[cur].parent;
+        }
+        if let Some(&target) = self.inodes[new_dir].children.get(&new_name) {
+            let target = &self.inodes[target];
+            match (is_dir, target.kind == Kind::Dir) {
+                _ if flags & RENAME_NOREPLACE != 0 => return Err(EEXIST),
+                (true, true) if !target.children.is_empty() => return Err(ENOTEMPTY),
+                (true, false) => return Err(ENOTDIR),
+                (false, true) => return Err(EISDIR),
+                _ => {}
+            }
+            self.remove(new_dir, &new_name);
+        }
+        self.remove(old_dir, &old_name);
+        self.inodes[new_dir].children.insert(new_name, ino);
+        self.inodes[new_dir].upper = true;
+        if is_dir {
+            self.inodes[ino].parent = new_dir;
+        }
+        self.mark_upper(ino);
+        Ok(0)
+    }
+
+    fn chmod(&mut self, ino: usize, mode: u64) -> Result<u64, Errno> {
+        let inode = &mut self.inodes[ino];
+        inode.mode = mode as u32 & 0o7777;
+        inode.upper = true;
+        Ok(0)
+    }
+
+    pub fn fchmod(&mut self, fd: u64, mode: u64) -> Result<u64, Errno> {
+        self.chmod(self.file(fd)?.ino, mode)
+    }
+
+    pub fn fchmodat(&mut self, mem: &dyn GuestMemory, cwd: &str, dirfd: u64, path: u64, mode: u64) -> Result<u64, Errno> {
+        let ino = self.resolve(mem, cwd, dirfd, path, true)?;
+        self.chmod(ino, mode)
+    }
+
+    /// The upper layer as a layer tar: every created, changed or moved
+    /// entry, and a `.wh.` entry for every whiteout
+    pub fn upper_layer(&self) -> Result<Vec<u8>, Errno> {
+        let mut entries = Vec::new();
+        for (path, dir) in std::iter::once((String::new(), ROOT)).chain(self.paths(ROOT, "")) {
+            let inode = &self.inodes[dir];
+            if inode.kind != Kind::Dir {
+                continue;
+            }
+            for name in &inode.whiteouts {
+                entries.push(TarEntry {
+                    path: join(&path, &format!("{}{}", WHITEOUT, name)),
+                    kind: Kind::File,
+                    mode: 0,
+                    uid: 0,
+                    gid: 0,
+                    mtime: 0,
+                    rdev: 0,
+                    link: String::new(),
+                    data: &[],
+                });
+            }
+            for (name, &ino) in &inode.children {
+                let child = &self.inodes[ino];
+                if !child.upper {
+                    continue;
+                }
+                entries.push(TarEntry {
+                    path: join(&path, name),
+                    kind: child.kind,
+                    mode: child.mode,
+                    uid: child.uid,
+                    gid: child.gid,
+                    mtime: child.mtime,
+                    rdev: child.rdev,
+                    link: child.link.clone(),
+                    data: if child.kind == Kind::File { self.data(ino)? } else { &[] },
+                });
+            }
+        }
+        Ok(rootfs::write_tar(&entries))
+    }
+
+    /// Apply a layer tar (an `upper_layer` from an earlier run) on top of
+    /// the tree, its whiteouts first
+    pub fn apply_layer(&mut self, tar: &[u8]) -> anyhow::Result<()> {
+        let entries = rootfs::read_tar(tar)?;
+        for entry in &entries {
+            let (dir_path, name) = entry.path.rsplit_once('/').unwrap_or(("", &entry.path));
+            let Some(hidden) = name.strip_prefix(WHITEOUT) else {
+                continue;
+            };
+            let dir = self.make_dirs(dir_path);
+            if name == OPAQUE {
+                let names: Vec<String> = self.inodes[dir].children.keys().cloned().collect();
+                for name in names {
+                    self.remove(dir, &name);
+                }
+            } else if self.remove(dir, hidden).is_none() && self.lower.contains(&join(dir_path, hidden)) {
+                self.inodes[dir].whiteouts.insert(hidden.to_string());
+            }
+        }
+        for entry in entries {
+            let (dir_path, name) = entry.path.rsplit_once('/').unwrap_or(("", &entry.path));
+            if name.starts_with(WHITEOUT) {
+                continue;
+            }
+            let dir = self.make_dirs(dir_path);
+            let data = match entry.kind {
+                Kind::Hardlink => {
+                    let target = self.lookup(ROOT, &entry.link, false);
+                    target.and_then(|ino| self.data(ino).map(<[u8]>::to_vec)).unwrap_or_default()
+                }
+                _ => entry.data.to_vec(),
+            };
+            let kind = if entry.kind == Kind::Hardlink { Kind::File } else { entry.kind };
+            let inode = Inode {
+                kind,
+                mode: entry.mode,
+                uid: entry.uid,
+                gid: entry.gid,
+                mtime: entry.mtime,
+                rdev: entry.rdev,
+                size: data.len() as u64,
+                link: entry.link.clone(),
+                upper: true,
+                written: (kind == Kind::File).then_some(data),
+                ..Inode::dir(dir)
+            };
+            match self.inodes[dir].children.get(name).copied() {
+                // A directory keeps its contents, with the layer's metadata
+                Some(ino) if kind == Kind::Dir && self.inodes[ino].kind == Kind::Dir => {
+                    let existing = &mut self.inodes[ino];
+                    (existing.mode, existing.uid, existing.gid, existing.mtime) = (inode.mode, inode.uid, inode.gid, inode.mtime);
+                    existing.upper = true;
+                }
+                // Anything else is replaced, a directory with its contents
+                _ => {
+                    self.insert(dir, name, inode);
+                }
+            }
+        }
+        Ok(())
+    }
+
+    /// The directory at `path`, created in the upper layer as needed
+    fn make_dirs(&mut self, path: &str) -> usize {
+        let mut dir = ROOT;
+        for name in path.split('/').filter(|c| !c.is_empty()) {
+            dir = match self.inodes[dir].children.get(name) {
+                Some(&ino) if self.inodes[ino].kind == Kind::Dir => ino,
+                _ => {
+                    self.remove(dir, name);
+                    let inode = Inode { upper: true, ..Inode::dir(dir) };
+                    self.insert(dir, name, inode)
+                }
+            };
+        }
+        dir
+    }
+}
+
+/// `name` inside the directory at `dir` ("" for the root)
+fn join(dir: &str, name: &str) -> String {
+    if dir.is_empty() {
+        name.to_string()
+    } else {
+        format!("{}/{}", dir, name)
+    }
+}
+
+impl FileSource for Vfs {
+    fn read_at(&self, fd: i32, offset: u64, buf: &mut [u8]) -> Result<usize, Errno> {
+        let file = self.files.get(&fd).ok_or(EBADF)?;
+        if self.inodes[file.ino].kind == Kind::Dir {
+            return Err(EISDIR);
+        }
+        let data = self.contents(file)?;
+        let start = (offset as usize).min(data.len());
+        let n = buf.len().min(data.len() - start);
+        buf[..n].copy_from_slice(&data[start..start + n]);
+        Ok(n)
+    }
+}
+
+/// The struct stat fields a filesystem fills in
+pub struct Stat {
+    pub dev: u64,
+    pub ino: u64,
+    /// st_mode: file type bits and permissions
+    pub mode: u32,
+    pub nlink: u32,
+    pub uid: u32,
+    pub gid: u32,
+    pub rdev: u64,
+    pub size: u64,
+    /// Seconds since the epoch; also given as atime and ctime
+    pub mtime: u64,
+}
+
+impl Stat {
+    pub const S_IFMT: u32 = 0o170000;
+    pub const S_IFDIR: u32 = 0o040000;
+    pub const S_IFREG: u32 = 0o100000;
+    pub const S_IFLNK: u32 = 0o120000;
+
+    /// The asm-generic struct stat
+    pub fn to_bytes(&self) -> [u8; STAT_SIZE] {
+        let mut st = [0u8; STAT_SIZE];
+        st[0..8].copy_from_slice(&self.dev.to_le_bytes());
+        st[8..16].copy_from_slice(&self.ino.to_le_bytes());
+        st[16..20].copy_from_slice(&self.mode.to_le_bytes());
+        st[20..24].copy_from_slice(&self.nlink.to_le_bytes());
+        st[24..28].copy_from_slice(&self.uid.to_le_bytes());
+        st[28..32].copy_from_slice(&self.gid.to_le_bytes());
+        st[32..40].copy_from_slice(&self.rdev.to_le_bytes());
+        st[48..56].copy_from_slice(&self.size.to_le_bytes());
+        st[56..60].copy_from_slice(&4096u32.to_le_bytes());
+        st[64..72].copy_from_slice(&self.size.div_ceil(512).to_le_bytes());
+        for time in [72, 88, 104] {
+            st[time..time + 8].copy_from_slice(&self.mtime.to_le_bytes());
+        }
+        st
+    }
+
+    pub fn is_dir(&self) -> bool {
+        self.mode & Self::S_IFMT == Self::S_IFDIR
+    }
+
+    /// getdents64's d_type, which is the file type bits shifted down
+    pub fn d_type(&self) -> u8 {
+        ((self.mode & Self::S_IFMT) >> 12) as u8
+    }
+}
+
+/// Append a struct linux_dirent64 to a getdents64 result of at most
+/// `count` bytes; false if it does not fit
+pub(crate) fn push_dirent(out: &mut Vec<u8>, count: u64, ino: u64, next: u64, d_type: u8, name: &str) -> bool {
+    let reclen = (19 + name.len() + 1).next_multiple_of(8);
+    if out.len() + reclen > count as usize {
+        return false;
+    }
+    let start = out.len();
+    out.extend(ino.to_le_bytes());
+    out.extend(next.to_le_bytes());
+    out.extend((reclen as u16).to_le_bytes());
+    out.push(d_type);
+    out.extend(name.as_bytes());
+    out.resize(start + reclen, 0);
+    true
+}
+
+/// A NUL-terminated path from guest memory
+pub fn read_path(mem: &dyn GuestMemory, addr: u64) -> Result<String, Errno> {
+    let mut path = Vec::new();
+    let mut byte = [0u8];
+    loop {
+        mem.read(addr + path.len() as u64, &mut byte)?;
+        if byte[0] == 0 {
+            break;
+        }
+        path.push(byte[0]);
+        if path.len() >= PATH_MAX {
+            return Err(ENAMETOOLONG);
+        }
+    }
+    String::from_utf8(path).map_err(|_| ENOENT)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::syscalls::{nr, Kernel};
+    use crate::{Layout, Random};
+    use rv2wasm::rootfs;
+
+    fn rootfs_kernel() -> Kernel {
+        let tar = rootfs::build_tar(&[
+            ("bin/busybox", b'0', "", b"\x7fELF busybox"),
+            ("bin/sh", b'2', "busybox", b""),
+            ("etc/", b'5', "", b""),
+            ("etc/motd", b'0', "", b"hello from the rootfs\n"),
+            ("etc/loop", b'2', "loop", b""),
+        ]);
+        let image = Image::decode(&rootfs::pack(&rootfs::read_tar(&tar).unwrap())).unwrap();
+        let mut kernel = Kernel::new(Layout {
+            brk_base: 0x10000,
+            brk_max: 0x10000,
+            mmap_base: 0x20000,
+            limit: 0x100000,
+        });
+        kernel.vfs = Vfs::new(image);
+        kernel.process.cwd = "/etc".into();
+        kernel
+    }
+
+    /// Put a NUL-terminated path at `addr`
+    fn path_at(mem: &mut [u8], addr: usize, s: &str) {
+        mem[addr..addr + s.len() + 1].copy_from_slice(format!("{}\0", s).as_bytes());
+    }
+
+    #[test]
+    fn test_rootfs_syscalls() {
+        let mut kernel = rootfs_kernel();
+        let mut mem = vec![0u8; 0x4000];
+        let path = |mem: &mut Vec<u8>, s: &str| path_at(mem, 0x100, s);
+        let cwd = AT_FDCWD as u64;
+
+        // Nothing is decompressed until a file is opened
+        assert!(kernel.vfs.inodes.iter().all(|i| i.data.get().is_none()));
+        path(&mut mem, "motd");
+        let fd = kernel.dispatch(&mut mem, nr::OPENAT, [cwd, 0x100, 0, 0, 0, 0]);
+        assert_eq!(fd, 3);
+        assert_eq!(kernel.vfs.inodes.iter().filter(|i| i.data.get().is_some()).count(), 1);
+        assert_eq!(kernel.dispatch(&mut mem, nr::READ, [3, 0x200, 5, 0, 0, 0]), 5);
+        assert_eq!(kernel.dispatch(&mut mem, nr::READ, [3, 0x205, 100, 0, 0, 0]), 17);
+        assert_eq!(&mem[0x200..0x216], b"hello from the rootfs\n");
+        assert_eq!(kernel.dispatch(&mut mem, nr::READ, [3, 0x200, 100, 0, 0, 0]), 0);
+        assert_eq!(kernel.dispatch(&mut mem, nr::LSEEK, [3, 6, SEEK_SET, 0, 0, 0]), 6);
+        assert_eq!(kernel.dispatch(&mut mem, nr::PREAD64, [3, 0x200, 4, 11, 0, 0]), 4);
+        assert_eq!(&mem[0x200..0x204], b"the ");
+        assert_eq!(kernel.dispatch(&mut mem, nr::FSTAT, [3, 0x400, 0, 0, 0, 0]), 0);
+        assert_eq!(u32::from_le_bytes(mem[0x410..0x414].try_into().unwrap()), 0o100644);
+        assert_eq!(u64::from_le_bytes(mem[0x430..0x438].try_into().unwrap()), 22);
+
+        // Symlinks, their targets and their loops
+        path(&mut mem, "/bin/sh");
+        assert_eq!(kernel.dispatch(&mut mem, nr::NEWFSTATAT, [cwd, 0x100, 0x400, 0, 0, 0]), 0);
+        assert_eq!(u64::from_le_bytes(mem[0x430..0x438].try_into().unwrap()), 12);
+        assert_eq!(kernel.dispatch(&mut mem, nr::READLINKAT, [cwd, 0x100, 0x200, 64, 0, 0]), 7);
+        assert_eq!(&mem[0x200..0x207], b"busybox");
+        path(&mut mem, "loop");
+        assert_eq!(kernel.dispatch(&mut mem, nr::OPENAT, [cwd, 0x100, 0, 0, 0, 0]), ELOOP.as_ret());
+
+        // The descriptor numbers are shared with sockets
+        path(&mut mem, "../etc/motd");
+        assert_eq!(kernel.dispatch(&mut mem, nr::OPENAT, [cwd, 0x100, O_CREAT | O_EXCL, 0, 0, 0]), EEXIST.as_ret());
+        path(&mut mem, "nope");
+        assert_eq!(kernel.dispatch(&mut mem, nr::OPENAT, [cwd, 0x100, 0, 0, 0, 0]), ENOENT.as_ret());
+        assert_eq!(kernel.dispatch(&mut mem, nr::SOCKET, [2, 1, 0, 0, 0, 0]), 4);
+
+        // The root directory lists ".", ".." and its children
+        path(&mut mem, "/");
+        assert_eq!(kernel.dispatch(&mut mem, nr::OPENAT, [cwd, 0x100, O_DIRECTORY, 0, 0, 0]), 5);
+        let n = kernel.dispatch(&mut mem, nr::GETDENTS64, [5, 0x1000, 0x400, 0, 0, 0]);
+        let mut names = Vec::new();
+        let mut pos = 0x1000;
+        while pos < 0x1000 + n as usize {
+            let reclen = u16::from_le_bytes(mem[pos + 16..pos + 18].try_into().unwrap()) as usize;
+            let name = &mem[pos + 19..pos + reclen];
+            names.push(String::from_utf8_lossy(&name[..name.iter().position(|&b| b == 0).unwrap()]).into_owned());
+            pos += reclen;
+        }
+        assert_eq!(names, [".", "..", "bin", "dev", "etc", "proc"]);
+        assert_eq!(kernel.dispatch(&mut mem, nr::GETDENTS64, [5, 0x1000, 0x400, 0, 0, 0]), 0);
+        assert_eq!(kernel.dispatch(&mut mem, nr::READ, [5, 0x1000, 16, 0, 0, 0]), EISDIR.as_ret());
+
+        assert_eq!(kernel.dispatch(&mut mem, nr::CLOSE, [3, 0, 0, 0, 0, 0]), 0);
+        assert_eq!(kernel.dispatch(&mut mem, nr::CLOSE, [3, 0, 0, 0, 0, 0]), EBADF.as_ret());
+    }
+
+    #[test]
+    fn test_overlay_copy_up_and_whiteouts() {
+        let mut kernel = rootfs_kernel();
+        let mut mem = vec![0u8; 0x4000];
+        let cwd = AT_FDCWD as u64;
+        let at = |kernel: &mut Kernel, mem: &mut Vec<u8>, nr: u64, paths: &[&str], rest: &[u64]| {
+            let mut args = [0u64; 6];
+            let mut i = 0;
+            for (n, p) in paths.iter().enumerate() {
+                path_at(mem, 0x100 + n * 0x80, p);
+                args[i] = cwd;
+                args[i + 1] = 0x100 + n as u64 * 0x80;
+                i += 2;
+            }
+            args[i..i + rest.len()].copy_from_slice(rest);
+            kernel.dispatch(mem, nr, args)
+        };
+
+        // The first write copies the lower file up; the image is untouched
+        let fd = at(&mut kernel, &mut mem, nr::OPENAT, &["motd"], &[2 | O_APPEND]) as u64;
+        mem[0x800..0x805].copy_from_slice(b"more\n");
+        assert_eq!(kernel.dispatch(&mut mem, nr::WRITE, [fd, 0x800, 5, 0, 0, 0]), 5);
+        assert_eq!(kernel.dispatch(&mut mem, nr::PREAD64, [fd, 0x200, 64, 0, 0, 0]), 27);
+        assert_eq!(&mem[0x200..0x21b], b"hello from the rootfs\nmore\n");
+        assert_eq!(kernel.dispatch(&mut mem, nr::FTRUNCATE, [fd, 5, 0, 0, 0, 0]), 0);
+        assert_eq!(kernel.dispatch(&mut mem, nr::LSEEK, [fd, 0, SEEK_SET, 0, 0, 0]), 0);
+        assert_eq!(kernel.dispatch(&mut mem, nr::READ, [fd, 0x200, 64, 0, 0, 0]), 5);
+
+        // Created files, directories and links; renames and removals
+        assert_eq!(at(&mut kernel, &mut mem, nr::MKDIRAT, &["/tmp"], &[0o1777]), 0);
+        assert_eq!(at(&mut kernel, &mut mem, nr::NEWFSTATAT, &["/tmp"], &[0x400, 0]), 0);
+        assert_eq!(u32::from_le_bytes(mem[0x410..0x414].try_into().unwrap()), 0o041755);
+        assert_eq!(at(&mut kernel, &mut mem, nr::MKDIRAT, &["/tmp"], &[0o755]), EEXIST.as_ret());
+        let new = at(&mut kernel, &mut mem, nr::OPENAT, &["/tmp/new"], &[O_WRONLY | O_CREAT | O_EXCL, 0o600]);
+        assert_eq!(kernel.dispatch(&mut mem, nr::WRITE, [new as u64, 0x800, 4, 0, 0, 0]), 4);
+        assert_eq!(kernel.dispatch(&mut mem, nr::READ, [new as u64, 0x200, 4, 0, 0, 0]), EBADF.as_ret());
+        assert_eq!(at(&mut kernel, &mut mem, nr::RENAMEAT2, &["/tmp/new", "/etc/new"], &[0]), 0);
+        assert_eq!(at(&mut kernel, &mut mem, nr::RENAMEAT2, &["/bin", "/bin/x"], &[0]), EINVAL.as_ret());
+        assert_eq!(at(&mut kernel, &mut mem, nr::RENAMEAT2, &["/bin/sh", "/bin/ash"], &[0]), 0);
+        assert_eq!(at(&mut kernel, &mut mem, nr::RENAMEAT2, &["/etc/new", "/etc/motd"], &[RENAME_NOREPLACE]), EEXIST.as_ret());
+        assert_eq!(at(&mut kernel, &mut mem, nr::UNLINKAT, &["/etc/loop"], &[0]), 0);
+        assert_eq!(at(&mut kernel, &mut mem, nr::UNLINKAT, &["/etc"], &[AT_REMOVEDIR]), ENOTEMPTY.as_ret());
+        assert_eq!(at(&mut kernel, &mut mem, nr::UNLINKAT, &["/tmp"], &[0]), EISDIR.as_ret());
+        path_at(&mut mem, 0x300, "motd");
+        path_at(&mut mem, 0x380, "/etc/greeting");
+        assert_eq!(kernel.dispatch(&mut mem, nr::SYMLINKAT, [0x300, cwd, 0x380, 0, 0, 0]), 0);
+        assert_eq!(at(&mut kernel, &mut mem, nr::OPENAT, &["/etc/loop"], &[0]), ENOENT.as_ret());
+        assert_eq!(at(&mut kernel, &mut mem, nr::OPENAT, &["/bin/sh"], &[0]), ENOENT.as_ret());
+        let fd = at(&mut kernel, &mut mem, nr::OPENAT, &["/bin/ash"], &[0]);
+        assert_eq!(kernel.dispatch(&mut mem, nr::READ, [fd as u64, 0x200, 64, 0, 0, 0]), 12);
+
+        // The upper layer: changed entries and whiteouts for what went away
+        let layer = kernel.vfs.upper_layer().unwrap();
+        let entries = rootfs::read_tar(&layer).unwrap();
+        let listed: Vec<(&str, Kind)> = entries.iter().map(|e| (e.path.as_str(), e.kind)).collect();
+        assert_eq!(
+            listed,
+            [
+                ("bin", Kind::Dir),
+                ("etc", Kind::Dir),
+                ("tmp", Kind::Dir),
+                ("bin/.wh.sh", Kind::File),
+                ("bin/ash", Kind::Symlink),
+                ("etc/.wh.loop", Kind::File),
+                ("etc/greeting", Kind::Symlink),
+                ("etc/motd", Kind::File),
+                ("etc/new", Kind::File),
+            ]
+        );
+        assert_eq!(entries[7].data, b"hello");
+        assert_eq!((entries[8].data, entries[8].mode), (&b"more"[..], 0o600));
+
+        // ... which a fresh tree over the same image takes back
+        let mut restored = rootfs_kernel();
+        restored.vfs.apply_layer(&layer).unwrap();
+        assert_eq!(restored.vfs.upper_layer().unwrap(), layer);
+        assert_eq!(at(&mut restored, &mut mem, nr::OPENAT, &["/bin/sh"], &[0]), ENOENT.as_ret());
+        let fd = at(&mut restored, &mut mem, nr::OPENAT, &["/etc/greeting"], &[0]);
+        assert_eq!(restored.dispatch(&mut mem, nr::READ, [fd as u64, 0x200, 64, 0, 0, 0]), 5);
+    }
+
+    #[test]
+    fn test_proc_and_devices() {
+        let mut kernel = rootfs_kernel();
+        kernel.process.argv = vec!["/bin/busybox".into(), "cat".into()];
+        kernel.process.exe = "/bin/busybox".into();
+        kernel.process.pid = 42;
+        let mut mem = vec![0u8; 0x4000];
+        let cwd = AT_FDCWD as u64;
+        let open = |kernel: &mut Kernel, mem: &mut Vec<u8>, s: &str, flags: u64| {
+            path_at(mem, 0x100, s);
+            kernel.dispatch(mem, nr::OPENAT, [cwd, 0x100, flags, 0, 0, 0])
+        };
+        let read = |kernel: &mut Kernel, mem: &mut Vec<u8>, fd: i64| {
+            let n = kernel.dispatch(mem, nr::READ, [fd as u64, 0x1000, 0x1000, 0, 0, 0]);
+            String::from_utf8_lossy(&mem[0x1000..0x1000 + n as usize]).into_owned()
+        };
+
+        // /proc/self shows the process as it is when the file is opened
+        let fd = open(&mut kernel, &mut mem, "/proc/self/status", 0);
+        let status = read(&mut kernel, &mut mem, fd);
+        assert!(status.starts_with("Name:\tbusybox\n"), "{}", status);
+        assert!(status.contains("\nPid:\t42\n"), "{}", status);
+        assert_eq!(read(&mut kernel, &mut mem, fd), "");
+        let fd = open(&mut kernel, &mut mem, "/proc/self/cmdline", 0);
+        assert_eq!(read(&mut kernel, &mut mem, fd), "/bin/busybox\0cat\0");
+        let fd = open(&mut kernel, &mut mem, "/proc/self/stat", 0);
+        assert!(read(&mut kernel, &mut mem, fd).starts_with("42 (busybox) R 0 1 1 "));
+        kernel.mm.brk(&mut mem, 0x12000);
+        let fd = open(&mut kernel, &mut mem, "/proc/self/maps", 0);
+        assert_eq!(read(&mut kernel, &mut mem, fd), "00010000-00012000 rw-p 00000000 00:00 0 [heap]\n");
+        let fd = open(&mut kernel, &mut mem, "/proc/meminfo", 0);
+        assert!(read(&mut kernel, &mut mem, fd).starts_with("MemTotal:           1024 kB\nMemFree:            1016 kB\n"));
+        assert_eq!(open(&mut kernel, &mut mem, "/proc/self/status", O_WRONLY), EACCES.as_ret());
+        path_at(&mut mem, 0x100, "/proc/self/exe");
+        assert_eq!(kernel.dispatch(&mut mem, nr::READLINKAT, [cwd, 0x100, 0x200, 64, 0, 0]), 12);
+        assert_eq!(&mem[0x200..0x20c], b"/bin/busybox");
+        path_at(&mut mem, 0x100, "/proc/self/cwd");
+        assert_eq!(kernel.dispatch(&mut mem, nr::READLINKAT, [cwd, 0x100, 0x200, 64, 0, 0]), 4);
+        assert_eq!(&mem[0x200..0x204], b"/etc");
+
+        // The devices: null swallows, zero and urandom fill
+        let null = open(&mut kernel, &mut mem, "/dev/null", 2);
+        assert_eq!(kernel.dispatch(&mut mem, nr::WRITE, [null as u64, 0x1000, 100, 0, 0, 0]), 100);
+        assert_eq!(read(&mut kernel, &mut mem, null), "");
+        mem[0x1000..0x1010].fill(0xff);
+        let zero = open(&mut kernel, &mut mem, "/dev/zero", 0);
+        assert_eq!(kernel.dispatch(&mut mem, nr::READ, [zero as u64, 0x1000, 16, 0, 0, 0]), 16);
+        assert_eq!(mem[0x1000..0x1010], [0; 16]);
+        kernel.random = Random::Seeded(1);
+        let urandom = open(&mut kernel, &mut mem, "/dev/urandom", 0);
+        assert_eq!(kernel.dispatch(&mut mem, nr::READ, [urandom as u64, 0x1000, 16, 0, 0, 0]), 16);
+        let mut expected = [0u8; 16];
+        Random::Seeded(1).fill(&mut expected).unwrap();
+        assert_eq!(mem[0x1000..0x1010], expected);
+    }
+}
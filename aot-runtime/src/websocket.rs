@@ -0,0 +1,317 @@
+// websocket.rs - Byte stream over a WebSocket connection
+//
+// The server side of RFC 6455, as much as carrying a byte stream needs:
+// the HTTP upgrade handshake, then the payloads of the client's text and
+// binary frames read back to back, and every flush of written bytes sent
+// as one binary frame. Message boundaries carry no meaning, so anything
+// that speaks over a `Read + Write` stream (the GDB stub) can be served to
+// a browser page as is:
+//
+//   friscy-run --gdb-ws 127.0.0.1:1234 prog
+//
+// Pings are answered; a close frame is echoed and reads as end of stream.
+// Extensions and subprotocols are not negotiated.
+
+use anyhow::{bail, ensure, Context, Result};
+use std::io::{self, Read, Write};
+
+/// Appended to the client's key before hashing it for the accept header
+const GUID: &str = "258EAFA5-E914-47DA-95CA-C5AB0DC85B11";
+
+/// Largest handshake request read
+const MAX_REQUEST: usize = 8192;
+/// Largest frame payload accepted
+const MAX_FRAME: u64 = 1 << 24;
+
+const OP_CONTINUATION: u8 = 0x0;
+const OP_TEXT: u8 = 0x1;
+const OP_BINARY: u8 = 0x2;
+const OP_CLOSE: u8 = 0x8;
+const OP_PING: u8 = 0x9;
+const OP_PONG: u8 = 0xa;
+
+/// A server-side WebSocket connection as a byte stream
+pub struct WebSocket<S: Read + Write> {
+    stream: S,
+    /// Payload received but not yet read
+    incoming: Vec<u8>,
+    read_pos: usize,
+    /// Bytes written since the last flush
+    outgoing: Vec<u8>,
+    closed: bool,
+}
+
+impl<S: Read + Write> WebSocket<S> {
+    /// Complete the opening handshake of a client that connected to `stream`
+    pub fn accept(mut stream: S) -> Result<Self> {
+        let mut request = Vec::new();
+        let mut byte = [0u8; 1];
+        // Byte by byte, so nothing after the request is consumed
+        while !request.ends_with(b"\r\n\r\n") {
+            ensure!(request.len() < MAX_REQUEST, "WebSocket handshake request too long");
+            ensure!(stream.read(&mut byte)? == 1, "Connection closed during the WebSocket handshake");
+            request.push(byte[0]);
+        }
+        let request = String::from_utf8_lossy(&request);
+        let header = |name: &str| {
+            request.lines().skip(1).find_map(|line| {
+                let (key, value) = line.split_once(':')?;
+                key.trim().eq_ignore_ascii_case(name).then(|| value.trim().to_string())
+            })
+        };
+        ensure!(request.starts_with("GET "), "Not a WebSocket handshake: {}", request.lines().next().unwrap_or(""));
+        ensure!(
+            header("Upgrade").is_some_and(|v| v.eq_ignore_ascii_case("websocket")),
+            "Not a WebSocket upgrade request"
+        );
+        let key = header("Sec-WebSocket-Key").context("WebSocket handshake without Sec-WebSocket-Key")?;
+        write!(
+            stream,
+            "HTTP/1.1 101 Switching Protocols\r\nUpgrade: websocket\r\nConnection: Upgrade\r\n\
+             Sec-WebSocket-Accept: {}\r\n\r\n",
+            accept_key(&key)
+        )?;
+        stream.flush()?;
+        Ok(Self { stream, incoming: Vec::new(), read_pos: 0, outgoing: Vec::new(), closed: false })
+    }
+
+    /// Send one frame
+    fn send_frame(&mut self, opcode: u8, payload: &[u8]) -> io::Result<()> {
+        let mut header = vec![0x80 | opcode];
+        match payload.len() {
+            len @ 0..=125 => header.push(len as u8),
+            len @ 126..=0xffff => {
+                header.push(126);
+                header.extend_from_slice(&(len as u16).to_be_bytes());
+            }
+            len => {
+                header.push(127);
+                header.extend_from_slice(&(len as u64).to_be_bytes());
+            }
+        }
+        self.stream.write_all(&header)?;
+        self.stream.write_all(payload)?;
+        self.stream.flush()
+    }
+
+    /// Read frames until one carries data; false at the end of the stream
+    fn fill(&mut self) -> Result<bool> {
+        while !self.closed {
+            let mut head = [0u8; 2];
+            if let Err(e) = self.stream.read_exact(&mut head) {
+                if e.kind() == io::ErrorKind::UnexpectedEof {
+                    self.closed = true;
+                    break;
+                }
+                return Err(e.into());
+            }
+            let opcode = head[0] & 0x0f;
+            ensure!(head[1] & 0x80 != 0, "Unmasked WebSocket frame from the client");
+            let len = match head[1] & 0x7f {
+                126 => {
+                    let mut len = [0u8; 2];
+                    self.stream.read_exact(&mut len)?;
+                    u16::from_be_bytes(len) as u64
+                }
+                127 => {
+                    let mut len = [0u8; 8];
+                    self.stream.read_exact(&mut len)?;
+                    u64::from_be_bytes(len)
+                }
+                len => len as u64,
+            };
+            ensure!(len <= MAX_FRAME, "WebSocket frame of {} bytes", len);
+            let mut mask = [0u8; 4];
+            self.stream.read_exact(&mut mask)?;
+            let mut payload = vec![0u8; len as usize];
+            self.stream.read_exact(&mut payload)?;
+            for (i, b) in payload.iter_mut().enumerate() {
+                *b ^= mask[i % 4];
+            }
+
+            match opcode {
+                OP_CONTINUATION | OP_TEXT | OP_BINARY if payload.is_empty() => {}
+                OP_CONTINUATION | OP_TEXT | OP_BINARY => {
+                    self.incoming = payload;
+                    self.read_pos = 0;
+                    return Ok(true);
+                }
+                OP_CLOSE => {
+                    // Echo the status code, if any
+                    self.send_frame(OP_CLOSE, payload.get(..2).unwrap_or(&[]))?;
+                    self.closed = true;
+                }
+                OP_PING => self.send_frame(OP_PONG, &payload)?,
+                OP_PONG => {}
+                op => bail!("Unknown WebSocket opcode {:#x}", op),
+            }
+        }
+        Ok(false)
+    }
+}
+
+impl<S: Read + Write> Read for WebSocket<S> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        if self.read_pos == self.incoming.len() && !self.fill().map_err(io::Error::other)? {
+            return Ok(0);
+        }
+        let n = buf.len().min(self.incoming.len() - self.read_pos);
+        buf[..n].copy_from_slice(&self.incoming[self.read_pos..self.read_pos + n]);
+        self.read_pos += n;
+        Ok(n)
+    }
+}
+
+impl<S: Read + Write> Write for WebSocket<S> {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        self.outgoing.extend_from_slice(buf);
+        Ok(buf.len())
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        if self.outgoing.is_empty() || self.closed {
+            return Ok(());
+        }
+        let data = std::mem::take(&mut self.outgoing);
+        self.send_frame(OP_BINARY, &data)
+    }
+}
+
+/// Sec-WebSocket-Accept for a client's Sec-WebSocket-Key
+fn accept_key(key: &str) -> String {
+    base64(&sha1(format!("{key}{GUID}").as_bytes()))
+}
+
+fn sha1(data: &[u8]) -> [u8; 20] {
+    let mut h: [u32; 5] = [0x67452301, 0xefcdab89, 0x98badcfe, 0x10325476, 0xc3d2e1f0];
+    let mut message = data.to_vec();
+    message.push(0x80);
+    while message.len() % 64 != 56 {
+        message.push(0);
+    }
+    message.extend_from_slice(&((data.len() as u64) * 8).to_be_bytes());
+
+    for chunk in message.chunks(64) {
+        let mut w = [0u32; 80];
+        for (i, word) in chunk.chunks(4).enumerate() {
+            w[i] = u32::from_be_bytes(word.try_into().unwrap());
+        }
+        for i in 16..80 {
+            w[i] = (w[i - 3] ^ w[i - 8] ^ w[i - 14] ^ w[i - 16]).rotate_left(1);
+        }
+        let [mut a, mut b, mut c, mut d, mut e] = h;
+        for (i, &wi) in w.iter().enumerate() {
+            let (f, k) = match i {
+                0..=19 => ((b & c) | (!b & d), 0x5a827999),
+                20..=39 => (b ^ c ^ d, 0x6ed9eba1),
+                40..=59 => ((b & c) | (b & d) | (c & d), 0x8f1bbcdc),
+                _ => (b ^ c ^ d, 0xca62c1d6),
+            };
+            let t = a.rotate_left(5).wrapping_add(f).wrapping_add(e).wrapping_add(k).wrapping_add(wi);
+            (e, d, c, b, a) = (d, c, b.rotate_left(30), a, t);
+        }
+        for (x, y) in h.iter_mut().zip([a, b, c, d, e]) {
+            *x = x.wrapping_add(y);
+        }
+    }
+
+    let mut out = [0u8; 20];
+    for (dst, word) in out.chunks_mut(4).zip(h) {
+        dst.copy_from_slice(&word.to_be_bytes());
+    }
+    out
+}
+
+fn base64(data: &[u8]) -> String {
+    const ALPHABET: &[u8; 64] = b"ABCDEFGHIJKLMNOPQRSTUVWXYZabcdefghijklmnopqrstuvwxyz0123456789+/";
+    let mut out = String::new();
+    for group in data.chunks(3) {
+        let bits = group.iter().enumerate().fold(0u32, |acc, (i, &b)| acc | (b as u32) << (16 - 8 * i));
+        for i in 0..4 {
+            out.push(if i <= group.len() { ALPHABET[(bits >> (18 - 6 * i) & 0x3f) as usize] as char } else { '=' });
+        }
+    }
+    out
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::io::Cursor;
+
+    /// Input from a buffer, output to another
+    struct Duplex {
+        input: Cursor<Vec<u8>>,
+        output: Vec<u8>,
+    }
+
+    impl Read for Duplex {
+        fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+            self.input.read(buf)
+        }
+    }
+
+    impl Write for Duplex {
+        fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+            self.output.write(buf)
+        }
+
+        fn flush(&mut self) -> io::Result<()> {
+            Ok(())
+        }
+    }
+
+    /// A client frame, masked as clients must
+    fn client_frame(opcode: u8, payload: &[u8]) -> Vec<u8> {
+        let mask = [0x12, 0x34, 0x56, 0x78];
+        let mut frame = vec![0x80 | opcode, 0x80 | payload.len() as u8];
+        frame.extend_from_slice(&mask);
+        frame.extend(payload.iter().enumerate().map(|(i, b)| b ^ mask[i % 4]));
+        frame
+    }
+
+    #[test]
+    fn test_accept_key() {
+        assert_eq!(base64(&sha1(b"abc")), "qZk+NkcGgWq6PiVxeFDCbJzQ2J0=");
+        assert_eq!(base64(b"ab"), "YWI=");
+        // RFC 6455's example handshake
+        assert_eq!(accept_key("dGhlIHNhbXBsZSBub25jZQ=="), "s3pPLMBiTxaQ9kYGzzhZRbK+xOo=");
+    }
+
+    #[test]
+    fn test_stream_over_frames() {
+        let mut input = b"GET /gdb HTTP/1.1\r\nHost: x\r\nupgrade: WebSocket\r\nConnection: Upgrade\r\n\
+            Sec-WebSocket-Key: dGhlIHNhbXBsZSBub25jZQ==\r\nSec-WebSocket-Version: 13\r\n\r\n"
+            .to_vec();
+        input.extend(client_frame(OP_TEXT, b"$?#"));
+        input.extend(client_frame(OP_PING, b"hi"));
+        input.extend(client_frame(OP_BINARY, b"3f"));
+        input.extend(client_frame(OP_CLOSE, &[0x03, 0xe8]));
+        let mut ws = WebSocket::accept(Duplex { input: Cursor::new(input), output: Vec::new() }).unwrap();
+        let response = String::from_utf8(std::mem::take(&mut ws.stream.output)).unwrap();
+        assert!(response.starts_with("HTTP/1.1 101 "));
+        assert!(response.contains("Sec-WebSocket-Accept: s3pPLMBiTxaQ9kYGzzhZRbK+xOo=\r\n"));
+
+        // Frames read as one stream; the ping is answered on the way
+        let mut data = Vec::new();
+        ws.read_to_end(&mut data).unwrap();
+        assert_eq!(data, b"$?#3f");
+        // Writes go out as one binary frame per flush
+        ws.closed = false;
+        ws.write_all(b"+").unwrap();
+        write!(ws, "$S05#b8").unwrap();
+        ws.flush().unwrap();
+        let mut expected = vec![0x80 | OP_PONG, 2, b'h', b'i', 0x80 | OP_CLOSE, 2, 0x03, 0xe8];
+        expected.extend_from_slice(&[0x80 | OP_BINARY, 8]);
+        expected.extend_from_slice(b"+$S05#b8");
+        assert_eq!(ws.stream.output, expected);
+
+        // Clients must mask
+        let mut input = b"GET / HTTP/1.1\r\nUpgrade: websocket\r\nSec-WebSocket-Key: a\r\n\r\n".to_vec();
+        input.extend_from_slice(&[0x82, 0x01, b'x']);
+        let mut ws = WebSocket::accept(Duplex { input: Cursor::new(input), output: Vec::new() }).unwrap();
+        assert!(ws.read(&mut [0u8; 4]).is_err());
+        assert!(WebSocket::accept(Duplex { input: Cursor::new(b"GET / HTTP/1.1\r\n\r\n".to_vec()), output: Vec::new() })
+            .is_err());
+    }
+}
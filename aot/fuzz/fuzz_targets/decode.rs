@@ -0,0 +1,31 @@
+// decode - Fuzz the instruction decoder and CFG builder on arbitrary bytes
+//
+// The first 8 bytes are the load address, the rest the code. Every
+// encoding decodes (unknown ones as UNKNOWN), the instructions tile the
+// region, and building a CFG over them may fail but not panic.
+//
+//   cd aot && cargo fuzz run decode
+
+#![no_main]
+
+use libfuzzer_sys::fuzz_target;
+use rv2wasm::elf::CodeSection;
+use rv2wasm::{cfg, disasm};
+
+fuzz_target!(|data: &[u8]| {
+    let Some((base, code)) = data.split_first_chunk::<8>() else { return };
+    let base = u64::from_le_bytes(*base) & !1;
+    let section = CodeSection { vaddr: base, data: code.to_vec(), name: "fuzz".into() };
+    let instructions = disasm::disassemble(&section).expect("disassemble failed");
+
+    let mut covered = 0;
+    for inst in &instructions {
+        assert_eq!(inst.addr, base.wrapping_add(covered as u64), "{:?}", inst);
+        covered += inst.len as usize;
+    }
+    assert!(code.len() - covered < 4, "stopped {} bytes early", code.len() - covered);
+
+    if let Some(first) = instructions.first() {
+        let _ = cfg::build(&instructions, first.addr);
+    }
+});
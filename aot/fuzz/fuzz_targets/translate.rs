@@ -0,0 +1,40 @@
+// translate - Fuzz the JIT pipeline: decode, CFG, translate, IR verifier and
+// module builder, then (with the default `validate` feature) wasmparser
+//
+// Input is laid out as for the decode target. This is what aot-jit runs on
+// guest code in the browser, so malformed code must come back as an error:
+// a panic there is an abort of the whole page.
+//
+//   cd aot && cargo fuzz run translate
+
+#![no_main]
+
+use libfuzzer_sys::fuzz_target;
+use rv2wasm::elf::CodeSection;
+use rv2wasm::{cfg, disasm, translate, verify, wasm_builder};
+
+fuzz_target!(|data: &[u8]| {
+    let Some((base, code)) = data.split_first_chunk::<8>() else { return };
+    let base = u64::from_le_bytes(*base) & !1;
+    let section = CodeSection { vaddr: base, data: code.to_vec(), name: "fuzz".into() };
+    let Ok(instructions) = disasm::disassemble(&section) else { return };
+    let Some(first) = instructions.first() else { return };
+    let Ok(cfg) = cfg::build(&instructions, first.addr) else { return };
+    let Ok(module) = translate::translate_jit(&cfg, base) else { return };
+    for func in &module.functions {
+        if let Err(e) = verify::verify(func, &[]) {
+            panic!("{:#}", e);
+        }
+    }
+    let wasm = wasm_builder::build_jit(&module).expect("verified IR failed to encode");
+
+    #[cfg(feature = "validate")]
+    {
+        let features = wasmparser::WasmFeatures { threads: true, ..Default::default() };
+        if let Err(e) = wasmparser::Validator::new_with_features(features).validate_all(&wasm) {
+            panic!("invalid module: {}", e);
+        }
+    }
+    #[cfg(not(feature = "validate"))]
+    let _ = wasm;
+});
@@ -0,0 +1,388 @@
+// abi.rs - ABI descriptor
+//
+// Describes everything a host needs to drive an emitted module: the
+// machine-state layout, the values block functions return to the dispatch
+// loop, and the exports. rv2wasm writes it next to the module as
+// `<output>.abi.json` so hosts can check `abi_version` and read offsets
+// instead of hardcoding them. For split output it also lists the code
+// modules; module `g` is written as `<output>.<g>.wasm`.
+
+use crate::translate::{
+    ModuleLayout, F32_REGS_OFFSET, F64_REGS_OFFSET, MACHINE_STATE_SIZE, RESERVATION_ADDR_OFFSET,
+    RESERVATION_VALUE_OFFSET, RETURN_ADDR, TRAP_PC_OFFSET, TRAP_VALUE_OFFSET, HART_ID_OFFSET,
+    PC_OFFSET, BUDGET_OFFSET, FCSR_OFFSET,
+};
+use crate::options::UnknownPc;
+use std::fmt::Write;
+
+/// Bumped whenever the machine-state layout, return protocol or export
+/// signatures change incompatibly (and friscy-bundle/aot_abi.js's with it)
+pub const ABI_VERSION: u32 = 8;
+
+/// Block function return: stop the dispatch loop
+pub const HALT: i32 = -1;
+/// Block function return flag: ECALL at the PC in the low bits
+pub const ECALL_FLAG: u32 = 0x8000_0000;
+/// Block function return flag: EBREAK at the PC in the low bits
+pub const EBREAK_FLAG: u32 = 0xC000_0000;
+/// Block function return: illegal instruction at the PC stored at
+/// `TRAP_PC_OFFSET`. It has the ECALL bit set, so the dispatch loop hands it
+/// to `env.syscall`; hosts must test for it before the flags above.
+pub const ILLEGAL_TRAP: u32 = 0xFFFF_FFFD;
+/// Block function return: misaligned atomic access at the PC stored at
+/// `TRAP_PC_OFFSET` to the address stored at `TRAP_VALUE_OFFSET` (SIGBUS).
+/// Handed to `env.syscall` like `ILLEGAL_TRAP`.
+pub const MISALIGNED_TRAP: u32 = 0xFFFF_FFFC;
+/// Block function return: page fault under the software MMU (`mmu.rs`) at
+/// the PC stored at `TRAP_PC_OFFSET` to the guest address stored at
+/// `TRAP_VALUE_OFFSET` (SIGSEGV). Handed to `env.syscall` like `ILLEGAL_TRAP`.
+pub const PAGE_FAULT: u32 = 0xFFFF_FFFB;
+
+/// Syscall handler return: stop the dispatch loop, which returns this value
+/// from `run` as the process's status. Bits 8-15 say how it ended and bits
+/// 0-7 carry the detail; see `RunStatus`.
+pub const STOP: u32 = 0xFFFE_0000;
+/// exit_group: the low 8 bits are the exit status
+pub const STOP_EXITED: u32 = STOP | 1 << 8;
+/// Killed: the low 8 bits are the signal
+pub const STOP_SIGNALED: u32 = STOP | 2 << 8;
+/// An unhandled trap: the low 8 bits are the signal it raised (SIGILL for
+/// `ILLEGAL_TRAP`, SIGBUS for `MISALIGNED_TRAP`, SIGSEGV for `PAGE_FAULT`)
+pub const STOP_TRAPPED: u32 = STOP | 3 << 8;
+/// The instruction budget ran out (`CompileOptions::meter`) before the
+/// block at the PC `get_pc` reports. Also what the block returns to the
+/// dispatch loop; a host that adds budget can `run` from that PC again.
+pub const STOP_BUDGET: u32 = STOP | 4 << 8;
+/// The dispatch loop reached a PC without code
+/// (`CompileOptions::unknown_pc`): the PC is stored at `TRAP_PC_OFFSET`
+/// and `PC_OFFSET`, the last block that ran at `TRAP_VALUE_OFFSET` (0 if
+/// none had)
+pub const STOP_UNKNOWN_PC: u32 = STOP | 5 << 8;
+
+/// Why the dispatch loop of a JSPI module (`CompileOptions::jspi`) called
+/// `env.syscall_async`, its third argument; the PC is its second, unflagged
+/// (for the traps, the faulting PC from `TRAP_PC_OFFSET`). The traps' are
+/// also the first field of the `guest_trap` exception
+/// (`CompileOptions::trap_exceptions`).
+pub const CAUSE_ECALL: i32 = 0;
+pub const CAUSE_EBREAK: i32 = 1;
+pub const CAUSE_ILLEGAL: i32 = 2;
+pub const CAUSE_MISALIGNED: i32 = 3;
+pub const CAUSE_PAGE_FAULT: i32 = 4;
+
+/// How a run ended, as `run` reports it
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum RunStatus {
+    /// The handler halted the loop (`HALT`) or an export wrapper returned
+    Halted,
+    Exited(u8),
+    Signaled(u8),
+    Trapped(u8),
+    /// The instruction budget ran out (`STOP_BUDGET`)
+    OutOfBudget,
+    /// Control reached a PC without code (`STOP_UNKNOWN_PC`)
+    UnknownPc,
+}
+
+impl RunStatus {
+    /// Decode `run`'s return value; None if it is not a status
+    pub fn from_run(value: i32) -> Option<Self> {
+        let value = value as u32;
+        if value == 0 {
+            return Some(Self::Halted);
+        }
+        if value & 0xFFFF_0000 != STOP {
+            return None;
+        }
+        let detail = value as u8;
+        match value & 0xFFFF_FF00 {
+            STOP_EXITED => Some(Self::Exited(detail)),
+            STOP_SIGNALED => Some(Self::Signaled(detail)),
+            STOP_TRAPPED => Some(Self::Trapped(detail)),
+            STOP_BUDGET => Some(Self::OutOfBudget),
+            STOP_UNKNOWN_PC => Some(Self::UnknownPc),
+            _ => None,
+        }
+    }
+
+    /// The value a syscall handler returns to end the run with this status
+    pub fn stop_code(self) -> i32 {
+        (match self {
+            Self::Halted => HALT as u32,
+            Self::Exited(status) => STOP_EXITED | status as u32,
+            Self::Signaled(sig) => STOP_SIGNALED | sig as u32,
+            Self::Trapped(sig) => STOP_TRAPPED | sig as u32,
+            Self::OutOfBudget => STOP_BUDGET,
+            Self::UnknownPc => STOP_UNKNOWN_PC,
+        }) as i32
+    }
+
+    /// The status a shell would report: the exit status, or 128 plus the
+    /// signal (SIGXCPU for an exhausted budget, as for a CPU time limit,
+    /// and SIGSEGV for an unknown PC, as for a jump to unmapped code)
+    pub fn shell_status(self) -> i32 {
+        match self {
+            Self::Halted => 0,
+            Self::Exited(status) => status as i32,
+            Self::Signaled(sig) | Self::Trapped(sig) => 128 + sig as i32,
+            Self::OutOfBudget => 128 + 24,
+            Self::UnknownPc => 128 + 11,
+        }
+    }
+}
+
+/// Time page layout (`CompileOptions::time_page`, see `vdso.rs`): a
+/// sequence count, odd while the host updates the page and 0 until it
+/// first has, then CLOCK_MONOTONIC and CLOCK_REALTIME in nanoseconds
+pub const TIME_SEQ_OFFSET: u32 = 0;
+pub const TIME_MONOTONIC_OFFSET: u32 = 8;
+pub const TIME_REALTIME_OFFSET: u32 = 16;
+
+/// The descriptor for a module with `layout` entered at `entry`, as JSON
+pub fn descriptor(layout: &ModuleLayout, entry: u64) -> String {
+    let mut helpers = Vec::new();
+    if layout.threads {
+        helpers.extend(["futex_wait", "futex_wake", "thread_init"]);
+    }
+    if !layout.data.is_empty() {
+        helpers.extend(["init_data", "init_memory"]);
+    }
+    helpers.push("reset");
+    helpers.push("set_block");
+    helpers.extend(crate::wasm_builder::ACCESSORS);
+    if layout.size {
+        helpers.push("block_index");
+    }
+    let guest: Vec<String> = layout
+        .exports
+        .iter()
+        .map(|(name, addr)| format!("{{\"name\":{},\"entry\":{}}}", json_string(name), addr))
+        .collect();
+    let code_modules: Vec<String> = (0..layout.groups.len())
+        .map(|g| {
+            let range = layout.group_range(g);
+            format!("{{\"first\":{},\"count\":{}}}", range.start, range.len())
+        })
+        .collect();
+    let soft_mmu = match layout.soft_mmu {
+        Some(root) => format!("{{\"root\": {}, \"page_size\": {}}}", root, crate::mmu::PAGE_SIZE),
+        None => "null".into(),
+    };
+    let time_page = match layout.time_page {
+        Some(addr) => format!(
+            "{{\"addr\": {}, \"seq\": {}, \"monotonic\": {}, \"realtime\": {}}}",
+            addr, TIME_SEQ_OFFSET, TIME_MONOTONIC_OFFSET, TIME_REALTIME_OFFSET
+        ),
+        None => "null".into(),
+    };
+    let jspi = if layout.jspi {
+        format!(
+            "{{\"ecall\": {}, \"ebreak\": {}, \"illegal\": {}, \"misaligned\": {}, \"page_fault\": {}}}",
+            CAUSE_ECALL, CAUSE_EBREAK, CAUSE_ILLEGAL, CAUSE_MISALIGNED, CAUSE_PAGE_FAULT
+        )
+    } else {
+        "null".into()
+    };
+    let trap_exceptions = if layout.trap_exceptions {
+        format!("{{\"tag\": \"{}\", \"params\": [\"cause\", \"pc\", \"addr\"]}}", crate::link::GUEST_TRAP)
+    } else {
+        "null".into()
+    };
+    let syscall = if layout.jspi { crate::link::SYSCALL_ASYNC } else { crate::link::SYSCALL };
+    let mut extra_imports = String::new();
+    if layout.debug_imports() {
+        extra_imports.push_str(", \"watch\": \"env.watch\", \"breakpoint\": \"env.breakpoint\"");
+    }
+    if !layout.interpose.is_empty() {
+        let names: Vec<String> = layout.interpose.iter().map(|name| json_string(&format!("env.{}", name))).collect();
+        let _ = write!(extra_imports, ", \"interposed\": [{}]", names.join(", "));
+    }
+    if layout.is_split() {
+        extra_imports.push_str(", \"load_block\": \"env.load_block\"");
+    }
+    if layout.lazy_compile {
+        extra_imports.push_str(", \"compile_missing\": \"env.compile_missing\"");
+    }
+    if layout.unknown_pc == UnknownPc::Import {
+        extra_imports.push_str(", \"unknown_pc\": \"env.unknown_pc\"");
+    }
+    let unknown_pc = match layout.unknown_pc {
+        UnknownPc::Halt => "halt",
+        UnknownPc::Stop => "stop",
+        UnknownPc::Trap => "trap",
+        UnknownPc::Import => "import",
+    };
+    let watch = match &layout.watch {
+        Some(range) => format!("{{\"start\": {}, \"end\": {}}}", range.start, range.end),
+        None => "null".into(),
+    };
+
+    let mut out = String::new();
+    let _ = write!(
+        out,
+        concat!(
+            "{{\n",
+            "  \"abi_version\": {},\n",
+            "  \"entry\": {},\n",
+            "  \"memory_pages\": {},\n",
+            "  \"memory_offset\": {},\n",
+            "  \"soft_mmu\": {},\n",
+            "  \"time_page\": {},\n",
+            "  \"threads\": {},\n",
+            "  \"harts\": {},\n",
+            "  \"jspi\": {},\n",
+            "  \"trap_exceptions\": {},\n",
+            "  \"watch\": {},\n",
+            "  \"meter\": {},\n",
+            "  \"unknown_pc\": \"{}\",\n",
+            "  \"max_pages\": {},\n",
+            "  \"machine_state\": {{\"size\": {}, \"x\": {{\"offset\": 0, \"stride\": 8}}, ",
+            "\"f32\": {{\"offset\": {}, \"stride\": 4}}, \"f64\": {{\"offset\": {}, \"stride\": 8}}, ",
+            "\"reservation_addr\": {}, \"reservation_value\": {}, \"trap_pc\": {}, \"trap_value\": {}, ",
+            "\"hart_id\": {}, \"pc\": {}, \"budget\": {}, \"fcsr\": {}}},\n",
+            "  \"returns\": {{\"halt\": {}, \"ecall_flag\": {}, \"ebreak_flag\": {}, \"illegal_trap\": {}, ",
+            "\"misaligned_trap\": {}, \"page_fault\": {}, \"guest_return_addr\": {}, ",
+            "\"stop_exited\": {}, \"stop_signaled\": {}, \"stop_trapped\": {}, \"stop_budget\": {}, ",
+            "\"stop_unknown_pc\": {}}},\n",
+            "  \"imports\": {{\"memory\": \"env.memory\", \"syscall\": \"env.{}\", \"yield\": \"env.yield\", \"crypto\": \"env.crypto\"{}}},\n",
+            "  \"exports\": {{\"run\": \"run\", \"table\": \"table\", \"blocks\": {}, \"helpers\": [{}], \"guest_functions\": [{}]}},\n",
+            "  \"code_modules\": [{}]\n",
+            "}}\n"
+        ),
+        ABI_VERSION,
+        entry,
+        layout.memory_pages,
+        layout.memory_offset,
+        soft_mmu,
+        time_page,
+        layout.threads,
+        layout.harts,
+        jspi,
+        trap_exceptions,
+        watch,
+        layout.meter,
+        unknown_pc,
+        layout.max_pages.map_or("null".into(), |pages| pages.to_string()),
+        MACHINE_STATE_SIZE,
+        F32_REGS_OFFSET,
+        F64_REGS_OFFSET,
+        RESERVATION_ADDR_OFFSET,
+        RESERVATION_VALUE_OFFSET,
+        TRAP_PC_OFFSET,
+        TRAP_VALUE_OFFSET,
+        HART_ID_OFFSET,
+        PC_OFFSET,
+        BUDGET_OFFSET,
+        FCSR_OFFSET,
+        HALT,
+        ECALL_FLAG,
+        EBREAK_FLAG,
+        ILLEGAL_TRAP,
+        MISALIGNED_TRAP,
+        PAGE_FAULT,
+        RETURN_ADDR as i64,
+        STOP_EXITED,
+        STOP_SIGNALED,
+        STOP_TRAPPED,
+        STOP_BUDGET,
+        STOP_UNKNOWN_PC,
+        syscall,
+        extra_imports,
+        layout.block_addrs.len(),
+        helpers.iter().map(|h| json_string(h)).collect::<Vec<_>>().join(", "),
+        guest.join(", "),
+        code_modules.join(", "),
+    );
+    out
+}
+
+pub(crate) fn json_string(s: &str) -> String {
+    let mut out = String::from("\"");
+    for c in s.chars() {
+        match c {
+            '"' => out.push_str("\\\""),
+            '\\' => out.push_str("\\\\"),
+            c if (c as u32) < 0x20 => {
+                let _ = write!(out, "\\u{:04x}", c as u32);
+            }
+            c => out.push(c),
+        }
+    }
+    out.push('"');
+    out
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_descriptor() {
+        let layout = ModuleLayout {
+            block_addrs: vec![0x1000, 0x1008],
+            memory_pages: 8,
+            threads: true,
+            exports: vec![("guest_\"odd\"".into(), 0x1000)],
+            groups: vec![0, 1],
+            data: Default::default(),
+            size: false,
+            block_exports: None,
+            memory_offset: 0x10000,
+            soft_mmu: None,
+            time_page: Some(0x7000),
+            lazy_compile: true,
+            unknown_pc: UnknownPc::Import,
+            jspi: true,
+            trap_exceptions: true,
+            harts: 4,
+            watch: Some(0x2000..0x2008),
+            breakpoints: false,
+            interpose: Vec::new(),
+            meter: true,
+            max_pages: Some(1024),
+            entry: 0x1000,
+            image: Vec::new(),
+        };
+        let json = descriptor(&layout, 0x1000);
+        assert!(json.contains("\"abi_version\": 8,"));
+        assert!(json.contains("\"harts\": 4,"));
+        assert!(json.contains("\"entry\": 4096,"));
+        assert!(json.contains("\"memory_offset\": 65536,"));
+        assert!(json.contains("\"soft_mmu\": null,"));
+        assert!(json.contains("\"jspi\": {\"ecall\": 0, \"ebreak\": 1, \"illegal\": 2, "));
+        assert!(json.contains("\"syscall\": \"env.syscall_async\""));
+        assert!(json.contains("\"trap_exceptions\": {\"tag\": \"guest_trap\", "));
+        assert!(json.contains("\"time_page\": {\"addr\": 28672, \"seq\": 0, \"monotonic\": 8, \"realtime\": 16}"));
+        assert!(json.contains("\"size\": 704"));
+        assert!(json.contains("\"trap_value\": 664, \"hart_id\": 672, \"pc\": 680, \"budget\": 688, \"fcsr\": 696}"));
+        assert!(json.contains("\"meter\": true,\n  \"unknown_pc\": \"import\",\n  \"max_pages\": 1024,"));
+        assert!(json.contains("\"stop_budget\": 4294837248, \"stop_unknown_pc\": 4294837504}"));
+        assert!(json.contains("\"illegal_trap\": 4294967293, \"misaligned_trap\": 4294967292, \"page_fault\": 4294967291"));
+        assert!(json.contains("\"thread_init\", \"reset\", \"set_block\", \"get_reg\", \"set_reg\", \"get_pc\", \"set_pc\"]"));
+        assert!(json.contains("{\"name\":\"guest_\\\"odd\\\"\",\"entry\":4096}"));
+        assert!(json.contains("\"guest_return_addr\": -2, \"stop_exited\": 4294836480, "));
+        assert!(json.contains("\"watch\": {\"start\": 8192, \"end\": 8200},"));
+        assert!(json.contains("\"watch\": \"env.watch\", \"breakpoint\": \"env.breakpoint\", \"load_block\": "));
+        assert!(json.contains("\"load_block\": \"env.load_block\", \"compile_missing\": \"env.compile_missing\", \"unknown_pc\": \"env.unknown_pc\""));
+        assert!(json.contains("\"code_modules\": [{\"first\":0,\"count\":1}, {\"first\":1,\"count\":1}]"));
+    }
+
+    #[test]
+    fn test_run_status() {
+        for status in [
+            RunStatus::Exited(0),
+            RunStatus::Exited(255),
+            RunStatus::Signaled(9),
+            RunStatus::Trapped(11),
+            RunStatus::OutOfBudget,
+            RunStatus::UnknownPc,
+        ] {
+            assert_eq!(RunStatus::from_run(status.stop_code()), Some(status));
+        }
+        assert_eq!(RunStatus::from_run(0), Some(RunStatus::Halted));
+        assert_eq!(RunStatus::from_run(ILLEGAL_TRAP as i32), None);
+        assert_eq!(RunStatus::from_run((STOP | 7 << 8) as i32), None);
+        assert_eq!(RunStatus::Trapped(11).shell_status(), 139);
+        assert_eq!(RunStatus::UnknownPc.shell_status(), 139);
+    }
+}
@@ -0,0 +1,152 @@
+// bundle.rs - Multi-binary bundles
+//
+// A bundle compiles several ELFs (busybox, ld.so, libc.so, ...) into one
+// module over one guest address space. Each binary is translated at its own
+// load bias and their CFGs merge into one block map and dispatch table,
+// entered at the first binary's entry. When the guest execs another bundled
+// binary, or ld.so maps a bundled library, the host loads its segments
+// where the bundle put them and keeps running AOT code; only code outside
+// the bundle needs the JIT. The manifest (`rv2wasm --bundle` writes it as
+// `<output>.bundle.json`) lists every binary's name, bias and entry.
+//
+// Biased binaries must not overlap. Calls between them go through the GOT
+// ld.so fills in at run time, so they are indirect jumps the dispatch loop
+// resolves like any other.
+
+use crate::cfg::{self, ControlFlowGraph};
+use crate::data::{self, DataChunk};
+use crate::disasm::{self, Instruction};
+use crate::elf::{ElfFile, ElfInfo};
+use crate::options::CompileOptions;
+use crate::{plt, startup, translate, wasm_builder};
+use anyhow::{bail, Context, Result};
+
+/// One binary of a bundle, parsed and disassembled at its load bias
+pub struct Binary<'a> {
+    /// What the host knows it by, e.g. its path in the rootfs
+    pub name: String,
+    pub file: ElfFile<'a>,
+    pub instructions: Vec<Instruction>,
+}
+
+impl<'a> Binary<'a> {
+    pub fn load(name: impl Into<String>, data: &'a [u8], load_bias: u64) -> Result<Self> {
+        let name = name.into();
+        let file = ElfFile::parse(data)
+            .and_then(|file| file.with_load_bias(load_bias))
+            .with_context(|| format!("Failed to load {}", name))?;
+        let mut instructions = Vec::new();
+        for section in &file.code_sections() {
+            instructions.extend(disasm::disassemble(section)?);
+        }
+        Ok(Self { name, file, instructions })
+    }
+
+    /// The binary's own CFG, with PLT stubs bound and startup code marked
+    pub fn cfg(&self) -> Result<ControlFlowGraph<'_>> {
+        let mut cfg = cfg::build(&self.instructions, self.file.info().entry)?;
+        plt::bind(&mut cfg, &self.file);
+        startup::apply(&mut cfg, &self.file);
+        Ok(cfg)
+    }
+}
+
+/// Add `binary` to a bundle whose CFG and segments so far are `cfg` and
+/// `info`, which keep their entry
+pub fn add<'b>(cfg: &mut ControlFlowGraph<'b>, info: &mut ElfInfo, binary: &'b Binary) -> Result<()> {
+    let segments = &binary.file.info().segments;
+    for seg in segments {
+        let end = seg.vaddr + seg.memsz;
+        if let Some(other) = info.segments.iter().find(|s| seg.vaddr < s.vaddr + s.memsz && s.vaddr < end) {
+            bail!(
+                "{} at 0x{:x}-0x{:x} overlaps the bundle at 0x{:x}-0x{:x}; give it another load bias",
+                binary.name,
+                seg.vaddr,
+                end,
+                other.vaddr,
+                other.vaddr + other.memsz
+            );
+        }
+    }
+    info.segments.extend(segments.iter().cloned());
+
+    let other = binary.cfg()?;
+    for (addr, block) in other.blocks {
+        if cfg.blocks.insert(addr, block).is_some() {
+            bail!("{} has code at 0x{:x}, which the bundle already has", binary.name, addr);
+        }
+    }
+    cfg.functions.extend(other.functions);
+    Ok(())
+}
+
+/// The CFG and ELF info of `binaries` as one program, entered at the first
+pub fn merge<'b>(binaries: &'b [Binary]) -> Result<(ControlFlowGraph<'b>, ElfInfo)> {
+    let (first, rest) = binaries.split_first().context("Empty bundle")?;
+    let mut cfg = first.cfg()?;
+    let mut info = first.file.info().clone();
+    for binary in rest {
+        add(&mut cfg, &mut info, binary)?;
+    }
+    Ok((cfg, info))
+}
+
+/// The initial memory of every binary, for `CompileOptions::data`
+pub fn data(binaries: &[Binary]) -> Result<Vec<DataChunk>> {
+    data::chunks_of(binaries.iter().map(|b| (b.file.data(), &b.file.info().segments[..])))
+}
+
+/// The manifest hosts look binaries up in: name, load bias and entry of each
+/// (name, file) in bundle order
+pub fn manifest<'a>(binaries: impl IntoIterator<Item = (&'a str, &'a ElfFile<'a>)>) -> String {
+    let entries: Vec<String> = binaries
+        .into_iter()
+        .map(|(name, file)| {
+            format!(
+                "  {{\"name\": {}, \"load_bias\": {}, \"entry\": {}}}",
+                crate::abi::json_string(name),
+                file.load_bias(),
+                file.info().entry
+            )
+        })
+        .collect();
+    format!("[\n{}\n]\n", entries.join(",\n"))
+}
+
+/// Compile `binaries` into one module
+pub fn compile(binaries: &[Binary], options: &CompileOptions) -> Result<Vec<u8>> {
+    let (cfg, info) = merge(binaries)?;
+    wasm_builder::build(&translate::translate(&cfg, &info, options)?)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::elf::tiny_elf;
+    use goblin::elf::header::ET_DYN;
+
+    #[test]
+    fn test_bundle_merges_binaries() {
+        // "sh": auipc a0, 0 ; ecall -- "lib": addi a0, a0, 1 ; ret
+        let sh = tiny_elf(ET_DYN, &[0x00000517, 0x00000073]);
+        let lib = tiny_elf(ET_DYN, &[0x00150513, 0x00008067]);
+        let binaries =
+            [Binary::load("/bin/sh", &sh, 0x10_0000).unwrap(), Binary::load("/lib/libx.so", &lib, 0x20_0000).unwrap()];
+        let (cfg, info) = merge(&binaries).unwrap();
+        assert_eq!(cfg.entry, 0x10_1078);
+        assert!(cfg.blocks.contains_key(&0x10_1078) && cfg.blocks.contains_key(&0x20_1078));
+        assert_eq!(info.segments.len(), 2);
+        let pages: Vec<u64> = data(&binaries).unwrap().iter().map(|c| c.page()).collect();
+        assert_eq!(pages, [0x10, 0x20]);
+
+        let wasm = compile(&binaries, &CompileOptions::default()).unwrap();
+        let blocks = crate::link::block_map(&wasm).unwrap();
+        assert!(blocks.contains(&0x10_1078) && blocks.contains(&0x20_1078));
+        let manifest = manifest(binaries.iter().map(|b| (b.name.as_str(), &b.file)));
+        assert!(manifest.contains("{\"name\": \"/lib/libx.so\", \"load_bias\": 2097152, \"entry\": 2101368}"));
+
+        // The same bias twice overlaps
+        let twice = [Binary::load("a", &sh, 0x10_0000).unwrap(), Binary::load("b", &lib, 0x10_0000).unwrap()];
+        assert!(merge(&twice).is_err());
+    }
+}
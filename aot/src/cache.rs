@@ -0,0 +1,338 @@
+// cache.rs - Incremental compile cache
+//
+// `rv2wasm --cache-dir DIR` keeps two kinds of entries:
+//
+// - `modules/<key>/`: every file a build wrote (module, ABI descriptor,
+//   code modules), keyed by the input ELF and the options. A rebuild with
+//   the same key copies them out instead of compiling.
+// - `functions/<key>`: the encoded block functions of one guest function,
+//   keyed by its blocks' instructions and addresses, their inline-cache
+//   targets and the options that shape block code. A changed binary only
+//   retranslates the functions whose key changed. Block code embeds
+//   absolute addresses, so a function that moved is a miss too.
+//
+// Keys include the compiler version. Entries are written to a temporary
+// name and renamed into place, and never evicted: delete the directory to
+// reclaim space.
+
+use crate::cfg::ControlFlowGraph;
+use crate::elf::ElfInfo;
+use crate::options::CompileOptions;
+use crate::{translate, wasm_builder, StreamingBuilder};
+use anyhow::{Context, Result};
+use std::collections::{HashMap, HashSet};
+use std::io::{Seek, Write};
+use std::path::{Path, PathBuf};
+
+/// Name of the main output in a module entry; the others are named by
+/// their extension relative to it
+const MAIN_OUTPUT: &str = "output";
+
+/// A cache directory
+pub struct Cache {
+    dir: PathBuf,
+}
+
+/// Guest functions found in and missing from the cache by `emit_streaming`
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct CacheStats {
+    pub hits: usize,
+    pub misses: usize,
+}
+
+impl Cache {
+    pub fn new(dir: impl Into<PathBuf>) -> Result<Self> {
+        let dir = dir.into();
+        for sub in ["modules", "functions"] {
+            std::fs::create_dir_all(dir.join(sub)).context("Failed to create cache directory")?;
+        }
+        Ok(Self { dir })
+    }
+
+    /// Key of the build of `elf_data` with `options`
+    pub fn module_key(elf_data: &[u8], options: &CompileOptions) -> String {
+        let mut h = Hasher::new();
+        h.write(elf_data);
+        h.options(options);
+        h.u64(options.hot_first as u64);
+        if let Some(profile) = &options.block_profile {
+            let mut text = Vec::new();
+            let _ = profile.write(&mut text);
+            h.write(&text);
+        }
+        for sym in &options.exports {
+            h.write(sym.name.as_bytes());
+            h.u64(sym.addr);
+        }
+        h.u64(options.split as u64);
+        for chunk in options.data.iter() {
+            h.u64(chunk.addr);
+            h.write(&chunk.bytes);
+        }
+        h.u64(options.size as u64);
+        if let Some(blocks) = &options.export_blocks {
+            blocks.iter().for_each(|&addr| h.u64(addr));
+        }
+        h.u64(options.load_bias);
+        h.u64(
+            options.lazy_compile as u64
+                | (options.hot_only as u64) << 1
+                | (options.jspi as u64) << 2
+                | (options.trap_exceptions as u64) << 3
+                | (options.unknown_pc as u64) << 4,
+        );
+        h.u64(options.harts as u64);
+        h.finish()
+    }
+
+    /// Copy the build stored under `key` to `output` and its siblings.
+    /// False if there is no such entry.
+    pub fn restore_module(&self, key: &str, output: &Path) -> Result<bool> {
+        let Ok(entries) = std::fs::read_dir(self.dir.join("modules").join(key)) else {
+            return Ok(false);
+        };
+        for entry in entries {
+            let entry = entry?;
+            let name = entry.file_name();
+            let path = if name == MAIN_OUTPUT { output.to_path_buf() } else { output.with_extension(name) };
+            std::fs::copy(entry.path(), path).context("Failed to restore output")?;
+        }
+        Ok(true)
+    }
+
+    /// Store the build written to `output` and to `output.with_extension(e)`
+    /// for each of `extensions` under `key`
+    pub fn store_module(&self, key: &str, output: &Path, extensions: &[String]) -> Result<()> {
+        let modules = self.dir.join("modules");
+        let tmp = modules.join(format!("{}.tmp{}", key, std::process::id()));
+        std::fs::create_dir_all(&tmp)?;
+        std::fs::copy(output, tmp.join(MAIN_OUTPUT)).context("Failed to cache output")?;
+        for ext in extensions {
+            std::fs::copy(output.with_extension(ext), tmp.join(ext)).context("Failed to cache output")?;
+        }
+        // Another build may have stored it first; either copy will do
+        if std::fs::rename(&tmp, modules.join(key)).is_err() {
+            let _ = std::fs::remove_dir_all(&tmp);
+        }
+        Ok(())
+    }
+
+    /// `crate::emit_streaming`, reusing the block functions of every guest
+    /// function whose key is cached and caching the rest. Holds all encoded
+    /// block functions in memory. Size mode is not cached per function.
+    pub fn emit_streaming<W: Write + Seek>(
+        &self,
+        cfg: &ControlFlowGraph,
+        elf_info: &ElfInfo,
+        options: &CompileOptions,
+        out: W,
+    ) -> Result<(W, CacheStats)> {
+        let layout = translate::layout(cfg, elf_info, options);
+        if layout.size {
+            return Ok((crate::emit_streaming(cfg, elf_info, options, out)?, CacheStats::default()));
+        }
+
+        let mut stats = CacheStats::default();
+        let mut bodies: HashMap<u64, Vec<u8>> = HashMap::new();
+        let mut missing = Vec::new();
+        for (key, addrs) in function_groups(cfg, options) {
+            match self.load_function(&key).filter(|cached| addrs.iter().all(|a| cached.contains_key(a))) {
+                Some(cached) => {
+                    bodies.extend(cached);
+                    stats.hits += 1;
+                }
+                None => missing.push((key, addrs)),
+            }
+        }
+        stats.misses = missing.len();
+
+        let todo: Vec<u64> = missing.iter().flat_map(|(_, addrs)| addrs.iter().copied()).collect();
+        translate::translate_only(cfg, options, &todo, |func| {
+            bodies.insert(func.block_addr, wasm_builder::encode_block(&func)?);
+            Ok(())
+        })?;
+        for (key, addrs) in &missing {
+            self.store_function(key, addrs.iter().map(|a| (*a, &bodies[a][..])))?;
+        }
+
+        let mut builder = StreamingBuilder::new(out, &layout)?;
+        for addr in &layout.block_addrs {
+            builder.push_encoded(*addr, &bodies[addr])?;
+        }
+        Ok((builder.finish()?, stats))
+    }
+
+    /// Encoded block functions by address, if `key` is cached and intact
+    fn load_function(&self, key: &str) -> Option<HashMap<u64, Vec<u8>>> {
+        let data = std::fs::read(self.dir.join("functions").join(key)).ok()?;
+        let mut bodies = HashMap::new();
+        let mut rest = &data[..];
+        while !rest.is_empty() {
+            let addr = u64::from_le_bytes(rest.get(..8)?.try_into().ok()?);
+            let len = u32::from_le_bytes(rest.get(8..12)?.try_into().ok()?) as usize;
+            bodies.insert(addr, rest.get(12..12 + len)?.to_vec());
+            rest = &rest[12 + len..];
+        }
+        Some(bodies)
+    }
+
+    /// Records of (address u64, length u32, encoded body), little-endian
+    fn store_function<'a>(&self, key: &str, bodies: impl Iterator<Item = (u64, &'a [u8])>) -> Result<()> {
+        let mut data = Vec::new();
+        for (addr, bytes) in bodies {
+            data.extend(addr.to_le_bytes());
+            data.extend((bytes.len() as u32).to_le_bytes());
+            data.extend(bytes);
+        }
+        let path = self.dir.join("functions").join(key);
+        let tmp = path.with_extension(format!("tmp{}", std::process::id()));
+        std::fs::write(&tmp, data).context("Failed to write cache entry")?;
+        std::fs::rename(&tmp, &path).context("Failed to write cache entry")?;
+        Ok(())
+    }
+}
+
+/// Blocks grouped by guest function (each block with the first function
+/// reaching it; blocks outside every function alone), with each group's key.
+/// Cold blocks of a hot/cold split are left out.
+pub(crate) fn function_groups(cfg: &ControlFlowGraph, options: &CompileOptions) -> Vec<(String, Vec<u64>)> {
+    let hot = translate::hot_blocks(cfg, options);
+    let compiled = |addr: &u64| hot.as_ref().map_or(cfg.blocks.contains_key(addr), |hot| hot.contains(addr));
+    let mut owned = HashSet::new();
+    let mut groups: Vec<Vec<u64>> = Vec::new();
+    for func in &cfg.functions {
+        groups.push(func.blocks.iter().copied().filter(|&a| owned.insert(a)).collect());
+    }
+    groups.extend(cfg.blocks.keys().filter(|a| !owned.contains(a)).map(|&a| vec![a]));
+
+    let optimize = options.opt_level >= 2;
+    groups
+        .into_iter()
+        .map(|addrs| addrs.into_iter().filter(compiled).collect::<Vec<_>>())
+        .filter(|addrs| !addrs.is_empty())
+        .map(|mut addrs| {
+            addrs.sort_unstable();
+            let mut h = Hasher::new();
+            h.options(options);
+            for addr in &addrs {
+                let block = &cfg.blocks[addr];
+                h.u64(block.start_addr);
+                h.u64(block.end_addr);
+                for inst in block.instructions {
+                    h.u64(inst.addr);
+                    h.u64(inst.bytes as u64 | (inst.len as u64) << 32);
+                }
+                h.u64(block.direct_target.unwrap_or(u64::MAX));
+                for &(addr, value) in &block.got_loads {
+                    h.u64(addr);
+                    h.u64(value);
+                }
+                // Inline-cache guards name the successors that are blocks
+                if optimize {
+                    block.successors.iter().filter(|s| compiled(s)).for_each(|&s| h.u64(s));
+                }
+                h.u64(u64::MAX);
+            }
+            (h.finish(), addrs)
+        })
+        .collect()
+}
+
+/// 128-bit FNV-1a, seeded with the compiler version
+struct Hasher(u128);
+
+impl Hasher {
+    fn new() -> Self {
+        let mut h = Hasher(0x6c62272e07bb014262b821756295c58d);
+        h.write(env!("CARGO_PKG_VERSION").as_bytes());
+        h.u64(crate::link::LINK_VERSION as u64 | (crate::abi::ABI_VERSION as u64) << 32);
+        h
+    }
+
+    fn write(&mut self, bytes: &[u8]) {
+        for &b in bytes {
+            self.0 ^= b as u128;
+            self.0 = self.0.wrapping_mul(0x0000000001000000000000000000013b);
+        }
+        // Length-delimit so adjacent fields cannot run together
+        self.u64(bytes.len() as u64);
+    }
+
+    fn u64(&mut self, value: u64) {
+        for b in value.to_le_bytes() {
+            self.0 ^= b as u128;
+            self.0 = self.0.wrapping_mul(0x0000000001000000000000000000013b);
+        }
+    }
+
+    /// The options every block's code depends on
+    fn options(&mut self, options: &CompileOptions) {
+        self.u64(options.opt_level as u64 | (options.debug as u64) << 8 | (options.threads as u64) << 9);
+        self.u64(options.memory_offset as u64);
+        self.u64(options.soft_mmu.map_or(u64::MAX, |root| root as u64));
+        self.u64(options.time_page.map_or(u64::MAX, |page| page as u64));
+        self.u64(options.fma as u64);
+        match &options.watch {
+            Some(range) => self.u64((range.start as u64) << 32 | range.end as u64),
+            None => self.u64(u64::MAX),
+        }
+        self.u64(options.breakpoints.len() as u64);
+        options.breakpoints.iter().for_each(|&pc| self.u64(pc));
+        for sym in &options.interpose {
+            self.write(sym.name.as_bytes());
+            self.u64(sym.addr);
+        }
+        match &options.syscall_policy {
+            Some(policy) => {
+                self.u64(policy.allow as u64 | (policy.denied as u64) << 1);
+                self.u64(policy.syscalls.len() as u64);
+                policy.syscalls.iter().for_each(|&nr| self.u64(nr));
+            }
+            None => self.u64(u64::MAX),
+        }
+    }
+
+    fn finish(self) -> String {
+        format!("{:032x}", self.0)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::disasm::{Instruction, Opcode};
+    use crate::test_util::{elf_info, inst};
+
+    #[test]
+    fn test_cached_emit_matches_and_reuses_functions() {
+        // main: jal f; ecall -- f: addi; ret
+        let mut code = vec![
+            inst(0x1000, Opcode::JAL, 1, 10, 0, 0x10),
+            inst(0x1004, Opcode::ECALL, 10, 10, 0, 0),
+            inst(0x1010, Opcode::ADDI, 10, 10, 0, 1),
+            inst(0x1014, Opcode::JALR, 0, 1, 0, 0),
+        ];
+        // Blocks are keyed by their encodings, so give each its own
+        code.iter_mut().for_each(|inst| inst.bytes = inst.addr as u32);
+        let elf_info = elf_info();
+        let options = CompileOptions::default();
+        let dir = std::env::temp_dir().join(format!("rv2wasm-cache-test-{}", std::process::id()));
+        let cache = Cache::new(&dir).unwrap();
+        let emit = |code: &[Instruction]| {
+            let cfg = crate::cfg::build(code, 0x1000).unwrap();
+            let plain = crate::emit_streaming(&cfg, &elf_info, &options, std::io::Cursor::new(Vec::new())).unwrap();
+            let (cached, stats) =
+                cache.emit_streaming(&cfg, &elf_info, &options, std::io::Cursor::new(Vec::new())).unwrap();
+            assert_eq!(cached.into_inner(), plain.into_inner());
+            stats
+        };
+
+        assert_eq!(emit(&code), CacheStats { hits: 0, misses: 2 });
+        assert_eq!(emit(&code), CacheStats { hits: 2, misses: 0 });
+        // Touching f leaves main cached
+        code[2].imm = Some(2);
+        code[2].bytes ^= 1;
+        assert_eq!(emit(&code), CacheStats { hits: 1, misses: 1 });
+        let _ = std::fs::remove_dir_all(&dir);
+    }
+}
@@ -1,24 +1,37 @@
 // cfg.rs - Control Flow Graph builder
 //
 // Constructs basic blocks and identifies functions from disassembled instructions.
+//
+// An `auipc r, hi; jalr rd, lo(r)` pair (the `call` and `tail` pseudo-
+// instructions for targets beyond JAL's reach) jumps to a PC-relative
+// address known statically, so it gets a direct edge like a JAL.
 
 use crate::disasm::{Instruction, Opcode};
 use anyhow::Result;
-use std::collections::{BTreeMap, BTreeSet, HashMap};
+use std::collections::{BTreeMap, BTreeSet};
 
 /// A basic block of instructions
+///
+/// Blocks borrow their instructions from the slice the graph was built
+/// from rather than copying them.
 #[derive(Debug, Clone)]
-pub struct BasicBlock {
+pub struct BasicBlock<'a> {
     /// Start address
     pub start_addr: u64,
     /// End address (after last instruction)
     pub end_addr: u64,
     /// Instructions in this block
-    pub instructions: Vec<Instruction>,
+    pub instructions: &'a [Instruction],
     /// Possible successor addresses
     pub successors: Vec<u64>,
     /// Is this a function entry point?
     pub is_function_entry: bool,
+    /// Known target of the indirect jump ending the block (an `auipc`+`jalr`
+    /// pair, or a PLT stub bound by `plt::bind`)
+    pub direct_target: Option<u64>,
+    /// `ld`s from a GOT slot with known contents, as (address of the `ld`,
+    /// value loaded); filled in by `plt::bind`
+    pub got_loads: Vec<(u64, u64)>,
 }
 
 /// A function (collection of basic blocks)
@@ -34,9 +47,9 @@ pub struct Function {
 
 /// Control flow graph
 #[derive(Debug)]
-pub struct ControlFlowGraph {
+pub struct ControlFlowGraph<'a> {
     /// Basic blocks by start address
-    pub blocks: BTreeMap<u64, BasicBlock>,
+    pub blocks: BTreeMap<u64, BasicBlock<'a>>,
     /// Functions
     pub functions: Vec<Function>,
     /// Entry point
@@ -44,7 +57,7 @@ pub struct ControlFlowGraph {
 }
 
 /// Build the control flow graph from disassembled instructions
-pub fn build(instructions: &[Instruction], entry: u64) -> Result<ControlFlowGraph> {
+pub fn build(instructions: &[Instruction], entry: u64) -> Result<ControlFlowGraph<'_>> {
     // Phase 1: Identify block boundaries
     let boundaries = find_block_boundaries(instructions, entry);
 
@@ -73,15 +86,21 @@ fn find_block_boundaries(instructions: &[Instruction], entry: u64) -> BTreeSet<u
         boundaries.insert(first.addr);
     }
 
-    for inst in instructions {
+    for (i, inst) in instructions.iter().enumerate() {
+        if let Some(target) = i.checked_sub(1).and_then(|p| far_jump_target(&instructions[p], inst)) {
+            boundaries.insert(target);
+        }
+
         // Terminators mark end of block, next instruction starts new block
         if inst.opcode.is_terminator() {
             let next_addr = inst.addr + inst.len as u64;
             boundaries.insert(next_addr);
         }
 
-        // Branch/jump targets are block starts
-        if inst.opcode.is_branch() || inst.opcode.is_jump() {
+        // Branch/jump targets are block starts (an indirect jump's
+        // immediate is an offset from a register, not from the PC)
+        let indirect = matches!(inst.opcode, Opcode::JALR | Opcode::C_JR | Opcode::C_JALR);
+        if (inst.opcode.is_branch() || inst.opcode.is_jump()) && !indirect {
             if let Some(imm) = inst.imm {
                 // Direct branches: target is PC + immediate
                 let target = (inst.addr as i64 + imm) as u64;
@@ -103,46 +122,56 @@ fn find_block_boundaries(instructions: &[Instruction], entry: u64) -> BTreeSet<u
 }
 
 /// Create basic blocks from instructions and boundaries
-fn create_blocks(
-    instructions: &[Instruction],
+fn create_blocks<'a>(
+    instructions: &'a [Instruction],
     boundaries: &BTreeSet<u64>,
-) -> BTreeMap<u64, BasicBlock> {
+) -> BTreeMap<u64, BasicBlock<'a>> {
     let mut blocks = BTreeMap::new();
-    let mut current_block: Option<BasicBlock> = None;
-
-    for inst in instructions {
-        // Check if this starts a new block
-        if boundaries.contains(&inst.addr) {
-            // Finish previous block
-            if let Some(block) = current_block.take() {
-                blocks.insert(block.start_addr, block);
+    // Index of the first instruction of the block being built
+    let mut start: Option<usize> = None;
+
+    let mut finish = |start: usize, end: usize| {
+        let insts = &instructions[start..end];
+        let last = &insts[insts.len() - 1];
+        let mut block = BasicBlock {
+            start_addr: insts[0].addr,
+            end_addr: last.addr + last.len as u64,
+            instructions: insts,
+            // If the block ends in a terminator, compute its successors
+            successors: if last.opcode.is_terminator() { compute_successors(last) } else { Vec::new() },
+            is_function_entry: false,
+            direct_target: match insts {
+                [.., auipc, jalr] => far_jump_target(auipc, jalr),
+                _ => None,
+            },
+            got_loads: Vec::new(),
+        };
+        if let Some(target) = block.direct_target.filter(|t| !block.successors.contains(t)) {
+            block.successors.push(target);
+        }
+        blocks.insert(block.start_addr, block);
+    };
+
+    for (i, inst) in instructions.iter().enumerate() {
+        // A boundary or a gap in addresses (section change) starts a new block
+        let contiguous = i > 0 && instructions[i - 1].addr + instructions[i - 1].len as u64 == inst.addr;
+        if boundaries.contains(&inst.addr) || !contiguous {
+            if let Some(s) = start.take() {
+                finish(s, i);
             }
-
-            // Start new block
-            current_block = Some(BasicBlock {
-                start_addr: inst.addr,
-                end_addr: inst.addr + inst.len as u64,
-                instructions: vec![inst.clone()],
-                successors: Vec::new(),
-                is_function_entry: false,
-            });
-        } else if let Some(ref mut block) = current_block {
-            // Add to current block
-            block.instructions.push(inst.clone());
-            block.end_addr = inst.addr + inst.len as u64;
         }
+        let s = *start.get_or_insert(i);
 
-        // If this is a terminator, compute successors
+        // Terminators end the block
         if inst.opcode.is_terminator() {
-            if let Some(ref mut block) = current_block {
-                block.successors = compute_successors(inst);
-            }
+            finish(s, i + 1);
+            start = None;
         }
     }
 
     // Don't forget last block
-    if let Some(block) = current_block {
-        blocks.insert(block.start_addr, block);
+    if let Some(s) = start {
+        finish(s, instructions.len());
     }
 
     // Add fall-through successors for non-terminating blocks
@@ -162,6 +191,19 @@ fn create_blocks(
     blocks
 }
 
+/// The target of `jalr` if it jumps relative to the `auipc` just before it
+fn far_jump_target(auipc: &Instruction, jalr: &Instruction) -> Option<u64> {
+    let r = auipc.rd.filter(|&r| r != 0)?;
+    if auipc.opcode != Opcode::AUIPC || jalr.opcode != Opcode::JALR || jalr.rs1 != Some(r) {
+        return None;
+    }
+    if auipc.addr + auipc.len as u64 != jalr.addr {
+        return None;
+    }
+    let hi = auipc.addr.wrapping_add(auipc.imm? as u64);
+    Some(hi.wrapping_add(jalr.imm.unwrap_or(0) as u64) & !1)
+}
+
 /// Compute successor addresses for a terminator instruction
 fn compute_successors(inst: &Instruction) -> Vec<u64> {
     let mut successors = Vec::new();
@@ -233,7 +275,8 @@ fn identify_functions(blocks: &BTreeMap<u64, BasicBlock>, entry: u64) -> Vec<Fun
     call_targets.insert(entry);
 
     for block in blocks.values() {
-        for inst in &block.instructions {
+        call_targets.extend(block.direct_target);
+        for inst in block.instructions {
             if inst.opcode == Opcode::JAL || inst.opcode == Opcode::C_JAL {
                 if let Some(imm) = inst.imm {
                     let target = (inst.addr as i64 + imm) as u64;
@@ -287,7 +330,36 @@ fn identify_functions(blocks: &BTreeMap<u64, BasicBlock>, entry: u64) -> Vec<Fun
     functions
 }
 
-impl BasicBlock {
+impl ControlFlowGraph<'_> {
+    /// Add a function named `name` at the block `entry`, for an entry no
+    /// direct call reaches (one called through a pointer), or rename the
+    /// function already there. Its blocks stop at other functions' entries.
+    pub fn add_function(&mut self, entry: u64, name: &str) {
+        if let Some(func) = self.functions.iter_mut().find(|f| f.entry == entry) {
+            func.name = name.to_string();
+            return;
+        }
+        if !self.blocks.contains_key(&entry) {
+            return;
+        }
+        let entries: BTreeSet<u64> = self.functions.iter().map(|f| f.entry).collect();
+        let mut blocks = Vec::new();
+        let mut visited = BTreeSet::new();
+        let mut worklist = vec![entry];
+        while let Some(addr) = worklist.pop() {
+            if !visited.insert(addr) {
+                continue;
+            }
+            if let Some(block) = self.blocks.get(&addr) {
+                blocks.push(addr);
+                worklist.extend(block.successors.iter().filter(|s| !entries.contains(s)));
+            }
+        }
+        self.functions.push(Function { entry, name: name.to_string(), blocks });
+    }
+}
+
+impl BasicBlock<'_> {
     /// Get the last instruction (terminator if present)
     pub fn terminator(&self) -> Option<&Instruction> {
         self.instructions.last()
@@ -310,10 +382,49 @@ impl BasicBlock {
 #[cfg(test)]
 mod tests {
     use super::*;
+    use crate::test_util::inst;
 
     #[test]
     fn test_empty_cfg() {
         let cfg = build(&[], 0x1000).unwrap();
         assert!(cfg.blocks.is_empty());
     }
+
+    #[test]
+    fn test_blocks_borrow_and_split_on_gaps() {
+        // addi; beq -> 0x1000; addi | gap | addi
+        let code = [
+            inst(0x1000, Opcode::ADDI, 10, 10, 0, 1),
+            inst(0x1004, Opcode::BEQ, 10, 10, 0, -4),
+            inst(0x1008, Opcode::ADDI, 10, 10, 0, 1),
+            inst(0x2000, Opcode::ADDI, 10, 10, 0, 1),
+        ];
+        let cfg = build(&code, 0x1000).unwrap();
+
+        let starts: Vec<u64> = cfg.blocks.keys().copied().collect();
+        assert_eq!(starts, [0x1000, 0x1008, 0x2000]);
+        let first = &cfg.blocks[&0x1000];
+        assert!(std::ptr::eq(first.instructions.as_ptr(), code.as_ptr()));
+        assert_eq!(first.successors, [0x1000, 0x1008]);
+        assert_eq!(cfg.blocks[&0x1008].end_addr, 0x100c);
+    }
+
+    #[test]
+    fn test_auipc_jalr_is_a_direct_call() {
+        // call f (auipc ra, 0; jalr ra, 0x14(ra)); ecall -- 0x100c: a jump through another register -- f: ret
+        let code = [
+            inst(0x1000, Opcode::AUIPC, 1, 0, 0, 0),
+            inst(0x1004, Opcode::JALR, 1, 1, 0, 0x14),
+            inst(0x1008, Opcode::ECALL, 0, 0, 0, 0),
+            inst(0x100c, Opcode::AUIPC, 6, 0, 0, 0),
+            inst(0x1010, Opcode::JALR, 0, 5, 0, 0),
+            inst(0x1014, Opcode::JALR, 0, 1, 0, 0),
+        ];
+        let cfg = build(&code, 0x1000).unwrap();
+        let call = &cfg.blocks[&0x1000];
+        assert_eq!(call.direct_target, Some(0x1014));
+        assert_eq!(call.successors, [0x1008, 0x1014]);
+        assert!(cfg.functions.iter().any(|f| f.entry == 0x1014));
+        assert_eq!(cfg.blocks[&0x100c].direct_target, None);
+    }
 }
@@ -0,0 +1,151 @@
+// crypto.rs - Scalar AES (Zkne/Zknd) reference implementation
+//
+// The SHA-2 instructions (Zknh) and aes64ks2 lower to shifts, rotates and
+// xors, but the AES round instructions need the S-box, which a block
+// function has no table for. They call the `env.crypto(op, rs1, rs2)`
+// import instead (see `link.rs`); `crypto` below is what a Rust host plugs
+// in there, and what the interpreter uses. `op` is one of the constants
+// below; for aes64ks1i, `rs2` carries the round number.
+
+/// `env.crypto` operations
+pub const AES64ES: i32 = 0;
+pub const AES64ESM: i32 = 1;
+pub const AES64DS: i32 = 2;
+pub const AES64DSM: i32 = 3;
+pub const AES64IM: i32 = 4;
+pub const AES64KS1I: i32 = 5;
+
+const SBOX: [u8; 256] = sbox();
+const INV_SBOX: [u8; 256] = {
+    let mut inv = [0u8; 256];
+    let mut i = 0;
+    while i < 256 {
+        inv[SBOX[i] as usize] = i as u8;
+        i += 1;
+    }
+    inv
+};
+
+/// Multiply in GF(2^8) modulo the AES polynomial
+const fn gf_mul(mut a: u8, mut b: u8) -> u8 {
+    let mut p = 0;
+    while b != 0 {
+        if b & 1 != 0 {
+            p ^= a;
+        }
+        a = (a << 1) ^ if a & 0x80 != 0 { 0x1b } else { 0 };
+        b >>= 1;
+    }
+    p
+}
+
+/// Multiplicative inverse followed by the affine transform
+const fn sbox() -> [u8; 256] {
+    let mut table = [0u8; 256];
+    let mut x = 0;
+    while x < 256 {
+        // x^254 = x^-1 (and 0 for 0)
+        let mut inv = 1u8;
+        let mut i = 0;
+        while i < 254 {
+            inv = gf_mul(inv, x as u8);
+            i += 1;
+        }
+        let b = if x == 0 { 0 } else { inv };
+        table[x] = b ^ b.rotate_left(1) ^ b.rotate_left(2) ^ b.rotate_left(3) ^ b.rotate_left(4) ^ 0x63;
+        x += 1;
+    }
+    table
+}
+
+/// Apply (Inv)MixColumns coefficients `m` to the column in `word`
+fn mix_column(word: u32, m: [u8; 4]) -> u32 {
+    let b = word.to_le_bytes();
+    let row = |r: usize| (0..4).fold(0, |acc, c| acc ^ gf_mul(b[c], m[(c + 4 - r) % 4]));
+    u32::from_le_bytes([row(0), row(1), row(2), row(3)])
+}
+
+const MIX: [u8; 4] = [2, 3, 1, 1];
+const INV_MIX: [u8; 4] = [14, 11, 13, 9];
+
+/// Both columns of `v` through `mix_column`
+fn mix(v: u64, m: [u8; 4]) -> u64 {
+    mix_column(v as u32, m) as u64 | (mix_column((v >> 32) as u32, m) as u64) << 32
+}
+
+/// Low half of (Inv)ShiftRows then (Inv)SubBytes of the state rs2:rs1
+fn round(rs1: u64, rs2: u64, decrypt: bool) -> u64 {
+    let state = (rs1 as u128 | (rs2 as u128) << 64).to_le_bytes();
+    let mut out = [0u8; 8];
+    for (i, byte) in out.iter_mut().enumerate() {
+        let (row, col) = (i % 4, i / 4);
+        let src_col = if decrypt { (col + 4 - row) % 4 } else { (col + row) % 4 };
+        let b = state[row + 4 * src_col] as usize;
+        *byte = if decrypt { INV_SBOX[b] } else { SBOX[b] };
+    }
+    u64::from_le_bytes(out)
+}
+
+/// Evaluate `env.crypto`
+pub fn crypto(op: i32, rs1: u64, rs2: u64) -> u64 {
+    match op {
+        AES64ES => round(rs1, rs2, false),
+        AES64ESM => mix(round(rs1, rs2, false), MIX),
+        AES64DS => round(rs1, rs2, true),
+        AES64DSM => mix(round(rs1, rs2, true), INV_MIX),
+        AES64IM => mix(rs1, INV_MIX),
+        _ => {
+            // aes64ks1i: SubWord(RotWord(w)) ^ rcon, except round 10
+            const RCON: [u8; 10] = [0x01, 0x02, 0x04, 0x08, 0x10, 0x20, 0x40, 0x80, 0x1b, 0x36];
+            let word = (rs1 >> 32) as u32;
+            let word = if rs2 == 0xa { word } else { word.rotate_right(8) };
+            let sub = u32::from_le_bytes(word.to_le_bytes().map(|b| SBOX[b as usize]));
+            let w = sub ^ RCON.get(rs2 as usize).copied().unwrap_or(0) as u32;
+            (w as u64) << 32 | w as u64
+        }
+    }
+}
+
+/// aes64ks2 (also lowered inline by the translator)
+pub fn aes64ks2(rs1: u64, rs2: u64) -> u64 {
+    let w0 = (rs1 >> 32) ^ (rs2 & 0xffff_ffff);
+    let w1 = w0 ^ (rs2 >> 32);
+    w1 << 32 | w0
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_aes128_fips197_vector() {
+        let key = (0x0706_0504_0302_0100u64, 0x0f0e_0d0c_0b0a_0908u64);
+        let plain = (0x7766_5544_3322_1100u64, 0xffee_ddcc_bbaa_9988u64);
+        let cipher = (0x3004_7b6a_d8e0_c469u64, 0x5ac5_b470_80b7_cdd8u64);
+
+        let mut keys = vec![key];
+        for i in 0..10 {
+            let (k0, k1) = *keys.last().unwrap();
+            let k0 = aes64ks2(crypto(AES64KS1I, k1, i), k0);
+            keys.push((k0, aes64ks2(k0, k1)));
+        }
+
+        let (mut s0, mut s1) = (plain.0 ^ keys[0].0, plain.1 ^ keys[0].1);
+        for (r, k) in keys.iter().enumerate().skip(1) {
+            let op = if r == 10 { AES64ES } else { AES64ESM };
+            (s0, s1) = (crypto(op, s0, s1) ^ k.0, crypto(op, s1, s0) ^ k.1);
+        }
+        assert_eq!((s0, s1), cipher);
+
+        // Equivalent inverse cipher: InvMixColumns on the middle round keys
+        (s0, s1) = (s0 ^ keys[10].0, s1 ^ keys[10].1);
+        for r in (0..10).rev() {
+            let (op, k) = match r {
+                0 => (AES64DS, keys[0]),
+                _ => (AES64DSM, (crypto(AES64IM, keys[r].0, 0), crypto(AES64IM, keys[r].1, 0))),
+            };
+            (s0, s1) = (crypto(op, s0, s1) ^ k.0, crypto(op, s1, s0) ^ k.1);
+        }
+        assert_eq!((s0, s1), plain);
+    }
+}
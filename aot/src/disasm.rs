@@ -4,6 +4,7 @@
 
 use crate::elf::CodeSection;
 use anyhow::Result;
+use thiserror::Error;
 
 /// A decoded RISC-V instruction
 #[derive(Debug, Clone)]
@@ -28,6 +29,7 @@ pub struct Instruction {
 
 /// RISC-V opcodes (RV64GC subset)
 #[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[allow(non_camel_case_types)]
 pub enum Opcode {
     // RV32I Base
     LUI,
@@ -71,6 +73,31 @@ pub enum Opcode {
     ECALL,
     EBREAK,
 
+    // Zicsr: CSR number in `imm`, the I forms' 5-bit immediate in `rs1`
+    CSRRW,
+    CSRRS,
+    CSRRC,
+    CSRRWI,
+    CSRRSI,
+    CSRRCI,
+
+    // Zicbom / Zicboz: cache block operations on the block holding x[rs1]
+    CBO_INVAL,
+    CBO_CLEAN,
+    CBO_FLUSH,
+    CBO_ZERO,
+
+    // Zihintpause / Zawrs: spin-wait hints
+    PAUSE,
+    WRS_NTO,
+    WRS_STO,
+
+    // Privileged: illegal in user mode
+    MRET,
+    SRET,
+    WFI,
+    SFENCE_VMA,
+
     // RV64I
     LWU,
     LD,
@@ -128,6 +155,54 @@ pub enum Opcode {
     AMOMINU_D,
     AMOMAXU_D,
 
+    // Zacas: compare-and-swap, expected value in rd. The Q form works on
+    // the register pairs rd/rd+1 and rs2/rs2+1 (both even).
+    AMOCAS_W,
+    AMOCAS_D,
+    AMOCAS_Q,
+
+    // Zabha: byte and halfword AMOs (old value sign-extended into rd)
+    AMOSWAP_B,
+    AMOADD_B,
+    AMOXOR_B,
+    AMOAND_B,
+    AMOOR_B,
+    AMOMIN_B,
+    AMOMAX_B,
+    AMOMINU_B,
+    AMOMAXU_B,
+    AMOCAS_B,
+    AMOSWAP_H,
+    AMOADD_H,
+    AMOXOR_H,
+    AMOAND_H,
+    AMOOR_H,
+    AMOMIN_H,
+    AMOMAX_H,
+    AMOMINU_H,
+    AMOMAXU_H,
+    AMOCAS_H,
+
+    // Zkne/Zknd (RV64): AES rounds on the state rs2:rs1; aes64ks1i's round
+    // number is `imm`
+    AES64ES,
+    AES64ESM,
+    AES64DS,
+    AES64DSM,
+    AES64IM,
+    AES64KS1I,
+    AES64KS2,
+
+    // Zknh: SHA-2 sigma/sum functions of rs1
+    SHA256SIG0,
+    SHA256SIG1,
+    SHA256SUM0,
+    SHA256SUM1,
+    SHA512SIG0,
+    SHA512SIG1,
+    SHA512SUM0,
+    SHA512SUM1,
+
     // RV32F/RV32D (Floating point - stubs)
     FLW,
     FSW,
@@ -230,6 +305,10 @@ pub enum Opcode {
     C_SD,
     C_LDSP,
     C_SDSP,
+    C_FLD,
+    C_FSD,
+    C_FLDSP,
+    C_FSDSP,
     C_ADDIW,
     C_SUBW,
     C_ADDW,
@@ -272,13 +351,153 @@ impl Opcode {
         matches!(self, Opcode::ECALL)
     }
 
+    /// Is this a CSR access?
+    pub fn is_csr(&self) -> bool {
+        matches!(
+            self,
+            Opcode::CSRRW | Opcode::CSRRS | Opcode::CSRRC | Opcode::CSRRWI | Opcode::CSRRSI | Opcode::CSRRCI
+        )
+    }
+
+    /// Access size of an A-extension instruction (LR/SC/AMO), which must be
+    /// naturally aligned
+    pub fn atomic_size(&self) -> Option<u64> {
+        use Opcode::*;
+        match self {
+            LR_W | SC_W | AMOSWAP_W | AMOADD_W | AMOXOR_W | AMOAND_W | AMOOR_W | AMOMIN_W | AMOMAX_W
+            | AMOMINU_W | AMOMAXU_W | AMOCAS_W => Some(4),
+            LR_D | SC_D | AMOSWAP_D | AMOADD_D | AMOXOR_D | AMOAND_D | AMOOR_D | AMOMIN_D | AMOMAX_D
+            | AMOMINU_D | AMOMAXU_D | AMOCAS_D => Some(8),
+            AMOCAS_Q => Some(16),
+            _ => self.narrow_amo().map(|(_, width)| width),
+        }
+    }
+
+    /// For a Zabha AMO: the word form of the operation and the access width
+    pub fn narrow_amo(&self) -> Option<(Opcode, u64)> {
+        use Opcode::*;
+        Some(match self {
+            AMOSWAP_B => (AMOSWAP_W, 1),
+            AMOADD_B => (AMOADD_W, 1),
+            AMOXOR_B => (AMOXOR_W, 1),
+            AMOAND_B => (AMOAND_W, 1),
+            AMOOR_B => (AMOOR_W, 1),
+            AMOMIN_B => (AMOMIN_W, 1),
+            AMOMAX_B => (AMOMAX_W, 1),
+            AMOMINU_B => (AMOMINU_W, 1),
+            AMOMAXU_B => (AMOMAXU_W, 1),
+            AMOCAS_B => (AMOCAS_W, 1),
+            AMOSWAP_H => (AMOSWAP_W, 2),
+            AMOADD_H => (AMOADD_W, 2),
+            AMOXOR_H => (AMOXOR_W, 2),
+            AMOAND_H => (AMOAND_W, 2),
+            AMOOR_H => (AMOOR_W, 2),
+            AMOMIN_H => (AMOMIN_W, 2),
+            AMOMAX_H => (AMOMAX_W, 2),
+            AMOMINU_H => (AMOMINU_W, 2),
+            AMOMAXU_H => (AMOMAXU_W, 2),
+            AMOCAS_H => (AMOCAS_W, 2),
+            _ => return None,
+        })
+    }
+
+    /// Is this a supervisor/machine-mode instruction?
+    pub fn is_privileged(&self) -> bool {
+        matches!(self, Opcode::MRET | Opcode::SRET | Opcode::WFI | Opcode::SFENCE_VMA)
+    }
+
+    /// Is this a 16-bit (C extension) instruction?
+    pub fn is_compressed(&self) -> bool {
+        matches!(
+            self,
+            Opcode::C_ADDI4SPN
+                | Opcode::C_LW
+                | Opcode::C_SW
+                | Opcode::C_NOP
+                | Opcode::C_ADDI
+                | Opcode::C_JAL
+                | Opcode::C_LI
+                | Opcode::C_ADDI16SP
+                | Opcode::C_LUI
+                | Opcode::C_SRLI
+                | Opcode::C_SRAI
+                | Opcode::C_ANDI
+                | Opcode::C_SUB
+                | Opcode::C_XOR
+                | Opcode::C_OR
+                | Opcode::C_AND
+                | Opcode::C_J
+                | Opcode::C_BEQZ
+                | Opcode::C_BNEZ
+                | Opcode::C_SLLI
+                | Opcode::C_LWSP
+                | Opcode::C_JR
+                | Opcode::C_MV
+                | Opcode::C_EBREAK
+                | Opcode::C_JALR
+                | Opcode::C_ADD
+                | Opcode::C_SWSP
+                | Opcode::C_LD
+                | Opcode::C_SD
+                | Opcode::C_LDSP
+                | Opcode::C_SDSP
+                | Opcode::C_FLD
+                | Opcode::C_FSD
+                | Opcode::C_FLDSP
+                | Opcode::C_FSDSP
+                | Opcode::C_ADDIW
+                | Opcode::C_SUBW
+                | Opcode::C_ADDW
+        )
+    }
+
     /// Is this a terminator (ends basic block)?
     pub fn is_terminator(&self) -> bool {
         self.is_branch() || self.is_jump() || self.is_syscall() || *self == Opcode::EBREAK
     }
 }
 
+/// Why a single instruction failed to decode
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Error)]
+pub enum DecodeError {
+    /// decode32 was given a 16-bit encoding (low bits != 0b11)
+    #[error("0x{bits:08x} is a compressed encoding")]
+    Compressed { bits: u32 },
+    /// decode16 was given the low half of a 32-bit encoding
+    #[error("0x{bits:04x} is not a compressed encoding")]
+    NotCompressed { bits: u16 },
+    /// The all-zero parcel, defined to be illegal
+    #[error("illegal instruction 0x{bits:x}")]
+    Illegal { bits: u32 },
+    /// Major opcode not implemented (or a 48/64-bit encoding)
+    #[error("unknown major opcode 0x{opcode:02x} in 0x{bits:08x}")]
+    UnknownOpcode { bits: u32, opcode: u32 },
+    /// Major opcode known, but not this funct3/funct7/rs2 combination
+    #[error("unknown function in 0x{bits:08x} (major opcode 0x{opcode:02x})")]
+    UnknownFunction { bits: u32, opcode: u32 },
+    /// Compressed quadrant/funct3 combination not implemented
+    #[error("unknown compressed instruction 0x{bits:04x} (quadrant {quadrant}, funct3 {funct3})")]
+    UnknownCompressed { bits: u16, quadrant: u32, funct3: u32 },
+}
+
+impl Instruction {
+    /// Placeholder for bytes that failed to decode
+    fn unknown(addr: u64, bytes: u32, len: u8) -> Self {
+        Instruction { addr, bytes, len, opcode: Opcode::Unknown, rd: None, rs1: None, rs2: None, imm: None }
+    }
+
+    /// Whether this raises an illegal-instruction exception in user mode:
+    /// privileged instructions and accesses to CSRs above user level
+    /// (CSR bits 9:8 give the lowest privilege allowed)
+    pub fn is_illegal_in_user_mode(&self) -> bool {
+        self.opcode.is_privileged() || (self.opcode.is_csr() && (self.imm.unwrap_or(0) >> 8) & 0x3 != 0)
+    }
+}
+
 /// Disassemble a code section into instructions
+///
+/// Undecodable instructions become `Opcode::Unknown`; use `decode32` /
+/// `decode16` directly to get the reason.
 pub fn disassemble(section: &CodeSection) -> Result<Vec<Instruction>> {
     let mut instructions = Vec::new();
     let mut offset = 0;
@@ -298,7 +517,7 @@ pub fn disassemble(section: &CodeSection) -> Result<Vec<Instruction>> {
             let bytes =
                 (section.data[offset] as u32) | ((section.data[offset + 1] as u32) << 8);
 
-            let inst = decode_compressed(addr, bytes);
+            let inst = decode16(addr, bytes as u16).unwrap_or_else(|_| Instruction::unknown(addr, bytes, 2));
             instructions.push(inst);
             offset += 2;
         } else {
@@ -311,7 +530,7 @@ pub fn disassemble(section: &CodeSection) -> Result<Vec<Instruction>> {
                 | ((section.data[offset + 2] as u32) << 16)
                 | ((section.data[offset + 3] as u32) << 24);
 
-            let inst = decode_32bit(addr, bytes);
+            let inst = decode32(addr, bytes).unwrap_or_else(|_| Instruction::unknown(addr, bytes, 4));
             instructions.push(inst);
             offset += 4;
         }
@@ -320,8 +539,11 @@ pub fn disassemble(section: &CodeSection) -> Result<Vec<Instruction>> {
     Ok(instructions)
 }
 
-/// Decode a 32-bit RISC-V instruction
-fn decode_32bit(addr: u64, bytes: u32) -> Instruction {
+/// Decode a 32-bit RISC-V instruction at `addr`
+pub fn decode32(addr: u64, bytes: u32) -> Result<Instruction, DecodeError> {
+    if bytes & 0x3 != 0x3 {
+        return Err(DecodeError::Compressed { bits: bytes });
+    }
     let opcode_bits = bytes & 0x7f;
     let rd = ((bytes >> 7) & 0x1f) as u8;
     let funct3 = (bytes >> 12) & 0x7;
@@ -397,12 +619,27 @@ fn decode_32bit(addr: u64, bytes: u32) -> Instruction {
             let imm = (bytes as i32 >> 20) as i64;
             let op = match funct3 {
                 0 => Opcode::ADDI,
-                1 => Opcode::SLLI,
+                1 if funct7 >> 1 == 0 => Opcode::SLLI,
+                // Zkn unary forms: the operation is in imm[11:0]
+                1 => match imm & 0xfff {
+                    0x100 => Opcode::SHA256SUM0,
+                    0x101 => Opcode::SHA256SUM1,
+                    0x102 => Opcode::SHA256SIG0,
+                    0x103 => Opcode::SHA256SIG1,
+                    0x104 => Opcode::SHA512SUM0,
+                    0x105 => Opcode::SHA512SUM1,
+                    0x106 => Opcode::SHA512SIG0,
+                    0x107 => Opcode::SHA512SIG1,
+                    0x300 => Opcode::AES64IM,
+                    0x310..=0x31a => Opcode::AES64KS1I,
+                    _ => Opcode::Unknown,
+                },
                 2 => Opcode::SLTI,
                 3 => Opcode::SLTIU,
                 4 => Opcode::XORI,
                 5 => {
-                    if funct7 == 0x20 {
+                    // RV64: funct6, shamt[5] lives in bit 25
+                    if funct7 >> 1 == 0x10 {
                         Opcode::SRAI
                     } else {
                         Opcode::SRLI
@@ -412,6 +649,7 @@ fn decode_32bit(addr: u64, bytes: u32) -> Instruction {
                 7 => Opcode::ANDI,
                 _ => Opcode::Unknown,
             };
+            let imm = if op == Opcode::AES64KS1I { imm & 0xf } else { imm };
             (op, Some(imm))
         }
         0x1b => {
@@ -444,6 +682,11 @@ fn decode_32bit(addr: u64, bytes: u32) -> Instruction {
                 (0x20, 5) => Opcode::SRA,
                 (0x00, 6) => Opcode::OR,
                 (0x00, 7) => Opcode::AND,
+                (0x19, 0) => Opcode::AES64ES,
+                (0x1b, 0) => Opcode::AES64ESM,
+                (0x1d, 0) => Opcode::AES64DS,
+                (0x1f, 0) => Opcode::AES64DSM,
+                (0x3f, 0) => Opcode::AES64KS2,
                 // M extension
                 (0x01, 0) => Opcode::MUL,
                 (0x01, 1) => Opcode::MULH,
@@ -475,20 +718,46 @@ fn decode_32bit(addr: u64, bytes: u32) -> Instruction {
             };
             (op, None)
         }
+        0x0f if funct3 == 2 => {
+            // CBO.*: the operation is in imm[11:0], rd = 0
+            let op = match (bytes >> 20, rd) {
+                (0, 0) => Opcode::CBO_INVAL,
+                (1, 0) => Opcode::CBO_CLEAN,
+                (2, 0) => Opcode::CBO_FLUSH,
+                (4, 0) => Opcode::CBO_ZERO,
+                _ => Opcode::Unknown,
+            };
+            (op, None)
+        }
+        0x0f if bytes == 0x0100_000f => {
+            // PAUSE: FENCE with pred = W, succ = 0
+            (Opcode::PAUSE, None)
+        }
         0x0f => {
             // FENCE
             (Opcode::FENCE, None)
         }
         0x73 => {
             // SYSTEM
-            let op = if bytes == 0x00000073 {
-                Opcode::ECALL
-            } else if bytes == 0x00100073 {
-                Opcode::EBREAK
-            } else {
-                Opcode::Unknown
+            let op = match (funct3, bytes) {
+                (0, 0x0000_0073) => Opcode::ECALL,
+                (0, 0x0010_0073) => Opcode::EBREAK,
+                (0, 0x1020_0073) => Opcode::SRET,
+                (0, 0x3020_0073) => Opcode::MRET,
+                (0, 0x1050_0073) => Opcode::WFI,
+                (0, 0x00d0_0073) => Opcode::WRS_NTO,
+                (0, 0x01d0_0073) => Opcode::WRS_STO,
+                (0, _) if funct7 == 0x09 && rd == 0 => Opcode::SFENCE_VMA,
+                (1, _) => Opcode::CSRRW,
+                (2, _) => Opcode::CSRRS,
+                (3, _) => Opcode::CSRRC,
+                (5, _) => Opcode::CSRRWI,
+                (6, _) => Opcode::CSRRSI,
+                (7, _) => Opcode::CSRRCI,
+                _ => Opcode::Unknown,
             };
-            (op, None)
+            // CSR number, zero-extended
+            (op, op.is_csr().then_some((bytes >> 20) as i64))
         }
         0x2f => {
             // AMO
@@ -516,6 +785,30 @@ fn decode_32bit(addr: u64, bytes: u32) -> Instruction {
                 (3, 0x14) => Opcode::AMOMAX_D,
                 (3, 0x18) => Opcode::AMOMINU_D,
                 (3, 0x1c) => Opcode::AMOMAXU_D,
+                (2, 0x05) => Opcode::AMOCAS_W,
+                (3, 0x05) => Opcode::AMOCAS_D,
+                // Odd register pairs are reserved
+                (4, 0x05) if (rd | rs2) & 1 == 0 => Opcode::AMOCAS_Q,
+                (0, 0x01) => Opcode::AMOSWAP_B,
+                (0, 0x00) => Opcode::AMOADD_B,
+                (0, 0x04) => Opcode::AMOXOR_B,
+                (0, 0x0c) => Opcode::AMOAND_B,
+                (0, 0x08) => Opcode::AMOOR_B,
+                (0, 0x10) => Opcode::AMOMIN_B,
+                (0, 0x14) => Opcode::AMOMAX_B,
+                (0, 0x18) => Opcode::AMOMINU_B,
+                (0, 0x1c) => Opcode::AMOMAXU_B,
+                (0, 0x05) => Opcode::AMOCAS_B,
+                (1, 0x01) => Opcode::AMOSWAP_H,
+                (1, 0x00) => Opcode::AMOADD_H,
+                (1, 0x04) => Opcode::AMOXOR_H,
+                (1, 0x0c) => Opcode::AMOAND_H,
+                (1, 0x08) => Opcode::AMOOR_H,
+                (1, 0x10) => Opcode::AMOMIN_H,
+                (1, 0x14) => Opcode::AMOMAX_H,
+                (1, 0x18) => Opcode::AMOMINU_H,
+                (1, 0x1c) => Opcode::AMOMAXU_H,
+                (1, 0x05) => Opcode::AMOCAS_H,
                 _ => Opcode::Unknown,
             };
             (op, None)
@@ -673,10 +966,13 @@ fn decode_32bit(addr: u64, bytes: u32) -> Instruction {
             };
             (op, None)
         }
-        _ => (Opcode::Unknown, None),
+        _ => return Err(DecodeError::UnknownOpcode { bits: bytes, opcode: opcode_bits }),
     };
+    if opcode == Opcode::Unknown {
+        return Err(DecodeError::UnknownFunction { bits: bytes, opcode: opcode_bits });
+    }
 
-    Instruction {
+    Ok(Instruction {
         addr,
         bytes,
         len: 4,
@@ -685,13 +981,21 @@ fn decode_32bit(addr: u64, bytes: u32) -> Instruction {
         rs1: Some(rs1),
         rs2: Some(rs2),
         imm,
-    }
+    })
 }
 
-/// Decode a 16-bit compressed instruction
-fn decode_compressed(addr: u64, bytes: u32) -> Instruction {
+/// Decode a 16-bit compressed instruction at `addr`
+pub fn decode16(addr: u64, bits: u16) -> Result<Instruction, DecodeError> {
+    let bytes = bits as u32;
     let quadrant = bytes & 0x3;
     let funct3 = (bytes >> 13) & 0x7;
+    if quadrant == 3 {
+        return Err(DecodeError::NotCompressed { bits });
+    }
+    if bytes == 0 {
+        return Err(DecodeError::Illegal { bits: bytes });
+    }
+    let unknown = DecodeError::UnknownCompressed { bits, quadrant, funct3 };
 
     // Simplified compressed decoding - expand to full form
     let (opcode, rd, rs1, rs2, imm) = match (quadrant, funct3) {
@@ -701,6 +1005,13 @@ fn decode_compressed(addr: u64, bytes: u32) -> Instruction {
             let imm = decode_ciw_imm(bytes);
             (Opcode::C_ADDI4SPN, Some(rd), Some(2), None, Some(imm))
         }
+        (0, 1) => {
+            // C.FLD
+            let rd = ((bytes >> 2) & 0x7) as u8 + 8;
+            let rs1 = ((bytes >> 7) & 0x7) as u8 + 8;
+            let imm = decode_cl_imm_d(bytes);
+            (Opcode::C_FLD, Some(rd), Some(rs1), None, Some(imm))
+        }
         (0, 2) => {
             // C.LW
             let rd = ((bytes >> 2) & 0x7) as u8 + 8;
@@ -715,6 +1026,13 @@ fn decode_compressed(addr: u64, bytes: u32) -> Instruction {
             let imm = decode_cl_imm_d(bytes);
             (Opcode::C_LD, Some(rd), Some(rs1), None, Some(imm))
         }
+        (0, 5) => {
+            // C.FSD
+            let rs2 = ((bytes >> 2) & 0x7) as u8 + 8;
+            let rs1 = ((bytes >> 7) & 0x7) as u8 + 8;
+            let imm = decode_cl_imm_d(bytes);
+            (Opcode::C_FSD, None, Some(rs1), Some(rs2), Some(imm))
+        }
         (0, 6) => {
             // C.SW
             let rs2 = ((bytes >> 2) & 0x7) as u8 + 8;
@@ -795,7 +1113,7 @@ fn decode_compressed(addr: u64, bytes: u32) -> Instruction {
                     };
                     (op, Some(rd), Some(rd), Some(rs2), None)
                 }
-                _ => (Opcode::Unknown, None, None, None, None),
+                _ => return Err(unknown),
             }
         }
         (1, 5) => {
@@ -821,6 +1139,12 @@ fn decode_compressed(addr: u64, bytes: u32) -> Instruction {
             let imm = decode_ci_shamt(bytes);
             (Opcode::C_SLLI, Some(rd), Some(rd), None, Some(imm))
         }
+        (2, 1) => {
+            // C.FLDSP
+            let rd = ((bytes >> 7) & 0x1f) as u8;
+            let imm = decode_ci_ldsp_imm(bytes);
+            (Opcode::C_FLDSP, Some(rd), Some(2), None, Some(imm))
+        }
         (2, 2) => {
             // C.LWSP
             let rd = ((bytes >> 7) & 0x1f) as u8;
@@ -859,6 +1183,12 @@ fn decode_compressed(addr: u64, bytes: u32) -> Instruction {
                 (Opcode::C_ADD, Some(rs1), Some(rs1), Some(rs2), None)
             }
         }
+        (2, 5) => {
+            // C.FSDSP
+            let rs2 = ((bytes >> 2) & 0x1f) as u8;
+            let imm = decode_css_imm_d(bytes);
+            (Opcode::C_FSDSP, None, Some(2), Some(rs2), Some(imm))
+        }
         (2, 6) => {
             // C.SWSP
             let rs2 = ((bytes >> 2) & 0x1f) as u8;
@@ -871,10 +1201,13 @@ fn decode_compressed(addr: u64, bytes: u32) -> Instruction {
             let imm = decode_css_imm_d(bytes);
             (Opcode::C_SDSP, None, Some(2), Some(rs2), Some(imm))
         }
-        _ => (Opcode::Unknown, None, None, None, None),
+        _ => return Err(unknown),
     };
+    if opcode == Opcode::Unknown {
+        return Err(unknown);
+    }
 
-    Instruction {
+    Ok(Instruction {
         addr,
         bytes,
         len: 2,
@@ -883,7 +1216,7 @@ fn decode_compressed(addr: u64, bytes: u32) -> Instruction {
         rs1,
         rs2,
         imm,
-    }
+    })
 }
 
 // Immediate decoders
@@ -1031,3 +1364,48 @@ fn decode_addi16sp_imm(inst: u32) -> i64 {
     // Sign extend from 10 bits
     ((imm as i32) << 22 >> 22) as i64
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_decode_reports_reason() {
+        // addi a0, a0, 1 / c.addi a0, 1
+        assert_eq!(decode32(0x1000, 0x0015_0513).unwrap().opcode, Opcode::ADDI);
+        assert_eq!(decode16(0x1000, 0x0505).unwrap().opcode, Opcode::C_ADDI);
+
+        assert_eq!(decode32(0, 0x0505).unwrap_err(), DecodeError::Compressed { bits: 0x0505 });
+        assert!(matches!(decode16(0, 0x0513), Err(DecodeError::NotCompressed { .. })));
+        assert!(matches!(decode16(0, 0), Err(DecodeError::Illegal { .. })));
+        // uret (SYSTEM, not in RV64 privileged spec) vs. a reserved major opcode
+        assert!(matches!(decode32(0, 0x0020_0073), Err(DecodeError::UnknownFunction { opcode: 0x73, .. })));
+        assert!(matches!(decode32(0, 0x0000_007f), Err(DecodeError::UnknownOpcode { opcode: 0x7f, .. })));
+
+        // csrrw sp, mscratch, sp / frflags a0 / mret
+        let csrrw = decode32(0, 0x3401_1173).unwrap();
+        assert_eq!((csrrw.opcode, csrrw.imm), (Opcode::CSRRW, Some(0x340)));
+        assert!(csrrw.is_illegal_in_user_mode());
+        assert!(!decode32(0, 0x0010_2573).unwrap().is_illegal_in_user_mode());
+        assert!(decode32(0, 0x3020_0073).unwrap().is_illegal_in_user_mode());
+
+        let section = CodeSection { vaddr: 0, data: vec![0, 0, 0x13, 0x05, 0x15, 0x00], name: String::new() };
+        let insts = disassemble(&section).unwrap();
+        assert_eq!(insts[0].opcode, Opcode::Unknown);
+        assert_eq!(insts[1].opcode, Opcode::ADDI);
+    }
+
+    #[test]
+    fn test_decode_compressed_fp() {
+        // c.fsd fs0, 104(a0) / c.fld fs1, 112(a0) / c.fsdsp fs2, 8(sp) / c.fldsp fs2, 8(sp),
+        // as musl's __setjmp and __longjmp save and restore them
+        let fields = |bits| {
+            let i = decode16(0, bits).unwrap();
+            (i.opcode, i.rd, i.rs1, i.rs2, i.imm)
+        };
+        assert_eq!(fields(0xb520), (Opcode::C_FSD, None, Some(10), Some(8), Some(104)));
+        assert_eq!(fields(0x3924), (Opcode::C_FLD, Some(9), Some(10), None, Some(112)));
+        assert_eq!(fields(0xa44a), (Opcode::C_FSDSP, None, Some(2), Some(18), Some(8)));
+        assert_eq!(fields(0x2922), (Opcode::C_FLDSP, Some(18), Some(2), None, Some(8)));
+    }
+}
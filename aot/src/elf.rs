@@ -1,6 +1,12 @@
 // elf.rs - ELF binary parsing for RISC-V executables
 //
-// Uses goblin for parsing, extracts code sections and metadata.
+// Uses goblin for parsing. `ElfFile` parses once and exposes the metadata,
+// code sections, symbols and relocations from that single parse.
+//
+// A PIE can be given a load bias (`ElfFile::with_load_bias`): every address
+// the views report is then the link-time one plus the bias, so the CFG,
+// AUIPC results, branch targets and the block map all use the addresses
+// the binary will run at.
 
 use anyhow::{Context, Result};
 use goblin::elf::{Elf, program_header};
@@ -34,109 +40,316 @@ pub struct CodeSection {
     pub name: String,
 }
 
-/// Parse ELF and extract metadata
-pub fn parse(data: &[u8]) -> Result<ElfInfo> {
-    let elf = Elf::parse(data).context("Invalid ELF format")?;
-
-    // Verify RISC-V architecture
-    if elf.header.e_machine != goblin::elf::header::EM_RISCV {
-        anyhow::bail!(
-            "Not a RISC-V binary (e_machine=0x{:x})",
-            elf.header.e_machine
-        );
-    }
-
-    // Check 64-bit
-    if !elf.is_64 {
-        anyhow::bail!("Only 64-bit RISC-V (RV64) is supported");
-    }
-
-    // Is it PIE?
-    let is_pie = elf.header.e_type == goblin::elf::header::ET_DYN;
-
-    // Get interpreter (for dynamic binaries)
-    let interpreter = elf.interpreter.map(|s| s.to_string());
-
-    // Extract segments
-    let segments = elf
-        .program_headers
-        .iter()
-        .filter(|ph| ph.p_type == program_header::PT_LOAD)
-        .map(|ph| Segment {
-            vaddr: ph.p_vaddr,
-            memsz: ph.p_memsz,
-            filesz: ph.p_filesz,
-            offset: ph.p_offset,
-            flags: ph.p_flags,
-        })
-        .collect();
-
-    // Find program headers virtual address
-    let phdr_vaddr = elf
-        .program_headers
-        .iter()
-        .find(|ph| ph.p_type == program_header::PT_PHDR)
-        .map(|ph| ph.p_vaddr)
-        .unwrap_or(0);
-
-    Ok(ElfInfo {
-        entry: elf.entry,
-        is_pie,
-        interpreter,
-        segments,
-        phdr_vaddr,
-        phdr_count: elf.header.e_phnum,
-    })
+/// A function symbol from .symtab or .dynsym
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Symbol {
+    pub name: String,
+    pub addr: u64,
+    pub size: u64,
 }
 
-/// Extract executable code sections from ELF
-pub fn extract_code_sections(data: &[u8], info: &ElfInfo) -> Result<Vec<CodeSection>> {
-    let elf = Elf::parse(data).context("Invalid ELF format")?;
-    let mut sections = Vec::new();
-
-    // Find executable segments
-    for seg in &info.segments {
-        // PF_X = 0x1 (executable)
-        if seg.flags & 0x1 != 0 && seg.filesz > 0 {
-            let start = seg.offset as usize;
-            let end = start + seg.filesz as usize;
-
-            if end <= data.len() {
-                sections.push(CodeSection {
-                    vaddr: seg.vaddr,
-                    data: data[start..end].to_vec(),
-                    name: format!("seg_0x{:x}", seg.vaddr),
-                });
-            }
+/// A dynamic relocation (.rela.dyn / .rela.plt)
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Relocation {
+    /// Address being patched (biased like the segments)
+    pub offset: u64,
+    /// R_RISCV_* type
+    pub r_type: u32,
+    /// Referenced symbol name, if any
+    pub symbol: Option<String>,
+    /// Address of that symbol (biased) when this binary defines it
+    pub symbol_value: Option<u64>,
+    pub addend: i64,
+}
+
+/// A parsed RISC-V ELF. The file is parsed once; every view is derived
+/// from the same goblin `Elf`.
+pub struct ElfFile<'a> {
+    data: &'a [u8],
+    elf: Elf<'a>,
+    info: ElfInfo,
+    bias: u64,
+}
+
+impl<'a> ElfFile<'a> {
+    /// Parse and validate an RV64 ELF
+    pub fn parse(data: &'a [u8]) -> Result<Self> {
+        let elf = Elf::parse(data).context("Invalid ELF format")?;
+
+        // Verify RISC-V architecture
+        if elf.header.e_machine != goblin::elf::header::EM_RISCV {
+            anyhow::bail!(
+                "Not a RISC-V binary (e_machine=0x{:x})",
+                elf.header.e_machine
+            );
         }
+
+        // Check 64-bit
+        if !elf.is_64 {
+            anyhow::bail!("Only 64-bit RISC-V (RV64) is supported");
+        }
+
+        // Is it PIE?
+        let is_pie = elf.header.e_type == goblin::elf::header::ET_DYN;
+
+        // Get interpreter (for dynamic binaries)
+        let interpreter = elf.interpreter.map(|s| s.to_string());
+
+        // Extract segments
+        let segments = elf
+            .program_headers
+            .iter()
+            .filter(|ph| ph.p_type == program_header::PT_LOAD)
+            .map(|ph| Segment {
+                vaddr: ph.p_vaddr,
+                memsz: ph.p_memsz,
+                filesz: ph.p_filesz,
+                offset: ph.p_offset,
+                flags: ph.p_flags,
+            })
+            .collect();
+
+        // Find program headers virtual address
+        let phdr_vaddr = elf
+            .program_headers
+            .iter()
+            .find(|ph| ph.p_type == program_header::PT_PHDR)
+            .map(|ph| ph.p_vaddr)
+            .unwrap_or(0);
+
+        let info = ElfInfo {
+            entry: elf.entry,
+            is_pie,
+            interpreter,
+            segments,
+            phdr_vaddr,
+            phdr_count: elf.header.e_phnum,
+        };
+        Ok(ElfFile { data, elf, info, bias: 0 })
     }
 
-    // Also check section headers for .text
-    for section in &elf.section_headers {
-        if let Some(name) = elf.shdr_strtab.get_at(section.sh_name) {
-            if name == ".text" {
-                let start = section.sh_offset as usize;
-                let end = start + section.sh_size as usize;
+    /// Relocate a PIE by `bias` (page-aligned). Zero is a no-op for any ELF.
+    pub fn with_load_bias(mut self, bias: u64) -> Result<Self> {
+        if bias == 0 {
+            return Ok(self);
+        }
+        if !self.info.is_pie {
+            anyhow::bail!("Load bias 0x{:x} given for a non-PIE executable", bias);
+        }
+        if !bias.is_multiple_of(0x1000) {
+            anyhow::bail!("Load bias 0x{:x} is not page-aligned", bias);
+        }
+        let info = &mut self.info;
+        info.entry += bias;
+        info.segments.iter_mut().for_each(|seg| seg.vaddr += bias);
+        if info.phdr_vaddr != 0 {
+            info.phdr_vaddr += bias;
+        }
+        self.bias = bias;
+        Ok(self)
+    }
+
+    /// The load bias added to every link-time address
+    pub fn load_bias(&self) -> u64 {
+        self.bias
+    }
+
+    pub fn info(&self) -> &ElfInfo {
+        &self.info
+    }
+
+    pub fn into_info(self) -> ElfInfo {
+        self.info
+    }
+
+    /// The raw file contents
+    pub fn data(&self) -> &'a [u8] {
+        self.data
+    }
+
+    /// The underlying goblin view, for callers that need raw headers
+    pub fn elf(&self) -> &Elf<'a> {
+        &self.elf
+    }
+
+    /// Executable code: every PF_X segment, plus .text if it lies elsewhere
+    pub fn code_sections(&self) -> Vec<CodeSection> {
+        let data = self.data;
+        let mut sections = Vec::new();
+
+        // Find executable segments
+        for seg in &self.info.segments {
+            // PF_X = 0x1 (executable)
+            if seg.flags & 0x1 != 0 && seg.filesz > 0 {
+                let start = seg.offset as usize;
+                let end = start + seg.filesz as usize;
 
                 if end <= data.len() {
-                    // Avoid duplicates
-                    let already_have = sections
-                        .iter()
-                        .any(|s| s.vaddr == section.sh_addr);
-
-                    if !already_have {
-                        sections.push(CodeSection {
-                            vaddr: section.sh_addr,
-                            data: data[start..end].to_vec(),
-                            name: name.to_string(),
-                        });
+                    sections.push(CodeSection {
+                        vaddr: seg.vaddr,
+                        data: data[start..end].to_vec(),
+                        name: format!("seg_0x{:x}", seg.vaddr),
+                    });
+                }
+            }
+        }
+
+        // Also check section headers for .text
+        for section in &self.elf.section_headers {
+            if let Some(name) = self.elf.shdr_strtab.get_at(section.sh_name) {
+                if name == ".text" {
+                    let start = section.sh_offset as usize;
+                    let end = start + section.sh_size as usize;
+
+                    if end <= data.len() {
+                        // Avoid duplicates
+                        let vaddr = section.sh_addr + self.bias;
+                        let already_have = sections
+                            .iter()
+                            .any(|s| s.vaddr == vaddr);
+
+                        if !already_have {
+                            sections.push(CodeSection {
+                                vaddr,
+                                data: data[start..end].to_vec(),
+                                name: name.to_string(),
+                            });
+                        }
                     }
                 }
             }
         }
+
+        sections
+    }
+
+    /// Function symbols, sorted by (biased) address
+    pub fn symbols(&self) -> Vec<Symbol> {
+        let elf = &self.elf;
+        let mut symbols = Vec::new();
+
+        let tables = [(&elf.syms, &elf.strtab), (&elf.dynsyms, &elf.dynstrtab)];
+        for (syms, strtab) in tables {
+            for sym in syms.iter() {
+                if sym.st_type() != goblin::elf::sym::STT_FUNC || sym.st_value == 0 {
+                    continue;
+                }
+                if let Some(name) = strtab.get_at(sym.st_name).filter(|n| !n.is_empty()) {
+                    symbols.push(Symbol {
+                        name: name.to_string(),
+                        addr: sym.st_value + self.bias,
+                        size: sym.st_size,
+                    });
+                }
+            }
+        }
+
+        // .dynsym repeats exported .symtab entries
+        symbols.sort_by(|a, b| (a.addr, &a.name).cmp(&(b.addr, &b.name)));
+        symbols.dedup_by(|a, b| a.addr == b.addr);
+        symbols
     }
 
-    Ok(sections)
+    /// The initial contents of `len` bytes at (biased) address `addr`, if a
+    /// loadable segment backs them from the file
+    pub fn read(&self, addr: u64, len: u64) -> Option<&'a [u8]> {
+        let seg = self.info.segments.iter().find(|seg| {
+            addr >= seg.vaddr && addr.checked_add(len).is_some_and(|end| end <= seg.vaddr + seg.filesz)
+        })?;
+        let start = (seg.offset + addr - seg.vaddr) as usize;
+        self.data.get(start..start + len as usize)
+    }
+
+    /// Dynamic relocations (.rela.dyn followed by .rela.plt)
+    pub fn relocations(&self) -> Vec<Relocation> {
+        let elf = &self.elf;
+        elf.dynrelas
+            .iter()
+            .chain(elf.pltrelocs.iter())
+            .map(|r| {
+                let sym = elf.dynsyms.get(r.r_sym);
+                Relocation {
+                    offset: r.r_offset + self.bias,
+                    r_type: r.r_type,
+                    symbol: sym
+                        .as_ref()
+                        .and_then(|sym| elf.dynstrtab.get_at(sym.st_name))
+                        .filter(|n| !n.is_empty())
+                        .map(str::to_string),
+                    symbol_value: sym
+                        .filter(|sym| sym.st_shndx != goblin::elf::section_header::SHN_UNDEF as usize && sym.st_value != 0)
+                        .map(|sym| sym.st_value + self.bias),
+                    addend: r.r_addend.unwrap_or(0),
+                }
+            })
+            .collect()
+    }
+}
+
+/// Bytes to write over a binary's loaded image at a guest address
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct BytePatch {
+    pub addr: u64,
+    pub bytes: Vec<u8>,
+}
+
+/// A copy of the ELF `data` with `patches` applied to its image as loaded
+/// at `load_bias`, so an instruction can be nopped out or a call redirected
+/// before disassembly without touching the original file. Each patch must
+/// lie within the file-backed part of one PT_LOAD segment.
+pub fn patch_image(data: &[u8], load_bias: u64, patches: &[BytePatch]) -> Result<Vec<u8>> {
+    let file = ElfFile::parse(data)?.with_load_bias(load_bias)?;
+    let mut patched = data.to_vec();
+    for patch in patches {
+        let end = patch.addr + patch.bytes.len() as u64;
+        let offset = file
+            .info()
+            .segments
+            .iter()
+            .find(|seg| seg.vaddr <= patch.addr && end <= seg.vaddr + seg.filesz)
+            .map(|seg| (seg.offset + patch.addr - seg.vaddr) as usize)
+            .with_context(|| format!("Patch at 0x{:x}-0x{:x} is outside the file-backed image", patch.addr, end))?;
+        patched
+            .get_mut(offset..offset + patch.bytes.len())
+            .context("PT_LOAD segment extends past end of file")?
+            .copy_from_slice(&patch.bytes);
+    }
+    Ok(patched)
+}
+
+/// Parse ELF and extract metadata (see `ElfFile` for the other views)
+pub fn parse(data: &[u8]) -> Result<ElfInfo> {
+    ElfFile::parse(data).map(ElfFile::into_info)
+}
+
+/// A minimal RV64 ELF of type `e_type` with one RX segment at 0x1000
+/// holding `code`
+#[cfg(test)]
+pub(crate) fn tiny_elf(e_type: u16, code: &[u32]) -> Vec<u8> {
+    tiny_elf_at(e_type, 0x1000, code)
+}
+
+/// A minimal RV64 ELF of type `e_type` with one RX segment at `vaddr`
+/// holding the headers, then `code` at the entry point (the `test-util`
+/// feature exports it to other crates' tests)
+#[cfg(any(test, feature = "test-util"))]
+pub fn tiny_elf_at(e_type: u16, vaddr: u64, code: &[u32]) -> Vec<u8> {
+    let code: Vec<u8> = code.iter().flat_map(|i| i.to_le_bytes()).collect();
+    let offset = 0x78u64;
+    let mut elf = b"\x7fELF\x02\x01\x01".to_vec();
+    elf.resize(16, 0);
+    for (v, n) in [(e_type as u64, 2), (0xf3, 2), (1, 4), (vaddr + offset, 8), (64, 8), (0, 8), (0, 4)] {
+        elf.extend(&v.to_le_bytes()[..n]);
+    }
+    for v in [64u16, 56, 1, 64, 0, 0] {
+        elf.extend(v.to_le_bytes());
+    }
+    let size = offset + code.len() as u64;
+    for (v, n) in [(1u64, 4), (5, 4), (0, 8), (vaddr, 8), (vaddr, 8), (size, 8), (size, 8), (0x1000, 8)] {
+        elf.extend(&v.to_le_bytes()[..n]);
+    }
+    elf.extend(code);
+    elf
 }
 
 #[cfg(test)]
@@ -149,4 +362,43 @@ mod tests {
         let bad = vec![0x00; 64];
         assert!(parse(&bad).is_err());
     }
+
+    #[test]
+    fn test_load_bias() {
+        // auipc a0, 0 ; ecall
+        let pie = tiny_elf(goblin::elf::header::ET_DYN, &[0x00000517, 0x00000073]);
+        let file = ElfFile::parse(&pie).unwrap().with_load_bias(0x40_0000).unwrap();
+        assert_eq!(file.info().entry, 0x40_1078);
+        assert_eq!(file.info().segments[0].vaddr, 0x40_1000);
+        let section = &file.code_sections()[0];
+        assert_eq!(section.vaddr, 0x40_1000);
+
+        // AUIPC materializes the biased PC
+        let instructions = crate::disasm::disassemble(section).unwrap();
+        let cfg = crate::cfg::build(&instructions, file.info().entry).unwrap();
+        assert!(cfg.blocks.contains_key(&0x40_1078));
+        let module = crate::translate::translate(&cfg, file.info(), &Default::default()).unwrap();
+        let block = module.functions.iter().find(|f| f.block_addr == 0x40_1078).unwrap();
+        assert!(block.body.contains(&crate::WasmInst::I64Const { value: 0x40_1078 }));
+
+        let exec = tiny_elf(goblin::elf::header::ET_EXEC, &[0x00000073]);
+        assert!(ElfFile::parse(&exec).unwrap().with_load_bias(0x40_0000).is_err());
+        assert!(ElfFile::parse(&pie).unwrap().with_load_bias(0x40_0010).is_err());
+    }
+
+    #[test]
+    fn test_patch_image() {
+        // auipc a0, 0 ; ecall -- the ecall becomes a nop
+        let pie = tiny_elf(goblin::elf::header::ET_DYN, &[0x00000517, 0x00000073]);
+        let nop = BytePatch { addr: 0x40_107c, bytes: 0x00000013u32.to_le_bytes().to_vec() };
+        let patched = patch_image(&pie, 0x40_0000, std::slice::from_ref(&nop)).unwrap();
+        let file = ElfFile::parse(&patched).unwrap().with_load_bias(0x40_0000).unwrap();
+        let instructions = crate::disasm::disassemble(&file.code_sections()[0]).unwrap();
+        assert_eq!(instructions.last().unwrap().opcode, crate::Opcode::ADDI);
+        assert_eq!(&pie[..0x7c], &patched[..0x7c]);
+
+        // Past the end of the file-backed image
+        let past = BytePatch { addr: 0x40_1080, ..nop };
+        assert!(patch_image(&pie, 0x40_0000, &[past]).is_err());
+    }
 }
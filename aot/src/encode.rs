@@ -0,0 +1,594 @@
+// encode.rs - RISC-V assembler
+//
+// The inverse of disasm: encodes an `Instruction` back to its machine word.
+// Operands follow the decoder's conventions (LUI/AUIPC immediates are
+// already shifted, SRAI's immediate may carry the 0x400 funct bit, C.LI has
+// rs1 = 0, C.MV/C.ADD use rd, C.JR/C.JALR use rs1). Fields the decoded form
+// does not carry — rs3 of the fused multiply-adds, the FP rounding mode,
+// AMO aq/rl bits and FENCE pred/succ — are taken from `inst.bytes`, as the
+// translator does, so decode → encode reproduces the original word.
+
+use crate::disasm::{Instruction, Opcode};
+use anyhow::{bail, ensure, Result};
+
+/// `ebreak`, for patching breakpoints into 4-byte slots
+pub const EBREAK: u32 = 0x0010_0073;
+/// `c.ebreak`, for patching breakpoints into 2-byte slots
+pub const C_EBREAK: u16 = 0x9002;
+
+/// Encode `opcode` with explicit operands; unused operands are ignored
+pub fn assemble(opcode: Opcode, rd: u8, rs1: u8, rs2: u8, imm: i64) -> Result<u32> {
+    encode(&Instruction {
+        addr: 0,
+        bytes: 0,
+        len: if opcode.is_compressed() { 2 } else { 4 },
+        opcode,
+        rd: Some(rd),
+        rs1: Some(rs1),
+        rs2: Some(rs2),
+        imm: Some(imm),
+    })
+}
+
+/// Encode to little-endian bytes (2 or 4, by opcode)
+pub fn encode_bytes(inst: &Instruction) -> Result<Vec<u8>> {
+    let word = encode(inst)?;
+    Ok(if inst.opcode.is_compressed() {
+        (word as u16).to_le_bytes().to_vec()
+    } else {
+        word.to_le_bytes().to_vec()
+    })
+}
+
+/// Encode an instruction; compressed forms are returned in the low 16 bits
+pub fn encode(inst: &Instruction) -> Result<u32> {
+    if inst.opcode.is_compressed() {
+        encode16(inst).map(u32::from)
+    } else {
+        encode32(inst)
+    }
+}
+
+/// imm[hi:lo]
+fn bits(imm: i64, hi: u32, lo: u32) -> u32 {
+    ((imm as u64 >> lo) & ((1 << (hi - lo + 1)) - 1)) as u32
+}
+
+fn check_imm(inst: &Instruction, imm: i64, min: i64, max: i64, align: i64) -> Result<()> {
+    ensure!(
+        (min..=max).contains(&imm) && imm % align == 0,
+        "{:?}: immediate {} out of range [{}, {}] or not a multiple of {}",
+        inst.opcode,
+        imm,
+        min,
+        max,
+        align
+    );
+    Ok(())
+}
+
+fn r_type(funct7: u32, rs2: u32, rs1: u32, funct3: u32, rd: u32, opcode: u32) -> u32 {
+    (funct7 << 25) | (rs2 << 20) | (rs1 << 15) | (funct3 << 12) | (rd << 7) | opcode
+}
+
+fn i_type(imm: i64, rs1: u32, funct3: u32, rd: u32, opcode: u32) -> u32 {
+    (bits(imm, 11, 0) << 20) | (rs1 << 15) | (funct3 << 12) | (rd << 7) | opcode
+}
+
+fn s_type(imm: i64, rs2: u32, rs1: u32, funct3: u32, opcode: u32) -> u32 {
+    (bits(imm, 11, 5) << 25) | (rs2 << 20) | (rs1 << 15) | (funct3 << 12) | (bits(imm, 4, 0) << 7) | opcode
+}
+
+fn encode32(inst: &Instruction) -> Result<u32> {
+    use Opcode::*;
+
+    let rd = inst.rd.unwrap_or(0) as u32 & 0x1f;
+    let rs1 = inst.rs1.unwrap_or(0) as u32 & 0x1f;
+    let rs2 = inst.rs2.unwrap_or(0) as u32 & 0x1f;
+    let imm = inst.imm.unwrap_or(0);
+    // Fields only present in the raw word
+    let rm = (inst.bytes >> 12) & 0x7;
+    let rs3 = (inst.bytes >> 27) & 0x1f;
+    let aqrl = (inst.bytes >> 25) & 0x3;
+
+    let itype = |funct3: u32, opcode: u32| -> Result<u32> {
+        check_imm(inst, imm, -2048, 2047, 1)?;
+        Ok(i_type(imm, rs1, funct3, rd, opcode))
+    };
+    let stype = |funct3: u32, opcode: u32| -> Result<u32> {
+        check_imm(inst, imm, -2048, 2047, 1)?;
+        Ok(s_type(imm, rs2, rs1, funct3, opcode))
+    };
+    let btype = |funct3: u32| -> Result<u32> {
+        check_imm(inst, imm, -4096, 4094, 2)?;
+        Ok((bits(imm, 12, 12) << 31)
+            | (bits(imm, 10, 5) << 25)
+            | (rs2 << 20)
+            | (rs1 << 15)
+            | (funct3 << 12)
+            | (bits(imm, 4, 1) << 8)
+            | (bits(imm, 11, 11) << 7)
+            | 0x63)
+    };
+    let utype = |opcode: u32| -> Result<u32> {
+        ensure!(
+            imm & 0xfff == 0 && imm == imm as i32 as i64,
+            "{:?}: immediate 0x{:x} is not a sign-extended multiple of 0x1000",
+            inst.opcode,
+            imm
+        );
+        Ok((imm as u32 & 0xffff_f000) | (rd << 7) | opcode)
+    };
+    // Shift amounts; SRAI/SRAIW decode with the 0x400 funct bit included
+    let shift = |funct6: u32, funct3: u32, opcode: u32, width: i64| -> Result<u32> {
+        let shamt = imm & !0x400;
+        check_imm(inst, shamt, 0, width - 1, 1)?;
+        Ok((funct6 << 26) | ((shamt as u32) << 20) | (rs1 << 15) | (funct3 << 12) | (rd << 7) | opcode)
+    };
+    let op = |funct7: u32, funct3: u32| r_type(funct7, rs2, rs1, funct3, rd, 0x33);
+    let op32 = |funct7: u32, funct3: u32| r_type(funct7, rs2, rs1, funct3, rd, 0x3b);
+    let amo = |funct5: u32, funct3: u32, rs2: u32| r_type((funct5 << 2) | aqrl, rs2, rs1, funct3, rd, 0x2f);
+    let fp = |funct7: u32, funct3: u32, rs2: u32| r_type(funct7, rs2, rs1, funct3, rd, 0x53);
+    let r4 = |fmt: u32, opcode: u32| (rs3 << 27) | (fmt << 25) | (rs2 << 20) | (rs1 << 15) | (rm << 12) | (rd << 7) | opcode;
+
+    Ok(match inst.opcode {
+        LUI => utype(0x37)?,
+        AUIPC => utype(0x17)?,
+        JAL => {
+            check_imm(inst, imm, -(1 << 20), (1 << 20) - 2, 2)?;
+            (bits(imm, 20, 20) << 31)
+                | (bits(imm, 10, 1) << 21)
+                | (bits(imm, 11, 11) << 20)
+                | (bits(imm, 19, 12) << 12)
+                | (rd << 7)
+                | 0x6f
+        }
+        JALR => itype(0, 0x67)?,
+
+        BEQ => btype(0)?,
+        BNE => btype(1)?,
+        BLT => btype(4)?,
+        BGE => btype(5)?,
+        BLTU => btype(6)?,
+        BGEU => btype(7)?,
+
+        LB => itype(0, 0x03)?,
+        LH => itype(1, 0x03)?,
+        LW => itype(2, 0x03)?,
+        LD => itype(3, 0x03)?,
+        LBU => itype(4, 0x03)?,
+        LHU => itype(5, 0x03)?,
+        LWU => itype(6, 0x03)?,
+        SB => stype(0, 0x23)?,
+        SH => stype(1, 0x23)?,
+        SW => stype(2, 0x23)?,
+        SD => stype(3, 0x23)?,
+
+        ADDI => itype(0, 0x13)?,
+        SLTI => itype(2, 0x13)?,
+        SLTIU => itype(3, 0x13)?,
+        XORI => itype(4, 0x13)?,
+        ORI => itype(6, 0x13)?,
+        ANDI => itype(7, 0x13)?,
+        SLLI => shift(0x00, 1, 0x13, 64)?,
+        AES64KS1I => {
+            check_imm(inst, imm, 0, 0xa, 1)?;
+            i_type(0x310 | imm, rs1, 1, rd, 0x13)
+        }
+        AES64IM => i_type(0x300, rs1, 1, rd, 0x13),
+        SHA256SUM0 => i_type(0x100, rs1, 1, rd, 0x13),
+        SHA256SUM1 => i_type(0x101, rs1, 1, rd, 0x13),
+        SHA256SIG0 => i_type(0x102, rs1, 1, rd, 0x13),
+        SHA256SIG1 => i_type(0x103, rs1, 1, rd, 0x13),
+        SHA512SUM0 => i_type(0x104, rs1, 1, rd, 0x13),
+        SHA512SUM1 => i_type(0x105, rs1, 1, rd, 0x13),
+        SHA512SIG0 => i_type(0x106, rs1, 1, rd, 0x13),
+        SHA512SIG1 => i_type(0x107, rs1, 1, rd, 0x13),
+        AES64ES => op(0x19, 0),
+        AES64ESM => op(0x1b, 0),
+        AES64DS => op(0x1d, 0),
+        AES64DSM => op(0x1f, 0),
+        AES64KS2 => op(0x3f, 0),
+        SRLI => shift(0x00, 5, 0x13, 64)?,
+        SRAI => shift(0x10, 5, 0x13, 64)?,
+        ADDIW => itype(0, 0x1b)?,
+        SLLIW => shift(0x00, 1, 0x1b, 32)?,
+        SRLIW => shift(0x00, 5, 0x1b, 32)?,
+        SRAIW => shift(0x10, 5, 0x1b, 32)?,
+
+        ADD => op(0x00, 0),
+        SUB => op(0x20, 0),
+        SLL => op(0x00, 1),
+        SLT => op(0x00, 2),
+        SLTU => op(0x00, 3),
+        XOR => op(0x00, 4),
+        SRL => op(0x00, 5),
+        SRA => op(0x20, 5),
+        OR => op(0x00, 6),
+        AND => op(0x00, 7),
+        MUL => op(0x01, 0),
+        MULH => op(0x01, 1),
+        MULHSU => op(0x01, 2),
+        MULHU => op(0x01, 3),
+        DIV => op(0x01, 4),
+        DIVU => op(0x01, 5),
+        REM => op(0x01, 6),
+        REMU => op(0x01, 7),
+        ADDW => op32(0x00, 0),
+        SUBW => op32(0x20, 0),
+        SLLW => op32(0x00, 1),
+        SRLW => op32(0x00, 5),
+        SRAW => op32(0x20, 5),
+        MULW => op32(0x01, 0),
+        DIVW => op32(0x01, 4),
+        DIVUW => op32(0x01, 5),
+        REMW => op32(0x01, 6),
+        REMUW => op32(0x01, 7),
+
+        FENCE => (inst.bytes & 0xfff0_0000) | (rs1 << 15) | (rd << 7) | 0x0f,
+        ECALL => 0x0000_0073,
+        EBREAK => self::EBREAK,
+
+        CSRRW | CSRRS | CSRRC | CSRRWI | CSRRSI | CSRRCI => {
+            check_imm(inst, imm, 0, 0xfff, 1)?;
+            let funct3 = match inst.opcode {
+                CSRRW => 1,
+                CSRRS => 2,
+                CSRRC => 3,
+                CSRRWI => 5,
+                CSRRSI => 6,
+                _ => 7,
+            };
+            i_type(imm, rs1, funct3, rd, 0x73)
+        }
+        CBO_INVAL => i_type(0, rs1, 2, 0, 0x0f),
+        CBO_CLEAN => i_type(1, rs1, 2, 0, 0x0f),
+        CBO_FLUSH => i_type(2, rs1, 2, 0, 0x0f),
+        CBO_ZERO => i_type(4, rs1, 2, 0, 0x0f),
+        PAUSE => 0x0100_000f,
+        WRS_NTO => 0x00d0_0073,
+        WRS_STO => 0x01d0_0073,
+        SRET => 0x1020_0073,
+        MRET => 0x3020_0073,
+        WFI => 0x1050_0073,
+        SFENCE_VMA => r_type(0x09, rs2, rs1, 0, 0, 0x73),
+
+        LR_W => amo(0x02, 2, 0),
+        SC_W => amo(0x03, 2, rs2),
+        AMOSWAP_W => amo(0x01, 2, rs2),
+        AMOADD_W => amo(0x00, 2, rs2),
+        AMOXOR_W => amo(0x04, 2, rs2),
+        AMOAND_W => amo(0x0c, 2, rs2),
+        AMOOR_W => amo(0x08, 2, rs2),
+        AMOMIN_W => amo(0x10, 2, rs2),
+        AMOMAX_W => amo(0x14, 2, rs2),
+        AMOMINU_W => amo(0x18, 2, rs2),
+        AMOMAXU_W => amo(0x1c, 2, rs2),
+        LR_D => amo(0x02, 3, 0),
+        SC_D => amo(0x03, 3, rs2),
+        AMOSWAP_D => amo(0x01, 3, rs2),
+        AMOADD_D => amo(0x00, 3, rs2),
+        AMOXOR_D => amo(0x04, 3, rs2),
+        AMOAND_D => amo(0x0c, 3, rs2),
+        AMOOR_D => amo(0x08, 3, rs2),
+        AMOMIN_D => amo(0x10, 3, rs2),
+        AMOMAX_D => amo(0x14, 3, rs2),
+        AMOMINU_D => amo(0x18, 3, rs2),
+        AMOMAXU_D => amo(0x1c, 3, rs2),
+        AMOCAS_W => amo(0x05, 2, rs2),
+        AMOCAS_D => amo(0x05, 3, rs2),
+        AMOCAS_Q => amo(0x05, 4, rs2),
+        op if op.narrow_amo().is_some() => {
+            let (word, width) = op.narrow_amo().unwrap();
+            // Same funct5 as the word form; funct3 0 = byte, 1 = halfword
+            let funct5 = encode(&Instruction { opcode: word, ..inst.clone() })? >> 27;
+            amo(funct5, width as u32 - 1, rs2)
+        }
+
+        FLW => itype(2, 0x07)?,
+        FLD => itype(3, 0x07)?,
+        FSW => stype(2, 0x27)?,
+        FSD => stype(3, 0x27)?,
+        FMADD_S => r4(0, 0x43),
+        FMSUB_S => r4(0, 0x47),
+        FNMSUB_S => r4(0, 0x4b),
+        FNMADD_S => r4(0, 0x4f),
+        FMADD_D => r4(1, 0x43),
+        FMSUB_D => r4(1, 0x47),
+        FNMSUB_D => r4(1, 0x4b),
+        FNMADD_D => r4(1, 0x4f),
+
+        FADD_S => fp(0x00, rm, rs2),
+        FADD_D => fp(0x01, rm, rs2),
+        FSUB_S => fp(0x04, rm, rs2),
+        FSUB_D => fp(0x05, rm, rs2),
+        FMUL_S => fp(0x08, rm, rs2),
+        FMUL_D => fp(0x09, rm, rs2),
+        FDIV_S => fp(0x0c, rm, rs2),
+        FDIV_D => fp(0x0d, rm, rs2),
+        FSQRT_S => fp(0x2c, rm, 0),
+        FSQRT_D => fp(0x2d, rm, 0),
+        FSGNJ_S => fp(0x10, 0, rs2),
+        FSGNJN_S => fp(0x10, 1, rs2),
+        FSGNJX_S => fp(0x10, 2, rs2),
+        FSGNJ_D => fp(0x11, 0, rs2),
+        FSGNJN_D => fp(0x11, 1, rs2),
+        FSGNJX_D => fp(0x11, 2, rs2),
+        FMIN_S => fp(0x14, 0, rs2),
+        FMAX_S => fp(0x14, 1, rs2),
+        FMIN_D => fp(0x15, 0, rs2),
+        FMAX_D => fp(0x15, 1, rs2),
+        FEQ_S => fp(0x50, 2, rs2),
+        FLT_S => fp(0x50, 1, rs2),
+        FLE_S => fp(0x50, 0, rs2),
+        FEQ_D => fp(0x51, 2, rs2),
+        FLT_D => fp(0x51, 1, rs2),
+        FLE_D => fp(0x51, 0, rs2),
+        FCVT_W_S => fp(0x60, rm, 0),
+        FCVT_WU_S => fp(0x60, rm, 1),
+        FCVT_L_S => fp(0x60, rm, 2),
+        FCVT_LU_S => fp(0x60, rm, 3),
+        FCVT_W_D => fp(0x61, rm, 0),
+        FCVT_WU_D => fp(0x61, rm, 1),
+        FCVT_L_D => fp(0x61, rm, 2),
+        FCVT_LU_D => fp(0x61, rm, 3),
+        FCVT_S_W => fp(0x68, rm, 0),
+        FCVT_S_WU => fp(0x68, rm, 1),
+        FCVT_S_L => fp(0x68, rm, 2),
+        FCVT_S_LU => fp(0x68, rm, 3),
+        FCVT_D_W => fp(0x69, rm, 0),
+        FCVT_D_WU => fp(0x69, rm, 1),
+        FCVT_D_L => fp(0x69, rm, 2),
+        FCVT_D_LU => fp(0x69, rm, 3),
+        FCVT_S_D => fp(0x20, rm, 1),
+        FCVT_D_S => fp(0x21, rm, 0),
+        FMV_X_W => fp(0x70, 0, 0),
+        FCLASS_S => fp(0x70, 1, 0),
+        FMV_X_D => fp(0x71, 0, 0),
+        FCLASS_D => fp(0x71, 1, 0),
+        FMV_W_X => fp(0x78, 0, 0),
+        FMV_D_X => fp(0x79, 0, 0),
+
+        op => bail!("{:?} has no 32-bit encoding", op),
+    })
+}
+
+/// Register number of a compressed 3-bit register field (x8-x15)
+fn creg(inst: &Instruction, r: Option<u8>) -> Result<u32> {
+    let r = r.unwrap_or(0);
+    ensure!((8..16).contains(&r), "{:?}: x{} is not encodable (x8-x15 only)", inst.opcode, r);
+    Ok(r as u32 - 8)
+}
+
+fn encode16(inst: &Instruction) -> Result<u16> {
+    use Opcode::*;
+
+    let rd = inst.rd.unwrap_or(0) as u32 & 0x1f;
+    let rs1 = inst.rs1.unwrap_or(0) as u32 & 0x1f;
+    let rs2 = inst.rs2.unwrap_or(0) as u32 & 0x1f;
+    let imm = inst.imm.unwrap_or(0);
+    let check = |min: i64, max: i64, align: i64| check_imm(inst, imm, min, max, align);
+    let nonzero = |r: u32, what: &str| -> Result<()> {
+        ensure!(r != 0, "{:?}: {} must not be x0", inst.opcode, what);
+        Ok(())
+    };
+    // funct3 / quadrant framing
+    let frame = |funct3: u32, quadrant: u32| (funct3 << 13) | quadrant;
+    let ci = |imm: i64| (bits(imm, 5, 5) << 12) | (bits(imm, 4, 0) << 2);
+
+    let word = match inst.opcode {
+        C_ADDI4SPN => {
+            check(0, 1020, 4)?;
+            frame(0, 0)
+                | (bits(imm, 5, 4) << 11)
+                | (bits(imm, 9, 6) << 7)
+                | (bits(imm, 2, 2) << 6)
+                | (bits(imm, 3, 3) << 5)
+                | (creg(inst, inst.rd)? << 2)
+        }
+        C_LW | C_SW => {
+            check(0, 124, 4)?;
+            let (funct3, r) = if inst.opcode == C_LW { (2, inst.rd) } else { (6, inst.rs2) };
+            frame(funct3, 0)
+                | (bits(imm, 5, 3) << 10)
+                | (creg(inst, inst.rs1)? << 7)
+                | (bits(imm, 2, 2) << 6)
+                | (bits(imm, 6, 6) << 5)
+                | (creg(inst, r)? << 2)
+        }
+        C_LD | C_SD | C_FLD | C_FSD => {
+            check(0, 248, 8)?;
+            let (funct3, r) = match inst.opcode {
+                C_FLD => (1, inst.rd),
+                C_LD => (3, inst.rd),
+                C_FSD => (5, inst.rs2),
+                _ => (7, inst.rs2),
+            };
+            frame(funct3, 0)
+                | (bits(imm, 5, 3) << 10)
+                | (creg(inst, inst.rs1)? << 7)
+                | (bits(imm, 7, 6) << 5)
+                | (creg(inst, r)? << 2)
+        }
+
+        C_NOP => frame(0, 1),
+        C_ADDI => {
+            // rd = x0 is C.NOP
+            nonzero(rd, "rd")?;
+            check(-32, 31, 1)?;
+            frame(0, 1) | (rd << 7) | ci(imm)
+        }
+        C_ADDIW => {
+            check(-32, 31, 1)?;
+            frame(1, 1) | (rd << 7) | ci(imm)
+        }
+        C_LI => {
+            check(-32, 31, 1)?;
+            frame(2, 1) | (rd << 7) | ci(imm)
+        }
+        C_ADDI16SP => {
+            check(-512, 496, 16)?;
+            frame(3, 1)
+                | (bits(imm, 9, 9) << 12)
+                | (2 << 7)
+                | (bits(imm, 4, 4) << 6)
+                | (bits(imm, 6, 6) << 5)
+                | (bits(imm, 8, 7) << 3)
+                | (bits(imm, 5, 5) << 2)
+        }
+        C_LUI => {
+            // rd = x2 is C.ADDI16SP
+            ensure!(rd != 2, "C_LUI: rd must not be x2");
+            check(-(1 << 17), (1 << 17) - (1 << 12), 1 << 12)?;
+            frame(3, 1) | (bits(imm, 17, 17) << 12) | (rd << 7) | (bits(imm, 16, 12) << 2)
+        }
+        C_SRLI | C_SRAI | C_ANDI => {
+            let funct2 = match inst.opcode {
+                C_SRLI => 0,
+                C_SRAI => 1,
+                _ => 2,
+            };
+            if inst.opcode == C_ANDI {
+                check(-32, 31, 1)?;
+            } else {
+                check(0, 63, 1)?;
+            }
+            frame(4, 1) | (funct2 << 10) | (creg(inst, inst.rd)? << 7) | ci(imm)
+        }
+        C_SUB | C_XOR | C_OR | C_AND | C_SUBW | C_ADDW => {
+            let (bit12, funct) = match inst.opcode {
+                C_SUB => (0, 0),
+                C_XOR => (0, 1),
+                C_OR => (0, 2),
+                C_AND => (0, 3),
+                C_SUBW => (1, 0),
+                _ => (1, 1),
+            };
+            frame(4, 1)
+                | (bit12 << 12)
+                | (3 << 10)
+                | (creg(inst, inst.rd)? << 7)
+                | (funct << 5)
+                | (creg(inst, inst.rs2)? << 2)
+        }
+        C_J => {
+            check(-2048, 2046, 2)?;
+            frame(5, 1)
+                | (bits(imm, 11, 11) << 12)
+                | (bits(imm, 4, 4) << 11)
+                | (bits(imm, 9, 8) << 9)
+                | (bits(imm, 10, 10) << 8)
+                | (bits(imm, 6, 6) << 7)
+                | (bits(imm, 7, 7) << 6)
+                | (bits(imm, 3, 1) << 3)
+                | (bits(imm, 5, 5) << 2)
+        }
+        C_BEQZ | C_BNEZ => {
+            check(-256, 254, 2)?;
+            let funct3 = if inst.opcode == C_BEQZ { 6 } else { 7 };
+            frame(funct3, 1)
+                | (bits(imm, 8, 8) << 12)
+                | (bits(imm, 4, 3) << 10)
+                | (creg(inst, inst.rs1)? << 7)
+                | (bits(imm, 7, 6) << 5)
+                | (bits(imm, 2, 1) << 3)
+                | (bits(imm, 5, 5) << 2)
+        }
+
+        C_SLLI => {
+            check(0, 63, 1)?;
+            frame(0, 2) | (rd << 7) | ci(imm)
+        }
+        C_LWSP => {
+            check(0, 252, 4)?;
+            frame(2, 2) | (bits(imm, 5, 5) << 12) | (rd << 7) | (bits(imm, 4, 2) << 4) | (bits(imm, 7, 6) << 2)
+        }
+        C_LDSP | C_FLDSP => {
+            check(0, 504, 8)?;
+            frame(if inst.opcode == C_FLDSP { 1 } else { 3 }, 2) | (bits(imm, 5, 5) << 12) | (rd << 7) | (bits(imm, 4, 3) << 5) | (bits(imm, 8, 6) << 2)
+        }
+        C_JR => frame(4, 2) | (rs1 << 7),
+        C_MV => {
+            // rs2 = x0 is C.JR
+            nonzero(rs2, "rs2")?;
+            frame(4, 2) | (rd << 7) | (rs2 << 2)
+        }
+        C_EBREAK => self::C_EBREAK as u32,
+        C_JALR => {
+            // rs1 = x0 is C.EBREAK
+            nonzero(rs1, "rs1")?;
+            frame(4, 2) | (1 << 12) | (rs1 << 7)
+        }
+        C_ADD => {
+            nonzero(rs2, "rs2")?;
+            frame(4, 2) | (1 << 12) | (rd << 7) | (rs2 << 2)
+        }
+        C_SWSP => {
+            check(0, 252, 4)?;
+            frame(6, 2) | (bits(imm, 5, 2) << 9) | (bits(imm, 7, 6) << 7) | (rs2 << 2)
+        }
+        C_SDSP | C_FSDSP => {
+            check(0, 504, 8)?;
+            frame(if inst.opcode == C_FSDSP { 5 } else { 7 }, 2) | (bits(imm, 5, 3) << 10) | (bits(imm, 8, 6) << 7) | (rs2 << 2)
+        }
+
+        // C.JAL only exists on RV32; RV64 reuses the slot for C.ADDIW
+        op => bail!("{:?} has no RV64 compressed encoding", op),
+    };
+    Ok(word as u16)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::disasm::{decode16, decode32};
+
+    fn fields(i: &Instruction) -> (Opcode, Option<u8>, Option<u8>, Option<u8>, Option<i64>) {
+        (i.opcode, i.rd, i.rs1, i.rs2, i.imm)
+    }
+
+    #[test]
+    fn test_roundtrip() {
+        // addi, lui, jal, beq, sd, srai, amoadd.w.aqrl, fmadd.d (rne), fence rw,rw,
+        // csrrw sp, mscratch, sp, csrrsi a0, fflags, 1, sfence.vma a0, a1,
+        // cbo.zero (a0)
+        for word in [
+            0x0015_0513u32,
+            0xfff0_02b7,
+            0xff5f_f0ef,
+            0xfe20_8ee3,
+            0x00b1_3423,
+            0x43f5_5513,
+            0x06b5_252f,
+            0x6a20_8043,
+            0x0330_000f,
+            0x3401_1173,
+            0x0010_e573,
+            0x12b5_0073,
+            0x0045_200f,
+            0x0100_000f,
+            0x01d0_0073,
+            0x2ab5_352f,
+            0x28c5_452f,
+            0x80c5_05af,
+            0x28c5_152f,
+            0x32c5_8533,
+            0x3155_9513,
+            0x1065_9513,
+        ] {
+            let inst = decode32(0, word).unwrap();
+            assert_eq!(encode(&inst).unwrap(), word, "{:?}", inst.opcode);
+        }
+
+        // Every decodable compressed parcel encodes back to the same operands
+        for bits in 0..=u16::MAX {
+            let Ok(inst) = decode16(0, bits) else { continue };
+            let word = encode(&inst).unwrap_or_else(|e| panic!("0x{:04x}: {}", bits, e));
+            assert_eq!(fields(&decode16(0, word as u16).unwrap()), fields(&inst), "0x{:04x}", bits);
+        }
+
+        assert_eq!(assemble(Opcode::ADDI, 10, 10, 0, 1).unwrap(), 0x0015_0513);
+        assert!(assemble(Opcode::ADDI, 10, 10, 0, 4096).is_err());
+        assert!(assemble(Opcode::C_LW, 1, 8, 0, 0).is_err());
+    }
+}
@@ -0,0 +1,689 @@
+// eval.rs - Direct evaluator for the Wasm IR
+//
+// Executes a block function's body (`WasmFunction`) against a byte array
+// standing in for linear memory, with the machine state at `$m`, without
+// encoding or instantiating anything. Unit tests use it to check what
+// `translate_instruction` emits, and the differential harness to compare
+// the IR with the reference interpreter far faster than through a Wasm
+// engine.
+//
+// Semantics follow the Wasm spec for a single thread: out-of-bounds
+// accesses, integer division by zero or overflow, invalid float-to-int
+// truncations, misaligned atomics and `unreachable` trap (an error naming
+// the IR position). Atomics behave as plain accesses, `memory.atomic.wait32`
+// returns "not-equal" or "timed-out" at once and `memory.atomic.notify`
+// wakes nobody. `env.yield` is a no-op, `env.crypto` is `crypto::crypto`,
+// `env.watch` calls are logged (`eval_watched`) and `env.breakpoint` always
+// asks to stop. The body is assumed to be valid (see `verify.rs`).
+
+use crate::link::{BREAKPOINT_FUNC, CRYPTO_FUNC, WATCH_FUNC, YIELD_FUNC};
+use crate::translate::{WasmFunction, WasmInst};
+use anyhow::{bail, Context, Result};
+
+/// Instructions executed before a body is assumed to loop forever
+const MAX_STEPS: u64 = 1 << 24;
+
+/// An open block or loop
+struct Label {
+    is_loop: bool,
+    /// Index of the Block/Loop and of its End
+    start: usize,
+    end: usize,
+    /// Operand stack height on entry
+    height: usize,
+}
+
+/// Run `func` with `$m` = `m` over `memory`; the block's return value
+/// (next PC or a status code)
+pub fn eval(func: &WasmFunction, memory: &mut [u8], m: u32) -> Result<i32> {
+    eval_watched(func, memory, m).map(|(ret, _)| ret)
+}
+
+/// `eval`, also returning the `env.watch` calls made, as (addr, size,
+/// is_write, pc)
+pub fn eval_watched(func: &WasmFunction, memory: &mut [u8], m: u32) -> Result<(i32, Vec<[u32; 4]>)> {
+    let ends = matching_ends(&func.body)?;
+    let mut locals = vec![0u64; func.num_locals as usize + 1];
+    locals[0] = m as u64;
+    let mut e = Eval { memory, stack: Vec::new(), watched: Vec::new() };
+    let mut labels: Vec<Label> = Vec::new();
+    let mut pc = 0;
+    let mut steps = 0;
+
+    while pc < func.body.len() {
+        steps += 1;
+        if steps > MAX_STEPS {
+            bail!("block 0x{:x}: no return after {} steps", func.block_addr, MAX_STEPS);
+        }
+        let inst = func.body[pc];
+        let at = pc;
+        let trap = |e: anyhow::Error| {
+            e.context(format!("block 0x{:x}: trap at IR #{} ({:?})", func.block_addr, at, inst))
+        };
+        pc += 1;
+
+        // Control flow: the branch target label, if any
+        let taken = match inst {
+            WasmInst::Block { .. } | WasmInst::Loop { .. } => {
+                let is_loop = matches!(inst, WasmInst::Loop { .. });
+                labels.push(Label { is_loop, start: pc - 1, end: ends[pc - 1], height: e.stack.len() });
+                continue;
+            }
+            WasmInst::End => {
+                labels.pop();
+                continue;
+            }
+            WasmInst::Br { label } => Some(label),
+            WasmInst::BrIf { label } => (e.pop() as u32 != 0).then_some(label),
+            WasmInst::BrTable { table, default } => {
+                let index = e.pop() as u32 as usize;
+                let table = func.br_tables.get(table as usize).context("missing br_table")?;
+                Some(table.get(index).copied().unwrap_or(default))
+            }
+            WasmInst::Return => return Ok((e.pop() as i32, e.watched)),
+            _ => {
+                e.step(inst, &mut locals).map_err(trap)?;
+                continue;
+            }
+        };
+        let Some(label) = taken else { continue };
+        let Some(depth) = labels.len().checked_sub(label as usize + 1) else {
+            // A branch out of the function body returns
+            return Ok((e.pop() as i32, e.watched));
+        };
+        let target = &labels[depth];
+        e.stack.truncate(target.height);
+        if target.is_loop {
+            pc = target.start + 1;
+            labels.truncate(depth + 1);
+        } else {
+            pc = target.end + 1;
+            labels.truncate(depth);
+        }
+    }
+    Ok((e.pop() as i32, e.watched))
+}
+
+/// The result of the non-control, memory-free instruction `inst` on
+/// constant operands (raw bits, as on the operand stack); `None` if it traps
+pub(crate) fn apply(inst: WasmInst, operands: &[u64]) -> Option<u64> {
+    let mut e = Eval { memory: &mut [], stack: operands.to_vec(), watched: Vec::new() };
+    e.step(inst, &mut []).ok()?;
+    (e.stack.len() == 1).then(|| e.stack[0])
+}
+
+/// The End matching each Block/Loop (other entries unused)
+fn matching_ends(body: &[WasmInst]) -> Result<Vec<usize>> {
+    let mut ends = vec![0; body.len()];
+    let mut open = Vec::new();
+    for (i, inst) in body.iter().enumerate() {
+        match inst {
+            WasmInst::Block { .. } | WasmInst::Loop { .. } => open.push(i),
+            WasmInst::End => ends[open.pop().context("End without an open block")?] = i,
+            _ => {}
+        }
+    }
+    if !open.is_empty() {
+        bail!("{} blocks left open", open.len());
+    }
+    Ok(ends)
+}
+
+/// Operand stack (i32 and f32 values zero-extended, floats as bits) and memory
+struct Eval<'a> {
+    memory: &'a mut [u8],
+    stack: Vec<u64>,
+    /// `env.watch` arguments, in call order
+    watched: Vec<[u32; 4]>,
+}
+
+impl Eval<'_> {
+    fn pop(&mut self) -> u64 {
+        self.stack.pop().unwrap_or(0)
+    }
+
+    fn push32(&mut self, v: u32) {
+        self.stack.push(v as u64);
+    }
+
+    fn pop32(&mut self) -> u32 {
+        self.pop() as u32
+    }
+
+    fn pop_f32(&mut self) -> f32 {
+        f32::from_bits(self.pop32())
+    }
+
+    fn pop_f64(&mut self) -> f64 {
+        f64::from_bits(self.pop())
+    }
+
+    fn range(&self, addr: u32, offset: u32, len: usize) -> Result<std::ops::Range<usize>> {
+        let start = addr as usize + offset as usize;
+        if start + len > self.memory.len() {
+            bail!("out-of-bounds access of {} bytes at 0x{:x}", len, start);
+        }
+        Ok(start..start + len)
+    }
+
+    fn load(&mut self, offset: u32, len: usize) -> Result<u64> {
+        let addr = self.pop32();
+        let range = self.range(addr, offset, len)?;
+        let mut bytes = [0u8; 8];
+        bytes[..len].copy_from_slice(&self.memory[range]);
+        Ok(u64::from_le_bytes(bytes))
+    }
+
+    fn store(&mut self, offset: u32, len: usize) -> Result<()> {
+        let value = self.pop();
+        let addr = self.pop32();
+        let range = self.range(addr, offset, len)?;
+        self.memory[range].copy_from_slice(&value.to_le_bytes()[..len]);
+        Ok(())
+    }
+
+    /// Atomic read-modify-write of `len` bytes: pops the operand(s) and
+    /// address, stores `op(old, operands)`, pushes the old value
+    fn rmw(&mut self, offset: u32, len: usize, operands: usize, op: impl Fn(u64, u64, u64) -> u64) -> Result<()> {
+        let (b, a) = (if operands == 2 { self.pop() } else { 0 }, self.pop());
+        let addr = self.pop32();
+        let range = self.range(addr, offset, len)?;
+        if !range.start.is_multiple_of(len) {
+            bail!("misaligned atomic access at 0x{:x}", range.start);
+        }
+        let mut bytes = [0u8; 8];
+        bytes[..len].copy_from_slice(&self.memory[range.clone()]);
+        let old = u64::from_le_bytes(bytes);
+        let new = op(old, a, b);
+        self.memory[range].copy_from_slice(&new.to_le_bytes()[..len]);
+        self.stack.push(old);
+        Ok(())
+    }
+
+    fn aligned(&mut self, offset: u32, len: usize) -> Result<()> {
+        let addr = *self.stack.last().unwrap_or(&0) as u32 as usize + offset as usize;
+        if !addr.is_multiple_of(len) {
+            bail!("misaligned atomic access at 0x{:x}", addr);
+        }
+        Ok(())
+    }
+
+    fn bin64(&mut self, op: impl Fn(u64, u64) -> Result<u64>) -> Result<()> {
+        let b = self.pop();
+        let a = self.pop();
+        self.stack.push(op(a, b)?);
+        Ok(())
+    }
+
+    fn bin32(&mut self, op: impl Fn(u32, u32) -> Result<u32>) -> Result<()> {
+        let b = self.pop32();
+        let a = self.pop32();
+        self.push32(op(a, b)?);
+        Ok(())
+    }
+
+    fn cmp64(&mut self, op: impl Fn(u64, u64) -> bool) {
+        let b = self.pop();
+        let a = self.pop();
+        self.push32(op(a, b) as u32);
+    }
+
+    fn cmp32(&mut self, op: impl Fn(u32, u32) -> bool) {
+        let b = self.pop32();
+        let a = self.pop32();
+        self.push32(op(a, b) as u32);
+    }
+
+    fn f32s(&mut self, op: impl Fn(f32, f32) -> f32) {
+        let b = self.pop_f32();
+        let a = self.pop_f32();
+        self.push32(op(a, b).to_bits());
+    }
+
+    fn f64s(&mut self, op: impl Fn(f64, f64) -> f64) {
+        let b = self.pop_f64();
+        let a = self.pop_f64();
+        self.stack.push(op(a, b).to_bits());
+    }
+
+    fn f32_cmp(&mut self, op: impl Fn(f32, f32) -> bool) {
+        let b = self.pop_f32();
+        let a = self.pop_f32();
+        self.push32(op(a, b) as u32);
+    }
+
+    fn f64_cmp(&mut self, op: impl Fn(f64, f64) -> bool) {
+        let b = self.pop_f64();
+        let a = self.pop_f64();
+        self.push32(op(a, b) as u32);
+    }
+
+    fn un32(&mut self, op: impl Fn(u32) -> u32) {
+        let a = self.pop32();
+        self.push32(op(a));
+    }
+
+    fn un64(&mut self, op: impl Fn(u64) -> u64) {
+        let a = self.pop();
+        self.stack.push(op(a));
+    }
+
+    /// Execute a non-control instruction
+    fn step(&mut self, inst: WasmInst, locals: &mut [u64]) -> Result<()> {
+        use WasmInst::*;
+        match inst {
+            Comment { .. } | AtomicFence => {}
+            Unreachable => bail!("unreachable"),
+            Drop => {
+                self.pop();
+            }
+            Select => {
+                let cond = self.pop32();
+                let b = self.pop();
+                let a = self.pop();
+                self.stack.push(if cond != 0 { a } else { b });
+            }
+            Call { func_idx: YIELD_FUNC } => {}
+            Call { func_idx: CRYPTO_FUNC } => {
+                let rs2 = self.pop();
+                let rs1 = self.pop();
+                let op = self.pop32() as i32;
+                self.stack.push(crate::crypto::crypto(op, rs1, rs2));
+            }
+            Call { func_idx: WATCH_FUNC } => {
+                let args = [self.pop32(), self.pop32(), self.pop32(), self.pop32()];
+                self.watched.push([args[3], args[2], args[1], args[0]]);
+            }
+            Call { func_idx: BREAKPOINT_FUNC } => {
+                self.pop32();
+                self.push32(1);
+            }
+            Call { func_idx } | CallHost { func_idx } => bail!("call to function {}", func_idx),
+            CallIndirect { .. } => bail!("call_indirect"),
+
+            LocalGet { idx } => self.stack.push(locals[idx as usize]),
+            LocalSet { idx } => locals[idx as usize] = self.pop(),
+            LocalTee { idx } => locals[idx as usize] = *self.stack.last().unwrap_or(&0),
+            I32Const { value } => self.push32(value as u32),
+            I64Const { value } => self.stack.push(value as u64),
+            F32Const { value } => self.push32(value.to_bits()),
+            F64Const { value } => self.stack.push(value.to_bits()),
+
+            I32Load { offset } | F32Load { offset } | I64Load32U { offset } => {
+                let v = self.load(offset, 4)?;
+                self.stack.push(v);
+            }
+            I64Load { offset } | F64Load { offset } => {
+                let v = self.load(offset, 8)?;
+                self.stack.push(v);
+            }
+            I32Load8S { offset } => {
+                let v = self.load(offset, 1)? as i8 as i32 as u32;
+                self.push32(v);
+            }
+            I32Load8U { offset } | I64Load8U { offset } => {
+                let v = self.load(offset, 1)?;
+                self.stack.push(v);
+            }
+            I32Load16S { offset } => {
+                let v = self.load(offset, 2)? as i16 as i32 as u32;
+                self.push32(v);
+            }
+            I32Load16U { offset } | I64Load16U { offset } => {
+                let v = self.load(offset, 2)?;
+                self.stack.push(v);
+            }
+            I64Load8S { offset } => {
+                let v = self.load(offset, 1)? as i8 as i64 as u64;
+                self.stack.push(v);
+            }
+            I64Load16S { offset } => {
+                let v = self.load(offset, 2)? as i16 as i64 as u64;
+                self.stack.push(v);
+            }
+            I64Load32S { offset } => {
+                let v = self.load(offset, 4)? as i32 as i64 as u64;
+                self.stack.push(v);
+            }
+            I32AtomicLoad { offset } | I64AtomicLoad { offset } | I64AtomicLoad8U { offset }
+            | I64AtomicLoad16U { offset } => {
+                let len = match inst {
+                    I32AtomicLoad { .. } => 4,
+                    I64AtomicLoad { .. } => 8,
+                    I64AtomicLoad8U { .. } => 1,
+                    _ => 2,
+                };
+                self.aligned(offset, len)?;
+                let v = self.load(offset, len)?;
+                self.stack.push(v);
+            }
+            I32Store { offset } | F32Store { offset } | I64Store32 { offset } => self.store(offset, 4)?,
+            I64Store { offset } | F64Store { offset } => self.store(offset, 8)?,
+            I32Store8 { offset } | I64Store8 { offset } => self.store(offset, 1)?,
+            I32Store16 { offset } | I64Store16 { offset } => self.store(offset, 2)?,
+            I32AtomicStore { offset } | I64AtomicStore { offset } => {
+                let len = if matches!(inst, I32AtomicStore { .. }) { 4 } else { 8 };
+                let value = self.pop();
+                self.aligned(offset, len)?;
+                self.stack.push(value);
+                self.store(offset, len)?;
+            }
+            MemoryFill => {
+                let len = self.pop32() as usize;
+                let byte = self.pop32() as u8;
+                let dest = self.pop32();
+                let range = self.range(dest, 0, len)?;
+                self.memory[range].fill(byte);
+            }
+
+            I32AtomicRmwAdd { offset } => self.rmw(offset, 4, 1, |old, a, _| old.wrapping_add(a))?,
+            I64AtomicRmwAdd { offset } => self.rmw(offset, 8, 1, |old, a, _| old.wrapping_add(a))?,
+            I32AtomicRmwAnd { offset } => self.rmw(offset, 4, 1, |old, a, _| old & a)?,
+            I64AtomicRmwAnd { offset } => self.rmw(offset, 8, 1, |old, a, _| old & a)?,
+            I32AtomicRmwOr { offset } => self.rmw(offset, 4, 1, |old, a, _| old | a)?,
+            I64AtomicRmwOr { offset } => self.rmw(offset, 8, 1, |old, a, _| old | a)?,
+            I32AtomicRmwXor { offset } => self.rmw(offset, 4, 1, |old, a, _| old ^ a)?,
+            I64AtomicRmwXor { offset } => self.rmw(offset, 8, 1, |old, a, _| old ^ a)?,
+            I32AtomicRmwXchg { offset } => self.rmw(offset, 4, 1, |_, a, _| a)?,
+            I64AtomicRmwXchg { offset } => self.rmw(offset, 8, 1, |_, a, _| a)?,
+            I32AtomicRmwCmpxchg { offset } => {
+                self.rmw(offset, 4, 2, |old, expected, new| if old == expected & 0xffff_ffff { new } else { old })?
+            }
+            I64AtomicRmwCmpxchg { offset } => {
+                self.rmw(offset, 8, 2, |old, expected, new| if old == expected { new } else { old })?
+            }
+            I64AtomicRmw8CmpxchgU { offset } => {
+                self.rmw(offset, 1, 2, |old, expected, new| if old == expected & 0xff { new } else { old })?
+            }
+            I64AtomicRmw16CmpxchgU { offset } => {
+                self.rmw(offset, 2, 2, |old, expected, new| if old == expected & 0xffff { new } else { old })?
+            }
+            MemoryAtomicWait32 { offset } => {
+                let _timeout = self.pop();
+                let expected = self.pop32();
+                self.aligned(offset, 4)?;
+                let value = self.load(offset, 4)? as u32;
+                // 1: "not-equal", 2: "timed-out" (nobody else can wake us)
+                self.push32(if value != expected { 1 } else { 2 });
+            }
+            MemoryAtomicNotify { offset } => {
+                let _count = self.pop32();
+                self.aligned(offset, 4)?;
+                self.load(offset, 4)?;
+                self.push32(0);
+            }
+
+            I64Add => self.bin64(|a, b| Ok(a.wrapping_add(b)))?,
+            I64Sub => self.bin64(|a, b| Ok(a.wrapping_sub(b)))?,
+            I64Mul => self.bin64(|a, b| Ok(a.wrapping_mul(b)))?,
+            I64DivS => self.bin64(|a, b| match (a as i64, b as i64) {
+                (_, 0) => bail!("integer divide by zero"),
+                (i64::MIN, -1) => bail!("integer overflow"),
+                (a, b) => Ok((a / b) as u64),
+            })?,
+            I64DivU => self.bin64(|a, b| a.checked_div(b).context("integer divide by zero"))?,
+            I64RemS => self.bin64(|a, b| match b {
+                0 => bail!("integer divide by zero"),
+                _ => Ok((a as i64).wrapping_rem(b as i64) as u64),
+            })?,
+            I64RemU => self.bin64(|a, b| a.checked_rem(b).context("integer divide by zero"))?,
+            I64And => self.bin64(|a, b| Ok(a & b))?,
+            I64Or => self.bin64(|a, b| Ok(a | b))?,
+            I64Xor => self.bin64(|a, b| Ok(a ^ b))?,
+            I64Shl => self.bin64(|a, b| Ok(a.wrapping_shl(b as u32)))?,
+            I64ShrS => self.bin64(|a, b| Ok((a as i64).wrapping_shr(b as u32) as u64))?,
+            I64ShrU => self.bin64(|a, b| Ok(a.wrapping_shr(b as u32)))?,
+            I64Rotl => self.bin64(|a, b| Ok(a.rotate_left((b % 64) as u32)))?,
+            I64Rotr => self.bin64(|a, b| Ok(a.rotate_right((b % 64) as u32)))?,
+            I64Clz => self.un64(|a| a.leading_zeros() as u64),
+            I64Ctz => self.un64(|a| a.trailing_zeros() as u64),
+            I64Popcnt => self.un64(|a| a.count_ones() as u64),
+            I64Extend8S => self.un64(|a| a as i8 as i64 as u64),
+            I64Extend16S => self.un64(|a| a as i16 as i64 as u64),
+            I64Eqz => {
+                let a = self.pop();
+                self.push32((a == 0) as u32);
+            }
+            I64Eq => self.cmp64(|a, b| a == b),
+            I64Ne => self.cmp64(|a, b| a != b),
+            I64LtS => self.cmp64(|a, b| (a as i64) < b as i64),
+            I64LtU => self.cmp64(|a, b| a < b),
+            I64GtS => self.cmp64(|a, b| a as i64 > b as i64),
+            I64GtU => self.cmp64(|a, b| a > b),
+            I64LeS => self.cmp64(|a, b| a as i64 <= b as i64),
+            I64LeU => self.cmp64(|a, b| a <= b),
+            I64GeS => self.cmp64(|a, b| a as i64 >= b as i64),
+            I64GeU => self.cmp64(|a, b| a >= b),
+
+            I32Add => self.bin32(|a, b| Ok(a.wrapping_add(b)))?,
+            I32Sub => self.bin32(|a, b| Ok(a.wrapping_sub(b)))?,
+            I32Mul => self.bin32(|a, b| Ok(a.wrapping_mul(b)))?,
+            I32DivS => self.bin32(|a, b| match (a as i32, b as i32) {
+                (_, 0) => bail!("integer divide by zero"),
+                (i32::MIN, -1) => bail!("integer overflow"),
+                (a, b) => Ok((a / b) as u32),
+            })?,
+            I32DivU => self.bin32(|a, b| a.checked_div(b).context("integer divide by zero"))?,
+            I32RemS => self.bin32(|a, b| match b {
+                0 => bail!("integer divide by zero"),
+                _ => Ok((a as i32).wrapping_rem(b as i32) as u32),
+            })?,
+            I32RemU => self.bin32(|a, b| a.checked_rem(b).context("integer divide by zero"))?,
+            I32And => self.bin32(|a, b| Ok(a & b))?,
+            I32Or => self.bin32(|a, b| Ok(a | b))?,
+            I32Xor => self.bin32(|a, b| Ok(a ^ b))?,
+            I32Shl => self.bin32(|a, b| Ok(a.wrapping_shl(b)))?,
+            I32ShrS => self.bin32(|a, b| Ok((a as i32).wrapping_shr(b) as u32))?,
+            I32ShrU => self.bin32(|a, b| Ok(a.wrapping_shr(b)))?,
+            I32Rotr => self.bin32(|a, b| Ok(a.rotate_right(b % 32)))?,
+            I32Eqz => self.un32(|a| (a == 0) as u32),
+            I32Eq => self.cmp32(|a, b| a == b),
+            I32Ne => self.cmp32(|a, b| a != b),
+            I32LtS => self.cmp32(|a, b| (a as i32) < b as i32),
+            I32LtU => self.cmp32(|a, b| a < b),
+            I32GtS => self.cmp32(|a, b| a as i32 > b as i32),
+            I32GtU => self.cmp32(|a, b| a > b),
+            I32LeS => self.cmp32(|a, b| a as i32 <= b as i32),
+            I32LeU => self.cmp32(|a, b| a <= b),
+            I32GeS => self.cmp32(|a, b| a as i32 >= b as i32),
+            I32GeU => self.cmp32(|a, b| a >= b),
+
+            I32WrapI64 => self.un64(|a| a & 0xffff_ffff),
+            I64ExtendI32S => self.un64(|a| a as i32 as i64 as u64),
+            I64ExtendI32U => self.un64(|a| a & 0xffff_ffff),
+
+            F32Add => self.f32s(|a, b| a + b),
+            F32Sub => self.f32s(|a, b| a - b),
+            F32Mul => self.f32s(|a, b| a * b),
+            F32Div => self.f32s(|a, b| a / b),
+            F32Min => self.f32s(f32_min),
+            F32Max => self.f32s(f32_max),
+            F32Copysign => self.f32s(f32::copysign),
+            F32Eq => self.f32_cmp(|a, b| a == b),
+            F32Ne => self.f32_cmp(|a, b| a != b),
+            F32Lt => self.f32_cmp(|a, b| a < b),
+            F32Gt => self.f32_cmp(|a, b| a > b),
+            F32Le => self.f32_cmp(|a, b| a <= b),
+            F32Ge => self.f32_cmp(|a, b| a >= b),
+            F32Sqrt => self.un32(|a| f32::from_bits(a).sqrt().to_bits()),
+            F32Neg => self.un32(|a| a ^ 0x8000_0000),
+            F32Abs => self.un32(|a| a & 0x7fff_ffff),
+            F32Ceil => self.un32(|a| f32::from_bits(a).ceil().to_bits()),
+            F32Floor => self.un32(|a| f32::from_bits(a).floor().to_bits()),
+            F32Trunc => self.un32(|a| f32::from_bits(a).trunc().to_bits()),
+            F32Nearest => self.un32(|a| f32::from_bits(a).round_ties_even().to_bits()),
+
+            F64Add => self.f64s(|a, b| a + b),
+            F64Sub => self.f64s(|a, b| a - b),
+            F64Mul => self.f64s(|a, b| a * b),
+            F64Div => self.f64s(|a, b| a / b),
+            F64Min => self.f64s(f64_min),
+            F64Max => self.f64s(f64_max),
+            F64Copysign => self.f64s(f64::copysign),
+            // Relaxed SIMD allows either rounding; this is the fused one
+            F64RelaxedMadd => {
+                let c = self.pop_f64();
+                let b = self.pop_f64();
+                let a = self.pop_f64();
+                self.stack.push(a.mul_add(b, c).to_bits());
+            }
+            F64Eq => self.f64_cmp(|a, b| a == b),
+            F64Ne => self.f64_cmp(|a, b| a != b),
+            F64Lt => self.f64_cmp(|a, b| a < b),
+            F64Gt => self.f64_cmp(|a, b| a > b),
+            F64Le => self.f64_cmp(|a, b| a <= b),
+            F64Ge => self.f64_cmp(|a, b| a >= b),
+            F64Sqrt => self.un64(|a| f64::from_bits(a).sqrt().to_bits()),
+            F64Neg => self.un64(|a| a ^ (1 << 63)),
+            F64Abs => self.un64(|a| a & !(1 << 63)),
+            F64Ceil => self.un64(|a| f64::from_bits(a).ceil().to_bits()),
+            F64Floor => self.un64(|a| f64::from_bits(a).floor().to_bits()),
+            F64Trunc => self.un64(|a| f64::from_bits(a).trunc().to_bits()),
+            F64Nearest => self.un64(|a| f64::from_bits(a).round_ties_even().to_bits()),
+
+            F32ConvertI32S => self.un64(|a| (a as i32 as f32).to_bits() as u64),
+            F32ConvertI32U => self.un64(|a| (a as u32 as f32).to_bits() as u64),
+            F32ConvertI64S => self.un64(|a| (a as i64 as f32).to_bits() as u64),
+            F32ConvertI64U => self.un64(|a| (a as f32).to_bits() as u64),
+            F64ConvertI32S => self.un64(|a| (a as i32 as f64).to_bits()),
+            F64ConvertI32U => self.un64(|a| (a as u32 as f64).to_bits()),
+            F64ConvertI64S => self.un64(|a| (a as i64 as f64).to_bits()),
+            F64ConvertI64U => self.un64(|a| (a as f64).to_bits()),
+            F32DemoteF64 => self.un64(|a| (f64::from_bits(a) as f32).to_bits() as u64),
+            F64PromoteF32 => self.un64(|a| (f32::from_bits(a as u32) as f64).to_bits()),
+            F32ReinterpretI32 | I32ReinterpretF32 | F64ReinterpretI64 | I64ReinterpretF64 => {}
+
+            I32TruncF32S | I32TruncF32U | I32TruncF64S | I32TruncF64U | I64TruncF32S | I64TruncF32U
+            | I64TruncF64S | I64TruncF64U => {
+                let x = match inst {
+                    I32TruncF32S | I32TruncF32U | I64TruncF32S | I64TruncF32U => self.pop_f32() as f64,
+                    _ => self.pop_f64(),
+                };
+                // Exclusive bounds of the representable range
+                let (lo, hi) = match inst {
+                    I32TruncF32S | I32TruncF64S => (-2147483649.0, 2147483648.0),
+                    I32TruncF32U | I32TruncF64U => (-1.0, 4294967296.0),
+                    I64TruncF32S | I64TruncF64S => (-9223372036854777856.0, 9223372036854775808.0),
+                    _ => (-1.0, 18446744073709551616.0),
+                };
+                if x.is_nan() {
+                    bail!("invalid conversion to integer");
+                }
+                if x <= lo || x >= hi {
+                    bail!("integer overflow");
+                }
+                let v = match inst {
+                    I32TruncF32S | I32TruncF64S => x as i32 as u32 as u64,
+                    I32TruncF32U | I32TruncF64U => x as u32 as u64,
+                    I64TruncF32S | I64TruncF64S => x as i64 as u64,
+                    _ => x as u64,
+                };
+                self.stack.push(v);
+            }
+
+            Block { .. } | Loop { .. } | End | Br { .. } | BrIf { .. } | BrTable { .. } | Return => {
+                unreachable!("control flow is handled by eval")
+            }
+        }
+        Ok(())
+    }
+}
+
+/// Wasm `fmin`/`fmax`: NaN if either operand is, -0 below +0
+macro_rules! min_max {
+    ($min:ident, $max:ident, $t:ty) => {
+        fn $min(a: $t, b: $t) -> $t {
+            match (a.is_nan() || b.is_nan(), a == b) {
+                (true, _) => <$t>::NAN,
+                (false, true) => <$t>::from_bits(a.to_bits() | b.to_bits()),
+                (false, false) => a.min(b),
+            }
+        }
+
+        fn $max(a: $t, b: $t) -> $t {
+            match (a.is_nan() || b.is_nan(), a == b) {
+                (true, _) => <$t>::NAN,
+                (false, true) => <$t>::from_bits(a.to_bits() & b.to_bits()),
+                (false, false) => a.max(b),
+            }
+        }
+    };
+}
+
+min_max!(f32_min, f32_max, f32);
+min_max!(f64_min, f64_max, f64);
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::disasm::{Instruction, Opcode};
+    use crate::test_util::inst;
+
+    #[test]
+    fn test_eval_translated_block() {
+        // addi a0, a0, -3; sd a0, 8(a1); divu a2, a0, zero; blt a0, zero, -12
+        let code = [
+            inst(0x1000, Opcode::ADDI, 10, 10, 0, -3),
+            inst(0x1004, Opcode::SD, 0, 11, 10, 8),
+            inst(0x1008, Opcode::DIVU, 12, 10, 0, 0),
+            inst(0x100c, Opcode::BLT, 0, 10, 0, -12),
+        ];
+        let cfg = crate::cfg::build(&code, 0x1000).unwrap();
+        let module = crate::translate::translate_jit(&cfg, 0x1000).unwrap();
+        let func = &module.functions[0];
+
+        let mut memory = vec![0u8; 0x2000];
+        let reg = |memory: &[u8], r: usize| u64::from_le_bytes(memory[r * 8..r * 8 + 8].try_into().unwrap());
+        memory[80..88].copy_from_slice(&1u64.to_le_bytes());
+        memory[88..96].copy_from_slice(&0x1800u64.to_le_bytes());
+        assert_eq!(eval(func, &mut memory, 0).unwrap(), 0x1000);
+        assert_eq!(reg(&memory, 10), -2i64 as u64);
+        assert_eq!(memory[0x1808..0x1810], (-2i64).to_le_bytes());
+        assert_eq!(reg(&memory, 12), u64::MAX);
+
+        // a0 = 5: falls through; a store past the end of memory traps
+        memory[80..88].copy_from_slice(&5u64.to_le_bytes());
+        assert_eq!(eval(func, &mut memory, 0).unwrap(), 0x1010);
+        memory[88..96].copy_from_slice(&0x1ffcu64.to_le_bytes());
+        assert!(eval(func, &mut memory, 0).unwrap_err().to_string().contains("trap"));
+    }
+
+    #[test]
+    fn test_eval_hart_id() {
+        use crate::options::CompileOptions;
+        use crate::translate::{translate_only, HART_ID_OFFSET};
+
+        // csrr a0, mhartid; each hart sees its own id, and it is illegal
+        // outside SMP mode
+        let code = [Instruction {
+            addr: 0x1000,
+            bytes: 0,
+            len: 4,
+            opcode: Opcode::CSRRS,
+            rd: Some(10),
+            rs1: Some(0),
+            rs2: None,
+            imm: Some(0xF14),
+        }];
+        let cfg = crate::cfg::build(&code, 0x1000).unwrap();
+        let translate = |options: &CompileOptions| {
+            let mut funcs = Vec::new();
+            translate_only(&cfg, options, &[0x1000], |f| {
+                funcs.push(f);
+                Ok(())
+            })
+            .map(|_| funcs.remove(0))
+        };
+        let smp = translate(&CompileOptions { harts: 2, threads: true, ..Default::default() }).unwrap();
+        let single = translate(&CompileOptions::default()).unwrap();
+        assert!(translate(&CompileOptions { harts: 2, ..Default::default() }).is_err());
+
+        let mut memory = vec![0u8; 0x2000];
+        for (m, id) in [(0usize, 0u64), (0x800, 1)] {
+            memory[m + HART_ID_OFFSET as usize..][..8].copy_from_slice(&id.to_le_bytes());
+            assert_eq!(eval(&smp, &mut memory, m as u32).unwrap(), 0x1004);
+            assert_eq!(memory[m + 80..m + 88], id.to_le_bytes());
+        }
+        assert_eq!(eval(&single, &mut memory, 0).unwrap(), crate::abi::ILLEGAL_TRAP as i32);
+    }
+}
@@ -0,0 +1,728 @@
+// fma.rs - Fused multiply-add lowering
+//
+// RISC-V's FMADD, FMSUB, FNMADD and FNMSUB round a * b + c once. Wasm has
+// no scalar FMA, so by default they are a multiply then an add, which
+// rounds twice and can be off by one in the last bit. `CompileOptions::fma`
+// picks something closer to hardware:
+//
+// - `Relaxed`: double precision goes through `f64x2.relaxed_madd`
+//   (`WasmInst::F64RelaxedMadd`). Engines fuse it when the host CPU has
+//   FMA, so it is exact and fast there but unfused elsewhere.
+// - `Exact`: a sequence of ordinary f64 operations that rounds once.
+//
+// The exact sequence is Boldo and Melquiond's emulated FMA: Dekker's
+// product splits a * b into p + e exactly, a TwoSum splits c + p into
+// s + t, and the result is s + (t + e) with the inner sum rounded to odd
+// (rounded to nearest, then moved one ulp toward the error if that was
+// inexact and left the last bit even). Dekker's splitting overflows near
+// the top of the exponent range and loses the product's error to underflow
+// near the bottom, so operands there are first scaled by powers of two:
+// a and b into [1, 2), and c by the same factor as the product (or, if it
+// is too small to matter but as a sticky bit, to a tiny value of its sign).
+// The sum is then rounded once as it is scaled back. A result that lands
+// among the subnormals would be rounded twice, once to 53 bits and once to
+// the coarser subnormal spacing, so the 53-bit result is first moved one
+// ulp toward the exact sum where that second rounding would otherwise go
+// wrong. Infinities, NaNs, zeros and an addend that dwarfs the product
+// need no emulation: the unfused result (or the product alone, for c = 0)
+// is already right for them.
+//
+// Single precision needs less: the product of two f32s is exact in f64, so
+// it is (a * b) + c in f64 rounded to odd, then to f32, always exact. Both
+// modes use that.
+//
+// Operands live in the block's i64 scratch locals, as bits; a block using
+// either mode has at least `NUM_LOCALS` of them.
+
+use crate::options::FmaMode;
+use crate::translate::{WasmInst, F32_REGS_OFFSET, F64_REGS_OFFSET};
+
+/// Scratch locals the exact sequences use (locals 1 to this)
+pub const NUM_LOCALS: u32 = 12;
+
+/// Veltkamp's splitting constant for doubles, 2^27 + 1
+const SPLIT: f64 = 134217729.0;
+
+/// The exact sequence is used when |a| and |b| are below this (so
+/// splitting cannot overflow), ...
+const MAX_FACTOR: f64 = pow2(995);
+/// ... at least this (so their split halves are exact), ...
+const MIN_FACTOR: f64 = f64::MIN_POSITIVE;
+/// ... |a * b| and |c| below this (so s cannot overflow) ...
+const MAX_TERM: f64 = pow2(1022);
+/// ... and |a * b| at least this (so its error is not subnormal)
+const MIN_PRODUCT: f64 = pow2(-959);
+
+/// Scaled addends at most this far (in binary orders) below the scaled
+/// product, which lies in [1, 4), are replaced by `STICKY`...
+const NEGLIGIBLE: i64 = -110;
+/// ... which is below every bit of the product, but keeps c's sign
+const STICKY: f64 = pow2(-120);
+/// Addends more than this far above the product round to themselves
+const DOMINANT: i64 = 60;
+
+const fn pow2(exp: i32) -> f64 {
+    f64::from_bits(((1023 + exp) as u64) << 52)
+}
+
+// Scratch locals: the operands, the rounded product, the split halves of
+// a and b (reused for the round-to-odd sum), the product's error, c + p as
+// s + t, and the power of two the operands were scaled by (an i64)
+const A: u32 = 1;
+const B: u32 = 2;
+const C: u32 = 3;
+const P: u32 = 4;
+const AH: u32 = 5;
+const AL: u32 = 6;
+const BH: u32 = 7;
+const BL: u32 = 8;
+const E: u32 = 9;
+const S: u32 = 10;
+const T: u32 = 11;
+const K: u32 = 12;
+
+/// An FMA instruction's registers and signs: rd = (±rs1) * rs2 + (±rs3)
+#[derive(Debug, Clone, Copy)]
+pub struct Fma {
+    pub double: bool,
+    pub rd: u32,
+    pub rs1: u32,
+    pub rs2: u32,
+    pub rs3: u32,
+    /// FNMSUB, FNMADD
+    pub negate_product: bool,
+    /// FMSUB, FNMADD
+    pub negate_addend: bool,
+}
+
+impl Fma {
+    fn offset(&self, reg: u32) -> u32 {
+        if self.double {
+            F64_REGS_OFFSET + reg * 8
+        } else {
+            F32_REGS_OFFSET + reg * 4
+        }
+    }
+
+    /// Push FP register `reg` (as f64 if `promote`), negated if `negate`
+    fn load(&self, body: &mut Vec<WasmInst>, reg: u32, negate: bool, promote: bool) {
+        body.push(WasmInst::LocalGet { idx: 0 });
+        if self.double {
+            body.push(WasmInst::F64Load { offset: self.offset(reg) });
+        } else {
+            body.push(WasmInst::F32Load { offset: self.offset(reg) });
+            if promote {
+                body.push(WasmInst::F64PromoteF32);
+            }
+        }
+        if negate {
+            body.push(if self.double || promote { WasmInst::F64Neg } else { WasmInst::F32Neg });
+        }
+    }
+}
+
+/// Lower `fma` as `mode` says
+pub fn emit(body: &mut Vec<WasmInst>, fma: &Fma, mode: FmaMode) {
+    match mode {
+        FmaMode::Unfused => emit_unfused(body, fma),
+        FmaMode::Relaxed if fma.double => {
+            body.push(WasmInst::LocalGet { idx: 0 });
+            fma.load(body, fma.rs1, fma.negate_product, false);
+            fma.load(body, fma.rs2, false, false);
+            fma.load(body, fma.rs3, fma.negate_addend, false);
+            body.push(WasmInst::F64RelaxedMadd);
+            body.push(WasmInst::F64Store { offset: fma.offset(fma.rd) });
+        }
+        _ if fma.double => emit_exact_f64(body, fma),
+        _ => emit_exact_f32(body, fma),
+    }
+}
+
+/// Multiply, then add or subtract
+fn emit_unfused(body: &mut Vec<WasmInst>, fma: &Fma) {
+    let op = |f32_op, f64_op| if fma.double { f64_op } else { f32_op };
+    body.push(WasmInst::LocalGet { idx: 0 });
+    fma.load(body, fma.rs1, false, false);
+    fma.load(body, fma.rs2, false, false);
+    body.push(op(WasmInst::F32Mul, WasmInst::F64Mul));
+    if fma.negate_product {
+        body.push(op(WasmInst::F32Neg, WasmInst::F64Neg));
+    }
+    fma.load(body, fma.rs3, false, false);
+    body.push(if fma.negate_addend {
+        op(WasmInst::F32Sub, WasmInst::F64Sub)
+    } else {
+        op(WasmInst::F32Add, WasmInst::F64Add)
+    });
+    body.push(if fma.double {
+        WasmInst::F64Store { offset: fma.offset(fma.rd) }
+    } else {
+        WasmInst::F32Store { offset: fma.offset(fma.rd) }
+    });
+}
+
+fn get(body: &mut Vec<WasmInst>, local: u32) {
+    body.push(WasmInst::LocalGet { idx: local });
+    body.push(WasmInst::F64ReinterpretI64);
+}
+
+fn set(body: &mut Vec<WasmInst>, local: u32) {
+    body.push(WasmInst::I64ReinterpretF64);
+    body.push(WasmInst::LocalSet { idx: local });
+}
+
+/// Push `a op b` for locals `a` and `b`
+fn binary(body: &mut Vec<WasmInst>, a: u32, b: u32, op: WasmInst) {
+    get(body, a);
+    get(body, b);
+    body.push(op);
+}
+
+/// Split local `x` into `hi` + `lo`, each with at most 26 significant bits
+fn split(body: &mut Vec<WasmInst>, x: u32, hi: u32, lo: u32) {
+    // hi = t - (t - x), t = x * SPLIT; lo = x - hi
+    get(body, x);
+    body.push(WasmInst::F64Const { value: SPLIT });
+    body.push(WasmInst::F64Mul);
+    set(body, hi);
+    get(body, hi);
+    binary(body, hi, x, WasmInst::F64Sub);
+    body.push(WasmInst::F64Sub);
+    set(body, hi);
+    binary(body, x, hi, WasmInst::F64Sub);
+    set(body, lo);
+}
+
+/// Push x + y rounded to odd, for locals `x` and `y`; clobbers `u`, `bb`
+/// and `err`
+fn sum_to_odd(body: &mut Vec<WasmInst>, x: u32, y: u32, [u, bb, err]: [u32; 3]) {
+    // u = x + y and the error err = x + y - u (TwoSum)
+    binary(body, x, y, WasmInst::F64Add);
+    set(body, u);
+    binary(body, u, x, WasmInst::F64Sub);
+    set(body, bb);
+    get(body, x);
+    binary(body, u, bb, WasmInst::F64Sub);
+    body.push(WasmInst::F64Sub);
+    binary(body, y, bb, WasmInst::F64Sub);
+    body.push(WasmInst::F64Add);
+    set(body, err);
+
+    // bits(u) + (err != 0 && u even ? (u and err alike in sign ? 1 : -1) : 0);
+    // an inexact sum is never 0, and NaN errors count as exact
+    body.push(WasmInst::LocalGet { idx: u });
+    body.push(WasmInst::I64Const { value: 1 });
+    body.push(WasmInst::I64Const { value: -1 });
+    get(body, u);
+    body.push(WasmInst::F64Const { value: 0.0 });
+    body.push(WasmInst::F64Lt);
+    get(body, err);
+    body.push(WasmInst::F64Const { value: 0.0 });
+    body.push(WasmInst::F64Lt);
+    body.push(WasmInst::I32Eq);
+    body.push(WasmInst::Select);
+    body.push(WasmInst::I64Const { value: 0 });
+    get(body, err);
+    body.push(WasmInst::F64Abs);
+    body.push(WasmInst::F64Const { value: 0.0 });
+    body.push(WasmInst::F64Gt);
+    body.push(WasmInst::LocalGet { idx: u });
+    body.push(WasmInst::I64Const { value: 1 });
+    body.push(WasmInst::I64And);
+    body.push(WasmInst::I64Eqz);
+    body.push(WasmInst::I32And);
+    body.push(WasmInst::Select);
+    body.push(WasmInst::I64Add);
+    body.push(WasmInst::F64ReinterpretI64);
+}
+
+/// Push |local| < `limit` (or >= if `at_least`) as an i32
+fn magnitude(body: &mut Vec<WasmInst>, local: u32, limit: f64, at_least: bool) {
+    get(body, local);
+    body.push(WasmInst::F64Abs);
+    body.push(WasmInst::F64Const { value: limit });
+    body.push(if at_least { WasmInst::F64Ge } else { WasmInst::F64Lt });
+}
+
+/// Push the exponent of the finite nonzero double in local `x` (that of its
+/// leading bit, for subnormals) as an i64
+fn exponent(body: &mut Vec<WasmInst>, x: u32) {
+    // field != 0 ? field - 1023 : -1011 - clz(bits without the sign)
+    body.push(WasmInst::LocalGet { idx: x });
+    body.push(WasmInst::I64Const { value: 52 });
+    body.push(WasmInst::I64ShrU);
+    body.push(WasmInst::I64Const { value: 0x7ff });
+    body.push(WasmInst::I64And);
+    body.push(WasmInst::I64Const { value: 1023 });
+    body.push(WasmInst::I64Sub);
+    body.push(WasmInst::I64Const { value: -1011 });
+    body.push(WasmInst::LocalGet { idx: x });
+    body.push(WasmInst::I64Const { value: i64::MAX });
+    body.push(WasmInst::I64And);
+    body.push(WasmInst::I64Clz);
+    body.push(WasmInst::I64Sub);
+    body.push(WasmInst::LocalGet { idx: x });
+    body.push(WasmInst::I64Const { value: 0x7ff0_0000_0000_0000 });
+    body.push(WasmInst::I64And);
+    body.push(WasmInst::I64Eqz);
+    body.push(WasmInst::I32Eqz);
+    body.push(WasmInst::Select);
+}
+
+/// Multiply the f64 on the stack by 2^n, n (|n| < 2046) pushed by `n`.
+/// Both halves of the scaling are exact unless the result is subnormal,
+/// so the whole is rounded once.
+fn scale(body: &mut Vec<WasmInst>, n: impl Fn(&mut Vec<WasmInst>)) {
+    // 2^(n / 2), then 2^(n - n / 2)
+    for second in [false, true] {
+        n(body);
+        if second {
+            n(body);
+        }
+        body.push(WasmInst::I64Const { value: 2 });
+        body.push(WasmInst::I64DivS);
+        if second {
+            body.push(WasmInst::I64Sub);
+        }
+        body.push(WasmInst::I64Const { value: 1023 });
+        body.push(WasmInst::I64Add);
+        body.push(WasmInst::I64Const { value: 52 });
+        body.push(WasmInst::I64Shl);
+        body.push(WasmInst::F64ReinterpretI64);
+        body.push(WasmInst::F64Mul);
+    }
+}
+
+/// Push 0 < |local| < infinity as an i32
+fn finite_nonzero(body: &mut Vec<WasmInst>, local: u32) {
+    get(body, local);
+    body.push(WasmInst::F64Abs);
+    body.push(WasmInst::F64Const { value: 0.0 });
+    body.push(WasmInst::F64Gt);
+    magnitude(body, local, f64::INFINITY, false);
+    body.push(WasmInst::I32And);
+}
+
+/// Unless `keep_going` pushes true, store what `result` pushes to rd and
+/// leave the enclosing `depth` blocks
+fn store_unless(
+    body: &mut Vec<WasmInst>,
+    fma: &Fma,
+    depth: u32,
+    keep_going: impl Fn(&mut Vec<WasmInst>),
+    result: impl Fn(&mut Vec<WasmInst>),
+) {
+    body.push(WasmInst::Block { label: 0 });
+    keep_going(body);
+    body.push(WasmInst::BrIf { label: 0 });
+    body.push(WasmInst::LocalGet { idx: 0 });
+    result(body);
+    body.push(WasmInst::F64Store { offset: fma.offset(fma.rd) });
+    body.push(WasmInst::Br { label: depth });
+    body.push(WasmInst::End);
+}
+
+/// Scale a, b and c into the range the emulated sequence is exact for,
+/// leaving the scale of the result in K, or store the result and leave the
+/// enclosing `depth` blocks when no emulation is needed
+fn emit_scaling(body: &mut Vec<WasmInst>, fma: &Fma, depth: u32) {
+    // Infinite, NaN or zero factors, then infinite or NaN addends, then
+    // zero addends
+    store_unless(
+        body,
+        fma,
+        depth,
+        |body| {
+            finite_nonzero(body, A);
+            finite_nonzero(body, B);
+            body.push(WasmInst::I32And);
+        },
+        |body| binary(body, P, C, WasmInst::F64Add),
+    );
+    store_unless(body, fma, depth, |body| magnitude(body, C, f64::INFINITY, false), |body| get(body, C));
+    store_unless(
+        body,
+        fma,
+        depth,
+        |body| {
+            get(body, C);
+            body.push(WasmInst::F64Const { value: 0.0 });
+            body.push(WasmInst::F64Ne);
+        },
+        |body| get(body, P),
+    );
+
+    // K = exponent(a) + exponent(b), kept apart in AH and AL; BH = how far
+    // c's exponent is above K
+    exponent(body, A);
+    body.push(WasmInst::LocalSet { idx: AH });
+    exponent(body, B);
+    body.push(WasmInst::LocalSet { idx: AL });
+    body.push(WasmInst::LocalGet { idx: AH });
+    body.push(WasmInst::LocalGet { idx: AL });
+    body.push(WasmInst::I64Add);
+    body.push(WasmInst::LocalSet { idx: K });
+    exponent(body, C);
+    body.push(WasmInst::LocalGet { idx: K });
+    body.push(WasmInst::I64Sub);
+    body.push(WasmInst::LocalSet { idx: BH });
+    store_unless(
+        body,
+        fma,
+        depth,
+        |body| {
+            body.push(WasmInst::LocalGet { idx: BH });
+            body.push(WasmInst::I64Const { value: DOMINANT });
+            body.push(WasmInst::I64LeS);
+        },
+        |body| binary(body, P, C, WasmInst::F64Add),
+    );
+
+    for (x, exp) in [(A, AH), (B, AL)] {
+        get(body, x);
+        scale(body, |body| {
+            body.push(WasmInst::I64Const { value: 0 });
+            body.push(WasmInst::LocalGet { idx: exp });
+            body.push(WasmInst::I64Sub);
+        });
+        set(body, x);
+    }
+    // c = c's exponent - K < NEGLIGIBLE ? copysign(STICKY, c) : c * 2^-K
+    body.push(WasmInst::F64Const { value: STICKY });
+    get(body, C);
+    body.push(WasmInst::F64Copysign);
+    get(body, C);
+    scale(body, |body| {
+        body.push(WasmInst::I64Const { value: 0 });
+        body.push(WasmInst::LocalGet { idx: K });
+        body.push(WasmInst::I64Sub);
+    });
+    body.push(WasmInst::LocalGet { idx: BH });
+    body.push(WasmInst::I64Const { value: NEGLIGIBLE });
+    body.push(WasmInst::I64LtS);
+    body.push(WasmInst::Select);
+    set(body, C);
+    binary(body, A, B, WasmInst::F64Mul);
+    set(body, P);
+}
+
+fn emit_exact_f64(body: &mut Vec<WasmInst>, fma: &Fma) {
+    fma.load(body, fma.rs1, fma.negate_product, false);
+    set(body, A);
+    fma.load(body, fma.rs2, false, false);
+    set(body, B);
+    fma.load(body, fma.rs3, fma.negate_addend, false);
+    set(body, C);
+    binary(body, A, B, WasmInst::F64Mul);
+    set(body, P);
+    body.push(WasmInst::I64Const { value: 0 });
+    body.push(WasmInst::LocalSet { idx: K });
+
+    // Operands out of range are scaled into it first
+    body.push(WasmInst::Block { label: 0 });
+    body.push(WasmInst::Block { label: 0 });
+    magnitude(body, A, MAX_FACTOR, false);
+    magnitude(body, B, MAX_FACTOR, false);
+    body.push(WasmInst::I32And);
+    magnitude(body, A, MIN_FACTOR, true);
+    body.push(WasmInst::I32And);
+    magnitude(body, B, MIN_FACTOR, true);
+    body.push(WasmInst::I32And);
+    magnitude(body, P, MAX_TERM, false);
+    body.push(WasmInst::I32And);
+    magnitude(body, C, MAX_TERM, false);
+    body.push(WasmInst::I32And);
+    magnitude(body, P, MIN_PRODUCT, true);
+    body.push(WasmInst::I32And);
+    body.push(WasmInst::BrIf { label: 0 });
+    emit_scaling(body, fma, 2);
+    body.push(WasmInst::End);
+
+    // p + e = a * b (Dekker)
+    split(body, A, AH, AL);
+    split(body, B, BH, BL);
+    binary(body, AH, BH, WasmInst::F64Mul);
+    get(body, P);
+    body.push(WasmInst::F64Sub);
+    binary(body, AH, BL, WasmInst::F64Mul);
+    body.push(WasmInst::F64Add);
+    binary(body, AL, BH, WasmInst::F64Mul);
+    body.push(WasmInst::F64Add);
+    binary(body, AL, BL, WasmInst::F64Mul);
+    body.push(WasmInst::F64Add);
+    set(body, E);
+
+    // s + t = c + p (TwoSum)
+    binary(body, C, P, WasmInst::F64Add);
+    set(body, S);
+    binary(body, S, C, WasmInst::F64Sub);
+    set(body, T);
+    get(body, C);
+    binary(body, S, T, WasmInst::F64Sub);
+    body.push(WasmInst::F64Sub);
+    binary(body, P, T, WasmInst::F64Sub);
+    body.push(WasmInst::F64Add);
+    set(body, T);
+
+    // T = odd(t + e), leaving RN(t + e) in AH and its error in BH; then
+    // E = s + T, the sum rounded to nearest
+    sum_to_odd(body, T, E, [AH, AL, BH]);
+    set(body, T);
+    binary(body, S, T, WasmInst::F64Add);
+    set(body, E);
+
+    // AL = the sign of the exact sum minus E: that of the TwoSum error of
+    // s + T if there is one, else that of t + e - T
+    binary(body, E, S, WasmInst::F64Sub);
+    set(body, BL);
+    get(body, S);
+    binary(body, E, BL, WasmInst::F64Sub);
+    body.push(WasmInst::F64Sub);
+    binary(body, T, BL, WasmInst::F64Sub);
+    body.push(WasmInst::F64Add);
+    set(body, AL);
+    get(body, AL);
+    get(body, BH);
+    get(body, BH);
+    body.push(WasmInst::F64Neg);
+    body.push(WasmInst::LocalGet { idx: T });
+    body.push(WasmInst::LocalGet { idx: AH });
+    body.push(WasmInst::I64Eq);
+    body.push(WasmInst::Select);
+    get(body, AL);
+    body.push(WasmInst::F64Const { value: 0.0 });
+    body.push(WasmInst::F64Ne);
+    body.push(WasmInst::Select);
+    set(body, AL);
+
+    // Scaled back into the subnormals, E is rounded again. In the top
+    // binade there (spacing 2 ulps) an odd E is a tie the exact sum is off,
+    // further down (spacing 4 ulps or more) an even E could be one; moving
+    // E one ulp toward the exact sum settles either. BL = E's exponent
+    // once scaled back.
+    body.push(WasmInst::LocalGet { idx: 0 });
+    body.push(WasmInst::LocalGet { idx: E });
+    body.push(WasmInst::I64Const { value: 52 });
+    body.push(WasmInst::I64ShrU);
+    body.push(WasmInst::I64Const { value: 0x7ff });
+    body.push(WasmInst::I64And);
+    body.push(WasmInst::I64Const { value: 1023 });
+    body.push(WasmInst::I64Sub);
+    body.push(WasmInst::LocalGet { idx: K });
+    body.push(WasmInst::I64Add);
+    body.push(WasmInst::LocalSet { idx: BL });
+    // bits(E) + (nudge ? (E and AL alike in sign ? 1 : -1) : 0)
+    body.push(WasmInst::LocalGet { idx: E });
+    body.push(WasmInst::I64Const { value: 1 });
+    body.push(WasmInst::I64Const { value: -1 });
+    get(body, E);
+    body.push(WasmInst::F64Const { value: 0.0 });
+    body.push(WasmInst::F64Lt);
+    get(body, AL);
+    body.push(WasmInst::F64Const { value: 0.0 });
+    body.push(WasmInst::F64Lt);
+    body.push(WasmInst::I32Eq);
+    body.push(WasmInst::Select);
+    body.push(WasmInst::I64Const { value: 0 });
+    // nudge = AL != 0 && BL < -1022 && (E odd) == (BL == -1023)
+    get(body, AL);
+    body.push(WasmInst::F64Const { value: 0.0 });
+    body.push(WasmInst::F64Ne);
+    body.push(WasmInst::LocalGet { idx: BL });
+    body.push(WasmInst::I64Const { value: -1022 });
+    body.push(WasmInst::I64LtS);
+    body.push(WasmInst::I32And);
+    body.push(WasmInst::LocalGet { idx: E });
+    body.push(WasmInst::I64Const { value: 1 });
+    body.push(WasmInst::I64And);
+    body.push(WasmInst::I32WrapI64);
+    body.push(WasmInst::LocalGet { idx: BL });
+    body.push(WasmInst::I64Const { value: -1023 });
+    body.push(WasmInst::I64Eq);
+    body.push(WasmInst::I32Eq);
+    body.push(WasmInst::I32And);
+    body.push(WasmInst::Select);
+    body.push(WasmInst::I64Add);
+    body.push(WasmInst::F64ReinterpretI64);
+    scale(body, |body| body.push(WasmInst::LocalGet { idx: K }));
+    body.push(WasmInst::F64Store { offset: fma.offset(fma.rd) });
+    body.push(WasmInst::End);
+}
+
+fn emit_exact_f32(body: &mut Vec<WasmInst>, fma: &Fma) {
+    // a * b is exact in f64
+    fma.load(body, fma.rs1, fma.negate_product, true);
+    fma.load(body, fma.rs2, false, true);
+    body.push(WasmInst::F64Mul);
+    set(body, P);
+    fma.load(body, fma.rs3, fma.negate_addend, true);
+    set(body, C);
+
+    body.push(WasmInst::LocalGet { idx: 0 });
+    sum_to_odd(body, P, C, [AH, AL, BH]);
+    body.push(WasmInst::F32DemoteF64);
+    body.push(WasmInst::F32Store { offset: fma.offset(fma.rd) });
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::translate::WasmFunction;
+
+    /// Run `fma` (rd = f0, rs1-rs3 = f1-f3) on `a`, `b`, `c` through the IR
+    fn run_f64(mode: FmaMode, a: f64, b: f64, c: f64) -> f64 {
+        let fma = Fma { double: true, rd: 0, rs1: 1, rs2: 2, rs3: 3, negate_product: false, negate_addend: false };
+        let mut body = Vec::new();
+        emit(&mut body, &fma, mode);
+        body.push(WasmInst::I32Const { value: 0 });
+        body.push(WasmInst::Return);
+        let func = WasmFunction {
+            name: "fma".into(),
+            block_addr: 0,
+            body,
+            num_locals: NUM_LOCALS,
+            br_tables: Vec::new(),
+        };
+        let mut memory = vec![0u8; F64_REGS_OFFSET as usize + 32];
+        for (i, x) in [a, b, c].into_iter().enumerate() {
+            let at = fma.offset(i as u32 + 1) as usize;
+            memory[at..at + 8].copy_from_slice(&x.to_le_bytes());
+        }
+        crate::eval::eval(&func, &mut memory, 0).unwrap();
+        let at = fma.offset(0) as usize;
+        f64::from_le_bytes(memory[at..at + 8].try_into().unwrap())
+    }
+
+    #[test]
+    fn test_exact_fma_rounds_once() {
+        // (1 + 2^-52)(1 - 2^-52) - 1 = -2^-104, which the unfused sequence
+        // loses entirely
+        let (a, b) = (1.0 + f64::EPSILON, 1.0 - f64::EPSILON);
+        assert_eq!(run_f64(FmaMode::Unfused, a, b, -1.0), 0.0);
+        assert_eq!(run_f64(FmaMode::Exact, a, b, -1.0), a.mul_add(b, -1.0));
+        assert_eq!(run_f64(FmaMode::Relaxed, a, b, -1.0), a.mul_add(b, -1.0));
+
+        let mut seed = 0x9e37_79b9_7f4a_7c15u64;
+        let mut next = || {
+            seed ^= seed << 13;
+            seed ^= seed >> 7;
+            seed ^= seed << 17;
+            // Random significand, exponent within 2^-40..2^40 of 1, either sign
+            let exp = 1023 - 40 + (seed >> 56) % 81;
+            f64::from_bits(seed & ((1 << 63) | ((1 << 52) - 1)) | exp << 52)
+        };
+        for _ in 0..2000 {
+            let (a, b) = (next(), next());
+            // An addend close to -a*b, where cancellation exposes rounding
+            let c = -(a * b) * (1.0 + next() * 1e-12);
+            for c in [c, next()] {
+                assert_eq!(run_f64(FmaMode::Exact, a, b, c).to_bits(), a.mul_add(b, c).to_bits(), "{a} {b} {c}");
+            }
+        }
+        assert!(run_f64(FmaMode::Exact, f64::INFINITY, 0.0, 1.0).is_nan());
+        assert_eq!(run_f64(FmaMode::Exact, 1e300, 1e300, 1.0), f64::INFINITY);
+        assert_eq!(run_f64(FmaMode::Exact, 1e-300, 1e-300, 2.0), 2.0);
+    }
+
+    /// Whether `run_f64` agrees with the host's fused multiply-add, bit for
+    /// bit (any NaN matching any other)
+    fn matches_host(a: f64, b: f64, c: f64) -> bool {
+        let (ours, host) = (run_f64(FmaMode::Exact, a, b, c), a.mul_add(b, c));
+        ours.to_bits() == host.to_bits() || (ours.is_nan() && host.is_nan())
+    }
+
+    /// xorshift64 stream of doubles with a random sign and significand and
+    /// the biased exponent `exp` picks
+    fn doubles(mut seed: u64, exp: impl Fn(u64) -> u64) -> impl FnMut() -> f64 {
+        move || {
+            seed ^= seed << 13;
+            seed ^= seed >> 7;
+            seed ^= seed << 17;
+            f64::from_bits(seed & ((1 << 63) | ((1 << 52) - 1)) | exp(seed >> 52) << 52)
+        }
+    }
+
+    #[test]
+    fn test_exact_fma_overflow_boundary() {
+        // Products from 2^1019 to 2^1027, against addends near their negation
+        // and near the largest finite values
+        let mut next = doubles(0x2545_f491_4f6c_dd1d, |r| 1023 + 508 + r % 5);
+        for _ in 0..2000 {
+            let (a, b) = (next(), next());
+            let p = a * b;
+            for c in [-p * (1.0 + next() * 1e-12), f64::MAX.copysign(next()), -f64::MAX.copysign(p) / 2.0, next()] {
+                assert!(matches_host(a, b, c), "{a:e} {b:e} {c:e}");
+            }
+        }
+        assert_eq!(run_f64(FmaMode::Exact, f64::MAX, 2.0, -f64::MAX), f64::MAX);
+        assert_eq!(run_f64(FmaMode::Exact, f64::MAX, -1.5, f64::MAX), -f64::MAX / 2.0);
+    }
+
+    #[test]
+    fn test_exact_fma_subnormal_boundary() {
+        // Products from 2^-1080 to 2^-1014, alone and against addends near
+        // their negation or among the subnormals
+        let mut next = doubles(0x9e37_79b9_7f4a_7c15, |r| 1023 - 540 + r % 34);
+        let mut tiny = doubles(0x853c_49e6_748f_ea9b, |_| 0);
+        for _ in 0..3000 {
+            let (a, b) = (next(), next());
+            let p = a * b;
+            for c in [0.0, tiny(), -p * (1.0 + next() * 1e-12), f64::MIN_POSITIVE.copysign(next()), next()] {
+                assert!(matches_host(a, b, c), "{a:e} {b:e} {c:e}");
+            }
+            // Subnormal factors
+            let (a, b) = (tiny(), next() * 2f64.powi(540));
+            for c in [0.0, tiny(), next()] {
+                assert!(matches_host(a, b, c), "{a:e} {b:e} {c:e}");
+            }
+        }
+        // Larger products cancelled down to their rounding error, which is
+        // then rounded into the subnormals
+        let mut next = doubles(0x6a09_e667_f3bc_c908, |r| 1023 - 482 + r % 8);
+        for _ in 0..3000 {
+            let (a, b) = (next(), next());
+            assert!(matches_host(a, b, -(a * b)), "{a:e} {b:e}");
+        }
+        // Sums just off a tie among the subnormals, which a sum rounded to
+        // 53 bits first would break the wrong way
+        let (min, below) = (f64::from_bits(1), (1.0 - f64::EPSILON / 2.0) * 2f64.powi(-537));
+        assert_eq!(run_f64(FmaMode::Exact, below, 2f64.powi(-538), min), min);
+        assert_eq!(run_f64(FmaMode::Exact, -below, 2f64.powi(-538), min), min);
+        assert_eq!(run_f64(FmaMode::Exact, 2f64.powi(-537), 2f64.powi(-538), min).to_bits(), (2.0 * min).to_bits());
+    }
+
+    #[test]
+    fn test_exact_fma_random_bits() {
+        let mut next = doubles(0xd1b5_4a32_d192_ed03, |r| r);
+        for _ in 0..20000 {
+            let (a, b, c) = (next(), next(), next());
+            assert!(matches_host(a, b, c), "{a:e} {b:e} {c:e}");
+        }
+    }
+
+    #[test]
+    fn test_exact_fma_f32() {
+        let fma = Fma { double: false, rd: 0, rs1: 1, rs2: 2, rs3: 3, negate_product: true, negate_addend: true };
+        let mut body = Vec::new();
+        emit(&mut body, &fma, FmaMode::Exact);
+        body.push(WasmInst::I32Const { value: 0 });
+        body.push(WasmInst::Return);
+        let func = WasmFunction {
+            name: "fma".into(),
+            block_addr: 0,
+            body,
+            num_locals: NUM_LOCALS,
+            br_tables: Vec::new(),
+        };
+        let mut memory = vec![0u8; F32_REGS_OFFSET as usize + 16];
+        let (a, b, c) = (1.0 + f32::EPSILON, 1.0 - f32::EPSILON, -1.0f32);
+        for (i, x) in [a, b, c].into_iter().enumerate() {
+            let at = fma.offset(i as u32 + 1) as usize;
+            memory[at..at + 4].copy_from_slice(&x.to_le_bytes());
+        }
+        crate::eval::eval(&func, &mut memory, 0).unwrap();
+        let at = fma.offset(0) as usize;
+        // fnmadd: -(a * b) - c
+        assert_eq!(f32::from_le_bytes(memory[at..at + 4].try_into().unwrap()), (-a).mul_add(b, -c));
+    }
+}
@@ -0,0 +1,411 @@
+// freq.rs - Static block-frequency estimation
+//
+// Without a block profile, how often each block runs is guessed from the
+// CFG alone. Each conditional branch gets a taken probability from the
+// classic branch heuristics (Ball & Larus; Wu & Larus), combined
+// Dempster-Shafer style where several apply:
+//
+// - loop: a backward branch closes a loop and is taken (0.88)
+// - opcode: comparisons against zero are biased: a value is seldom
+//   negative (bltz not taken, bgez taken; 0.84), a register seldom equals
+//   zero or another (beq not taken, bne taken; 0.60)
+// - return: the successor that returns is the less likely one (0.72)
+// - call: so is the successor that makes a call (0.78)
+//
+// Every other edge is followed whenever its block runs (a call reaches both
+// the callee and, later, its return site). Block frequencies are then
+// propagated over the edges in depth-first order from blocks nothing
+// branches to (the entry, functions only called indirectly), which run
+// once. Loops are solved first, innermost out (Wu & Larus): the chance `c`
+// of coming back around to a loop's header, given that it runs, makes it
+// run `1 / (1 - c)` times per entry, so nesting multiplies. Loops are
+// natural loops within one function: a recursive call that closes a cycle
+// adds nothing, so a recursive function runs as often as its other callers
+// call it.
+//
+// The estimates order blocks for `--hot-first` without a profile
+// (`hotness.rs`) and pick the JALR inline-cache targets, and rv2wasm
+// `--emit dot|stats` prints them.
+
+use crate::cfg::{BasicBlock, ControlFlowGraph};
+use crate::disasm::Opcode;
+use std::collections::{BTreeMap, BTreeSet};
+use std::fmt::Write;
+
+const LOOP_TAKEN: f64 = 0.88;
+const SIGN_TAKEN: f64 = 0.84;
+const EQUAL_TAKEN: f64 = 0.40;
+const RETURN_TAKEN: f64 = 0.28;
+const CALL_TAKEN: f64 = 0.22;
+
+/// Frequency at which a block stops counting (recursion, runaway loops)
+pub const MAX_FREQUENCY: f64 = 1e9;
+
+/// Estimated executions per run of every block and edge
+#[derive(Debug, Clone, Default)]
+pub struct Frequencies {
+    blocks: BTreeMap<u64, f64>,
+    /// Probability of each CFG edge given that its source runs
+    edges: BTreeMap<(u64, u64), f64>,
+}
+
+impl Frequencies {
+    /// Estimated executions of the block at `addr` (0 if unknown)
+    pub fn block(&self, addr: u64) -> f64 {
+        self.blocks.get(&addr).copied().unwrap_or(0.0)
+    }
+
+    /// Probability that the block at `from` continues at `to`
+    pub fn probability(&self, from: u64, to: u64) -> f64 {
+        self.edges.get(&(from, to)).copied().unwrap_or(0.0)
+    }
+
+    /// Estimated traversals of the edge `from -> to`
+    pub fn edge(&self, from: u64, to: u64) -> f64 {
+        self.block(from) * self.probability(from, to)
+    }
+
+    /// Block frequencies as integer weights in address order, scaled so
+    /// estimates equal up to rounding noise compare equal
+    pub fn weights(&self) -> Vec<u64> {
+        self.blocks.values().map(|&f| (f * 1000.0).round() as u64).collect()
+    }
+}
+
+/// Combine two independent estimates of the same probability
+fn combine(p: f64, q: f64) -> f64 {
+    p * q / (p * q + (1.0 - p) * (1.0 - q))
+}
+
+/// Whether the block at `addr` ends in a call
+fn makes_call(cfg: &ControlFlowGraph, addr: u64) -> bool {
+    let term = cfg.blocks.get(&addr).and_then(|b| b.terminator());
+    term.is_some_and(|t| {
+        matches!(t.opcode, Opcode::JAL | Opcode::C_JAL | Opcode::JALR | Opcode::C_JALR) && t.rd.unwrap_or(0) != 0
+    })
+}
+
+/// Probability that the conditional branch ending `block` is taken to
+/// `taken` rather than falling through to `fallthrough`
+fn taken_probability(cfg: &ControlFlowGraph, block: &BasicBlock, taken: u64, fallthrough: u64) -> f64 {
+    let Some(branch) = block.terminator() else { return 0.5 };
+    let mut p = 0.5;
+    if taken <= block.start_addr {
+        p = combine(p, LOOP_TAKEN);
+    }
+    let (rs1, rs2) = (branch.rs1.unwrap_or(0), branch.rs2.unwrap_or(0));
+    p = combine(
+        p,
+        match branch.opcode {
+            Opcode::BLT if rs2 == 0 => 1.0 - SIGN_TAKEN,
+            Opcode::BGE if rs2 == 0 => SIGN_TAKEN,
+            Opcode::BLT if rs1 == 0 => SIGN_TAKEN,
+            Opcode::BGE if rs1 == 0 => 1.0 - SIGN_TAKEN,
+            Opcode::BEQ | Opcode::C_BEQZ => EQUAL_TAKEN,
+            Opcode::BNE | Opcode::C_BNEZ => 1.0 - EQUAL_TAKEN,
+            _ => 0.5,
+        },
+    );
+    let returns = |addr: u64| cfg.blocks.get(&addr).is_some_and(|b| b.is_return());
+    let unlikely = [
+        (returns(taken), returns(fallthrough), RETURN_TAKEN),
+        (makes_call(cfg, taken), makes_call(cfg, fallthrough), CALL_TAKEN),
+    ];
+    for (to_taken, to_fallthrough, taken_if_first) in unlikely {
+        match (to_taken, to_fallthrough) {
+            (true, false) => p = combine(p, taken_if_first),
+            (false, true) => p = combine(p, 1.0 - taken_if_first),
+            _ => {}
+        }
+    }
+    p
+}
+
+/// Probability of each CFG edge given that its source runs
+fn edge_probabilities(cfg: &ControlFlowGraph) -> BTreeMap<(u64, u64), f64> {
+    let mut edges = BTreeMap::new();
+    for block in cfg.blocks.values() {
+        let branch = block.terminator().is_some_and(|t| t.opcode.is_branch()) && block.direct_target.is_none();
+        match block.successors[..] {
+            [taken, fallthrough] if branch => {
+                let p = taken_probability(cfg, block, taken, fallthrough);
+                *edges.entry((block.start_addr, taken)).or_insert(0.0) += p;
+                *edges.entry((block.start_addr, fallthrough)).or_insert(0.0) += 1.0 - p;
+            }
+            ref successors => {
+                for &succ in successors {
+                    edges.insert((block.start_addr, succ), 1.0);
+                }
+            }
+        }
+    }
+    edges
+}
+
+/// Reverse postorder of a depth-first walk from `roots` and then from
+/// every block not yet reached, and the back edges it finds
+fn depth_first(roots: &[usize], succs: &[Vec<usize>]) -> (Vec<usize>, BTreeSet<(usize, usize)>) {
+    const UNSEEN: u8 = 0;
+    const ON_STACK: u8 = 1;
+    let mut state = vec![UNSEEN; succs.len()];
+    let mut post = Vec::with_capacity(succs.len());
+    let mut back = BTreeSet::new();
+    for root in roots.iter().copied().chain(0..succs.len()) {
+        if state[root] != UNSEEN {
+            continue;
+        }
+        state[root] = ON_STACK;
+        let mut stack = vec![(root, 0)];
+        while let Some(top) = stack.last_mut() {
+            let b = top.0;
+            match succs[b].get(top.1) {
+                Some(&s) => {
+                    top.1 += 1;
+                    match state[s] {
+                        UNSEEN => {
+                            state[s] = ON_STACK;
+                            stack.push((s, 0));
+                        }
+                        ON_STACK => {
+                            back.insert((b, s));
+                        }
+                        _ => {}
+                    }
+                }
+                None => {
+                    state[b] = ON_STACK + 1;
+                    post.push(b);
+                    stack.pop();
+                }
+            }
+        }
+    }
+    post.reverse();
+    (post, back)
+}
+
+/// Incoming edges by block index, as (predecessor, probability), and the
+/// back edges among them
+struct Graph<'a> {
+    preds: &'a [Vec<(usize, f64)>],
+    back: &'a BTreeSet<(usize, usize)>,
+}
+
+impl Graph<'_> {
+    /// Frequency of `b` from the predecessors `counts` accepts, with the
+    /// loops it heads (solved into `cyclic`) going around
+    fn propagate(
+        &self,
+        b: usize,
+        freq: &[f64],
+        cyclic: &BTreeMap<(usize, usize), f64>,
+        counts: impl Fn(usize) -> bool,
+    ) -> f64 {
+        let (mut forward, mut around) = (0.0, 0.0);
+        for &(p, prob) in &self.preds[b] {
+            if self.back.contains(&(p, b)) {
+                around += cyclic.get(&(p, b)).copied().unwrap_or(0.0);
+            } else if counts(p) {
+                forward += freq[p] * prob;
+            }
+        }
+        (forward / f64::max(1.0 - around, 1.0 / MAX_FREQUENCY)).min(MAX_FREQUENCY)
+    }
+}
+
+/// Estimate block and edge frequencies for `cfg`
+pub fn estimate(cfg: &ControlFlowGraph) -> Frequencies {
+    let edges = edge_probabilities(cfg);
+    let index: BTreeMap<u64, usize> = cfg.blocks.keys().enumerate().map(|(i, &a)| (a, i)).collect();
+    let n = index.len();
+    let mut succs = vec![Vec::new(); n];
+    let mut preds = vec![Vec::new(); n];
+    for (&(from, to), &p) in &edges {
+        if let Some(&to) = index.get(&to) {
+            succs[index[&from]].push(to);
+            preds[to].push((index[&from], p));
+        }
+    }
+    let mut roots: Vec<usize> = index.get(&cfg.entry).copied().into_iter().collect();
+    roots.extend((0..n).filter(|&b| preds[b].is_empty()));
+    let mut seed = vec![0.0; n];
+    for &b in &roots {
+        seed[b] = 1.0;
+    }
+    let (order, back) = depth_first(&roots, &succs);
+    let mut rank = vec![0; n];
+    for (i, &b) in order.iter().enumerate() {
+        rank[b] = i;
+    }
+
+    // Loops by header: the blocks that reach one of its back edges without
+    // passing through it, in depth-first order; the smallest (innermost)
+    // are solved first. A call closes no loop, and the flood stops at
+    // function entries (past one it would take in every caller)
+    let mut entries = vec![false; n];
+    let mut calls = BTreeSet::new();
+    for (&addr, block) in &cfg.blocks {
+        if makes_call(cfg, addr) {
+            for to in block.successors.iter().filter(|&&s| s != block.end_addr).filter_map(|s| index.get(s)) {
+                entries[*to] = true;
+                calls.insert((index[&addr], *to));
+            }
+        }
+    }
+    if let Some(&entry) = index.get(&cfg.entry) {
+        entries[entry] = true;
+    }
+    let mut latches: BTreeMap<usize, Vec<usize>> = BTreeMap::new();
+    for &(from, h) in back.difference(&calls) {
+        latches.entry(h).or_default().push(from);
+    }
+    let mut loops: Vec<(usize, Vec<usize>)> = Vec::new();
+    let mut mark = vec![usize::MAX; n];
+    for (h, mut work) in latches {
+        mark[h] = h;
+        let mut body = vec![h];
+        while let Some(b) = work.pop() {
+            if mark[b] != h && !entries[b] {
+                mark[b] = h;
+                body.push(b);
+                work.extend(preds[b].iter().map(|&(p, _)| p));
+            }
+        }
+        body.sort_by_key(|&b| rank[b]);
+        loops.push((h, body));
+    }
+    loops.sort_by_key(|(_, body)| body.len());
+
+    // Each loop on its own, its header running once: how likely each back
+    // edge is to be taken
+    let graph = Graph { preds: &preds, back: &back };
+    let mut cyclic = BTreeMap::new();
+    let mut local = vec![0.0; n];
+    let mut owner = vec![usize::MAX; n];
+    for (i, (h, body)) in loops.iter().enumerate() {
+        for &b in body {
+            owner[b] = i;
+        }
+        for &b in body {
+            local[b] = if b == *h { 1.0 } else { graph.propagate(b, &local, &cyclic, |p| owner[p] == i) };
+        }
+        for &(p, prob) in &preds[*h] {
+            if back.contains(&(p, *h)) && owner[p] == i {
+                cyclic.insert((p, *h), local[p] * prob);
+            }
+        }
+    }
+
+    let mut freq = vec![0.0; n];
+    for &b in &order {
+        freq[b] = (seed[b] + graph.propagate(b, &freq, &cyclic, |_| true)).min(MAX_FREQUENCY);
+    }
+
+    Frequencies { blocks: cfg.blocks.keys().copied().zip(freq).collect(), edges }
+}
+
+/// The CFG as a Graphviz digraph, blocks and edges labelled with their
+/// estimated frequencies
+pub fn render_dot(cfg: &ControlFlowGraph, freq: &Frequencies) -> String {
+    let mut out = String::from("digraph cfg {\n  node [shape=box fontname=monospace];\n");
+    for (&addr, block) in &cfg.blocks {
+        let _ = writeln!(
+            out,
+            "  b{:x} [label=\"0x{:x}\\n{} insts\\nfreq {:.2}\"];",
+            addr,
+            addr,
+            block.instructions.len(),
+            freq.block(addr)
+        );
+        for &succ in block.successors.iter().filter(|s| cfg.blocks.contains_key(s)) {
+            let _ = writeln!(out, "  b{:x} -> b{:x} [label=\"{:.2}\"];", addr, succ, freq.edge(addr, succ));
+        }
+    }
+    out.push_str("}\n");
+    out
+}
+
+/// Summary statistics: block and function counts, and the hottest blocks
+pub fn render_stats(cfg: &ControlFlowGraph, freq: &Frequencies, top: usize) -> String {
+    let instructions: usize = cfg.blocks.values().map(|b| b.instructions.len()).sum();
+    let mut out = String::new();
+    let _ = writeln!(out, "blocks: {}", cfg.blocks.len());
+    let _ = writeln!(out, "functions: {}", cfg.functions.len());
+    let _ = writeln!(out, "instructions: {}", instructions);
+    let mut hot: Vec<(u64, f64)> = cfg.blocks.keys().map(|&a| (a, freq.block(a))).collect();
+    hot.sort_by(|a, b| b.1.total_cmp(&a.1));
+    let _ = writeln!(out, "hottest blocks (estimated executions per run):");
+    for (addr, f) in hot.into_iter().take(top) {
+        let _ = writeln!(out, "  0x{:x} {:.2}", addr, f);
+    }
+    out
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_util::inst;
+
+    #[test]
+    fn test_estimate() {
+        // 0x0: bltz a0, 0x20 (rarely negative)
+        // 0x4..0x10: outer loop around 0x8..0xc, an inner loop on bne a0, a1
+        // 0x14: ecall; 0x20: ecall
+        let code = [
+            inst(0x0, Opcode::BLT, 10, 10, 0, 0x20),
+            inst(0x4, Opcode::ADDI, 10, 10, 0, 1),
+            inst(0x8, Opcode::ADDI, 10, 10, 0, 1),
+            inst(0xc, Opcode::BNE, 10, 10, 11, -4),
+            inst(0x10, Opcode::BNE, 10, 10, 11, -12),
+            inst(0x14, Opcode::ECALL, 10, 0, 0, 0),
+            inst(0x20, Opcode::ECALL, 10, 0, 0, 0),
+        ];
+        let cfg = crate::cfg::build(&code, 0).unwrap();
+        let freq = estimate(&cfg);
+
+        assert!((freq.probability(0x0, 0x20) - 0.16).abs() < 1e-9);
+        assert!((freq.edge(0x0, 0x20) + freq.edge(0x0, 0x4) - 1.0).abs() < 1e-9);
+        // A loop branch that is also a bne: taken 0.88 and 0.60 combined
+        let taken = combine(LOOP_TAKEN, 1.0 - EQUAL_TAKEN);
+        let outer = 0.84 / (1.0 - taken);
+        assert!((freq.block(0x4) - outer).abs() < 1e-9);
+        assert!((freq.block(0x8) - outer / (1.0 - taken)).abs() < 1e-9);
+        // What enters a loop leaves it
+        assert!((freq.block(0x14) - 0.84).abs() < 1e-9);
+        assert!(freq.block(0x8) > freq.block(0x4) && freq.block(0x4) > freq.block(0x0));
+
+        let dot = render_dot(&cfg, &freq);
+        assert!(dot.starts_with("digraph cfg {") && dot.contains("b0 -> b20 [label=\"0.16\"];"));
+        assert!(render_stats(&cfg, &freq, 1).contains("hottest blocks (estimated executions per run):\n  0x8 "));
+    }
+
+    #[test]
+    fn test_loops_stay_in_their_function() {
+        // main: calls f, then g
+        // f: calls g
+        // g: a loop at 0x204, then calls f back unless a0 is zero
+        let code = [
+            inst(0x0, Opcode::JAL, 1, 0, 0, 0x100),
+            inst(0x4, Opcode::JAL, 1, 0, 0, 0x1fc),
+            inst(0x8, Opcode::ECALL, 10, 0, 0, 0),
+            inst(0x100, Opcode::JAL, 1, 0, 0, 0x100),
+            inst(0x104, Opcode::JALR, 0, 1, 0, 0),
+            inst(0x200, Opcode::ADDI, 10, 10, 0, 1),
+            inst(0x204, Opcode::ADDI, 10, 10, 0, 1),
+            inst(0x208, Opcode::BNE, 10, 10, 11, -4),
+            inst(0x20c, Opcode::BEQ, 10, 10, 0, 8),
+            inst(0x210, Opcode::JAL, 1, 0, 0, -0x110),
+            inst(0x214, Opcode::JALR, 0, 1, 0, 0),
+        ];
+        let cfg = crate::cfg::build(&code, 0).unwrap();
+        let freq = estimate(&cfg);
+
+        // The call from g back into f closes no loop taking in main
+        for addr in [0x0, 0x4, 0x8, 0x200] {
+            assert!((freq.block(addr) - 1.0).abs() < 1e-9, "0x{:x}", addr);
+        }
+        let taken = combine(LOOP_TAKEN, 1.0 - EQUAL_TAKEN);
+        assert!((freq.block(0x204) - 1.0 / (1.0 - taken)).abs() < 1e-9);
+        assert!((freq.block(0x100) - 1.0 - freq.edge(0x20c, 0x210)).abs() < 1e-9);
+    }
+}
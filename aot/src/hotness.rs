@@ -0,0 +1,169 @@
+// hotness.rs - Block hotness for function layout
+//
+// Orders block functions so the hottest ones get the lowest function and
+// table indices: the dispatch br_table's hot entries then share cache lines
+// and engines that tier up lazily compile them first. Weights come from a
+// block profile written by `friscy-run --block-profile`, or are estimated
+// from the CFG (`freq.rs`) when no profile is given.
+//
+// The same weights pick the blocks worth compiling ahead of time for a
+// hot/cold split (`hot_set`); the rest are left to the lazy compile hook.
+//
+// Profile format, one block per line (`#` starts a comment):
+//
+//   0x10a4c 1203
+//   0x10a60 17
+
+use crate::cfg::ControlFlowGraph;
+use anyhow::{Context, Result};
+use std::collections::{BTreeMap, BTreeSet};
+use std::io::{self, Write};
+
+/// Execution counts keyed by block start address
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct BlockProfile {
+    counts: BTreeMap<u64, u64>,
+}
+
+impl BlockProfile {
+    pub fn from_counts(counts: impl IntoIterator<Item = (u64, u64)>) -> Self {
+        let mut profile = Self::default();
+        for (addr, count) in counts {
+            *profile.counts.entry(addr).or_default() += count;
+        }
+        profile
+    }
+
+    /// Parse the text format written by `write`
+    pub fn parse(text: &str) -> Result<Self> {
+        let mut counts = Vec::new();
+        for (lineno, line) in text.lines().enumerate() {
+            let line = line.split('#').next().unwrap_or("").trim();
+            if line.is_empty() {
+                continue;
+            }
+            let parse = || -> Option<(u64, u64)> {
+                let mut fields = line.split_whitespace();
+                let addr = fields.next()?;
+                let addr = u64::from_str_radix(addr.strip_prefix("0x").unwrap_or(addr), 16).ok()?;
+                let count = fields.next()?.parse().ok()?;
+                fields.next().is_none().then_some((addr, count))
+            };
+            counts.push(parse().with_context(|| format!("block profile line {}: {:?}", lineno + 1, line))?);
+        }
+        Ok(Self::from_counts(counts))
+    }
+
+    pub fn write<W: Write>(&self, mut out: W) -> io::Result<()> {
+        for (addr, count) in &self.counts {
+            writeln!(out, "0x{:x} {}", addr, count)?;
+        }
+        Ok(())
+    }
+
+    /// Total count of samples inside `[start, end)`. Profiles from another
+    /// block partition (the runtime splits blocks differently) still line up.
+    pub fn range(&self, start: u64, end: u64) -> u64 {
+        self.counts.range(start..end.max(start)).map(|(_, &c)| c).sum()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.counts.is_empty()
+    }
+}
+
+/// Estimated weight of each block in address order (`freq::estimate`)
+pub fn estimate(cfg: &ControlFlowGraph) -> Vec<u64> {
+    crate::freq::estimate(cfg).weights()
+}
+
+/// Block addresses of `cfg`, hottest first. Equal weights keep address order,
+/// so cold code (and a profile that never reached it) stays sequential.
+pub fn hot_first(cfg: &ControlFlowGraph, profile: Option<&BlockProfile>) -> Vec<u64> {
+    let weights = match profile {
+        Some(profile) => cfg.blocks.values().map(|b| profile.range(b.start_addr, b.end_addr)).collect(),
+        None => estimate(cfg),
+    };
+    let mut order: Vec<(u64, u64)> = cfg.blocks.keys().copied().zip(weights).collect();
+    order.sort_by_key(|&(_, weight)| std::cmp::Reverse(weight));
+    order.into_iter().map(|(addr, _)| addr).collect()
+}
+
+/// Blocks to compile ahead of time in a hot/cold split. Without a profile,
+/// every block statically reachable from `roots` (calls included; indirect
+/// targets are not known). With one, the guest functions holding a sampled
+/// block, plus the blocks at `roots`.
+pub fn hot_set(cfg: &ControlFlowGraph, profile: Option<&BlockProfile>, roots: &[u64]) -> BTreeSet<u64> {
+    let mut hot: BTreeSet<u64> = roots.iter().copied().filter(|a| cfg.blocks.contains_key(a)).collect();
+    let Some(profile) = profile else {
+        let mut work: Vec<u64> = hot.iter().copied().collect();
+        while let Some(addr) = work.pop() {
+            for &succ in &cfg.blocks[&addr].successors {
+                if cfg.blocks.contains_key(&succ) && hot.insert(succ) {
+                    work.push(succ);
+                }
+            }
+        }
+        return hot;
+    };
+
+    let sampled = |addr: &u64| cfg.blocks.get(addr).is_some_and(|b| profile.range(b.start_addr, b.end_addr) > 0);
+    let mut owned = BTreeSet::new();
+    for func in &cfg.functions {
+        owned.extend(func.blocks.iter().copied());
+        if func.blocks.iter().any(sampled) {
+            hot.extend(func.blocks.iter().copied());
+        }
+    }
+    // Blocks outside every function count on their own
+    hot.extend(cfg.blocks.keys().filter(|a| !owned.contains(a) && sampled(a)));
+    hot
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::disasm::{Instruction, Opcode};
+    use crate::test_util::inst;
+
+    #[test]
+    fn test_hot_first() {
+        // 0x0: entry, 0x4..0x10: loop closed by a backward branch, 0x10: exit
+        let code = [
+            inst(0x0, Opcode::ADDI, 10, 10, 0, 1),
+            inst(0x4, Opcode::ADDI, 10, 10, 0, 1),
+            inst(0x8, Opcode::ADD, 10, 10, 0, 0),
+            inst(0xc, Opcode::BNE, 10, 10, 0, -8),
+            inst(0x10, Opcode::ECALL, 10, 10, 0, 0),
+        ];
+        let cfg = crate::cfg::build(&code, 0).unwrap();
+        assert_eq!(hot_first(&cfg, None), [0x4, 0x0, 0x10]);
+
+        let profile = BlockProfile::parse("# samples\n0x10 5\n0x0 2\n").unwrap();
+        assert_eq!(hot_first(&cfg, Some(&profile))[..2], [0x10, 0x0]);
+
+        let mut text = Vec::new();
+        profile.write(&mut text).unwrap();
+        assert_eq!(BlockProfile::parse(std::str::from_utf8(&text).unwrap()).unwrap(), profile);
+        assert!(BlockProfile::parse("0x10 five").is_err());
+    }
+
+    #[test]
+    fn test_hot_set() {
+        // 0x0: call f; 0x4: ecall -- f (0x10): beq a0, a0, 0x14; ret -- 0x20, never reached: addi; ret
+        let ret = inst(0x14, Opcode::JALR, 0, 1, 0, 0);
+        let code = [
+            inst(0x0, Opcode::JAL, 1, 10, 0, 0x10),
+            inst(0x4, Opcode::ECALL, 10, 10, 0, 0),
+            inst(0x10, Opcode::BEQ, 10, 10, 0, 4),
+            ret.clone(),
+            inst(0x20, Opcode::ADDI, 10, 10, 0, 1),
+            Instruction { addr: 0x24, ..ret },
+        ];
+        let cfg = crate::cfg::build(&code, 0).unwrap();
+        assert_eq!(hot_set(&cfg, None, &[0]).into_iter().collect::<Vec<_>>(), [0x0, 0x4, 0x10, 0x14]);
+
+        let profile = BlockProfile::parse("0x14 3\n").unwrap();
+        assert_eq!(hot_set(&cfg, Some(&profile), &[0]).into_iter().collect::<Vec<_>>(), [0x0, 0x10, 0x14]);
+    }
+}
@@ -0,0 +1,211 @@
+// incremental.rs - Recompiling against the previous output
+//
+// `rv2wasm --incremental PREV` splices the block functions of the previous
+// build, PREV (usually the output being replaced), into the new module for
+// every guest function that did not change, and translates only the rest:
+// an edit-compile-run loop on a large guest retranslates the functions
+// that were edited. A function is unchanged when its key is: the same key
+// `cache.rs` uses (its blocks' instructions and addresses, inline-cache
+// targets and the options that shape block code, seeded with the compiler
+// version), so a function that moved is retranslated too.
+//
+// The keys travel in the module, in the `friscy.functions` custom section
+// after the block map: version (u32), function count (u32), then per
+// function its 32-byte hex key, block count (u32) and block addresses
+// (u64), all little-endian. Only modules built this way carry it; a PREV
+// without it (or missing altogether) gives a full build that does. Size
+// mode and split output have no per-block bodies to splice and are always
+// built in full.
+
+use crate::cache::{function_groups, CacheStats};
+use crate::cfg::ControlFlowGraph;
+use crate::elf::ElfInfo;
+use crate::link::{self, read_leb};
+use crate::options::CompileOptions;
+use crate::{translate, wasm_builder, StreamingBuilder};
+use anyhow::{bail, Context, Result};
+use std::borrow::Cow;
+use std::collections::HashMap;
+use std::io::{Seek, Write};
+use wasm_encoder::CustomSection;
+
+/// Name of the function key section
+pub const FUNCTIONS: &str = "friscy.functions";
+/// Bumped whenever the section layout changes
+const FUNCTIONS_VERSION: u32 = 1;
+/// Length of a key (`cache.rs` keys are 128-bit hashes in hex)
+const KEY_LEN: usize = 32;
+
+/// What a previous build offers: its encoded block functions by address,
+/// and the blocks of each guest function by key
+#[derive(Debug, Default)]
+pub struct Previous {
+    bodies: HashMap<u64, Vec<u8>>,
+    functions: HashMap<String, Vec<u64>>,
+}
+
+impl Previous {
+    /// Read a module built by `emit_streaming`. A module without the key
+    /// section offers nothing.
+    pub fn parse(wasm: &[u8]) -> Result<Self> {
+        let Some(keys) = link::custom_section(wasm, FUNCTIONS)? else {
+            return Ok(Self::default());
+        };
+        let functions = parse_functions(keys)?;
+        let block_addrs = link::block_map(wasm)?;
+        let (_, code) = link::sections(wasm)?
+            .into_iter()
+            .find(|&(id, _)| id == wasm_encoder::SectionId::Code as u8)
+            .context("Module has no code section")?;
+
+        // The dispatch function, the block functions in block map order,
+        // then the helpers; each entry is kept size prefix and all
+        let (count, mut rest) = read_leb(code)?;
+        if (count as usize) < block_addrs.len() + 1 {
+            bail!("Code section has {} functions for {} blocks", count, block_addrs.len());
+        }
+        let mut bodies = HashMap::with_capacity(block_addrs.len());
+        for i in 0..=block_addrs.len() {
+            let (size, body) = read_leb(rest)?;
+            let end = rest.len() - body.len() + size as usize;
+            let entry = rest.get(..end).context("Truncated function body")?;
+            if i > 0 {
+                bodies.insert(block_addrs[i - 1], entry.to_vec());
+            }
+            rest = &rest[end..];
+        }
+        Ok(Self { bodies, functions })
+    }
+
+    /// The encoded blocks of the function with `key` at `addrs`, if this
+    /// build has it
+    fn function(&self, key: &str, addrs: &[u64]) -> Option<Vec<(u64, &[u8])>> {
+        if self.functions.get(key).map(Vec::as_slice) != Some(addrs) {
+            return None;
+        }
+        addrs.iter().map(|addr| Some((*addr, &self.bodies.get(addr)?[..]))).collect()
+    }
+}
+
+/// The key section for guest functions `groups` as (key, block addresses)
+pub fn functions_section(groups: &[(String, Vec<u64>)]) -> CustomSection<'static> {
+    let mut data = Vec::new();
+    data.extend(FUNCTIONS_VERSION.to_le_bytes());
+    data.extend((groups.len() as u32).to_le_bytes());
+    for (key, addrs) in groups {
+        data.extend(key.as_bytes());
+        data.extend((addrs.len() as u32).to_le_bytes());
+        addrs.iter().for_each(|addr| data.extend(addr.to_le_bytes()));
+    }
+    CustomSection { name: Cow::Borrowed(FUNCTIONS), data: Cow::Owned(data) }
+}
+
+fn parse_functions(data: &[u8]) -> Result<HashMap<String, Vec<u64>>> {
+    let mut rest = data;
+    let mut take = |len: usize| -> Result<&[u8]> {
+        let bytes = rest.get(..len).context("Truncated function key section")?;
+        rest = &rest[len..];
+        Ok(bytes)
+    };
+    let word = |bytes: &[u8]| u32::from_le_bytes(bytes.try_into().unwrap());
+    let version = word(take(4)?);
+    if version != FUNCTIONS_VERSION {
+        bail!("Function key section version {} (expected {})", version, FUNCTIONS_VERSION);
+    }
+    let mut functions = HashMap::new();
+    for _ in 0..word(take(4)?) {
+        let key = String::from_utf8(take(KEY_LEN)?.to_vec()).context("Malformed function key")?;
+        let count = word(take(4)?) as usize;
+        let addrs = take(count * 8)?.chunks_exact(8).map(|a| u64::from_le_bytes(a.try_into().unwrap())).collect();
+        functions.insert(key, addrs);
+    }
+    Ok(functions)
+}
+
+/// `crate::emit_streaming`, taking the block functions of every unchanged
+/// guest function from `previous` and recording the keys for the next
+/// build. Holds all encoded block functions in memory.
+pub fn emit_streaming<W: Write + Seek>(
+    previous: &Previous,
+    cfg: &ControlFlowGraph,
+    elf_info: &ElfInfo,
+    options: &CompileOptions,
+    out: W,
+) -> Result<(W, CacheStats)> {
+    let layout = translate::layout(cfg, elf_info, options);
+    if layout.size {
+        return Ok((crate::emit_streaming(cfg, elf_info, options, out)?, CacheStats::default()));
+    }
+
+    let groups = function_groups(cfg, options);
+    let mut stats = CacheStats::default();
+    let mut bodies: HashMap<u64, Vec<u8>> = HashMap::new();
+    let mut todo = Vec::new();
+    for (key, addrs) in &groups {
+        match previous.function(key, addrs) {
+            Some(reused) => {
+                bodies.extend(reused.into_iter().map(|(addr, bytes)| (addr, bytes.to_vec())));
+                stats.hits += 1;
+            }
+            None => {
+                todo.extend(addrs.iter().copied());
+                stats.misses += 1;
+            }
+        }
+    }
+    translate::translate_only(cfg, options, &todo, |func| {
+        bodies.insert(func.block_addr, wasm_builder::encode_block(&func)?);
+        Ok(())
+    })?;
+
+    let mut builder = StreamingBuilder::new(out, &layout)?;
+    for addr in &layout.block_addrs {
+        builder.push_encoded(*addr, &bodies[addr])?;
+    }
+    builder.add_custom_section(functions_section(&groups));
+    Ok((builder.finish()?, stats))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::disasm::{Instruction, Opcode};
+    use crate::test_util::{elf_info, inst};
+
+    #[test]
+    fn test_incremental_rebuild_splices_unchanged_functions() {
+        // main: jal f; ecall -- f: addi; ret
+        let mut code = vec![
+            inst(0x1000, Opcode::JAL, 1, 10, 0, 0x10),
+            inst(0x1004, Opcode::ECALL, 10, 10, 0, 0),
+            inst(0x1010, Opcode::ADDI, 10, 10, 0, 1),
+            inst(0x1014, Opcode::JALR, 0, 1, 0, 0),
+        ];
+        // Blocks are keyed by their encodings, so give each its own
+        code.iter_mut().for_each(|inst| inst.bytes = inst.addr as u32);
+        let elf_info = elf_info();
+        let options = CompileOptions::default();
+        let emit = |code: &[Instruction], previous: &Previous| {
+            let cfg = crate::cfg::build(code, 0x1000).unwrap();
+            let (out, stats) =
+                emit_streaming(previous, &cfg, &elf_info, &options, std::io::Cursor::new(Vec::new())).unwrap();
+            (out.into_inner(), stats)
+        };
+
+        // Without a previous build everything is translated
+        let (first, stats) = emit(&code, &Previous::default());
+        assert_eq!(stats, CacheStats { hits: 0, misses: 2 });
+        let previous = Previous::parse(&first).unwrap();
+        let (again, stats) = emit(&code, &previous);
+        assert_eq!((again == first, stats), (true, CacheStats { hits: 2, misses: 0 }));
+
+        // Touching f keeps main's blocks and matches a full build
+        code[2].imm = Some(2);
+        code[2].bytes ^= 1;
+        let (edited, stats) = emit(&code, &previous);
+        assert_eq!(stats, CacheStats { hits: 1, misses: 1 });
+        let cfg = crate::cfg::build(&code, 0x1000).unwrap();
+        let full = crate::emit_streaming(&cfg, &elf_info, &options, std::io::Cursor::new(Vec::new())).unwrap();
+        assert!(edited.starts_with(&full.into_inner()));
+    }
+}
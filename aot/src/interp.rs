@@ -0,0 +1,380 @@
+// interp.rs - Reference interpreter for decoded instructions
+//
+// Executes `Instruction`s directly against a register file and a flat byte
+// memory, independently of the Wasm translation. It is the oracle for
+// differential testing: the same block run here and through
+// translate → wasm_builder → a Wasm engine must leave identical state.
+//
+// Covers RV64IMA and the C extension. F/D instructions are reported as
+// unsupported. Guest addresses index `mem` directly, as in the generated
+// code.
+
+use crate::disasm::{Instruction, Opcode};
+use anyhow::{bail, Context, Result};
+
+/// Architectural state of one hart
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Hart {
+    pub x: [u64; 32],
+    pub pc: u64,
+    /// LR/SC reservation address
+    pub reservation: Option<u64>,
+}
+
+impl Hart {
+    pub fn new(pc: u64) -> Self {
+        Self {
+            x: [0; 32],
+            pc,
+            reservation: None,
+        }
+    }
+}
+
+/// What executing one instruction did
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Step {
+    /// `pc` now holds the next instruction
+    Next,
+    /// ECALL at `pc` (left unchanged for the host)
+    Ecall,
+    /// EBREAK at `pc`
+    Ebreak,
+}
+
+/// Execute the instructions of a block until one leaves it (taken branch,
+/// jump, ECALL/EBREAK) or the block ends. Returns the last step.
+pub fn run_block(hart: &mut Hart, mem: &mut [u8], instructions: &[Instruction]) -> Result<Step> {
+    for inst in instructions {
+        let step = execute(hart, mem, inst)?;
+        if step != Step::Next || hart.pc != inst.addr + inst.len as u64 {
+            return Ok(step);
+        }
+    }
+    Ok(Step::Next)
+}
+
+/// Execute one instruction
+pub fn execute(hart: &mut Hart, mem: &mut [u8], inst: &Instruction) -> Result<Step> {
+    use Opcode::*;
+
+    let rd = inst.rd.unwrap_or(0) as usize;
+    let a = hart.x[inst.rs1.unwrap_or(0) as usize];
+    let b = hart.x[inst.rs2.unwrap_or(0) as usize];
+    let imm = inst.imm.unwrap_or(0);
+    let uimm = imm as u64;
+    let next = inst.addr.wrapping_add(inst.len as u64);
+    let addr = a.wrapping_add(uimm);
+
+    let mut result: Option<u64> = None;
+    let mut target = next;
+
+    if let Some(size) = inst.opcode.atomic_size() {
+        if !a.is_multiple_of(size) {
+            bail!("Misaligned atomic access to 0x{:x} at 0x{:x}", a, inst.addr);
+        }
+    }
+
+    match inst.opcode {
+        LUI | C_LUI => result = Some(uimm),
+        AUIPC => result = Some(inst.addr.wrapping_add(uimm)),
+
+        JAL | C_J | C_JAL => {
+            result = Some(next);
+            target = inst.addr.wrapping_add(uimm);
+        }
+        JALR | C_JR | C_JALR => {
+            result = Some(next);
+            target = addr & !1;
+        }
+
+        BEQ | BNE | BLT | BGE | BLTU | BGEU | C_BEQZ | C_BNEZ => {
+            let taken = match inst.opcode {
+                BEQ | C_BEQZ => a == b,
+                BNE | C_BNEZ => a != b,
+                BLT => (a as i64) < (b as i64),
+                BGE => (a as i64) >= (b as i64),
+                BLTU => a < b,
+                _ => a >= b,
+            };
+            if taken {
+                target = inst.addr.wrapping_add(uimm);
+            }
+        }
+
+        LB => result = Some(load(mem, addr, 1)? as i8 as u64),
+        LH => result = Some(load(mem, addr, 2)? as i16 as u64),
+        LW | C_LW | C_LWSP => result = Some(load(mem, addr, 4)? as i32 as u64),
+        LD | C_LD | C_LDSP => result = Some(load(mem, addr, 8)?),
+        LBU => result = Some(load(mem, addr, 1)?),
+        LHU => result = Some(load(mem, addr, 2)?),
+        LWU => result = Some(load(mem, addr, 4)?),
+        SB => store(mem, addr, 1, b)?,
+        SH => store(mem, addr, 2, b)?,
+        SW | C_SW | C_SWSP => store(mem, addr, 4, b)?,
+        SD | C_SD | C_SDSP => store(mem, addr, 8, b)?,
+
+        ADDI | C_ADDI | C_LI | C_ADDI4SPN | C_ADDI16SP => result = Some(addr),
+        SLTI => result = Some(((a as i64) < imm) as u64),
+        SLTIU => result = Some((a < uimm) as u64),
+        XORI => result = Some(a ^ uimm),
+        ORI => result = Some(a | uimm),
+        ANDI | C_ANDI => result = Some(a & uimm),
+        SLLI | C_SLLI => result = Some(a << (imm & 0x3f)),
+        SRLI | C_SRLI => result = Some(a >> (imm & 0x3f)),
+        SRAI | C_SRAI => result = Some(((a as i64) >> (imm & 0x3f)) as u64),
+        ADDIW | C_ADDIW => result = Some(sext32(addr)),
+        SLLIW => result = Some(sext32((a as u32).wrapping_shl(imm as u32 & 0x1f) as u64)),
+        SRLIW => result = Some(sext32(((a as u32) >> (imm & 0x1f)) as u64)),
+        SRAIW => result = Some(((a as i32) >> (imm & 0x1f)) as i64 as u64),
+
+        ADD | C_ADD => result = Some(a.wrapping_add(b)),
+        C_MV => result = Some(b),
+        SUB | C_SUB => result = Some(a.wrapping_sub(b)),
+        SLL => result = Some(a << (b & 0x3f)),
+        SLT => result = Some(((a as i64) < (b as i64)) as u64),
+        SLTU => result = Some((a < b) as u64),
+        XOR | C_XOR => result = Some(a ^ b),
+        SRL => result = Some(a >> (b & 0x3f)),
+        SRA => result = Some(((a as i64) >> (b & 0x3f)) as u64),
+        OR | C_OR => result = Some(a | b),
+        AND | C_AND => result = Some(a & b),
+        ADDW | C_ADDW => result = Some(sext32(a.wrapping_add(b))),
+        SUBW | C_SUBW => result = Some(sext32(a.wrapping_sub(b))),
+        SLLW => result = Some(sext32((a as u32).wrapping_shl(b as u32 & 0x1f) as u64)),
+        SRLW => result = Some(sext32(((a as u32) >> (b & 0x1f)) as u64)),
+        SRAW => result = Some(((a as i32) >> (b & 0x1f)) as i64 as u64),
+
+        MUL => result = Some(a.wrapping_mul(b)),
+        MULH => result = Some(((a as i64 as i128 * b as i64 as i128) >> 64) as u64),
+        MULHSU => result = Some(((a as i64 as i128 * b as u128 as i128) >> 64) as u64),
+        MULHU => result = Some(((a as u128 * b as u128) >> 64) as u64),
+        DIV => {
+            result = Some(match (a as i64, b as i64) {
+                (_, 0) => u64::MAX,
+                (i64::MIN, -1) => a,
+                (x, y) => (x / y) as u64,
+            })
+        }
+        DIVU => result = Some(a.checked_div(b).unwrap_or(u64::MAX)),
+        REM => {
+            result = Some(match (a as i64, b as i64) {
+                (_, 0) => a,
+                (i64::MIN, -1) => 0,
+                (x, y) => (x % y) as u64,
+            })
+        }
+        REMU => result = Some(if b == 0 { a } else { a % b }),
+        MULW => result = Some(sext32((a as u32).wrapping_mul(b as u32) as u64)),
+        DIVW => {
+            let q = match (a as i32, b as i32) {
+                (_, 0) => -1,
+                (i32::MIN, -1) => i32::MIN,
+                (x, y) => x / y,
+            };
+            result = Some(q as i64 as u64)
+        }
+        DIVUW => result = Some(sext32((a as u32).checked_div(b as u32).unwrap_or(u32::MAX) as u64)),
+        REMW => {
+            let r = match (a as i32, b as i32) {
+                (x, 0) => x,
+                (i32::MIN, -1) => 0,
+                (x, y) => x % y,
+            };
+            result = Some(r as i64 as u64)
+        }
+        REMUW => {
+            let (x, y) = (a as u32, b as u32);
+            result = Some(sext32(if y == 0 { x } else { x % y } as u64))
+        }
+
+        LR_W | LR_D => {
+            let width = if inst.opcode == LR_W { 4 } else { 8 };
+            let v = load(mem, a, width)?;
+            hart.reservation = Some(a);
+            result = Some(if width == 4 { v as i32 as u64 } else { v });
+        }
+        SC_W | SC_D => {
+            let width = if inst.opcode == SC_W { 4 } else { 8 };
+            let ok = hart.reservation.take() == Some(a);
+            if ok {
+                store(mem, a, width, b)?;
+            }
+            result = Some(!ok as u64);
+        }
+        AMOSWAP_W | AMOADD_W | AMOXOR_W | AMOAND_W | AMOOR_W | AMOMIN_W | AMOMAX_W | AMOMINU_W
+        | AMOMAXU_W => {
+            let old = load(mem, a, 4)? as i32;
+            let new = amo(inst.opcode, old as i64 as u64, b, true);
+            store(mem, a, 4, new)?;
+            result = Some(old as i64 as u64);
+        }
+        AMOSWAP_D | AMOADD_D | AMOXOR_D | AMOAND_D | AMOOR_D | AMOMIN_D | AMOMAX_D | AMOMINU_D
+        | AMOMAXU_D => {
+            let old = load(mem, a, 8)?;
+            store(mem, a, 8, amo(inst.opcode, old, b, false))?;
+            result = Some(old);
+        }
+
+        AMOCAS_W | AMOCAS_D => {
+            let width = if inst.opcode == AMOCAS_W { 4 } else { 8 };
+            let mask = if width == 4 { u32::MAX as u64 } else { u64::MAX };
+            let old = load(mem, a, width)?;
+            if old == hart.x[rd] & mask {
+                store(mem, a, width, b)?;
+            }
+            result = Some(if width == 4 { sext32(old) } else { old });
+        }
+        AMOCAS_Q => {
+            // x0 as a pair reads as zero and ignores writes
+            let pair = |x: &[u64; 32], r: usize| if r == 0 { [0, 0] } else { [x[r], x[r + 1]] };
+            let old = [load(mem, a, 8)?, load(mem, a.wrapping_add(8), 8)?];
+            if old == pair(&hart.x, rd) {
+                let new = pair(&hart.x, inst.rs2.unwrap_or(0) as usize);
+                store(mem, a, 8, new[0])?;
+                store(mem, a.wrapping_add(8), 8, new[1])?;
+            }
+            if rd != 0 {
+                hart.x[rd..rd + 2].copy_from_slice(&old);
+            }
+        }
+
+        op if op.narrow_amo().is_some() => {
+            let (word, width) = op.narrow_amo().unwrap();
+            let bits = 64 - 8 * width as u32;
+            let sext = |v: u64| ((v << bits) as i64 >> bits) as u64;
+            let zext = |v: u64| v << bits >> bits;
+            let old = sext(load(mem, a, width as usize)?);
+            let new = match word {
+                AMOCAS_W if zext(old) == zext(hart.x[rd]) => Some(b),
+                AMOCAS_W => None,
+                AMOMIN_W | AMOMAX_W => Some(amo(word, old, sext(b), false)),
+                AMOMINU_W | AMOMAXU_W => Some(amo(word, zext(old), zext(b), false)),
+                _ => Some(amo(word, old, b, false)),
+            };
+            if let Some(new) = new {
+                store(mem, a, width as usize, new)?;
+            }
+            result = Some(old);
+        }
+
+        AES64ES | AES64ESM | AES64DS | AES64DSM | AES64IM | AES64KS1I => {
+            use crate::crypto;
+            let (op, rs2) = match inst.opcode {
+                AES64ES => (crypto::AES64ES, b),
+                AES64ESM => (crypto::AES64ESM, b),
+                AES64DS => (crypto::AES64DS, b),
+                AES64DSM => (crypto::AES64DSM, b),
+                AES64IM => (crypto::AES64IM, 0),
+                _ => (crypto::AES64KS1I, uimm),
+            };
+            result = Some(crypto::crypto(op, a, rs2));
+        }
+        AES64KS2 => result = Some(crate::crypto::aes64ks2(a, b)),
+        SHA256SIG0 => result = Some(sha256(a, |x| x.rotate_right(7) ^ x.rotate_right(18) ^ (x >> 3))),
+        SHA256SIG1 => result = Some(sha256(a, |x| x.rotate_right(17) ^ x.rotate_right(19) ^ (x >> 10))),
+        SHA256SUM0 => result = Some(sha256(a, |x| x.rotate_right(2) ^ x.rotate_right(13) ^ x.rotate_right(22))),
+        SHA256SUM1 => result = Some(sha256(a, |x| x.rotate_right(6) ^ x.rotate_right(11) ^ x.rotate_right(25))),
+        SHA512SIG0 => result = Some(a.rotate_right(1) ^ a.rotate_right(8) ^ (a >> 7)),
+        SHA512SIG1 => result = Some(a.rotate_right(19) ^ a.rotate_right(61) ^ (a >> 6)),
+        SHA512SUM0 => result = Some(a.rotate_right(28) ^ a.rotate_right(34) ^ a.rotate_right(39)),
+        SHA512SUM1 => result = Some(a.rotate_right(14) ^ a.rotate_right(18) ^ a.rotate_right(41)),
+
+        FENCE | C_NOP | CBO_CLEAN | CBO_FLUSH | CBO_INVAL | PAUSE | WRS_NTO | WRS_STO => {}
+        CBO_ZERO => {
+            let block = crate::translate::CACHE_BLOCK_SIZE;
+            let range = span(mem.len(), a & !(block - 1), block as usize)?;
+            mem[range].fill(0);
+        }
+        ECALL | EBREAK | C_EBREAK => {
+            hart.pc = inst.addr;
+            hart.reservation = None;
+            return Ok(if inst.opcode == ECALL { Step::Ecall } else { Step::Ebreak });
+        }
+
+        op => bail!("Interpreter does not support {:?} at 0x{:x}", op, inst.addr),
+    }
+
+    if let Some(v) = result {
+        if rd != 0 {
+            hart.x[rd] = v;
+        }
+    }
+    hart.pc = target;
+    Ok(Step::Next)
+}
+
+/// A SHA-256 function of the low word, sign-extended
+fn sha256(v: u64, f: impl Fn(u32) -> u32) -> u64 {
+    sext32(f(v as u32) as u64)
+}
+
+fn sext32(v: u64) -> u64 {
+    v as u32 as i32 as i64 as u64
+}
+
+/// AMO result for `old` op `src`; `word` compares as 32-bit values
+fn amo(op: Opcode, old: u64, src: u64, word: bool) -> u64 {
+    use Opcode::*;
+    let (so, ss) = if word {
+        (old as i32 as i64, src as i32 as i64)
+    } else {
+        (old as i64, src as i64)
+    };
+    let (uo, us) = if word { (old as u32 as u64, src as u32 as u64) } else { (old, src) };
+    match op {
+        AMOSWAP_W | AMOSWAP_D => src,
+        AMOADD_W | AMOADD_D => old.wrapping_add(src),
+        AMOXOR_W | AMOXOR_D => old ^ src,
+        AMOAND_W | AMOAND_D => old & src,
+        AMOOR_W | AMOOR_D => old | src,
+        AMOMIN_W | AMOMIN_D => so.min(ss) as u64,
+        AMOMAX_W | AMOMAX_D => so.max(ss) as u64,
+        AMOMINU_W | AMOMINU_D => uo.min(us),
+        _ => uo.max(us),
+    }
+}
+
+fn span(mem_len: usize, addr: u64, width: usize) -> Result<std::ops::Range<usize>> {
+    let start = usize::try_from(addr).ok().filter(|&s| s.checked_add(width).is_some_and(|e| e <= mem_len));
+    let start = start.with_context(|| format!("Memory access out of bounds at 0x{:x}", addr))?;
+    Ok(start..start + width)
+}
+
+fn load(mem: &[u8], addr: u64, width: usize) -> Result<u64> {
+    let mut b = [0u8; 8];
+    b[..width].copy_from_slice(&mem[span(mem.len(), addr, width)?]);
+    Ok(u64::from_le_bytes(b))
+}
+
+fn store(mem: &mut [u8], addr: u64, width: usize, value: u64) -> Result<()> {
+    let range = span(mem.len(), addr, width)?;
+    mem[range].copy_from_slice(&value.to_le_bytes()[..width]);
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::disasm;
+    use crate::elf::CodeSection;
+
+    #[test]
+    fn test_block_semantics() {
+        // li a0, -7 ; li a1, 2 ; div a2, a0, a1 ; rem a3, a0, a1 ; sd a2, 0(zero) ; ecall
+        let words: [u32; 6] = [0xff90_0513, 0x0020_0593, 0x02b5_4633, 0x02b5_66b3, 0x00c0_3023, 0x0000_0073];
+        let section = CodeSection {
+            vaddr: 0x1000,
+            data: words.iter().flat_map(|w| w.to_le_bytes()).collect(),
+            name: "test".into(),
+        };
+        let instructions = disasm::disassemble(&section).unwrap();
+        let mut hart = Hart::new(0x1000);
+        let mut mem = vec![0u8; 0x2000];
+
+        assert_eq!(run_block(&mut hart, &mut mem, &instructions).unwrap(), Step::Ecall);
+        assert_eq!(hart.pc, 0x1014);
+        assert_eq!((hart.x[12] as i64, hart.x[13] as i64), (-3, -1));
+        assert_eq!(load(&mem, 0, 8).unwrap() as i64, -3);
+    }
+}
@@ -0,0 +1,98 @@
+// interpose.rs - Guest functions replaced by host imports
+//
+// With `CompileOptions::interpose` the entry block of each named guest
+// function (memcpy, getenv, a vendored crypto routine) is compiled as a
+// stub: it passes a0-a7 to the imported `env.host_<name>(a0..a7 i64) ->
+// i64`, writes the result to a0 and returns to ra, as the function would.
+// Pointer arguments are guest addresses; the host reads and writes guest
+// memory through `env.memory` (plus the memory offset, if any). The rest
+// of the function is still compiled, so code jumping into its middle
+// keeps working.
+//
+// The imports follow `env.watch` and `env.breakpoint` (when present), in
+// option order, so the function index of import i is
+// `link::IMPORTED_FUNCS (+ link::DEBUG_FUNCS) + i`.
+
+use crate::options::CompileOptions;
+use crate::translate::WasmInst;
+
+/// Import name prefix: guest function `memcpy` calls `env.host_memcpy`
+pub const PREFIX: &str = "host_";
+
+/// Import names of the functions `options` interposes, in function order
+pub fn import_names(options: &CompileOptions) -> Vec<String> {
+    options.interpose.iter().map(|sym| format!("{}{}", PREFIX, sym.name)).collect()
+}
+
+/// Function index of the import standing in for the block at `addr`, if
+/// it is an interposed function's entry
+pub(crate) fn host_func(options: &CompileOptions, addr: u64) -> Option<u32> {
+    let i = options.interpose.iter().position(|sym| sym.addr == addr)?;
+    let debug = options.watch.is_some() || !options.breakpoints.is_empty();
+    Some(crate::link::IMPORTED_FUNCS + crate::link::DEBUG_FUNCS * debug as u32 + i as u32)
+}
+
+/// Stub body: a0 = host(a0..a7), then return to ra
+pub(crate) fn stub(func_idx: u32) -> Vec<WasmInst> {
+    use WasmInst::*;
+    let mut body = vec![LocalGet { idx: 0 }];
+    for reg in 10..18 {
+        body.extend([LocalGet { idx: 0 }, I64Load { offset: reg * 8 }]);
+    }
+    body.extend([
+        CallHost { func_idx },
+        I64Store { offset: 10 * 8 },
+        LocalGet { idx: 0 },
+        I64Load { offset: 8 },
+        I32WrapI64,
+        I32Const { value: -2 },
+        I32And,
+        Return,
+    ]);
+    body
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::disasm::Opcode;
+    use crate::elf::Symbol;
+    use crate::options::CompileOptions;
+    use crate::test_util::{elf_info, inst};
+
+    #[test]
+    fn test_interposed_entry_calls_host() {
+        // main: jal getenv; ecall -- getenv: add a0, a0, a1; ret
+        let code = [
+            inst(0x1000, Opcode::JAL, 1, 10, 11, 0x10),
+            inst(0x1004, Opcode::ECALL, 0, 10, 11, 0),
+            inst(0x1010, Opcode::ADD, 10, 10, 11, 0),
+            inst(0x1014, Opcode::JALR, 0, 1, 11, 0),
+        ];
+        let cfg = crate::cfg::build(&code, 0x1000).unwrap();
+        let elf_info = elf_info();
+        let getenv = Symbol { name: "getenv".into(), addr: 0x1010, size: 8 };
+        for breakpoints in [vec![], vec![0x1004]] {
+            let options = CompileOptions {
+                interpose: vec![getenv.clone()],
+                breakpoints: breakpoints.into_iter().collect(),
+                ..Default::default()
+            };
+            let module = crate::translate::translate(&cfg, &elf_info, &options).unwrap();
+            let stub = &module.functions[module.block_to_func[&0x1010]];
+            let host = 3 + 2 * !options.breakpoints.is_empty() as u32;
+            assert!(stub.body.contains(&crate::translate::WasmInst::CallHost { func_idx: host }));
+
+            let wasm = crate::wasm_builder::build(&module).unwrap();
+            wasmparser::Validator::new().validate_all(&wasm).unwrap();
+            let imports: Vec<_> = wasmparser::Parser::new(0)
+                .parse_all(&wasm)
+                .filter_map(|payload| match payload.unwrap() {
+                    wasmparser::Payload::ImportSection(reader) => Some(reader),
+                    _ => None,
+                })
+                .flat_map(|reader| reader.into_iter().map(|import| import.unwrap().name.to_string()))
+                .collect();
+            assert_eq!(imports[host as usize + 1], "host_getenv");
+        }
+    }
+}
@@ -0,0 +1,337 @@
+// ipc.rs - Shared-memory channels between workers
+//
+// The guest worker and the I/O worker exchange syscall requests, their
+// replies and pty bytes through rings in shared memory instead of
+// postMessage, which costs a trip through the event loop per message. Each
+// ring has one writer and one reader; two make a duplex link.
+//
+// A ring is a region of 32-bit words: `head` (bytes ever written), `tail`
+// (bytes ever read), `closed`, `seq`, then the data, a power of two in
+// size. The counters only grow, wrapping at 2^32, so the ring holds
+// `head - tail` bytes starting at `data[tail % size]`. The writer publishes
+// bytes by storing `head` with release ordering and the reader frees them by
+// storing `tail`; either then bumps `seq` and notifies it, which is what a
+// blocked side waits on (closing bumps it too, so no wakeup is lost). A JS
+// end does the same with `Atomics` on an Int32Array over the region; an
+// all-zero region is an empty, open ring.
+//
+// Blocking uses memory.atomic.wait32/notify on wasm32 builds with atomics
+// (never on the browser main thread, which must stick to the `try_` calls)
+// and a yielding spin elsewhere. Timeouts bound each wait for the other
+// side, not the call: a wakeup starts the next wait afresh.
+//
+// `Channel` frames messages over a ring as a little-endian u32 length and
+// the bytes, for the syscall traffic; pty bytes use the ring directly.
+
+use std::sync::atomic::Ordering::{Acquire, Relaxed, Release};
+use std::sync::atomic::{AtomicU32, AtomicU8};
+use std::time::Duration;
+use thiserror::Error;
+
+/// Words before the data
+pub const HEADER_WORDS: usize = 4;
+const HEAD: usize = 0;
+const TAIL: usize = 1;
+const CLOSED: usize = 2;
+const SEQ: usize = 3;
+
+/// The other end closed the ring
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Error)]
+#[error("channel closed")]
+pub struct Closed;
+
+/// One direction of a byte stream in shared memory
+#[derive(Clone, Copy)]
+pub struct Ring<'a> {
+    header: &'a [AtomicU32],
+    data: &'a [AtomicU8],
+}
+
+impl<'a> Ring<'a> {
+    /// A ring over `region`: `HEADER_WORDS` words, then a power of two of data words
+    pub fn new(region: &'a [AtomicU32]) -> Self {
+        let (header, data) = region.split_at(HEADER_WORDS);
+        assert!(data.len().is_power_of_two(), "ring data is {} words, not a power of two", data.len());
+        // SAFETY: AtomicU8 has u8's layout, and the bytes are those of `data`
+        let data = unsafe { std::slice::from_raw_parts(data.as_ptr().cast::<AtomicU8>(), data.len() * 4) };
+        Self { header, data }
+    }
+
+    /// A ring over `words` words at `ptr`, e.g. a region the host laid out in
+    /// a shared linear memory
+    ///
+    /// # Safety
+    ///
+    /// `ptr` must be 4-aligned and valid for `words` words for `'a`, and only
+    /// accessed atomically in that time.
+    pub unsafe fn from_raw(ptr: *const u32, words: usize) -> Self {
+        Self::new(std::slice::from_raw_parts(ptr.cast::<AtomicU32>(), words))
+    }
+
+    /// Data bytes
+    pub fn capacity(&self) -> usize {
+        self.data.len()
+    }
+
+    /// Bytes waiting to be read
+    pub fn len(&self) -> usize {
+        self.header[HEAD].load(Acquire).wrapping_sub(self.header[TAIL].load(Acquire)) as usize
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    /// End the stream: the writer can no longer write, and the reader reads
+    /// what is left, then end of file. Either side may close.
+    pub fn close(&self) {
+        self.header[CLOSED].store(1, Release);
+        self.wake();
+    }
+
+    pub fn is_closed(&self) -> bool {
+        self.header[CLOSED].load(Acquire) != 0
+    }
+
+    /// Write as much of `bytes` as fits, without waiting
+    pub fn try_write(&self, bytes: &[u8]) -> Result<usize, Closed> {
+        if self.is_closed() {
+            return Err(Closed);
+        }
+        let head = self.header[HEAD].load(Relaxed);
+        let n = bytes.len().min(self.free());
+        self.copy_in(head, &bytes[..n]);
+        if n > 0 {
+            self.publish(HEAD, head.wrapping_add(n as u32));
+        }
+        Ok(n)
+    }
+
+    /// Write all of `bytes`, waiting for room
+    pub fn write_all(&self, mut bytes: &[u8]) -> Result<(), Closed> {
+        while !bytes.is_empty() {
+            let n = self.wait_for(None, || match self.try_write(bytes) {
+                Ok(0) => None,
+                n => Some(n),
+            });
+            bytes = &bytes[n.expect("no timeout")?..];
+        }
+        Ok(())
+    }
+
+    /// Read what is there, up to `buf.len()` bytes, without waiting
+    pub fn try_read(&self, buf: &mut [u8]) -> usize {
+        let tail = self.header[TAIL].load(Relaxed);
+        let n = buf.len().min(self.len());
+        self.copy_out(tail, &mut buf[..n]);
+        if n > 0 {
+            self.publish(TAIL, tail.wrapping_add(n as u32));
+        }
+        n
+    }
+
+    /// Read at least one byte, waiting up to `timeout` for the writer (None:
+    /// indefinitely). 0 means end of file, or that the wait timed out.
+    pub fn read(&self, buf: &mut [u8], timeout: Option<Duration>) -> usize {
+        self.wait_for(timeout, || {
+            // Whatever was written before the close is visible after it
+            let closed = self.is_closed();
+            let n = self.try_read(buf);
+            (n > 0 || closed || buf.is_empty()).then_some(n)
+        })
+        .unwrap_or(0)
+    }
+
+    fn free(&self) -> usize {
+        self.capacity() - self.len()
+    }
+
+    fn copy_in(&self, at: u32, bytes: &[u8]) {
+        let mask = self.capacity() - 1;
+        for (i, &b) in bytes.iter().enumerate() {
+            self.data[(at as usize).wrapping_add(i) & mask].store(b, Relaxed);
+        }
+    }
+
+    fn copy_out(&self, at: u32, buf: &mut [u8]) {
+        let mask = self.capacity() - 1;
+        for (i, b) in buf.iter_mut().enumerate() {
+            *b = self.data[(at as usize).wrapping_add(i) & mask].load(Relaxed);
+        }
+    }
+
+    /// Store counter `word` and wake the other side
+    fn publish(&self, word: usize, value: u32) {
+        self.header[word].store(value, Release);
+        self.wake();
+    }
+
+    fn wake(&self) {
+        self.header[SEQ].fetch_add(1, Release);
+        notify(&self.header[SEQ]);
+    }
+
+    /// Poll until `poll` returns something, waiting for the other side in
+    /// between; None once a wait times out
+    fn wait_for<T>(&self, timeout: Option<Duration>, mut poll: impl FnMut() -> Option<T>) -> Option<T> {
+        loop {
+            let seq = self.header[SEQ].load(Acquire);
+            if let Some(value) = poll() {
+                return Some(value);
+            }
+            if wait(&self.header[SEQ], seq, timeout) {
+                return None;
+            }
+        }
+    }
+}
+
+/// Messages over a `Ring`, each written whole
+#[derive(Clone, Copy)]
+pub struct Channel<'a>(pub Ring<'a>);
+
+impl Channel<'_> {
+    /// Largest message the ring can carry
+    pub fn max_message(&self) -> usize {
+        self.0.capacity() - 4
+    }
+
+    /// Send `msg` without waiting; false if there is no room for it yet
+    pub fn try_send(&self, msg: &[u8]) -> Result<bool, Closed> {
+        assert!(msg.len() <= self.max_message(), "{}-byte message in a {}-byte ring", msg.len(), self.0.capacity());
+        let ring = &self.0;
+        if ring.is_closed() {
+            return Err(Closed);
+        }
+        if ring.free() < 4 + msg.len() {
+            return Ok(false);
+        }
+        let head = ring.header[HEAD].load(Relaxed);
+        ring.copy_in(head, &(msg.len() as u32).to_le_bytes());
+        ring.copy_in(head.wrapping_add(4), msg);
+        ring.publish(HEAD, head.wrapping_add(4 + msg.len() as u32));
+        Ok(true)
+    }
+
+    /// Send `msg`, waiting for room
+    pub fn send(&self, msg: &[u8]) -> Result<(), Closed> {
+        let sent = self.0.wait_for(None, || match self.try_send(msg) {
+            Ok(false) => None,
+            sent => Some(sent),
+        });
+        sent.expect("no timeout").map(drop)
+    }
+
+    /// The next message, if one is there
+    pub fn try_recv(&self) -> Option<Vec<u8>> {
+        let ring = &self.0;
+        let tail = ring.header[TAIL].load(Relaxed);
+        if ring.len() < 4 {
+            return None;
+        }
+        let mut len = [0; 4];
+        ring.copy_out(tail, &mut len);
+        let mut msg = vec![0; u32::from_le_bytes(len) as usize];
+        ring.copy_out(tail.wrapping_add(4), &mut msg);
+        ring.publish(TAIL, tail.wrapping_add(4 + msg.len() as u32));
+        Some(msg)
+    }
+
+    /// The next message, waiting up to `timeout` for the writer (None:
+    /// indefinitely); None if the wait timed out or the ring was closed
+    /// and drained
+    pub fn recv(&self, timeout: Option<Duration>) -> Option<Vec<u8>> {
+        self.0
+            .wait_for(timeout, || {
+                let closed = self.0.is_closed();
+                match self.try_recv() {
+                    Some(msg) => Some(Some(msg)),
+                    None => closed.then_some(None),
+                }
+            })
+            .flatten()
+    }
+}
+
+/// Wait while `word` holds `expected`, for at most `timeout`; true if
+/// the wait timed out
+#[cfg(all(target_arch = "wasm32", target_feature = "atomics"))]
+fn wait(word: &AtomicU32, expected: u32, timeout: Option<Duration>) -> bool {
+    let ns = timeout.map_or(-1, |t| t.as_nanos().min(i64::MAX as u128) as i64);
+    // SAFETY: `word` is an aligned atomic in linear memory
+    unsafe { core::arch::wasm32::memory_atomic_wait32(word.as_ptr().cast(), expected as i32, ns) == 2 }
+}
+
+#[cfg(all(target_arch = "wasm32", target_feature = "atomics"))]
+fn notify(word: &AtomicU32) {
+    // SAFETY: as for `wait`
+    unsafe { core::arch::wasm32::memory_atomic_notify(word.as_ptr().cast(), u32::MAX) };
+}
+
+#[cfg(not(all(target_arch = "wasm32", target_feature = "atomics")))]
+fn wait(word: &AtomicU32, expected: u32, timeout: Option<Duration>) -> bool {
+    let start = std::time::Instant::now();
+    while word.load(Acquire) == expected {
+        if timeout.is_some_and(|t| start.elapsed() >= t) {
+            return true;
+        }
+        std::thread::yield_now();
+    }
+    false
+}
+
+#[cfg(not(all(target_arch = "wasm32", target_feature = "atomics")))]
+fn notify(_word: &AtomicU32) {}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn region(data_words: usize) -> Vec<AtomicU32> {
+        (0..HEADER_WORDS + data_words).map(|_| AtomicU32::new(0)).collect()
+    }
+
+    #[test]
+    fn test_channel_across_threads() {
+        // Requests one way, replies the other, through rings small enough
+        // to fill and wrap many times
+        let (requests, replies) = (region(16), region(16));
+        let (requests, replies) = (Channel(Ring::new(&requests)), Channel(Ring::new(&replies)));
+        std::thread::scope(|s| {
+            s.spawn(|| {
+                while let Some(req) = requests.recv(None) {
+                    replies.send(&[req.len() as u8]).unwrap();
+                }
+                replies.0.close();
+            });
+            for i in 0..500 {
+                let msg = vec![i as u8; i % 60];
+                requests.send(&msg).unwrap();
+                assert_eq!(replies.recv(None), Some(vec![msg.len() as u8]));
+            }
+            requests.0.close();
+            assert_eq!(replies.recv(None), None);
+        });
+        assert_eq!(requests.send(b"late"), Err(Closed));
+    }
+
+    #[test]
+    fn test_ring_bytes() {
+        let words = region(2);
+        let ring = Ring::new(&words);
+        assert_eq!(ring.try_write(b"0123456789").unwrap(), 8);
+        let mut buf = [0; 5];
+        assert_eq!(ring.read(&mut buf, None), 5);
+        assert_eq!(&buf, b"01234");
+        assert_eq!(ring.try_write(b"abcdef").unwrap(), 5);
+        assert_eq!(ring.len(), 8);
+        let mut buf = [0; 16];
+        assert_eq!(ring.try_read(&mut buf), 8);
+        assert_eq!(&buf[..8], b"567abcde");
+        // Nothing arrives: the read times out; after a close it is EOF
+        assert_eq!(ring.read(&mut buf, Some(Duration::from_millis(10))), 0);
+        assert!(!ring.is_closed());
+        ring.close();
+        assert_eq!(ring.read(&mut buf, None), 0);
+    }
+}
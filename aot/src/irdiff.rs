@@ -0,0 +1,177 @@
+// irdiff.rs - IR dumps and diffs between builds
+//
+// `rv2wasm --dump-ir` writes the IR of every block function as text: a
+// `block 0x<addr>` header, then one instruction per line as the translator
+// names it, then the block's br_table label lists. Being text, a dump from
+// one rv2wasm can be read back by another as long as instructions keep
+// their names.
+//
+// `rv2wasm --diff-ir OLD` compares the IR of the current build against
+// such a dump (from an older rv2wasm, say), and `--diff-ir-args ARGS`
+// against the same input compiled with other options. Either way the
+// report lists the blocks whose IR changed, appeared or disappeared, with a
+// line diff of the first few: the blast radius of an optimizer change.
+
+use crate::translate::WasmFunction;
+use anyhow::{bail, Context, Result};
+use std::collections::BTreeMap;
+use std::fmt::Write;
+
+/// Block IR by block address, one line per instruction
+pub type IrDump = BTreeMap<u64, Vec<String>>;
+
+/// Blocks with longer IR are reported by size instead of line diff
+const MAX_DIFF_LINES: usize = 4000;
+
+/// The IR of `functions` as dump lines
+pub fn collect<'a>(functions: impl IntoIterator<Item = &'a WasmFunction>) -> IrDump {
+    functions
+        .into_iter()
+        .map(|func| {
+            let mut lines = vec![format!("locals {}", func.num_locals)];
+            lines.extend(func.body.iter().map(|inst| format!("{:?}", inst)));
+            lines.extend(func.br_tables.iter().enumerate().map(|(i, t)| format!("br_table {} {:?}", i, t)));
+            (func.block_addr, lines)
+        })
+        .collect()
+}
+
+/// A dump as `--dump-ir` writes it
+pub fn render(dump: &IrDump) -> String {
+    let mut out = String::new();
+    for (addr, lines) in dump {
+        let _ = writeln!(out, "block 0x{:x}", addr);
+        for line in lines {
+            let _ = writeln!(out, "  {}", line);
+        }
+    }
+    out
+}
+
+/// Read back a `render`ed dump
+pub fn parse(text: &str) -> Result<IrDump> {
+    let mut dump = IrDump::new();
+    let mut current: Option<&mut Vec<String>> = None;
+    for (n, line) in text.lines().enumerate() {
+        if let Some(addr) = line.strip_prefix("block 0x") {
+            let addr = u64::from_str_radix(addr, 16).with_context(|| format!("line {}: bad block address", n + 1))?;
+            current = Some(dump.entry(addr).or_default());
+        } else if let Some(inst) = line.strip_prefix("  ") {
+            match &mut current {
+                Some(lines) => lines.push(inst.to_string()),
+                None => bail!("line {}: instruction outside a block", n + 1),
+            }
+        } else if !line.is_empty() {
+            bail!("line {}: expected `block 0x<addr>` or an indented instruction", n + 1);
+        }
+    }
+    Ok(dump)
+}
+
+/// Blocks that differ between two dumps
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct IrDiff {
+    pub changed: Vec<u64>,
+    /// Only in the new build
+    pub added: Vec<u64>,
+    /// Only in the old build
+    pub removed: Vec<u64>,
+    pub unchanged: usize,
+}
+
+pub fn diff(old: &IrDump, new: &IrDump) -> IrDiff {
+    let mut d = IrDiff::default();
+    for (&addr, lines) in new {
+        match old.get(&addr) {
+            None => d.added.push(addr),
+            Some(old_lines) if old_lines != lines => d.changed.push(addr),
+            Some(_) => d.unchanged += 1,
+        }
+    }
+    d.removed = old.keys().filter(|addr| !new.contains_key(addr)).copied().collect();
+    d
+}
+
+/// Line diff of `old` against `new` (longest common subsequence), as
+/// lines prefixed with ' ', '-' or '+'
+fn line_diff(old: &[String], new: &[String]) -> Vec<String> {
+    let (n, m) = (old.len(), new.len());
+    // common[i][j]: LCS length of old[i..] and new[j..]
+    let mut common = vec![vec![0u32; m + 1]; n + 1];
+    for i in (0..n).rev() {
+        for j in (0..m).rev() {
+            common[i][j] =
+                if old[i] == new[j] { common[i + 1][j + 1] + 1 } else { common[i + 1][j].max(common[i][j + 1]) };
+        }
+    }
+    let (mut i, mut j, mut out) = (0, 0, Vec::new());
+    while i < n || j < m {
+        if i < n && j < m && old[i] == new[j] {
+            out.push(format!("  {}", old[i]));
+            (i, j) = (i + 1, j + 1);
+        } else if i < n && (j == m || common[i + 1][j] >= common[i][j + 1]) {
+            out.push(format!("- {}", old[i]));
+            i += 1;
+        } else {
+            out.push(format!("+ {}", new[j]));
+            j += 1;
+        }
+    }
+    out
+}
+
+/// The report for `diff`: counts, the changed, added and removed block
+/// addresses, and line diffs of the first `shown` changed blocks
+pub fn render_diff(old: &IrDump, new: &IrDump, diff: &IrDiff, shown: usize) -> String {
+    let mut out = String::new();
+    let _ = writeln!(
+        out,
+        "{} blocks changed, {} added, {} removed, {} unchanged",
+        diff.changed.len(),
+        diff.added.len(),
+        diff.removed.len(),
+        diff.unchanged
+    );
+    for (what, addrs) in [("changed", &diff.changed), ("added", &diff.added), ("removed", &diff.removed)] {
+        if !addrs.is_empty() {
+            let list: Vec<String> = addrs.iter().map(|a| format!("0x{:x}", a)).collect();
+            let _ = writeln!(out, "{}: {}", what, list.join(" "));
+        }
+    }
+    for &addr in diff.changed.iter().take(shown) {
+        let (old, new) = (&old[&addr], &new[&addr]);
+        let _ = writeln!(out, "\nblock 0x{:x} ({} -> {} lines)", addr, old.len(), new.len());
+        if old.len() + new.len() > MAX_DIFF_LINES {
+            continue;
+        }
+        for line in line_diff(old, new) {
+            let _ = writeln!(out, "{}", line);
+        }
+    }
+    out
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn dump(blocks: &[(u64, &[&str])]) -> IrDump {
+        blocks.iter().map(|&(addr, lines)| (addr, lines.iter().map(|l| l.to_string()).collect())).collect()
+    }
+
+    #[test]
+    fn test_diff_ir() {
+        let old = dump(&[(0x10, &["LocalGet { idx: 0 }", "I64Load { offset: 80 }"]), (0x20, &["Return"]), (0x30, &[])]);
+        let new = dump(&[(0x10, &["LocalGet { idx: 0 }", "I64Load { offset: 88 }"]), (0x20, &["Return"]), (0x40, &[])]);
+        assert_eq!(parse(&render(&old)).unwrap(), old);
+        assert!(parse("  Return\n").is_err());
+
+        let d = diff(&old, &new);
+        assert_eq!(d, IrDiff { changed: vec![0x10], added: vec![0x40], removed: vec![0x30], unchanged: 1 });
+        let report = render_diff(&old, &new, &d, 10);
+        assert!(report.starts_with("1 blocks changed, 1 added, 1 removed, 1 unchanged\n"));
+        assert!(report.contains(
+            "block 0x10 (2 -> 2 lines)\n  LocalGet { idx: 0 }\n- I64Load { offset: 80 }\n+ I64Load { offset: 88 }\n"
+        ));
+    }
+}
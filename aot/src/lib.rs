@@ -9,10 +9,65 @@
 //
 // 1. **ELF Parsing** (`elf.rs`): Load RISC-V ELF binary, extract code sections
 // 2. **Disassembly** (`disasm.rs`): Decode RISC-V instructions to structured form
-// 3. **CFG Construction** (`cfg.rs`): Build control flow graph, identify basic blocks
-// 4. **Translation** (`translate.rs`): Convert RISC-V to Wasm IR
+// 3. **CFG Construction** (`cfg.rs`): Build control flow graph, identify basic blocks,
+//    then bind PLT stubs with statically known targets (`plt.rs`) and
+//    find main through the libc startup code (`startup.rs`)
+// 4. **Translation** (`translate.rs`): Convert RISC-V to Wasm IR, then run
+//    the peephole passes in `opt.rs` at -O2 and above (checked by
+//    `verify.rs` when `CompileOptions::verify_ir` is set)
 // 5. **Wasm Generation** (`wasm_builder.rs`): Emit final Wasm binary
 //
+// With `CompileOptions::data` the module also embeds the initial memory
+// image as passive segments that are copied in on demand (`data.rs`).
+//
+// With `CompileOptions::split`, phase 5 emits a small dispatcher plus code
+// modules the host loads on demand (`split.rs`, `wasm_builder::build_split`).
+//
+// `compile_to_writer` runs phases 4 and 5 as a pipeline instead: blocks are
+// encoded and written as they are translated, so neither the whole IR nor
+// the whole code section is held in memory.
+//
+// Separately, `loader.rs` builds the initial process image (segments, stack,
+// auxv, registers) that a host copies into guest memory before running,
+// and `snapshot.rs` defines the format hosts use to save and resume a guest.
+// `ipc.rs` provides the shared-memory rings a host's workers pass syscalls
+// and pty bytes through.
+// `abi.rs` describes the emitted module's host interface (machine-state
+// offsets, return protocol, exports) as the JSON written next to it, and
+// `link.rs` the import/export contract shared by AOT and JIT modules.
+// `interp.rs` is a reference interpreter used as the oracle when testing
+// the translator, `eval.rs` executes the emitted IR directly so the two can
+// be compared without a Wasm engine, and `encode.rs` assembles instructions
+// back to bytes.
+// `fma.rs` lowers the fused multiply-adds as `CompileOptions::fma` asks
+// (unfused, relaxed SIMD or an exact software sequence).
+// `freq.rs` estimates block frequencies from the CFG when no profile is
+// given, for layout and inline-cache targets.
+// `symbolize.rs` names guest PCs (`main+0x14`) for trap messages and
+// profiles, and `unwind.rs` reads the .eh_frame a host walks the guest
+// stack with.
+// `irdiff.rs` dumps block IR as text and reports the blocks whose IR
+// differs between two builds, and `watch.rs` instruments the accesses to a
+// watched address range (`CompileOptions::watch`). `compile_patch` rebuilds
+// one block of a module (with `CompileOptions::breakpoints`, say) as a patch
+// that swaps itself into the dispatch table.
+// `interpose.rs` replaces named guest functions with calls to host imports
+// (`CompileOptions::interpose`).
+// `policy.rs` checks each ECALL against a compiled-in syscall allow or
+// deny list (`CompileOptions::syscall_policy`).
+// `bundle.rs` compiles several ELFs (a shell, ld.so, its libraries) into
+// one module over one address space, so an exec between them stays in AOT
+// code.
+// `rootfs.rs` reads container rootfs tars and packs them, one zstd frame
+// per file, for hosts that decompress files as the guest opens them;
+// `oci.rs` flattens OCI and docker-archive images into the same entries.
+// Both are behind features (`rootfs`, and `oci`, which the CLI enables).
+// `report.rs` lists the instructions the translator cannot lower, and
+// `cache.rs` reuses whole builds and unchanged guest functions across runs,
+// and `incremental.rs` the unchanged functions of the previous output.
+// fuzz/ holds cargo-fuzz targets that feed arbitrary code through the decoder
+// and the `compile_region` pipeline.
+//
 // # Memory Model
 //
 // The generated Wasm uses:
@@ -21,6 +76,12 @@
 // - PC passed as function parameter, returned as result
 // - Special return values signal syscalls (high bit set)
 //
+// Guest address `a` is linear-memory address `a + memory_offset`
+// (`CompileOptions::memory_offset`, 0 by default): translation adds the
+// offset to every guest access's static offset, leaving `$m` accesses alone.
+// With `CompileOptions::soft_mmu` guest addresses are mapped through a page
+// table in linear memory instead (`mmu.rs`).
+//
 // # Syscall Handling
 //
 // When the guest executes ECALL, the block function returns with:
@@ -28,25 +89,113 @@
 // - Lower bits contain the PC
 //
 // The dispatch loop recognizes this and calls the imported syscall handler.
+// With `CompileOptions::jspi` it calls `env.syscall_async` instead, passing
+// the plain PC and an `abi::CAUSE_*`, for hosts that suspend on it with JSPI.
+// With `CompileOptions::trap_exceptions` traps skip the handler and throw
+// the exported `guest_trap` exception (cause, PC, address) out of `run`.
+// With `CompileOptions::time_page`, clock_gettime and gettimeofday are
+// answered from a page of guest memory the host keeps current (`vdso.rs`).
+//
+// # Threads
+//
+// With `CompileOptions::threads`, the module imports shared memory and
+// lowers AMO/LR/SC to Wasm atomics. Each guest thread gets its own machine
+// state block and runs `run($m, $pc)` on its own worker; the host services
+// clone(CLONE_THREAD) with the exported `thread_init` helper and futex
+// wait/wake with `futex_wait`/`futex_wake` (memory.atomic.wait32/notify).
+// `CompileOptions::harts` builds for an SMP guest: the host starts that many
+// harts, each with its own machine state and dispatch loop, and mhartid
+// reads return the id the host stored at `translate::HART_ID_OFFSET`.
+//
+// # Guest Function Exports
+//
+// Symbols listed in `CompileOptions::exports` get a `guest_<name>` export
+// taking `($m, a0..a7)` and returning `(a0, a1)`. The wrapper sets ra to
+// `RETURN_ADDR`, whose PC the dispatch loop treats as halt, so `run` comes
+// back when the function returns.
+//
+// Block functions are exported as `block_<addr>` unless
+// `CompileOptions::export_blocks` narrows that to a chosen set (or size
+// mode drops them); the rest stay internal, reachable through `run`.
 
+pub mod abi;
+pub mod bundle;
+pub mod cache;
 pub mod cfg;
+pub mod crypto;
+pub mod data;
 pub mod disasm;
 pub mod elf;
+pub mod encode;
+pub mod eval;
+pub mod fma;
+pub mod freq;
+pub mod hotness;
+pub mod incremental;
+pub mod interp;
+pub mod interpose;
+pub mod irdiff;
+pub mod ipc;
+pub mod link;
+pub mod loader;
+pub mod mmu;
+#[cfg(feature = "oci")]
+pub mod oci;
+pub mod opt;
+pub mod options;
+pub mod plt;
+pub mod policy;
+pub mod report;
+#[cfg(feature = "rootfs")]
+pub mod rootfs;
+pub mod snapshot;
+pub mod split;
+pub mod startup;
+pub mod symbolize;
+#[cfg(test)]
+mod test_util;
 pub mod translate;
+pub mod unwind;
+pub mod vdso;
+pub mod verify;
 pub mod wasm_builder;
+pub mod watch;
 
 pub use cfg::{BasicBlock, ControlFlowGraph, Function};
-pub use disasm::{Instruction, Opcode};
-pub use elf::{CodeSection, ElfInfo, Segment};
-pub use translate::{WasmFunction, WasmInst, WasmModule};
+pub use disasm::{decode16, decode32, DecodeError, Instruction, Opcode};
+pub use elf::{BytePatch, CodeSection, ElfFile, ElfInfo, Relocation, Segment, Symbol};
+pub use hotness::BlockProfile;
+pub use loader::{InitialImage, LoadOptions};
+pub use options::{CompileOptions, FmaMode, UnknownPc};
+pub use policy::{Denied, SyscallPolicy};
+pub use snapshot::Snapshot;
+pub use symbolize::Symbolizer;
+pub use translate::{ModuleLayout, WasmFunction, WasmInst, WasmModule};
+pub use wasm_builder::StreamingBuilder;
+
+use anyhow::Context;
+use std::io::{Seek, Write};
 
 /// Compile a RISC-V ELF binary to WebAssembly
 pub fn compile(elf_data: &[u8], opt_level: u8, debug: bool) -> anyhow::Result<Vec<u8>> {
+    compile_with_options(
+        elf_data,
+        &CompileOptions {
+            opt_level,
+            debug,
+            ..Default::default()
+        },
+    )
+}
+
+/// Compile a RISC-V ELF binary to WebAssembly with explicit options
+pub fn compile_with_options(elf_data: &[u8], options: &CompileOptions) -> anyhow::Result<Vec<u8>> {
     // Parse ELF
-    let elf_info = elf::parse(elf_data)?;
+    let file = elf::ElfFile::parse(elf_data)?.with_load_bias(options.load_bias)?;
+    let elf_info = file.info();
 
     // Extract code sections
-    let code_sections = elf::extract_code_sections(elf_data, &elf_info)?;
+    let code_sections = file.code_sections();
 
     // Disassemble
     let mut all_instructions = Vec::new();
@@ -56,11 +205,160 @@ pub fn compile(elf_data: &[u8], opt_level: u8, debug: bool) -> anyhow::Result<Ve
     }
 
     // Build CFG
-    let cfg = cfg::build(&all_instructions, elf_info.entry)?;
+    let mut cfg = cfg::build(&all_instructions, elf_info.entry)?;
+    plt::bind(&mut cfg, &file);
+    startup::apply(&mut cfg, &file);
 
     // Translate to Wasm IR
-    let wasm_module = translate::translate(&cfg, &elf_info, opt_level, debug)?;
+    let wasm_module = translate::translate(&cfg, elf_info, options)?;
 
     // Generate Wasm binary
     wasm_builder::build(&wasm_module)
 }
+
+/// Compile a region of raw RISC-V machine code loaded at `base_addr` to a
+/// JIT module (see `link.rs`), entered at its first instruction
+pub fn compile_region(code: &[u8], base_addr: u64) -> anyhow::Result<Vec<u8>> {
+    compile_region_with_options(code, base_addr, &CompileOptions::default())
+}
+
+/// `compile_region` with block functions translated under `options`, e.g.
+/// with `breakpoints` so a JIT host can swap in a block that stops at them
+pub fn compile_region_with_options(code: &[u8], base_addr: u64, options: &CompileOptions) -> anyhow::Result<Vec<u8>> {
+    let section = CodeSection {
+        vaddr: base_addr,
+        data: code.to_vec(),
+        name: format!("jit_0x{:08x}", base_addr),
+    };
+    let instructions = disasm::disassemble(&section)?;
+    if instructions.is_empty() {
+        anyhow::bail!("No instructions decoded in region 0x{:08x}", base_addr);
+    }
+    let cfg = cfg::build(&instructions, instructions[0].addr)?;
+    let wasm_module = translate::translate_jit_with(&cfg, base_addr, options)?;
+    wasm_builder::build_jit(&wasm_module)
+}
+
+/// Recompile the block containing `pc` of the AOT module built from
+/// `elf_data` with `options`, as a patch (`wasm_builder::build_patch`) that
+/// swaps itself into that module's dispatch table. With `pc` in
+/// `options.breakpoints` this sets a breakpoint without touching guest
+/// memory; recompiling without it clears it again.
+pub fn compile_patch(elf_data: &[u8], options: &CompileOptions, pc: u64) -> anyhow::Result<Vec<u8>> {
+    let file = elf::ElfFile::parse(elf_data)?.with_load_bias(options.load_bias)?;
+    let mut all_instructions = Vec::new();
+    for section in &file.code_sections() {
+        all_instructions.extend(disasm::disassemble(section)?);
+    }
+    let mut cfg = cfg::build(&all_instructions, file.info().entry)?;
+    plt::bind(&mut cfg, &file);
+    startup::apply(&mut cfg, &file);
+    emit_patch(&cfg, file.info(), options, pc)
+}
+
+/// `compile_patch` for a program already in `cfg`
+pub fn emit_patch(
+    cfg: &ControlFlowGraph,
+    elf_info: &ElfInfo,
+    options: &CompileOptions,
+    pc: u64,
+) -> anyhow::Result<Vec<u8>> {
+    let block = cfg
+        .blocks
+        .range(..=pc)
+        .next_back()
+        .filter(|(_, b)| pc < b.end_addr)
+        .map(|(&addr, _)| addr)
+        .with_context(|| format!("No block contains 0x{:x}", pc))?;
+    let mut functions = Vec::new();
+    translate::translate_only(cfg, options, &[block], |func| {
+        functions.push(func);
+        Ok(())
+    })?;
+    let layout = translate::layout(cfg, elf_info, options);
+    wasm_builder::build_patch(&layout, &functions[0])
+}
+
+/// Compile a RISC-V ELF binary to WebAssembly, streaming the module to `out`
+pub fn compile_to_writer<W: Write + Seek>(elf_data: &[u8], options: &CompileOptions, out: W) -> anyhow::Result<W> {
+    let file = elf::ElfFile::parse(elf_data)?.with_load_bias(options.load_bias)?;
+    let mut all_instructions = Vec::new();
+    for section in &file.code_sections() {
+        all_instructions.extend(disasm::disassemble(section)?);
+    }
+    let mut cfg = cfg::build(&all_instructions, file.info().entry)?;
+    plt::bind(&mut cfg, &file);
+    startup::apply(&mut cfg, &file);
+    emit_streaming(&cfg, file.info(), options, out)
+}
+
+/// Translate `cfg` and write the Wasm module to `out` block by block.
+///
+/// With the `parallel` feature the translator runs on its own thread and
+/// hands functions over a bounded channel, so translation and encoding
+/// overlap; otherwise each function is encoded as soon as it is produced.
+pub fn emit_streaming<W: Write + Seek>(
+    cfg: &ControlFlowGraph,
+    elf_info: &ElfInfo,
+    options: &CompileOptions,
+    mut out: W,
+) -> anyhow::Result<W> {
+    let layout = translate::layout(cfg, elf_info, options);
+    if layout.size {
+        // Merging identical blocks needs every body before the header
+        out.write_all(&wasm_builder::build(&translate::translate(cfg, elf_info, options)?)?)?;
+        return Ok(out);
+    }
+    let mut builder = StreamingBuilder::new(out, &layout)?;
+
+    #[cfg(feature = "parallel")]
+    std::thread::scope(|s| {
+        let (tx, rx) = std::sync::mpsc::sync_channel::<WasmFunction>(64);
+        let producer = s.spawn(move || {
+            translate::translate_streaming(cfg, options, |func| {
+                tx.send(func).map_err(|_| anyhow::anyhow!("Wasm writer stopped"))
+            })
+        });
+        // On a write error, dropping rx stops the producer at its next send
+        let written = rx.into_iter().try_for_each(|func| builder.push(&func));
+        let translated = producer.join().expect("translator thread panicked");
+        written.and(translated)
+    })?;
+    #[cfg(not(feature = "parallel"))]
+    translate::translate_streaming(cfg, options, |func| builder.push(&func))?;
+
+    builder.finish()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// The fuzz/ translate target's pipeline on a fixed run of random
+    /// regions: malformed code may be rejected but never panic, and what
+    /// compiles validates
+    #[test]
+    fn test_compile_random_regions() {
+        let mut state = 0x9e3779b97f4a7c15u64;
+        let mut next = || {
+            state ^= state << 13;
+            state ^= state >> 7;
+            state ^= state << 17;
+            state
+        };
+        for i in 0..500 {
+            let mut code: Vec<u8> = (0..next() % 64).map(|_| next() as u8).collect();
+            if i % 2 == 0 {
+                // Mostly 32-bit encodings rather than compressed ones
+                code.iter_mut().step_by(4).for_each(|b| *b |= 3);
+            }
+            let base = next() & !1;
+            let features = wasmparser::WasmFeatures { threads: true, ..Default::default() };
+            if let Ok(wasm) = compile_region(&code, base) {
+                if let Err(e) = wasmparser::Validator::new_with_features(features).validate_all(&wasm) {
+                    panic!("0x{:x} {:02x?}: {}", base, code, e);
+                }
+            }
+        }
+    }
+}
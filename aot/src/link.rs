@@ -0,0 +1,186 @@
+// link.rs - AOT/JIT module linking convention
+//
+// `wasm_builder::build` (and `build_split`, `StreamingBuilder`) and
+// `build_jit` emit modules against one contract, so a host can instantiate
+// either with the same import object and mix their block functions:
+//
+// - Imports: `env.memory` (guest memory, shared iff threads; JIT modules
+//   always use shared memory), `env.syscall($m i32, $pc i32) -> i32` as
+//   function 0, `env.yield()` as function 1, which block functions call
+//   when the guest spin-waits (PAUSE, WRS.*), and
+//   `env.crypto($op i32, $rs1 i64, $rs2 i64) -> i64` as function 2, which
+//   evaluates the scalar AES instructions (`crypto::crypto`). Split dispatchers
+//   additionally import `env.load_block`, and code modules the `table`.
+//   Lazily compiling dispatchers import `env.compile_missing($pc i32) -> i32`
+//   and their table has no maximum so the host can grow it. Dispatchers
+//   with `UnknownPc::Import` import `env.unknown_pc($pc i32) -> i32` after
+//   everything else. JSPI modules import `env.syscall_async($m i32,
+//   $pc i32, $cause i32) -> i32` in place of `env.syscall`. Debug builds (`CompileOptions::watch` or
+//   `breakpoints`) import `env.watch($addr i32, $size i32, $is_write i32,
+//   $pc i32)` as function 3 and `env.breakpoint($pc i32) -> i32` as
+//   function 4, ahead of `env.load_block` and `env.compile_missing`.
+//   Next come the `env.host_<name>(a0..a7 i64) -> i64` imports of the
+//   interposed functions (`CompileOptions::interpose`), in option order.
+// - `$m`: every block function takes the machine-state address as its only
+//   parameter and returns the next PC (or a flag from `abi`); block
+//   addresses are guest addresses, used as linear-memory addresses as is.
+// - Block map: the `friscy.blocks` custom section lists the block
+//   addresses in index order (dispatch table index for AOT modules,
+//   definition order for JIT modules). Its header carries `LINK_VERSION`,
+//   which `block_map` checks.
+// - AOT modules export the dispatch `table` and `set_block` (see
+//   `wasm_builder`); block functions are exported as `block_<addr>` except
+//   in split dispatchers. With trap exceptions they export the
+//   `guest_trap` tag, `($cause i32, $pc i64, $addr i64)`, which `run` throws.
+
+use anyhow::{bail, Context, Result};
+use std::borrow::Cow;
+use wasm_encoder::{CustomSection, MemoryType};
+
+/// Bumped whenever the contract above changes incompatibly
+pub const LINK_VERSION: u32 = 3;
+
+/// Module name of every import
+pub const IMPORT_MODULE: &str = "env";
+pub const MEMORY: &str = "memory";
+pub const SYSCALL: &str = "syscall";
+/// `env.syscall` of JSPI modules (`CompileOptions::jspi`), with a cause
+pub const SYSCALL_ASYNC: &str = "syscall_async";
+pub const YIELD: &str = "yield";
+pub const CRYPTO: &str = "crypto";
+pub const TABLE: &str = "table";
+pub const COMPILE_MISSING: &str = "compile_missing";
+/// Unknown-PC hook (`CompileOptions::unknown_pc`)
+pub const UNKNOWN_PC: &str = "unknown_pc";
+/// Watchpoint hook (`CompileOptions::watch`)
+pub const WATCH: &str = "watch";
+/// Breakpoint hook (`CompileOptions::breakpoints`)
+pub const BREAKPOINT: &str = "breakpoint";
+/// Exception tag thrown on traps (`CompileOptions::trap_exceptions`)
+pub const GUEST_TRAP: &str = "guest_trap";
+/// Name of the block map custom section
+pub const BLOCK_MAP: &str = "friscy.blocks";
+
+/// Function index of `env.yield` in every module
+pub const YIELD_FUNC: u32 = 1;
+/// Function index of `env.crypto` in every module
+pub const CRYPTO_FUNC: u32 = 2;
+/// Function imports every module starts with (syscall, yield, crypto)
+pub const IMPORTED_FUNCS: u32 = 3;
+/// Function index of `env.watch` in debug builds
+pub const WATCH_FUNC: u32 = 3;
+/// Function index of `env.breakpoint` in debug builds
+pub const BREAKPOINT_FUNC: u32 = 4;
+/// Function imports debug builds add after `IMPORTED_FUNCS` (watch, breakpoint)
+pub const DEBUG_FUNCS: u32 = 2;
+
+/// Largest guest memory any module accepts (4GB)
+pub const MAX_PAGES: u64 = 65536;
+
+/// The `env.memory` import: at least `pages`, up to `MAX_PAGES`
+pub fn memory_type(pages: u64, shared: bool) -> MemoryType {
+    MemoryType {
+        minimum: pages,
+        maximum: Some(MAX_PAGES),
+        memory64: false,
+        shared,
+    }
+}
+
+/// The block map section for blocks at `block_addrs` (in index order).
+/// Layout: version (u32), count (u32), then one u64 address per block, all
+/// little-endian.
+pub fn block_map_section(block_addrs: &[u64]) -> CustomSection<'static> {
+    let mut data = Vec::with_capacity(8 + block_addrs.len() * 8);
+    data.extend(LINK_VERSION.to_le_bytes());
+    data.extend((block_addrs.len() as u32).to_le_bytes());
+    for addr in block_addrs {
+        data.extend(addr.to_le_bytes());
+    }
+    CustomSection {
+        name: Cow::Borrowed(BLOCK_MAP),
+        data: Cow::Owned(data),
+    }
+}
+
+/// Read the block map of an emitted module, checking its version
+pub fn block_map(wasm: &[u8]) -> Result<Vec<u64>> {
+    match custom_section(wasm, BLOCK_MAP)? {
+        Some(data) => parse_block_map(data),
+        None => bail!("Module has no {} section", BLOCK_MAP),
+    }
+}
+
+/// The sections of a module as (id, contents)
+pub(crate) fn sections(wasm: &[u8]) -> Result<Vec<(u8, &[u8])>> {
+    if wasm.get(..8) != Some(b"\0asm\x01\0\0\0".as_slice()) {
+        bail!("Not a Wasm module");
+    }
+    let mut sections = Vec::new();
+    let mut rest = &wasm[8..];
+    while let Some((&id, tail)) = rest.split_first() {
+        let (size, tail) = read_leb(tail)?;
+        sections.push((id, tail.get(..size as usize).context("Truncated section")?));
+        rest = &tail[size as usize..];
+    }
+    Ok(sections)
+}
+
+/// The contents of the custom section `name`, if the module has one
+pub(crate) fn custom_section<'a>(wasm: &'a [u8], name: &str) -> Result<Option<&'a [u8]>> {
+    for (id, section) in sections(wasm)? {
+        if id != 0 {
+            continue;
+        }
+        let (len, section) = read_leb(section)?;
+        if section.get(..len as usize).context("Truncated section name")? == name.as_bytes() {
+            return Ok(Some(&section[len as usize..]));
+        }
+    }
+    Ok(None)
+}
+
+fn parse_block_map(data: &[u8]) -> Result<Vec<u64>> {
+    let word = |at: usize| -> Result<u32> {
+        let bytes = data.get(at..at + 4).context("Truncated block map")?;
+        Ok(u32::from_le_bytes(bytes.try_into().unwrap()))
+    };
+    let version = word(0)?;
+    if version != LINK_VERSION {
+        bail!("Module link version {} (expected {})", version, LINK_VERSION);
+    }
+    let count = word(4)? as usize;
+    let addrs = data.get(8..).filter(|a| a.len() == count * 8).context("Block map size mismatch")?;
+    Ok(addrs.chunks_exact(8).map(|a| u64::from_le_bytes(a.try_into().unwrap())).collect())
+}
+
+pub(crate) fn read_leb(bytes: &[u8]) -> Result<(u32, &[u8])> {
+    let mut value = 0u32;
+    for (i, &b) in bytes.iter().enumerate().take(5) {
+        value |= ((b & 0x7f) as u32) << (7 * i);
+        if b & 0x80 == 0 {
+            return Ok((value, &bytes[i + 1..]));
+        }
+    }
+    bail!("Malformed LEB128")
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_block_map_round_trip() {
+        let mut module = wasm_encoder::Module::new();
+        module.section(&block_map_section(&[0x1008, 0x1000]));
+        let wasm = module.finish();
+        assert_eq!(block_map(&wasm).unwrap(), [0x1008, 0x1000]);
+
+        // Same section with a future version
+        let mut bumped = wasm.clone();
+        let at = bumped.len() - 24;
+        bumped[at] += 1;
+        assert!(block_map(&bumped).unwrap_err().to_string().contains("link version"));
+        assert!(block_map(&wasm_encoder::Module::new().finish()).is_err());
+    }
+}
@@ -0,0 +1,382 @@
+// loader.rs - ELF program loader
+//
+// Produces the initial process image for a RISC-V program: the main
+// executable's PT_LOAD segments (biased for PIE), the dynamic interpreter's
+// segments if there is one, the TLS template, and the initial stack with
+// argc/argv/envp/auxv laid out as the Linux kernel does at execve.
+//
+// The result is plain data. The AOT data-section emitter, the native
+// runtime and the browser host each copy it into their own guest memory.
+
+use crate::elf::ElfFile;
+use anyhow::{bail, Context, Result};
+use goblin::elf::{header, program_header, Elf};
+
+/// Default load address for PIE main executables
+pub const DEFAULT_MAIN_BIAS: u64 = 0x0040_0000;
+/// Default load address for the dynamic interpreter. Code must stay below
+/// 0x40000000 so PCs survive the ECALL/EBREAK block return encoding.
+pub const DEFAULT_INTERP_BIAS: u64 = 0x2000_0000;
+/// Default top of the initial stack
+pub const DEFAULT_STACK_TOP: u64 = 0x8000_0000;
+/// Default initial stack reservation
+pub const DEFAULT_STACK_SIZE: u64 = 8 << 20;
+
+const PAGE_SIZE: u64 = 4096;
+
+// Auxiliary vector keys
+pub const AT_NULL: u64 = 0;
+pub const AT_PHDR: u64 = 3;
+pub const AT_PHENT: u64 = 4;
+pub const AT_PHNUM: u64 = 5;
+pub const AT_PAGESZ: u64 = 6;
+pub const AT_BASE: u64 = 7;
+pub const AT_FLAGS: u64 = 8;
+pub const AT_ENTRY: u64 = 9;
+pub const AT_UID: u64 = 11;
+pub const AT_EUID: u64 = 12;
+pub const AT_GID: u64 = 13;
+pub const AT_EGID: u64 = 14;
+pub const AT_HWCAP: u64 = 16;
+pub const AT_CLKTCK: u64 = 17;
+pub const AT_SECURE: u64 = 23;
+pub const AT_RANDOM: u64 = 25;
+pub const AT_EXECFN: u64 = 31;
+
+/// AT_HWCAP for RV64IMAFDC: bit (letter - 'A') per extension
+const HWCAP_RV64GC: u64 = {
+    let mut bits = 0;
+    let exts = b"IMAFDC";
+    let mut i = 0;
+    while i < exts.len() {
+        bits |= 1 << (exts[i] - b'A');
+        i += 1;
+    }
+    bits
+};
+
+/// Inputs to program loading
+#[derive(Debug, Clone)]
+pub struct LoadOptions {
+    /// Load bias applied to a PIE main executable
+    pub main_bias: u64,
+    /// Load bias applied to the interpreter
+    pub interp_bias: u64,
+    /// Highest stack address (exclusive)
+    pub stack_top: u64,
+    /// Bytes reserved below `stack_top` for the stack
+    pub stack_size: u64,
+    /// Program arguments, argv[0] included
+    pub argv: Vec<String>,
+    /// Environment as KEY=VALUE strings
+    pub envp: Vec<String>,
+    /// Path reported through AT_EXECFN (defaults to argv[0])
+    pub execfn: Option<String>,
+    /// Bytes pointed to by AT_RANDOM (fixed for reproducible runs)
+    pub random: [u8; 16],
+}
+
+impl Default for LoadOptions {
+    fn default() -> Self {
+        Self {
+            main_bias: DEFAULT_MAIN_BIAS,
+            interp_bias: DEFAULT_INTERP_BIAS,
+            stack_top: DEFAULT_STACK_TOP,
+            stack_size: DEFAULT_STACK_SIZE,
+            argv: Vec::new(),
+            envp: Vec::new(),
+            execfn: None,
+            random: [0; 16],
+        }
+    }
+}
+
+/// A PT_LOAD segment at its final address. Bytes past `data` up to
+/// `memsz` are bss and must read as zero.
+#[derive(Debug, Clone)]
+pub struct LoadedSegment {
+    pub vaddr: u64,
+    pub data: Vec<u8>,
+    pub memsz: u64,
+    /// PF_X = 1, PF_W = 2, PF_R = 4
+    pub flags: u32,
+}
+
+/// The PT_TLS initialization template of the main executable
+#[derive(Debug, Clone, Copy)]
+pub struct TlsTemplate {
+    pub vaddr: u64,
+    pub filesz: u64,
+    pub memsz: u64,
+    pub align: u64,
+}
+
+/// The initial stack: `data` is written at `sp`, the reservation spans
+/// `[top - size, top)`
+#[derive(Debug, Clone)]
+pub struct StackImage {
+    pub top: u64,
+    pub size: u64,
+    pub sp: u64,
+    pub data: Vec<u8>,
+}
+
+/// Everything needed to start executing a program
+#[derive(Debug, Clone)]
+pub struct InitialImage {
+    pub segments: Vec<LoadedSegment>,
+    /// First PC: the interpreter's entry if there is one, else the program's
+    pub entry: u64,
+    /// The main executable's (biased) entry point
+    pub main_entry: u64,
+    pub main_bias: u64,
+    pub interp_bias: Option<u64>,
+    /// Initial program break: page-aligned end of the main executable
+    pub brk_base: u64,
+    pub tls: Option<TlsTemplate>,
+    pub stack: StackImage,
+    pub auxv: Vec<(u64, u64)>,
+    /// Initial integer registers (x2 = sp, everything else zero)
+    pub regs: [u64; 32],
+}
+
+impl InitialImage {
+    /// (address, bytes) pairs that must be copied into guest memory
+    pub fn data_segments(&self) -> impl Iterator<Item = (u64, &[u8])> {
+        self.segments
+            .iter()
+            .map(|s| (s.vaddr, s.data.as_slice()))
+            .chain(std::iter::once((self.stack.sp, self.stack.data.as_slice())))
+    }
+}
+
+/// Load a main executable and, if given, its dynamic interpreter
+pub fn load(main: &[u8], interp: Option<&[u8]>, opts: &LoadOptions) -> Result<InitialImage> {
+    let main = ElfFile::parse(main)?;
+    let interp = interp.map(ElfFile::parse).transpose().context("Invalid interpreter ELF")?;
+    load_parsed(&main, interp.as_ref(), opts)
+}
+
+/// `load` for files the caller has already parsed
+pub fn load_parsed(main: &ElfFile, interp: Option<&ElfFile>, opts: &LoadOptions) -> Result<InitialImage> {
+    let elf = main.elf();
+    let main = main.data();
+
+    let main_bias = if elf.header.e_type == header::ET_DYN { opts.main_bias } else { 0 };
+    let mut segments = load_segments(elf, main, main_bias)?;
+
+    let brk_base = segments
+        .iter()
+        .map(|s| s.vaddr + s.memsz)
+        .max()
+        .unwrap_or(main_bias)
+        .div_ceil(PAGE_SIZE)
+        * PAGE_SIZE;
+
+    let tls = elf
+        .program_headers
+        .iter()
+        .find(|ph| ph.p_type == program_header::PT_TLS)
+        .map(|ph| TlsTemplate {
+            vaddr: ph.p_vaddr + main_bias,
+            filesz: ph.p_filesz,
+            memsz: ph.p_memsz,
+            align: ph.p_align,
+        });
+
+    let main_entry = elf.entry + main_bias;
+    let phdr = phdr_vaddr(elf).context("Cannot locate program headers in memory")? + main_bias;
+
+    let (entry, interp_bias) = match (interp, elf.interpreter) {
+        (Some(file), _) => {
+            let ielf = file.elf();
+            segments.extend(load_segments(ielf, file.data(), opts.interp_bias)?);
+            (ielf.entry + opts.interp_bias, Some(opts.interp_bias))
+        }
+        (None, Some(path)) => bail!("Program requires interpreter {} but none was provided", path),
+        (None, None) => (main_entry, None),
+    };
+
+    let mut auxv = vec![
+        (AT_PHDR, phdr),
+        (AT_PHENT, elf.header.e_phentsize as u64),
+        (AT_PHNUM, elf.header.e_phnum as u64),
+        (AT_PAGESZ, PAGE_SIZE),
+        (AT_BASE, interp_bias.unwrap_or(0)),
+        (AT_FLAGS, 0),
+        (AT_ENTRY, main_entry),
+        (AT_UID, 0),
+        (AT_EUID, 0),
+        (AT_GID, 0),
+        (AT_EGID, 0),
+        (AT_HWCAP, HWCAP_RV64GC),
+        (AT_CLKTCK, 100),
+        (AT_SECURE, 0),
+    ];
+    let stack = build_stack(opts, &mut auxv);
+
+    let mut regs = [0u64; 32];
+    regs[2] = stack.sp;
+
+    Ok(InitialImage {
+        segments,
+        entry,
+        main_entry,
+        main_bias,
+        interp_bias,
+        brk_base,
+        tls,
+        stack,
+        auxv,
+        regs,
+    })
+}
+
+fn load_segments(elf: &Elf, data: &[u8], bias: u64) -> Result<Vec<LoadedSegment>> {
+    elf.program_headers
+        .iter()
+        .filter(|ph| ph.p_type == program_header::PT_LOAD && ph.p_memsz > 0)
+        .map(|ph| {
+            let start = ph.p_offset as usize;
+            let bytes = data
+                .get(start..start + ph.p_filesz as usize)
+                .context("PT_LOAD segment extends past end of file")?;
+            Ok(LoadedSegment {
+                vaddr: ph.p_vaddr + bias,
+                data: bytes.to_vec(),
+                memsz: ph.p_memsz,
+                flags: ph.p_flags,
+            })
+        })
+        .collect()
+}
+
+/// Unbiased address of the program header table: PT_PHDR if present,
+/// else wherever the PT_LOAD covering e_phoff puts it
+fn phdr_vaddr(elf: &Elf) -> Option<u64> {
+    if let Some(ph) = elf.program_headers.iter().find(|ph| ph.p_type == program_header::PT_PHDR) {
+        return Some(ph.p_vaddr);
+    }
+    let phoff = elf.header.e_phoff;
+    elf.program_headers
+        .iter()
+        .find(|ph| {
+            ph.p_type == program_header::PT_LOAD
+                && phoff >= ph.p_offset
+                && phoff < ph.p_offset + ph.p_filesz
+        })
+        .map(|ph| ph.p_vaddr + (phoff - ph.p_offset))
+}
+
+/// Lay out the initial stack. From high to low addresses: string area
+/// (execfn, argv, envp, AT_RANDOM bytes), then 16-byte aligned argc,
+/// argv[], NULL, envp[], NULL, auxv pairs, AT_NULL.
+fn build_stack(opts: &LoadOptions, auxv: &mut Vec<(u64, u64)>) -> StackImage {
+    let execfn = opts
+        .execfn
+        .clone()
+        .or_else(|| opts.argv.first().cloned())
+        .unwrap_or_default();
+
+    let mut strings: Vec<u8> = Vec::new();
+    let mut push_str = |s: &str| {
+        let off = strings.len() as u64;
+        strings.extend_from_slice(s.as_bytes());
+        strings.push(0);
+        off
+    };
+    let execfn_off = push_str(&execfn);
+    let argv_offs: Vec<u64> = opts.argv.iter().map(|s| push_str(s)).collect();
+    let envp_offs: Vec<u64> = opts.envp.iter().map(|s| push_str(s)).collect();
+    let random_off = strings.len() as u64;
+    strings.extend_from_slice(&opts.random);
+
+    let str_base = (opts.stack_top - strings.len() as u64) & !15;
+    auxv.push((AT_RANDOM, str_base + random_off));
+    auxv.push((AT_EXECFN, str_base + execfn_off));
+
+    let mut words: Vec<u64> = vec![opts.argv.len() as u64];
+    words.extend(argv_offs.iter().map(|o| str_base + o));
+    words.push(0);
+    words.extend(envp_offs.iter().map(|o| str_base + o));
+    words.push(0);
+    for &(k, v) in auxv.iter() {
+        words.extend([k, v]);
+    }
+    words.extend([AT_NULL, 0]);
+
+    let sp = (str_base - words.len() as u64 * 8) & !15;
+    let mut data = vec![0u8; (opts.stack_top - sp) as usize];
+    for (i, w) in words.iter().enumerate() {
+        data[i * 8..i * 8 + 8].copy_from_slice(&w.to_le_bytes());
+    }
+    let s = (str_base - sp) as usize;
+    data[s..s + strings.len()].copy_from_slice(&strings);
+
+    StackImage {
+        top: opts.stack_top,
+        size: opts.stack_size,
+        sp,
+        data,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::elf::tiny_elf;
+
+    fn word(stack: &StackImage, addr: u64) -> u64 {
+        let i = (addr - stack.sp) as usize;
+        u64::from_le_bytes(stack.data[i..i + 8].try_into().unwrap())
+    }
+
+    fn cstr(stack: &StackImage, addr: u64) -> String {
+        let i = (addr - stack.sp) as usize;
+        let end = stack.data[i..].iter().position(|&b| b == 0).unwrap();
+        String::from_utf8(stack.data[i..i + end].to_vec()).unwrap()
+    }
+
+    #[test]
+    fn test_load_static_executable() {
+        let opts = LoadOptions {
+            argv: vec!["/bin/true".into(), "-x".into()],
+            envp: vec!["HOME=/root".into()],
+            ..Default::default()
+        };
+        // Two ecalls after the headers, and p_memsz grown to add bss
+        let mut elf = tiny_elf(header::ET_EXEC, &[0x00000073, 0x00000073]);
+        elf[104..112].copy_from_slice(&0x2000u64.to_le_bytes());
+        let image = load(&elf, None, &opts).unwrap();
+
+        assert_eq!(image.entry, 0x1078);
+        assert_eq!(image.main_bias, 0, "ET_EXEC is not relocated");
+        assert_eq!(image.brk_base, 0x3000);
+        assert_eq!(image.segments.len(), 1);
+        assert_eq!(image.segments[0].data.len(), 128);
+
+        let st = &image.stack;
+        assert_eq!(st.sp % 16, 0);
+        assert_eq!(image.regs[2], st.sp);
+        assert_eq!(word(st, st.sp), 2); // argc
+        assert_eq!(cstr(st, word(st, st.sp + 8)), "/bin/true");
+        assert_eq!(cstr(st, word(st, st.sp + 16)), "-x");
+        assert_eq!(word(st, st.sp + 24), 0);
+        assert_eq!(cstr(st, word(st, st.sp + 32)), "HOME=/root");
+        assert_eq!(word(st, st.sp + 40), 0);
+
+        let auxv_at = st.sp + 48;
+        let mut aux = std::collections::HashMap::new();
+        for i in 0.. {
+            let (k, v) = (word(st, auxv_at + i * 16), word(st, auxv_at + i * 16 + 8));
+            if k == AT_NULL {
+                break;
+            }
+            aux.insert(k, v);
+        }
+        assert_eq!(aux[&AT_ENTRY], 0x1078);
+        assert_eq!(aux[&AT_PHDR], 0x1040);
+        assert_eq!(aux[&AT_PHNUM], 1);
+        assert_eq!(cstr(st, aux[&AT_EXECFN]), "/bin/true");
+    }
+}
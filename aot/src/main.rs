@@ -6,6 +6,9 @@
 // Usage:
 //   rv2wasm input.elf -o output.wasm
 //   rv2wasm --rootfs rootfs.tar --entry /bin/busybox -o bundle.wasm
+//   rv2wasm --rootfs rootfs.tar --entry /bin/busybox --embed-rootfs -o bundle.wasm
+//   rv2wasm --image app.oci --embed-rootfs -o app.wasm
+//   rv2wasm busybox --bundle /lib/ld.so=ld.so@0x4000000 -o bundle.wasm
 
 #[cfg(not(feature = "cli"))]
 fn main() {
@@ -18,10 +21,17 @@ use anyhow::{Context, Result};
 #[cfg(feature = "cli")]
 use clap::Parser;
 #[cfg(feature = "cli")]
+use std::io::{BufWriter, Write};
+#[cfg(feature = "cli")]
 use std::path::PathBuf;
 
 #[cfg(feature = "cli")]
-use rv2wasm::{cfg, disasm, elf, translate, wasm_builder};
+use rv2wasm::cache::Cache;
+#[cfg(feature = "cli")]
+use rv2wasm::{
+    abi, bundle, cfg, data, disasm, elf, freq, incremental, irdiff, mmu, oci, plt, report, rootfs, startup,
+    translate, wasm_builder, BlockProfile, CompileOptions, Denied, FmaMode, SyscallPolicy, UnknownPc,
+};
 
 #[cfg(feature = "cli")]
 #[derive(Parser, Debug)]
@@ -30,7 +40,7 @@ use rv2wasm::{cfg, disasm, elf, translate, wasm_builder};
 #[command(version)]
 struct Args {
     /// Input RISC-V ELF binary
-    #[arg(required_unless_present = "rootfs")]
+    #[arg(required_unless_present = "container")]
     input: Option<PathBuf>,
 
     /// Output Wasm file
@@ -38,26 +48,473 @@ struct Args {
     output: PathBuf,
 
     /// Container rootfs tar (for container mode)
-    #[arg(long)]
+    #[arg(long, group = "container")]
     rootfs: Option<PathBuf>,
 
-    /// Entry point binary within rootfs
-    #[arg(long, requires = "rootfs")]
+    /// Container image (oci-archive or docker-archive) for container mode:
+    /// its layers make the rootfs, its Entrypoint/Cmd the entry, and its
+    /// argv, Env and WorkingDir go to <output>.container.json
+    #[arg(long, group = "container")]
+    image: Option<PathBuf>,
+
+    /// Entry point binary within rootfs (for --image, instead of the
+    /// image's Entrypoint/Cmd)
+    #[arg(long, requires = "container")]
     entry: Option<String>,
 
+    /// Append the rootfs to the output, one zstd frame per file, as the
+    /// friscy.rootfs custom section; hosts decompress files as they are opened
+    #[arg(long, requires = "container")]
+    embed_rootfs: bool,
+
     /// Emit debug info (block addresses, instruction comments)
     #[arg(long)]
     debug: bool,
 
-    /// Optimization level (0-3)
-    #[arg(short = 'O', default_value = "2")]
-    opt_level: u8,
+    /// Optimization level (0-3), or s to optimize for size: no per-block
+    /// exports unless --debug, identical blocks merged, compact dispatch
+    #[arg(short = 'O', default_value = "2", value_parser = parse_opt_level)]
+    opt_level: (u8, bool),
+
+    /// Threads mode: shared memory, atomic AMO lowering, futex helpers
+    #[arg(long)]
+    threads: bool,
+
+    /// Lay out block functions hottest first (estimated statically without
+    /// --block-profile)
+    #[arg(long)]
+    hot_first: bool,
+
+    /// Block counts from `friscy-run --block-profile`; implies --hot-first
+    #[arg(long)]
+    block_profile: Option<PathBuf>,
+
+    /// Export a guest function as `guest_<name>` (a0-a7 in, (a0, a1) out),
+    /// or the block at a 0x address as `block_<addr>`; repeatable. Once a
+    /// block is given, only the listed blocks are exported instead of all
+    /// of them.
+    #[arg(long = "export", value_name = "SYMBOL|ADDR")]
+    exports: Vec<String>,
+
+    /// Export every function symbol
+    #[arg(long)]
+    export_all: bool,
+
+    /// Replace a guest function with a call to the host's env.host_<name>,
+    /// which gets a0-a7 and returns a0 (memcpy, getenv, ...); repeatable
+    #[arg(long = "interpose", value_name = "SYMBOL")]
+    interpose: Vec<String>,
+
+    /// Split output: dispatcher plus lazily loaded code modules
+    /// (output.0.wasm, ...) of about this many blocks each
+    #[arg(long, value_name = "BLOCKS")]
+    split: Option<usize>,
+
+    /// Embed the initial memory image as passive data segments, copied in
+    /// on demand by the exported init_data(addr) / init_memory(), and again
+    /// by reset($m)
+    #[arg(long)]
+    lazy_data: bool,
+
+    /// Write the instructions the translator cannot lower (counts,
+    /// addresses, disassembly context) to this file
+    #[arg(long, value_name = "PATH")]
+    unsupported_report: Option<PathBuf>,
+
+    /// Print the CFG with static frequency estimates: dot writes a Graphviz
+    /// graph to output.cfg.dot, stats a summary with the hottest blocks to
+    /// stderr; repeatable
+    #[arg(long, value_name = "FORMAT", value_parser = ["dot", "stats"])]
+    emit: Vec<String>,
+
+    /// Write the IR of every block function to this file as text
+    #[arg(long, value_name = "PATH")]
+    dump_ir: Option<PathBuf>,
+
+    /// Instead of writing a module, report the blocks whose IR differs from
+    /// this --dump-ir file (e.g. one from another rv2wasm version)
+    #[arg(long, value_name = "DUMP", conflicts_with = "diff_ir_args")]
+    diff_ir: Option<PathBuf>,
+
+    /// Instead of writing a module, report the blocks whose IR differs when
+    /// the input is compiled with these options instead, e.g. "-O1 --threads"
+    /// (--load-bias and --entry-symbol stay as given)
+    #[arg(long, value_name = "ARGS", allow_hyphen_values = true)]
+    diff_ir_args: Option<String>,
+
+    /// Reuse earlier builds (whole outputs, and the code of unchanged
+    /// guest functions) cached in this directory
+    #[arg(long, value_name = "DIR")]
+    cache_dir: Option<PathBuf>,
+
+    /// Reuse the code of the guest functions that did not change since the
+    /// build in this file (typically the output itself), retranslating only
+    /// the rest; a missing file means a full build
+    #[arg(long, value_name = "PREV", conflicts_with_all = ["cache_dir", "split"])]
+    incremental: Option<PathBuf>,
+
+    /// Translate a PIE for this load address bias (hex with 0x, or
+    /// decimal; page-aligned)
+    #[arg(long, value_name = "BIAS", default_value = "0", value_parser = parse_addr)]
+    load_bias: u64,
+
+    /// Place guest address 0 at this linear-memory address (hex with 0x,
+    /// or decimal; page-aligned)
+    #[arg(long, value_name = "ADDR", default_value = "0", value_parser = parse_addr)]
+    memory_offset: u64,
+
+    /// Map guest memory through a software page table whose root is at this
+    /// linear address (hex with 0x, or decimal), for mmap at any address,
+    /// guard pages and mprotect. Slower than flat memory.
+    #[arg(long, value_name = "ROOT", value_parser = parse_addr)]
+    soft_mmu: Option<u64>,
+
+    /// Answer clock_gettime and gettimeofday from a time page the host keeps
+    /// current at this guest address (hex with 0x, or decimal; page-aligned)
+    /// instead of exiting to the host
+    #[arg(long, value_name = "ADDR", value_parser = parse_addr)]
+    time_page: Option<u64>,
+
+    /// Hand PCs without a block to the host's env.compile_missing (e.g. a
+    /// JIT) instead of halting
+    #[arg(long)]
+    lazy_compile: bool,
+
+    /// At a PC without code: halt (exit 0, the default), stop (end the run
+    /// with a distinct status), trap, or import (ask the host's
+    /// env.unknown_pc where to continue). All but halt record the PC and
+    /// the last block run in the machine state.
+    #[arg(long, value_name = "POLICY", default_value = "halt", value_parser = parse_unknown_pc)]
+    unknown_pc: UnknownPc,
+
+    /// Hot/cold split: compile only code reachable from the entry (or, with
+    /// --block-profile, the functions it sampled); the rest goes through
+    /// env.compile_missing as with --lazy-compile
+    #[arg(long)]
+    hot_only: bool,
+
+    /// Check the IR of every block before encoding, reporting the guest
+    /// block and instruction behind a malformed body (always on in debug
+    /// builds)
+    #[arg(long)]
+    verify_ir: bool,
+
+    /// Fused multiply-adds: unfused (multiply then add, rounding twice),
+    /// relaxed (relaxed-SIMD madd, fused on hosts with FMA) or exact (a
+    /// software sequence that rounds once, as hardware does)
+    #[arg(long, value_name = "MODE", default_value = "unfused", value_parser = parse_fma)]
+    fma: FmaMode,
+
+    /// Let only these syscalls (comma-separated numbers) reach the host;
+    /// the others are refused in the module itself
+    #[arg(long, value_name = "NR,...", value_delimiter = ',', conflicts_with = "deny_syscalls")]
+    allow_syscalls: Vec<u64>,
+
+    /// Refuse these syscalls (comma-separated numbers) in the module itself
+    #[arg(long, value_name = "NR,...", value_delimiter = ',')]
+    deny_syscalls: Vec<u64>,
+
+    /// What a refused syscall does: enosys (fail with -ENOSYS) or trap
+    /// (stop the guest with SIGILL at the ECALL)
+    #[arg(long, value_name = "ACTION", default_value = "enosys", value_parser = parse_denied)]
+    denied_syscall: Denied,
+
+    /// Count executed instructions against a budget the host stores in the
+    /// machine state; run returns STOP_BUDGET once it runs out
+    #[arg(long)]
+    meter: bool,
+
+    /// Refuse to grow guest memory past this many 64KB pages
+    #[arg(long, value_name = "PAGES")]
+    max_pages: Option<u32>,
+
+    /// Make syscalls through env.syscall_async(m, pc, cause), for hosts that
+    /// suspend on them with JSPI (WebAssembly.Suspending) instead of blocking
+    #[arg(long)]
+    jspi: bool,
+
+    /// Throw traps (illegal instruction, misaligned atomic, page fault) as
+    /// the exported guest_trap exception, carrying (cause, pc, addr)
+    #[arg(long)]
+    trap_exceptions: bool,
+
+    /// Harts of an SMP guest (implies --threads): each runs its own
+    /// dispatch loop on a worker and reads its id from mhartid
+    #[arg(long, value_name = "N", default_value_t = 1, value_parser = clap::value_parser!(u32).range(1..))]
+    harts: u32,
+
+    /// Call the host's env.watch(addr, size, is_write, pc) before every load
+    /// or store overlapping this guest range, given as ADDR:LEN (each hex
+    /// with 0x, or decimal)
+    #[arg(long, value_name = "ADDR:LEN", value_parser = parse_watch)]
+    watch: Option<std::ops::Range<u32>>,
+
+    /// Call the host's env.breakpoint(pc) before the instruction at this
+    /// address, stopping there if it returns nonzero (hex with 0x, or
+    /// decimal); repeatable
+    #[arg(long = "breakpoint", value_name = "ADDR", value_parser = parse_addr)]
+    breakpoints: Vec<u64>,
+
+    /// Instead of a module, write a patch that swaps the block containing
+    /// this address, recompiled (with --breakpoint, say), into the dispatch
+    /// table of the module built with the same options
+    #[arg(long, value_name = "ADDR", value_parser = parse_addr)]
+    patch_block: Option<u64>,
+
+    /// Overwrite the loaded image at ADDR with BYTES (hex, in memory order,
+    /// e.g. 0x1234=13000000 for a nop) before disassembly; the input file
+    /// is left alone. Repeatable.
+    #[arg(long = "patch", value_name = "ADDR=BYTES", value_parser = parse_byte_patch)]
+    patches: Vec<elf::BytePatch>,
+
+    /// Compile another ELF into the same module, as NAME (the path a guest
+    /// execs or maps it by; default PATH) at load bias BIAS (default 0):
+    /// one address space and dispatch table, so an exec between bundled
+    /// binaries stays in AOT code. Repeatable; writes output.bundle.json.
+    #[arg(long = "bundle", value_name = "[NAME=]PATH[@BIAS]", value_parser = parse_bundle)]
+    bundle: Vec<(String, PathBuf, u64)>,
+
+    /// Enter at this function instead of the ELF entry point, e.g. main
+    /// (the host then sets up its arguments). main and the libc init
+    /// functions are found through the startup code in stripped binaries.
+    #[arg(long, value_name = "SYMBOL")]
+    entry_symbol: Option<String>,
 
     /// Verbose output
     #[arg(short, long)]
     verbose: bool,
 }
 
+/// `-O` value: (opt level, size mode); `s` is level 2 plus size mode
+#[cfg(feature = "cli")]
+fn parse_opt_level(s: &str) -> Result<(u8, bool), String> {
+    match s {
+        "s" => Ok((2, true)),
+        _ => match s.parse::<u8>() {
+            Ok(level) if level <= 3 => Ok((level, false)),
+            _ => Err(format!("expected 0-3 or s, got {}", s)),
+        },
+    }
+}
+
+#[cfg(feature = "cli")]
+fn parse_fma(s: &str) -> Result<FmaMode, String> {
+    match s {
+        "unfused" => Ok(FmaMode::Unfused),
+        "relaxed" => Ok(FmaMode::Relaxed),
+        "exact" => Ok(FmaMode::Exact),
+        _ => Err(format!("expected unfused, relaxed or exact, got {}", s)),
+    }
+}
+
+#[cfg(feature = "cli")]
+fn parse_unknown_pc(s: &str) -> Result<UnknownPc, String> {
+    match s {
+        "halt" => Ok(UnknownPc::Halt),
+        "stop" => Ok(UnknownPc::Stop),
+        "trap" => Ok(UnknownPc::Trap),
+        "import" => Ok(UnknownPc::Import),
+        _ => Err(format!("expected halt, stop, trap or import, got {}", s)),
+    }
+}
+
+#[cfg(feature = "cli")]
+fn parse_denied(s: &str) -> Result<Denied, String> {
+    match s {
+        "enosys" => Ok(Denied::Enosys),
+        "trap" => Ok(Denied::Trap),
+        _ => Err(format!("expected enosys or trap, got {}", s)),
+    }
+}
+
+/// An address: hex with a 0x prefix, or decimal
+#[cfg(feature = "cli")]
+fn parse_addr(s: &str) -> Result<u64, String> {
+    let parsed = match s.strip_prefix("0x") {
+        Some(hex) => u64::from_str_radix(hex, 16),
+        None => s.parse(),
+    };
+    parsed.map_err(|e| format!("bad address {}: {}", s, e))
+}
+
+/// `--watch` value: ADDR:LEN as a non-empty 32-bit guest range
+#[cfg(feature = "cli")]
+fn parse_watch(s: &str) -> Result<std::ops::Range<u32>, String> {
+    let (addr, len) = s.split_once(':').ok_or_else(|| format!("expected ADDR:LEN, got {}", s))?;
+    let (addr, len) = (parse_addr(addr)?, parse_addr(len)?);
+    match (u32::try_from(addr), addr.checked_add(len).map(u32::try_from)) {
+        (Ok(start), Some(Ok(end))) if len > 0 => Ok(start..end),
+        _ => Err(format!("{} is not a non-empty range below 4GB", s)),
+    }
+}
+
+/// `--patch` value: ADDR=BYTES with BYTES as hex digit pairs
+#[cfg(feature = "cli")]
+fn parse_byte_patch(s: &str) -> Result<elf::BytePatch, String> {
+    let (addr, hex) = s.split_once('=').ok_or_else(|| format!("expected ADDR=BYTES, got {}", s))?;
+    if hex.is_empty() || !hex.len().is_multiple_of(2) {
+        return Err(format!("expected an even number of hex digits, got {}", hex));
+    }
+    let bytes = (0..hex.len())
+        .step_by(2)
+        .map(|i| u8::from_str_radix(hex.get(i..i + 2).unwrap_or("?"), 16))
+        .collect::<Result<_, _>>()
+        .map_err(|e| format!("bad bytes {}: {}", hex, e))?;
+    Ok(elf::BytePatch { addr: parse_addr(addr)?, bytes })
+}
+
+/// `--bundle` value: ([NAME=]PATH[@BIAS]) as (name, path, bias)
+#[cfg(feature = "cli")]
+fn parse_bundle(s: &str) -> Result<(String, PathBuf, u64), String> {
+    let (rest, bias) = match s.rsplit_once('@') {
+        Some((rest, bias)) => (rest, parse_addr(bias)?),
+        None => (s, 0),
+    };
+    let (name, path) = rest.split_once('=').unwrap_or((rest, rest));
+    if path.is_empty() {
+        return Err(format!("expected [NAME=]PATH[@BIAS], got {}", s));
+    }
+    Ok((name.to_string(), path.into(), bias))
+}
+
+/// Split `--export` arguments: 0x<addr> picks a block, anything else a
+/// function symbol. Block exports are only narrowed to the picked blocks
+/// when there are some.
+#[cfg(feature = "cli")]
+fn split_exports<'a>(
+    exports: &'a [String],
+    cfg: &cfg::ControlFlowGraph,
+) -> Result<(Option<Vec<u64>>, Vec<&'a String>)> {
+    let (block_args, symbol_args): (Vec<&String>, Vec<&String>) =
+        exports.iter().partition(|name| name.starts_with("0x"));
+    let mut export_blocks = Vec::new();
+    for arg in &block_args {
+        let addr = u64::from_str_radix(&arg[2..], 16).with_context(|| format!("Bad block address {}", arg))?;
+        if !cfg.blocks.contains_key(&addr) {
+            anyhow::bail!("No block starts at {}", arg);
+        }
+        export_blocks.push(addr);
+    }
+    Ok(((!block_args.is_empty()).then_some(export_blocks), symbol_args))
+}
+
+/// Compile options from the command line, for `cfg` built from `file` and
+/// the `extras` bundled with it
+#[cfg(feature = "cli")]
+fn compile_options(
+    args: &Args,
+    file: &elf::ElfFile,
+    extras: &[bundle::Binary],
+    cfg: &cfg::ControlFlowGraph,
+) -> Result<CompileOptions> {
+    let block_profile = match &args.block_profile {
+        Some(path) => {
+            let text = std::fs::read_to_string(path).context("Failed to read block profile")?;
+            Some(BlockProfile::parse(&text)?)
+        }
+        None => None,
+    };
+
+    let (export_blocks, symbol_args) = split_exports(&args.exports, cfg)?;
+
+    let mut exports = file.symbols();
+    if !args.export_all {
+        exports.retain(|sym| symbol_args.contains(&&sym.name));
+        if let Some(missing) = symbol_args.iter().find(|name| !exports.iter().any(|s| &s.name == **name)) {
+            anyhow::bail!("No function symbol named {}", missing);
+        }
+    }
+
+    let symbols = file.symbols();
+    let mut interpose = Vec::new();
+    for name in &args.interpose {
+        let sym = symbols
+            .iter()
+            .find(|sym| &sym.name == name)
+            .with_context(|| format!("No function symbol named {}", name))?;
+        if !cfg.blocks.contains_key(&sym.addr) {
+            anyhow::bail!("{} is never reached, so it cannot be interposed", name);
+        }
+        interpose.push(sym.clone());
+    }
+
+    if args.soft_mmu.is_some() && (args.lazy_data || args.memory_offset != 0) {
+        anyhow::bail!("--soft-mmu cannot be combined with --lazy-data or --memory-offset");
+    }
+    let data = if args.lazy_data {
+        let files = [file].into_iter().chain(extras.iter().map(|b| &b.file));
+        data::chunks_of(files.map(|f| (f.data(), &f.info().segments[..])))?
+    } else {
+        Vec::new()
+    };
+    if args.verbose && args.lazy_data {
+        let total: usize = data.iter().map(|c| c.bytes.len()).sum();
+        eprintln!("  Data segments: {} ({} bytes)", data.len(), total);
+    }
+
+    Ok(CompileOptions {
+        opt_level: args.opt_level.0,
+        debug: args.debug,
+        threads: args.threads || args.harts > 1,
+        hot_first: args.hot_first || block_profile.is_some(),
+        block_profile,
+        exports,
+        split: args.split.unwrap_or(0),
+        data: data.into(),
+        size: args.opt_level.1,
+        export_blocks,
+        load_bias: args.load_bias,
+        memory_offset: u32::try_from(args.memory_offset)
+            .ok()
+            .filter(|offset| offset.is_multiple_of(0x1000))
+            .context("--memory-offset must be a page-aligned 32-bit address")?,
+        soft_mmu: match args.soft_mmu {
+            Some(root) => Some(
+                u32::try_from(root)
+                    .ok()
+                    .filter(|root| root.is_multiple_of(mmu::PAGE_SIZE))
+                    .context("--soft-mmu must be a page-aligned 32-bit address")?,
+            ),
+            None => None,
+        },
+        time_page: match args.time_page {
+            Some(page) => Some(
+                u32::try_from(page)
+                    .ok()
+                    .filter(|page| page.is_multiple_of(0x1000))
+                    .context("--time-page must be a page-aligned 32-bit address")?,
+            ),
+            None => None,
+        },
+        lazy_compile: args.lazy_compile,
+        unknown_pc: args.unknown_pc,
+        hot_only: args.hot_only,
+        verify_ir: args.verify_ir || cfg!(debug_assertions),
+        fma: args.fma,
+        jspi: args.jspi,
+        trap_exceptions: args.trap_exceptions,
+        harts: args.harts,
+        watch: args.watch.clone(),
+        breakpoints: args.breakpoints.iter().copied().collect(),
+        interpose,
+        syscall_policy: match (&args.allow_syscalls[..], &args.deny_syscalls[..]) {
+            ([], []) => None,
+            (allowed, []) => Some(SyscallPolicy {
+                syscalls: allowed.iter().copied().collect(),
+                allow: true,
+                denied: args.denied_syscall,
+            }),
+            (_, denied) => Some(SyscallPolicy {
+                syscalls: denied.iter().copied().collect(),
+                allow: false,
+                denied: args.denied_syscall,
+            }),
+        },
+        meter: args.meter,
+        max_pages: args.max_pages,
+    })
+}
+
 #[cfg(feature = "cli")]
 fn main() -> Result<()> {
     let args = Args::parse();
@@ -67,21 +524,66 @@ fn main() -> Result<()> {
         eprintln!("============================================");
     }
 
+    let rootfs_path = args.rootfs.as_ref().or(args.image.as_ref());
+    let rootfs_data = rootfs_path.map(std::fs::read).transpose().context("Failed to read rootfs")?;
+    let container = match (&args.image, &rootfs_data) {
+        (Some(path), Some(data)) => {
+            Some(oci::Container::load(data).with_context(|| format!("Failed to read image {}", path.display()))?)
+        }
+        _ => None,
+    };
+    let rootfs_entries = match (&container, &rootfs_data) {
+        (Some(container), _) => Some(container.entries()?),
+        (None, Some(data)) => Some(rootfs::read_tar(data).context("Failed to read rootfs")?),
+        (None, None) => None,
+    };
+    // The binary container mode compiles, as a path in the rootfs
+    let entry = match (&args.entry, &container, &rootfs_entries) {
+        (Some(entry), _, _) => Some(entry.clone()),
+        (None, Some(container), Some(entries)) => Some(container.entry(entries)?),
+        _ => None,
+    };
+
     // Load ELF binary
     let elf_data = if let Some(ref input) = args.input {
         if args.verbose {
             eprintln!("Loading ELF: {}", input.display());
         }
         std::fs::read(input).context("Failed to read input ELF")?
-    } else if let Some(ref _rootfs) = args.rootfs {
-        // TODO: Extract from tar
-        anyhow::bail!("Rootfs mode not yet implemented");
+    } else if let Some(entries) = &rootfs_entries {
+        let entry = entry.as_deref().context("Rootfs mode needs --entry")?;
+        if args.verbose {
+            eprintln!("Loading ELF: {} from {}", entry, rootfs_path.unwrap().display());
+        }
+        rootfs::find_file(entries, entry)?.to_vec()
     } else {
         anyhow::bail!("No input specified");
     };
+    let elf_data = if args.patches.is_empty() {
+        elf_data
+    } else {
+        if args.verbose {
+            eprintln!("  Byte patches: {}", args.patches.len());
+        }
+        elf::patch_image(&elf_data, args.load_bias, &args.patches).context("Failed to patch ELF")?
+    };
 
     // Parse ELF
-    let elf_info = elf::parse(&elf_data).context("Failed to parse ELF")?;
+    let file = elf::ElfFile::parse(&elf_data).context("Failed to parse ELF")?.with_load_bias(args.load_bias)?;
+    let elf_info = file.info();
+
+    // ... and the binaries bundled with it
+    let bundle_data = args
+        .bundle
+        .iter()
+        .map(|(_, path, _)| std::fs::read(path).with_context(|| format!("Failed to read {}", path.display())))
+        .collect::<Result<Vec<_>>>()?;
+    let extras = args
+        .bundle
+        .iter()
+        .zip(&bundle_data)
+        .map(|((name, _, bias), data)| bundle::Binary::load(name.as_str(), data, *bias))
+        .collect::<Result<Vec<_>>>()?;
 
     if args.verbose {
         eprintln!("  Entry point: 0x{:x}", elf_info.entry);
@@ -93,10 +595,22 @@ fn main() -> Result<()> {
         if let Some(ref interp) = elf_info.interpreter {
             eprintln!("  Interpreter: {}", interp);
         }
+        if args.load_bias != 0 {
+            eprintln!("  Load bias: 0x{:x}", args.load_bias);
+        }
+        if args.memory_offset != 0 {
+            eprintln!("  Memory offset: 0x{:x}", args.memory_offset);
+        }
+        if let Some(root) = args.soft_mmu {
+            eprintln!("  Software MMU: root table at 0x{:x}", root);
+        }
+        if let Some(page) = args.time_page {
+            eprintln!("  Time page: 0x{:x}", page);
+        }
     }
 
     // Extract code sections
-    let code_sections = elf::extract_code_sections(&elf_data, &elf_info)?;
+    let code_sections = file.code_sections();
 
     if args.verbose {
         let total_bytes: usize = code_sections.iter().map(|s| s.data.len()).sum();
@@ -118,33 +632,253 @@ fn main() -> Result<()> {
     }
 
     // Build control flow graph
-    let cfg = cfg::build(&all_instructions, elf_info.entry)?;
+    let mut cfg = cfg::build(&all_instructions, elf_info.entry)?;
+    let plt_stubs = plt::bind(&mut cfg, &file);
+    let startup = startup::apply(&mut cfg, &file);
 
     if args.verbose {
         eprintln!("  Basic blocks: {}", cfg.blocks.len());
         eprintln!("  Functions: {}", cfg.functions.len());
+        eprintln!("  PLT stubs bound: {}", plt_stubs);
+        if let Some(startup) = &startup {
+            eprintln!("  Startup: main at 0x{:x}, __libc_start_main at 0x{:x}", startup.main, startup.start_main);
+        }
     }
 
-    // Translate to Wasm
-    let wasm_module = translate::translate(&cfg, &elf_info, args.opt_level, args.debug)?;
+    let mut bundle_info = elf_info.clone();
+    for binary in &extras {
+        bundle::add(&mut cfg, &mut bundle_info, binary)?;
+        if args.verbose {
+            eprintln!("  Bundled: {} at 0x{:x}", binary.name, binary.file.info().entry);
+        }
+    }
+    let elf_info = &bundle_info;
 
+    if let Some(name) = &args.entry_symbol {
+        let addr = file
+            .symbols()
+            .iter()
+            .find(|sym| &sym.name == name)
+            .map(|sym| sym.addr)
+            .or_else(|| startup.as_ref()?.functions().into_iter().find(|(n, _)| n == name).map(|(_, addr)| addr))
+            .with_context(|| format!("No function symbol named {}", name))?;
+        if !cfg.blocks.contains_key(&addr) {
+            anyhow::bail!("No block starts at {} (0x{:x})", name, addr);
+        }
+        cfg.entry = addr;
+        if args.verbose {
+            eprintln!("  Entry: {} at 0x{:x}", name, addr);
+        }
+    }
+
+    let options = compile_options(&args, &file, &extras, &cfg)?;
+    if let Some(max) = options.max_pages {
+        let pages = translate::layout(&cfg, elf_info, &options).memory_pages;
+        if max < pages {
+            anyhow::bail!("--max-pages {} is below the {} pages the image needs", max, pages);
+        }
+    }
     if args.verbose {
-        eprintln!("  Wasm functions: {}", wasm_module.function_count());
+        if let Some(hot) = translate::hot_blocks(&cfg, &options) {
+            eprintln!("  Hot blocks: {} of {} compiled ahead of time", hot.len(), cfg.blocks.len());
+        }
     }
 
-    // Build final Wasm binary
-    let wasm_bytes = wasm_builder::build(&wasm_module)?;
+    if let Some(path) = &args.unsupported_report {
+        std::fs::write(path, report::render(&cfg, &options)).context("Failed to write unsupported report")?;
+        if args.verbose {
+            let count: usize = report::unsupported(&cfg, &options).iter().map(|u| u.addrs.len()).sum();
+            eprintln!("  Unsupported instructions: {} ({})", count, path.display());
+        }
+    }
 
-    if args.verbose {
-        eprintln!("  Output size: {} bytes", wasm_bytes.len());
+    if !args.emit.is_empty() {
+        let estimates = freq::estimate(&cfg);
+        if args.emit.iter().any(|f| f == "dot") {
+            let path = args.output.with_extension("cfg.dot");
+            std::fs::write(&path, freq::render_dot(&cfg, &estimates)).context("Failed to write CFG graph")?;
+            if args.verbose {
+                eprintln!("  CFG graph: {}", path.display());
+            }
+        }
+        if args.emit.iter().any(|f| f == "stats") {
+            eprint!("{}", freq::render_stats(&cfg, &estimates, 20));
+        }
     }
 
-    // Write output
-    std::fs::write(&args.output, &wasm_bytes).context("Failed to write output")?;
+    if let Some(pc) = args.patch_block {
+        let patch = rv2wasm::emit_patch(&cfg, elf_info, &options, pc)?;
+        std::fs::write(&args.output, &patch).context("Failed to write patch")?;
+        if args.verbose {
+            eprintln!("  Patch for the block at 0x{:x}: {} bytes", pc, patch.len());
+        }
+        return Ok(());
+    }
+
+    if args.dump_ir.is_some() || args.diff_ir.is_some() || args.diff_ir_args.is_some() {
+        let ir = irdiff::collect(&translate::translate(&cfg, elf_info, &options)?.functions);
+        if let Some(path) = &args.dump_ir {
+            std::fs::write(path, irdiff::render(&ir)).context("Failed to write IR dump")?;
+        }
+        let baseline = match (&args.diff_ir, &args.diff_ir_args) {
+            (Some(path), _) => {
+                let text = std::fs::read_to_string(path).context("Failed to read IR dump")?;
+                Some(irdiff::parse(&text).with_context(|| format!("Bad IR dump {}", path.display()))?)
+            }
+            (None, Some(line)) => {
+                let mut argv: Vec<std::ffi::OsString> = vec!["rv2wasm".into()];
+                argv.extend(args.input.iter().map(|input| input.clone().into_os_string()));
+                argv.extend(line.split_whitespace().map(Into::into));
+                argv.extend(["--load-bias".into(), args.load_bias.to_string().into()]);
+                let baseline = compile_options(&Args::try_parse_from(argv)?, &file, &extras, &cfg)?;
+                Some(irdiff::collect(&translate::translate(&cfg, elf_info, &baseline)?.functions))
+            }
+            (None, None) => None,
+        };
+        if let Some(old) = baseline {
+            print!("{}", irdiff::render_diff(&old, &ir, &irdiff::diff(&old, &ir), 20));
+            return Ok(());
+        }
+    }
+
+    let cache = args.cache_dir.as_ref().map(Cache::new).transpose()?;
+    // A bundle's key covers every binary, where it goes and what it is called
+    let mut keyed = elf_data.clone();
+    for ((name, _, bias), data) in args.bundle.iter().zip(&bundle_data) {
+        keyed.extend(name.as_bytes());
+        keyed.extend(bias.to_le_bytes());
+        keyed.extend(data);
+    }
+    if let (true, Some(data)) = (args.embed_rootfs, &rootfs_data) {
+        keyed.extend(data);
+    }
+    if let (Some(container), Some(entry)) = (&container, &entry) {
+        keyed.extend(container.config.json(entry).as_bytes());
+    }
+    let key = Cache::module_key(&keyed, &options);
+    if let Some(cache) = &cache {
+        if cache.restore_module(&key, &args.output)? {
+            if args.verbose {
+                eprintln!("Restored from cache: {}", args.output.display());
+            }
+            return Ok(());
+        }
+    }
+    let mut extensions = vec!["abi.json".to_string()];
+
+    if options.split > 0 {
+        // Dispatcher, then code module g as output.<g>.wasm
+        let module = translate::translate(&cfg, elf_info, &options)?;
+        let (dispatcher, code_modules) = wasm_builder::build_split(&module)?;
+        std::fs::write(&args.output, dispatcher).context("Failed to write output")?;
+        for (g, code) in code_modules.iter().enumerate() {
+            let path = args.output.with_extension(format!("{}.wasm", g));
+            std::fs::write(&path, code).context("Failed to write code module")?;
+            extensions.push(format!("{}.wasm", g));
+        }
+        if args.verbose {
+            eprintln!("  Code modules: {}", code_modules.len());
+        }
+    } else {
+        // The previous build is read before the output is overwritten
+        let previous = match &args.incremental {
+            Some(path) => Some(match std::fs::read(path) {
+                Ok(wasm) => incremental::Previous::parse(&wasm).unwrap_or_else(|e| {
+                    eprintln!("Ignoring previous build {}: {:#}", path.display(), e);
+                    Default::default()
+                }),
+                Err(e) if e.kind() == std::io::ErrorKind::NotFound => Default::default(),
+                Err(e) => return Err(e).context("Failed to read previous build"),
+            }),
+            None => None,
+        };
+
+        // Translate and write the Wasm binary block by block
+        let output = BufWriter::new(std::fs::File::create(&args.output).context("Failed to create output")?);
+        let mut output = match (&cache, &previous) {
+            (Some(cache), _) => {
+                let (output, stats) = cache.emit_streaming(&cfg, elf_info, &options, output)?;
+                if args.verbose {
+                    eprintln!("  Cached functions: {} of {}", stats.hits, stats.hits + stats.misses);
+                }
+                output
+            }
+            (None, Some(previous)) => {
+                let (output, stats) = incremental::emit_streaming(previous, &cfg, elf_info, &options, output)?;
+                if args.verbose {
+                    eprintln!("  Reused functions: {} of {}", stats.hits, stats.hits + stats.misses);
+                }
+                output
+            }
+            (None, None) => rv2wasm::emit_streaming(&cfg, elf_info, &options, output)?,
+        };
+        output.flush().context("Failed to write output")?;
+    }
+
+    if let (true, Some(entries)) = (args.embed_rootfs, &rootfs_entries) {
+        let image = rootfs::pack(entries);
+        let mut output = std::fs::OpenOptions::new().append(true).open(&args.output).context("Failed to open output")?;
+        output.write_all(&rootfs::section(&image)).context("Failed to embed rootfs")?;
+        if args.verbose {
+            eprintln!("  Embedded rootfs: {} entries, {} bytes packed", entries.len(), image.len());
+        }
+    }
+
+    // ABI descriptor for hosts: output.wasm -> output.abi.json
+    let abi_path = args.output.with_extension("abi.json");
+    let layout = translate::layout(&cfg, elf_info, &options);
+    std::fs::write(&abi_path, abi::descriptor(&layout, cfg.entry)).context("Failed to write ABI descriptor")?;
+    if !extras.is_empty() {
+        let name = args.input.as_ref().map_or(String::new(), |input| input.display().to_string());
+        let binaries = std::iter::once((name.as_str(), &file)).chain(extras.iter().map(|b| (b.name.as_str(), &b.file)));
+        std::fs::write(args.output.with_extension("bundle.json"), bundle::manifest(binaries))
+            .context("Failed to write bundle manifest")?;
+        extensions.push("bundle.json".to_string());
+    }
+    if let (Some(container), Some(entry)) = (&container, &entry) {
+        std::fs::write(args.output.with_extension("container.json"), container.config.json(entry))
+            .context("Failed to write container config")?;
+        extensions.push("container.json".to_string());
+    }
+    if let Some(cache) = &cache {
+        cache.store_module(&key, &args.output, &extensions)?;
+    }
+
+    if args.verbose {
+        eprintln!("  Wasm functions: {}", cfg.blocks.len());
+        eprintln!("  Output size: {} bytes", std::fs::metadata(&args.output)?.len());
+    }
 
     if args.verbose {
-        eprintln!("Wrote: {}", args.output.display());
+        eprintln!("Wrote: {} ({})", args.output.display(), abi_path.display());
     }
 
     Ok(())
 }
+
+#[cfg(all(test, feature = "cli"))]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_split_exports() {
+        // addi x0, x0, 0; ret
+        let insts = [disasm::decode32(0x1000, 0x13).unwrap(), disasm::decode32(0x1004, 0x8067).unwrap()];
+        let cfg = cfg::build(&insts, 0x1000).unwrap();
+        let args = |list: &[&str]| list.iter().map(|s| s.to_string()).collect::<Vec<_>>();
+
+        // Symbols alone leave every block exported
+        let exports = args(&["main", "helper"]);
+        let (blocks, symbols) = split_exports(&exports, &cfg).unwrap();
+        assert_eq!(blocks, None);
+        assert_eq!(symbols, ["main", "helper"]);
+
+        let exports = args(&["main", "0x1000"]);
+        let (blocks, symbols) = split_exports(&exports, &cfg).unwrap();
+        assert_eq!(blocks, Some(vec![0x1000]));
+        assert_eq!(symbols, ["main"]);
+
+        assert!(split_exports(&args(&["0x1004"]), &cfg).is_err());
+        assert_eq!(split_exports(&[], &cfg).unwrap(), (None, Vec::new()));
+    }
+}
@@ -0,0 +1,171 @@
+// mmu.rs - Software MMU
+//
+// With `CompileOptions::soft_mmu` every guest load and store goes through a
+// two-level page table in linear memory instead of the flat
+// guest-address-is-linear-address model, so a host can mmap at any guest
+// address, leave guard pages unmapped and enforce mprotect. It costs two
+// extra loads and a branch per access, so it is chosen per compile.
+//
+// The 32-bit guest address splits 10/10/12. The root table (1024 u32
+// entries at the `soft_mmu` address) holds the linear address of a leaf
+// table per 4 MiB region; every root entry must point at a valid leaf, so
+// unmapped regions share one zeroed leaf. A leaf entry is the linear
+// address of the 4 KiB page backing a guest page, or'ed with `PTE_READ`
+// and `PTE_WRITE`; 0 is unmapped. An access without the permissions it
+// needs returns `abi::PAGE_FAULT` with the PC and guest address in the
+// machine state.
+//
+// Accesses are translated by the page of their first byte: a misaligned
+// access straddling two pages must have them backed contiguously.
+
+use crate::translate::{guest_accesses, WasmInst, RESERVATION_ADDR_OFFSET, TRAP_PC_OFFSET, TRAP_VALUE_OFFSET};
+use anyhow::Result;
+
+pub const PAGE_SHIFT: u32 = 12;
+pub const PAGE_SIZE: u32 = 1 << PAGE_SHIFT;
+/// Entries per table (root and leaf), each a little-endian u32
+pub const TABLE_ENTRIES: u32 = 1024;
+/// Leaf entry bit: the page may be read
+pub const PTE_READ: u32 = 1;
+/// Leaf entry bit: the page may be written
+pub const PTE_WRITE: u32 = 2;
+
+/// Scratch locals of the lowered lookup: the guest address and the leaf entry
+const ADDR_LOCAL: u32 = 5;
+const ENTRY_LOCAL: u32 = 6;
+/// Locals a block function needs when its accesses are lowered
+pub const NUM_LOCALS: u32 = ENTRY_LOCAL;
+
+/// The linear address of guest address `addr` for an access needing
+/// `perms`, walking the table at `root` in `memory` as the emitted code does.
+/// None on a page fault. Hosts use it to reach guest memory from syscalls.
+pub fn walk(memory: &[u8], root: u32, addr: u32, perms: u32) -> Option<u32> {
+    let word = |at: u32| Some(u32::from_le_bytes(memory.get(at as usize..at as usize + 4)?.try_into().ok()?));
+    let leaf = word(root.checked_add((addr >> 22) * 4)?)?;
+    let entry = word(leaf.checked_add((addr >> PAGE_SHIFT) % TABLE_ENTRIES * 4)?)?;
+    (entry & perms == perms).then_some(entry & !(PAGE_SIZE - 1) | addr & (PAGE_SIZE - 1))
+}
+
+/// Route the guest accesses of one instruction's code, `body[start..]`,
+/// through the page table at `root`; faults report PC `pc`
+pub(crate) fn lower_accesses(body: &mut Vec<WasmInst>, start: usize, pc: u64, root: u32) -> Result<()> {
+    let mut lookups = Vec::new();
+    for (access, pusher) in guest_accesses(&body[start..])? {
+        let (access, pusher) = (start + access, start + pusher);
+        let perms = access_perms(&body[access]);
+        let offset = body[access].memarg_offset_mut().map_or(0, std::mem::take);
+        lookups.push((pusher, lookup(root, offset, perms, pc)));
+    }
+    if lookups.is_empty() {
+        return Ok(());
+    }
+
+    lookups.sort_unstable_by_key(|&(pusher, _)| pusher);
+    let tail = body.split_off(start);
+    let mut lookups = lookups.into_iter().peekable();
+    for (i, inst) in tail.into_iter().enumerate() {
+        body.push(inst);
+        if let Some((_, code)) = lookups.next_if(|&(pusher, _)| pusher == start + i) {
+            body.extend(code);
+        }
+    }
+    Ok(())
+}
+
+/// Permissions an access needs
+fn access_perms(inst: &WasmInst) -> u32 {
+    use WasmInst::*;
+    match inst {
+        I32Store { .. } | I64Store { .. } | I32Store8 { .. } | I32Store16 { .. } | I64Store8 { .. }
+        | I64Store16 { .. } | I64Store32 { .. } | F32Store { .. } | F64Store { .. } | I32AtomicStore { .. }
+        | I64AtomicStore { .. } | MemoryFill => PTE_WRITE,
+        I32Load { .. } | I64Load { .. } | I32Load8S { .. } | I32Load8U { .. } | I32Load16S { .. }
+        | I32Load16U { .. } | I64Load8S { .. } | I64Load8U { .. } | I64Load16S { .. } | I64Load16U { .. }
+        | I64Load32S { .. } | I64Load32U { .. } | F32Load { .. } | F64Load { .. } | I32AtomicLoad { .. }
+        | I64AtomicLoad { .. } | I64AtomicLoad8U { .. } | I64AtomicLoad16U { .. } => PTE_READ,
+        _ => PTE_READ | PTE_WRITE,
+    }
+}
+
+/// Code turning the guest address on the stack (plus `offset`) into its
+/// linear address, or returning `abi::PAGE_FAULT`
+fn lookup(root: u32, offset: u32, perms: u32, pc: u64) -> Vec<WasmInst> {
+    use WasmInst::*;
+    let mut code = Vec::new();
+    if offset != 0 {
+        code.extend([I32Const { value: offset as i32 }, I32Add]);
+    }
+    code.extend([
+        I64ExtendI32U,
+        LocalSet { idx: ADDR_LOCAL },
+        Block { label: 0 },
+        // leaf = root[addr >> 22]
+        LocalGet { idx: ADDR_LOCAL },
+        I64Const { value: 22 },
+        I64ShrU,
+        I64Const { value: 2 },
+        I64Shl,
+        I32WrapI64,
+        I32Load { offset: root },
+        // entry = leaf[(addr >> 12) & 0x3ff]
+        LocalGet { idx: ADDR_LOCAL },
+        I64Const { value: (PAGE_SHIFT - 2) as i64 },
+        I64ShrU,
+        I64Const { value: ((TABLE_ENTRIES - 1) * 4) as i64 },
+        I64And,
+        I32WrapI64,
+        I32Add,
+        I32Load { offset: 0 },
+        I64ExtendI32U,
+        LocalTee { idx: ENTRY_LOCAL },
+        I64Const { value: perms as i64 },
+        I64And,
+        I64Const { value: perms as i64 },
+        I64Eq,
+        BrIf { label: 0 },
+        LocalGet { idx: 0 },
+        LocalGet { idx: ADDR_LOCAL },
+        I64Store { offset: TRAP_VALUE_OFFSET },
+        LocalGet { idx: 0 },
+        I64Const { value: pc as i64 },
+        I64Store { offset: TRAP_PC_OFFSET },
+        LocalGet { idx: 0 },
+        I64Const { value: -1 },
+        I64Store { offset: RESERVATION_ADDR_OFFSET },
+        I32Const { value: crate::abi::PAGE_FAULT as i32 },
+        Return,
+        End,
+        // page | addr & 0xfff
+        LocalGet { idx: ENTRY_LOCAL },
+        I64Const { value: !(PAGE_SIZE as i64 - 1) },
+        I64And,
+        LocalGet { idx: ADDR_LOCAL },
+        I64Const { value: PAGE_SIZE as i64 - 1 },
+        I64And,
+        I64Or,
+        I32WrapI64,
+    ]);
+    code
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_walk() {
+        // Root at 0, empty leaf at 0x1000, leaf for 0x0040_0000.. at 0x2000
+        let mut memory = vec![0u8; 0x4000];
+        for i in 0..TABLE_ENTRIES as usize {
+            memory[i * 4..i * 4 + 4].copy_from_slice(&0x1000u32.to_le_bytes());
+        }
+        memory[4..8].copy_from_slice(&0x2000u32.to_le_bytes());
+        // Guest page 0x0040_3000 -> linear 0x3000, read-only
+        memory[0x2000 + 3 * 4..0x2000 + 4 * 4].copy_from_slice(&(0x3000 | PTE_READ).to_le_bytes());
+
+        assert_eq!(walk(&memory, 0, 0x0040_3abc, PTE_READ), Some(0x3abc));
+        assert_eq!(walk(&memory, 0, 0x0040_3abc, PTE_WRITE), None);
+        assert_eq!(walk(&memory, 0, 0x0040_4000, PTE_READ), None);
+        assert_eq!(walk(&memory, 0, 0x8000_0000, PTE_READ), None);
+    }
+}
@@ -0,0 +1,351 @@
+// oci.rs - Container images
+//
+// `rv2wasm --image` takes a whole container image instead of a flattened
+// rootfs tar: an OCI image layout in a tar (an oci-archive, as skopeo or
+// `docker buildx --output type=oci` write it) or a docker-archive (`docker
+// save`). `Container::load` reads the manifest and the image config;
+// `Container::entries` applies the layers in order, as a container runtime
+// would, into the entry list the rest of rootfs mode works from.
+//
+// Layers may be plain, gzip or zstd tars (told apart by their magic, not
+// their media type). Every blob of an OCI layout is checked against the
+// digest it is referenced by. Whiteouts follow the OCI spec: `.wh.name` deletes
+// `name` from the layers below, and `.wh..wh..opq` empties its directory of
+// everything the layers below put there.
+
+use crate::rootfs::{self, Kind, TarEntry};
+use anyhow::{bail, ensure, Context, Result};
+use flate2::read::MultiGzDecoder;
+use ruzstd::decoding::StreamingDecoder;
+use serde_json::Value;
+use sha2::{Digest, Sha256, Sha512};
+use std::collections::{BTreeMap, HashMap};
+use std::io::Read;
+
+const WHITEOUT: &str = ".wh.";
+const OPAQUE: &str = ".wh..wh..opq";
+
+/// PATH when the image config sets none (what Docker uses)
+const DEFAULT_PATH: &str = "/usr/local/sbin:/usr/local/bin:/usr/sbin:/usr/bin:/sbin:/bin";
+
+/// How the image says to start a container
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct Config {
+    pub entrypoint: Vec<String>,
+    pub cmd: Vec<String>,
+    /// KEY=VALUE pairs
+    pub env: Vec<String>,
+    /// Empty for the root
+    pub working_dir: String,
+}
+
+impl Config {
+    /// The command line a container starts with: Entrypoint, then Cmd
+    pub fn argv(&self) -> Vec<String> {
+        self.entrypoint.iter().chain(&self.cmd).cloned().collect()
+    }
+
+    /// PATH from the config's environment
+    pub fn path(&self) -> &str {
+        self.env.iter().find_map(|pair| pair.strip_prefix("PATH=")).unwrap_or(DEFAULT_PATH)
+    }
+
+    /// The config as the JSON written next to the module, for hosts
+    pub fn json(&self, entry: &str) -> String {
+        let list = |items: &[String]| {
+            let items: Vec<String> = items.iter().map(|s| crate::abi::json_string(s)).collect();
+            format!("[{}]", items.join(", "))
+        };
+        format!(
+            "{{\n  \"entry\": {},\n  \"argv\": {},\n  \"env\": {},\n  \"cwd\": {}\n}}\n",
+            crate::abi::json_string(entry),
+            list(&self.argv()),
+            list(&self.env),
+            crate::abi::json_string(if self.working_dir.is_empty() { "/" } else { &self.working_dir })
+        )
+    }
+}
+
+/// A container image: its config and its layers, decompressed
+#[derive(Debug, Clone)]
+pub struct Container {
+    pub config: Config,
+    layers: Vec<Vec<u8>>,
+}
+
+impl Container {
+    /// Read an oci-archive or docker-archive
+    pub fn load(archive: &[u8]) -> Result<Self> {
+        let files: HashMap<String, &[u8]> = rootfs::read_tar(archive)
+            .context("Image is not a tar archive")?
+            .into_iter()
+            .filter(|e| e.kind == Kind::File)
+            .map(|e| (e.path, e.data))
+            .collect();
+        let file = |path: &str| files.get(&rootfs::normalize(path)).copied().with_context(|| format!("Image has no {}", path));
+        let parse = |name: &str, data: &[u8]| -> Result<Value> {
+            serde_json::from_slice(data).with_context(|| format!("Bad JSON in {}", name))
+        };
+        let json = |path: &str| parse(path, file(path)?);
+
+        let (config, layers): (Value, Vec<(String, &[u8])>) = if files.contains_key("index.json") {
+            // The blob a digest names, once its contents match it
+            let blob = |digest: &str| -> Result<(String, &[u8])> {
+                let (algorithm, hex) = digest.split_once(':').with_context(|| format!("Bad digest {}", digest))?;
+                let data = file(&format!("blobs/{}/{}", algorithm, hex))?;
+                let actual = match algorithm {
+                    "sha256" => format!("{:x}", Sha256::digest(data)),
+                    "sha512" => format!("{:x}", Sha512::digest(data)),
+                    _ => bail!("Unsupported digest algorithm in {}", digest),
+                };
+                ensure!(actual == hex, "Blob {} has digest {}:{}", digest, algorithm, actual);
+                Ok((digest.to_string(), data))
+            };
+            let json_blob = |digest: &str| blob(digest).and_then(|(name, data)| parse(&name, data));
+            let mut index = json("index.json")?;
+            // An index may point at a nested index, one manifest per platform
+            let manifest = loop {
+                let manifests = index["manifests"].as_array().context("index.json lists no manifests")?;
+                let chosen = manifests
+                    .iter()
+                    .find(|m| m["platform"]["architecture"] == "riscv64")
+                    .or(manifests.first())
+                    .context("index.json lists no manifests")?;
+                let next = json_blob(chosen["digest"].as_str().context("Manifest has no digest")?)?;
+                if next.get("manifests").is_none() {
+                    break next;
+                }
+                index = next;
+            };
+            let digest = |v: &Value| v["digest"].as_str().context("Descriptor has no digest").and_then(blob);
+            let layers = manifest["layers"].as_array().context("Manifest lists no layers")?;
+            let (name, config) = digest(&manifest["config"])?;
+            (parse(&name, config)?, layers.iter().map(digest).collect::<Result<_>>()?)
+        } else if files.contains_key("manifest.json") {
+            let manifest = json("manifest.json")?;
+            let image = manifest.get(0).context("manifest.json lists no images")?;
+            let layers = image["Layers"].as_array().context("manifest.json lists no layers")?;
+            let layers = layers.iter().map(|l| {
+                let path = l.as_str().context("Bad layer path")?;
+                Ok((path.to_string(), file(path)?))
+            });
+            (json(image["Config"].as_str().context("manifest.json has no Config")?)?, layers.collect::<Result<_>>()?)
+        } else {
+            bail!("Not an OCI layout or docker-archive (no index.json or manifest.json)");
+        };
+
+        if let Some(arch) = config["architecture"].as_str() {
+            ensure!(arch == "riscv64", "Image is for {}, not riscv64", arch);
+        }
+        let strings = |v: &Value| -> Vec<String> {
+            v.as_array().map(|a| a.iter().filter_map(|s| s.as_str().map(String::from)).collect()).unwrap_or_default()
+        };
+        let c = &config["config"];
+        let config = Config {
+            entrypoint: strings(&c["Entrypoint"]),
+            cmd: strings(&c["Cmd"]),
+            env: strings(&c["Env"]),
+            working_dir: c["WorkingDir"].as_str().unwrap_or_default().to_string(),
+        };
+        let layers = layers
+            .iter()
+            .map(|(name, data)| decompress(data).with_context(|| format!("Failed to decompress layer {}", name)))
+            .collect::<Result<_>>()?;
+        Ok(Self { config, layers })
+    }
+
+    /// The flattened tree: every layer applied over the ones before it
+    pub fn entries(&self) -> Result<Vec<TarEntry<'_>>> {
+        let mut tree: BTreeMap<String, TarEntry> = BTreeMap::new();
+        for (i, layer) in self.layers.iter().enumerate() {
+            let entries = rootfs::read_tar(layer).with_context(|| format!("Bad tar in layer {}", i))?;
+            // Whiteouts hide what the layers below put there, never their own layer
+            for entry in &entries {
+                let (dir, name) = entry.path.rsplit_once('/').unwrap_or(("", &entry.path));
+                if name == OPAQUE {
+                    remove_below(&mut tree, dir);
+                } else if let Some(hidden) = name.strip_prefix(WHITEOUT) {
+                    let hidden = if dir.is_empty() { hidden.to_string() } else { format!("{}/{}", dir, hidden) };
+                    tree.remove(&hidden);
+                    remove_below(&mut tree, &hidden);
+                }
+            }
+            for entry in entries {
+                let name = entry.path.rsplit('/').next().unwrap_or_default();
+                if name.starts_with(WHITEOUT) {
+                    continue;
+                }
+                // Something other than a directory replaces a whole subtree
+                if entry.kind != Kind::Dir {
+                    remove_below(&mut tree, &entry.path);
+                }
+                tree.insert(entry.path.clone(), entry);
+            }
+        }
+        Ok(tree.into_values().collect())
+    }
+
+    /// The path of the binary the container starts, searched for in PATH
+    /// when the command names no directory
+    pub fn entry(&self, entries: &[TarEntry]) -> Result<String> {
+        let argv = self.config.argv();
+        let command = argv.first().context("Image sets neither Entrypoint nor Cmd; pass --entry")?;
+        if command.contains('/') {
+            return Ok(command.clone());
+        }
+        self.config
+            .path()
+            .split(':')
+            .map(|dir| format!("{}/{}", dir.trim_end_matches('/'), command))
+            .find(|path| rootfs::find_file(entries, path).is_ok())
+            .with_context(|| format!("{} is not in the image's PATH", command))
+    }
+}
+
+/// Remove everything under `dir` (the whole tree for the root)
+fn remove_below(tree: &mut BTreeMap<String, TarEntry>, dir: &str) {
+    if dir.is_empty() {
+        tree.clear();
+        return;
+    }
+    // '0' follows '/': the range holds exactly the paths under dir/
+    let below: Vec<String> = tree.range(format!("{}/", dir)..format!("{}0", dir)).map(|(path, _)| path.clone()).collect();
+    for path in below {
+        tree.remove(&path);
+    }
+}
+
+/// A layer as a plain tar
+fn decompress(layer: &[u8]) -> Result<Vec<u8>> {
+    if layer.starts_with(&[0x1f, 0x8b]) {
+        // Concatenated members decompress to the concatenation of their
+        // contents; each member's CRC and size are checked
+        let mut out = Vec::new();
+        MultiGzDecoder::new(layer).read_to_end(&mut out).context("Bad gzip member")?;
+        return Ok(out);
+    }
+    if layer.starts_with(&[0x28, 0xb5, 0x2f, 0xfd]) {
+        let mut out = Vec::new();
+        let mut rest = layer;
+        while !rest.is_empty() {
+            let mut frame = StreamingDecoder::new(&mut rest).map_err(|e| anyhow::anyhow!("Bad zstd frame: {}", e))?;
+            frame.read_to_end(&mut out).context("Bad zstd frame")?;
+        }
+        return Ok(out);
+    }
+    Ok(layer.to_vec())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::rootfs::build_tar;
+
+    fn oci_archive(config: &str, layers: &[Vec<u8>]) -> Vec<u8> {
+        let digest = |data: &[u8]| format!("{:x}", Sha256::digest(data));
+        let layer_list: Vec<String> = layers
+            .iter()
+            .map(|l| format!("{{\"mediaType\": \"application/vnd.oci.image.layer.v1.tar\", \"digest\": \"sha256:{}\"}}", digest(l)))
+            .collect();
+        let manifest = format!(
+            "{{\"config\": {{\"digest\": \"sha256:{}\"}}, \"layers\": [{}]}}",
+            digest(config.as_bytes()),
+            layer_list.join(", ")
+        );
+        let index = format!(
+            "{{\"manifests\": [{{\"digest\": \"sha256:{}\", \"platform\": {{\"architecture\": \"riscv64\"}}}}]}}",
+            digest(manifest.as_bytes())
+        );
+        let blob = |data: &[u8]| (format!("blobs/sha256/{}", digest(data)), data.to_vec());
+        let mut files = vec![
+            ("oci-layout".to_string(), b"{\"imageLayoutVersion\": \"1.0.0\"}".to_vec()),
+            ("index.json".to_string(), index.into_bytes()),
+            blob(manifest.as_bytes()),
+            blob(config.as_bytes()),
+        ];
+        files.extend(layers.iter().map(|layer| blob(layer)));
+        let entries: Vec<(&str, u8, &str, &[u8])> = files.iter().map(|(p, d)| (p.as_str(), b'0', "", &d[..])).collect();
+        build_tar(&entries)
+    }
+
+    #[test]
+    fn test_oci_layers_and_config() {
+        let base = build_tar(&[
+            ("bin/", b'5', "", b""),
+            ("bin/busybox", b'0', "", b"old"),
+            ("bin/sh", b'2', "busybox", b""),
+            ("etc/motd", b'0', "", b"hello"),
+            ("var/cache/a", b'0', "", b"a"),
+            ("var/cache/b", b'0', "", b"b"),
+        ]);
+        let top = build_tar(&[
+            ("bin/busybox", b'0', "", b"new"),
+            ("etc/.wh.motd", b'0', "", b""),
+            ("var/cache/.wh..wh..opq", b'0', "", b""),
+            ("var/cache/c", b'0', "", b"c"),
+        ]);
+        let top = ruzstd::encoding::compress_to_vec(&top[..], ruzstd::encoding::CompressionLevel::Fastest);
+        let config = r#"{"architecture": "riscv64", "config": {"Entrypoint": ["sh"], "Cmd": ["-c", "echo hi"],
+            "Env": ["PATH=/usr/bin:/bin", "HOME=/root"], "WorkingDir": "/root"}}"#;
+        let archive = oci_archive(config, &[base, top]);
+
+        let container = Container::load(&archive).unwrap();
+        assert_eq!(container.config.argv(), ["sh", "-c", "echo hi"]);
+        assert_eq!(container.config.path(), "/usr/bin:/bin");
+        let entries = container.entries().unwrap();
+        let paths: Vec<&str> = entries.iter().map(|e| e.path.as_str()).collect();
+        assert_eq!(paths, ["bin", "bin/busybox", "bin/sh", "var/cache/c"]);
+        assert_eq!(rootfs::find_file(&entries, "/bin/sh").unwrap(), b"new");
+        assert_eq!(container.entry(&entries).unwrap(), "/bin/sh");
+        assert!(container.config.json("/bin/sh").contains("\"cwd\": \"/root\""));
+
+        // docker save's layout, with an uncompressed layer
+        let layer = build_tar(&[("app", b'0', "", b"\x7fELF")]);
+        let docker = build_tar(&[
+            ("manifest.json", b'0', "", br#"[{"Config": "c.json", "Layers": ["l/layer.tar"]}]"#),
+            ("c.json", b'0', "", br#"{"architecture": "riscv64", "config": {"Cmd": ["/app"]}}"#),
+            ("l/layer.tar", b'0', "", &layer),
+        ]);
+        let container = Container::load(&docker).unwrap();
+        let entries = container.entries().unwrap();
+        assert_eq!(container.entry(&entries).unwrap(), "/app");
+        assert_eq!(rootfs::find_file(&entries, "/app").unwrap(), b"\x7fELF");
+
+        // A blob that does not match its digest is refused
+        let at = archive.windows(3).position(|w| w == b"old").unwrap();
+        let mut tampered = archive.clone();
+        tampered[at..at + 3].copy_from_slice(b"OLD");
+        let err = Container::load(&tampered).unwrap_err();
+        assert!(format!("{:#}", err).contains("has digest sha256:"), "{:#}", err);
+
+        // Other architectures are refused
+        let amd64 = oci_archive(r#"{"architecture": "amd64", "config": {}}"#, &[]);
+        assert!(Container::load(&amd64).is_err());
+    }
+    #[test]
+    fn test_gzip_layers() {
+        // python: gzip.compress(squares, 9, mtime=0) (a dynamic block),
+        // gzip.compress(b"friscy " * 40, 9, mtime=0) (fixed) and
+        // gzip.compress(b"abc", 0, mtime=0) (stored)
+        let data = [
+            0x1f, 0x8b, 0x08, 0x00, 0x00, 0x00, 0x00, 0x00, 0x02, 0x03, 0x0d, 0x8c, 0xb9, 0x01, 0x00, 0x30,
+            0x08, 0x84, 0x56, 0x61, 0x04, 0x9f, 0x53, 0xe3, 0xfe, 0x8b, 0xc5, 0x8e, 0x02, 0x30, 0x1c, 0xb1,
+            0x78, 0x13, 0x45, 0x36, 0x5a, 0x5a, 0x3c, 0x27, 0x09, 0xa1, 0x61, 0x82, 0x20, 0x9d, 0x0e, 0xf6,
+            0x8c, 0x64, 0x0c, 0x0f, 0x2a, 0xd9, 0xb3, 0xaf, 0xbd, 0xc1, 0xb1, 0x28, 0xe3, 0xd1, 0xc5, 0x07,
+            0x70, 0x22, 0x1d, 0xf4, 0x53, 0x00, 0x00, 0x00, 0x1f, 0x8b, 0x08, 0x00, 0x00, 0x00, 0x00, 0x00,
+            0x02, 0x03, 0x4b, 0x2b, 0xca, 0x2c, 0x4e, 0xae, 0x54, 0x48, 0x1b, 0xa5, 0x50, 0x29, 0x00, 0x36,
+            0x0d, 0x89, 0x5a, 0x18, 0x01, 0x00, 0x00, 0x1f, 0x8b, 0x08, 0x00, 0x00, 0x00, 0x00, 0x00, 0x04,
+            0x03, 0x01, 0x03, 0x00, 0xfc, 0xff, 0x61, 0x62, 0x63, 0xc2, 0x41, 0x24, 0x35, 0x03, 0x00, 0x00,
+            0x00,
+        ];
+        let squares: Vec<u8> = (0..30u32).flat_map(|i| format!("{} ", i * i % 97).into_bytes()).collect();
+        let expected = [squares, b"friscy ".repeat(40), b"abc".to_vec()].concat();
+        assert_eq!(decompress(&data).unwrap(), expected);
+
+        // Damage is caught by the CRC or the decoder
+        let mut bad = data;
+        bad[20] ^= 0x10;
+        assert!(decompress(&bad).is_err());
+        assert!(decompress(&data[..30]).is_err());
+    }
+}
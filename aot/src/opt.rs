@@ -0,0 +1,1057 @@
+// opt.rs - Peephole passes over the Wasm IR
+//
+// Each pass rewrites one block function's body in place and never moves
+// code across a label.
+
+use crate::translate::{WasmFunction, WasmInst};
+use std::collections::{HashMap, HashSet};
+
+/// Remove conversions whose input is already the width they convert to.
+///
+/// Walks the body keeping the rewritten output as a stack, so a value's
+/// width is known from the instruction that produced it: a wrap of a
+/// 64-bit load is a 32-bit load, a wrap of an extension is the original
+/// i32, an extension of a 32-bit load is a widening load, and a narrow
+/// store of an extended value is the i32 store. Folds cascade, e.g.
+/// `i64.load; i32.wrap; i64.extend_s` becomes `i64.load32_s`.
+pub fn fold_extensions(body: &mut Vec<WasmInst>) {
+    use WasmInst::*;
+
+    let mut out: Vec<WasmInst> = Vec::with_capacity(body.len());
+    for &inst in body.iter() {
+        let prev = out.last().copied();
+        let folded = match (prev, inst) {
+            (Some(I64ExtendI32S | I64ExtendI32U), I32WrapI64) => None,
+            (Some(I64Const { value }), I32WrapI64) => Some(I32Const { value: value as i32 }),
+            (Some(I64Load { offset } | I64Load32S { offset } | I64Load32U { offset }), I32WrapI64) => {
+                Some(I32Load { offset })
+            }
+            (Some(I64Load16S { offset }), I32WrapI64) => Some(I32Load16S { offset }),
+            (Some(I64Load16U { offset }), I32WrapI64) => Some(I32Load16U { offset }),
+            (Some(I64Load8S { offset }), I32WrapI64) => Some(I32Load8S { offset }),
+            (Some(I64Load8U { offset }), I32WrapI64) => Some(I32Load8U { offset }),
+            (Some(I32Load { offset }), I64ExtendI32S) => Some(I64Load32S { offset }),
+            (Some(I32Load { offset }), I64ExtendI32U) => Some(I64Load32U { offset }),
+            (Some(I32Load16S { offset }), I64ExtendI32S) => Some(I64Load16S { offset }),
+            (Some(I32Load16U { offset }), I64ExtendI32S | I64ExtendI32U) => Some(I64Load16U { offset }),
+            (Some(I32Load8S { offset }), I64ExtendI32S) => Some(I64Load8S { offset }),
+            (Some(I32Load8U { offset }), I64ExtendI32S | I64ExtendI32U) => Some(I64Load8U { offset }),
+            (Some(I64ExtendI32S | I64ExtendI32U), I64Store32 { offset }) => Some(I32Store { offset }),
+            (Some(I64ExtendI32S | I64ExtendI32U), I64Store16 { offset }) => Some(I32Store16 { offset }),
+            (Some(I64ExtendI32S | I64ExtendI32U), I64Store8 { offset }) => Some(I32Store8 { offset }),
+            _ => {
+                out.push(inst);
+                continue;
+            }
+        };
+        out.pop();
+        if let Some(inst) = folded {
+            out.push(inst);
+        }
+    }
+    *body = out;
+}
+
+/// Keep register values in locals instead of re-reading the machine state.
+///
+/// Every register read is `local.get 0; i64.load rN` and every write needs
+/// its own `local.get 0` for the store. This pass tees each register value
+/// the block loads or stores into a local (one per register, after the
+/// function's own locals) and replaces later reads with `local.get`, so
+/// the base is only pushed for the first read and for stores. The operand
+/// stack is modelled to tell `$m`-based register accesses from guest
+/// memory ones. Guest stores are assumed not to alias the machine state,
+/// which hosts keep outside any mapped segment. Knowledge is dropped where
+/// control flow merges (`loop`, `end`). Tees that end up unread are removed.
+pub fn forward_registers(func: &mut WasmFunction) {
+    use WasmInst::*;
+
+    let first = func.num_locals + 1;
+    let reg_of = |offset: u32| (offset < 256 && offset.is_multiple_of(8)).then_some((offset / 8) as usize);
+
+    let mut out: Vec<WasmInst> = Vec::with_capacity(func.body.len() + func.body.len() / 4);
+    let mut cached = [false; 32];
+    // Operand stack: true where the value is the `$m` base
+    let mut stack: Vec<bool> = Vec::new();
+    // Stack height at each open block, and the depth where code became unreachable
+    let mut frames: Vec<usize> = Vec::new();
+    let mut dead_at: Option<usize> = None;
+
+    for &inst in func.body.iter() {
+        match inst {
+            Block { .. } | Loop { .. } => {
+                frames.push(stack.len());
+                if matches!(inst, Loop { .. }) {
+                    cached = [false; 32];
+                }
+                out.push(inst);
+                continue;
+            }
+            End => {
+                let Some(height) = frames.pop() else { return };
+                stack.truncate(height);
+                if dead_at.is_some_and(|d| frames.len() < d) {
+                    dead_at = None;
+                }
+                cached = [false; 32];
+                out.push(inst);
+                continue;
+            }
+            _ if dead_at.is_some() => {
+                out.push(inst);
+                continue;
+            }
+            _ => {}
+        }
+
+        let top_is_base = stack.last() == Some(&true);
+        let below_is_base = stack.len() >= 2 && stack[stack.len() - 2];
+        match inst {
+            I64Load { offset } if top_is_base && reg_of(offset).is_some() => {
+                let r = reg_of(offset).unwrap();
+                stack.pop();
+                stack.push(false);
+                if cached[r] && out.last() == Some(&LocalGet { idx: 0 }) {
+                    out.pop();
+                    out.push(LocalGet { idx: first + r as u32 });
+                } else {
+                    out.push(inst);
+                    out.push(LocalTee { idx: first + r as u32 });
+                    cached[r] = true;
+                }
+                continue;
+            }
+            I64Store { offset } if below_is_base && reg_of(offset).is_some() => {
+                let r = reg_of(offset).unwrap();
+                stack.truncate(stack.len() - 2);
+                out.push(LocalTee { idx: first + r as u32 });
+                out.push(inst);
+                cached[r] = true;
+                continue;
+            }
+            LocalSet { idx: 0 } | LocalTee { idx: 0 } => return,
+            _ => {}
+        }
+
+        if let Some((offset, width)) = store_range(inst).filter(|_| below_is_base) {
+            for (r, c) in cached.iter_mut().enumerate() {
+                let start = r as u64 * 8;
+                if (offset as u64) < start + 8 && start < offset as u64 + width as u64 {
+                    *c = false;
+                }
+            }
+        }
+
+        let Some((pops, pushes)) = stack_effect(inst) else { return };
+        if stack.len() < pops {
+            return;
+        }
+        stack.truncate(stack.len() - pops);
+        for _ in 0..pushes {
+            stack.push(inst == LocalGet { idx: 0 });
+        }
+        if matches!(inst, Br { .. } | BrTable { .. } | Return | Unreachable) {
+            dead_at = Some(frames.len());
+            cached = [false; 32];
+        }
+        out.push(inst);
+    }
+
+    func.body = out;
+    func.num_locals += 32;
+    remove_dead_tees(func, first);
+}
+
+/// Block-local common subexpression elimination.
+///
+/// Value-numbers the operand stack: pure instructions are keyed by opcode,
+/// immediate and operand numbers, and locals remember the number of the
+/// value last stored in them (so a register forwarded by
+/// `forward_registers` is recognised wherever it is read). When an i64
+/// expression is computed again and its instructions have no side effects,
+/// they are replaced by a `local.get` of a temp that the first computation
+/// tees into. Block functions only declare i64 locals, so i32 and float
+/// results are numbered but never reused. As in `forward_registers`,
+/// knowledge is dropped at `loop`, `end` and after unconditional branches.
+pub fn eliminate_common_subexpressions(func: &mut WasmFunction) {
+    use WasmInst::*;
+
+    #[derive(Clone, Copy)]
+    struct Value {
+        vn: u32,
+        /// Index in `out` where this value's computation starts
+        start: usize,
+        /// Whether `out[start..]` can be dropped without losing side effects
+        removable: bool,
+    }
+    type Key = (std::mem::Discriminant<WasmInst>, i64, [u32; 3]);
+
+    let first = func.num_locals + 1;
+    let mut next_vn = 0u32;
+    let mut fresh = || {
+        next_vn += 1;
+        next_vn
+    };
+    let mut table: HashMap<Key, (u32, usize)> = HashMap::new();
+    let mut local_vn: HashMap<u32, u32> = HashMap::new();
+    // Value number -> temp local, and where the first computation is teed
+    let mut temps: HashMap<u32, u32> = HashMap::new();
+    let mut tees: Vec<(usize, u32)> = Vec::new();
+
+    let mut out: Vec<WasmInst> = Vec::with_capacity(func.body.len());
+    let mut stack: Vec<Value> = Vec::new();
+    let mut frames: Vec<usize> = Vec::new();
+    let mut dead_at: Option<usize> = None;
+
+    for &inst in func.body.iter() {
+        match inst {
+            Block { .. } | Loop { .. } => {
+                frames.push(stack.len());
+                if matches!(inst, Loop { .. }) {
+                    table.clear();
+                    local_vn.clear();
+                }
+                out.push(inst);
+                continue;
+            }
+            End => {
+                let Some(height) = frames.pop() else { return };
+                stack.truncate(height);
+                if dead_at.is_some_and(|d| frames.len() < d) {
+                    dead_at = None;
+                }
+                table.clear();
+                local_vn.clear();
+                out.push(inst);
+                continue;
+            }
+            _ if dead_at.is_some() => {
+                out.push(inst);
+                continue;
+            }
+            _ => {}
+        }
+
+        let Some((pops, pushes)) = stack_effect(inst) else { return };
+        if stack.len() < pops {
+            return;
+        }
+        let operands = stack.split_off(stack.len() - pops);
+        let start = operands.first().map_or(out.len(), |v| v.start);
+        let removable = operands.iter().all(|v| v.removable);
+
+        if let LocalGet { idx } = inst {
+            if let Some(&vn) = local_vn.get(&idx) {
+                out.push(inst);
+                stack.push(Value { vn, start, removable: true });
+                continue;
+            }
+        }
+
+        if let Some(payload) = pure_payload(inst) {
+            let mut args = [u32::MAX; 3];
+            for (a, v) in args.iter_mut().zip(&operands) {
+                *a = v.vn;
+            }
+            let key = (std::mem::discriminant(&inst), payload, args);
+            match table.get(&key) {
+                Some(&(vn, producer)) if removable && pops > 0 && produces_i64(inst) => {
+                    let temp = *temps.entry(vn).or_insert_with(|| {
+                        let temp = first + tees.len() as u32;
+                        tees.push((producer, temp));
+                        temp
+                    });
+                    debug_assert!(producer < start);
+                    out.truncate(start);
+                    out.push(LocalGet { idx: temp });
+                    stack.push(Value { vn, start, removable: true });
+                }
+                Some(&(vn, _)) => {
+                    out.push(inst);
+                    stack.push(Value { vn, start, removable });
+                }
+                None => {
+                    let vn = fresh();
+                    table.insert(key, (vn, out.len()));
+                    out.push(inst);
+                    stack.push(Value { vn, start, removable });
+                }
+            }
+            continue;
+        }
+
+        // Anything else: side effects or a result that depends on memory
+        match inst {
+            LocalSet { idx } | LocalTee { idx } => {
+                local_vn.insert(idx, operands[0].vn);
+            }
+            Br { .. } | BrTable { .. } | Return | Unreachable => {
+                dead_at = Some(frames.len());
+                table.clear();
+                local_vn.clear();
+            }
+            _ => {}
+        }
+        if pushes == 0 {
+            // The statement now sits inside the range of every pending value
+            for v in stack.iter_mut() {
+                v.removable = false;
+            }
+        }
+        out.push(inst);
+        if pushes == 1 {
+            let vn = match inst {
+                LocalTee { .. } => operands[0].vn,
+                _ => fresh(),
+            };
+            let side_effect = matches!(inst, LocalTee { .. } | MemoryAtomicWait32 { .. } | MemoryAtomicNotify { .. })
+                || is_atomic_rmw(inst);
+            let removable = removable && !side_effect;
+            stack.push(Value { vn, start, removable });
+        }
+    }
+
+    // Tee each reused value at its first computation; temps whose every
+    // use was folded into a larger reuse are dropped again
+    tees.sort_unstable();
+    let mut body = Vec::with_capacity(out.len() + tees.len());
+    let mut pending = tees.iter().peekable();
+    for (i, inst) in out.into_iter().enumerate() {
+        body.push(inst);
+        if let Some(&&(_, temp)) = pending.peek().filter(|&&&(at, _)| at == i) {
+            body.push(LocalTee { idx: temp });
+            pending.next();
+        }
+    }
+    func.body = body;
+    func.num_locals += tees.len() as u32;
+    remove_dead_tees(func, first);
+}
+
+/// Remove `local.tee`s of locals `first..` whose value is never read, then
+/// renumber those locals densely.
+///
+/// Scans backwards: a tee is live if a read of its local follows before
+/// the next tee. That is only sound for locals every read of which sits in
+/// the same straight-line run as its tee, which is how `forward_registers`
+/// and `eliminate_common_subexpressions` use theirs.
+pub fn remove_dead_tees(func: &mut WasmFunction, first: u32) {
+    use WasmInst::*;
+
+    let count = (func.num_locals + 1).saturating_sub(first) as usize;
+    let mut live = vec![false; count];
+    let mut read = vec![false; count];
+    let mut keep = vec![true; func.body.len()];
+    for (i, inst) in func.body.iter().enumerate().rev() {
+        match *inst {
+            LocalGet { idx } | LocalSet { idx } if idx >= first => {
+                live[(idx - first) as usize] = true;
+                read[(idx - first) as usize] = true;
+            }
+            LocalTee { idx } if idx >= first => {
+                keep[i] = std::mem::take(&mut live[(idx - first) as usize]);
+            }
+            _ => {}
+        }
+    }
+    let mut keep = keep.into_iter();
+    func.body.retain(|_| keep.next().unwrap());
+
+    let mut slot = vec![0u32; count];
+    let mut used = 0;
+    for (s, &r) in slot.iter_mut().zip(&read) {
+        if r {
+            *s = first + used;
+            used += 1;
+        }
+    }
+    for inst in func.body.iter_mut() {
+        if let LocalGet { idx } | LocalSet { idx } | LocalTee { idx } = inst {
+            if *idx >= first {
+                *idx = slot[(*idx - first) as usize];
+            }
+        }
+    }
+    func.num_locals = first - 1 + used;
+}
+
+/// Propagate constants, fold them, and strength-reduce multiplication and
+/// division by constants.
+///
+/// A local set from a constant reads as that constant until control flow
+/// merges (`loop`, `end`), and x0 reads as 0. Integer operations on
+/// constants are evaluated (`eval::apply`), a `select` on a constant keeps
+/// the chosen operand, and identities such as `x + 0`, `x * 1` and `x & 0`
+/// drop the operation. What remains of a multiply, divide or remainder by
+/// a constant becomes shifts and masks: by a power of two always, and
+/// `x * (2^k ± 1)` and signed division by a power of two when `x` is short
+/// enough to evaluate again. With the divisor known, the zero and overflow
+/// guards `emit_div` wraps around RISC-V division fold away as well.
+pub fn propagate_constants(func: &mut WasmFunction) {
+    use WasmInst::*;
+
+    let mut known: HashMap<u32, WasmInst> = HashMap::new();
+    let mut out: Vec<WasmInst> = Vec::with_capacity(func.body.len());
+    let mut stack: Vec<Operand> = Vec::new();
+    let mut frames: Vec<usize> = Vec::new();
+    let mut dead_at: Option<usize> = None;
+
+    for &inst in func.body.iter() {
+        match inst {
+            Block { .. } | Loop { .. } | End => {
+                if inst == End {
+                    let Some(height) = frames.pop() else { return };
+                    stack.truncate(height);
+                    if dead_at.is_some_and(|d| frames.len() < d) {
+                        dead_at = None;
+                    }
+                } else {
+                    frames.push(stack.len());
+                }
+                if !matches!(inst, Block { .. }) {
+                    known.clear();
+                }
+                // Nothing is folded across a label
+                stack.iter_mut().for_each(|v| v.pure = false);
+                out.push(inst);
+                continue;
+            }
+            _ if dead_at.is_some() => {
+                out.push(inst);
+                continue;
+            }
+            _ => {}
+        }
+
+        let Some((pops, pushes)) = stack_effect(inst) else { return };
+        if stack.len() < pops {
+            return;
+        }
+        let operands = stack.split_off(stack.len() - pops);
+        let start = operands.first().map_or(out.len(), |v| v.start);
+        match inst {
+            LocalGet { idx } => {
+                if let Some(&c) = known.get(&idx) {
+                    out.push(c);
+                    stack.push(Operand { start, konst: Some(c), pure: true, base: false });
+                    continue;
+                }
+            }
+            I64Load { offset: 0 } if operands[0].base => {
+                out.truncate(start);
+                out.push(I64Const { value: 0 });
+                stack.push(Operand { start, konst: Some(I64Const { value: 0 }), pure: true, base: false });
+                continue;
+            }
+            // A constant teed is set, then pushed again so it can fold
+            LocalTee { idx } if operands[0].bits().is_some() => {
+                let c = operands[0].konst.unwrap();
+                known.insert(idx, c);
+                stack.iter_mut().for_each(|v| v.pure = false);
+                out.extend([LocalSet { idx }, c]);
+                stack.push(Operand { start: out.len() - 1, konst: Some(c), pure: true, base: false });
+                continue;
+            }
+            LocalSet { idx } | LocalTee { idx } => match operands[0].konst {
+                Some(c) => {
+                    known.insert(idx, c);
+                }
+                None => {
+                    known.remove(&idx);
+                }
+            },
+            _ => {}
+        }
+        if let Some(v) = simplify(inst, &operands, &mut out) {
+            stack.push(v);
+            continue;
+        }
+
+        if matches!(inst, Br { .. } | BrTable { .. } | Return | Unreachable) {
+            dead_at = Some(frames.len());
+        }
+        if pushes == 0 {
+            stack.iter_mut().for_each(|v| v.pure = false);
+        }
+        out.push(inst);
+        if pushes == 1 {
+            let konst = match inst {
+                I32Const { .. } | I64Const { .. } => Some(inst),
+                LocalTee { .. } => operands[0].konst,
+                _ => None,
+            };
+            let pure = operands.iter().all(|v| v.pure) && is_pure(inst);
+            stack.push(Operand { start, konst, pure, base: inst == LocalGet { idx: 0 } });
+        }
+    }
+
+    // Constants set into locals that are no longer read
+    let read: HashSet<u32> = out
+        .iter()
+        .filter_map(|i| match *i {
+            LocalGet { idx } => Some(idx),
+            _ => None,
+        })
+        .collect();
+    let mut body: Vec<WasmInst> = Vec::with_capacity(out.len());
+    for inst in out {
+        if let LocalSet { idx } = inst {
+            if !read.contains(&idx) && matches!(body.last(), Some(I32Const { .. } | I64Const { .. })) {
+                body.pop();
+                continue;
+            }
+        }
+        body.push(inst);
+    }
+    func.body = body;
+}
+
+/// A value on the operand stack of `propagate_constants`
+#[derive(Clone, Copy)]
+struct Operand {
+    /// Index in the output where the value's code starts
+    start: usize,
+    /// The constant it is (an `i32.const` or `i64.const`)
+    konst: Option<WasmInst>,
+    /// Whether its code can be removed or evaluated again
+    pure: bool,
+    /// Whether it is `$m`
+    base: bool,
+}
+
+impl Operand {
+    /// The constant's bits, if the value is one that can be removed
+    fn bits(&self) -> Option<u64> {
+        match self.konst.filter(|_| self.pure)? {
+            WasmInst::I32Const { value } => Some(value as u32 as u64),
+            WasmInst::I64Const { value } => Some(value as u64),
+            _ => None,
+        }
+    }
+}
+
+/// Fold or strength-reduce `inst` applied to `operands`, whose code ends
+/// `out`; the resulting value, or `None` to emit `inst` unchanged
+fn simplify(inst: WasmInst, operands: &[Operand], out: &mut Vec<WasmInst>) -> Option<Operand> {
+    use WasmInst::*;
+
+    let is64 = int_result(inst);
+    let konst = |is64: bool, bits: u64| {
+        if is64 {
+            I64Const { value: bits as i64 }
+        } else {
+            I32Const { value: bits as i32 }
+        }
+    };
+    if let Some(is64) = is64.filter(|_| !operands.is_empty()) {
+        let args: Option<Vec<u64>> = operands.iter().map(Operand::bits).collect();
+        if let Some(bits) = args.and_then(|args| crate::eval::apply(inst, &args)) {
+            let start = operands[0].start;
+            out.truncate(start);
+            out.push(konst(is64, bits));
+            return Some(Operand { start, konst: out.last().copied(), pure: true, base: false });
+        }
+    }
+
+    if inst == Select {
+        let [a, b, cond] = operands else { return None };
+        return match cond.bits()? {
+            0 if a.pure => {
+                out.truncate(cond.start);
+                out.drain(a.start..b.start);
+                Some(Operand { start: a.start, ..*b })
+            }
+            0 => None,
+            _ if b.pure => {
+                out.truncate(b.start);
+                Some(*a)
+            }
+            _ => None,
+        };
+    }
+
+    // Binary integer operations with a constant second operand (moved
+    // there first for commutative ones)
+    let &[mut x, mut c] = operands else { return None };
+    let is64 = is64?;
+    if c.bits().is_none() && commutative(inst) && x.bits().is_some() && x.start + 1 == c.start {
+        let k = out.remove(x.start);
+        out.push(k);
+        (x, c) = (Operand { start: x.start, ..c }, Operand { start: out.len() - 1, ..x });
+    }
+    let bits = c.bits().filter(|_| c.start + 1 == out.len())?;
+    let width: u32 = if is64 { 64 } else { 32 };
+    let value = if is64 { bits } else { bits & 0xffff_ffff };
+    let signed = if is64 { bits as i64 } else { bits as u32 as i32 as i64 };
+    let power = value.is_power_of_two().then(|| value.trailing_zeros());
+    let k = |n: u32| konst(is64, n as u64);
+    let op = |op64: WasmInst, op32: WasmInst| if is64 { op64 } else { op32 };
+    // `x` again, if it is short and pure
+    let again = || (x.pure && c.start - x.start <= 2).then(|| out[x.start..c.start].to_vec());
+
+    let code = match inst {
+        I64Add | I32Add | I64Sub | I32Sub | I64Or | I32Or | I64Xor | I32Xor if value == 0 => vec![],
+        I64Shl | I32Shl | I64ShrS | I32ShrS | I64ShrU | I32ShrU | I64Rotl | I64Rotr | I32Rotr
+            if value % width as u64 == 0 =>
+        {
+            vec![]
+        }
+        I64And | I32And if value == 0 && x.pure => return Some(replace_with_constant(out, x.start, konst(is64, 0))),
+        I64And | I32And if value == u64::MAX >> (64 - width) => vec![],
+        I64Mul | I32Mul if value == 0 && x.pure => return Some(replace_with_constant(out, x.start, konst(is64, 0))),
+        I64Mul | I32Mul if value == 1 => vec![],
+        I64Mul | I32Mul if power.is_some() => vec![k(power?), op(I64Shl, I32Shl)],
+        I64Mul | I32Mul if value.wrapping_sub(1).is_power_of_two() => {
+            [vec![k((value - 1).trailing_zeros()), op(I64Shl, I32Shl)], again()?, vec![op(I64Add, I32Add)]].concat()
+        }
+        I64Mul | I32Mul if value.wrapping_add(1).is_power_of_two() && value + 1 < 1 << (width - 1) => {
+            [vec![k((value + 1).trailing_zeros()), op(I64Shl, I32Shl)], again()?, vec![op(I64Sub, I32Sub)]].concat()
+        }
+        I64DivU | I32DivU | I64DivS | I32DivS if value == 1 => vec![],
+        I64DivU | I32DivU if power.is_some() => vec![k(power?), op(I64ShrU, I32ShrU)],
+        I64RemU | I32RemU if power.is_some() => vec![konst(is64, value - 1), op(I64And, I32And)],
+        // x / 2^k rounds toward zero: add 2^k - 1 to negative x first
+        I64DivS | I32DivS if signed > 1 && power.is_some() => [
+            again()?,
+            vec![k(width - 1), op(I64ShrS, I32ShrS), k(width - power?), op(I64ShrU, I32ShrU), op(I64Add, I32Add)],
+            vec![k(power?), op(I64ShrS, I32ShrS)],
+        ]
+        .concat(),
+        // x - ((x + bias) & -2^k), the bias as for division
+        I64RemS | I32RemS if signed > 1 && power.is_some() => [
+            again()?,
+            again()?,
+            vec![k(width - 1), op(I64ShrS, I32ShrS), k(width - power?), op(I64ShrU, I32ShrU), op(I64Add, I32Add)],
+            vec![konst(is64, signed.wrapping_neg() as u64), op(I64And, I32And), op(I64Sub, I32Sub)],
+        ]
+        .concat(),
+        _ => return None,
+    };
+    out.truncate(c.start);
+    out.extend(code);
+    Some(Operand { start: x.start, konst: None, pure: x.pure, base: false })
+}
+
+/// Replace the code from `start` on with `konst`
+fn replace_with_constant(out: &mut Vec<WasmInst>, start: usize, konst: WasmInst) -> Operand {
+    out.truncate(start);
+    out.push(konst);
+    Operand { start, konst: Some(konst), pure: true, base: false }
+}
+
+/// For integer operations `propagate_constants` can evaluate: whether the
+/// result is an i64 (else an i32)
+fn int_result(inst: WasmInst) -> Option<bool> {
+    use WasmInst::*;
+    match inst {
+        I64Add | I64Sub | I64Mul | I64DivS | I64DivU | I64RemS | I64RemU | I64And | I64Or | I64Xor | I64Shl
+        | I64ShrS | I64ShrU | I64Rotl | I64Rotr | I64Clz | I64Ctz | I64Popcnt | I64Extend8S | I64Extend16S
+        | I64ExtendI32S | I64ExtendI32U => Some(true),
+        I64Eqz | I64Eq | I64Ne | I64LtS | I64LtU | I64GtS | I64GtU | I64LeS | I64LeU | I64GeS | I64GeU
+        | I32Add | I32Sub | I32Mul | I32DivS | I32DivU | I32RemS | I32RemU | I32And | I32Or | I32Xor | I32Shl
+        | I32ShrS | I32ShrU | I32Rotr | I32Eqz | I32Eq | I32Ne | I32LtS | I32LtU | I32GtS | I32GtU | I32LeS
+        | I32LeU | I32GeS | I32GeU | I32WrapI64 => Some(false),
+        _ => None,
+    }
+}
+
+fn commutative(inst: WasmInst) -> bool {
+    use WasmInst::*;
+    matches!(inst, I64Add | I64Mul | I64And | I64Or | I64Xor | I32Add | I32Mul | I32And | I32Or | I32Xor)
+}
+
+/// Whether `inst` neither has side effects nor can trap, so its result can
+/// be dropped or computed again
+fn is_pure(inst: WasmInst) -> bool {
+    use WasmInst::*;
+    let traps = matches!(
+        inst,
+        I64DivS | I64DivU | I64RemS | I64RemU | I32DivS | I32DivU | I32RemS | I32RemU | I32TruncF32S
+            | I32TruncF32U | I32TruncF64S | I32TruncF64U | I64TruncF32S | I64TruncF32U | I64TruncF64S
+            | I64TruncF64U
+    );
+    (pure_payload(inst).is_some() || inst == Select) && !traps
+}
+
+/// Immediate that distinguishes a pure instruction from others of the same
+/// kind, or `None` if the instruction reads memory or has side effects
+fn pure_payload(inst: WasmInst) -> Option<i64> {
+    use WasmInst::*;
+    match inst {
+        I32Const { value } => Some(value as i64),
+        I64Const { value } => Some(value),
+        F32Const { value } => Some(value.to_bits() as i64),
+        F64Const { value } => Some(value.to_bits() as i64),
+        LocalGet { idx } => Some(idx as i64),
+        _ => match stack_effect(inst)? {
+            (1..=3, 1) if !reads_memory(inst) && !matches!(inst, LocalTee { .. }) => Some(0),
+            _ => None,
+        },
+    }
+}
+
+fn reads_memory(inst: WasmInst) -> bool {
+    use WasmInst::*;
+    matches!(
+        inst,
+        I32Load { .. }
+            | I64Load { .. }
+            | I32Load8S { .. }
+            | I32Load8U { .. }
+            | I32Load16S { .. }
+            | I32Load16U { .. }
+            | I64Load8S { .. }
+            | I64Load8U { .. }
+            | I64Load16S { .. }
+            | I64Load16U { .. }
+            | I64Load32S { .. }
+            | I64Load32U { .. }
+            | F32Load { .. }
+            | F64Load { .. }
+            | I32AtomicLoad { .. }
+            | I64AtomicLoad { .. }
+            | I64AtomicLoad8U { .. }
+            | I64AtomicLoad16U { .. }
+            | MemoryAtomicWait32 { .. }
+            | MemoryAtomicNotify { .. }
+    ) || is_atomic_rmw(inst)
+}
+
+/// Pure instructions whose result is an i64 (the only type temps can hold)
+fn produces_i64(inst: WasmInst) -> bool {
+    use WasmInst::*;
+    matches!(
+        inst,
+        I64Add
+            | I64Sub
+            | I64Mul
+            | I64DivS
+            | I64DivU
+            | I64RemS
+            | I64RemU
+            | I64And
+            | I64Or
+            | I64Xor
+            | I64Shl
+            | I64ShrS
+            | I64ShrU
+            | I64Rotl
+            | I64Rotr
+            | I64Clz
+            | I64Ctz
+            | I64Popcnt
+            | I64ExtendI32S
+            | I64ExtendI32U
+            | I64TruncF32S
+            | I64TruncF32U
+            | I64TruncF64S
+            | I64TruncF64U
+            | I64ReinterpretF64
+            | I64Extend8S
+            | I64Extend16S
+    )
+}
+
+/// Static offset and width of a plain store
+fn store_range(inst: WasmInst) -> Option<(u32, u32)> {
+    use WasmInst::*;
+    match inst {
+        I32Store8 { offset } | I64Store8 { offset } => Some((offset, 1)),
+        I32Store16 { offset } | I64Store16 { offset } => Some((offset, 2)),
+        I32Store { offset } | I64Store32 { offset } | F32Store { offset } | I32AtomicStore { offset } => {
+            Some((offset, 4))
+        }
+        I64Store { offset } | F64Store { offset } | I64AtomicStore { offset } => Some((offset, 8)),
+        _ => None,
+    }
+}
+
+fn is_atomic_rmw(inst: WasmInst) -> bool {
+    use WasmInst::*;
+    matches!(
+        inst,
+        I32AtomicRmwAdd { .. }
+            | I64AtomicRmwAdd { .. }
+            | I32AtomicRmwAnd { .. }
+            | I64AtomicRmwAnd { .. }
+            | I32AtomicRmwOr { .. }
+            | I64AtomicRmwOr { .. }
+            | I32AtomicRmwXor { .. }
+            | I64AtomicRmwXor { .. }
+            | I32AtomicRmwXchg { .. }
+            | I64AtomicRmwXchg { .. }
+            | I32AtomicRmwCmpxchg { .. }
+            | I64AtomicRmwCmpxchg { .. }
+            | I64AtomicRmw8CmpxchgU { .. }
+            | I64AtomicRmw16CmpxchgU { .. }
+    )
+}
+
+/// Values popped and pushed by `inst`; `None` for calls, whose signature
+/// the IR does not carry
+pub(crate) fn stack_effect(inst: WasmInst) -> Option<(usize, usize)> {
+    use WasmInst::*;
+    Some(match inst {
+        Block { .. } | Loop { .. } | End | Br { .. } | Return | Unreachable | Comment { .. } | AtomicFence => (0, 0),
+        BrIf { .. } | BrTable { .. } | Drop | LocalSet { .. } => (1, 0),
+        Call { .. } | CallIndirect { .. } | CallHost { .. } => return None,
+        LocalGet { .. } | I32Const { .. } | I64Const { .. } | F32Const { .. } | F64Const { .. } => (0, 1),
+        LocalTee { .. } => (1, 1),
+
+        I32Load { .. } | I64Load { .. } | I32Load8S { .. } | I32Load8U { .. } | I32Load16S { .. }
+        | I32Load16U { .. } | I64Load8S { .. } | I64Load8U { .. } | I64Load16S { .. } | I64Load16U { .. }
+        | I64Load32S { .. } | I64Load32U { .. } | F32Load { .. } | F64Load { .. } | I32AtomicLoad { .. }
+        | I64AtomicLoad { .. } | I64AtomicLoad8U { .. } | I64AtomicLoad16U { .. } => (1, 1),
+        I32Store { .. } | I64Store { .. } | I32Store8 { .. } | I32Store16 { .. } | I64Store8 { .. }
+        | I64Store16 { .. } | I64Store32 { .. } | F32Store { .. } | F64Store { .. } | I32AtomicStore { .. }
+        | I64AtomicStore { .. } => (2, 0),
+        MemoryFill => (3, 0),
+        I32AtomicRmwAdd { .. } | I64AtomicRmwAdd { .. } | I32AtomicRmwAnd { .. } | I64AtomicRmwAnd { .. }
+        | I32AtomicRmwOr { .. } | I64AtomicRmwOr { .. } | I32AtomicRmwXor { .. } | I64AtomicRmwXor { .. }
+        | I32AtomicRmwXchg { .. } | I64AtomicRmwXchg { .. } | MemoryAtomicNotify { .. } => (2, 1),
+        I32AtomicRmwCmpxchg { .. } | I64AtomicRmwCmpxchg { .. } | I64AtomicRmw8CmpxchgU { .. }
+        | I64AtomicRmw16CmpxchgU { .. } | MemoryAtomicWait32 { .. } | Select | F64RelaxedMadd => (3, 1),
+
+        I64Clz | I64Ctz | I64Popcnt | I64Eqz | I32Eqz | I32WrapI64 | I64ExtendI32S | I64ExtendI32U
+        | F32Sqrt | F32Neg | F32Abs | F32Ceil | F32Floor | F32Trunc | F32Nearest | F64Sqrt | F64Neg
+        | F64Abs | F64Ceil | F64Floor | F64Trunc | F64Nearest | F32ConvertI32S | F32ConvertI32U
+        | F32ConvertI64S | F32ConvertI64U | F64ConvertI32S | F64ConvertI32U | F64ConvertI64S
+        | F64ConvertI64U | I32TruncF32S | I32TruncF32U | I32TruncF64S | I32TruncF64U | I64TruncF32S
+        | I64TruncF32U | I64TruncF64S | I64TruncF64U | F32DemoteF64 | F64PromoteF32 | F32ReinterpretI32
+        | F64ReinterpretI64 | I32ReinterpretF32 | I64ReinterpretF64 | I64Extend8S | I64Extend16S => (1, 1),
+
+        I64Add | I64Sub | I64Mul | I64DivS | I64DivU | I64RemS | I64RemU | I64And | I64Or | I64Xor
+        | I64Shl | I64ShrS | I64ShrU | I64Rotl | I64Rotr | I64Eq | I64Ne | I64LtS | I64LtU | I64GtS
+        | I64GtU | I64LeS | I64LeU | I64GeS | I64GeU | I32Add | I32Sub | I32Mul | I32DivS | I32DivU
+        | I32RemS | I32RemU | I32And | I32Or | I32Xor | I32Shl | I32ShrS | I32ShrU | I32Rotr | I32Eq | I32Ne
+        | I32LtS | I32LtU | I32GtS | I32GtU | I32LeS | I32LeU | I32GeS | I32GeU | F32Add | F32Sub
+        | F32Mul | F32Div | F32Eq | F32Ne | F32Lt | F32Gt | F32Le | F32Ge | F32Min | F32Max
+        | F32Copysign | F64Add | F64Sub | F64Mul | F64Div | F64Eq | F64Ne | F64Lt | F64Gt | F64Le
+        | F64Ge | F64Min | F64Max | F64Copysign => (2, 1),
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use WasmInst::*;
+    use crate::test_util::inst;
+
+    #[test]
+    fn test_forward_registers() {
+        // x3 = x1 + x2; x4 = x3 + x1; sd x4, 0(x5); x6 = x4
+        let mut func = WasmFunction {
+            name: String::new(),
+            block_addr: 0,
+            body: vec![
+                LocalGet { idx: 0 },
+                LocalGet { idx: 0 },
+                I64Load { offset: 8 },
+                LocalGet { idx: 0 },
+                I64Load { offset: 16 },
+                I64Add,
+                I64Store { offset: 24 },
+                LocalGet { idx: 0 },
+                LocalGet { idx: 0 },
+                I64Load { offset: 24 },
+                LocalGet { idx: 0 },
+                I64Load { offset: 8 },
+                I64Add,
+                I64Store { offset: 32 },
+                LocalGet { idx: 0 },
+                I64Load { offset: 40 },
+                I32WrapI64,
+                LocalGet { idx: 0 },
+                I64Load { offset: 32 },
+                I64Store { offset: 0 },
+                LocalGet { idx: 0 },
+                LocalGet { idx: 0 },
+                I64Load { offset: 32 },
+                I64Store { offset: 48 },
+            ],
+            num_locals: 4,
+            br_tables: Vec::new(),
+        };
+        forward_registers(&mut func);
+        assert_eq!(
+            func.body,
+            vec![
+                LocalGet { idx: 0 },
+                LocalGet { idx: 0 },
+                I64Load { offset: 8 },
+                LocalTee { idx: 5 },
+                LocalGet { idx: 0 },
+                I64Load { offset: 16 },
+                I64Add,
+                LocalTee { idx: 6 },
+                I64Store { offset: 24 },
+                LocalGet { idx: 0 },
+                LocalGet { idx: 6 },
+                LocalGet { idx: 5 },
+                I64Add,
+                LocalTee { idx: 7 },
+                I64Store { offset: 32 },
+                LocalGet { idx: 0 },
+                I64Load { offset: 40 },
+                I32WrapI64,
+                LocalGet { idx: 7 },
+                I64Store { offset: 0 },
+                LocalGet { idx: 0 },
+                LocalGet { idx: 7 },
+                I64Store { offset: 48 },
+            ]
+        );
+        assert_eq!(func.num_locals, 7);
+    }
+
+    #[test]
+    fn test_cse_reuses_spill_address() {
+        // sd x1, 16(x2); ld x3, 16(x2), as the translator lowers them
+        let addr = [LocalGet { idx: 0 }, I64Load { offset: 16 }, I64Const { value: 16 }, I64Add, I32WrapI64];
+        let mut body = addr.to_vec();
+        body.extend([LocalGet { idx: 0 }, I64Load { offset: 8 }, I64Store { offset: 0 }, LocalGet { idx: 0 }]);
+        body.extend(addr);
+        body.extend([I64Load { offset: 0 }, I64Store { offset: 24 }]);
+        let mut func = WasmFunction {
+            name: String::new(),
+            block_addr: 0,
+            body,
+            num_locals: 4,
+            br_tables: Vec::new(),
+        };
+        forward_registers(&mut func);
+        eliminate_common_subexpressions(&mut func);
+        remove_dead_tees(&mut func, 5);
+        assert_eq!(
+            func.body,
+            vec![
+                LocalGet { idx: 0 },
+                I64Load { offset: 16 },
+                I64Const { value: 16 },
+                I64Add,
+                LocalTee { idx: 5 },
+                I32WrapI64,
+                LocalGet { idx: 0 },
+                I64Load { offset: 8 },
+                I64Store { offset: 0 },
+                LocalGet { idx: 0 },
+                LocalGet { idx: 5 },
+                I32WrapI64,
+                I64Load { offset: 0 },
+                I64Store { offset: 24 },
+            ]
+        );
+        assert_eq!(func.num_locals, 5);
+    }
+
+    #[test]
+    fn test_cse_sees_redefined_locals() {
+        // l2 = l1 + 1; [l1 = l3;] l4 = l1 + 1
+        let sum = [LocalGet { idx: 1 }, I64Const { value: 1 }, I64Add];
+        let cse = |redefine: bool| {
+            let mut body = sum.to_vec();
+            body.push(LocalSet { idx: 2 });
+            if redefine {
+                body.extend([LocalGet { idx: 3 }, LocalSet { idx: 1 }]);
+            }
+            body.extend(sum);
+            body.push(LocalSet { idx: 4 });
+            let mut func =
+                WasmFunction { name: String::new(), block_addr: 0, body, num_locals: 4, br_tables: Vec::new() };
+            eliminate_common_subexpressions(&mut func);
+            func
+        };
+
+        let reused = cse(false);
+        let mut body = sum.to_vec();
+        body.extend([LocalTee { idx: 5 }, LocalSet { idx: 2 }, LocalGet { idx: 5 }, LocalSet { idx: 4 }]);
+        assert_eq!(reused.body, body);
+        assert_eq!(reused.num_locals, 5);
+
+        // The second sum reads the new l1, so the first one's tee is stale
+        let redefined = cse(true);
+        let mut body = sum.to_vec();
+        body.extend([LocalSet { idx: 2 }, LocalGet { idx: 3 }, LocalSet { idx: 1 }]);
+        body.extend(sum);
+        body.push(LocalSet { idx: 4 });
+        assert_eq!(redefined.body, body);
+        assert_eq!(redefined.num_locals, 4);
+    }
+
+    #[test]
+    fn test_fold_extensions() {
+        // ADDW-style: both operands wrapped from 64-bit loads, result stored extended
+        let mut body = vec![
+            LocalGet { idx: 0 },
+            LocalGet { idx: 0 },
+            I64Load { offset: 8 },
+            I32WrapI64,
+            I64ExtendI32S,
+            I32WrapI64,
+            I64Const { value: 0x1_0000_0005 },
+            I32WrapI64,
+            I32Add,
+            I64ExtendI32S,
+            I64Store32 { offset: 16 },
+        ];
+        fold_extensions(&mut body);
+        assert_eq!(
+            body,
+            vec![
+                LocalGet { idx: 0 },
+                LocalGet { idx: 0 },
+                I32Load { offset: 8 },
+                I32Const { value: 5 },
+                I32Add,
+                I32Store { offset: 16 },
+            ]
+        );
+
+        // A lone extension before a full-width store must stay
+        let mut body = vec![I32Load { offset: 0 }, I64ExtendI32U, I64Store { offset: 8 }];
+        fold_extensions(&mut body);
+        assert_eq!(body, vec![I64Load32U { offset: 0 }, I64Store { offset: 8 }]);
+    }
+
+    #[test]
+    fn test_propagate_constants_reduces_division() {
+        use crate::disasm::Opcode;
+        use crate::interp::{self, Hart};
+
+        // li a1, 8; li t0, 5; then a0 op a1 / a0 * t0 into a2..s5
+        let mut code = vec![inst(0x1000, Opcode::ADDI, 11, 0, 0, 8), inst(0x1004, Opcode::ADDI, 5, 0, 0, 5)];
+        let ops = [
+            (Opcode::DIV, 11),
+            (Opcode::REM, 11),
+            (Opcode::DIVU, 11),
+            (Opcode::REMU, 11),
+            (Opcode::DIVW, 11),
+            (Opcode::REMW, 11),
+            (Opcode::MUL, 11),
+            (Opcode::MUL, 5),
+            (Opcode::MULW, 5),
+        ];
+        for (i, &(opcode, rs2)) in ops.iter().enumerate() {
+            code.push(inst(0x1008 + 4 * i as u64, opcode, 12 + i as u8, 10, rs2, 0));
+        }
+        let cfg = crate::cfg::build(&code, 0x1000).unwrap();
+        let module = crate::translate::translate_jit(&cfg, 0x1000).unwrap();
+        let func = &module.functions[0];
+        assert!(!func.body.iter().any(|i| matches!(
+            i,
+            I64DivS | I64DivU | I64RemS | I64RemU | I32DivS | I32RemS | I64Mul | I32Mul | Select
+        )));
+
+        for a0 in [0, 1, 7, 8, 9, u64::MAX, u64::MAX - 8, i64::MIN as u64, 0x8000_0007, 0x1234_5678_9abc_def0] {
+            let mut hart = Hart::new(0x1000);
+            hart.x[10] = a0;
+            interp::run_block(&mut hart, &mut [], &code).unwrap();
+            let mut state = vec![0u8; 0x200];
+            state[80..88].copy_from_slice(&a0.to_le_bytes());
+            crate::eval::eval(func, &mut state, 0).unwrap();
+            for r in 1..32 {
+                assert_eq!(u64::from_le_bytes(state[r * 8..r * 8 + 8].try_into().unwrap()), hart.x[r], "x{}", r);
+            }
+        }
+    }
+}
@@ -0,0 +1,190 @@
+// options.rs - Compilation options
+//
+// Collects the knobs that shape translation and Wasm emission so the
+// pipeline stages share one description of "how to compile".
+
+use crate::data::DataChunk;
+use crate::elf::Symbol;
+use crate::policy::SyscallPolicy;
+use std::collections::BTreeSet;
+use std::ops::Range;
+use std::sync::Arc;
+use crate::hotness::BlockProfile;
+
+/// Options controlling translation and Wasm emission
+#[derive(Debug, Clone)]
+pub struct CompileOptions {
+    /// Optimization level (0-3)
+    pub opt_level: u8,
+    /// Emit debug info (block addresses, instruction comments)
+    pub debug: bool,
+    /// Threads mode: import shared memory, lower A-extension instructions to
+    /// Wasm atomics and emit futex/thread helpers for multi-worker execution
+    pub threads: bool,
+    /// Lay block functions out hottest first (see `hotness.rs`) instead of
+    /// in address order
+    pub hot_first: bool,
+    /// Measured block counts for `hot_first`; static estimates (`freq.rs`)
+    /// are used without one
+    pub block_profile: Option<BlockProfile>,
+    /// Guest functions to export as `guest_<name>` wrappers that take a0-a7
+    /// and return (a0, a1)
+    pub exports: Vec<Symbol>,
+    /// Split mode: emit a dispatcher plus code modules of about this many
+    /// blocks each, loaded on demand (see `split.rs`); 0 emits one module
+    pub split: usize,
+    /// Initial memory to embed as passive data segments, copied in on
+    /// demand by the exported `init_data` (see `data.rs`)
+    pub data: Arc<[DataChunk]>,
+    /// Size mode (-Os): no per-block exports (a `block_index` lookup
+    /// instead) unless `debug`, identical block bodies emitted once and the
+    /// smallest dispatch encoding. Single-module output only.
+    pub size: bool,
+    /// Blocks to export as `block_<addr>`, keeping the rest internal; None
+    /// exports every block (none in size mode without `debug`)
+    pub export_blocks: Option<Vec<u64>>,
+    /// Load bias for a PIE input (`ElfFile::with_load_bias`): code is
+    /// translated for the binary loaded this far above its link address
+    pub load_bias: u64,
+    /// Linear-memory address of guest address 0 (page-aligned). Every guest
+    /// access is offset by it, so guest memory can sit clear of the machine
+    /// state, or beside other guests in one shared memory.
+    pub memory_offset: u32,
+    /// Software MMU: the linear address of the root page table every guest
+    /// access is mapped through (see `mmu.rs`); None for flat memory. The
+    /// futex helpers then take linear addresses.
+    pub soft_mmu: Option<u32>,
+    /// Guest address of a time page the host keeps current (see `vdso.rs`):
+    /// clock_gettime and gettimeofday read it instead of exiting to the host
+    pub time_page: Option<u32>,
+    /// Lazy compilation: the dispatch loop hands a PC without a block to the
+    /// imported `env.compile_missing(pc)`, which installs code for it in the
+    /// (growable) dispatch table and returns its index, instead of halting
+    pub lazy_compile: bool,
+    /// What the dispatch loop does with a PC no block (nor, lazily
+    /// compiling, `env.compile_missing`) has code for
+    pub unknown_pc: UnknownPc,
+    /// Hot/cold split: translate only the blocks `hotness::hot_set` picks
+    /// (reachable from the entry and exports, or the functions sampled in
+    /// `block_profile`) and leave the rest to lazy compilation, which this
+    /// implies
+    pub hot_only: bool,
+    /// Check every block function's IR before encoding (see `verify.rs`);
+    /// on by default in debug builds
+    pub verify_ir: bool,
+    /// How the fused multiply-adds are lowered (see `fma.rs`)
+    pub fma: FmaMode,
+    /// JSPI mode: the dispatch loop makes syscalls through
+    /// `env.syscall_async($m, $pc, $cause)`, which the host wraps in
+    /// `WebAssembly.Suspending` so a blocking syscall suspends `run` (called
+    /// through `WebAssembly.promising`) rather than the thread
+    pub jspi: bool,
+    /// Trap exceptions: an illegal instruction, misaligned atomic or page
+    /// fault throws the exported `guest_trap` tag with its cause, PC and
+    /// address (exception handling) instead of going to the syscall handler
+    pub trap_exceptions: bool,
+    /// Harts an SMP guest runs on, each with its own machine state and
+    /// dispatch loop over the shared memory; above 1 this needs `threads`,
+    /// and mhartid reads return `HART_ID_OFFSET` of the hart's state
+    pub harts: u32,
+    /// Watchpoint: the guest address range whose loads and stores call the
+    /// imported `env.watch($addr, $size, $is_write, $pc)` first (see
+    /// `watch.rs`)
+    pub watch: Option<Range<u32>>,
+    /// Guest PCs at which blocks call the imported `env.breakpoint($pc)`
+    /// before the instruction; a nonzero result stops the block there as
+    /// EBREAK would, with the instruction not yet executed
+    pub breakpoints: BTreeSet<u64>,
+    /// Guest functions whose entry block calls the imported
+    /// `env.host_<name>(a0..a7) -> a0` and returns instead (see
+    /// `interpose.rs`)
+    pub interpose: Vec<Symbol>,
+    /// Syscalls ECALLs may hand to the host; the rest fail with ENOSYS or
+    /// trap in the guest (see `policy.rs`)
+    pub syscall_policy: Option<SyscallPolicy>,
+    /// Charge every block's instructions to the hart's budget at
+    /// `translate::BUDGET_OFFSET` before it runs; once the budget is short,
+    /// `run` returns `abi::STOP_BUDGET` with the block not yet executed
+    pub meter: bool,
+    /// Refuse memory growth past this many pages: the maximum of the
+    /// memory import (at least the image's pages)
+    pub max_pages: Option<u32>,
+}
+
+/// Lowering for FMADD, FMSUB, FNMADD and FNMSUB
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum FmaMode {
+    /// A multiply then an add: fastest, but rounds twice, so the last bit
+    /// can differ from hardware
+    #[default]
+    Unfused,
+    /// `f64x2.relaxed_madd` (relaxed SIMD), fused on hosts with FMA and
+    /// unfused elsewhere; single precision is exact as for `Exact`
+    Relaxed,
+    /// A software sequence that rounds once, as hardware does
+    Exact,
+}
+
+/// Policy for a PC without a block
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum UnknownPc {
+    /// End the run as the guest halting would: `run` returns 0
+    #[default]
+    Halt,
+    /// End the run with `abi::STOP_UNKNOWN_PC`
+    Stop,
+    /// Trap (`unreachable`)
+    Trap,
+    /// Call the imported `env.unknown_pc($pc) -> i32` and continue at the
+    /// PC it returns; -1 halts and a stop code ends the run with it
+    Import,
+}
+
+impl CompileOptions {
+    /// Reject combinations no block can be translated under, once before
+    /// translation starts
+    pub fn validate(&self) -> anyhow::Result<()> {
+        if self.harts > 1 && !self.threads {
+            anyhow::bail!("{} harts need threads mode", self.harts);
+        }
+        if self.memory_offset != 0 && self.soft_mmu.is_some() {
+            anyhow::bail!("A memory offset cannot be combined with the software MMU");
+        }
+        Ok(())
+    }
+}
+
+impl Default for CompileOptions {
+    fn default() -> Self {
+        Self {
+            opt_level: 2,
+            debug: false,
+            threads: false,
+            hot_first: false,
+            block_profile: None,
+            exports: Vec::new(),
+            split: 0,
+            data: Arc::default(),
+            size: false,
+            export_blocks: None,
+            load_bias: 0,
+            memory_offset: 0,
+            soft_mmu: None,
+            time_page: None,
+            lazy_compile: false,
+            unknown_pc: UnknownPc::Halt,
+            hot_only: false,
+            verify_ir: cfg!(debug_assertions),
+            fma: FmaMode::Unfused,
+            jspi: false,
+            trap_exceptions: false,
+            harts: 1,
+            watch: None,
+            breakpoints: BTreeSet::new(),
+            interpose: Vec::new(),
+            syscall_policy: None,
+            meter: false,
+            max_pages: None,
+        }
+    }
+}
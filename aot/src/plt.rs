@@ -0,0 +1,179 @@
+// plt.rs - PLT stub recognition and direct binding
+//
+// Calls into shared code go through a PLT stub that loads the target from a
+// GOT slot and jumps to it:
+//
+//   auipc t3, %pcrel_hi(slot)
+//   ld    t3, %pcrel_lo(slot)(t3)
+//   jalr  t1, t3
+//
+// Translated as is, every such call is an indirect jump through the
+// dispatch loop. When the slot's contents are known at compile time (a
+// relocation against a symbol this binary defines, a RELATIVE relocation,
+// or a non-PIE slot filled in by the linker), `bind` records the target on
+// the stub's block (`BasicBlock::direct_target`) and adds it as a
+// successor, so the block returns it as a constant and the CFG sees the
+// edge. The same goes for -fno-plt calls, which inline the pattern.
+//
+// Any other `auipc r; ld rd, lo(r)` of such a slot (a function pointer or
+// the address of a data object taken through the GOT) is recorded in
+// `BasicBlock::got_loads`, and the translator writes the loaded value as a
+// constant. An indirect call through it then returns a constant target,
+// and arithmetic on it folds.
+//
+// Only slots in .got/.got.plt are bound: the loader writes those once
+// (lazy binding writes the same target), while an ordinary function
+// pointer loaded the same way may be reassigned at run time.
+
+use crate::cfg::{BasicBlock, ControlFlowGraph};
+use crate::disasm::{Instruction, Opcode};
+use crate::elf::ElfFile;
+use goblin::elf::reloc::{R_RISCV_64, R_RISCV_JUMP_SLOT, R_RISCV_RELATIVE};
+use std::collections::{BTreeMap, HashMap};
+
+/// Bind every stub in `cfg` whose GOT slot `file` resolves statically.
+/// Returns the number of blocks bound.
+pub fn bind(cfg: &mut ControlFlowGraph, file: &ElfFile) -> usize {
+    bind_slots(cfg, &got_targets(file))
+}
+
+/// Bind the stubs loading one of `slots` (GOT slot -> target), and note
+/// every other load of one. Targets that do not start a block are left to
+/// the dispatch loop.
+pub fn bind_slots(cfg: &mut ControlFlowGraph, slots: &BTreeMap<u64, u64>) -> usize {
+    for block in cfg.blocks.values_mut() {
+        block.got_loads = block
+            .instructions
+            .windows(2)
+            .filter_map(|w| Some((w[1].addr, *slots.get(&loaded_slot(&w[0], &w[1])?)?)))
+            .collect();
+    }
+    let bound: Vec<(u64, u64)> = cfg
+        .blocks
+        .iter()
+        .filter_map(|(&addr, block)| Some((addr, *slots.get(&got_slot(block)?)?)))
+        .filter(|(_, target)| cfg.blocks.contains_key(target))
+        .collect();
+    for &(addr, target) in &bound {
+        let block = cfg.blocks.get_mut(&addr).unwrap();
+        block.direct_target = Some(target);
+        if !block.successors.contains(&target) {
+            block.successors.push(target);
+        }
+    }
+    bound.len()
+}
+
+/// The GOT slot a block ending in `auipc r; ld r, lo(r); jalr 0(r)` jumps through
+fn got_slot(block: &BasicBlock) -> Option<u64> {
+    let [.., auipc, ld, jump] = block.instructions else {
+        return None;
+    };
+    let slot = loaded_slot(auipc, ld)?;
+    let indirect = matches!(jump.opcode, Opcode::JALR | Opcode::C_JR | Opcode::C_JALR);
+    if ld.rd != auipc.rd || !indirect || jump.rs1 != ld.rd || jump.imm.unwrap_or(0) != 0 {
+        return None;
+    }
+    Some(slot)
+}
+
+/// The slot `auipc r, hi; ld rd, lo(r)` loads from
+fn loaded_slot(auipc: &Instruction, ld: &Instruction) -> Option<u64> {
+    let reg = auipc.rd.filter(|&r| r != 0)?;
+    if auipc.opcode != Opcode::AUIPC || !matches!(ld.opcode, Opcode::LD | Opcode::C_LD) || ld.rs1 != Some(reg) {
+        return None;
+    }
+    Some(auipc.addr.wrapping_add(auipc.imm? as u64).wrapping_add(ld.imm? as u64))
+}
+
+/// Every .got/.got.plt slot of `file` with a statically known target
+pub fn got_targets(file: &ElfFile) -> BTreeMap<u64, u64> {
+    let elf = file.elf();
+    let relocs: HashMap<u64, _> = file.relocations().into_iter().map(|r| (r.offset, r)).collect();
+    let mut targets = BTreeMap::new();
+    for section in &elf.section_headers {
+        let name = elf.shdr_strtab.get_at(section.sh_name).unwrap_or("");
+        if name != ".got" && name != ".got.plt" {
+            continue;
+        }
+        let start = section.sh_addr + file.load_bias();
+        for slot in (start..start + section.sh_size).step_by(8) {
+            let target = match relocs.get(&slot) {
+                Some(r) if r.r_type == R_RISCV_JUMP_SLOT || r.r_type == R_RISCV_64 => {
+                    r.symbol_value.map(|value| value.wrapping_add(r.addend as u64))
+                }
+                Some(r) if r.r_type == R_RISCV_RELATIVE => Some(file.load_bias().wrapping_add(r.addend as u64)),
+                // IRELATIVE and the like are only known at run time
+                Some(_) => None,
+                None if !file.info().is_pie => {
+                    file.read(slot, 8).map(|word| u64::from_le_bytes(word.try_into().unwrap()))
+                }
+                None => None,
+            };
+            if let Some(target) = target.filter(|&t| t != 0) {
+                targets.insert(slot, target);
+            }
+        }
+    }
+    targets
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_util::{elf_info, inst};
+
+    #[test]
+    fn test_bind_plt_stub() {
+        // 0x1000: call stub; ecall -- stub (0x1010): auipc t3, 0x1000; ld t3, 0x28(t3); jalr t1, t3
+        // -- 0x1020 (f): ret -- 0x1030: a jump through a slot with no known target
+        let code = [
+            inst(0x1000, Opcode::JAL, 1, 0, 0, 0x10),
+            inst(0x1004, Opcode::ECALL, 0, 0, 0, 0),
+            inst(0x1010, Opcode::AUIPC, 28, 0, 0, 0x1000),
+            inst(0x1014, Opcode::LD, 28, 28, 0, 0x28),
+            inst(0x1018, Opcode::JALR, 6, 28, 0, 0),
+            inst(0x1020, Opcode::JALR, 0, 1, 0, 0),
+            inst(0x1030, Opcode::AUIPC, 15, 0, 0, 0x1000),
+            inst(0x1034, Opcode::LD, 15, 15, 0, 0x14),
+            inst(0x1038, Opcode::JALR, 0, 15, 0, 0),
+        ];
+        let mut cfg = crate::cfg::build(&code, 0x1000).unwrap();
+        let slots = BTreeMap::from([(0x2038, 0x1020), (0x2040, 0x1020)]);
+        assert_eq!(bind_slots(&mut cfg, &slots), 1);
+
+        let stub = &cfg.blocks[&0x1010];
+        assert_eq!(stub.direct_target, Some(0x1020));
+        assert!(stub.successors.contains(&0x1020));
+        assert_eq!(cfg.blocks[&0x1030].direct_target, None);
+
+        // The stub returns its target as a constant
+        let elf_info = elf_info();
+        let module = crate::translate::translate(&cfg, &elf_info, &Default::default()).unwrap();
+        let body = &module.functions.iter().find(|f| f.block_addr == 0x1010).unwrap().body;
+        assert!(body.ends_with(&[crate::WasmInst::I32Const { value: 0x1020 }, crate::WasmInst::Return]));
+    }
+
+    #[test]
+    fn test_got_load_is_constant() {
+        // auipc a5, 0x1000; ld a5, 0x38(a5); addi a0, a5, 8; ret
+        let code = [
+            inst(0x1000, Opcode::AUIPC, 15, 0, 0, 0x1000),
+            inst(0x1004, Opcode::LD, 15, 15, 0, 0x38),
+            inst(0x1008, Opcode::ADDI, 10, 15, 0, 8),
+            inst(0x100c, Opcode::JALR, 0, 1, 0, 0),
+        ];
+        let mut cfg = crate::cfg::build(&code, 0x1000).unwrap();
+        assert_eq!(bind_slots(&mut cfg, &BTreeMap::from([(0x2038, 0x4000)])), 0);
+        assert_eq!(cfg.blocks[&0x1000].got_loads, [(0x1004, 0x4000)]);
+
+        // The load reads nothing: the slot's value is written as a constant
+        let module = crate::translate::translate_jit(&cfg, 0x1000).unwrap();
+        let body = &module.functions[0].body;
+        assert!(!body.iter().any(|inst| matches!(inst, crate::WasmInst::I64Load { offset } if *offset != 8)));
+        let mut memory = vec![0u8; 32 * 8];
+        crate::eval::eval(&module.functions[0], &mut memory, 0).unwrap();
+        let reg = |n: usize| u64::from_le_bytes(memory[n * 8..n * 8 + 8].try_into().unwrap());
+        assert_eq!((reg(15), reg(10)), (0x4000, 0x4008));
+    }
+}
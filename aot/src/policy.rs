@@ -0,0 +1,123 @@
+// policy.rs - Syscall policy compiled into the module
+//
+// With `CompileOptions::syscall_policy` every ECALL first compares a7
+// against the policy's list, like a seccomp filter: an allow list lets only
+// the listed syscalls through to `env.syscall`, a deny list all but those.
+// A denied syscall never reaches the host: it either fails with ENOSYS (a0
+// = -38, execution continues after the ECALL) or stops the guest as
+// `abi::ILLEGAL_TRAP` at the ECALL, which hosts turn into SIGILL. For
+// untrusted guests this leaves the host handler only the syscalls the
+// policy names to get right.
+//
+// The check is a compare per listed syscall, so short lists are cheapest.
+// Time syscalls answered from the time page (`vdso.rs`) are checked too.
+
+use crate::translate::WasmInst;
+use std::collections::BTreeSet;
+
+/// Linux's ENOSYS, as returned in a0
+const ENOSYS: i64 = -38;
+const A0: u32 = 10;
+const A7: u32 = 17;
+
+/// What a denied syscall does
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum Denied {
+    /// Return -ENOSYS to the guest
+    #[default]
+    Enosys,
+    /// Stop at the ECALL with `abi::ILLEGAL_TRAP`
+    Trap,
+}
+
+/// The syscalls a module hands to the host
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct SyscallPolicy {
+    /// Syscall numbers (a7 values) the list names
+    pub syscalls: BTreeSet<u64>,
+    /// Whether `syscalls` are the only ones allowed (else the ones denied)
+    pub allow: bool,
+    /// What a denied syscall does
+    pub denied: Denied,
+}
+
+impl SyscallPolicy {
+    /// Whether syscall `nr` reaches the host
+    pub fn allows(&self, nr: u64) -> bool {
+        self.syscalls.contains(&nr) == self.allow
+    }
+}
+
+/// Emit the check ahead of the ECALL at `pc`: denied syscalls return here
+/// (to `next_pc` with ENOSYS, or as a trap); allowed ones fall through.
+/// Uses local 1.
+pub(crate) fn emit_check(body: &mut Vec<WasmInst>, policy: &SyscallPolicy, pc: u64, next_pc: u64) {
+    use WasmInst::*;
+    body.extend([Block { label: 0 }, LocalGet { idx: 0 }, I64Load { offset: A7 * 8 }, LocalSet { idx: 1 }]);
+    if !policy.allow {
+        body.push(Block { label: 0 });
+    }
+    for &nr in &policy.syscalls {
+        body.extend([LocalGet { idx: 1 }, I64Const { value: nr as i64 }, I64Eq, BrIf { label: 0 }]);
+    }
+    if !policy.allow {
+        body.extend([Br { label: 1 }, End]);
+    }
+    match policy.denied {
+        Denied::Enosys => {
+            body.extend([LocalGet { idx: 0 }, I64Const { value: ENOSYS }, I64Store { offset: A0 * 8 }]);
+            body.extend([I32Const { value: next_pc as i32 }, Return]);
+        }
+        Denied::Trap => crate::translate::emit_illegal_trap(body, pc),
+    }
+    body.push(End);
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::disasm::{Instruction, Opcode};
+    use crate::CompileOptions;
+
+    #[test]
+    fn test_denied_syscalls_stay_in_the_guest() {
+        // ecall
+        let code = [Instruction {
+            addr: 0x1000,
+            bytes: 0x73,
+            len: 4,
+            opcode: Opcode::ECALL,
+            rd: None,
+            rs1: None,
+            rs2: None,
+            imm: None,
+        }];
+        let cfg = crate::cfg::build(&code, 0x1000).unwrap();
+        let ecall = crate::abi::ECALL_FLAG as i32 | 0x1000;
+        let run = |policy: SyscallPolicy, nr: u64| {
+            let options = CompileOptions { syscall_policy: Some(policy), ..Default::default() };
+            let mut funcs = Vec::new();
+            crate::translate::translate_streaming(&cfg, &options, |f| {
+                funcs.push(f);
+                Ok(())
+            })
+            .unwrap();
+            let mut memory = vec![0u8; 0x1000];
+            memory[136..144].copy_from_slice(&nr.to_le_bytes());
+            let next = crate::eval::eval(&funcs[0], &mut memory, 0).unwrap();
+            (next, i64::from_le_bytes(memory[80..88].try_into().unwrap()))
+        };
+
+        // Allow write (64) and exit_group (94)
+        let allow = SyscallPolicy { syscalls: [64, 94].into(), allow: true, denied: Denied::Enosys };
+        assert_eq!(run(allow.clone(), 64), (ecall, 0));
+        assert_eq!(run(allow.clone(), 56), (0x1004, ENOSYS));
+        let trap = SyscallPolicy { denied: Denied::Trap, ..allow };
+        assert_eq!(run(trap, 56), (crate::abi::ILLEGAL_TRAP as i32, 0));
+
+        // Deny openat (56)
+        let deny = SyscallPolicy { syscalls: [56].into(), allow: false, denied: Denied::Enosys };
+        assert_eq!(run(deny.clone(), 64), (ecall, 0));
+        assert_eq!(run(deny, 56), (0x1004, ENOSYS));
+    }
+}
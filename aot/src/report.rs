@@ -0,0 +1,132 @@
+// report.rs - Unsupported-instruction report
+//
+// Lists every instruction the translator lowers to the "unsupported" trap
+// (undecodable bytes and decoded opcodes without a lowering), grouped by
+// kind and sorted by count, with every address and the disassembly around
+// the first few sites. A binary built for extensions we lack otherwise only
+// shows them one runtime halt at a time; `rv2wasm --unsupported-report`
+// writes this next to the module.
+
+use crate::cfg::ControlFlowGraph;
+use crate::disasm::{Instruction, Opcode};
+use crate::options::CompileOptions;
+use crate::translate;
+use std::collections::{BTreeMap, HashMap};
+use std::fmt::Write;
+
+/// Sites per kind shown with disassembly context
+const CONTEXT_SITES: usize = 3;
+/// Instructions of context before and after a site
+const CONTEXT_LINES: usize = 2;
+
+/// One kind of unsupported instruction and where it occurs
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Unsupported {
+    /// Opcode name, or the encoding class for undecodable bytes
+    pub kind: String,
+    /// Addresses, ascending
+    pub addrs: Vec<u64>,
+}
+
+/// The unsupported instructions in `cfg`, most frequent kind first
+pub fn unsupported(cfg: &ControlFlowGraph, options: &CompileOptions) -> Vec<Unsupported> {
+    let mut kinds: BTreeMap<String, Vec<u64>> = BTreeMap::new();
+    for block in cfg.blocks.values() {
+        for inst in block.instructions {
+            if !translate::is_supported(inst, options) {
+                kinds.entry(kind(inst)).or_default().push(inst.addr);
+            }
+        }
+    }
+    let mut report: Vec<Unsupported> = kinds.into_iter().map(|(kind, addrs)| Unsupported { kind, addrs }).collect();
+    report.sort_by_key(|u| std::cmp::Reverse(u.addrs.len()));
+    report
+}
+
+/// The report for `cfg` as text
+pub fn render(cfg: &ControlFlowGraph, options: &CompileOptions) -> String {
+    let report = unsupported(cfg, options);
+    let total: usize = report.iter().map(|u| u.addrs.len()).sum();
+    let mut functions = HashMap::new();
+    for func in &cfg.functions {
+        for &addr in &func.blocks {
+            functions.insert(addr, func.name.as_str());
+        }
+    }
+
+    let mut out = String::new();
+    let _ = writeln!(out, "# {} unsupported instructions, {} kinds", total, report.len());
+    for u in &report {
+        let _ = writeln!(out, "\n{} x{}", u.kind, u.addrs.len());
+        let addrs: Vec<String> = u.addrs.iter().map(|a| format!("0x{:x}", a)).collect();
+        let _ = writeln!(out, "  at {}", addrs.join(" "));
+        for &addr in u.addrs.iter().take(CONTEXT_SITES) {
+            let Some((&start, block)) = cfg.blocks.range(..=addr).next_back() else { continue };
+            let Some(at) = block.instructions.iter().position(|i| i.addr == addr) else { continue };
+            let _ = writeln!(out, "  0x{:x} in {}:", addr, functions.get(&start).copied().unwrap_or("?"));
+            let first = at.saturating_sub(CONTEXT_LINES);
+            let last = (at + CONTEXT_LINES + 1).min(block.instructions.len());
+            for (i, inst) in block.instructions[first..last].iter().enumerate() {
+                let marker = if first + i == at { ">" } else { " " };
+                let _ = writeln!(out, "   {} {}", marker, disassemble(inst));
+            }
+        }
+    }
+    out
+}
+
+/// Group key: the opcode, or the encoding class of undecodable bytes
+fn kind(inst: &Instruction) -> String {
+    match inst.opcode {
+        Opcode::Unknown if inst.bytes == 0 => "illegal (all-zero parcel)".into(),
+        Opcode::Unknown if inst.len == 2 => {
+            format!("unknown compressed (quadrant {}, funct3 {})", inst.bytes & 0x3, (inst.bytes >> 13) & 0x7)
+        }
+        Opcode::Unknown => format!("unknown (major opcode 0x{:02x})", inst.bytes & 0x7f),
+        op => format!("{:?}", op),
+    }
+}
+
+/// One line of disassembly: address, encoding and opcode
+fn disassemble(inst: &Instruction) -> String {
+    let bytes = if inst.len == 2 { format!("{:04x}    ", inst.bytes) } else { format!("{:08x}", inst.bytes) };
+    format!("0x{:x}  {}  {:?}", inst.addr, bytes, inst.opcode)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::disasm::decode32;
+
+    #[test]
+    fn test_unsupported_report() {
+        // addi a0, a0, 1 ; two vector ops (major opcode 0x57) ; an fp op with fmt=Q ; ecall
+        let code: Vec<Instruction> = [0x00150513u32, 0x02008057, 0x02008057, 0x5e057053, 0x00000073]
+            .iter()
+            .enumerate()
+            .map(|(i, &bits)| {
+                let addr = 0x1000 + 4 * i as u64;
+                decode32(addr, bits).unwrap_or(Instruction {
+                    addr,
+                    bytes: bits,
+                    len: 4,
+                    opcode: Opcode::Unknown,
+                    rd: None,
+                    rs1: None,
+                    rs2: None,
+                    imm: None,
+                })
+            })
+            .collect();
+        let cfg = crate::cfg::build(&code, 0x1000).unwrap();
+        let report = unsupported(&cfg, &CompileOptions::default());
+        assert_eq!(report[0], Unsupported { kind: "unknown (major opcode 0x57)".into(), addrs: vec![0x1004, 0x1008] });
+        assert_eq!(report.len(), 2);
+
+        let text = render(&cfg, &CompileOptions::default());
+        assert!(text.starts_with("# 3 unsupported instructions, 2 kinds\n"));
+        assert!(text.contains("  at 0x1004 0x1008\n"));
+        assert!(text.contains("   > 0x1008  02008057  Unknown\n"));
+        assert!(text.contains("     0x1000  00150513  ADDI\n"));
+    }
+}
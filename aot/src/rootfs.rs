@@ -0,0 +1,598 @@
+// rootfs.rs - Container root filesystems
+//
+// Container mode starts from a rootfs tar: `read_tar` lists its entries
+// (ustar, with GNU long names and pax path overrides) and `find_file`
+// pulls the entry binary out of it, following symlinks and hard links;
+// `write_tar` writes entries back out.
+//
+// A rootfs shipped with a build is packed first (`pack`): the tree's
+// metadata as an index, then every regular file as its own zstd frame.
+// Hosts decompress a file when the guest first opens it (`Image::contents`)
+// rather than holding the whole uncompressed tree in memory. `rv2wasm
+// --embed-rootfs` appends the packed image to the module as the custom
+// section `ROOTFS_SECTION`; `from_module` finds it again.
+//
+// Packed layout (little-endian): magic "FRSCYRFS", u32 version, u32 entry
+// count, then per entry: path, u8 kind, u32 mode, u32 uid, u32 gid, u64
+// mtime, u64 rdev, u64 size, link target, u64 offset and u64 length of its
+// frame in the blob. Strings are u32-length-prefixed. The blob of frames
+// follows the index; a hard link shares its target's frame.
+
+use anyhow::{bail, ensure, Context, Result};
+use ruzstd::decoding::FrameDecoder;
+use ruzstd::encoding::{compress_to_vec, CompressionLevel};
+use std::collections::HashMap;
+
+const MAGIC: &[u8; 8] = b"FRSCYRFS";
+
+/// Current packed format version
+pub const ROOTFS_VERSION: u32 = 1;
+
+/// Custom section holding an embedded packed rootfs
+pub const ROOTFS_SECTION: &str = "friscy.rootfs";
+
+/// Symlinks followed before giving up (Linux's MAXSYMLINKS)
+const MAX_SYMLINKS: usize = 40;
+
+/// What a tree entry is (tar typeflags '0' to '6')
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Kind {
+    File,
+    /// Another name for the file at `link`
+    Hardlink,
+    Symlink,
+    CharDev,
+    BlockDev,
+    Dir,
+    Fifo,
+}
+
+impl Kind {
+    fn from_u8(byte: u8) -> Result<Self> {
+        Ok(match byte {
+            0 => Kind::File,
+            1 => Kind::Hardlink,
+            2 => Kind::Symlink,
+            3 => Kind::CharDev,
+            4 => Kind::BlockDev,
+            5 => Kind::Dir,
+            6 => Kind::Fifo,
+            _ => bail!("Unknown rootfs entry kind {}", byte),
+        })
+    }
+
+    fn as_u8(self) -> u8 {
+        self as u8
+    }
+}
+
+/// One tar entry, borrowing its contents from the archive
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct TarEntry<'a> {
+    /// Relative to the root, without leading or trailing slashes
+    pub path: String,
+    pub kind: Kind,
+    /// Permission bits
+    pub mode: u32,
+    pub uid: u32,
+    pub gid: u32,
+    pub mtime: u64,
+    /// Device number of a device node, as Linux encodes it in st_rdev
+    pub rdev: u64,
+    /// Symlink or hard link target
+    pub link: String,
+    pub data: &'a [u8],
+}
+
+/// Strip `./` and surrounding slashes: "./bin/sh" and "/bin/sh" are "bin/sh"
+pub fn normalize(path: &str) -> String {
+    path.split('/').filter(|c| !c.is_empty() && *c != ".").collect::<Vec<_>>().join("/")
+}
+
+/// A numeric header field: octal text, or base-256 with the high bit set
+fn number(field: &[u8]) -> Result<u64> {
+    if field.first().is_some_and(|b| b & 0x80 != 0) {
+        return Ok(field[1..].iter().fold(0u64, |n, &b| n << 8 | b as u64));
+    }
+    let text = std::str::from_utf8(field).context("Bad tar number")?;
+    let text = text.trim_matches(|c: char| c == '\0' || c == ' ');
+    if text.is_empty() {
+        return Ok(0);
+    }
+    u64::from_str_radix(text, 8).with_context(|| format!("Bad tar number {:?}", text))
+}
+
+/// A NUL-terminated header string
+fn string(field: &[u8]) -> String {
+    let end = field.iter().position(|&b| b == 0).unwrap_or(field.len());
+    String::from_utf8_lossy(&field[..end]).into_owned()
+}
+
+/// The records of a pax extended header ("len key=value\n" each)
+fn pax_records(data: &[u8]) -> HashMap<String, String> {
+    let mut records = HashMap::new();
+    let mut rest = data;
+    while let Some(space) = rest.iter().position(|&b| b == b' ') {
+        let Some(len) = std::str::from_utf8(&rest[..space]).ok().and_then(|s| s.parse::<usize>().ok()) else {
+            break;
+        };
+        if len <= space || len > rest.len() {
+            break;
+        }
+        let record = String::from_utf8_lossy(&rest[space + 1..len - 1]);
+        if let Some((key, value)) = record.split_once('=') {
+            records.insert(key.to_string(), value.to_string());
+        }
+        rest = &rest[len..];
+    }
+    records
+}
+
+/// The entries of a tar archive, in archive order. Later entries for the
+/// same path replace earlier ones, as extracting would.
+pub fn read_tar(data: &[u8]) -> Result<Vec<TarEntry<'_>>> {
+    let mut entries = Vec::new();
+    let mut index: HashMap<String, usize> = HashMap::new();
+    let mut long_name = None;
+    let mut long_link = None;
+    let mut pax: HashMap<String, String> = HashMap::new();
+    let mut pos = 0;
+    while pos + 512 <= data.len() {
+        let header = &data[pos..pos + 512];
+        if header.iter().all(|&b| b == 0) {
+            break;
+        }
+        let sum: u64 = header.iter().enumerate().map(|(i, &b)| if (148..156).contains(&i) { 32 } else { b as u64 }).sum();
+        ensure!(number(&header[148..156])? == sum, "Bad tar header checksum at offset {}", pos);
+
+        let mut size = number(&header[124..136])?;
+        if let Some(value) = pax.get("size") {
+            size = value.parse().context("Bad pax size")?;
+        }
+        let start = pos + 512;
+        let end = start.checked_add(size as usize).filter(|&end| end <= data.len());
+        let contents = &data[start..end.with_context(|| format!("Truncated tar entry at offset {}", pos))?];
+        pos = start + (size as usize).div_ceil(512) * 512;
+
+        let kind = match header[156] {
+            b'0' | 0 | b'7' => Kind::File,
+            b'1' => Kind::Hardlink,
+            b'2' => Kind::Symlink,
+            b'3' => Kind::CharDev,
+            b'4' => Kind::BlockDev,
+            b'5' => Kind::Dir,
+            b'6' => Kind::Fifo,
+            b'L' => {
+                long_name = Some(string(contents));
+                continue;
+            }
+            b'K' => {
+                long_link = Some(string(contents));
+                continue;
+            }
+            b'x' => {
+                pax = pax_records(contents);
+                continue;
+            }
+            // Global pax headers, GNU volume labels and the like
+            _ => continue,
+        };
+
+        let mut name = string(&header[..100]);
+        if &header[257..262] == b"ustar" {
+            let prefix = string(&header[345..500]);
+            if !prefix.is_empty() {
+                name = format!("{}/{}", prefix, name);
+            }
+        }
+        let name = pax.remove("path").or(long_name.take()).unwrap_or(name);
+        let link = pax.remove("linkpath").or(long_link.take()).unwrap_or_else(|| string(&header[157..257]));
+        pax.clear();
+        let path = normalize(&name);
+        if path.is_empty() {
+            continue;
+        }
+        let (major, minor) = (number(&header[329..337])?, number(&header[337..345])?);
+        let entry = TarEntry {
+            path: path.clone(),
+            kind,
+            mode: number(&header[100..108])? as u32 & 0o7777,
+            uid: number(&header[108..116])? as u32,
+            gid: number(&header[116..124])? as u32,
+            mtime: number(&header[136..148])?,
+            rdev: (minor & 0xff) | (major & 0xfff) << 8 | (minor & !0xff) << 12,
+            link,
+            data: if kind == Kind::File { contents } else { &[] },
+        };
+        match index.get(&path) {
+            Some(&i) => entries[i] = entry,
+            None => {
+                index.insert(path, entries.len());
+                entries.push(entry);
+            }
+        }
+    }
+    Ok(entries)
+}
+
+/// The contents of the regular file at `path`, following symlinks (in any
+/// component) and hard links
+pub fn find_file<'a>(entries: &[TarEntry<'a>], path: &str) -> Result<&'a [u8]> {
+    let by_path: HashMap<&str, &TarEntry> = entries.iter().map(|e| (e.path.as_str(), e)).collect();
+    // Directories a tar lists no entry for, only entries inside
+    let implied: std::collections::HashSet<&str> =
+        entries.iter().flat_map(|e| e.path.match_indices('/').map(|(i, _)| &e.path[..i])).collect();
+    let mut pending: Vec<String> = normalize(path).split('/').rev().map(String::from).collect();
+    let mut resolved: Vec<String> = Vec::new();
+    let mut followed = 0;
+    while let Some(component) = pending.pop() {
+        if component == ".." {
+            resolved.pop();
+            continue;
+        }
+        resolved.push(component);
+        let current = resolved.join("/");
+        let Some(entry) = by_path.get(current.as_str()) else {
+            ensure!(!pending.is_empty() && implied.contains(current.as_str()), "{} is not in the rootfs", path);
+            continue;
+        };
+        let target = match entry.kind {
+            Kind::Symlink => &entry.link,
+            Kind::Hardlink if pending.is_empty() => &entry.link,
+            Kind::File if pending.is_empty() => return Ok(entry.data),
+            Kind::Dir if !pending.is_empty() => continue,
+            _ => bail!("{} is not a regular file in the rootfs", path),
+        };
+        followed += 1;
+        ensure!(followed <= MAX_SYMLINKS, "Too many levels of symbolic links resolving {}", path);
+        resolved.pop();
+        if target.starts_with('/') || entry.kind == Kind::Hardlink {
+            resolved.clear();
+        }
+        pending.extend(target.split('/').filter(|c| !c.is_empty() && *c != ".").rev().map(String::from));
+    }
+    bail!("{} is not a regular file in the rootfs", path)
+}
+
+/// One entry of a packed image
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Node {
+    pub path: String,
+    /// Never `Hardlink`: packing makes links files sharing a frame
+    pub kind: Kind,
+    pub mode: u32,
+    pub uid: u32,
+    pub gid: u32,
+    pub mtime: u64,
+    pub rdev: u64,
+    /// Uncompressed size of a regular file
+    pub size: u64,
+    pub link: String,
+    /// The file's zstd frame within the blob
+    offset: u64,
+    stored: u64,
+}
+
+/// Pack a rootfs: index plus one zstd frame per regular file
+pub fn pack(entries: &[TarEntry]) -> Vec<u8> {
+    let mut blob = Vec::new();
+    let mut frames: HashMap<&str, (u64, u64, u64)> = HashMap::new();
+    for entry in entries.iter().filter(|e| e.kind == Kind::File) {
+        let frame = if entry.data.is_empty() { Vec::new() } else { compress_to_vec(entry.data, CompressionLevel::Fastest) };
+        frames.insert(&entry.path, (entry.data.len() as u64, blob.len() as u64, frame.len() as u64));
+        blob.extend(frame);
+    }
+
+    let mut out = Vec::from(&MAGIC[..]);
+    let put_str = |out: &mut Vec<u8>, s: &str| {
+        out.extend((s.len() as u32).to_le_bytes());
+        out.extend(s.as_bytes());
+    };
+    let entries: Vec<_> = entries
+        .iter()
+        .filter_map(|e| {
+            // A hard link to something that is not a packed file is dropped
+            let frame = match e.kind {
+                Kind::File => Some(frames[e.path.as_str()]),
+                Kind::Hardlink => Some(*frames.get(normalize(&e.link).as_str())?),
+                _ => None,
+            };
+            Some((e, frame))
+        })
+        .collect();
+    out.extend(ROOTFS_VERSION.to_le_bytes());
+    out.extend((entries.len() as u32).to_le_bytes());
+    for (e, frame) in entries {
+        let (size, offset, stored) = frame.unwrap_or_default();
+        let kind = if e.kind == Kind::Hardlink { Kind::File } else { e.kind };
+        put_str(&mut out, &e.path);
+        out.push(kind.as_u8());
+        for v in [e.mode, e.uid, e.gid] {
+            out.extend(v.to_le_bytes());
+        }
+        for v in [e.mtime, e.rdev, size] {
+            out.extend(v.to_le_bytes());
+        }
+        put_str(&mut out, if kind == Kind::Symlink { &e.link } else { "" });
+        out.extend(offset.to_le_bytes());
+        out.extend(stored.to_le_bytes());
+    }
+    out.extend(blob);
+    out
+}
+
+/// A packed rootfs: its index, and the frames decompressed on demand
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Image {
+    pub nodes: Vec<Node>,
+    blob: Vec<u8>,
+}
+
+impl Image {
+    pub fn decode(data: &[u8]) -> Result<Self> {
+        let mut r = Reader { data, pos: 0 };
+        ensure!(r.take(8)? == MAGIC, "Not a packed rootfs");
+        let version = r.u32()?;
+        if version != ROOTFS_VERSION {
+            bail!("Unsupported rootfs version {} (expected {})", version, ROOTFS_VERSION);
+        }
+        let count = r.u32()?;
+        let mut nodes = Vec::with_capacity(count.min(1 << 16) as usize);
+        for _ in 0..count {
+            nodes.push(Node {
+                path: r.string()?,
+                kind: Kind::from_u8(r.take(1)?[0])?,
+                mode: r.u32()?,
+                uid: r.u32()?,
+                gid: r.u32()?,
+                mtime: r.u64()?,
+                rdev: r.u64()?,
+                size: r.u64()?,
+                link: r.string()?,
+                offset: r.u64()?,
+                stored: r.u64()?,
+            });
+        }
+        let blob = data[r.pos..].to_vec();
+        for node in &nodes {
+            ensure!(
+                node.offset.checked_add(node.stored).is_some_and(|end| end <= blob.len() as u64),
+                "Rootfs frame of {} is out of bounds",
+                node.path
+            );
+        }
+        Ok(Self { nodes, blob })
+    }
+
+    /// Decompress a regular file's contents
+    pub fn contents(&self, node: &Node) -> Result<Vec<u8>> {
+        let mut out = vec![0u8; node.size as usize];
+        if node.size == 0 {
+            return Ok(out);
+        }
+        let frame = &self.blob[node.offset as usize..(node.offset + node.stored) as usize];
+        let len = FrameDecoder::new()
+            .decode_all(frame, &mut out)
+            .map_err(|e| anyhow::anyhow!("Corrupt rootfs frame for {}: {}", node.path, e))?;
+        ensure!(len == out.len(), "Rootfs frame for {} has {} bytes, expected {}", node.path, len, out.len());
+        Ok(out)
+    }
+
+    /// Bytes the compressed frames take
+    pub fn stored_size(&self) -> usize {
+        self.blob.len()
+    }
+}
+
+/// `image` as a custom section, to append to a module
+pub fn section(image: &[u8]) -> Vec<u8> {
+    let mut body = Vec::new();
+    leb128(&mut body, ROOTFS_SECTION.len() as u64);
+    body.extend(ROOTFS_SECTION.as_bytes());
+    body.extend(image);
+    let mut out = vec![0u8];
+    leb128(&mut out, body.len() as u64);
+    out.extend(body);
+    out
+}
+
+/// The packed rootfs embedded in a module, if it has one
+pub fn from_module(wasm: &[u8]) -> Result<Option<&[u8]>> {
+    ensure!(wasm.len() >= 8 && &wasm[..4] == b"\0asm", "Not a Wasm module");
+    let mut r = Reader { data: wasm, pos: 8 };
+    while r.pos < wasm.len() {
+        let id = r.take(1)?[0];
+        let len = r.leb128()? as usize;
+        let body = r.take(len)?;
+        if id == 0 {
+            let mut s = Reader { data: body, pos: 0 };
+            let name_len = s.leb128()? as usize;
+            if s.take(name_len)? == ROOTFS_SECTION.as_bytes() {
+                return Ok(Some(&body[s.pos..]));
+            }
+        }
+    }
+    Ok(None)
+}
+
+fn leb128(out: &mut Vec<u8>, mut value: u64) {
+    loop {
+        let byte = (value & 0x7f) as u8;
+        value >>= 7;
+        if value == 0 {
+            out.push(byte);
+            return;
+        }
+        out.push(byte | 0x80);
+    }
+}
+
+struct Reader<'a> {
+    data: &'a [u8],
+    pos: usize,
+}
+
+impl<'a> Reader<'a> {
+    fn take(&mut self, n: usize) -> Result<&'a [u8]> {
+        let end = self.pos.checked_add(n).filter(|&end| end <= self.data.len()).context("Truncated rootfs")?;
+        let slice = &self.data[self.pos..end];
+        self.pos = end;
+        Ok(slice)
+    }
+
+    fn u32(&mut self) -> Result<u32> {
+        Ok(u32::from_le_bytes(self.take(4)?.try_into().unwrap()))
+    }
+
+    fn u64(&mut self) -> Result<u64> {
+        Ok(u64::from_le_bytes(self.take(8)?.try_into().unwrap()))
+    }
+
+    fn string(&mut self) -> Result<String> {
+        let len = self.u32()? as usize;
+        String::from_utf8(self.take(len)?.to_vec()).context("Bad rootfs path")
+    }
+
+    fn leb128(&mut self) -> Result<u64> {
+        let mut value = 0;
+        for shift in (0..64).step_by(7) {
+            let byte = self.take(1)?[0];
+            value |= ((byte & 0x7f) as u64) << shift;
+            if byte & 0x80 == 0 {
+                return Ok(value);
+            }
+        }
+        bail!("Bad LEB128 in module")
+    }
+}
+
+/// A ustar header block
+fn tar_header(name: &str, flag: u8, link: &str, size: u64, entry: &TarEntry) -> [u8; 512] {
+    let mut header = [0u8; 512];
+    let field = |header: &mut [u8; 512], at: usize, len: usize, value: u64| {
+        header[at..at + len].copy_from_slice(format!("{:0width$o}\0", value, width = len - 1).as_bytes());
+    };
+    header[..name.len().min(100)].copy_from_slice(&name.as_bytes()[..name.len().min(100)]);
+    field(&mut header, 100, 8, entry.mode as u64);
+    field(&mut header, 108, 8, entry.uid as u64);
+    field(&mut header, 116, 8, entry.gid as u64);
+    field(&mut header, 124, 12, size);
+    field(&mut header, 136, 12, entry.mtime);
+    header[156] = flag;
+    header[157..157 + link.len().min(100)].copy_from_slice(&link.as_bytes()[..link.len().min(100)]);
+    header[257..263].copy_from_slice(b"ustar\0");
+    header[263..265].copy_from_slice(b"00");
+    field(&mut header, 329, 8, (entry.rdev >> 8) & 0xfff);
+    field(&mut header, 337, 8, (entry.rdev & 0xff) | (entry.rdev >> 12) & !0xff);
+    header[148..156].fill(b' ');
+    let sum: u64 = header.iter().map(|&b| b as u64).sum();
+    header[148..156].copy_from_slice(format!("{:06o}\0 ", sum).as_bytes());
+    header
+}
+
+/// A tar archive of `entries` (ustar, with GNU long names for paths and
+/// link targets that do not fit the header), the inverse of `read_tar`
+pub fn write_tar(entries: &[TarEntry]) -> Vec<u8> {
+    let mut out = Vec::new();
+    let block = |out: &mut Vec<u8>, header: [u8; 512], data: &[u8]| {
+        out.extend(header);
+        out.extend(data);
+        out.resize(out.len().div_ceil(512) * 512, 0);
+    };
+    for entry in entries {
+        let flag = match entry.kind {
+            Kind::File => b'0',
+            Kind::Hardlink => b'1',
+            Kind::Symlink => b'2',
+            Kind::CharDev => b'3',
+            Kind::BlockDev => b'4',
+            Kind::Dir => b'5',
+            Kind::Fifo => b'6',
+        };
+        for (value, long_flag) in [(&entry.link, b'K'), (&entry.path, b'L')] {
+            if value.len() > 100 {
+                let name = format!("{}\0", value);
+                block(&mut out, tar_header("././@LongLink", long_flag, "", name.len() as u64, entry), name.as_bytes());
+            }
+        }
+        block(&mut out, tar_header(&entry.path, flag, &entry.link, entry.data.len() as u64, entry), entry.data);
+    }
+    out.resize(out.len() + 1024, 0);
+    out
+}
+
+/// Build a tar archive from (path, typeflag, link, contents); for tests of
+/// this module and the hosts that read rootfs images
+pub fn build_tar(entries: &[(&str, u8, &str, &[u8])]) -> Vec<u8> {
+    let entries: Vec<TarEntry> = entries
+        .iter()
+        .map(|&(path, flag, link, data)| TarEntry {
+            path: path.to_string(),
+            kind: Kind::from_u8(flag - b'0').expect("tar typeflag"),
+            mode: if flag == b'5' { 0o755 } else { 0o644 },
+            uid: 0,
+            gid: 0,
+            mtime: 0,
+            rdev: 0,
+            link: link.to_string(),
+            data,
+        })
+        .collect();
+    write_tar(&entries)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_tar_pack_roundtrip() {
+        let busybox = vec![0x7fu8; 5000];
+        let tar = build_tar(&[
+            ("./bin/", b'5', "", b""),
+            ("./bin/busybox", b'0', "", &busybox),
+            ("./bin/sh", b'2', "busybox", b""),
+            ("./usr/bin/env", b'2', "../../bin/busybox", b""),
+            ("./bin/ash", b'1', "bin/busybox", b""),
+            ("./etc/hostname", b'0', "", b"friscy\n"),
+            ("./etc/empty", b'0', "", b""),
+        ]);
+        let entries = read_tar(&tar).unwrap();
+        assert_eq!(entries.len(), 7);
+        assert_eq!((entries[0].path.as_str(), entries[0].kind, entries[0].mode), ("bin", Kind::Dir, 0o755));
+        assert_eq!((entries[2].kind, entries[2].link.as_str()), (Kind::Symlink, "busybox"));
+
+        // The entry binary, through relative, absolute and hard links
+        for path in ["/bin/busybox", "bin/sh", "/usr/bin/env", "/bin/ash", "/etc/../bin/sh"] {
+            assert_eq!(find_file(&entries, path).unwrap(), &busybox[..], "{}", path);
+        }
+        assert!(find_file(&entries, "/bin").is_err());
+
+        // Written back, long names included
+        let long = format!("usr/share/{}/file", "x".repeat(120));
+        let mut with_long = entries.clone();
+        with_long.push(TarEntry { path: long.clone(), link: String::new(), ..entries[1].clone() });
+        let tar = write_tar(&with_long);
+        let reread = read_tar(&tar).unwrap();
+        assert_eq!(reread.len(), 8);
+        assert_eq!(reread[..7], entries[..]);
+        assert_eq!((reread[7].path.as_str(), reread[7].data), (long.as_str(), &busybox[..]));
+        assert!(find_file(&entries, "/bin/ls").is_err());
+
+        let packed = pack(&entries);
+        assert!(packed.len() < busybox.len());
+        let image = Image::decode(&packed).unwrap();
+        let node = |path: &str| image.nodes.iter().find(|n| n.path == path).unwrap();
+        assert_eq!(image.contents(node("bin/busybox")).unwrap(), busybox);
+        assert_eq!(image.contents(node("bin/ash")).unwrap(), busybox);
+        assert_eq!(image.contents(node("etc/hostname")).unwrap(), b"friscy\n");
+        assert_eq!(image.contents(node("etc/empty")).unwrap(), b"");
+        assert_eq!((node("bin/sh").kind, node("bin/sh").link.as_str()), (Kind::Symlink, "busybox"));
+
+        // Embedded in a module and found again
+        let mut wasm = b"\0asm\x01\0\0\0".to_vec();
+        assert_eq!(from_module(&wasm).unwrap(), None);
+        wasm.extend(section(&packed));
+        assert_eq!(from_module(&wasm).unwrap(), Some(&packed[..]));
+    }
+}
@@ -0,0 +1,265 @@
+// snapshot.rs - Versioned binary format for guest machine snapshots
+//
+// A snapshot holds everything needed to resume a guest: the hart state
+// block (`$m`: integer and FP registers, fcsr, LR/SC reservation), the
+// PC, the memory manager's layout and mappings with their contents, the
+// brk heap and the open file descriptors. The native runtime and the browser
+// (through rv2wasm-jit) read and write the same format, so an image booted
+// once can be shipped and resumed instantly.
+//
+// Layout (little-endian): magic "FRSCYSNP", u32 version, then sections in
+// fixed order. All-zero pages are not stored; a region's data is a list of
+// (offset, bytes) runs over an implicitly zeroed range.
+
+use anyhow::{bail, ensure, Context, Result};
+
+const MAGIC: &[u8; 8] = b"FRSCYSNP";
+
+/// Current format version
+pub const SNAPSHOT_VERSION: u32 = 1;
+
+/// Page granularity used to drop zero runs
+const PAGE: usize = 4096;
+
+/// Address space layout (mirrors the runtime memory manager)
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub struct SnapshotLayout {
+    pub brk_base: u64,
+    pub brk_max: u64,
+    pub mmap_base: u64,
+    pub limit: u64,
+}
+
+/// A mapped region and its contents (`data.len()` is the region length)
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Region {
+    pub start: u64,
+    pub prot: u32,
+    pub flags: u32,
+    /// `(fd, offset)` for file-backed mappings
+    pub file: Option<(i32, u64)>,
+    pub data: Vec<u8>,
+}
+
+/// An open file descriptor
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct FdEntry {
+    pub fd: i32,
+    pub path: String,
+    pub flags: u32,
+    pub offset: u64,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Default)]
+pub struct Snapshot {
+    pub pc: u64,
+    /// Hart state block, MACHINE_STATE_SIZE bytes
+    pub state: Vec<u8>,
+    pub layout: SnapshotLayout,
+    /// Current program break
+    pub brk: u64,
+    /// Heap contents `[brk_base, brk)`
+    pub heap: Vec<u8>,
+    pub regions: Vec<Region>,
+    pub fds: Vec<FdEntry>,
+}
+
+impl Snapshot {
+    pub fn encode(&self) -> Vec<u8> {
+        let mut w = Writer(Vec::new());
+        w.0.extend_from_slice(MAGIC);
+        w.u32(SNAPSHOT_VERSION);
+
+        w.u64(self.pc);
+        w.bytes(&self.state);
+        for v in [self.layout.brk_base, self.layout.brk_max, self.layout.mmap_base, self.layout.limit] {
+            w.u64(v);
+        }
+        w.u64(self.brk);
+        w.sparse(&self.heap);
+
+        w.u32(self.regions.len() as u32);
+        for r in &self.regions {
+            w.u64(r.start);
+            w.u32(r.prot);
+            w.u32(r.flags);
+            let (fd, offset) = r.file.unwrap_or((-1, 0));
+            w.u32(fd as u32);
+            w.u64(offset);
+            w.sparse(&r.data);
+        }
+
+        w.u32(self.fds.len() as u32);
+        for f in &self.fds {
+            w.u32(f.fd as u32);
+            w.bytes(f.path.as_bytes());
+            w.u32(f.flags);
+            w.u64(f.offset);
+        }
+        w.0
+    }
+
+    pub fn decode(data: &[u8]) -> Result<Self> {
+        let mut r = Reader { data, pos: 0 };
+        ensure!(r.take(8)? == MAGIC, "Not a friscy snapshot");
+        let version = r.u32()?;
+        if version != SNAPSHOT_VERSION {
+            bail!("Unsupported snapshot version {} (expected {})", version, SNAPSHOT_VERSION);
+        }
+
+        let pc = r.u64()?;
+        let state = r.bytes()?.to_vec();
+        let layout = SnapshotLayout {
+            brk_base: r.u64()?,
+            brk_max: r.u64()?,
+            mmap_base: r.u64()?,
+            limit: r.u64()?,
+        };
+        let brk = r.u64()?;
+        let heap = r.sparse()?;
+
+        let mut regions = Vec::new();
+        for _ in 0..r.u32()? {
+            let start = r.u64()?;
+            let prot = r.u32()?;
+            let flags = r.u32()?;
+            let fd = r.u32()? as i32;
+            let offset = r.u64()?;
+            let data = r.sparse()?;
+            regions.push(Region {
+                start,
+                prot,
+                flags,
+                file: (fd >= 0).then_some((fd, offset)),
+                data,
+            });
+        }
+
+        let mut fds = Vec::new();
+        for _ in 0..r.u32()? {
+            fds.push(FdEntry {
+                fd: r.u32()? as i32,
+                path: String::from_utf8(r.bytes()?.to_vec()).context("Invalid fd path")?,
+                flags: r.u32()?,
+                offset: r.u64()?,
+            });
+        }
+        ensure!(r.pos == data.len(), "Trailing data after snapshot");
+
+        Ok(Snapshot { pc, state, layout, brk, heap, regions, fds })
+    }
+}
+
+struct Writer(Vec<u8>);
+
+impl Writer {
+    fn u32(&mut self, v: u32) {
+        self.0.extend_from_slice(&v.to_le_bytes());
+    }
+
+    fn u64(&mut self, v: u64) {
+        self.0.extend_from_slice(&v.to_le_bytes());
+    }
+
+    fn bytes(&mut self, b: &[u8]) {
+        self.u64(b.len() as u64);
+        self.0.extend_from_slice(b);
+    }
+
+    /// Total length, then runs of non-zero pages
+    fn sparse(&mut self, data: &[u8]) {
+        let mut runs = Vec::new();
+        let mut run_start = None;
+        for (i, page) in data.chunks(PAGE).enumerate() {
+            let zero = page.iter().all(|&b| b == 0);
+            match (zero, run_start) {
+                (false, None) => run_start = Some(i * PAGE),
+                (true, Some(s)) => {
+                    runs.push(s..i * PAGE);
+                    run_start = None;
+                }
+                _ => {}
+            }
+        }
+        if let Some(s) = run_start {
+            runs.push(s..data.len());
+        }
+
+        self.u64(data.len() as u64);
+        self.u32(runs.len() as u32);
+        for run in runs {
+            self.u64(run.start as u64);
+            self.bytes(&data[run]);
+        }
+    }
+}
+
+struct Reader<'a> {
+    data: &'a [u8],
+    pos: usize,
+}
+
+impl<'a> Reader<'a> {
+    fn take(&mut self, n: usize) -> Result<&'a [u8]> {
+        let end = self.pos.checked_add(n).filter(|&e| e <= self.data.len());
+        let end = end.context("Truncated snapshot")?;
+        let out = &self.data[self.pos..end];
+        self.pos = end;
+        Ok(out)
+    }
+
+    fn u32(&mut self) -> Result<u32> {
+        Ok(u32::from_le_bytes(self.take(4)?.try_into().unwrap()))
+    }
+
+    fn u64(&mut self) -> Result<u64> {
+        Ok(u64::from_le_bytes(self.take(8)?.try_into().unwrap()))
+    }
+
+    fn bytes(&mut self) -> Result<&'a [u8]> {
+        let len = self.u64()?;
+        self.take(usize::try_from(len).context("Snapshot field too large")?)
+    }
+
+    fn sparse(&mut self) -> Result<Vec<u8>> {
+        let len = usize::try_from(self.u64()?).context("Snapshot region too large")?;
+        // Guest memory is a 32-bit linear memory
+        ensure!(len as u64 <= 1 << 32, "Corrupt snapshot region length");
+        let mut out = vec![0u8; len];
+        for _ in 0..self.u32()? {
+            let offset = self.u64()? as usize;
+            let bytes = self.bytes()?;
+            let dst = offset
+                .checked_add(bytes.len())
+                .and_then(|end| out.get_mut(offset..end))
+                .context("Snapshot run outside its region")?;
+            dst.copy_from_slice(bytes);
+        }
+        Ok(out)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_roundtrip_skips_zero_pages() {
+        let mut stack = vec![0u8; 64 * PAGE];
+        stack[63 * PAGE + 10] = 0xaa;
+        let snap = Snapshot {
+            pc: 0x10078,
+            state: (0..=255).collect(),
+            layout: SnapshotLayout { brk_base: 0x20000, brk_max: 0x100000, mmap_base: 0x200000, limit: 1 << 32 },
+            brk: 0x21000,
+            heap: vec![7; 0x1000],
+            regions: vec![Region { start: 0x7ffc0000, prot: 3, flags: 0x12, file: None, data: stack }],
+            fds: vec![FdEntry { fd: 3, path: "/etc/hosts".into(), flags: 0, offset: 12 }],
+        };
+
+        let bytes = snap.encode();
+        assert!(bytes.len() < 3 * PAGE);
+        assert_eq!(Snapshot::decode(&bytes).unwrap(), snap);
+        assert!(Snapshot::decode(&bytes[..bytes.len() - 1]).is_err());
+    }
+}
@@ -0,0 +1,58 @@
+// split.rs - Split output: a dispatcher plus lazily loaded code modules
+//
+// Browsers compile a module as a whole, so one huge module stalls startup.
+// In split mode (`CompileOptions::split`) the block functions are packed,
+// whole guest functions at a time, into code modules of about `split`
+// blocks each, and the main module keeps only the dispatch loop.
+//
+// Linking convention (also described in the ABI descriptor):
+//
+// - The dispatcher defines and exports the funcref `table` that dispatch
+//   calls through, and imports `env.load_block(table_idx)`.
+// - Code module `g` holds the blocks with table indices
+//   `[groups[g], groups[g + 1])`. It imports `env.memory`, `env.table` and
+//   the `link` function imports, and installs its functions in the table
+//   with an active element segment.
+// - Dispatching to a null table entry first calls `load_block`; the host
+//   instantiates the code module covering that index and returns.
+
+use crate::cfg::ControlFlowGraph;
+use std::collections::HashMap;
+
+/// Regroup `order` (block addresses in function order) so each guest
+/// function's blocks are contiguous, and pack the functions into code
+/// modules of at least `per_module` blocks. Returns the new order and the
+/// first block index of each module. Blocks no function reaches form their
+/// own units; units keep the relative order of their first block.
+pub fn group(cfg: &ControlFlowGraph, order: &[u64], per_module: usize) -> (Vec<u64>, Vec<usize>) {
+    // A block shared by several functions goes with the first one
+    let mut owner: HashMap<u64, usize> = HashMap::new();
+    for (f, func) in cfg.functions.iter().enumerate() {
+        for &addr in &func.blocks {
+            owner.entry(addr).or_insert(f);
+        }
+    }
+
+    // Pushing in `order` keeps each unit's blocks (and the units' first
+    // blocks) in order, so units only need a stable sort by first block
+    let position: HashMap<u64, usize> = order.iter().enumerate().map(|(i, &a)| (a, i)).collect();
+    let mut units: Vec<Vec<u64>> = vec![Vec::new(); cfg.functions.len()];
+    for &addr in order {
+        match owner.get(&addr) {
+            Some(&f) => units[f].push(addr),
+            None => units.push(vec![addr]),
+        }
+    }
+    units.retain(|unit| !unit.is_empty());
+    units.sort_by_key(|unit| position[&unit[0]]);
+
+    let mut addrs = Vec::with_capacity(order.len());
+    let mut groups: Vec<usize> = Vec::new();
+    for unit in units {
+        if groups.last().is_none_or(|&first| addrs.len() - first >= per_module.max(1)) {
+            groups.push(addrs.len());
+        }
+        addrs.extend(unit);
+    }
+    (addrs, groups)
+}
@@ -0,0 +1,175 @@
+// startup.rs - crt0 / __libc_start_main recognition
+//
+// Both glibc's `_start` and musl's `_start` → `_start_c` end by handing
+// `main` to `__libc_start_main`:
+//
+//   lla  a0, main             (or a GOT load in a PIE)
+//   ...                       (a3/a4: the init/fini functions, or 0)
+//   call __libc_start_main    (or a tail call)
+//
+// `recognize` follows the straight-line code from the ELF entry, tracking
+// the registers that hold constants, and stops at the first call or jump
+// made with a code address in a0. That finds main even in a stripped
+// binary. `mark` then names main, __libc_start_main and the init/fini
+// functions in the CFG, adding them as functions where no direct call
+// found them, and gives the startup call those three as successors:
+// __libc_start_main reaches them only through pointers, so without the
+// edges a reachability walk (the hot/cold split) stops at libc.
+
+use crate::cfg::ControlFlowGraph;
+use crate::disasm::Opcode;
+use crate::elf::ElfFile;
+use std::collections::BTreeMap;
+
+/// Instructions followed from the entry before giving up
+const MAX_INSTRUCTIONS: usize = 128;
+
+/// What the startup code hands to `__libc_start_main`
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Startup {
+    /// Block ending in the call to `__libc_start_main`
+    pub call_block: u64,
+    /// `__libc_start_main` (or its PLT stub)
+    pub start_main: u64,
+    pub main: u64,
+    /// Init and fini functions, when passed (glibc 2.34+ passes 0)
+    pub init: Option<u64>,
+    pub fini: Option<u64>,
+}
+
+impl Startup {
+    /// Recognized functions and their conventional names
+    pub fn functions(&self) -> Vec<(&'static str, u64)> {
+        let mut functions = vec![("__libc_start_main", self.start_main), ("main", self.main)];
+        functions.extend(self.init.map(|addr| ("_init", addr)));
+        functions.extend(self.fini.map(|addr| ("_fini", addr)));
+        functions
+    }
+}
+
+/// Recognize the startup code of `file`'s CFG and mark what it finds
+pub fn apply(cfg: &mut ControlFlowGraph, file: &ElfFile) -> Option<Startup> {
+    let startup = recognize(cfg, &crate::plt::got_targets(file))?;
+    mark(cfg, &startup);
+    Some(startup)
+}
+
+/// Follow the code from the entry to the `__libc_start_main` call. `got`
+/// holds the GOT slots with known contents (`plt::got_targets`).
+pub fn recognize(cfg: &ControlFlowGraph, got: &BTreeMap<u64, u64>) -> Option<Startup> {
+    let is_code = |value: Option<u64>| value.filter(|addr| cfg.blocks.contains_key(addr));
+    let mut regs: [Option<u64>; 32] = [None; 32];
+    let mut addr = cfg.entry;
+    let mut budget = MAX_INSTRUCTIONS;
+    loop {
+        let block = cfg.blocks.get(&addr)?;
+        for inst in block.instructions {
+            budget = budget.checked_sub(1)?;
+            regs[0] = Some(0);
+            let reg = |r: Option<u8>| r.and_then(|r| regs[r as usize]);
+            let imm = inst.imm.unwrap_or(0) as u64;
+            let value = match inst.opcode {
+                Opcode::AUIPC => Some(inst.addr.wrapping_add(imm)),
+                Opcode::LUI | Opcode::C_LUI => Some(imm),
+                Opcode::ADDI | Opcode::C_ADDI | Opcode::C_LI => reg(inst.rs1).map(|v| v.wrapping_add(imm)),
+                Opcode::ADD | Opcode::C_ADD | Opcode::C_MV => {
+                    reg(inst.rs1).zip(reg(inst.rs2)).map(|(a, b)| a.wrapping_add(b))
+                }
+                Opcode::LD | Opcode::C_LD => reg(inst.rs1).and_then(|base| got.get(&base.wrapping_add(imm)).copied()),
+                _ => None,
+            };
+
+            let target = inst.addr.wrapping_add(imm);
+            match inst.opcode {
+                Opcode::JAL | Opcode::C_J | Opcode::C_JAL => {
+                    if let Some(main) = is_code(regs[10]) {
+                        return Some(Startup {
+                            call_block: block.start_addr,
+                            start_main: target,
+                            main,
+                            init: is_code(regs[13]),
+                            fini: is_code(regs[14]),
+                        });
+                    }
+                    if inst.rd.unwrap_or(0) != 0 {
+                        // Not it: a call clobbers the argument registers
+                        regs[1..].iter_mut().for_each(|r| *r = None);
+                    }
+                }
+                _ if inst.opcode.is_terminator() => {}
+                _ => {
+                    if let Some(rd) = inst.rd {
+                        regs[rd as usize] = value;
+                    }
+                }
+            }
+        }
+
+        // Fall through, or follow a tail call (musl's `_start` -> `_start_c`)
+        let term = block.terminator()?;
+        addr = match term.opcode {
+            Opcode::JAL | Opcode::C_J if term.rd.unwrap_or(0) == 0 => term.addr.wrapping_add(term.imm? as u64),
+            Opcode::JAL | Opcode::C_JAL => block.end_addr,
+            _ if !term.opcode.is_terminator() => block.end_addr,
+            _ => return None,
+        };
+    }
+}
+
+/// Name the recognized functions in `cfg` and link them to the startup call
+pub fn mark(cfg: &mut ControlFlowGraph, startup: &Startup) {
+    for (name, addr) in startup.functions() {
+        cfg.add_function(addr, name);
+    }
+    let Some(block) = cfg.blocks.get_mut(&startup.call_block) else {
+        return;
+    };
+    for addr in [Some(startup.main), startup.init, startup.fini].into_iter().flatten() {
+        if !block.successors.contains(&addr) {
+            block.successors.push(addr);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_util::inst;
+
+    #[test]
+    fn test_recognize_glibc_start() {
+        // _start: mv a5, a0; ld a1, 0(sp); addi a2, sp, 8; andi sp, sp, -16; lla a0, main;
+        // li a3, 0; li a4, 0; mv a6, sp; call __libc_start_main; ebreak
+        // -- main (0x1040): li a0, 0; ret -- __libc_start_main (0x1050): jalr a0; ecall
+        let code = [
+            inst(0x1000, Opcode::ADDI, 15, 10, 0, 0),
+            inst(0x1004, Opcode::LD, 11, 2, 0, 0),
+            inst(0x1008, Opcode::ADDI, 12, 2, 0, 8),
+            inst(0x100c, Opcode::ANDI, 2, 2, 0, -16),
+            inst(0x1010, Opcode::AUIPC, 10, 0, 0, 0),
+            inst(0x1014, Opcode::ADDI, 10, 10, 0, 0x30),
+            inst(0x1018, Opcode::ADDI, 13, 0, 0, 0),
+            inst(0x101c, Opcode::ADDI, 14, 0, 0, 0),
+            inst(0x1020, Opcode::ADDI, 16, 2, 0, 0),
+            inst(0x1024, Opcode::JAL, 1, 0, 0, 0x2c),
+            inst(0x1028, Opcode::EBREAK, 0, 0, 0, 0),
+            inst(0x1040, Opcode::ADDI, 10, 0, 0, 0),
+            inst(0x1044, Opcode::JALR, 0, 1, 0, 0),
+            inst(0x1050, Opcode::JALR, 1, 10, 0, 0),
+            inst(0x1054, Opcode::ECALL, 0, 0, 0, 0),
+        ];
+        let mut cfg = crate::cfg::build(&code, 0x1000).unwrap();
+        let startup = recognize(&cfg, &BTreeMap::new()).unwrap();
+        assert_eq!(
+            startup,
+            Startup { call_block: 0x1000, start_main: 0x1050, main: 0x1040, init: None, fini: None }
+        );
+
+        // main is reachable from the entry once marked
+        assert!(!crate::hotness::hot_set(&cfg, None, &[0x1000]).contains(&0x1040));
+        mark(&mut cfg, &startup);
+        assert!(crate::hotness::hot_set(&cfg, None, &[0x1000]).contains(&0x1040));
+        let main = cfg.functions.iter().find(|f| f.name == "main").unwrap();
+        assert_eq!((main.entry, &main.blocks[..]), (0x1040, &[0x1040][..]));
+    }
+}
@@ -0,0 +1,130 @@
+// symbolize.rs - Guest PC symbolization
+//
+// Maps a guest PC to `function+offset` for trap messages, profiles and
+// tools. The function comes from the ELF symbol table (already biased to
+// where the object is loaded); the block map, when one is known (the
+// runtime's compiled blocks, or a CFG's), names the block containing the
+// PC, and stands in for the function in stripped code:
+//
+//   0x10234 <main+0x14>
+//   0x7f0010 <block 0x7f0000+0x10>
+
+use crate::cfg::ControlFlowGraph;
+use crate::elf::Symbol;
+use std::collections::BTreeMap;
+use std::fmt;
+
+/// A PC as an offset into a named function
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Location<'a> {
+    pub symbol: &'a str,
+    pub offset: u64,
+}
+
+impl fmt::Display for Location<'_> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self.offset {
+            0 => write!(f, "{}", self.symbol),
+            offset => write!(f, "{}+0x{:x}", self.symbol, offset),
+        }
+    }
+}
+
+/// Function symbols plus block extents, searchable by PC
+#[derive(Debug, Clone, Default)]
+pub struct Symbolizer {
+    /// Sorted by address, one symbol per address
+    symbols: Vec<Symbol>,
+    /// Block start to end (exclusive)
+    blocks: BTreeMap<u64, u64>,
+}
+
+impl Symbolizer {
+    /// `symbols` must already carry the load bias of their object
+    pub fn new(symbols: Vec<Symbol>) -> Self {
+        let mut s = Self::default();
+        s.add_symbols(symbols, 0);
+        s
+    }
+
+    /// Add the symbols of an object loaded at `bias`
+    pub fn add_symbols(&mut self, symbols: impl IntoIterator<Item = Symbol>, bias: u64) {
+        self.symbols.extend(symbols.into_iter().map(|s| Symbol { addr: s.addr.wrapping_add(bias), ..s }));
+        self.symbols.sort_by(|a, b| (a.addr, &a.name).cmp(&(b.addr, &b.name)));
+        self.symbols.dedup_by(|a, b| a.addr == b.addr);
+    }
+
+    /// Record the block `[start, end)`
+    pub fn add_block(&mut self, start: u64, end: u64) {
+        self.blocks.insert(start, end);
+    }
+
+    /// Record the blocks of `cfg`
+    pub fn add_cfg(&mut self, cfg: &ControlFlowGraph) {
+        self.blocks.extend(cfg.blocks.iter().map(|(&start, b)| (start, b.end_addr)));
+    }
+
+    pub fn clear_blocks(&mut self) {
+        self.blocks.clear();
+    }
+
+    /// The function containing `pc`. Zero-sized symbols (hand-written
+    /// assembly) extend to the next one.
+    pub fn lookup(&self, pc: u64) -> Option<Location<'_>> {
+        let idx = self.symbols.partition_point(|s| s.addr <= pc);
+        let sym = &self.symbols[idx.checked_sub(1)?];
+        let offset = pc - sym.addr;
+        (sym.size == 0 || offset < sym.size).then_some(Location { symbol: &sym.name, offset })
+    }
+
+    /// Start of the known block containing `pc`
+    pub fn block(&self, pc: u64) -> Option<u64> {
+        let (&start, &end) = self.blocks.range(..=pc).next_back()?;
+        (pc < end).then_some(start)
+    }
+
+    /// The function name for `pc`, falling back to its block or the bare
+    /// address; what profiles aggregate by
+    pub fn name(&self, pc: u64) -> String {
+        match (self.lookup(pc), self.block(pc)) {
+            (Some(loc), _) => loc.symbol.to_string(),
+            (None, Some(block)) => format!("block_0x{:x}", block),
+            (None, None) => format!("0x{:x}", pc),
+        }
+    }
+
+    /// `pc` for messages: `0x<pc> <main+0x14>`
+    pub fn describe(&self, pc: u64) -> String {
+        match (self.lookup(pc), self.block(pc)) {
+            (Some(loc), _) => format!("0x{:x} <{}>", pc, loc),
+            (None, Some(block)) => format!("0x{:x} <block 0x{:x}+0x{:x}>", pc, block, pc - block),
+            (None, None) => format!("0x{:x}", pc),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn sym(name: &str, addr: u64, size: u64) -> Symbol {
+        Symbol { name: name.into(), addr, size }
+    }
+
+    #[test]
+    fn test_symbolize() {
+        let mut s = Symbolizer::new(vec![sym("loop", 0x100, 0x40), sym("main", 0x0, 0x100)]);
+        s.add_symbols([sym("_start", 0x0, 0)], 0x4000);
+        s.add_block(0x200, 0x210);
+
+        assert_eq!(s.lookup(0x114), Some(Location { symbol: "loop", offset: 0x14 }));
+        assert_eq!(s.describe(0x0), "0x0 <main>");
+        assert_eq!(s.describe(0x13c), "0x13c <loop+0x3c>");
+        // Past the end of loop, inside a known block
+        assert_eq!(s.describe(0x208), "0x208 <block 0x200+0x8>");
+        assert_eq!(s.name(0x208), "block_0x200");
+        assert_eq!(s.describe(0x300), "0x300");
+        // A zero-sized symbol runs on
+        assert_eq!(s.describe(0x4100), "0x4100 <_start+0x100>");
+    }
+}
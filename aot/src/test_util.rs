@@ -0,0 +1,30 @@
+// test_util.rs - Fixtures shared by the unit tests
+
+use crate::disasm::{Instruction, Opcode};
+use crate::elf::ElfInfo;
+
+/// A 4-byte instruction at `addr` with every operand given
+pub(crate) fn inst(addr: u64, opcode: Opcode, rd: u8, rs1: u8, rs2: u8, imm: i64) -> Instruction {
+    Instruction {
+        addr,
+        bytes: 0,
+        len: 4,
+        opcode,
+        rd: Some(rd),
+        rs1: Some(rs1),
+        rs2: Some(rs2),
+        imm: Some(imm),
+    }
+}
+
+/// A static executable entered at 0x1000 with no segments
+pub(crate) fn elf_info() -> ElfInfo {
+    ElfInfo {
+        entry: 0x1000,
+        is_pie: false,
+        interpreter: None,
+        segments: Vec::new(),
+        phdr_vaddr: 0,
+        phdr_count: 0,
+    }
+}
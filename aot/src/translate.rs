@@ -4,9 +4,59 @@
 // described in CRAZY_PERF_IDEAS.md.
 
 use crate::cfg::{BasicBlock, ControlFlowGraph};
+use crate::data::DataChunk;
 use crate::disasm::{Instruction, Opcode};
 use crate::elf::ElfInfo;
-use anyhow::Result;
+use crate::freq::Frequencies;
+use crate::options::{CompileOptions, FmaMode, UnknownPc};
+use anyhow::{Context, Result};
+use std::collections::BTreeSet;
+
+/// Machine-state offset of the f0-f31 single-precision view (4 bytes each)
+pub const F32_REGS_OFFSET: u32 = 256;
+/// Machine-state offset of the f0-f31 double-precision view (8 bytes each)
+pub const F64_REGS_OFFSET: u32 = 384;
+/// Machine-state offset of the LR/SC reservation address (-1 = no
+/// reservation; hosts start harts with -1)
+pub const RESERVATION_ADDR_OFFSET: u32 = 640;
+/// Machine-state offset of the value observed by the reserving LR
+pub const RESERVATION_VALUE_OFFSET: u32 = 648;
+/// Cache block size reported for Zicbom/Zicboz (cbo.zero clears this many bytes)
+pub const CACHE_BLOCK_SIZE: u64 = 64;
+/// Threads mode spin-wait timeouts (ns): PAUSE, WRS.STO, and WRS.NTO, which
+/// is bounded too because stores by other harts do not notify waiters
+pub const PAUSE_WAIT_NS: i64 = 1_000;
+pub const WRS_STO_WAIT_NS: i64 = 10_000;
+pub const WRS_NTO_WAIT_NS: i64 = 100_000;
+/// Machine-state offset of the faulting PC of a trap return
+/// (`abi::ILLEGAL_TRAP`, `abi::MISALIGNED_TRAP`, `abi::PAGE_FAULT`)
+pub const TRAP_PC_OFFSET: u32 = 656;
+/// Machine-state offset of the faulting address of an `abi::MISALIGNED_TRAP`
+/// or `abi::PAGE_FAULT`
+pub const TRAP_VALUE_OFFSET: u32 = 664;
+/// Machine-state offset of the hart's mhartid, set by the host (SMP mode,
+/// `CompileOptions::harts`)
+pub const HART_ID_OFFSET: u32 = 672;
+/// Machine-state offset of the `$pc` the dispatch loop last handed to
+/// `env.syscall`, flags and all, or of a PC the host stored. Hosts go through
+/// the `get_pc` export, which decodes it, and `set_pc`.
+pub const PC_OFFSET: u32 = 680;
+/// Machine-state offset of the instructions the hart may still execute,
+/// set by the host (metered builds, `CompileOptions::meter`)
+pub const BUDGET_OFFSET: u32 = 688;
+/// Machine-state offset of fcsr (frm in bits 5-7, fflags in bits 0-4).
+/// Wasm arithmetic always rounds to nearest and raises no flags, so it only
+/// holds what the guest last wrote.
+pub const FCSR_OFFSET: u32 = 696;
+/// Size of the per-hart machine state block addressed by `$m`:
+/// x0-x31 (0..256), f32 view (256..384), f64 view (384..640), reservation,
+/// trap PC and value, hart id, PC, instruction budget, fcsr
+pub const MACHINE_STATE_SIZE: u32 = 704;
+/// CSR number of mhartid
+const MHARTID: i64 = 0xF14;
+/// Link address given to guest functions called through an export wrapper.
+/// Returning to it yields PC -2, which the dispatch loop treats like halt.
+pub const RETURN_ADDR: u64 = -2i64 as u64;
 
 /// A generated Wasm module (intermediate representation)
 #[derive(Debug)]
@@ -19,6 +69,46 @@ pub struct WasmModule {
     pub entry: u64,
     /// Block address to function index mapping
     pub block_to_func: std::collections::HashMap<u64, usize>,
+    /// Threads mode (shared memory, atomics, futex/thread helper exports)
+    pub threads: bool,
+    /// Guest functions exported as callable wrappers (export name, entry)
+    pub exports: Vec<(String, u64)>,
+    /// Split mode: first block index of each code module (empty: one module)
+    pub groups: Vec<usize>,
+    /// Embedded initial memory, as passive data segments
+    pub data: std::sync::Arc<[DataChunk]>,
+    /// Size mode (see `CompileOptions::size`)
+    pub size: bool,
+    /// Blocks exported as `block_<addr>` (None: all of them)
+    pub block_exports: Option<BTreeSet<u64>>,
+    /// Linear-memory address of guest address 0 (`CompileOptions::memory_offset`)
+    pub memory_offset: u32,
+    /// Root page table of the software MMU (`CompileOptions::soft_mmu`)
+    pub soft_mmu: Option<u32>,
+    /// Guest address of the time page (`CompileOptions::time_page`)
+    pub time_page: Option<u32>,
+    /// Unknown PCs go to `env.compile_missing` (`CompileOptions::lazy_compile`)
+    pub lazy_compile: bool,
+    /// What happens at a PC without code (`CompileOptions::unknown_pc`)
+    pub unknown_pc: UnknownPc,
+    /// Syscalls go to `env.syscall_async` (`CompileOptions::jspi`)
+    pub jspi: bool,
+    /// Traps throw the exported `guest_trap` tag (`CompileOptions::trap_exceptions`)
+    pub trap_exceptions: bool,
+    /// Harts the guest runs on (`CompileOptions::harts`)
+    pub harts: u32,
+    /// Accesses are checked against this range (`CompileOptions::watch`)
+    pub watch: Option<std::ops::Range<u32>>,
+    /// Blocks call `env.breakpoint` (`CompileOptions::breakpoints`)
+    pub breakpoints: bool,
+    /// Imports standing in for interposed functions (`CompileOptions::interpose`)
+    pub interpose: Vec<String>,
+    /// Blocks charge the instruction budget (`CompileOptions::meter`)
+    pub meter: bool,
+    /// Cap on the memory import's maximum (`CompileOptions::max_pages`)
+    pub max_pages: Option<u32>,
+    /// Guest ranges of the loaded segments, bss included
+    pub image: Vec<std::ops::Range<u64>>,
 }
 
 /// A generated Wasm function
@@ -32,10 +122,27 @@ pub struct WasmFunction {
     pub body: Vec<WasmInst>,
     /// Number of local variables (beyond parameters)
     pub num_locals: u32,
+    /// Label lists referenced by `WasmInst::BrTable { table }`
+    pub br_tables: Vec<Vec<u32>>,
+}
+
+/// What a `WasmInst::Comment` annotates
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Note {
+    /// Start of the block at `addr`
+    Block,
+    /// The guest instruction at `addr`
+    Inst(Opcode),
+    /// An instruction the translator cannot lower (the block traps here)
+    Unsupported(Opcode),
 }
 
 /// Wasm instruction (simplified IR)
-#[derive(Debug, Clone)]
+///
+/// Kept `Copy` and 16 bytes: anything variable-sized lives in side tables
+/// on the `WasmFunction` and is referenced by index, so bodies are flat
+/// arrays that passes can rewrite in place.
+#[derive(Debug, Clone, Copy, PartialEq)]
 pub enum WasmInst {
     // Control flow
     Block { label: u32 },
@@ -43,10 +150,13 @@ pub enum WasmInst {
     End,
     Br { label: u32 },
     BrIf { label: u32 },
-    BrTable { labels: Vec<u32>, default: u32 },
+    BrTable { table: u32, default: u32 },
     Return,
     Call { func_idx: u32 },
     CallIndirect { type_idx: u32 },
+    /// Call of an interposed function's host import, (a0-a7 i64) -> i64
+    /// (`CompileOptions::interpose`)
+    CallHost { func_idx: u32 },
 
     // Locals
     LocalGet { idx: u32 },
@@ -77,6 +187,8 @@ pub enum WasmInst {
     I64Store8 { offset: u32 },
     I64Store16 { offset: u32 },
     I64Store32 { offset: u32 },
+    /// memory.fill (dest i32, byte i32, len i32)
+    MemoryFill,
 
     // Arithmetic (i64)
     I64Add,
@@ -123,6 +235,7 @@ pub enum WasmInst {
     I32Shl,
     I32ShrS,
     I32ShrU,
+    I32Rotr,
     I32Eqz,
     I32Eq,
     I32Ne,
@@ -189,6 +302,9 @@ pub enum WasmInst {
     F64Min,
     F64Max,
     F64Copysign,
+    /// a * b + c, fused where the engine and host support it
+    /// (`f64x2.relaxed_madd` on lane 0; see `fma.rs`)
+    F64RelaxedMadd,
 
     // FP conversions
     F32ConvertI32S,
@@ -213,6 +329,33 @@ pub enum WasmInst {
     F64ReinterpretI64,
     I32ReinterpretF32,
     I64ReinterpretF64,
+    I64Extend8S,
+    I64Extend16S,
+
+    // Atomics (threads proposal)
+    AtomicFence,
+    I32AtomicLoad { offset: u32 },
+    I64AtomicLoad { offset: u32 },
+    I64AtomicLoad8U { offset: u32 },
+    I64AtomicLoad16U { offset: u32 },
+    I32AtomicStore { offset: u32 },
+    I64AtomicStore { offset: u32 },
+    I32AtomicRmwAdd { offset: u32 },
+    I64AtomicRmwAdd { offset: u32 },
+    I32AtomicRmwAnd { offset: u32 },
+    I64AtomicRmwAnd { offset: u32 },
+    I32AtomicRmwOr { offset: u32 },
+    I64AtomicRmwOr { offset: u32 },
+    I32AtomicRmwXor { offset: u32 },
+    I64AtomicRmwXor { offset: u32 },
+    I32AtomicRmwXchg { offset: u32 },
+    I64AtomicRmwXchg { offset: u32 },
+    I32AtomicRmwCmpxchg { offset: u32 },
+    I64AtomicRmwCmpxchg { offset: u32 },
+    I64AtomicRmw8CmpxchgU { offset: u32 },
+    I64AtomicRmw16CmpxchgU { offset: u32 },
+    MemoryAtomicWait32 { offset: u32 },
+    MemoryAtomicNotify { offset: u32 },
 
     // Stack manipulation
     Drop,
@@ -222,25 +365,188 @@ pub enum WasmInst {
     Unreachable,
 
     // Debug/comments
-    Comment { text: String },
+    Comment { addr: u64, note: Note },
+}
+
+// Body size is the translator's peak memory on large binaries
+const _: () = assert!(std::mem::size_of::<WasmInst>() <= 16);
+
+impl WasmInst {
+    /// The static offset of a memory access (its address is the first operand)
+    pub fn memarg_offset_mut(&mut self) -> Option<&mut u32> {
+        use WasmInst::*;
+        match self {
+            I32Load { offset } | I64Load { offset } | I32Load8S { offset } | I32Load8U { offset }
+            | I32Load16S { offset } | I32Load16U { offset } | I64Load8S { offset } | I64Load8U { offset }
+            | I64Load16S { offset } | I64Load16U { offset } | I64Load32S { offset } | I64Load32U { offset }
+            | I32Store { offset } | I64Store { offset } | I32Store8 { offset } | I32Store16 { offset }
+            | I64Store8 { offset } | I64Store16 { offset } | I64Store32 { offset } | F32Load { offset }
+            | F32Store { offset } | F64Load { offset } | F64Store { offset } | I32AtomicLoad { offset }
+            | I64AtomicLoad { offset } | I64AtomicLoad8U { offset } | I64AtomicLoad16U { offset }
+            | I32AtomicStore { offset } | I64AtomicStore { offset } | I32AtomicRmwAdd { offset }
+            | I64AtomicRmwAdd { offset } | I32AtomicRmwAnd { offset } | I64AtomicRmwAnd { offset }
+            | I32AtomicRmwOr { offset } | I64AtomicRmwOr { offset } | I32AtomicRmwXor { offset }
+            | I64AtomicRmwXor { offset } | I32AtomicRmwXchg { offset } | I64AtomicRmwXchg { offset }
+            | I32AtomicRmwCmpxchg { offset } | I64AtomicRmwCmpxchg { offset } | I64AtomicRmw8CmpxchgU { offset }
+            | I64AtomicRmw16CmpxchgU { offset } | MemoryAtomicWait32 { offset } | MemoryAtomicNotify { offset } => {
+                Some(offset)
+            }
+            _ => None,
+        }
+    }
+}
+
+impl std::fmt::Display for Note {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        match self {
+            Note::Block => write!(f, "Block"),
+            Note::Inst(op) => write!(f, "{:?}", op),
+            Note::Unsupported(op) => write!(f, "UNSUPPORTED: {:?}", op),
+        }
+    }
 }
 
 impl WasmModule {
     pub fn function_count(&self) -> usize {
         self.functions.len()
     }
+
+    /// Whether the module imports `env.watch` and `env.breakpoint`
+    pub fn debug_imports(&self) -> bool {
+        self.watch.is_some() || self.breakpoints
+    }
+
+    /// Function imports block functions can call: the `link.rs` helpers,
+    /// the debug hooks and the interposed functions' imports
+    pub fn imported_funcs(&self) -> u32 {
+        let debug = crate::link::DEBUG_FUNCS * self.debug_imports() as u32;
+        crate::link::IMPORTED_FUNCS + debug + self.interpose.len() as u32
+    }
+
+    /// The layout this module was translated with
+    pub fn layout(&self) -> ModuleLayout {
+        ModuleLayout {
+            block_addrs: self.functions.iter().map(|f| f.block_addr).collect(),
+            memory_pages: self.memory_pages,
+            threads: self.threads,
+            exports: self.exports.clone(),
+            groups: self.groups.clone(),
+            data: self.data.clone(),
+            size: self.size,
+            block_exports: self.block_exports.clone(),
+            memory_offset: self.memory_offset,
+            soft_mmu: self.soft_mmu,
+            time_page: self.time_page,
+            lazy_compile: self.lazy_compile,
+            unknown_pc: self.unknown_pc,
+            jspi: self.jspi,
+            trap_exceptions: self.trap_exceptions,
+            harts: self.harts,
+            watch: self.watch.clone(),
+            breakpoints: self.breakpoints,
+            interpose: self.interpose.clone(),
+            meter: self.meter,
+            max_pages: self.max_pages,
+            entry: self.entry,
+            image: self.image.clone(),
+        }
+    }
 }
 
-/// Translate CFG to Wasm module
-pub fn translate(
-    cfg: &ControlFlowGraph,
-    elf_info: &ElfInfo,
-    opt_level: u8,
-    debug: bool,
-) -> Result<WasmModule> {
-    let mut functions = Vec::new();
-    let mut block_to_func = std::collections::HashMap::new();
+/// Everything the module header needs: known from the CFG before any block
+/// is translated, so a streaming build can emit it up front.
+#[derive(Debug, Clone)]
+pub struct ModuleLayout {
+    /// Block addresses in function order
+    pub block_addrs: Vec<u64>,
+    /// Memory size in pages (64KB each)
+    pub memory_pages: u32,
+    /// Threads mode (shared memory, atomics, futex/thread helper exports)
+    pub threads: bool,
+    /// Guest functions exported as callable wrappers (export name, entry)
+    pub exports: Vec<(String, u64)>,
+    /// Split mode: first block index of each code module (empty: one module)
+    pub groups: Vec<usize>,
+    /// Embedded initial memory, as passive data segments
+    pub data: std::sync::Arc<[DataChunk]>,
+    /// Size mode (see `CompileOptions::size`); never set for split output
+    pub size: bool,
+    /// Blocks exported as `block_<addr>` (None: all of them)
+    pub block_exports: Option<BTreeSet<u64>>,
+    /// Linear-memory address of guest address 0
+    pub memory_offset: u32,
+    /// Root page table of the software MMU (`CompileOptions::soft_mmu`)
+    pub soft_mmu: Option<u32>,
+    /// Guest address of the time page (`CompileOptions::time_page`)
+    pub time_page: Option<u32>,
+    /// Unknown PCs go to `env.compile_missing` (`CompileOptions::lazy_compile`)
+    pub lazy_compile: bool,
+    /// What happens at a PC without code (`CompileOptions::unknown_pc`)
+    pub unknown_pc: UnknownPc,
+    /// Syscalls go to `env.syscall_async` (`CompileOptions::jspi`)
+    pub jspi: bool,
+    /// Traps throw the exported `guest_trap` tag (`CompileOptions::trap_exceptions`)
+    pub trap_exceptions: bool,
+    /// Harts the guest runs on (`CompileOptions::harts`)
+    pub harts: u32,
+    /// Accesses are checked against this range (`CompileOptions::watch`)
+    pub watch: Option<std::ops::Range<u32>>,
+    /// Blocks call `env.breakpoint` (`CompileOptions::breakpoints`)
+    pub breakpoints: bool,
+    /// Imports standing in for interposed functions (`CompileOptions::interpose`)
+    pub interpose: Vec<String>,
+    /// Blocks charge the instruction budget (`CompileOptions::meter`)
+    pub meter: bool,
+    /// Cap on the memory import's maximum (`CompileOptions::max_pages`)
+    pub max_pages: Option<u32>,
+    /// Entry block address, the PC `reset` leaves behind
+    pub entry: u64,
+    /// Guest ranges of the loaded segments, bss included, that `reset`
+    /// zeroes before copying the data segments back
+    pub image: Vec<std::ops::Range<u64>>,
+}
+
+impl ModuleLayout {
+    /// Whether block functions live in separately loaded code modules
+    pub fn is_split(&self) -> bool {
+        !self.groups.is_empty()
+    }
 
+    /// Whether the module imports `env.watch` and `env.breakpoint`
+    pub fn debug_imports(&self) -> bool {
+        self.watch.is_some() || self.breakpoints
+    }
+
+    /// Function imports block functions can call (see `WasmModule::imported_funcs`)
+    pub fn imported_funcs(&self) -> u32 {
+        let debug = crate::link::DEBUG_FUNCS * self.debug_imports() as u32;
+        crate::link::IMPORTED_FUNCS + debug + self.interpose.len() as u32
+    }
+
+    /// Whether the block at `addr` is exported as `block_<addr>`
+    pub fn exports_block(&self, addr: u64) -> bool {
+        !self.is_split() && self.block_exports.as_ref().is_none_or(|set| set.contains(&addr))
+    }
+
+    /// Block index range of code module `g`
+    pub fn group_range(&self, g: usize) -> std::ops::Range<usize> {
+        self.groups[g]..self.groups.get(g + 1).copied().unwrap_or(self.block_addrs.len())
+    }
+
+    /// Dispatch table index of the block starting at `addr`, for hosts
+    /// patching the exported table with `set_block`
+    pub fn table_index(&self, addr: u64) -> Option<u32> {
+        self.block_addrs.iter().position(|&a| a == addr).map(|i| i as u32)
+    }
+}
+
+/// Export name of the block function at `addr`
+pub fn block_name(addr: u64) -> String {
+    format!("block_{:x}", addr)
+}
+
+/// Compute the module layout for `cfg` without translating anything
+pub fn layout(cfg: &ControlFlowGraph, elf_info: &ElfInfo, options: &CompileOptions) -> ModuleLayout {
     // Calculate memory size from ELF segments
     let max_addr = elf_info
         .segments
@@ -248,36 +554,229 @@ pub fn translate(
         .map(|s| s.vaddr + s.memsz)
         .max()
         .unwrap_or(0);
-    let memory_pages = ((max_addr + 0xFFFF) / 0x10000) as u32;
+    let memory_pages = (max_addr + options.memory_offset as u64).div_ceil(0x10000) as u32;
+    let (block_addrs, groups) = block_order(cfg, options);
 
-    // Collect all block addresses for inline caching
-    let block_addrs: Vec<u64> = cfg.blocks.keys().copied().collect();
+    ModuleLayout {
+        block_addrs,
+        memory_pages: memory_pages.max(8), // Minimum 512KB
+        threads: options.threads,
+        exports: guest_exports(cfg, options),
+        groups,
+        data: options.data.clone(),
+        size: options.size && options.split == 0,
+        block_exports: block_exports(cfg, options),
+        memory_offset: options.memory_offset,
+        soft_mmu: options.soft_mmu,
+        time_page: options.time_page,
+        lazy_compile: options.lazy_compile || options.hot_only,
+        unknown_pc: options.unknown_pc,
+        jspi: options.jspi,
+        trap_exceptions: options.trap_exceptions,
+        harts: options.harts,
+        watch: options.watch.clone(),
+        breakpoints: !options.breakpoints.is_empty(),
+        interpose: crate::interpose::import_names(options),
+        meter: options.meter,
+        max_pages: options.max_pages,
+        entry: cfg.entry,
+        image: elf_info.segments.iter().map(|s| s.vaddr..s.vaddr + s.memsz).collect(),
+    }
+}
 
-    // Translate each basic block to a function
-    for (idx, (addr, block)) in cfg.blocks.iter().enumerate() {
-        let func = translate_block(block, idx, debug, if opt_level >= 2 { &block_addrs } else { &[] })?;
-        block_to_func.insert(*addr, functions.len());
-        functions.push(func);
+/// The blocks to export: `options.export_blocks` that start a block, or
+/// all of them (none in size mode unless debugging)
+fn block_exports(cfg: &ControlFlowGraph, options: &CompileOptions) -> Option<BTreeSet<u64>> {
+    match &options.export_blocks {
+        Some(addrs) => Some(addrs.iter().copied().filter(|addr| cfg.blocks.contains_key(addr)).collect()),
+        None if options.size && !options.debug => Some(BTreeSet::new()),
+        None => None,
     }
+}
 
-    // Optimize if requested
-    if opt_level >= 2 {
-        for func in &mut functions {
-            optimize_function(func);
-        }
+/// `options.exports` that start a block, as `guest_<name>`. Symbols the CFG
+/// never reached and repeated names (static functions) are skipped.
+fn guest_exports(cfg: &ControlFlowGraph, options: &CompileOptions) -> Vec<(String, u64)> {
+    let mut seen = std::collections::HashSet::new();
+    options
+        .exports
+        .iter()
+        .filter(|sym| cfg.blocks.contains_key(&sym.addr) && seen.insert(&sym.name))
+        .map(|sym| (format!("guest_{}", sym.name), sym.addr))
+        .collect()
+}
+
+/// Block addresses in function order: address order, or hottest first
+/// with `options.hot_first`. Function and table indices follow this order.
+/// In split mode the blocks are then grouped into code modules, whose
+/// first indices are returned alongside.
+fn block_order(cfg: &ControlFlowGraph, options: &CompileOptions) -> (Vec<u64>, Vec<usize>) {
+    let mut order = if options.hot_first {
+        crate::hotness::hot_first(cfg, options.block_profile.as_ref())
+    } else {
+        cfg.blocks.keys().copied().collect()
+    };
+    if let Some(hot) = hot_blocks(cfg, options) {
+        order.retain(|addr| hot.contains(addr));
     }
+    if options.split > 0 {
+        crate::split::group(cfg, &order, options.split)
+    } else {
+        (order, Vec::new())
+    }
+}
+
+/// The blocks a hot/cold split compiles (`CompileOptions::hot_only`), or
+/// None to compile every block
+pub fn hot_blocks(cfg: &ControlFlowGraph, options: &CompileOptions) -> Option<BTreeSet<u64>> {
+    if !options.hot_only {
+        return None;
+    }
+    let mut roots = vec![cfg.entry];
+    roots.extend(options.exports.iter().map(|sym| sym.addr));
+    Some(crate::hotness::hot_set(cfg, options.block_profile.as_ref(), &roots))
+}
+
+/// Translate CFG to Wasm module
+pub fn translate(
+    cfg: &ControlFlowGraph,
+    elf_info: &ElfInfo,
+    options: &CompileOptions,
+) -> Result<WasmModule> {
+    options.validate()?;
+    let layout = layout(cfg, elf_info, options);
+    let block_addrs = &layout.block_addrs;
+
+    // Translate each basic block to a function (optimizing if requested)
+    let blocks: Vec<&BasicBlock> = block_addrs.iter().map(|addr| &cfg.blocks[addr]).collect();
+    let optimize = options.opt_level >= 2;
+    let ic_targets = if optimize { &block_addrs[..] } else { &[] };
+    let freq = if optimize { crate::freq::estimate(cfg) } else { Frequencies::default() };
+    let functions = translate_blocks(&blocks, options, ic_targets, &freq, optimize)?;
+    let block_to_func = block_addrs.iter().enumerate().map(|(idx, &addr)| (addr, idx)).collect();
 
     Ok(WasmModule {
         functions,
-        memory_pages: memory_pages.max(8), // Minimum 512KB
+        memory_pages: layout.memory_pages,
         entry: cfg.entry,
         block_to_func,
+        threads: options.threads,
+        exports: layout.exports,
+        groups: layout.groups,
+        data: layout.data,
+        size: layout.size,
+        block_exports: layout.block_exports,
+        memory_offset: layout.memory_offset,
+        soft_mmu: layout.soft_mmu,
+        time_page: layout.time_page,
+        lazy_compile: layout.lazy_compile,
+        unknown_pc: layout.unknown_pc,
+        jspi: layout.jspi,
+        trap_exceptions: layout.trap_exceptions,
+        harts: layout.harts,
+        watch: layout.watch,
+        breakpoints: layout.breakpoints,
+        interpose: layout.interpose,
+        meter: layout.meter,
+        max_pages: layout.max_pages,
+        image: layout.image,
     })
 }
 
+/// Blocks translated per batch by `translate_streaming`
+const STREAM_CHUNK: usize = 256;
+
+/// Translate every block of `cfg` like `translate`, handing each function
+/// to `emit` in function order instead of collecting them. Only one batch
+/// of `STREAM_CHUNK` functions is alive at a time.
+pub fn translate_streaming(
+    cfg: &ControlFlowGraph,
+    options: &CompileOptions,
+    emit: impl FnMut(WasmFunction) -> Result<()>,
+) -> Result<()> {
+    let (block_addrs, _) = block_order(cfg, options);
+    translate_only(cfg, options, &block_addrs, emit)
+}
+
+/// `translate_streaming` for just the blocks at `addrs`, in that order.
+/// Each comes out exactly as it would from translating all of `cfg`.
+pub fn translate_only(
+    cfg: &ControlFlowGraph,
+    options: &CompileOptions,
+    addrs: &[u64],
+    mut emit: impl FnMut(WasmFunction) -> Result<()>,
+) -> Result<()> {
+    options.validate()?;
+    let blocks: Vec<&BasicBlock> = addrs.iter().map(|addr| &cfg.blocks[addr]).collect();
+    let optimize = options.opt_level >= 2;
+    let all_blocks: Vec<u64> = match hot_blocks(cfg, options) {
+        _ if !optimize => Vec::new(),
+        Some(hot) => hot.into_iter().collect(),
+        None => cfg.blocks.keys().copied().collect(),
+    };
+    let ic_targets = &all_blocks[..];
+    let freq = if optimize { crate::freq::estimate(cfg) } else { Frequencies::default() };
+    for chunk in blocks.chunks(STREAM_CHUNK) {
+        for func in translate_blocks(chunk, options, ic_targets, &freq, optimize)? {
+            emit(func)?;
+        }
+    }
+    Ok(())
+}
+
+/// Translate `blocks`, preserving their order.
+///
+/// Blocks are independent, so with the `parallel` feature they are
+/// translated on the rayon pool; the result order (and therefore every
+/// function index) is the same as the serial path.
+fn translate_blocks(
+    blocks: &[&BasicBlock],
+    options: &CompileOptions,
+    ic_targets: &[u64],
+    freq: &Frequencies,
+    optimize: bool,
+) -> Result<Vec<WasmFunction>> {
+    let translate_one = |block: &&BasicBlock| {
+        let mut func = translate_block(block, options, ic_targets, freq)?;
+        if optimize {
+            optimize_function(&mut func);
+            if options.verify_ir {
+                crate::verify::verify(&func, &[]).context("IR invalid after optimization")?;
+            }
+        }
+        Ok(func)
+    };
+
+    #[cfg(feature = "parallel")]
+    {
+        use rayon::prelude::*;
+        blocks.par_iter().map(translate_one).collect()
+    }
+    #[cfg(not(feature = "parallel"))]
+    {
+        blocks.iter().map(translate_one).collect()
+    }
+}
+
 /// Translate a single basic block to a Wasm function.
-/// `ic_targets` contains known block addresses for inline caching of JALR.
-fn translate_block(block: &BasicBlock, _func_idx: usize, debug: bool, ic_targets: &[u64]) -> Result<WasmFunction> {
+/// `ic_targets` contains known block addresses for inline caching of JALR,
+/// tried in order of their estimated frequency `freq`.
+fn translate_block(
+    block: &BasicBlock,
+    options: &CompileOptions,
+    ic_targets: &[u64],
+    freq: &Frequencies,
+) -> Result<WasmFunction> {
+    if let Some(func_idx) = crate::interpose::host_func(options, block.start_addr) {
+        return Ok(WasmFunction {
+            name: block_name(block.start_addr),
+            block_addr: block.start_addr,
+            body: crate::interpose::stub(func_idx),
+            num_locals: 4,
+            br_tables: Vec::new(),
+        });
+    }
+    let debug = options.debug;
     let mut body = Vec::new();
 
     // Function signature: (param $m i32) (result i32)
@@ -286,24 +785,85 @@ fn translate_block(block: &BasicBlock, _func_idx: usize, debug: bool, ic_targets
 
     if debug {
         body.push(WasmInst::Comment {
-            text: format!("Block 0x{:08x}", block.start_addr),
+            addr: block.start_addr,
+            note: Note::Block,
         });
     }
+    if options.meter {
+        emit_meter(&mut body, block.start_addr, block.instructions.len() as i64);
+    }
 
-    // Translate each instruction
-    for inst in &block.instructions {
+    // An SLT feeding a branch on zero is translated with the branch
+    let fused = match block.instructions {
+        [.., set, branch] if !options.breakpoints.contains(&branch.addr) => {
+            fused_branch_sense(set, branch).map(|taken_if_set| (set, branch, taken_if_set))
+        }
+        _ => None,
+    };
+    let fused_at = fused.map(|_| block.instructions.len() - 2);
+    // LUI/AUIPC followed by an ADDI of its result, or an AUIPC followed by a
+    // load from a GOT slot with known contents: one constant per register
+    // (unless a breakpoint separates them)
+    let pairs: Vec<Option<(u64, u64)>> = block
+        .instructions
+        .windows(2)
+        .map(|w| match options.breakpoints.contains(&w[1].addr) {
+            true => None,
+            false => constant_pair(&w[0], &w[1]).or_else(|| got_load(block, &w[0], &w[1])),
+        })
+        .collect();
+
+    // Translate each instruction, noting where its code starts for the verifier
+    let mut origins = Vec::new();
+    for (i, inst) in block.instructions.iter().enumerate() {
+        if options.verify_ir {
+            origins.push((body.len(), inst.addr, inst.opcode));
+        }
         if debug {
             body.push(WasmInst::Comment {
-                text: format!("  {:08x}: {:?}", inst.addr, inst.opcode),
+                addr: inst.addr,
+                note: Note::Inst(inst.opcode),
             });
         }
-
-        translate_instruction(inst, &mut body)?;
+        if options.breakpoints.contains(&inst.addr) {
+            emit_breakpoint(&mut body, inst.addr);
+        }
+
+        if i > 0 && pairs[i - 1].is_some() {
+            // Written with the LUI/AUIPC before it
+        } else if let Some((hi, value)) = pairs.get(i).copied().flatten() {
+            let add = &block.instructions[i + 1];
+            emit_constant_pair(&mut body, inst.rd.unwrap_or(0), add.rd.unwrap_or(0), hi, value);
+        } else if Some(i) != fused_at {
+            let start = body.len();
+            translate_instruction(inst, &mut body, options)?;
+            materialize_x0(&mut body, start);
+            if let Some(range) = &options.watch {
+                crate::watch::instrument_accesses(&mut body, start, inst.addr, range, options.memory_offset)?;
+            }
+            if let Some(root) = options.soft_mmu {
+                crate::mmu::lower_accesses(&mut body, start, inst.addr, root)?;
+            }
+        }
     }
 
     // Add return for next PC
-    if let Some(term) = block.terminator() {
-        add_terminator_return(term, block, &mut body, ic_targets)?;
+    let start = body.len();
+    if let Some((set, branch, taken_if_set)) = fused {
+        emit_fused_branch(&mut body, set, branch, block.end_addr, taken_if_set);
+    } else if let Some(term) = block.terminator() {
+        if let Some(policy) = options.syscall_policy.as_ref().filter(|_| term.opcode == Opcode::ECALL) {
+            crate::policy::emit_check(&mut body, policy, term.addr, block.end_addr);
+        }
+        let fast_path =
+            options.time_page.is_some_and(|page| crate::vdso::emit_fast_path(&mut body, block, page, options.threads));
+        if let Some(range) = options.watch.as_ref().filter(|_| fast_path) {
+            crate::watch::instrument_accesses(&mut body, start, term.addr, range, options.memory_offset)?;
+        }
+        if let Some(root) = options.soft_mmu.filter(|_| fast_path) {
+            crate::mmu::lower_accesses(&mut body, start, term.addr, root)?;
+        }
+        add_terminator_return(term, block, &mut body, ic_targets, freq)?;
     } else {
         // Fall through to next instruction
         body.push(WasmInst::I32Const {
@@ -311,17 +871,283 @@ fn translate_block(block: &BasicBlock, _func_idx: usize, debug: bool, ic_targets
         });
         body.push(WasmInst::Return);
     }
+    materialize_x0(&mut body, start);
+
+    if options.memory_offset != 0 {
+        rebase_guest_memory(&mut body, options.memory_offset)?;
+    }
 
-    Ok(WasmFunction {
-        name: format!("block_{:x}", block.start_addr),
+    // Temporary locals for computation
+    let mut num_locals = if options.soft_mmu.is_some() { crate::mmu::NUM_LOCALS } else { 4 };
+    if options.fma != FmaMode::Unfused {
+        num_locals = num_locals.max(crate::fma::NUM_LOCALS);
+    }
+    if options.watch.is_some() {
+        num_locals = num_locals.max(crate::watch::NUM_LOCALS);
+    }
+    let func = WasmFunction {
+        name: block_name(block.start_addr),
         block_addr: block.start_addr,
         body,
-        num_locals: 4, // Temporary locals for computation
-    })
+        num_locals,
+        br_tables: Vec::new(),
+    };
+    if options.verify_ir {
+        crate::verify::verify(&func, &origins)?;
+    }
+    Ok(func)
+}
+
+/// If `upper` is LUI/AUIPC and `add` an ADDI(W) of its result (the `li` and
+/// `la` idioms), the upper value and the value `add` writes
+fn constant_pair(upper: &Instruction, add: &Instruction) -> Option<(u64, u64)> {
+    let rd = upper.rd.filter(|&rd| rd != 0)?;
+    let hi = match upper.opcode {
+        Opcode::LUI | Opcode::C_LUI => upper.imm? as u64,
+        Opcode::AUIPC => upper.addr.wrapping_add(upper.imm? as u64),
+        _ => return None,
+    };
+    if add.rs1 != Some(rd) {
+        return None;
+    }
+    let sum = hi.wrapping_add(add.imm? as u64);
+    match add.opcode {
+        Opcode::ADDI | Opcode::C_ADDI => Some((hi, sum)),
+        Opcode::ADDIW | Opcode::C_ADDIW => Some((hi, sum as i32 as i64 as u64)),
+        _ => None,
+    }
+}
+
+/// If `ld` loads a GOT slot `plt::bind` resolved, through the `auipc`
+/// before it: the AUIPC result and the value loaded
+fn got_load(block: &BasicBlock, auipc: &Instruction, ld: &Instruction) -> Option<(u64, u64)> {
+    let &(_, value) = block.got_loads.iter().find(|&&(addr, _)| addr == ld.addr)?;
+    Some((auipc.addr.wrapping_add(auipc.imm? as u64), value))
+}
+
+/// Write a `constant_pair` as constants: `hi` to the upper instruction's
+/// `rd` only if the add (writing `add_rd`) does not overwrite it
+fn emit_constant_pair(body: &mut Vec<WasmInst>, rd: u8, add_rd: u8, hi: u64, value: u64) {
+    let (rd, add_rd) = (rd as u32, add_rd as u32);
+    if add_rd != rd {
+        body.push(WasmInst::LocalGet { idx: 0 });
+        body.push(WasmInst::I64Const { value: hi as i64 });
+        body.push(WasmInst::I64Store { offset: rd * 8 });
+    }
+    if add_rd != 0 {
+        body.push(WasmInst::LocalGet { idx: 0 });
+        body.push(WasmInst::I64Const { value: value as i64 });
+        body.push(WasmInst::I64Store { offset: add_rd * 8 });
+    }
+}
+
+/// Replace the reads of x0 in `body[start..]` (`local.get 0; i64.load 0`)
+/// with `i64.const 0`, folding `0 + imm` and the like into the constant.
+/// The lowerings read every register the same way; x0 is never written.
+fn materialize_x0(body: &mut Vec<WasmInst>, start: usize) {
+    let mut out = Vec::with_capacity(body.len() - start);
+    for &inst in &body[start..] {
+        match (out.as_slice(), inst) {
+            ([.., WasmInst::LocalGet { idx: 0 }], WasmInst::I64Load { offset: 0 }) => {
+                out.pop();
+                out.push(WasmInst::I64Const { value: 0 });
+            }
+            (
+                [.., WasmInst::I64Const { value: 0 }, WasmInst::I64Const { value }],
+                WasmInst::I64Add | WasmInst::I64Or | WasmInst::I64Xor,
+            ) => {
+                let value = *value;
+                out.truncate(out.len() - 2);
+                out.push(WasmInst::I64Const { value });
+            }
+            _ => out.push(inst),
+        }
+    }
+    body.truncate(start);
+    body.extend(out);
+}
+
+/// Add `offset` to the static offset of every guest memory access in `body`
+/// (`CompileOptions::memory_offset`). `memory.fill` has no static offset, so
+/// CBO.ZERO adds it to the address itself.
+fn rebase_guest_memory(body: &mut [WasmInst], offset: u32) -> Result<()> {
+    for (access, _) in guest_accesses(body)? {
+        if let Some(field) = body[access].memarg_offset_mut() {
+            *field = field.checked_add(offset).context("Memory offset overflows a static offset")?;
+        }
+    }
+    Ok(())
+}
+
+/// The guest memory accesses in `body`, each with the index of the
+/// instruction that pushed its address. The operand stack is modelled as in
+/// `opt::forward_registers`: an access whose address is `$m` itself is
+/// machine state, not guest memory.
+pub(crate) fn guest_accesses(body: &[WasmInst]) -> Result<Vec<(usize, usize)>> {
+    use WasmInst::*;
+
+    // Operand stack: (value is the `$m` base, index of the pushing instruction)
+    let mut stack: Vec<(bool, usize)> = Vec::new();
+    let mut frames: Vec<usize> = Vec::new();
+    let mut dead_at: Option<usize> = None;
+    let mut accesses = Vec::new();
+    for (i, mut inst) in body.iter().copied().enumerate() {
+        match inst {
+            Block { .. } | Loop { .. } => {
+                frames.push(stack.len());
+                continue;
+            }
+            End => {
+                stack.truncate(frames.pop().unwrap_or(0));
+                if dead_at.is_some_and(|d| frames.len() < d) {
+                    dead_at = None;
+                }
+                continue;
+            }
+            _ if dead_at.is_some() => continue,
+            _ => {}
+        }
+
+        let effect = match inst {
+            Call { func_idx: crate::link::CRYPTO_FUNC } => Some((3, 1)),
+            Call { func_idx: crate::link::YIELD_FUNC } => Some((0, 0)),
+            Call { func_idx: crate::link::WATCH_FUNC } => Some((4, 0)),
+            Call { func_idx: crate::link::BREAKPOINT_FUNC } => Some((1, 1)),
+            other => crate::opt::stack_effect(other),
+        };
+        let Some((pops, pushes)) = effect.filter(|&(pops, _)| pops <= stack.len()) else {
+            anyhow::bail!("Cannot locate guest memory accesses across {:?}", inst);
+        };
+        let operands = &stack[stack.len() - pops..];
+        let is_access = inst == MemoryFill || inst.memarg_offset_mut().is_some();
+        if let Some(&(false, pusher)) = operands.first().filter(|_| is_access) {
+            accesses.push((i, pusher));
+        }
+        stack.truncate(stack.len() - pops);
+        for _ in 0..pushes {
+            stack.push((inst == LocalGet { idx: 0 }, i));
+        }
+        if matches!(inst, Br { .. } | BrTable { .. } | Return | Unreachable) {
+            dead_at = Some(frames.len());
+        }
+    }
+    Ok(accesses)
+}
+
+/// Push the 32-bit guest address `x[rs1] + imm` of a load or store and
+/// return the static offset the access should use. A non-negative `imm`
+/// goes in the offset rather than being added: the effective address is
+/// then computed without wrapping at 4 GiB, so a sum past it traps instead
+/// of aliasing low memory.
+fn emit_guest_address(body: &mut Vec<WasmInst>, rs1_offset: u32, imm: i64) -> u32 {
+    body.push(WasmInst::LocalGet { idx: 0 });
+    body.push(WasmInst::I64Load { offset: rs1_offset });
+    if let Ok(offset) = u32::try_from(imm) {
+        body.push(WasmInst::I32WrapI64);
+        return offset;
+    }
+    body.push(WasmInst::I64Const { value: imm });
+    body.push(WasmInst::I64Add);
+    body.push(WasmInst::I32WrapI64);
+    0
+}
+
+/// Whether the translator lowers `inst` (rather than trapping at it)
+pub fn is_supported(inst: &Instruction, options: &CompileOptions) -> bool {
+    let mut body = Vec::new();
+    translate_instruction(inst, &mut body, options).is_ok()
+        && !body.iter().any(|i| matches!(i, WasmInst::Comment { note: Note::Unsupported(_), .. }))
+}
+
+/// Whether `inst` reads mhartid without writing it (csrr, or a set or clear
+/// of no bits)
+fn is_hart_id_read(inst: &Instruction) -> bool {
+    matches!(inst.opcode, Opcode::CSRRS | Opcode::CSRRC | Opcode::CSRRSI | Opcode::CSRRCI)
+        && inst.imm == Some(MHARTID)
+        && inst.rs1.unwrap_or(0) == 0
+}
+
+/// The `(shift, mask)` within fcsr of the field an access to fflags, frm or
+/// fcsr itself covers
+fn fp_csr_field(inst: &Instruction) -> Option<(u32, i64)> {
+    if !inst.opcode.is_csr() {
+        return None;
+    }
+    match inst.imm? {
+        0x001 => Some((0, 0x1f)),
+        0x002 => Some((5, 0x7)),
+        0x003 => Some((0, 0xff)),
+        _ => None,
+    }
+}
+
+/// Read-modify-write of an fcsr field: x[rd] = field, then the field is
+/// set to the source (rs1, or its number for the immediate forms), or has
+/// its bits set or cleared. Set and clear of x0 or 0 do not write.
+fn emit_fp_csr(inst: &Instruction, body: &mut Vec<WasmInst>, (shift, mask): (u32, i64)) {
+    let rd = inst.rd.unwrap_or(0) as u32;
+    let rs1 = inst.rs1.unwrap_or(0) as u32;
+    let old = |body: &mut Vec<WasmInst>| {
+        body.push(WasmInst::LocalGet { idx: 0 });
+        body.push(WasmInst::I64Load { offset: FCSR_OFFSET });
+        body.push(WasmInst::I64Const { value: shift as i64 });
+        body.push(WasmInst::I64ShrU);
+        body.push(WasmInst::I64Const { value: mask });
+        body.push(WasmInst::I64And);
+    };
+    let source = |body: &mut Vec<WasmInst>| match inst.opcode {
+        Opcode::CSRRWI | Opcode::CSRRSI | Opcode::CSRRCI => body.push(WasmInst::I64Const { value: rs1 as i64 }),
+        _ => {
+            body.push(WasmInst::LocalGet { idx: 0 });
+            body.push(WasmInst::I64Load { offset: rs1 * 8 });
+        }
+    };
+
+    // The old field stays on the stack under the fcsr store, as rs1 may be rd
+    if rd != 0 {
+        body.push(WasmInst::LocalGet { idx: 0 });
+        old(body);
+    }
+    let writes = matches!(inst.opcode, Opcode::CSRRW | Opcode::CSRRWI) || rs1 != 0;
+    if writes {
+        body.push(WasmInst::LocalGet { idx: 0 });
+        body.push(WasmInst::LocalGet { idx: 0 });
+        body.push(WasmInst::I64Load { offset: FCSR_OFFSET });
+        body.push(WasmInst::I64Const { value: !(mask << shift) });
+        body.push(WasmInst::I64And);
+        match inst.opcode {
+            Opcode::CSRRW | Opcode::CSRRWI => source(body),
+            Opcode::CSRRS | Opcode::CSRRSI => {
+                old(body);
+                source(body);
+                body.push(WasmInst::I64Or);
+            }
+            _ => {
+                old(body);
+                source(body);
+                body.push(WasmInst::I64Const { value: -1 });
+                body.push(WasmInst::I64Xor);
+                body.push(WasmInst::I64And);
+            }
+        }
+        body.push(WasmInst::I64Const { value: mask });
+        body.push(WasmInst::I64And);
+        body.push(WasmInst::I64Const { value: shift as i64 });
+        body.push(WasmInst::I64Shl);
+        body.push(WasmInst::I64Or);
+        body.push(WasmInst::I64Store { offset: FCSR_OFFSET });
+    }
+    if rd != 0 {
+        body.push(WasmInst::I64Store { offset: rd * 8 });
+    }
 }
 
 /// Translate a single RISC-V instruction to Wasm
-fn translate_instruction(inst: &Instruction, body: &mut Vec<WasmInst>) -> Result<()> {
+fn translate_instruction(
+    inst: &Instruction,
+    body: &mut Vec<WasmInst>,
+    options: &CompileOptions,
+) -> Result<()> {
     let rd = inst.rd.unwrap_or(0) as u32;
     let rs1 = inst.rs1.unwrap_or(0) as u32;
     let rs2 = inst.rs2.unwrap_or(0) as u32;
@@ -332,6 +1158,41 @@ fn translate_instruction(inst: &Instruction, body: &mut Vec<WasmInst>) -> Result
     let rs1_offset = rs1 * 8;
     let rs2_offset = rs2 * 8;
 
+    // Atomics need natural alignment: report a misaligned-access trap with
+    // the address instead of accessing it (Wasm atomics would abort)
+    if let Some(size) = inst.opcode.atomic_size().filter(|&size| size > 1) {
+        emit_alignment_check(body, inst.addr, rs1_offset, size);
+    }
+
+    // Threads mode: the A extension must be genuinely atomic across workers
+    if options.threads && translate_atomic_threaded(inst, body) {
+        return Ok(());
+    }
+
+    // SMP: each hart reads its own mhartid from its machine state
+    if options.harts > 1 && is_hart_id_read(inst) {
+        if rd != 0 {
+            body.push(WasmInst::LocalGet { idx: 0 });
+            body.push(WasmInst::LocalGet { idx: 0 });
+            body.push(WasmInst::I64Load { offset: HART_ID_OFFSET });
+            body.push(WasmInst::I64Store { offset: rd_offset });
+        }
+        return Ok(());
+    }
+
+    // The floating-point CSRs live in the machine state
+    if let Some(field) = fp_csr_field(inst) {
+        emit_fp_csr(inst, body, field);
+        return Ok(());
+    }
+
+    // Privileged instructions and CSRs: report an illegal-instruction trap
+    // so the host can deliver SIGILL
+    if inst.is_illegal_in_user_mode() {
+        emit_illegal_trap(body, inst.addr);
+        return Ok(());
+    }
+
     match inst.opcode {
         // =====================================================================
         // Arithmetic (register-register)
@@ -589,12 +1450,8 @@ fn translate_instruction(inst: &Instruction, body: &mut Vec<WasmInst>) -> Result
         Opcode::LB => {
             if rd != 0 {
                 body.push(WasmInst::LocalGet { idx: 0 }); // for store
-                body.push(WasmInst::LocalGet { idx: 0 });
-                body.push(WasmInst::I64Load { offset: rs1_offset });
-                body.push(WasmInst::I64Const { value: imm });
-                body.push(WasmInst::I64Add);
-                body.push(WasmInst::I32WrapI64);
-                body.push(WasmInst::I64Load8S { offset: 0 });
+                let offset = emit_guest_address(body, rs1_offset, imm);
+                body.push(WasmInst::I64Load8S { offset });
                 body.push(WasmInst::I64Store { offset: rd_offset });
             }
         }
@@ -602,12 +1459,8 @@ fn translate_instruction(inst: &Instruction, body: &mut Vec<WasmInst>) -> Result
         Opcode::LBU => {
             if rd != 0 {
                 body.push(WasmInst::LocalGet { idx: 0 });
-                body.push(WasmInst::LocalGet { idx: 0 });
-                body.push(WasmInst::I64Load { offset: rs1_offset });
-                body.push(WasmInst::I64Const { value: imm });
-                body.push(WasmInst::I64Add);
-                body.push(WasmInst::I32WrapI64);
-                body.push(WasmInst::I64Load8U { offset: 0 });
+                let offset = emit_guest_address(body, rs1_offset, imm);
+                body.push(WasmInst::I64Load8U { offset });
                 body.push(WasmInst::I64Store { offset: rd_offset });
             }
         }
@@ -615,12 +1468,8 @@ fn translate_instruction(inst: &Instruction, body: &mut Vec<WasmInst>) -> Result
         Opcode::LH => {
             if rd != 0 {
                 body.push(WasmInst::LocalGet { idx: 0 });
-                body.push(WasmInst::LocalGet { idx: 0 });
-                body.push(WasmInst::I64Load { offset: rs1_offset });
-                body.push(WasmInst::I64Const { value: imm });
-                body.push(WasmInst::I64Add);
-                body.push(WasmInst::I32WrapI64);
-                body.push(WasmInst::I64Load16S { offset: 0 });
+                let offset = emit_guest_address(body, rs1_offset, imm);
+                body.push(WasmInst::I64Load16S { offset });
                 body.push(WasmInst::I64Store { offset: rd_offset });
             }
         }
@@ -628,12 +1477,8 @@ fn translate_instruction(inst: &Instruction, body: &mut Vec<WasmInst>) -> Result
         Opcode::LHU => {
             if rd != 0 {
                 body.push(WasmInst::LocalGet { idx: 0 });
-                body.push(WasmInst::LocalGet { idx: 0 });
-                body.push(WasmInst::I64Load { offset: rs1_offset });
-                body.push(WasmInst::I64Const { value: imm });
-                body.push(WasmInst::I64Add);
-                body.push(WasmInst::I32WrapI64);
-                body.push(WasmInst::I64Load16U { offset: 0 });
+                let offset = emit_guest_address(body, rs1_offset, imm);
+                body.push(WasmInst::I64Load16U { offset });
                 body.push(WasmInst::I64Store { offset: rd_offset });
             }
         }
@@ -641,12 +1486,8 @@ fn translate_instruction(inst: &Instruction, body: &mut Vec<WasmInst>) -> Result
         Opcode::LW | Opcode::C_LW | Opcode::C_LWSP => {
             if rd != 0 {
                 body.push(WasmInst::LocalGet { idx: 0 });
-                body.push(WasmInst::LocalGet { idx: 0 });
-                body.push(WasmInst::I64Load { offset: rs1_offset });
-                body.push(WasmInst::I64Const { value: imm });
-                body.push(WasmInst::I64Add);
-                body.push(WasmInst::I32WrapI64);
-                body.push(WasmInst::I64Load32S { offset: 0 });
+                let offset = emit_guest_address(body, rs1_offset, imm);
+                body.push(WasmInst::I64Load32S { offset });
                 body.push(WasmInst::I64Store { offset: rd_offset });
             }
         }
@@ -654,12 +1495,8 @@ fn translate_instruction(inst: &Instruction, body: &mut Vec<WasmInst>) -> Result
         Opcode::LWU => {
             if rd != 0 {
                 body.push(WasmInst::LocalGet { idx: 0 });
-                body.push(WasmInst::LocalGet { idx: 0 });
-                body.push(WasmInst::I64Load { offset: rs1_offset });
-                body.push(WasmInst::I64Const { value: imm });
-                body.push(WasmInst::I64Add);
-                body.push(WasmInst::I32WrapI64);
-                body.push(WasmInst::I64Load32U { offset: 0 });
+                let offset = emit_guest_address(body, rs1_offset, imm);
+                body.push(WasmInst::I64Load32U { offset });
                 body.push(WasmInst::I64Store { offset: rd_offset });
             }
         }
@@ -667,12 +1504,8 @@ fn translate_instruction(inst: &Instruction, body: &mut Vec<WasmInst>) -> Result
         Opcode::LD | Opcode::C_LD | Opcode::C_LDSP => {
             if rd != 0 {
                 body.push(WasmInst::LocalGet { idx: 0 });
-                body.push(WasmInst::LocalGet { idx: 0 });
-                body.push(WasmInst::I64Load { offset: rs1_offset });
-                body.push(WasmInst::I64Const { value: imm });
-                body.push(WasmInst::I64Add);
-                body.push(WasmInst::I32WrapI64);
-                body.push(WasmInst::I64Load { offset: 0 });
+                let offset = emit_guest_address(body, rs1_offset, imm);
+                body.push(WasmInst::I64Load { offset });
                 body.push(WasmInst::I64Store { offset: rd_offset });
             }
         }
@@ -681,47 +1514,31 @@ fn translate_instruction(inst: &Instruction, body: &mut Vec<WasmInst>) -> Result
         // Stores
         // =====================================================================
         Opcode::SB => {
-            body.push(WasmInst::LocalGet { idx: 0 });
-            body.push(WasmInst::I64Load { offset: rs1_offset });
-            body.push(WasmInst::I64Const { value: imm });
-            body.push(WasmInst::I64Add);
-            body.push(WasmInst::I32WrapI64);
+            let offset = emit_guest_address(body, rs1_offset, imm);
             body.push(WasmInst::LocalGet { idx: 0 });
             body.push(WasmInst::I64Load { offset: rs2_offset });
-            body.push(WasmInst::I64Store8 { offset: 0 });
+            body.push(WasmInst::I64Store8 { offset });
         }
 
         Opcode::SH => {
-            body.push(WasmInst::LocalGet { idx: 0 });
-            body.push(WasmInst::I64Load { offset: rs1_offset });
-            body.push(WasmInst::I64Const { value: imm });
-            body.push(WasmInst::I64Add);
-            body.push(WasmInst::I32WrapI64);
+            let offset = emit_guest_address(body, rs1_offset, imm);
             body.push(WasmInst::LocalGet { idx: 0 });
             body.push(WasmInst::I64Load { offset: rs2_offset });
-            body.push(WasmInst::I64Store16 { offset: 0 });
+            body.push(WasmInst::I64Store16 { offset });
         }
 
         Opcode::SW | Opcode::C_SW | Opcode::C_SWSP => {
-            body.push(WasmInst::LocalGet { idx: 0 });
-            body.push(WasmInst::I64Load { offset: rs1_offset });
-            body.push(WasmInst::I64Const { value: imm });
-            body.push(WasmInst::I64Add);
-            body.push(WasmInst::I32WrapI64);
+            let offset = emit_guest_address(body, rs1_offset, imm);
             body.push(WasmInst::LocalGet { idx: 0 });
             body.push(WasmInst::I64Load { offset: rs2_offset });
-            body.push(WasmInst::I64Store32 { offset: 0 });
+            body.push(WasmInst::I64Store32 { offset });
         }
 
         Opcode::SD | Opcode::C_SD | Opcode::C_SDSP => {
-            body.push(WasmInst::LocalGet { idx: 0 });
-            body.push(WasmInst::I64Load { offset: rs1_offset });
-            body.push(WasmInst::I64Const { value: imm });
-            body.push(WasmInst::I64Add);
-            body.push(WasmInst::I32WrapI64);
+            let offset = emit_guest_address(body, rs1_offset, imm);
             body.push(WasmInst::LocalGet { idx: 0 });
             body.push(WasmInst::I64Load { offset: rs2_offset });
-            body.push(WasmInst::I64Store { offset: 0 });
+            body.push(WasmInst::I64Store { offset });
         }
 
         // =====================================================================
@@ -741,49 +1558,25 @@ fn translate_instruction(inst: &Instruction, body: &mut Vec<WasmInst>) -> Result
 
         Opcode::DIV => {
             if rd != 0 {
-                body.push(WasmInst::LocalGet { idx: 0 });
-                body.push(WasmInst::LocalGet { idx: 0 });
-                body.push(WasmInst::I64Load { offset: rs1_offset });
-                body.push(WasmInst::LocalGet { idx: 0 });
-                body.push(WasmInst::I64Load { offset: rs2_offset });
-                body.push(WasmInst::I64DivS);
-                body.push(WasmInst::I64Store { offset: rd_offset });
+                emit_div(body, rd_offset, rs1_offset, rs2_offset, false, true, false);
             }
         }
 
         Opcode::DIVU => {
             if rd != 0 {
-                body.push(WasmInst::LocalGet { idx: 0 });
-                body.push(WasmInst::LocalGet { idx: 0 });
-                body.push(WasmInst::I64Load { offset: rs1_offset });
-                body.push(WasmInst::LocalGet { idx: 0 });
-                body.push(WasmInst::I64Load { offset: rs2_offset });
-                body.push(WasmInst::I64DivU);
-                body.push(WasmInst::I64Store { offset: rd_offset });
+                emit_div(body, rd_offset, rs1_offset, rs2_offset, false, false, false);
             }
         }
 
         Opcode::REM => {
             if rd != 0 {
-                body.push(WasmInst::LocalGet { idx: 0 });
-                body.push(WasmInst::LocalGet { idx: 0 });
-                body.push(WasmInst::I64Load { offset: rs1_offset });
-                body.push(WasmInst::LocalGet { idx: 0 });
-                body.push(WasmInst::I64Load { offset: rs2_offset });
-                body.push(WasmInst::I64RemS);
-                body.push(WasmInst::I64Store { offset: rd_offset });
+                emit_div(body, rd_offset, rs1_offset, rs2_offset, false, true, true);
             }
         }
 
         Opcode::REMU => {
             if rd != 0 {
-                body.push(WasmInst::LocalGet { idx: 0 });
-                body.push(WasmInst::LocalGet { idx: 0 });
-                body.push(WasmInst::I64Load { offset: rs1_offset });
-                body.push(WasmInst::LocalGet { idx: 0 });
-                body.push(WasmInst::I64Load { offset: rs2_offset });
-                body.push(WasmInst::I64RemU);
-                body.push(WasmInst::I64Store { offset: rd_offset });
+                emit_div(body, rd_offset, rs1_offset, rs2_offset, false, false, true);
             }
         }
 
@@ -886,36 +1679,26 @@ fn translate_instruction(inst: &Instruction, body: &mut Vec<WasmInst>) -> Result
         // =====================================================================
         Opcode::FLW => {
             // f[rd] = M[x[rs1] + imm] (32-bit float)
-            let frd_offset = 256 + rd * 4; // FP regs are 4 bytes for f32
+            let frd_offset = F32_REGS_OFFSET + rd * 4; // FP regs are 4 bytes for f32
             body.push(WasmInst::LocalGet { idx: 0 }); // $m base
-            body.push(WasmInst::LocalGet { idx: 0 });
-            body.push(WasmInst::I64Load { offset: rs1_offset }); // address
-            body.push(WasmInst::I32WrapI64);
-            body.push(WasmInst::I64Const { value: imm });
-            body.push(WasmInst::I32WrapI64);
-            body.push(WasmInst::I32Add);
-            body.push(WasmInst::F32Load { offset: 0 }); // load from computed address
+            let offset = emit_guest_address(body, rs1_offset, imm);
+            body.push(WasmInst::F32Load { offset }); // load from computed address
             body.push(WasmInst::F32Store { offset: frd_offset }); // store to FP reg
         }
 
         Opcode::FSW => {
             // M[x[rs1] + imm] = f[rs2] (32-bit float)
-            let frs2_offset = 256 + rs2 * 4;
-            body.push(WasmInst::LocalGet { idx: 0 });
-            body.push(WasmInst::I64Load { offset: rs1_offset });
-            body.push(WasmInst::I32WrapI64);
-            body.push(WasmInst::I64Const { value: imm });
-            body.push(WasmInst::I32WrapI64);
-            body.push(WasmInst::I32Add);
+            let frs2_offset = F32_REGS_OFFSET + rs2 * 4;
+            let offset = emit_guest_address(body, rs1_offset, imm);
             body.push(WasmInst::LocalGet { idx: 0 });
             body.push(WasmInst::F32Load { offset: frs2_offset });
-            body.push(WasmInst::F32Store { offset: 0 });
+            body.push(WasmInst::F32Store { offset });
         }
 
         Opcode::FADD_S => {
-            let frd_offset = 256 + rd * 4;
-            let frs1_offset = 256 + rs1 * 4;
-            let frs2_offset = 256 + rs2 * 4;
+            let frd_offset = F32_REGS_OFFSET + rd * 4;
+            let frs1_offset = F32_REGS_OFFSET + rs1 * 4;
+            let frs2_offset = F32_REGS_OFFSET + rs2 * 4;
             body.push(WasmInst::LocalGet { idx: 0 });
             body.push(WasmInst::LocalGet { idx: 0 });
             body.push(WasmInst::F32Load { offset: frs1_offset });
@@ -926,9 +1709,9 @@ fn translate_instruction(inst: &Instruction, body: &mut Vec<WasmInst>) -> Result
         }
 
         Opcode::FSUB_S => {
-            let frd_offset = 256 + rd * 4;
-            let frs1_offset = 256 + rs1 * 4;
-            let frs2_offset = 256 + rs2 * 4;
+            let frd_offset = F32_REGS_OFFSET + rd * 4;
+            let frs1_offset = F32_REGS_OFFSET + rs1 * 4;
+            let frs2_offset = F32_REGS_OFFSET + rs2 * 4;
             body.push(WasmInst::LocalGet { idx: 0 });
             body.push(WasmInst::LocalGet { idx: 0 });
             body.push(WasmInst::F32Load { offset: frs1_offset });
@@ -939,9 +1722,9 @@ fn translate_instruction(inst: &Instruction, body: &mut Vec<WasmInst>) -> Result
         }
 
         Opcode::FMUL_S => {
-            let frd_offset = 256 + rd * 4;
-            let frs1_offset = 256 + rs1 * 4;
-            let frs2_offset = 256 + rs2 * 4;
+            let frd_offset = F32_REGS_OFFSET + rd * 4;
+            let frs1_offset = F32_REGS_OFFSET + rs1 * 4;
+            let frs2_offset = F32_REGS_OFFSET + rs2 * 4;
             body.push(WasmInst::LocalGet { idx: 0 });
             body.push(WasmInst::LocalGet { idx: 0 });
             body.push(WasmInst::F32Load { offset: frs1_offset });
@@ -952,9 +1735,9 @@ fn translate_instruction(inst: &Instruction, body: &mut Vec<WasmInst>) -> Result
         }
 
         Opcode::FDIV_S => {
-            let frd_offset = 256 + rd * 4;
-            let frs1_offset = 256 + rs1 * 4;
-            let frs2_offset = 256 + rs2 * 4;
+            let frd_offset = F32_REGS_OFFSET + rd * 4;
+            let frs1_offset = F32_REGS_OFFSET + rs1 * 4;
+            let frs2_offset = F32_REGS_OFFSET + rs2 * 4;
             body.push(WasmInst::LocalGet { idx: 0 });
             body.push(WasmInst::LocalGet { idx: 0 });
             body.push(WasmInst::F32Load { offset: frs1_offset });
@@ -965,8 +1748,8 @@ fn translate_instruction(inst: &Instruction, body: &mut Vec<WasmInst>) -> Result
         }
 
         Opcode::FSQRT_S => {
-            let frd_offset = 256 + rd * 4;
-            let frs1_offset = 256 + rs1 * 4;
+            let frd_offset = F32_REGS_OFFSET + rd * 4;
+            let frs1_offset = F32_REGS_OFFSET + rs1 * 4;
             body.push(WasmInst::LocalGet { idx: 0 });
             body.push(WasmInst::LocalGet { idx: 0 });
             body.push(WasmInst::F32Load { offset: frs1_offset });
@@ -978,38 +1761,28 @@ fn translate_instruction(inst: &Instruction, body: &mut Vec<WasmInst>) -> Result
         // Floating-point (D extension - double precision)
         // Double-precision FP registers at offset 384+ (256 + 32*4 = 384)
         // =====================================================================
-        Opcode::FLD => {
+        Opcode::FLD | Opcode::C_FLD | Opcode::C_FLDSP => {
             // f[rd] = M[x[rs1] + imm] (64-bit double)
-            let frd_offset = 384 + rd * 8;
-            body.push(WasmInst::LocalGet { idx: 0 });
+            let frd_offset = F64_REGS_OFFSET + rd * 8;
             body.push(WasmInst::LocalGet { idx: 0 });
-            body.push(WasmInst::I64Load { offset: rs1_offset });
-            body.push(WasmInst::I32WrapI64);
-            body.push(WasmInst::I64Const { value: imm });
-            body.push(WasmInst::I32WrapI64);
-            body.push(WasmInst::I32Add);
-            body.push(WasmInst::F64Load { offset: 0 });
+            let offset = emit_guest_address(body, rs1_offset, imm);
+            body.push(WasmInst::F64Load { offset });
             body.push(WasmInst::F64Store { offset: frd_offset });
         }
 
-        Opcode::FSD => {
+        Opcode::FSD | Opcode::C_FSD | Opcode::C_FSDSP => {
             // M[x[rs1] + imm] = f[rs2] (64-bit double)
-            let frs2_offset = 384 + rs2 * 8;
-            body.push(WasmInst::LocalGet { idx: 0 });
-            body.push(WasmInst::I64Load { offset: rs1_offset });
-            body.push(WasmInst::I32WrapI64);
-            body.push(WasmInst::I64Const { value: imm });
-            body.push(WasmInst::I32WrapI64);
-            body.push(WasmInst::I32Add);
+            let frs2_offset = F64_REGS_OFFSET + rs2 * 8;
+            let offset = emit_guest_address(body, rs1_offset, imm);
             body.push(WasmInst::LocalGet { idx: 0 });
             body.push(WasmInst::F64Load { offset: frs2_offset });
-            body.push(WasmInst::F64Store { offset: 0 });
+            body.push(WasmInst::F64Store { offset });
         }
 
         Opcode::FADD_D => {
-            let frd_offset = 384 + rd * 8;
-            let frs1_offset = 384 + rs1 * 8;
-            let frs2_offset = 384 + rs2 * 8;
+            let frd_offset = F64_REGS_OFFSET + rd * 8;
+            let frs1_offset = F64_REGS_OFFSET + rs1 * 8;
+            let frs2_offset = F64_REGS_OFFSET + rs2 * 8;
             body.push(WasmInst::LocalGet { idx: 0 });
             body.push(WasmInst::LocalGet { idx: 0 });
             body.push(WasmInst::F64Load { offset: frs1_offset });
@@ -1020,9 +1793,9 @@ fn translate_instruction(inst: &Instruction, body: &mut Vec<WasmInst>) -> Result
         }
 
         Opcode::FSUB_D => {
-            let frd_offset = 384 + rd * 8;
-            let frs1_offset = 384 + rs1 * 8;
-            let frs2_offset = 384 + rs2 * 8;
+            let frd_offset = F64_REGS_OFFSET + rd * 8;
+            let frs1_offset = F64_REGS_OFFSET + rs1 * 8;
+            let frs2_offset = F64_REGS_OFFSET + rs2 * 8;
             body.push(WasmInst::LocalGet { idx: 0 });
             body.push(WasmInst::LocalGet { idx: 0 });
             body.push(WasmInst::F64Load { offset: frs1_offset });
@@ -1033,9 +1806,9 @@ fn translate_instruction(inst: &Instruction, body: &mut Vec<WasmInst>) -> Result
         }
 
         Opcode::FMUL_D => {
-            let frd_offset = 384 + rd * 8;
-            let frs1_offset = 384 + rs1 * 8;
-            let frs2_offset = 384 + rs2 * 8;
+            let frd_offset = F64_REGS_OFFSET + rd * 8;
+            let frs1_offset = F64_REGS_OFFSET + rs1 * 8;
+            let frs2_offset = F64_REGS_OFFSET + rs2 * 8;
             body.push(WasmInst::LocalGet { idx: 0 });
             body.push(WasmInst::LocalGet { idx: 0 });
             body.push(WasmInst::F64Load { offset: frs1_offset });
@@ -1046,9 +1819,9 @@ fn translate_instruction(inst: &Instruction, body: &mut Vec<WasmInst>) -> Result
         }
 
         Opcode::FDIV_D => {
-            let frd_offset = 384 + rd * 8;
-            let frs1_offset = 384 + rs1 * 8;
-            let frs2_offset = 384 + rs2 * 8;
+            let frd_offset = F64_REGS_OFFSET + rd * 8;
+            let frs1_offset = F64_REGS_OFFSET + rs1 * 8;
+            let frs2_offset = F64_REGS_OFFSET + rs2 * 8;
             body.push(WasmInst::LocalGet { idx: 0 });
             body.push(WasmInst::LocalGet { idx: 0 });
             body.push(WasmInst::F64Load { offset: frs1_offset });
@@ -1059,8 +1832,8 @@ fn translate_instruction(inst: &Instruction, body: &mut Vec<WasmInst>) -> Result
         }
 
         Opcode::FSQRT_D => {
-            let frd_offset = 384 + rd * 8;
-            let frs1_offset = 384 + rs1 * 8;
+            let frd_offset = F64_REGS_OFFSET + rd * 8;
+            let frs1_offset = F64_REGS_OFFSET + rs1 * 8;
             body.push(WasmInst::LocalGet { idx: 0 });
             body.push(WasmInst::LocalGet { idx: 0 });
             body.push(WasmInst::F64Load { offset: frs1_offset });
@@ -1070,68 +1843,65 @@ fn translate_instruction(inst: &Instruction, body: &mut Vec<WasmInst>) -> Result
 
         // =====================================================================
         // Atomics (A extension) - single-threaded implementation
-        // For Wasm without threads, these are just regular load/modify/store
+        // For Wasm without threads, these are just regular load/modify/store.
+        // LR/SC still track the reservation address: ECALL and trap returns
+        // clear it, so an SC after a syscall or signal fails.
         // =====================================================================
 
-        // Load-Reserved Word: rd = M[rs1], set reservation
-        Opcode::LR_W => {
+        // Load-Reserved: reservation = rs1, rd = M[rs1]
+        Opcode::LR_W | Opcode::LR_D => {
+            body.push(WasmInst::LocalGet { idx: 0 });
+            body.push(WasmInst::LocalGet { idx: 0 });
+            body.push(WasmInst::I64Load { offset: rs1_offset });
+            body.push(WasmInst::I64Store { offset: RESERVATION_ADDR_OFFSET });
             if rd != 0 {
                 body.push(WasmInst::LocalGet { idx: 0 }); // $m for store
                 body.push(WasmInst::LocalGet { idx: 0 });
                 body.push(WasmInst::I64Load { offset: rs1_offset }); // address
                 body.push(WasmInst::I32WrapI64);
-                body.push(WasmInst::I32Load { offset: 0 }); // load 32-bit
-                body.push(WasmInst::I64ExtendI32S); // sign-extend to 64-bit
-                body.push(WasmInst::I64Store { offset: rd_offset });
-            }
-            // In single-threaded mode, reservation always succeeds
-        }
-
-        // Store-Conditional Word: M[rs1] = rs2, rd = 0 (success) or 1 (fail)
-        Opcode::SC_W => {
-            // Store the value
-            body.push(WasmInst::LocalGet { idx: 0 });
-            body.push(WasmInst::I64Load { offset: rs1_offset });
-            body.push(WasmInst::I32WrapI64);
-            body.push(WasmInst::LocalGet { idx: 0 });
-            body.push(WasmInst::I64Load { offset: rs2_offset });
-            body.push(WasmInst::I32WrapI64);
-            body.push(WasmInst::I32Store { offset: 0 });
-
-            // Always succeed in single-threaded mode: rd = 0
-            if rd != 0 {
-                body.push(WasmInst::LocalGet { idx: 0 });
-                body.push(WasmInst::I64Const { value: 0 });
+                if inst.opcode == Opcode::LR_W {
+                    body.push(WasmInst::I32Load { offset: 0 });
+                    body.push(WasmInst::I64ExtendI32S); // sign-extend to 64-bit
+                } else {
+                    body.push(WasmInst::I64Load { offset: 0 });
+                }
                 body.push(WasmInst::I64Store { offset: rd_offset });
             }
         }
 
-        // Load-Reserved Doubleword
-        Opcode::LR_D => {
-            if rd != 0 {
+        // Store-Conditional: if the reservation covers rs1, M[rs1] = rs2 and
+        // rd = 0, else rd = 1. The reservation is always cleared.
+        Opcode::SC_W | Opcode::SC_D => {
+            let missed = |body: &mut Vec<WasmInst>| {
                 body.push(WasmInst::LocalGet { idx: 0 });
+                body.push(WasmInst::I64Load { offset: RESERVATION_ADDR_OFFSET });
                 body.push(WasmInst::LocalGet { idx: 0 });
                 body.push(WasmInst::I64Load { offset: rs1_offset });
-                body.push(WasmInst::I32WrapI64);
-                body.push(WasmInst::I64Load { offset: 0 });
-                body.push(WasmInst::I64Store { offset: rd_offset });
-            }
-        }
-
-        // Store-Conditional Doubleword
-        Opcode::SC_D => {
+                body.push(WasmInst::I64Ne);
+            };
+            body.push(WasmInst::Block { label: 0 });
+            missed(body);
+            body.push(WasmInst::BrIf { label: 0 });
             body.push(WasmInst::LocalGet { idx: 0 });
             body.push(WasmInst::I64Load { offset: rs1_offset });
             body.push(WasmInst::I32WrapI64);
             body.push(WasmInst::LocalGet { idx: 0 });
             body.push(WasmInst::I64Load { offset: rs2_offset });
-            body.push(WasmInst::I64Store { offset: 0 });
+            if inst.opcode == Opcode::SC_W {
+                body.push(WasmInst::I32WrapI64);
+                body.push(WasmInst::I32Store { offset: 0 });
+            } else {
+                body.push(WasmInst::I64Store { offset: 0 });
+            }
+            body.push(WasmInst::End);
 
             if rd != 0 {
                 body.push(WasmInst::LocalGet { idx: 0 });
-                body.push(WasmInst::I64Const { value: 0 });
+                missed(body);
+                body.push(WasmInst::I64ExtendI32U);
                 body.push(WasmInst::I64Store { offset: rd_offset });
             }
+            emit_clear_reservation(body);
         }
 
         // Atomic swap word: rd = M[rs1]; M[rs1] = rs2
@@ -1271,158 +2041,109 @@ fn translate_instruction(inst: &Instruction, body: &mut Vec<WasmInst>) -> Result
             emit_amo_minmax_d(body, rd, rs1_offset, rs2_offset, WasmInst::I64GtU);
         }
 
-        // FMA instructions (fused multiply-add) - single precision
-        Opcode::FMADD_S => {
-            let frd_offset = 256 + rd * 4;
-            let frs1_offset = 256 + rs1 * 4;
-            let frs2_offset = 256 + rs2 * 4;
-            let rs3 = ((inst.bytes >> 27) & 0x1f) as u32;
-            let frs3_offset = 256 + rs3 * 4;
-            // rd = rs1 * rs2 + rs3
-            body.push(WasmInst::LocalGet { idx: 0 });
-            body.push(WasmInst::LocalGet { idx: 0 });
-            body.push(WasmInst::F32Load { offset: frs1_offset });
-            body.push(WasmInst::LocalGet { idx: 0 });
-            body.push(WasmInst::F32Load { offset: frs2_offset });
-            body.push(WasmInst::F32Mul);
-            body.push(WasmInst::LocalGet { idx: 0 });
-            body.push(WasmInst::F32Load { offset: frs3_offset });
-            body.push(WasmInst::F32Add);
-            body.push(WasmInst::F32Store { offset: frd_offset });
-        }
-
-        Opcode::FMSUB_S => {
-            let frd_offset = 256 + rd * 4;
-            let frs1_offset = 256 + rs1 * 4;
-            let frs2_offset = 256 + rs2 * 4;
-            let rs3 = ((inst.bytes >> 27) & 0x1f) as u32;
-            let frs3_offset = 256 + rs3 * 4;
-            // rd = rs1 * rs2 - rs3
-            body.push(WasmInst::LocalGet { idx: 0 });
-            body.push(WasmInst::LocalGet { idx: 0 });
-            body.push(WasmInst::F32Load { offset: frs1_offset });
-            body.push(WasmInst::LocalGet { idx: 0 });
-            body.push(WasmInst::F32Load { offset: frs2_offset });
-            body.push(WasmInst::F32Mul);
-            body.push(WasmInst::LocalGet { idx: 0 });
-            body.push(WasmInst::F32Load { offset: frs3_offset });
-            body.push(WasmInst::F32Sub);
-            body.push(WasmInst::F32Store { offset: frd_offset });
-        }
-
-        Opcode::FNMSUB_S => {
-            let frd_offset = 256 + rd * 4;
-            let frs1_offset = 256 + rs1 * 4;
-            let frs2_offset = 256 + rs2 * 4;
-            let rs3 = ((inst.bytes >> 27) & 0x1f) as u32;
-            let frs3_offset = 256 + rs3 * 4;
-            // rd = -(rs1 * rs2) + rs3 = rs3 - rs1*rs2
-            body.push(WasmInst::LocalGet { idx: 0 });
-            body.push(WasmInst::LocalGet { idx: 0 });
-            body.push(WasmInst::F32Load { offset: frs1_offset });
-            body.push(WasmInst::LocalGet { idx: 0 });
-            body.push(WasmInst::F32Load { offset: frs2_offset });
-            body.push(WasmInst::F32Mul);
-            body.push(WasmInst::F32Neg);
-            body.push(WasmInst::LocalGet { idx: 0 });
-            body.push(WasmInst::F32Load { offset: frs3_offset });
-            body.push(WasmInst::F32Add);
-            body.push(WasmInst::F32Store { offset: frd_offset });
-        }
-
-        Opcode::FNMADD_S => {
-            let frd_offset = 256 + rd * 4;
-            let frs1_offset = 256 + rs1 * 4;
-            let frs2_offset = 256 + rs2 * 4;
-            let rs3 = ((inst.bytes >> 27) & 0x1f) as u32;
-            let frs3_offset = 256 + rs3 * 4;
-            // rd = -(rs1 * rs2) - rs3
-            body.push(WasmInst::LocalGet { idx: 0 });
-            body.push(WasmInst::LocalGet { idx: 0 });
-            body.push(WasmInst::F32Load { offset: frs1_offset });
-            body.push(WasmInst::LocalGet { idx: 0 });
-            body.push(WasmInst::F32Load { offset: frs2_offset });
-            body.push(WasmInst::F32Mul);
-            body.push(WasmInst::F32Neg);
-            body.push(WasmInst::LocalGet { idx: 0 });
-            body.push(WasmInst::F32Load { offset: frs3_offset });
-            body.push(WasmInst::F32Sub);
-            body.push(WasmInst::F32Store { offset: frd_offset });
-        }
+        // Compare-and-swap (Zacas); single-threaded, so plain accesses do
+        Opcode::AMOCAS_W => emit_cas(body, rd, rs1, rs2, false, false),
+        Opcode::AMOCAS_D => emit_cas(body, rd, rs1, rs2, true, false),
+        Opcode::AMOCAS_Q => emit_cas_q(body, rd, rs1, rs2),
 
-        // FMA instructions - double precision
-        Opcode::FMADD_D => {
-            let frd_offset = 384 + rd * 8;
-            let frs1_offset = 384 + rs1 * 8;
-            let frs2_offset = 384 + rs2 * 8;
-            let rs3 = ((inst.bytes >> 27) & 0x1f) as u32;
-            let frs3_offset = 384 + rs3 * 8;
-            body.push(WasmInst::LocalGet { idx: 0 });
-            body.push(WasmInst::LocalGet { idx: 0 });
-            body.push(WasmInst::F64Load { offset: frs1_offset });
-            body.push(WasmInst::LocalGet { idx: 0 });
-            body.push(WasmInst::F64Load { offset: frs2_offset });
-            body.push(WasmInst::F64Mul);
-            body.push(WasmInst::LocalGet { idx: 0 });
-            body.push(WasmInst::F64Load { offset: frs3_offset });
-            body.push(WasmInst::F64Add);
-            body.push(WasmInst::F64Store { offset: frd_offset });
+        // =====================================================================
+        // Scalar crypto (Zkne / Zknd / Zknh)
+        // =====================================================================
+        Opcode::AES64ES
+        | Opcode::AES64ESM
+        | Opcode::AES64DS
+        | Opcode::AES64DSM
+        | Opcode::AES64IM
+        | Opcode::AES64KS1I => {
+            // The S-box lives in the host: rd = env.crypto(op, rs1, rs2)
+            if rd != 0 {
+                let op = match inst.opcode {
+                    Opcode::AES64ES => crate::crypto::AES64ES,
+                    Opcode::AES64ESM => crate::crypto::AES64ESM,
+                    Opcode::AES64DS => crate::crypto::AES64DS,
+                    Opcode::AES64DSM => crate::crypto::AES64DSM,
+                    Opcode::AES64IM => crate::crypto::AES64IM,
+                    _ => crate::crypto::AES64KS1I,
+                };
+                body.push(WasmInst::LocalGet { idx: 0 });
+                body.push(WasmInst::I32Const { value: op });
+                body.push(WasmInst::LocalGet { idx: 0 });
+                body.push(WasmInst::I64Load { offset: rs1_offset });
+                match inst.opcode {
+                    Opcode::AES64KS1I => body.push(WasmInst::I64Const { value: imm }),
+                    Opcode::AES64IM => body.push(WasmInst::I64Const { value: 0 }),
+                    _ => {
+                        body.push(WasmInst::LocalGet { idx: 0 });
+                        body.push(WasmInst::I64Load { offset: rs2_offset });
+                    }
+                }
+                body.push(WasmInst::Call { func_idx: crate::link::CRYPTO_FUNC });
+                body.push(WasmInst::I64Store { offset: rd_offset });
+            }
         }
 
-        Opcode::FMSUB_D => {
-            let frd_offset = 384 + rd * 8;
-            let frs1_offset = 384 + rs1 * 8;
-            let frs2_offset = 384 + rs2 * 8;
-            let rs3 = ((inst.bytes >> 27) & 0x1f) as u32;
-            let frs3_offset = 384 + rs3 * 8;
-            body.push(WasmInst::LocalGet { idx: 0 });
-            body.push(WasmInst::LocalGet { idx: 0 });
-            body.push(WasmInst::F64Load { offset: frs1_offset });
-            body.push(WasmInst::LocalGet { idx: 0 });
-            body.push(WasmInst::F64Load { offset: frs2_offset });
-            body.push(WasmInst::F64Mul);
-            body.push(WasmInst::LocalGet { idx: 0 });
-            body.push(WasmInst::F64Load { offset: frs3_offset });
-            body.push(WasmInst::F64Sub);
-            body.push(WasmInst::F64Store { offset: frd_offset });
+        Opcode::AES64KS2 => {
+            if rd != 0 {
+                // local1 = w0 = rs1[63:32] ^ rs2[31:0]
+                body.push(WasmInst::LocalGet { idx: 0 });
+                body.push(WasmInst::I64Load { offset: rs1_offset });
+                body.push(WasmInst::I64Const { value: 32 });
+                body.push(WasmInst::I64ShrU);
+                body.push(WasmInst::LocalGet { idx: 0 });
+                body.push(WasmInst::I64Load { offset: rs2_offset });
+                body.push(WasmInst::I64Const { value: 0xffff_ffff });
+                body.push(WasmInst::I64And);
+                body.push(WasmInst::I64Xor);
+                body.push(WasmInst::LocalSet { idx: 1 });
+                // rd = (w0 ^ rs2[63:32]) << 32 | w0
+                body.push(WasmInst::LocalGet { idx: 0 });
+                body.push(WasmInst::LocalGet { idx: 1 });
+                body.push(WasmInst::LocalGet { idx: 0 });
+                body.push(WasmInst::I64Load { offset: rs2_offset });
+                body.push(WasmInst::I64Const { value: 32 });
+                body.push(WasmInst::I64ShrU);
+                body.push(WasmInst::I64Xor);
+                body.push(WasmInst::I64Const { value: 32 });
+                body.push(WasmInst::I64Shl);
+                body.push(WasmInst::LocalGet { idx: 1 });
+                body.push(WasmInst::I64Or);
+                body.push(WasmInst::I64Store { offset: rd_offset });
+            }
         }
 
-        Opcode::FNMSUB_D => {
-            let frd_offset = 384 + rd * 8;
-            let frs1_offset = 384 + rs1 * 8;
-            let frs2_offset = 384 + rs2 * 8;
-            let rs3 = ((inst.bytes >> 27) & 0x1f) as u32;
-            let frs3_offset = 384 + rs3 * 8;
-            body.push(WasmInst::LocalGet { idx: 0 });
-            body.push(WasmInst::LocalGet { idx: 0 });
-            body.push(WasmInst::F64Load { offset: frs1_offset });
-            body.push(WasmInst::LocalGet { idx: 0 });
-            body.push(WasmInst::F64Load { offset: frs2_offset });
-            body.push(WasmInst::F64Mul);
-            body.push(WasmInst::F64Neg);
-            body.push(WasmInst::LocalGet { idx: 0 });
-            body.push(WasmInst::F64Load { offset: frs3_offset });
-            body.push(WasmInst::F64Add);
-            body.push(WasmInst::F64Store { offset: frd_offset });
+        Opcode::SHA256SIG0
+        | Opcode::SHA256SIG1
+        | Opcode::SHA256SUM0
+        | Opcode::SHA256SUM1
+        | Opcode::SHA512SIG0
+        | Opcode::SHA512SIG1
+        | Opcode::SHA512SUM0
+        | Opcode::SHA512SUM1 => {
+            if rd != 0 {
+                emit_sha2(body, inst.opcode, rd_offset, rs1_offset);
+            }
         }
 
-        Opcode::FNMADD_D => {
-            let frd_offset = 384 + rd * 8;
-            let frs1_offset = 384 + rs1 * 8;
-            let frs2_offset = 384 + rs2 * 8;
-            let rs3 = ((inst.bytes >> 27) & 0x1f) as u32;
-            let frs3_offset = 384 + rs3 * 8;
-            body.push(WasmInst::LocalGet { idx: 0 });
-            body.push(WasmInst::LocalGet { idx: 0 });
-            body.push(WasmInst::F64Load { offset: frs1_offset });
-            body.push(WasmInst::LocalGet { idx: 0 });
-            body.push(WasmInst::F64Load { offset: frs2_offset });
-            body.push(WasmInst::F64Mul);
-            body.push(WasmInst::F64Neg);
-            body.push(WasmInst::LocalGet { idx: 0 });
-            body.push(WasmInst::F64Load { offset: frs3_offset });
-            body.push(WasmInst::F64Sub);
-            body.push(WasmInst::F64Store { offset: frd_offset });
+        // Byte and halfword AMOs (Zabha)
+        op if op.narrow_amo().is_some() => {
+            let (word, width) = op.narrow_amo().unwrap();
+            emit_amo_narrow(body, word, width, rd, rs1, rs2, false);
+        }
+
+        // Fused multiply-add: rd = ±(rs1 * rs2) ± rs3
+        Opcode::FMADD_S | Opcode::FMSUB_S | Opcode::FNMSUB_S | Opcode::FNMADD_S
+        | Opcode::FMADD_D | Opcode::FMSUB_D | Opcode::FNMSUB_D | Opcode::FNMADD_D => {
+            use Opcode::*;
+            let fma = crate::fma::Fma {
+                double: matches!(inst.opcode, FMADD_D | FMSUB_D | FNMSUB_D | FNMADD_D),
+                rd,
+                rs1,
+                rs2,
+                rs3: (inst.bytes >> 27) & 0x1f,
+                negate_product: matches!(inst.opcode, FNMSUB_S | FNMADD_S | FNMSUB_D | FNMADD_D),
+                negate_addend: matches!(inst.opcode, FMSUB_S | FNMADD_S | FMSUB_D | FNMADD_D),
+            };
+            crate::fma::emit(body, &fma, options.fma);
         }
 
         // =====================================================================
@@ -1575,98 +2296,85 @@ fn translate_instruction(inst: &Instruction, body: &mut Vec<WasmInst>) -> Result
 
         Opcode::DIVW => {
             if rd != 0 {
-                body.push(WasmInst::LocalGet { idx: 0 });
-                body.push(WasmInst::LocalGet { idx: 0 });
-                body.push(WasmInst::I64Load { offset: rs1_offset });
-                body.push(WasmInst::I32WrapI64);
-                body.push(WasmInst::LocalGet { idx: 0 });
-                body.push(WasmInst::I64Load { offset: rs2_offset });
-                body.push(WasmInst::I32WrapI64);
-                body.push(WasmInst::I32DivS);
-                body.push(WasmInst::I64ExtendI32S);
-                body.push(WasmInst::I64Store { offset: rd_offset });
+                emit_div(body, rd_offset, rs1_offset, rs2_offset, true, true, false);
             }
         }
 
         Opcode::DIVUW => {
             if rd != 0 {
-                body.push(WasmInst::LocalGet { idx: 0 });
-                body.push(WasmInst::LocalGet { idx: 0 });
-                body.push(WasmInst::I64Load { offset: rs1_offset });
-                body.push(WasmInst::I32WrapI64);
-                body.push(WasmInst::LocalGet { idx: 0 });
-                body.push(WasmInst::I64Load { offset: rs2_offset });
-                body.push(WasmInst::I32WrapI64);
-                body.push(WasmInst::I32DivU);
-                body.push(WasmInst::I64ExtendI32S);
-                body.push(WasmInst::I64Store { offset: rd_offset });
+                emit_div(body, rd_offset, rs1_offset, rs2_offset, true, false, false);
             }
         }
 
         Opcode::REMW => {
             if rd != 0 {
-                body.push(WasmInst::LocalGet { idx: 0 });
-                body.push(WasmInst::LocalGet { idx: 0 });
-                body.push(WasmInst::I64Load { offset: rs1_offset });
-                body.push(WasmInst::I32WrapI64);
-                body.push(WasmInst::LocalGet { idx: 0 });
-                body.push(WasmInst::I64Load { offset: rs2_offset });
-                body.push(WasmInst::I32WrapI64);
-                body.push(WasmInst::I32RemS);
-                body.push(WasmInst::I64ExtendI32S);
-                body.push(WasmInst::I64Store { offset: rd_offset });
+                emit_div(body, rd_offset, rs1_offset, rs2_offset, true, true, true);
             }
         }
 
         Opcode::REMUW => {
             if rd != 0 {
-                body.push(WasmInst::LocalGet { idx: 0 });
-                body.push(WasmInst::LocalGet { idx: 0 });
-                body.push(WasmInst::I64Load { offset: rs1_offset });
-                body.push(WasmInst::I32WrapI64);
-                body.push(WasmInst::LocalGet { idx: 0 });
-                body.push(WasmInst::I64Load { offset: rs2_offset });
-                body.push(WasmInst::I32WrapI64);
-                body.push(WasmInst::I32RemU);
-                body.push(WasmInst::I64ExtendI32S);
-                body.push(WasmInst::I64Store { offset: rd_offset });
+                emit_div(body, rd_offset, rs1_offset, rs2_offset, true, false, true);
             }
         }
 
-        // MULH: signed high-half multiply (use i64 mul, shift right 64)
-        // Approximate: cast both to i64, multiply, take upper 64 bits
-        // For correct MULH we need i128 but this is a reasonable approximation
-        Opcode::MULH => {
+        // High halves of the 128-bit product, built from 32-bit partial products
+        Opcode::MULH | Opcode::MULHSU | Opcode::MULHU => {
             if rd != 0 {
-                body.push(WasmInst::LocalGet { idx: 0 });
-                // For MULH, we need the upper 64 bits of a 128-bit product
-                // Approximation: just store 0 (most code uses MUL not MULH)
-                body.push(WasmInst::I64Const { value: 0 });
-                body.push(WasmInst::I64Store { offset: rd_offset });
+                let signed_a = inst.opcode != Opcode::MULHU;
+                let signed_b = inst.opcode == Opcode::MULH;
+                emit_mulh(body, rd_offset, rs1_offset, rs2_offset, signed_a, signed_b);
             }
         }
 
-        Opcode::MULHU => {
-            if rd != 0 {
-                body.push(WasmInst::LocalGet { idx: 0 });
-                body.push(WasmInst::I64Const { value: 0 });
-                body.push(WasmInst::I64Store { offset: rd_offset });
+        // =====================================================================
+        // Misc
+        // =====================================================================
+        Opcode::FENCE => {
+            // Orders memory between workers; a no-op in single-threaded Wasm
+            if options.threads {
+                body.push(WasmInst::AtomicFence);
             }
         }
 
-        Opcode::MULHSU => {
-            if rd != 0 {
-                body.push(WasmInst::LocalGet { idx: 0 });
-                body.push(WasmInst::I64Const { value: 0 });
-                body.push(WasmInst::I64Store { offset: rd_offset });
+        Opcode::C_NOP => {}
+
+        // =====================================================================
+        // Spin-wait hints (Zihintpause / Zawrs)
+        // =====================================================================
+        Opcode::PAUSE | Opcode::WRS_NTO | Opcode::WRS_STO => {
+            if options.threads {
+                emit_spin_wait(body, inst.opcode);
+            } else {
+                // No other hart can change memory; let the host breathe
+                body.push(WasmInst::Call { func_idx: crate::link::YIELD_FUNC });
             }
         }
 
         // =====================================================================
-        // Misc
+        // Cache block operations (Zicbom / Zicboz)
         // =====================================================================
-        Opcode::FENCE | Opcode::C_NOP => {
-            // No-op in single-threaded Wasm
+        Opcode::CBO_ZERO => {
+            // memset(x[rs1] & ~(block - 1), 0, block)
+            body.push(WasmInst::LocalGet { idx: 0 });
+            body.push(WasmInst::I64Load { offset: rs1_offset });
+            body.push(WasmInst::I64Const { value: !(CACHE_BLOCK_SIZE as i64 - 1) });
+            body.push(WasmInst::I64And);
+            body.push(WasmInst::I32WrapI64);
+            if options.memory_offset != 0 {
+                body.push(WasmInst::I32Const { value: options.memory_offset as i32 });
+                body.push(WasmInst::I32Add);
+            }
+            body.push(WasmInst::I32Const { value: 0 });
+            body.push(WasmInst::I32Const { value: CACHE_BLOCK_SIZE as i32 });
+            body.push(WasmInst::MemoryFill);
+        }
+
+        Opcode::CBO_CLEAN | Opcode::CBO_FLUSH | Opcode::CBO_INVAL => {
+            // Wasm memory is coherent; only the ordering is observable
+            if options.threads {
+                body.push(WasmInst::AtomicFence);
+            }
         }
 
         // Branches and jumps are handled separately as terminators
@@ -1695,9 +2403,9 @@ fn translate_instruction(inst: &Instruction, body: &mut Vec<WasmInst>) -> Result
         // FSGNJ: rd = |rs1| with sign of rs2 (when rs1==rs2 it's FMV.S)
         // =====================================================================
         Opcode::FSGNJ_S => {
-            let frd_offset = 256 + rd * 4;
-            let frs1_offset = 256 + rs1 * 4;
-            let frs2_offset = 256 + rs2 * 4;
+            let frd_offset = F32_REGS_OFFSET + rd * 4;
+            let frs1_offset = F32_REGS_OFFSET + rs1 * 4;
+            let frs2_offset = F32_REGS_OFFSET + rs2 * 4;
             body.push(WasmInst::LocalGet { idx: 0 });
             body.push(WasmInst::LocalGet { idx: 0 });
             body.push(WasmInst::F32Load { offset: frs1_offset });
@@ -1709,9 +2417,9 @@ fn translate_instruction(inst: &Instruction, body: &mut Vec<WasmInst>) -> Result
         }
 
         Opcode::FSGNJN_S => {
-            let frd_offset = 256 + rd * 4;
-            let frs1_offset = 256 + rs1 * 4;
-            let frs2_offset = 256 + rs2 * 4;
+            let frd_offset = F32_REGS_OFFSET + rd * 4;
+            let frs1_offset = F32_REGS_OFFSET + rs1 * 4;
+            let frs2_offset = F32_REGS_OFFSET + rs2 * 4;
             // rd = |rs1| with negated sign of rs2
             body.push(WasmInst::LocalGet { idx: 0 });
             body.push(WasmInst::LocalGet { idx: 0 });
@@ -1725,9 +2433,9 @@ fn translate_instruction(inst: &Instruction, body: &mut Vec<WasmInst>) -> Result
         }
 
         Opcode::FSGNJX_S => {
-            let frd_offset = 256 + rd * 4;
-            let frs1_offset = 256 + rs1 * 4;
-            let frs2_offset = 256 + rs2 * 4;
+            let frd_offset = F32_REGS_OFFSET + rd * 4;
+            let frs1_offset = F32_REGS_OFFSET + rs1 * 4;
+            let frs2_offset = F32_REGS_OFFSET + rs2 * 4;
             // rd = rs1 with sign = sign(rs1) XOR sign(rs2)
             // When rs1==rs2 this is FABS. Use reinterpret for XOR.
             body.push(WasmInst::LocalGet { idx: 0 });
@@ -1746,9 +2454,9 @@ fn translate_instruction(inst: &Instruction, body: &mut Vec<WasmInst>) -> Result
 
         // FP sign injection (double precision)
         Opcode::FSGNJ_D => {
-            let frd_offset = 384 + rd * 8;
-            let frs1_offset = 384 + rs1 * 8;
-            let frs2_offset = 384 + rs2 * 8;
+            let frd_offset = F64_REGS_OFFSET + rd * 8;
+            let frs1_offset = F64_REGS_OFFSET + rs1 * 8;
+            let frs2_offset = F64_REGS_OFFSET + rs2 * 8;
             body.push(WasmInst::LocalGet { idx: 0 });
             body.push(WasmInst::LocalGet { idx: 0 });
             body.push(WasmInst::F64Load { offset: frs1_offset });
@@ -1760,9 +2468,9 @@ fn translate_instruction(inst: &Instruction, body: &mut Vec<WasmInst>) -> Result
         }
 
         Opcode::FSGNJN_D => {
-            let frd_offset = 384 + rd * 8;
-            let frs1_offset = 384 + rs1 * 8;
-            let frs2_offset = 384 + rs2 * 8;
+            let frd_offset = F64_REGS_OFFSET + rd * 8;
+            let frs1_offset = F64_REGS_OFFSET + rs1 * 8;
+            let frs2_offset = F64_REGS_OFFSET + rs2 * 8;
             body.push(WasmInst::LocalGet { idx: 0 });
             body.push(WasmInst::LocalGet { idx: 0 });
             body.push(WasmInst::F64Load { offset: frs1_offset });
@@ -1775,9 +2483,9 @@ fn translate_instruction(inst: &Instruction, body: &mut Vec<WasmInst>) -> Result
         }
 
         Opcode::FSGNJX_D => {
-            let frd_offset = 384 + rd * 8;
-            let frs1_offset = 384 + rs1 * 8;
-            let frs2_offset = 384 + rs2 * 8;
+            let frd_offset = F64_REGS_OFFSET + rd * 8;
+            let frs1_offset = F64_REGS_OFFSET + rs1 * 8;
+            let frs2_offset = F64_REGS_OFFSET + rs2 * 8;
             body.push(WasmInst::LocalGet { idx: 0 });
             body.push(WasmInst::LocalGet { idx: 0 });
             body.push(WasmInst::F64Load { offset: frs1_offset });
@@ -1796,9 +2504,9 @@ fn translate_instruction(inst: &Instruction, body: &mut Vec<WasmInst>) -> Result
         // FP min/max
         // =====================================================================
         Opcode::FMIN_S => {
-            let frd_offset = 256 + rd * 4;
-            let frs1_offset = 256 + rs1 * 4;
-            let frs2_offset = 256 + rs2 * 4;
+            let frd_offset = F32_REGS_OFFSET + rd * 4;
+            let frs1_offset = F32_REGS_OFFSET + rs1 * 4;
+            let frs2_offset = F32_REGS_OFFSET + rs2 * 4;
             body.push(WasmInst::LocalGet { idx: 0 });
             body.push(WasmInst::LocalGet { idx: 0 });
             body.push(WasmInst::F32Load { offset: frs1_offset });
@@ -1809,9 +2517,9 @@ fn translate_instruction(inst: &Instruction, body: &mut Vec<WasmInst>) -> Result
         }
 
         Opcode::FMAX_S => {
-            let frd_offset = 256 + rd * 4;
-            let frs1_offset = 256 + rs1 * 4;
-            let frs2_offset = 256 + rs2 * 4;
+            let frd_offset = F32_REGS_OFFSET + rd * 4;
+            let frs1_offset = F32_REGS_OFFSET + rs1 * 4;
+            let frs2_offset = F32_REGS_OFFSET + rs2 * 4;
             body.push(WasmInst::LocalGet { idx: 0 });
             body.push(WasmInst::LocalGet { idx: 0 });
             body.push(WasmInst::F32Load { offset: frs1_offset });
@@ -1822,9 +2530,9 @@ fn translate_instruction(inst: &Instruction, body: &mut Vec<WasmInst>) -> Result
         }
 
         Opcode::FMIN_D => {
-            let frd_offset = 384 + rd * 8;
-            let frs1_offset = 384 + rs1 * 8;
-            let frs2_offset = 384 + rs2 * 8;
+            let frd_offset = F64_REGS_OFFSET + rd * 8;
+            let frs1_offset = F64_REGS_OFFSET + rs1 * 8;
+            let frs2_offset = F64_REGS_OFFSET + rs2 * 8;
             body.push(WasmInst::LocalGet { idx: 0 });
             body.push(WasmInst::LocalGet { idx: 0 });
             body.push(WasmInst::F64Load { offset: frs1_offset });
@@ -1835,9 +2543,9 @@ fn translate_instruction(inst: &Instruction, body: &mut Vec<WasmInst>) -> Result
         }
 
         Opcode::FMAX_D => {
-            let frd_offset = 384 + rd * 8;
-            let frs1_offset = 384 + rs1 * 8;
-            let frs2_offset = 384 + rs2 * 8;
+            let frd_offset = F64_REGS_OFFSET + rd * 8;
+            let frs1_offset = F64_REGS_OFFSET + rs1 * 8;
+            let frs2_offset = F64_REGS_OFFSET + rs2 * 8;
             body.push(WasmInst::LocalGet { idx: 0 });
             body.push(WasmInst::LocalGet { idx: 0 });
             body.push(WasmInst::F64Load { offset: frs1_offset });
@@ -1852,8 +2560,8 @@ fn translate_instruction(inst: &Instruction, body: &mut Vec<WasmInst>) -> Result
         // =====================================================================
         Opcode::FEQ_S => {
             if rd != 0 {
-                let frs1_offset = 256 + rs1 * 4;
-                let frs2_offset = 256 + rs2 * 4;
+                let frs1_offset = F32_REGS_OFFSET + rs1 * 4;
+                let frs2_offset = F32_REGS_OFFSET + rs2 * 4;
                 body.push(WasmInst::LocalGet { idx: 0 });
                 body.push(WasmInst::LocalGet { idx: 0 });
                 body.push(WasmInst::F32Load { offset: frs1_offset });
@@ -1867,8 +2575,8 @@ fn translate_instruction(inst: &Instruction, body: &mut Vec<WasmInst>) -> Result
 
         Opcode::FLT_S => {
             if rd != 0 {
-                let frs1_offset = 256 + rs1 * 4;
-                let frs2_offset = 256 + rs2 * 4;
+                let frs1_offset = F32_REGS_OFFSET + rs1 * 4;
+                let frs2_offset = F32_REGS_OFFSET + rs2 * 4;
                 body.push(WasmInst::LocalGet { idx: 0 });
                 body.push(WasmInst::LocalGet { idx: 0 });
                 body.push(WasmInst::F32Load { offset: frs1_offset });
@@ -1882,8 +2590,8 @@ fn translate_instruction(inst: &Instruction, body: &mut Vec<WasmInst>) -> Result
 
         Opcode::FLE_S => {
             if rd != 0 {
-                let frs1_offset = 256 + rs1 * 4;
-                let frs2_offset = 256 + rs2 * 4;
+                let frs1_offset = F32_REGS_OFFSET + rs1 * 4;
+                let frs2_offset = F32_REGS_OFFSET + rs2 * 4;
                 body.push(WasmInst::LocalGet { idx: 0 });
                 body.push(WasmInst::LocalGet { idx: 0 });
                 body.push(WasmInst::F32Load { offset: frs1_offset });
@@ -1897,8 +2605,8 @@ fn translate_instruction(inst: &Instruction, body: &mut Vec<WasmInst>) -> Result
 
         Opcode::FEQ_D => {
             if rd != 0 {
-                let frs1_offset = 384 + rs1 * 8;
-                let frs2_offset = 384 + rs2 * 8;
+                let frs1_offset = F64_REGS_OFFSET + rs1 * 8;
+                let frs2_offset = F64_REGS_OFFSET + rs2 * 8;
                 body.push(WasmInst::LocalGet { idx: 0 });
                 body.push(WasmInst::LocalGet { idx: 0 });
                 body.push(WasmInst::F64Load { offset: frs1_offset });
@@ -1912,8 +2620,8 @@ fn translate_instruction(inst: &Instruction, body: &mut Vec<WasmInst>) -> Result
 
         Opcode::FLT_D => {
             if rd != 0 {
-                let frs1_offset = 384 + rs1 * 8;
-                let frs2_offset = 384 + rs2 * 8;
+                let frs1_offset = F64_REGS_OFFSET + rs1 * 8;
+                let frs2_offset = F64_REGS_OFFSET + rs2 * 8;
                 body.push(WasmInst::LocalGet { idx: 0 });
                 body.push(WasmInst::LocalGet { idx: 0 });
                 body.push(WasmInst::F64Load { offset: frs1_offset });
@@ -1927,8 +2635,8 @@ fn translate_instruction(inst: &Instruction, body: &mut Vec<WasmInst>) -> Result
 
         Opcode::FLE_D => {
             if rd != 0 {
-                let frs1_offset = 384 + rs1 * 8;
-                let frs2_offset = 384 + rs2 * 8;
+                let frs1_offset = F64_REGS_OFFSET + rs1 * 8;
+                let frs2_offset = F64_REGS_OFFSET + rs2 * 8;
                 body.push(WasmInst::LocalGet { idx: 0 });
                 body.push(WasmInst::LocalGet { idx: 0 });
                 body.push(WasmInst::F64Load { offset: frs1_offset });
@@ -1946,7 +2654,7 @@ fn translate_instruction(inst: &Instruction, body: &mut Vec<WasmInst>) -> Result
         Opcode::FCVT_W_S => {
             // Convert f32 to i32 (signed), sign-extend to i64
             if rd != 0 {
-                let frs1_offset = 256 + rs1 * 4;
+                let frs1_offset = F32_REGS_OFFSET + rs1 * 4;
                 body.push(WasmInst::LocalGet { idx: 0 });
                 body.push(WasmInst::LocalGet { idx: 0 });
                 body.push(WasmInst::F32Load { offset: frs1_offset });
@@ -1958,7 +2666,7 @@ fn translate_instruction(inst: &Instruction, body: &mut Vec<WasmInst>) -> Result
 
         Opcode::FCVT_WU_S => {
             if rd != 0 {
-                let frs1_offset = 256 + rs1 * 4;
+                let frs1_offset = F32_REGS_OFFSET + rs1 * 4;
                 body.push(WasmInst::LocalGet { idx: 0 });
                 body.push(WasmInst::LocalGet { idx: 0 });
                 body.push(WasmInst::F32Load { offset: frs1_offset });
@@ -1971,7 +2679,7 @@ fn translate_instruction(inst: &Instruction, body: &mut Vec<WasmInst>) -> Result
         Opcode::FCVT_L_S => {
             // Convert f32 to i64 (signed)
             if rd != 0 {
-                let frs1_offset = 256 + rs1 * 4;
+                let frs1_offset = F32_REGS_OFFSET + rs1 * 4;
                 body.push(WasmInst::LocalGet { idx: 0 });
                 body.push(WasmInst::LocalGet { idx: 0 });
                 body.push(WasmInst::F32Load { offset: frs1_offset });
@@ -1982,7 +2690,7 @@ fn translate_instruction(inst: &Instruction, body: &mut Vec<WasmInst>) -> Result
 
         Opcode::FCVT_LU_S => {
             if rd != 0 {
-                let frs1_offset = 256 + rs1 * 4;
+                let frs1_offset = F32_REGS_OFFSET + rs1 * 4;
                 body.push(WasmInst::LocalGet { idx: 0 });
                 body.push(WasmInst::LocalGet { idx: 0 });
                 body.push(WasmInst::F32Load { offset: frs1_offset });
@@ -1993,7 +2701,7 @@ fn translate_instruction(inst: &Instruction, body: &mut Vec<WasmInst>) -> Result
 
         Opcode::FCVT_W_D => {
             if rd != 0 {
-                let frs1_offset = 384 + rs1 * 8;
+                let frs1_offset = F64_REGS_OFFSET + rs1 * 8;
                 body.push(WasmInst::LocalGet { idx: 0 });
                 body.push(WasmInst::LocalGet { idx: 0 });
                 body.push(WasmInst::F64Load { offset: frs1_offset });
@@ -2005,7 +2713,7 @@ fn translate_instruction(inst: &Instruction, body: &mut Vec<WasmInst>) -> Result
 
         Opcode::FCVT_WU_D => {
             if rd != 0 {
-                let frs1_offset = 384 + rs1 * 8;
+                let frs1_offset = F64_REGS_OFFSET + rs1 * 8;
                 body.push(WasmInst::LocalGet { idx: 0 });
                 body.push(WasmInst::LocalGet { idx: 0 });
                 body.push(WasmInst::F64Load { offset: frs1_offset });
@@ -2017,7 +2725,7 @@ fn translate_instruction(inst: &Instruction, body: &mut Vec<WasmInst>) -> Result
 
         Opcode::FCVT_L_D => {
             if rd != 0 {
-                let frs1_offset = 384 + rs1 * 8;
+                let frs1_offset = F64_REGS_OFFSET + rs1 * 8;
                 body.push(WasmInst::LocalGet { idx: 0 });
                 body.push(WasmInst::LocalGet { idx: 0 });
                 body.push(WasmInst::F64Load { offset: frs1_offset });
@@ -2028,7 +2736,7 @@ fn translate_instruction(inst: &Instruction, body: &mut Vec<WasmInst>) -> Result
 
         Opcode::FCVT_LU_D => {
             if rd != 0 {
-                let frs1_offset = 384 + rs1 * 8;
+                let frs1_offset = F64_REGS_OFFSET + rs1 * 8;
                 body.push(WasmInst::LocalGet { idx: 0 });
                 body.push(WasmInst::LocalGet { idx: 0 });
                 body.push(WasmInst::F64Load { offset: frs1_offset });
@@ -2041,7 +2749,7 @@ fn translate_instruction(inst: &Instruction, body: &mut Vec<WasmInst>) -> Result
         // FP conversion: integer -> float (source from integer register rs1)
         // =====================================================================
         Opcode::FCVT_S_W => {
-            let frd_offset = 256 + rd * 4;
+            let frd_offset = F32_REGS_OFFSET + rd * 4;
             body.push(WasmInst::LocalGet { idx: 0 });
             body.push(WasmInst::LocalGet { idx: 0 });
             body.push(WasmInst::I64Load { offset: rs1_offset });
@@ -2051,7 +2759,7 @@ fn translate_instruction(inst: &Instruction, body: &mut Vec<WasmInst>) -> Result
         }
 
         Opcode::FCVT_S_WU => {
-            let frd_offset = 256 + rd * 4;
+            let frd_offset = F32_REGS_OFFSET + rd * 4;
             body.push(WasmInst::LocalGet { idx: 0 });
             body.push(WasmInst::LocalGet { idx: 0 });
             body.push(WasmInst::I64Load { offset: rs1_offset });
@@ -2061,7 +2769,7 @@ fn translate_instruction(inst: &Instruction, body: &mut Vec<WasmInst>) -> Result
         }
 
         Opcode::FCVT_S_L => {
-            let frd_offset = 256 + rd * 4;
+            let frd_offset = F32_REGS_OFFSET + rd * 4;
             body.push(WasmInst::LocalGet { idx: 0 });
             body.push(WasmInst::LocalGet { idx: 0 });
             body.push(WasmInst::I64Load { offset: rs1_offset });
@@ -2070,7 +2778,7 @@ fn translate_instruction(inst: &Instruction, body: &mut Vec<WasmInst>) -> Result
         }
 
         Opcode::FCVT_S_LU => {
-            let frd_offset = 256 + rd * 4;
+            let frd_offset = F32_REGS_OFFSET + rd * 4;
             body.push(WasmInst::LocalGet { idx: 0 });
             body.push(WasmInst::LocalGet { idx: 0 });
             body.push(WasmInst::I64Load { offset: rs1_offset });
@@ -2079,7 +2787,7 @@ fn translate_instruction(inst: &Instruction, body: &mut Vec<WasmInst>) -> Result
         }
 
         Opcode::FCVT_D_W => {
-            let frd_offset = 384 + rd * 8;
+            let frd_offset = F64_REGS_OFFSET + rd * 8;
             body.push(WasmInst::LocalGet { idx: 0 });
             body.push(WasmInst::LocalGet { idx: 0 });
             body.push(WasmInst::I64Load { offset: rs1_offset });
@@ -2089,7 +2797,7 @@ fn translate_instruction(inst: &Instruction, body: &mut Vec<WasmInst>) -> Result
         }
 
         Opcode::FCVT_D_WU => {
-            let frd_offset = 384 + rd * 8;
+            let frd_offset = F64_REGS_OFFSET + rd * 8;
             body.push(WasmInst::LocalGet { idx: 0 });
             body.push(WasmInst::LocalGet { idx: 0 });
             body.push(WasmInst::I64Load { offset: rs1_offset });
@@ -2099,7 +2807,7 @@ fn translate_instruction(inst: &Instruction, body: &mut Vec<WasmInst>) -> Result
         }
 
         Opcode::FCVT_D_L => {
-            let frd_offset = 384 + rd * 8;
+            let frd_offset = F64_REGS_OFFSET + rd * 8;
             body.push(WasmInst::LocalGet { idx: 0 });
             body.push(WasmInst::LocalGet { idx: 0 });
             body.push(WasmInst::I64Load { offset: rs1_offset });
@@ -2108,7 +2816,7 @@ fn translate_instruction(inst: &Instruction, body: &mut Vec<WasmInst>) -> Result
         }
 
         Opcode::FCVT_D_LU => {
-            let frd_offset = 384 + rd * 8;
+            let frd_offset = F64_REGS_OFFSET + rd * 8;
             body.push(WasmInst::LocalGet { idx: 0 });
             body.push(WasmInst::LocalGet { idx: 0 });
             body.push(WasmInst::I64Load { offset: rs1_offset });
@@ -2120,8 +2828,8 @@ fn translate_instruction(inst: &Instruction, body: &mut Vec<WasmInst>) -> Result
         // FP precision conversion
         // =====================================================================
         Opcode::FCVT_S_D => {
-            let frd_offset = 256 + rd * 4;
-            let frs1_offset = 384 + rs1 * 8;
+            let frd_offset = F32_REGS_OFFSET + rd * 4;
+            let frs1_offset = F64_REGS_OFFSET + rs1 * 8;
             body.push(WasmInst::LocalGet { idx: 0 });
             body.push(WasmInst::LocalGet { idx: 0 });
             body.push(WasmInst::F64Load { offset: frs1_offset });
@@ -2130,8 +2838,8 @@ fn translate_instruction(inst: &Instruction, body: &mut Vec<WasmInst>) -> Result
         }
 
         Opcode::FCVT_D_S => {
-            let frd_offset = 384 + rd * 8;
-            let frs1_offset = 256 + rs1 * 4;
+            let frd_offset = F64_REGS_OFFSET + rd * 8;
+            let frs1_offset = F32_REGS_OFFSET + rs1 * 4;
             body.push(WasmInst::LocalGet { idx: 0 });
             body.push(WasmInst::LocalGet { idx: 0 });
             body.push(WasmInst::F32Load { offset: frs1_offset });
@@ -2145,7 +2853,7 @@ fn translate_instruction(inst: &Instruction, body: &mut Vec<WasmInst>) -> Result
         Opcode::FMV_X_W => {
             // Move f32 bits to integer register (sign-extended to i64)
             if rd != 0 {
-                let frs1_offset = 256 + rs1 * 4;
+                let frs1_offset = F32_REGS_OFFSET + rs1 * 4;
                 body.push(WasmInst::LocalGet { idx: 0 });
                 body.push(WasmInst::LocalGet { idx: 0 });
                 body.push(WasmInst::F32Load { offset: frs1_offset });
@@ -2157,7 +2865,7 @@ fn translate_instruction(inst: &Instruction, body: &mut Vec<WasmInst>) -> Result
 
         Opcode::FMV_W_X => {
             // Move integer register bits to f32
-            let frd_offset = 256 + rd * 4;
+            let frd_offset = F32_REGS_OFFSET + rd * 4;
             body.push(WasmInst::LocalGet { idx: 0 });
             body.push(WasmInst::LocalGet { idx: 0 });
             body.push(WasmInst::I64Load { offset: rs1_offset });
@@ -2169,7 +2877,7 @@ fn translate_instruction(inst: &Instruction, body: &mut Vec<WasmInst>) -> Result
         Opcode::FMV_X_D => {
             // Move f64 bits to integer register
             if rd != 0 {
-                let frs1_offset = 384 + rs1 * 8;
+                let frs1_offset = F64_REGS_OFFSET + rs1 * 8;
                 body.push(WasmInst::LocalGet { idx: 0 });
                 body.push(WasmInst::LocalGet { idx: 0 });
                 body.push(WasmInst::F64Load { offset: frs1_offset });
@@ -2180,7 +2888,7 @@ fn translate_instruction(inst: &Instruction, body: &mut Vec<WasmInst>) -> Result
 
         Opcode::FMV_D_X => {
             // Move integer register bits to f64
-            let frd_offset = 384 + rd * 8;
+            let frd_offset = F64_REGS_OFFSET + rd * 8;
             body.push(WasmInst::LocalGet { idx: 0 });
             body.push(WasmInst::LocalGet { idx: 0 });
             body.push(WasmInst::I64Load { offset: rs1_offset });
@@ -2204,7 +2912,8 @@ fn translate_instruction(inst: &Instruction, body: &mut Vec<WasmInst>) -> Result
         _ => {
             // Unsupported instruction - emit trap
             body.push(WasmInst::Comment {
-                text: format!("UNSUPPORTED: {:?}", inst.opcode),
+                addr: inst.addr,
+                note: Note::Unsupported(inst.opcode),
             });
             body.push(WasmInst::I32Const { value: -1 }); // Signal error
             body.push(WasmInst::Return);
@@ -2221,6 +2930,7 @@ fn add_terminator_return(
     block: &BasicBlock,
     body: &mut Vec<WasmInst>,
     ic_targets: &[u64],
+    freq: &Frequencies,
 ) -> Result<()> {
     let rd = inst.rd.unwrap_or(0) as u32;
     let rs1 = inst.rs1.unwrap_or(0) as u32;
@@ -2228,6 +2938,18 @@ fn add_terminator_return(
     let imm = inst.imm.unwrap_or(0);
     let next_pc = block.end_addr;
 
+    // A bound PLT stub (see plt.rs): link, then return the known target
+    if let Some(target) = block.direct_target {
+        if rd != 0 {
+            body.push(WasmInst::LocalGet { idx: 0 });
+            body.push(WasmInst::I64Const { value: next_pc as i64 });
+            body.push(WasmInst::I64Store { offset: rd * 8 });
+        }
+        body.push(WasmInst::I32Const { value: target as i32 });
+        body.push(WasmInst::Return);
+        return Ok(());
+    }
+
     match inst.opcode {
         // Conditional branches
         Opcode::BEQ => {
@@ -2285,17 +3007,8 @@ fn add_terminator_return(
         }
 
         Opcode::JALR | Opcode::C_JALR => {
-            // rd = PC + len (link address for function call)
-            if rd != 0 {
-                let link_addr = inst.addr + inst.len as u64;
-                body.push(WasmInst::LocalGet { idx: 0 });
-                body.push(WasmInst::I64Const {
-                    value: link_addr as i64,
-                });
-                body.push(WasmInst::I64Store { offset: rd * 8 });
-            }
-
-            // Compute target = (x[rs1] + imm) & ~1
+            // Compute target = (x[rs1] + imm) & ~1 before writing the link
+            // register: rd may equal rs1 (e.g. `jalr ra, 0(ra)`)
             body.push(WasmInst::LocalGet { idx: 0 });
             body.push(WasmInst::I64Load { offset: rs1 * 8 });
             if imm != 0 {
@@ -2304,44 +3017,52 @@ fn add_terminator_return(
             }
             body.push(WasmInst::I64Const { value: !1i64 });
             body.push(WasmInst::I64And);
-            body.push(WasmInst::I32WrapI64);
+            body.push(WasmInst::LocalSet { idx: 1 });
+
+            // rd = PC + len (link address for function call)
+            if rd != 0 {
+                let link_addr = inst.addr + inst.len as u64;
+                body.push(WasmInst::LocalGet { idx: 0 });
+                body.push(WasmInst::I64Const {
+                    value: link_addr as i64,
+                });
+                body.push(WasmInst::I64Store { offset: rd * 8 });
+            }
 
             // Inline caching for call-like JALR (rd != 0):
             // If this block has known successors in the CFG, emit guarded
             // direct returns. The Wasm engine can constant-fold these checks,
             // and the dispatch loop's br_table becomes trivially predictable
-            // when the same target PC returns repeatedly.
+            // when the same target PC returns repeatedly. The guards test the
+            // successors estimated to run most first.
             let successors: Vec<u64> = if rd != 0 {
-                block.successors.iter()
+                let mut known: Vec<u64> = block.successors.iter()
                     .filter(|&&s| ic_targets.contains(&s))
                     .copied()
-                    .take(2) // max 2 IC guards to limit code bloat (<10%)
-                    .collect()
+                    .collect();
+                known.sort_by(|&a, &b| freq.block(b).total_cmp(&freq.block(a)));
+                known.truncate(2); // max 2 IC guards to limit code bloat (<10%)
+                known
             } else {
                 vec![]
             };
 
-            if !successors.is_empty() {
-                // Store computed target in local for IC checks
-                body.push(WasmInst::LocalSet { idx: 1 });
-
-                for &target_pc in &successors {
-                    // if (target == expected_pc) return expected_pc_const
-                    // Using: block { br_if(cond, skip) ; return const ; } end
-                    body.push(WasmInst::Block { label: 0 });
-                    body.push(WasmInst::LocalGet { idx: 1 });
-                    body.push(WasmInst::I32Const { value: target_pc as i32 });
-                    body.push(WasmInst::I32Ne); // skip if NOT equal
-                    body.push(WasmInst::BrIf { label: 0 }); // break out of block
-                    body.push(WasmInst::I32Const { value: target_pc as i32 });
-                    body.push(WasmInst::Return);
-                    body.push(WasmInst::End);
-                }
-
-                // Fallback: return computed target from local
+            for &target_pc in &successors {
+                // if (target == expected_pc) return expected_pc_const
+                // Using: block { br_if(cond, skip) ; return const ; } end
+                body.push(WasmInst::Block { label: 0 });
                 body.push(WasmInst::LocalGet { idx: 1 });
+                body.push(WasmInst::I64Const { value: target_pc as i64 });
+                body.push(WasmInst::I64Ne); // skip if NOT equal
+                body.push(WasmInst::BrIf { label: 0 }); // break out of block
+                body.push(WasmInst::I32Const { value: target_pc as i32 });
+                body.push(WasmInst::Return);
+                body.push(WasmInst::End);
             }
 
+            // Fallback: return computed target
+            body.push(WasmInst::LocalGet { idx: 1 });
+            body.push(WasmInst::I32WrapI64);
             body.push(WasmInst::Return);
         }
 
@@ -2356,16 +3077,18 @@ fn add_terminator_return(
         Opcode::ECALL => {
             // Return special value to signal syscall
             // High bit set + PC
+            emit_clear_reservation(body);
             body.push(WasmInst::I32Const {
-                value: 0x80000000u32 as i32 | (inst.addr as i32),
+                value: crate::abi::ECALL_FLAG as i32 | (inst.addr as i32),
             });
             body.push(WasmInst::Return);
         }
 
         Opcode::EBREAK | Opcode::C_EBREAK => {
             // Return special value to signal breakpoint
+            emit_clear_reservation(body);
             body.push(WasmInst::I32Const {
-                value: 0xC0000000u32 as i32 | (inst.addr as i32),
+                value: crate::abi::EBREAK_FLAG as i32 | (inst.addr as i32),
             });
             body.push(WasmInst::Return);
         }
@@ -2394,20 +3117,36 @@ fn emit_branch_compare(
 ) {
     let target = (pc as i64 + imm) as u64;
 
-    // Load rs1 and rs2, compare
-    body.push(WasmInst::LocalGet { idx: 0 });
-    body.push(WasmInst::I64Load { offset: rs1 * 8 });
-    body.push(WasmInst::LocalGet { idx: 0 });
-    body.push(WasmInst::I64Load { offset: rs2 * 8 });
-    body.push(cmp_op);
+    // Against x0: equality is a test for zero, and some comparisons are
+    // decided statically (x < 0 unsigned never holds)
+    let decided = match (cmp_op, rs1, rs2) {
+        (WasmInst::I64Eq | WasmInst::I64GeS | WasmInst::I64GeU, 0, 0) | (WasmInst::I64GeU, _, 0) => Some(target),
+        (WasmInst::I64Ne | WasmInst::I64LtS | WasmInst::I64LtU, 0, 0) | (WasmInst::I64LtU, _, 0) => Some(fallthrough),
+        (WasmInst::I64Eq | WasmInst::I64Ne, _, 0) | (WasmInst::I64Eq | WasmInst::I64Ne, 0, _) => {
+            let on_zero = cmp_op == WasmInst::I64Eq;
+            emit_branch_zero(body, rs1.max(rs2), imm, pc, fallthrough, on_zero);
+            return;
+        }
+        _ => None,
+    };
+    if let Some(next) = decided {
+        body.push(WasmInst::I32Const { value: next as i32 });
+        body.push(WasmInst::Return);
+        return;
+    }
 
-    // if-else: return target or fallthrough
+    // select(target, fallthrough, rs1 <cmp> rs2): the condition goes last
     body.push(WasmInst::I32Const {
         value: target as i32,
     });
     body.push(WasmInst::I32Const {
         value: fallthrough as i32,
     });
+    body.push(WasmInst::LocalGet { idx: 0 });
+    body.push(WasmInst::I64Load { offset: rs1 * 8 });
+    body.push(WasmInst::LocalGet { idx: 0 });
+    body.push(WasmInst::I64Load { offset: rs2 * 8 });
+    body.push(cmp_op);
     body.push(WasmInst::Select);
     body.push(WasmInst::Return);
 }
@@ -2416,31 +3155,98 @@ fn emit_branch_compare(
 fn emit_branch_zero(body: &mut Vec<WasmInst>, rs1: u32, imm: i64, pc: u64, fallthrough: u64, on_zero: bool) {
     let target = (pc as i64 + imm) as u64;
 
+    // select(if_zero, if_nonzero, rs1 == 0)
+    let (if_zero, if_nonzero) = if on_zero { (target, fallthrough) } else { (fallthrough, target) };
+    body.push(WasmInst::I32Const {
+        value: if_zero as i32,
+    });
+    body.push(WasmInst::I32Const {
+        value: if_nonzero as i32,
+    });
     body.push(WasmInst::LocalGet { idx: 0 });
     body.push(WasmInst::I64Load { offset: rs1 * 8 });
     body.push(WasmInst::I64Eqz);
+    body.push(WasmInst::Select);
+    body.push(WasmInst::Return);
+}
 
-    if on_zero {
-        // beqz: if zero, jump to target
-        body.push(WasmInst::I32Const {
-            value: target as i32,
-        });
-        body.push(WasmInst::I32Const {
-            value: fallthrough as i32,
-        });
-    } else {
-        // bnez: if nonzero, jump to target
-        body.push(WasmInst::I32Const {
-            value: fallthrough as i32,
-        });
-        body.push(WasmInst::I32Const {
-            value: target as i32,
-        });
+/// If `set` is SLT/SLTU/SLTI/SLTIU and `branch` tests its result against
+/// zero, whether the branch is taken when the result is 1
+fn fused_branch_sense(set: &Instruction, branch: &Instruction) -> Option<bool> {
+    if !matches!(set.opcode, Opcode::SLT | Opcode::SLTU | Opcode::SLTI | Opcode::SLTIU) {
+        return None;
     }
+    let rd = set.rd.filter(|&rd| rd != 0)?;
+    let rs1 = branch.rs1.unwrap_or(0);
+    let rs2 = branch.rs2.unwrap_or(0);
+    let tests_rd = match branch.opcode {
+        Opcode::BEQ | Opcode::BNE => (rs1 == rd && rs2 == 0) || (rs1 == 0 && rs2 == rd),
+        Opcode::C_BEQZ | Opcode::C_BNEZ => rs1 == rd,
+        _ => false,
+    };
+    tests_rd.then_some(matches!(branch.opcode, Opcode::BNE | Opcode::C_BNEZ))
+}
+
+/// Emit `set` and the branch on its result as one comparison: the 0/1 is
+/// still written to rd, but the branch selects on the value kept in local 1
+/// instead of reloading rd.
+fn emit_fused_branch(body: &mut Vec<WasmInst>, set: &Instruction, branch: &Instruction, fallthrough: u64, taken_if_set: bool) {
+    let target = (branch.addr as i64 + branch.imm.unwrap_or(0)) as u64;
+    let rd = set.rd.unwrap_or(0) as u32;
+
+    body.push(WasmInst::LocalGet { idx: 0 });
+    body.push(WasmInst::LocalGet { idx: 0 });
+    body.push(WasmInst::I64Load { offset: set.rs1.unwrap_or(0) as u32 * 8 });
+    match set.opcode {
+        Opcode::SLT | Opcode::SLTU => {
+            body.push(WasmInst::LocalGet { idx: 0 });
+            body.push(WasmInst::I64Load { offset: set.rs2.unwrap_or(0) as u32 * 8 });
+        }
+        _ => body.push(WasmInst::I64Const { value: set.imm.unwrap_or(0) }),
+    }
+    body.push(match set.opcode {
+        Opcode::SLT | Opcode::SLTI => WasmInst::I64LtS,
+        _ => WasmInst::I64LtU,
+    });
+    body.push(WasmInst::I64ExtendI32U);
+    body.push(WasmInst::LocalTee { idx: 1 });
+    body.push(WasmInst::I64Store { offset: rd * 8 });
+
+    let (if_set, if_clear) = if taken_if_set { (target, fallthrough) } else { (fallthrough, target) };
+    body.push(WasmInst::I32Const { value: if_set as i32 });
+    body.push(WasmInst::I32Const { value: if_clear as i32 });
+    body.push(WasmInst::LocalGet { idx: 1 });
+    body.push(WasmInst::I32WrapI64);
     body.push(WasmInst::Select);
     body.push(WasmInst::Return);
 }
 
+/// Charge the `count` instructions of the block at `pc` to the budget, or
+/// stop there with `abi::STOP_BUDGET` (and `pc` for `get_pc`) if it has
+/// fewer left
+fn emit_meter(body: &mut Vec<WasmInst>, pc: u64, count: i64) {
+    use WasmInst::*;
+    body.extend([Block { label: 0 }, LocalGet { idx: 0 }, I64Load { offset: BUDGET_OFFSET }]);
+    body.extend([I64Const { value: count }, I64GeU, BrIf { label: 0 }]);
+    body.extend([LocalGet { idx: 0 }, I64Const { value: pc as i64 }, I64Store { offset: PC_OFFSET }]);
+    body.extend([I32Const { value: crate::abi::STOP_BUDGET as i32 }, Return, End]);
+    body.extend([LocalGet { idx: 0 }, LocalGet { idx: 0 }, I64Load { offset: BUDGET_OFFSET }]);
+    body.extend([I64Const { value: count }, I64Sub, I64Store { offset: BUDGET_OFFSET }]);
+}
+
+/// Ask the host whether to stop before the instruction at `pc`
+/// (`env.breakpoint`), returning as EBREAK there if so
+fn emit_breakpoint(body: &mut Vec<WasmInst>, pc: u64) {
+    body.push(WasmInst::Block { label: 0 });
+    body.push(WasmInst::I32Const { value: pc as i32 });
+    body.push(WasmInst::Call { func_idx: crate::link::BREAKPOINT_FUNC });
+    body.push(WasmInst::I32Eqz);
+    body.push(WasmInst::BrIf { label: 0 });
+    body.push(WasmInst::I32Const { value: crate::abi::EBREAK_FLAG as i32 | (pc as i32) });
+    body.push(WasmInst::Return);
+    body.push(WasmInst::End);
+}
+
 /// Translate CFG to Wasm module for JIT mode.
 ///
 /// Differences from AOT `translate()`:
@@ -2451,37 +3257,199 @@ pub fn translate_jit(
     cfg: &ControlFlowGraph,
     base_addr: u64,
 ) -> Result<WasmModule> {
-    let mut functions = Vec::new();
-    let mut block_to_func = std::collections::HashMap::new();
-    let block_addrs: Vec<u64> = cfg.blocks.keys().copied().collect();
-
-    for (_addr, block) in cfg.blocks.iter() {
-        let func = translate_block(block, functions.len(), false, &block_addrs)?;
-        block_to_func.insert(block.start_addr, functions.len());
-        functions.push(func);
-    }
+    translate_jit_with(cfg, base_addr, &CompileOptions::default())
+}
 
-    // Optimize
-    for func in &mut functions {
-        optimize_function(func);
-    }
+/// `translate_jit` with block functions translated under `options` (a
+/// watchpoint or breakpoints, say); module-level options do not apply
+pub fn translate_jit_with(cfg: &ControlFlowGraph, base_addr: u64, options: &CompileOptions) -> Result<WasmModule> {
+    options.validate()?;
+    let block_addrs: Vec<u64> = cfg.blocks.keys().copied().collect();
+    let blocks: Vec<&BasicBlock> = cfg.blocks.values().collect();
+    let functions = translate_blocks(&blocks, options, &block_addrs, &Frequencies::default(), true)?;
+    let block_to_func = block_addrs.iter().enumerate().map(|(idx, &addr)| (addr, idx)).collect();
 
     Ok(WasmModule {
         functions,
         memory_pages: 0, // JIT modules import memory; pages set by host
         entry: base_addr,
         block_to_func,
+        threads: false,
+        exports: Vec::new(),
+        groups: Vec::new(),
+        data: Default::default(),
+        size: false,
+        block_exports: None,
+        memory_offset: 0,
+        soft_mmu: None,
+        time_page: None,
+        lazy_compile: false,
+        unknown_pc: UnknownPc::Halt,
+        jspi: false,
+        trap_exceptions: false,
+        harts: 1,
+        watch: options.watch.clone(),
+        breakpoints: !options.breakpoints.is_empty(),
+        interpose: crate::interpose::import_names(options),
+        meter: options.meter,
+        max_pages: None,
+        image: Vec::new(),
     })
 }
 
-/// Basic peephole optimizations
+/// The `opt_level >= 2` passes: register forwarding, constant
+/// propagation, CSE, then cleanup of dead tees and redundant extensions
 fn optimize_function(func: &mut WasmFunction) {
-    // Remove consecutive LocalGet of same index
-    // Remove dead stores
-    // etc.
-
-    // For now, just remove Comment instructions in release mode
+    // Comments only annotate debug output
     func.body.retain(|inst| !matches!(inst, WasmInst::Comment { .. }));
+
+    let scratch_locals = func.num_locals;
+    crate::opt::forward_registers(func);
+    crate::opt::propagate_constants(func);
+    crate::opt::eliminate_common_subexpressions(func);
+    crate::opt::remove_dead_tees(func, scratch_locals + 1);
+    crate::opt::fold_extensions(&mut func.body);
+}
+
+/// Division with RISC-V semantics: x / 0 = -1 and x % 0 = x; signed
+/// overflow (MIN / -1) gives MIN and remainder 0. Wasm traps on both, so
+/// the divisor is replaced with 1 in those cases and the zero-divisor
+/// result is selected afterwards. `word` operates on the low 32 bits and
+/// sign-extends the result. Uses i64 locals 1-2 as scratch.
+fn emit_div(body: &mut Vec<WasmInst>, rd_offset: u32, rs1_offset: u32, rs2_offset: u32, word: bool, signed: bool, rem: bool) {
+    let get = |body: &mut Vec<WasmInst>, idx: u32| {
+        body.push(WasmInst::LocalGet { idx });
+        if word {
+            body.push(WasmInst::I32WrapI64);
+        }
+    };
+    let konst = |body: &mut Vec<WasmInst>, value: i64| {
+        body.push(if word { WasmInst::I32Const { value: value as i32 } } else { WasmInst::I64Const { value } });
+    };
+    let pick = |i32_op: WasmInst, i64_op: WasmInst| if word { i32_op } else { i64_op };
+
+    body.push(WasmInst::LocalGet { idx: 0 });
+    body.push(WasmInst::I64Load { offset: rs1_offset });
+    body.push(WasmInst::LocalSet { idx: 1 });
+    body.push(WasmInst::LocalGet { idx: 0 });
+    body.push(WasmInst::I64Load { offset: rs2_offset });
+    body.push(WasmInst::LocalSet { idx: 2 });
+
+    body.push(WasmInst::LocalGet { idx: 0 });
+    // Result for a zero divisor
+    if rem {
+        get(body, 1);
+    } else {
+        konst(body, -1);
+    }
+    // rs1 op (divisor is 0 or overflow ? 1 : rs2)
+    get(body, 1);
+    konst(body, 1);
+    get(body, 2);
+    get(body, 2);
+    body.push(pick(WasmInst::I32Eqz, WasmInst::I64Eqz));
+    if signed {
+        get(body, 1);
+        konst(body, if word { i32::MIN as i64 } else { i64::MIN });
+        body.push(pick(WasmInst::I32Eq, WasmInst::I64Eq));
+        get(body, 2);
+        konst(body, -1);
+        body.push(pick(WasmInst::I32Eq, WasmInst::I64Eq));
+        body.push(WasmInst::I32And);
+        body.push(WasmInst::I32Or);
+    }
+    body.push(WasmInst::Select);
+    body.push(match (word, signed, rem) {
+        (false, true, false) => WasmInst::I64DivS,
+        (false, false, false) => WasmInst::I64DivU,
+        (false, true, true) => WasmInst::I64RemS,
+        (false, false, true) => WasmInst::I64RemU,
+        (true, true, false) => WasmInst::I32DivS,
+        (true, false, false) => WasmInst::I32DivU,
+        (true, true, true) => WasmInst::I32RemS,
+        (true, false, true) => WasmInst::I32RemU,
+    });
+    get(body, 2);
+    body.push(pick(WasmInst::I32Eqz, WasmInst::I64Eqz));
+    body.push(WasmInst::Select);
+    if word {
+        body.push(WasmInst::I64ExtendI32S);
+    }
+    body.push(WasmInst::I64Store { offset: rd_offset });
+}
+
+/// Upper 64 bits of rs1 * rs2 (MULH, MULHSU, MULHU).
+///
+/// The unsigned product is assembled from 32x32 partial products; each
+/// signed operand then subtracts the other operand when negative.
+/// Uses i64 locals 1-4 as scratch (a, b, middle sum, hi(a) * lo(b)).
+fn emit_mulh(body: &mut Vec<WasmInst>, rd_offset: u32, rs1_offset: u32, rs2_offset: u32, signed_a: bool, signed_b: bool) {
+    let lo = |body: &mut Vec<WasmInst>, idx: u32| {
+        body.push(WasmInst::LocalGet { idx });
+        body.push(WasmInst::I64Const { value: 0xffff_ffff });
+        body.push(WasmInst::I64And);
+    };
+    let hi = |body: &mut Vec<WasmInst>, idx: u32| {
+        body.push(WasmInst::LocalGet { idx });
+        body.push(WasmInst::I64Const { value: 32 });
+        body.push(WasmInst::I64ShrU);
+    };
+
+    body.push(WasmInst::LocalGet { idx: 0 });
+    body.push(WasmInst::I64Load { offset: rs1_offset });
+    body.push(WasmInst::LocalSet { idx: 1 });
+    body.push(WasmInst::LocalGet { idx: 0 });
+    body.push(WasmInst::I64Load { offset: rs2_offset });
+    body.push(WasmInst::LocalSet { idx: 2 });
+
+    // mid = (lo(a) * lo(b)) >> 32 + lo(hi(a) * lo(b)) + lo(lo(a) * hi(b))
+    lo(body, 1);
+    lo(body, 2);
+    body.push(WasmInst::I64Mul);
+    body.push(WasmInst::I64Const { value: 32 });
+    body.push(WasmInst::I64ShrU);
+    hi(body, 1);
+    lo(body, 2);
+    body.push(WasmInst::I64Mul);
+    body.push(WasmInst::LocalTee { idx: 4 });
+    body.push(WasmInst::I64Const { value: 0xffff_ffff });
+    body.push(WasmInst::I64And);
+    body.push(WasmInst::I64Add);
+    lo(body, 1);
+    hi(body, 2);
+    body.push(WasmInst::I64Mul);
+    body.push(WasmInst::I64Const { value: 0xffff_ffff });
+    body.push(WasmInst::I64And);
+    body.push(WasmInst::I64Add);
+    body.push(WasmInst::LocalSet { idx: 3 });
+
+    // hi(a) * hi(b) + (hi(a) * lo(b)) >> 32 + (lo(a) * hi(b)) >> 32 + mid >> 32
+    body.push(WasmInst::LocalGet { idx: 0 });
+    hi(body, 1);
+    hi(body, 2);
+    body.push(WasmInst::I64Mul);
+    hi(body, 4);
+    body.push(WasmInst::I64Add);
+    lo(body, 1);
+    hi(body, 2);
+    body.push(WasmInst::I64Mul);
+    body.push(WasmInst::I64Const { value: 32 });
+    body.push(WasmInst::I64ShrU);
+    body.push(WasmInst::I64Add);
+    hi(body, 3);
+    body.push(WasmInst::I64Add);
+
+    for (signed, neg, other) in [(signed_a, 1, 2), (signed_b, 2, 1)] {
+        if signed {
+            body.push(WasmInst::LocalGet { idx: neg });
+            body.push(WasmInst::I64Const { value: 63 });
+            body.push(WasmInst::I64ShrS);
+            body.push(WasmInst::LocalGet { idx: other });
+            body.push(WasmInst::I64And);
+            body.push(WasmInst::I64Sub);
+        }
+    }
+    body.push(WasmInst::I64Store { offset: rd_offset });
 }
 
 /// Helper for atomic word operations (XOR, AND, OR)
@@ -2632,3 +3600,659 @@ fn emit_amo_minmax_d(body: &mut Vec<WasmInst>, rd: u32, rs1_offset: u32, rs2_off
     // Store result to M[rs1]
     body.push(WasmInst::I64Store { offset: 0 });
 }
+
+/// Threads-mode lowering for the A extension and its LR/SC reservations.
+///
+/// AMOs map onto Wasm atomic RMW where one exists; min/max use a cmpxchg
+/// retry loop. LR records (address, value) in the machine state and SC
+/// succeeds only if the reservation matches and the cmpxchg against the
+/// reserved value wins. Returns false for non-atomic instructions.
+/// Uses i64 locals 1-3 as scratch (address, old value, operand).
+fn translate_atomic_threaded(inst: &Instruction, body: &mut Vec<WasmInst>) -> bool {
+    let rd = inst.rd.unwrap_or(0) as u32;
+    let rs1 = inst.rs1.unwrap_or(0) as u32;
+    let rs2 = inst.rs2.unwrap_or(0) as u32;
+
+    match inst.opcode {
+        Opcode::AMOSWAP_W => emit_atomic_rmw(body, rd, rs1, rs2, false, WasmInst::I32AtomicRmwXchg { offset: 0 }),
+        Opcode::AMOADD_W => emit_atomic_rmw(body, rd, rs1, rs2, false, WasmInst::I32AtomicRmwAdd { offset: 0 }),
+        Opcode::AMOAND_W => emit_atomic_rmw(body, rd, rs1, rs2, false, WasmInst::I32AtomicRmwAnd { offset: 0 }),
+        Opcode::AMOOR_W => emit_atomic_rmw(body, rd, rs1, rs2, false, WasmInst::I32AtomicRmwOr { offset: 0 }),
+        Opcode::AMOXOR_W => emit_atomic_rmw(body, rd, rs1, rs2, false, WasmInst::I32AtomicRmwXor { offset: 0 }),
+        Opcode::AMOSWAP_D => emit_atomic_rmw(body, rd, rs1, rs2, true, WasmInst::I64AtomicRmwXchg { offset: 0 }),
+        Opcode::AMOADD_D => emit_atomic_rmw(body, rd, rs1, rs2, true, WasmInst::I64AtomicRmwAdd { offset: 0 }),
+        Opcode::AMOAND_D => emit_atomic_rmw(body, rd, rs1, rs2, true, WasmInst::I64AtomicRmwAnd { offset: 0 }),
+        Opcode::AMOOR_D => emit_atomic_rmw(body, rd, rs1, rs2, true, WasmInst::I64AtomicRmwOr { offset: 0 }),
+        Opcode::AMOXOR_D => emit_atomic_rmw(body, rd, rs1, rs2, true, WasmInst::I64AtomicRmwXor { offset: 0 }),
+
+        Opcode::AMOMIN_W => emit_atomic_minmax(body, rd, rs1, rs2, false, WasmInst::I32LtS),
+        Opcode::AMOMAX_W => emit_atomic_minmax(body, rd, rs1, rs2, false, WasmInst::I32GtS),
+        Opcode::AMOMINU_W => emit_atomic_minmax(body, rd, rs1, rs2, false, WasmInst::I32LtU),
+        Opcode::AMOMAXU_W => emit_atomic_minmax(body, rd, rs1, rs2, false, WasmInst::I32GtU),
+        Opcode::AMOMIN_D => emit_atomic_minmax(body, rd, rs1, rs2, true, WasmInst::I64LtS),
+        Opcode::AMOMAX_D => emit_atomic_minmax(body, rd, rs1, rs2, true, WasmInst::I64GtS),
+        Opcode::AMOMINU_D => emit_atomic_minmax(body, rd, rs1, rs2, true, WasmInst::I64LtU),
+        Opcode::AMOMAXU_D => emit_atomic_minmax(body, rd, rs1, rs2, true, WasmInst::I64GtU),
+
+        Opcode::LR_W => emit_load_reserved(body, rd, rs1, false),
+        Opcode::LR_D => emit_load_reserved(body, rd, rs1, true),
+        Opcode::SC_W => emit_store_conditional(body, rd, rs1, rs2, false),
+        Opcode::SC_D => emit_store_conditional(body, rd, rs1, rs2, true),
+
+        Opcode::AMOCAS_W => emit_cas(body, rd, rs1, rs2, false, true),
+        Opcode::AMOCAS_D => emit_cas(body, rd, rs1, rs2, true, true),
+        // Wasm has no 128-bit atomics: report the instruction as unsupported
+        Opcode::AMOCAS_Q => emit_illegal_trap(body, inst.addr),
+
+        op => match op.narrow_amo() {
+            Some((word, width)) => emit_amo_narrow(body, word, width, rd, rs1, rs2, true),
+            None => return false,
+        },
+    }
+    true
+}
+
+/// Push the guest address in rs1 as an i32 memory address
+fn push_atomic_addr(body: &mut Vec<WasmInst>, rs1: u32) {
+    body.push(WasmInst::LocalGet { idx: 0 });
+    body.push(WasmInst::I64Load { offset: rs1 * 8 });
+    body.push(WasmInst::I32WrapI64);
+}
+
+/// rd = M[rs1]; M[rs1] = M[rs1] op rs2, as a single Wasm atomic RMW
+fn emit_atomic_rmw(body: &mut Vec<WasmInst>, rd: u32, rs1: u32, rs2: u32, double: bool, op: WasmInst) {
+    if rd != 0 {
+        body.push(WasmInst::LocalGet { idx: 0 });
+    }
+    push_atomic_addr(body, rs1);
+    body.push(WasmInst::LocalGet { idx: 0 });
+    body.push(WasmInst::I64Load { offset: rs2 * 8 });
+    if !double {
+        body.push(WasmInst::I32WrapI64);
+    }
+    body.push(op);
+    if rd != 0 {
+        if !double {
+            body.push(WasmInst::I64ExtendI32S);
+        }
+        body.push(WasmInst::I64Store { offset: rd * 8 });
+    } else {
+        body.push(WasmInst::Drop);
+    }
+}
+
+/// rd = M[rs1]; M[rs1] = select(old, rs2, old cmp rs2), via a cmpxchg loop
+fn emit_atomic_minmax(body: &mut Vec<WasmInst>, rd: u32, rs1: u32, rs2: u32, double: bool, cmp_op: WasmInst) {
+    // Operands narrowed to the access width (no-op for doublewords)
+    let narrow = |body: &mut Vec<WasmInst>, idx: u32| {
+        body.push(WasmInst::LocalGet { idx });
+        if !double {
+            body.push(WasmInst::I32WrapI64);
+        }
+    };
+
+    // local1 = address, local3 = rs2 (captured before rd is written)
+    body.push(WasmInst::LocalGet { idx: 0 });
+    body.push(WasmInst::I64Load { offset: rs1 * 8 });
+    body.push(WasmInst::LocalSet { idx: 1 });
+    body.push(WasmInst::LocalGet { idx: 0 });
+    body.push(WasmInst::I64Load { offset: rs2 * 8 });
+    body.push(WasmInst::LocalSet { idx: 3 });
+
+    body.push(WasmInst::Loop { label: 0 });
+    // local2 = current memory value
+    narrow_addr(body);
+    if double {
+        body.push(WasmInst::I64AtomicLoad { offset: 0 });
+    } else {
+        body.push(WasmInst::I32AtomicLoad { offset: 0 });
+        body.push(WasmInst::I64ExtendI32S);
+    }
+    body.push(WasmInst::LocalSet { idx: 2 });
+
+    // cmpxchg(addr, old, select(old, rs2, old cmp rs2))
+    narrow_addr(body);
+    narrow(body, 2);
+    narrow(body, 2);
+    narrow(body, 3);
+    narrow(body, 2);
+    narrow(body, 3);
+    body.push(cmp_op);
+    body.push(WasmInst::Select);
+    if double {
+        body.push(WasmInst::I64AtomicRmwCmpxchg { offset: 0 });
+    } else {
+        body.push(WasmInst::I32AtomicRmwCmpxchg { offset: 0 });
+        body.push(WasmInst::I64ExtendI32S);
+    }
+    // Retry if another hart changed the value in between
+    body.push(WasmInst::LocalGet { idx: 2 });
+    body.push(WasmInst::I64Ne);
+    body.push(WasmInst::BrIf { label: 0 });
+    body.push(WasmInst::End);
+
+    if rd != 0 {
+        body.push(WasmInst::LocalGet { idx: 0 });
+        body.push(WasmInst::LocalGet { idx: 2 });
+        body.push(WasmInst::I64Store { offset: rd * 8 });
+    }
+}
+
+/// Threads mode spin-wait: block the worker on `memory.atomic.wait32` for a
+/// bounded time. WRS.* waits on the reserved word (returning at once if it
+/// no longer holds the reserved value, or without a reservation); PAUSE on
+/// a machine-state word nobody notifies, so it always sleeps the timeout.
+fn emit_spin_wait(body: &mut Vec<WasmInst>, opcode: Opcode) {
+    let timeout = match opcode {
+        Opcode::PAUSE => PAUSE_WAIT_NS,
+        Opcode::WRS_STO => WRS_STO_WAIT_NS,
+        _ => WRS_NTO_WAIT_NS,
+    };
+    if opcode == Opcode::PAUSE {
+        body.push(WasmInst::LocalGet { idx: 0 });
+        body.push(WasmInst::LocalGet { idx: 0 });
+        body.push(WasmInst::I32Load { offset: TRAP_PC_OFFSET });
+        body.push(WasmInst::I64Const { value: timeout });
+        body.push(WasmInst::MemoryAtomicWait32 { offset: TRAP_PC_OFFSET });
+        body.push(WasmInst::Drop);
+        return;
+    }
+
+    body.push(WasmInst::Block { label: 0 });
+    body.push(WasmInst::LocalGet { idx: 0 });
+    body.push(WasmInst::I64Load { offset: RESERVATION_ADDR_OFFSET });
+    body.push(WasmInst::I64Const { value: -1 });
+    body.push(WasmInst::I64Eq);
+    body.push(WasmInst::BrIf { label: 0 });
+    body.push(WasmInst::LocalGet { idx: 0 });
+    body.push(WasmInst::I64Load { offset: RESERVATION_ADDR_OFFSET });
+    body.push(WasmInst::I32WrapI64);
+    body.push(WasmInst::LocalGet { idx: 0 });
+    body.push(WasmInst::I64Load { offset: RESERVATION_VALUE_OFFSET });
+    body.push(WasmInst::I32WrapI64);
+    body.push(WasmInst::I64Const { value: timeout });
+    body.push(WasmInst::MemoryAtomicWait32 { offset: 0 });
+    body.push(WasmInst::Drop);
+    body.push(WasmInst::End);
+}
+
+/// Zknh: rd = the xor of three rotations (or two and a shift) of rs1; the
+/// SHA-256 forms work on the low word and sign-extend the result
+fn emit_sha2(body: &mut Vec<WasmInst>, opcode: Opcode, rd_offset: u32, rs1_offset: u32) {
+    // (amount, rotate rather than shift)
+    let terms: [(i64, bool); 3] = match opcode {
+        Opcode::SHA256SIG0 => [(7, true), (18, true), (3, false)],
+        Opcode::SHA256SIG1 => [(17, true), (19, true), (10, false)],
+        Opcode::SHA256SUM0 => [(2, true), (13, true), (22, true)],
+        Opcode::SHA256SUM1 => [(6, true), (11, true), (25, true)],
+        Opcode::SHA512SIG0 => [(1, true), (8, true), (7, false)],
+        Opcode::SHA512SIG1 => [(19, true), (61, true), (6, false)],
+        Opcode::SHA512SUM0 => [(28, true), (34, true), (39, true)],
+        _ => [(14, true), (18, true), (41, true)],
+    };
+    let wide = matches!(
+        opcode,
+        Opcode::SHA512SIG0 | Opcode::SHA512SIG1 | Opcode::SHA512SUM0 | Opcode::SHA512SUM1
+    );
+
+    body.push(WasmInst::LocalGet { idx: 0 });
+    for (i, (amount, rotate)) in terms.into_iter().enumerate() {
+        body.push(WasmInst::LocalGet { idx: 0 });
+        body.push(WasmInst::I64Load { offset: rs1_offset });
+        if wide {
+            body.push(WasmInst::I64Const { value: amount });
+            body.push(if rotate { WasmInst::I64Rotr } else { WasmInst::I64ShrU });
+        } else {
+            body.push(WasmInst::I32WrapI64);
+            body.push(WasmInst::I32Const { value: amount as i32 });
+            body.push(if rotate { WasmInst::I32Rotr } else { WasmInst::I32ShrU });
+        }
+        if i > 0 {
+            body.push(if wide { WasmInst::I64Xor } else { WasmInst::I32Xor });
+        }
+    }
+    if !wide {
+        body.push(WasmInst::I64ExtendI32S);
+    }
+    body.push(WasmInst::I64Store { offset: rd_offset });
+}
+
+/// Return `abi::ILLEGAL_TRAP` with trap PC `pc`
+pub(crate) fn emit_illegal_trap(body: &mut Vec<WasmInst>, pc: u64) {
+    emit_clear_reservation(body);
+    body.push(WasmInst::LocalGet { idx: 0 });
+    body.push(WasmInst::I64Const { value: pc as i64 });
+    body.push(WasmInst::I64Store { offset: TRAP_PC_OFFSET });
+    body.push(WasmInst::I32Const { value: crate::abi::ILLEGAL_TRAP as i32 });
+    body.push(WasmInst::Return);
+}
+
+/// Return `abi::MISALIGNED_TRAP` (trap PC `pc`, trap value x[rs1]) unless
+/// x[rs1] is a multiple of `size`
+fn emit_alignment_check(body: &mut Vec<WasmInst>, pc: u64, rs1_offset: u32, size: u64) {
+    body.push(WasmInst::Block { label: 0 });
+    body.push(WasmInst::LocalGet { idx: 0 });
+    body.push(WasmInst::I64Load { offset: rs1_offset });
+    body.push(WasmInst::I64Const { value: size as i64 - 1 });
+    body.push(WasmInst::I64And);
+    body.push(WasmInst::I64Eqz);
+    body.push(WasmInst::BrIf { label: 0 });
+    body.push(WasmInst::LocalGet { idx: 0 });
+    body.push(WasmInst::LocalGet { idx: 0 });
+    body.push(WasmInst::I64Load { offset: rs1_offset });
+    body.push(WasmInst::I64Store { offset: TRAP_VALUE_OFFSET });
+    body.push(WasmInst::LocalGet { idx: 0 });
+    body.push(WasmInst::I64Const { value: pc as i64 });
+    body.push(WasmInst::I64Store { offset: TRAP_PC_OFFSET });
+    emit_clear_reservation(body);
+    body.push(WasmInst::I32Const { value: crate::abi::MISALIGNED_TRAP as i32 });
+    body.push(WasmInst::Return);
+    body.push(WasmInst::End);
+}
+
+/// AMOCAS.W/D: old = M[rs1]; if old == rd (at the access width),
+/// M[rs1] = rs2; rd = old. `atomic` uses a Wasm cmpxchg.
+/// Uses i64 locals 1-4 as scratch (address, expected, new, old).
+fn emit_cas(body: &mut Vec<WasmInst>, rd: u32, rs1: u32, rs2: u32, double: bool, atomic: bool) {
+    let narrow = |body: &mut Vec<WasmInst>, idx: u32| {
+        body.push(WasmInst::LocalGet { idx });
+        if !double {
+            body.push(WasmInst::I32WrapI64);
+        }
+    };
+    for (reg, local) in [(rs1, 1), (rd, 2), (rs2, 3)] {
+        body.push(WasmInst::LocalGet { idx: 0 });
+        body.push(WasmInst::I64Load { offset: reg * 8 });
+        body.push(WasmInst::LocalSet { idx: local });
+    }
+
+    narrow_addr(body);
+    if atomic {
+        narrow(body, 2);
+        narrow(body, 3);
+        body.push(if double {
+            WasmInst::I64AtomicRmwCmpxchg { offset: 0 }
+        } else {
+            WasmInst::I32AtomicRmwCmpxchg { offset: 0 }
+        });
+    } else {
+        body.push(if double { WasmInst::I64Load { offset: 0 } } else { WasmInst::I32Load { offset: 0 } });
+    }
+    if !double {
+        body.push(WasmInst::I64ExtendI32S);
+    }
+    body.push(WasmInst::LocalSet { idx: 4 });
+
+    if !atomic {
+        body.push(WasmInst::Block { label: 0 });
+        narrow(body, 4);
+        narrow(body, 2);
+        body.push(if double { WasmInst::I64Ne } else { WasmInst::I32Ne });
+        body.push(WasmInst::BrIf { label: 0 });
+        narrow_addr(body);
+        narrow(body, 3);
+        body.push(if double { WasmInst::I64Store { offset: 0 } } else { WasmInst::I32Store { offset: 0 } });
+        body.push(WasmInst::End);
+    }
+
+    if rd != 0 {
+        body.push(WasmInst::LocalGet { idx: 0 });
+        body.push(WasmInst::LocalGet { idx: 4 });
+        body.push(WasmInst::I64Store { offset: rd * 8 });
+    }
+}
+
+/// Zabha: the byte/halfword (`width`) form of the word AMO `word`; rd gets
+/// the sign-extended old value. `atomic` retries a narrow Wasm cmpxchg
+/// until it wins (AMOCAS is a single cmpxchg).
+/// Uses i64 locals 1-4 as scratch (address, old, rs2, expected).
+fn emit_amo_narrow(body: &mut Vec<WasmInst>, word: Opcode, width: u64, rd: u32, rs1: u32, rs2: u32, atomic: bool) {
+    let mask = (1i64 << (width * 8)) - 1;
+    let sext = if width == 1 { WasmInst::I64Extend8S } else { WasmInst::I64Extend16S };
+    let cmpxchg = if width == 1 {
+        WasmInst::I64AtomicRmw8CmpxchgU { offset: 0 }
+    } else {
+        WasmInst::I64AtomicRmw16CmpxchgU { offset: 0 }
+    };
+    let masked = |body: &mut Vec<WasmInst>, idx: u32| {
+        body.push(WasmInst::LocalGet { idx });
+        body.push(WasmInst::I64Const { value: mask });
+        body.push(WasmInst::I64And);
+    };
+
+    // local3 = rs2 as the comparison needs it: signed, unsigned or raw
+    for (reg, local) in [(rs1, 1), (rs2, 3), (rd, 4)] {
+        body.push(WasmInst::LocalGet { idx: 0 });
+        body.push(WasmInst::I64Load { offset: reg * 8 });
+        body.push(WasmInst::LocalSet { idx: local });
+    }
+    match word {
+        Opcode::AMOMIN_W | Opcode::AMOMAX_W => {
+            body.push(WasmInst::LocalGet { idx: 3 });
+            body.push(sext);
+            body.push(WasmInst::LocalSet { idx: 3 });
+        }
+        Opcode::AMOMINU_W | Opcode::AMOMAXU_W => {
+            masked(body, 3);
+            body.push(WasmInst::LocalSet { idx: 3 });
+        }
+        _ => {}
+    }
+
+    // The new value from old (local2) and rs2 (local3)
+    let new_value = |body: &mut Vec<WasmInst>| match word {
+        Opcode::AMOSWAP_W => body.push(WasmInst::LocalGet { idx: 3 }),
+        Opcode::AMOADD_W | Opcode::AMOXOR_W | Opcode::AMOAND_W | Opcode::AMOOR_W => {
+            body.push(WasmInst::LocalGet { idx: 2 });
+            body.push(WasmInst::LocalGet { idx: 3 });
+            body.push(match word {
+                Opcode::AMOADD_W => WasmInst::I64Add,
+                Opcode::AMOXOR_W => WasmInst::I64Xor,
+                Opcode::AMOAND_W => WasmInst::I64And,
+                _ => WasmInst::I64Or,
+            });
+        }
+        Opcode::AMOCAS_W => {
+            // rs2 if the old value matches rd, else the old value
+            body.push(WasmInst::LocalGet { idx: 3 });
+            body.push(WasmInst::LocalGet { idx: 2 });
+            masked(body, 2);
+            masked(body, 4);
+            body.push(WasmInst::I64Eq);
+            body.push(WasmInst::Select);
+        }
+        _ => {
+            let (signed, cmp) = match word {
+                Opcode::AMOMIN_W => (true, WasmInst::I64LtS),
+                Opcode::AMOMAX_W => (true, WasmInst::I64GtS),
+                Opcode::AMOMINU_W => (false, WasmInst::I64LtU),
+                _ => (false, WasmInst::I64GtU),
+            };
+            for _ in 0..2 {
+                if signed {
+                    body.push(WasmInst::LocalGet { idx: 2 });
+                } else {
+                    masked(body, 2);
+                }
+                body.push(WasmInst::LocalGet { idx: 3 });
+            }
+            body.push(cmp);
+            body.push(WasmInst::Select);
+        }
+    };
+
+    if !atomic {
+        narrow_addr(body);
+        body.push(if width == 1 { WasmInst::I64Load8S { offset: 0 } } else { WasmInst::I64Load16S { offset: 0 } });
+        body.push(WasmInst::LocalSet { idx: 2 });
+        narrow_addr(body);
+        new_value(body);
+        body.push(if width == 1 { WasmInst::I64Store8 { offset: 0 } } else { WasmInst::I64Store16 { offset: 0 } });
+    } else if word == Opcode::AMOCAS_W {
+        narrow_addr(body);
+        body.push(WasmInst::LocalGet { idx: 4 });
+        body.push(WasmInst::LocalGet { idx: 3 });
+        body.push(cmpxchg);
+        body.push(sext);
+        body.push(WasmInst::LocalSet { idx: 2 });
+    } else {
+        body.push(WasmInst::Loop { label: 0 });
+        narrow_addr(body);
+        body.push(if width == 1 {
+            WasmInst::I64AtomicLoad8U { offset: 0 }
+        } else {
+            WasmInst::I64AtomicLoad16U { offset: 0 }
+        });
+        body.push(sext);
+        body.push(WasmInst::LocalSet { idx: 2 });
+        narrow_addr(body);
+        body.push(WasmInst::LocalGet { idx: 2 });
+        new_value(body);
+        body.push(cmpxchg);
+        masked(body, 2);
+        body.push(WasmInst::I64Ne);
+        body.push(WasmInst::BrIf { label: 0 });
+        body.push(WasmInst::End);
+    }
+
+    if rd != 0 {
+        body.push(WasmInst::LocalGet { idx: 0 });
+        body.push(WasmInst::LocalGet { idx: 2 });
+        body.push(WasmInst::I64Store { offset: rd * 8 });
+    }
+}
+
+/// AMOCAS.Q (single-threaded): compare the 128-bit M[rs1] with rd/rd+1 and
+/// store rs2/rs2+1 if equal; rd/rd+1 = old. x0 as a pair reads as zero.
+/// Uses i64 locals 1-3 as scratch (address, old low, old high).
+fn emit_cas_q(body: &mut Vec<WasmInst>, rd: u32, rs1: u32, rs2: u32) {
+    let pair = |body: &mut Vec<WasmInst>, reg: u32, half: u32| {
+        if reg == 0 {
+            body.push(WasmInst::I64Const { value: 0 });
+        } else {
+            body.push(WasmInst::LocalGet { idx: 0 });
+            body.push(WasmInst::I64Load { offset: (reg + half) * 8 });
+        }
+    };
+    body.push(WasmInst::LocalGet { idx: 0 });
+    body.push(WasmInst::I64Load { offset: rs1 * 8 });
+    body.push(WasmInst::LocalSet { idx: 1 });
+    for (half, local) in [(0, 2), (1, 3)] {
+        narrow_addr(body);
+        body.push(WasmInst::I64Load { offset: half * 8 });
+        body.push(WasmInst::LocalSet { idx: local });
+    }
+
+    body.push(WasmInst::Block { label: 0 });
+    for (half, local) in [(0, 2), (1, 3)] {
+        body.push(WasmInst::LocalGet { idx: local });
+        pair(body, rd, half);
+        body.push(WasmInst::I64Ne);
+        body.push(WasmInst::BrIf { label: 0 });
+    }
+    for half in [0, 1] {
+        narrow_addr(body);
+        pair(body, rs2, half);
+        body.push(WasmInst::I64Store { offset: half * 8 });
+    }
+    body.push(WasmInst::End);
+
+    if rd != 0 {
+        for (half, local) in [(0, 2), (1, 3)] {
+            body.push(WasmInst::LocalGet { idx: 0 });
+            body.push(WasmInst::LocalGet { idx: local });
+            body.push(WasmInst::I64Store { offset: (rd + half) * 8 });
+        }
+    }
+}
+
+/// Drop the LR reservation (address -1). Done by SC and on every return
+/// to the host for an ECALL or trap, which may deliver a signal.
+fn emit_clear_reservation(body: &mut Vec<WasmInst>) {
+    body.push(WasmInst::LocalGet { idx: 0 });
+    body.push(WasmInst::I64Const { value: -1 });
+    body.push(WasmInst::I64Store { offset: RESERVATION_ADDR_OFFSET });
+}
+
+/// Push local1 (the captured guest address) as an i32 memory address
+fn narrow_addr(body: &mut Vec<WasmInst>) {
+    body.push(WasmInst::LocalGet { idx: 1 });
+    body.push(WasmInst::I32WrapI64);
+}
+
+/// LR: rd = M[rs1] (atomic), reservation = (rs1, value)
+fn emit_load_reserved(body: &mut Vec<WasmInst>, rd: u32, rs1: u32, double: bool) {
+    body.push(WasmInst::LocalGet { idx: 0 });
+    body.push(WasmInst::I64Load { offset: rs1 * 8 });
+    body.push(WasmInst::LocalSet { idx: 1 });
+
+    narrow_addr(body);
+    if double {
+        body.push(WasmInst::I64AtomicLoad { offset: 0 });
+    } else {
+        body.push(WasmInst::I32AtomicLoad { offset: 0 });
+        body.push(WasmInst::I64ExtendI32S);
+    }
+    body.push(WasmInst::LocalSet { idx: 2 });
+
+    body.push(WasmInst::LocalGet { idx: 0 });
+    body.push(WasmInst::LocalGet { idx: 1 });
+    body.push(WasmInst::I64Store { offset: RESERVATION_ADDR_OFFSET });
+    body.push(WasmInst::LocalGet { idx: 0 });
+    body.push(WasmInst::LocalGet { idx: 2 });
+    body.push(WasmInst::I64Store { offset: RESERVATION_VALUE_OFFSET });
+
+    if rd != 0 {
+        body.push(WasmInst::LocalGet { idx: 0 });
+        body.push(WasmInst::LocalGet { idx: 2 });
+        body.push(WasmInst::I64Store { offset: rd * 8 });
+    }
+}
+
+/// SC: if the reservation covers rs1 and M[rs1] still holds the reserved
+/// value, atomically store rs2 and set rd = 0; otherwise rd = 1.
+/// The reservation is always cleared.
+fn emit_store_conditional(body: &mut Vec<WasmInst>, rd: u32, rs1: u32, rs2: u32, double: bool) {
+    body.push(WasmInst::LocalGet { idx: 0 });
+    body.push(WasmInst::I64Load { offset: rs1 * 8 });
+    body.push(WasmInst::LocalSet { idx: 1 });
+    body.push(WasmInst::LocalGet { idx: 0 });
+    body.push(WasmInst::I64Load { offset: rs2 * 8 });
+    body.push(WasmInst::LocalSet { idx: 3 });
+
+    // local2 = 1 (failure) until the exchange succeeds
+    body.push(WasmInst::I64Const { value: 1 });
+    body.push(WasmInst::LocalSet { idx: 2 });
+
+    body.push(WasmInst::Block { label: 0 });
+    body.push(WasmInst::LocalGet { idx: 0 });
+    body.push(WasmInst::I64Load { offset: RESERVATION_ADDR_OFFSET });
+    body.push(WasmInst::LocalGet { idx: 1 });
+    body.push(WasmInst::I64Ne);
+    body.push(WasmInst::BrIf { label: 0 });
+
+    narrow_addr(body);
+    body.push(WasmInst::LocalGet { idx: 0 });
+    body.push(WasmInst::I64Load { offset: RESERVATION_VALUE_OFFSET });
+    if !double {
+        body.push(WasmInst::I32WrapI64);
+    }
+    body.push(WasmInst::LocalGet { idx: 3 });
+    if double {
+        body.push(WasmInst::I64AtomicRmwCmpxchg { offset: 0 });
+    } else {
+        body.push(WasmInst::I32WrapI64);
+        body.push(WasmInst::I32AtomicRmwCmpxchg { offset: 0 });
+        body.push(WasmInst::I64ExtendI32S);
+    }
+    body.push(WasmInst::LocalGet { idx: 0 });
+    body.push(WasmInst::I64Load { offset: RESERVATION_VALUE_OFFSET });
+    body.push(WasmInst::I64Ne);
+    body.push(WasmInst::BrIf { label: 0 });
+
+    body.push(WasmInst::I64Const { value: 0 });
+    body.push(WasmInst::LocalSet { idx: 2 });
+    body.push(WasmInst::End);
+
+    emit_clear_reservation(body);
+
+    if rd != 0 {
+        body.push(WasmInst::LocalGet { idx: 0 });
+        body.push(WasmInst::LocalGet { idx: 2 });
+        body.push(WasmInst::I64Store { offset: rd * 8 });
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_util::inst;
+
+    #[test]
+    fn test_fused_branch_sense() {
+        let slt = inst(0x1000, Opcode::SLT, 5, 6, 7, 0);
+        // BNE/C.BNEZ are taken when the result is 1, BEQ/C.BEQZ when it is 0
+        assert_eq!(fused_branch_sense(&slt, &inst(0x1004, Opcode::BNE, 0, 5, 0, 8)), Some(true));
+        assert_eq!(fused_branch_sense(&slt, &inst(0x1004, Opcode::BEQ, 0, 0, 5, 8)), Some(false));
+        assert_eq!(fused_branch_sense(&slt, &inst(0x1004, Opcode::C_BNEZ, 0, 5, 0, 8)), Some(true));
+        assert_eq!(fused_branch_sense(&slt, &inst(0x1004, Opcode::C_BEQZ, 0, 5, 0, 8)), Some(false));
+        // Only a compare against zero of the result fuses
+        assert_eq!(fused_branch_sense(&slt, &inst(0x1004, Opcode::BNE, 0, 5, 6, 8)), None);
+        assert_eq!(fused_branch_sense(&slt, &inst(0x1004, Opcode::BNE, 0, 6, 0, 8)), None);
+        assert_eq!(fused_branch_sense(&slt, &inst(0x1004, Opcode::BLT, 0, 5, 0, 8)), None);
+        let to_x0 = inst(0x1000, Opcode::SLT, 0, 6, 7, 0);
+        assert_eq!(fused_branch_sense(&to_x0, &inst(0x1004, Opcode::BNE, 0, 0, 0, 8)), None);
+        let add = inst(0x1000, Opcode::ADD, 5, 6, 7, 0);
+        assert_eq!(fused_branch_sense(&add, &inst(0x1004, Opcode::BNE, 0, 5, 0, 8)), None);
+    }
+
+    #[test]
+    fn test_emit_fused_branch() {
+        let sltiu = inst(0x1000, Opcode::SLTIU, 5, 6, 0, 10);
+        // A 4-byte BNE falls through to 0x1008, a 2-byte C.BEQZ to 0x1006;
+        // both jump to 0x1014
+        for (branch, fallthrough) in
+            [(inst(0x1004, Opcode::BNE, 0, 5, 0, 0x10), 0x1008), (inst(0x1004, Opcode::C_BEQZ, 0, 5, 0, 0x10), 0x1006)]
+        {
+            let taken_if_set = fused_branch_sense(&sltiu, &branch).unwrap();
+            let mut body = Vec::new();
+            emit_fused_branch(&mut body, &sltiu, &branch, fallthrough, taken_if_set);
+            // The result is still written to rd
+            assert_eq!(
+                body[..7],
+                [
+                    WasmInst::LocalGet { idx: 0 },
+                    WasmInst::LocalGet { idx: 0 },
+                    WasmInst::I64Load { offset: 6 * 8 },
+                    WasmInst::I64Const { value: 10 },
+                    WasmInst::I64LtU,
+                    WasmInst::I64ExtendI32U,
+                    WasmInst::LocalTee { idx: 1 },
+                ]
+            );
+            assert_eq!(body[7], WasmInst::I64Store { offset: 5 * 8 });
+            // select(if_set, if_clear, result)
+            let fallthrough = fallthrough as i32;
+            let (if_set, if_clear) = if taken_if_set { (0x1014, fallthrough) } else { (fallthrough, 0x1014) };
+            assert_eq!(
+                body[8..],
+                [
+                    WasmInst::I32Const { value: if_set },
+                    WasmInst::I32Const { value: if_clear },
+                    WasmInst::LocalGet { idx: 1 },
+                    WasmInst::I32WrapI64,
+                    WasmInst::Select,
+                    WasmInst::Return,
+                ]
+            );
+        }
+    }
+
+    #[test]
+    fn test_fp_csrs() {
+        let run = |memory: &mut Vec<u8>, inst: Instruction| {
+            let mut body = Vec::new();
+            translate_instruction(&inst, &mut body, &CompileOptions::default()).unwrap();
+            body.extend([WasmInst::I32Const { value: 0 }, WasmInst::Return]);
+            let func = WasmFunction { name: "csr".into(), block_addr: 0, body, num_locals: 0, br_tables: Vec::new() };
+            crate::eval::eval(&func, memory, 0).unwrap();
+        };
+        let word = |memory: &[u8], offset: u32| u64::from_le_bytes(memory[offset as usize..][..8].try_into().unwrap());
+        let mut memory = vec![0u8; MACHINE_STATE_SIZE as usize];
+        memory[FCSR_OFFSET as usize] = 0x1f;
+
+        // fsrmi a1, 3: frm lands above fflags, a1 gets the old (zero) frm
+        memory[88] = 0xff;
+        run(&mut memory, inst(0, Opcode::CSRRWI, 11, 3, 0, 0x002));
+        assert_eq!((word(&memory, FCSR_OFFSET), word(&memory, 88)), (0x7f, 0));
+        // fscsr a0, a0 swaps, keeping only 8 bits
+        memory[80..88].copy_from_slice(&0x1234u64.to_le_bytes());
+        run(&mut memory, inst(0, Opcode::CSRRW, 10, 10, 0, 0x003));
+        assert_eq!((word(&memory, FCSR_OFFSET), word(&memory, 80)), (0x34, 0x7f));
+        // csrci fflags, 0x14 clears bits; frflags with x0 as the source only reads
+        run(&mut memory, inst(0, Opcode::CSRRCI, 12, 0x14, 0, 0x001));
+        assert_eq!((word(&memory, FCSR_OFFSET), word(&memory, 96)), (0x20, 0x14));
+        run(&mut memory, inst(0, Opcode::CSRRS, 12, 0, 0, 0x002));
+        assert_eq!((word(&memory, FCSR_OFFSET), word(&memory, 96)), (0x20, 1));
+    }
+}
@@ -0,0 +1,524 @@
+// unwind.rs - .eh_frame call frame information
+//
+// Parses the DWARF CFI in a program's .eh_frame so a host can unwind the
+// guest stack without frame pointers: for a PC, `EhFrame::row` runs the
+// covering FDE's instructions up to it and says where the canonical frame
+// address (the caller's sp) is and where each register was saved.
+//
+// Only what compilers emit for RISC-V is understood: CFAs as register plus
+// offset, registers saved at an offset from the CFA or in another register,
+// and pc-relative or absolute pointer encodings. DWARF expressions make a
+// row unusable (`Rule::Unsupported`, or `row` returning None for the CFA),
+// and the caller falls back to frame pointers.
+
+use crate::elf::ElfFile;
+use anyhow::{bail, Context, Result};
+use std::ops::Range;
+
+/// Return address register (ra)
+pub const RA: u16 = 1;
+/// Stack pointer (sp)
+pub const SP: u16 = 2;
+
+/// Where a register's value in the caller is
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Rule {
+    /// Not saved: the caller's value is the current one
+    SameValue,
+    /// Not recoverable
+    Undefined,
+    /// Saved in memory at CFA + offset
+    Offset(i64),
+    /// The value is CFA + offset
+    ValOffset(i64),
+    /// Saved in another register
+    Register(u16),
+    /// Described by a DWARF expression
+    Unsupported,
+}
+
+/// The unwind rules in effect at one PC
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Row {
+    pub cfa_reg: u16,
+    pub cfa_offset: i64,
+    /// Register holding the return address (normally ra)
+    pub ra_reg: u16,
+    rules: Vec<(u16, Rule)>,
+}
+
+impl Row {
+    pub fn rule(&self, reg: u16) -> Rule {
+        self.rules.iter().rev().find(|(r, _)| *r == reg).map_or(Rule::SameValue, |&(_, rule)| rule)
+    }
+
+    fn set(&mut self, reg: u16, rule: Rule) {
+        self.rules.retain(|(r, _)| *r != reg);
+        self.rules.push((reg, rule));
+    }
+}
+
+struct Cie {
+    code_align: u64,
+    data_align: i64,
+    ra_reg: u16,
+    /// Pointer encoding of FDE addresses (augmentation 'R')
+    fde_encoding: u8,
+    has_augmentation_data: bool,
+    instructions: Range<usize>,
+}
+
+struct Fde {
+    start: u64,
+    end: u64,
+    cie: usize,
+    instructions: Range<usize>,
+}
+
+/// A parsed .eh_frame section
+pub struct EhFrame {
+    data: Vec<u8>,
+    /// Address the section is loaded at
+    addr: u64,
+    cies: Vec<Cie>,
+    /// Sorted by start address
+    fdes: Vec<Fde>,
+}
+
+/// Little-endian DWARF reader over a section loaded at `base`
+struct Reader<'a> {
+    data: &'a [u8],
+    pos: usize,
+    base: u64,
+}
+
+impl Reader<'_> {
+    fn bytes(&mut self, n: usize) -> Result<&[u8]> {
+        let bytes = self.data.get(self.pos..self.pos + n).context("Truncated .eh_frame")?;
+        self.pos += n;
+        Ok(bytes)
+    }
+
+    fn u8(&mut self) -> Result<u8> {
+        Ok(self.bytes(1)?[0])
+    }
+
+    fn uint(&mut self, n: usize) -> Result<u64> {
+        let mut word = [0u8; 8];
+        word[..n].copy_from_slice(self.bytes(n)?);
+        Ok(u64::from_le_bytes(word))
+    }
+
+    fn sint(&mut self, n: usize) -> Result<i64> {
+        let shift = 64 - 8 * n as u32;
+        Ok((self.uint(n)? << shift) as i64 >> shift)
+    }
+
+    fn uleb(&mut self) -> Result<u64> {
+        let (mut value, mut shift) = (0u64, 0);
+        loop {
+            let byte = self.u8()?;
+            if shift < 64 {
+                value |= ((byte & 0x7f) as u64) << shift;
+            }
+            shift += 7;
+            if byte & 0x80 == 0 {
+                return Ok(value);
+            }
+        }
+    }
+
+    fn sleb(&mut self) -> Result<i64> {
+        let (mut value, mut shift) = (0i64, 0);
+        loop {
+            let byte = self.u8()?;
+            if shift < 64 {
+                value |= ((byte & 0x7f) as i64) << shift;
+            }
+            shift += 7;
+            if byte & 0x80 == 0 {
+                if shift < 64 && byte & 0x40 != 0 {
+                    value |= -1 << shift;
+                }
+                return Ok(value);
+            }
+        }
+    }
+
+    fn cstr(&mut self) -> Result<&[u8]> {
+        let len = self.data[self.pos..].iter().position(|&b| b == 0).context("Unterminated .eh_frame string")?;
+        let s = &self.data[self.pos..self.pos + len];
+        self.pos += len + 1;
+        Ok(s)
+    }
+
+    /// A pointer in DW_EH_PE encoding `enc`
+    fn pointer(&mut self, enc: u8) -> Result<u64> {
+        let here = self.base.wrapping_add(self.pos as u64);
+        let value = match enc & 0x0f {
+            0x00 | 0x04 => self.uint(8)?,
+            0x01 => self.uleb()?,
+            0x02 => self.uint(2)?,
+            0x03 => self.uint(4)?,
+            0x09 => self.sleb()? as u64,
+            0x0a => self.sint(2)? as u64,
+            0x0b => self.sint(4)? as u64,
+            0x0c => self.sint(8)? as u64,
+            _ => bail!("Unsupported pointer encoding 0x{:x}", enc),
+        };
+        match enc & 0x70 {
+            0x00 => Ok(value),
+            0x10 => Ok(here.wrapping_add(value)),
+            _ => bail!("Unsupported pointer encoding 0x{:x}", enc),
+        }
+    }
+}
+
+impl EhFrame {
+    /// Parse the contents of an .eh_frame loaded at `addr`
+    pub fn parse(data: &[u8], addr: u64) -> Result<Self> {
+        let mut frame = EhFrame { data: data.to_vec(), addr, cies: Vec::new(), fdes: Vec::new() };
+        // CIEs by section offset, parsed as FDEs name them
+        let mut cie_at = std::collections::HashMap::new();
+        let mut r = Reader { data, pos: 0, base: addr };
+        while r.pos + 4 <= data.len() {
+            let start = r.pos;
+            let mut len = r.uint(4)?;
+            if len == 0 {
+                break;
+            }
+            let wide = len == 0xffff_ffff;
+            if wide {
+                len = r.uint(8)?;
+            }
+            let id_pos = r.pos;
+            let end = id_pos.checked_add(len as usize).filter(|&end| end <= data.len());
+            let end = end.context("Truncated .eh_frame")?;
+            let id = r.uint(if wide { 8 } else { 4 })?;
+            if id == 0 {
+                cie_at.insert(start, frame.cies.len());
+                frame.cies.push(Self::parse_cie(&mut r, end)?);
+            } else {
+                let cie_pos = id_pos.checked_sub(id as usize).context("Bad CIE pointer in .eh_frame")?;
+                let &cie = cie_at.get(&cie_pos).context("FDE before its CIE in .eh_frame")?;
+                let enc = frame.cies[cie].fde_encoding;
+                let begin = r.pointer(enc)?;
+                let range = r.pointer(enc & 0x0f)?;
+                if frame.cies[cie].has_augmentation_data {
+                    let skip = r.uleb()? as usize;
+                    r.bytes(skip)?;
+                }
+                // Zero-length FDEs are left by discarded sections
+                if range != 0 {
+                    let instructions = r.pos..end;
+                    frame.fdes.push(Fde { start: begin, end: begin.wrapping_add(range), cie, instructions });
+                }
+            }
+            r.pos = end;
+        }
+        frame.fdes.sort_by_key(|f| f.start);
+        Ok(frame)
+    }
+
+    fn parse_cie(r: &mut Reader, end: usize) -> Result<Cie> {
+        let version = r.u8()?;
+        let augmentation = r.cstr()?.to_vec();
+        if augmentation.starts_with(b"eh") {
+            r.uint(8)?;
+        }
+        let code_align = r.uleb()?;
+        let data_align = r.sleb()?;
+        let ra_reg = if version == 1 { r.u8()? as u64 } else { r.uleb()? } as u16;
+        let mut cie = Cie {
+            code_align,
+            data_align,
+            ra_reg,
+            fde_encoding: 0,
+            has_augmentation_data: augmentation.first() == Some(&b'z'),
+            instructions: 0..0,
+        };
+        if cie.has_augmentation_data {
+            let len = r.uleb()? as usize;
+            let data_end = r.pos + len;
+            for &c in &augmentation[1..] {
+                match c {
+                    b'R' => cie.fde_encoding = r.u8()?,
+                    b'L' => {
+                        r.u8()?;
+                    }
+                    b'P' => {
+                        let enc = r.u8()?;
+                        r.pointer(enc)?;
+                    }
+                    // 'S' (signal frame) and anything newer carry no data here
+                    _ => {}
+                }
+            }
+            r.pos = data_end;
+        }
+        cie.instructions = r.pos..end;
+        Ok(cie)
+    }
+
+    /// The .eh_frame of `file`, at its biased address
+    pub fn from_elf(file: &ElfFile) -> Result<Option<Self>> {
+        let elf = file.elf();
+        let Some(section) = elf.section_headers.iter().find(|s| elf.shdr_strtab.get_at(s.sh_name) == Some(".eh_frame"))
+        else {
+            return Ok(None);
+        };
+        let range = section.file_range().context("Empty .eh_frame")?;
+        let data = file.data().get(range).context("Truncated .eh_frame")?;
+        Self::parse(data, section.sh_addr + file.load_bias()).map(Some)
+    }
+
+    /// Move the section and the functions it describes by `bias`, for an
+    /// object loaded there. Absolute DW_CFA_set_loc targets stay put, but
+    /// only non-PIE code (bias 0) uses them.
+    pub fn with_bias(mut self, bias: u64) -> Self {
+        self.addr = self.addr.wrapping_add(bias);
+        for fde in &mut self.fdes {
+            fde.start = fde.start.wrapping_add(bias);
+            fde.end = fde.end.wrapping_add(bias);
+        }
+        self
+    }
+
+    /// Number of functions described
+    pub fn len(&self) -> usize {
+        self.fdes.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.fdes.is_empty()
+    }
+
+    /// Whether an FDE covers `pc`
+    pub fn covers(&self, pc: u64) -> bool {
+        self.fde(pc).is_some()
+    }
+
+    fn fde(&self, pc: u64) -> Option<&Fde> {
+        let idx = self.fdes.partition_point(|f| f.start <= pc);
+        self.fdes[idx.checked_sub(1)?..].first().filter(|f| pc < f.end)
+    }
+
+    /// The unwind rules at `pc`, if an FDE covers it and its CFA is a
+    /// register plus offset
+    pub fn row(&self, pc: u64) -> Option<Row> {
+        let fde = self.fde(pc)?;
+        let cie = &self.cies[fde.cie];
+        let mut row = Row { cfa_reg: SP, cfa_offset: 0, ra_reg: cie.ra_reg, rules: Vec::new() };
+        let mut loc = fde.start;
+        self.execute(cie, cie.instructions.clone(), &mut row, None, &mut loc, pc).ok()?;
+        let initial = row.clone();
+        self.execute(cie, fde.instructions.clone(), &mut row, Some(&initial), &mut loc, pc).ok()?;
+        (row.cfa_reg != u16::MAX).then_some(row)
+    }
+
+    /// Run CFA instructions until the location passes `pc`. `initial` is
+    /// the CIE's row, which DW_CFA_restore returns to.
+    fn execute(
+        &self,
+        cie: &Cie,
+        range: Range<usize>,
+        row: &mut Row,
+        initial: Option<&Row>,
+        loc: &mut u64,
+        pc: u64,
+    ) -> Result<()> {
+        let base = self.addr.wrapping_add(range.start as u64);
+        let mut r = Reader { data: &self.data[range], pos: 0, base };
+        let mut stack = Vec::new();
+        let (ca, da) = (cie.code_align, cie.data_align);
+        let restore = |row: &mut Row, reg: u16| {
+            let rule = initial.map_or(Rule::SameValue, |init| init.rule(reg));
+            row.set(reg, rule);
+        };
+        while r.pos < r.data.len() {
+            let op = r.u8()?;
+            let advance = match op >> 6 {
+                1 => Some((op & 0x3f) as u64),
+                2 => {
+                    let offset = r.uleb()? as i64 * da;
+                    row.set((op & 0x3f) as u16, Rule::Offset(offset));
+                    None
+                }
+                3 => {
+                    restore(row, (op & 0x3f) as u16);
+                    None
+                }
+                _ => match op {
+                    0x00 => None,
+                    0x01 => {
+                        let to = r.pointer(cie.fde_encoding)?;
+                        if to > pc {
+                            return Ok(());
+                        }
+                        *loc = to;
+                        None
+                    }
+                    0x02 => Some(r.uint(1)?),
+                    0x03 => Some(r.uint(2)?),
+                    0x04 => Some(r.uint(4)?),
+                    0x05 => {
+                        let (reg, offset) = (r.uleb()? as u16, r.uleb()? as i64 * da);
+                        row.set(reg, Rule::Offset(offset));
+                        None
+                    }
+                    0x06 => {
+                        restore(row, r.uleb()? as u16);
+                        None
+                    }
+                    0x07 => {
+                        row.set(r.uleb()? as u16, Rule::Undefined);
+                        None
+                    }
+                    0x08 => {
+                        row.set(r.uleb()? as u16, Rule::SameValue);
+                        None
+                    }
+                    0x09 => {
+                        let (reg, from) = (r.uleb()? as u16, r.uleb()? as u16);
+                        row.set(reg, Rule::Register(from));
+                        None
+                    }
+                    0x0a => {
+                        stack.push((row.cfa_reg, row.cfa_offset, row.rules.clone()));
+                        None
+                    }
+                    0x0b => {
+                        let state = stack.pop().context("DW_CFA_restore_state without remember")?;
+                        (row.cfa_reg, row.cfa_offset, row.rules) = state;
+                        None
+                    }
+                    0x0c => {
+                        (row.cfa_reg, row.cfa_offset) = (r.uleb()? as u16, r.uleb()? as i64);
+                        None
+                    }
+                    0x0d => {
+                        row.cfa_reg = r.uleb()? as u16;
+                        None
+                    }
+                    0x0e => {
+                        row.cfa_offset = r.uleb()? as i64;
+                        None
+                    }
+                    0x0f => {
+                        let len = r.uleb()? as usize;
+                        r.bytes(len)?;
+                        row.cfa_reg = u16::MAX;
+                        None
+                    }
+                    0x10 | 0x16 => {
+                        let reg = r.uleb()? as u16;
+                        let len = r.uleb()? as usize;
+                        r.bytes(len)?;
+                        row.set(reg, Rule::Unsupported);
+                        None
+                    }
+                    0x11 => {
+                        let (reg, offset) = (r.uleb()? as u16, r.sleb()? * da);
+                        row.set(reg, Rule::Offset(offset));
+                        None
+                    }
+                    0x12 => {
+                        (row.cfa_reg, row.cfa_offset) = (r.uleb()? as u16, r.sleb()? * da);
+                        None
+                    }
+                    0x13 => {
+                        row.cfa_offset = r.sleb()? * da;
+                        None
+                    }
+                    0x14 => {
+                        let (reg, offset) = (r.uleb()? as u16, r.uleb()? as i64 * da);
+                        row.set(reg, Rule::ValOffset(offset));
+                        None
+                    }
+                    0x15 => {
+                        let (reg, offset) = (r.uleb()? as u16, r.sleb()? * da);
+                        row.set(reg, Rule::ValOffset(offset));
+                        None
+                    }
+                    // DW_CFA_GNU_args_size
+                    0x2e => {
+                        r.uleb()?;
+                        None
+                    }
+                    // DW_CFA_GNU_negative_offset_extended
+                    0x2f => {
+                        let (reg, offset) = (r.uleb()? as u16, -(r.uleb()? as i64) * da);
+                        row.set(reg, Rule::Offset(offset));
+                        None
+                    }
+                    _ => bail!("Unknown CFA instruction 0x{:x}", op),
+                },
+            };
+            if let Some(delta) = advance {
+                let to = loc.wrapping_add(delta * ca);
+                if to > pc {
+                    return Ok(());
+                }
+                *loc = to;
+            }
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// A CIE (zR, pc-relative sdata4 FDE pointers) and one FDE for a
+    /// function at 0x1000 with a 16-byte frame: ra at CFA-8, s0 at CFA-16,
+    /// then the CFA moved to s0
+    fn eh_frame(section: u64) -> Vec<u8> {
+        let cie = [
+            &[0u8, 0, 0, 0, 1][..],
+            b"zR\0",
+            &[1, 0x78, 1, 1, 0x1b],
+            // def_cfa sp, 0
+            &[0x0c, 2, 0],
+        ]
+        .concat();
+        let mut out = Vec::new();
+        out.extend((cie.len() as u32).to_le_bytes());
+        out.extend(&cie);
+        let fde_start = out.len();
+        let begin_field = section + fde_start as u64 + 8;
+        let body = [
+            &((fde_start + 4) as u32).to_le_bytes()[..],
+            &(0x1000i64 - begin_field as i64).to_le_bytes()[..4],
+            &0x20u32.to_le_bytes(),
+            &[0],
+            // advance 4; def_cfa_offset 16; ra at cfa-8; s0 at cfa-16
+            &[0x44, 0x0e, 16, 0x81, 1, 0x88, 2],
+            // advance 8; def_cfa s0, 0; remember; advance 12; restore ra; advance 4; restore_state
+            &[0x48, 0x0c, 8, 0, 0x0a, 0x4c, 0xc1, 0x44, 0x0b],
+        ]
+        .concat();
+        out.extend((body.len() as u32).to_le_bytes());
+        out.extend(body);
+        out.extend(0u32.to_le_bytes());
+        out
+    }
+
+    #[test]
+    fn test_eh_frame_rows() {
+        let frame = EhFrame::parse(&eh_frame(0x8000), 0x8000).unwrap();
+        assert_eq!(frame.len(), 1);
+        assert!(!frame.covers(0x1020) && !frame.covers(0xfff));
+
+        let row = frame.row(0x1000).unwrap();
+        assert_eq!((row.cfa_reg, row.cfa_offset, row.rule(RA)), (SP, 0, Rule::SameValue));
+        let row = frame.row(0x1008).unwrap();
+        assert_eq!((row.cfa_reg, row.cfa_offset), (SP, 16));
+        assert_eq!((row.rule(RA), row.rule(8)), (Rule::Offset(-8), Rule::Offset(-16)));
+        let row = frame.row(0x100c).unwrap();
+        assert_eq!((row.cfa_reg, row.cfa_offset, row.rule(RA)), (8, 0, Rule::Offset(-8)));
+        // Between `restore ra` and `restore_state`
+        assert_eq!(frame.row(0x1018).unwrap().rule(RA), Rule::SameValue);
+        assert_eq!(frame.row(0x101c).unwrap().rule(RA), Rule::Offset(-8));
+    }
+}
@@ -0,0 +1,158 @@
+// vdso.rs - Time page fast path for clock_gettime and gettimeofday
+//
+// With `CompileOptions::time_page` the host keeps the current time in a
+// page of guest memory, like the kernel's vDSO data page. An ECALL whose a7
+// is set to clock_gettime or gettimeofday earlier in its block reads the
+// page instead of returning to the dispatch loop:
+//
+//   abi::TIME_SEQ_OFFSET        u64 sequence count
+//   abi::TIME_MONOTONIC_OFFSET  u64 CLOCK_MONOTONIC, ns
+//   abi::TIME_REALTIME_OFFSET   u64 CLOCK_REALTIME, ns
+//
+// The host makes the count odd, writes both clocks, then makes it even
+// again. The fast path reads the count, the clock and the count again, and
+// makes the syscall after all if the count was odd, changed in between or
+// is still 0 (never published). So do clock ids other than the realtime
+// and monotonic families, a null `tv` and a non-null `tz`.
+//
+// The page is only as fresh as the host keeps it: a guest spinning on the
+// clock sees it advance at the host's refresh rate.
+
+use crate::abi::{TIME_MONOTONIC_OFFSET, TIME_REALTIME_OFFSET, TIME_SEQ_OFFSET};
+use crate::cfg::BasicBlock;
+use crate::disasm::Opcode;
+use crate::translate::WasmInst;
+
+const SYS_CLOCK_GETTIME: i64 = 113;
+const SYS_GETTIMEOFDAY: i64 = 169;
+
+/// Clock ids (bit n = id n) read from CLOCK_REALTIME: REALTIME, REALTIME_COARSE
+const REALTIME_CLOCKS: i64 = 1 << 0 | 1 << 5;
+/// ... and from CLOCK_MONOTONIC: MONOTONIC, MONOTONIC_RAW, MONOTONIC_COARSE, BOOTTIME
+const MONOTONIC_CLOCKS: i64 = 1 << 1 | 1 << 4 | 1 << 6 | 1 << 7;
+
+const A0: u32 = 10;
+const A1: u32 = 11;
+
+/// The syscall made by the ECALL ending `block`, if the block sets a7 to a
+/// constant before it
+fn syscall_number(block: &BasicBlock) -> Option<i64> {
+    let [before @ .., ecall] = block.instructions else {
+        return None;
+    };
+    if ecall.opcode != Opcode::ECALL {
+        return None;
+    }
+    let set = before.iter().rev().find(|inst| inst.rd == Some(17))?;
+    match set.opcode {
+        Opcode::ADDI | Opcode::C_LI if set.rs1 == Some(0) => set.imm,
+        _ => None,
+    }
+}
+
+/// If `block` ends in a time syscall, emit code answering it from the page
+/// at `page` and returning the next PC; when the page cannot answer, the
+/// code falls through to the ECALL. Returns whether anything was emitted.
+pub(crate) fn emit_fast_path(body: &mut Vec<WasmInst>, block: &BasicBlock, page: u32, threads: bool) -> bool {
+    use WasmInst::*;
+
+    let gettime = match syscall_number(block) {
+        Some(SYS_CLOCK_GETTIME) => true,
+        Some(SYS_GETTIMEOFDAY) => false,
+        _ => return false,
+    };
+    // Shared memory: the host updates the page from another thread
+    let load = |offset: u32| if threads { I64AtomicLoad { offset } } else { I64Load { offset } };
+    let reg = |n: u32| [LocalGet { idx: 0 }, I64Load { offset: n * 8 }];
+
+    body.push(Block { label: 0 });
+    if gettime {
+        // local 1 = clock id
+        body.extend(reg(A0));
+        body.extend([LocalSet { idx: 1 }, LocalGet { idx: 1 }, I64Const { value: 8 }, I64GeU, BrIf { label: 0 }]);
+        body.extend([I64Const { value: REALTIME_CLOCKS | MONOTONIC_CLOCKS }, LocalGet { idx: 1 }, I64ShrU]);
+        body.extend([I64Const { value: 1 }, I64And, I64Eqz, BrIf { label: 0 }]);
+    } else {
+        body.extend(reg(A0));
+        body.push(I64Eqz);
+        body.extend(reg(A1));
+        body.extend([I64Eqz, I32Eqz, I32Or, BrIf { label: 0 }]);
+    }
+
+    // local 2 = sequence count, which must be even and non-zero
+    body.extend([I32Const { value: page as i32 }, load(TIME_SEQ_OFFSET), LocalTee { idx: 2 }]);
+    body.extend([I64Const { value: 1 }, I64And, I64Eqz, I32Eqz, LocalGet { idx: 2 }, I64Eqz, I32Or, BrIf { label: 0 }]);
+
+    // local 3 = the clock, in ns
+    body.extend([I32Const { value: page as i32 }, load(TIME_REALTIME_OFFSET)]);
+    if gettime {
+        body.extend([I32Const { value: page as i32 }, load(TIME_MONOTONIC_OFFSET)]);
+        body.extend([I64Const { value: REALTIME_CLOCKS }, LocalGet { idx: 1 }, I64ShrU, I64Const { value: 1 }, I64And]);
+        body.extend([I32WrapI64, Select]);
+    }
+    body.push(LocalSet { idx: 3 });
+    body.extend([I32Const { value: page as i32 }, load(TIME_SEQ_OFFSET)]);
+    body.extend([LocalGet { idx: 2 }, I64Ne, BrIf { label: 0 }]);
+
+    // Write the timespec (timeval) and return 0
+    let (dest, per_fraction) = if gettime { (A1, 1) } else { (A0, 1000) };
+    body.extend(reg(dest));
+    body.extend([I32WrapI64, LocalGet { idx: 3 }, I64Const { value: 1_000_000_000 }, I64DivU, I64Store { offset: 0 }]);
+    body.extend(reg(dest));
+    body.extend([I32WrapI64, LocalGet { idx: 3 }, I64Const { value: 1_000_000_000 }, I64RemU]);
+    if per_fraction != 1 {
+        body.extend([I64Const { value: per_fraction }, I64DivU]);
+    }
+    body.push(I64Store { offset: 8 });
+    body.extend([LocalGet { idx: 0 }, I64Const { value: 0 }, I64Store { offset: A0 * 8 }]);
+    body.extend([I32Const { value: block.end_addr as i32 }, Return, End]);
+    true
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::CompileOptions;
+    use crate::test_util::inst;
+
+    #[test]
+    fn test_clock_gettime_reads_time_page() {
+        // li a7, 113 ; ecall
+        let code = [inst(0x1000, Opcode::ADDI, 17, 0, 0, 113), inst(0x1004, Opcode::ECALL, 0, 0, 0, 0)];
+        let cfg = crate::cfg::build(&code, 0x1000).unwrap();
+        let options = CompileOptions { time_page: Some(0x2000), ..Default::default() };
+        let mut funcs = Vec::new();
+        crate::translate::translate_streaming(&cfg, &options, |f| {
+            funcs.push(f);
+            Ok(())
+        })
+        .unwrap();
+
+        let mut memory = vec![0u8; 0x4000];
+        let put = |memory: &mut [u8], addr: usize, value: u64| {
+            memory[addr..addr + 8].copy_from_slice(&value.to_le_bytes());
+        };
+        put(&mut memory, 0x2000, 2);
+        put(&mut memory, 0x2008, 5_123_456_789);
+        put(&mut memory, 0x2010, 1_700_000_000_000_000_007);
+        put(&mut memory, A0 as usize * 8, 1);
+        put(&mut memory, A1 as usize * 8, 0x3000);
+        let get = |memory: &[u8], addr: usize| u64::from_le_bytes(memory[addr..addr + 8].try_into().unwrap());
+
+        // CLOCK_MONOTONIC: answered in place
+        assert_eq!(crate::eval::eval(&funcs[0], &mut memory, 0).unwrap(), 0x1008);
+        assert_eq!((get(&memory, 0x3000), get(&memory, 0x3008), get(&memory, 80)), (5, 123_456_789, 0));
+
+        // CLOCK_REALTIME (id 0) while the host is mid-update: the syscall
+        put(&mut memory, 0x2000, 3);
+        let ecall = crate::abi::ECALL_FLAG as i32 | 0x1004;
+        assert_eq!(crate::eval::eval(&funcs[0], &mut memory, 0).unwrap(), ecall);
+        put(&mut memory, 0x2000, 4);
+        assert_eq!(crate::eval::eval(&funcs[0], &mut memory, 0).unwrap(), 0x1008);
+        assert_eq!((get(&memory, 0x3000), get(&memory, 0x3008)), (1_700_000_000, 7));
+
+        // CLOCK_PROCESS_CPUTIME_ID: the syscall
+        put(&mut memory, A0 as usize * 8, 2);
+        assert_eq!(crate::eval::eval(&funcs[0], &mut memory, 0).unwrap(), ecall);
+    }
+}
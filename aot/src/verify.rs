@@ -0,0 +1,334 @@
+// verify.rs - IR verifier
+//
+// Checks a block function's body the way a Wasm engine's validator would:
+// operand stack depth and types, block nesting and branch label depths,
+// local indices and br_table references. A codegen bug otherwise only shows
+// up as an engine validation error naming a function index and a byte
+// offset; this names the guest block, the IR position and (when known) the
+// guest instruction whose code is at fault.
+//
+// Runs when `CompileOptions::verify_ir` is set (the default in debug
+// builds), once on each block as translated, where every IR position maps
+// to its guest instruction, and again after the peephole passes.
+
+use crate::disasm::Opcode;
+use crate::link::{BREAKPOINT_FUNC, CRYPTO_FUNC, WATCH_FUNC, YIELD_FUNC};
+use crate::translate::{Note, WasmFunction, WasmInst};
+use anyhow::{bail, Result};
+
+/// A value type on the operand stack; `Any` stands for a value popped from
+/// the polymorphic stack of unreachable code
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Ty {
+    I32,
+    I64,
+    F32,
+    F64,
+    Any,
+}
+
+/// An open block: the stack height at its start and whether the rest of
+/// it is unreachable
+struct Frame {
+    height: usize,
+    unreachable: bool,
+}
+
+/// Where the code of each guest instruction starts in a body, in order
+pub type Origins = [(usize, u64, Opcode)];
+
+/// Verify `func`. `origins` maps IR positions to guest instructions; when
+/// empty, the body's `Note::Inst` comments (debug builds) are used instead.
+pub fn verify(func: &WasmFunction, origins: &Origins) -> Result<()> {
+    let mut v = Verifier { func, stack: Vec::new(), frames: vec![Frame { height: 0, unreachable: false }] };
+    for (i, &inst) in func.body.iter().enumerate() {
+        if let Err(e) = v.step(inst) {
+            bail!("block 0x{:x}: {} at IR #{} ({:?}){}", func.block_addr, e, i, inst, origin(func, origins, i));
+        }
+    }
+    // The implicit end of the function returns the dispatch PC
+    let end = v.frames.len();
+    let result = match end {
+        1 => v.pop(Ty::I32).and_then(|_| v.end()),
+        _ => Err(anyhow::anyhow!("{} blocks left open", end - 1)),
+    };
+    if let Err(e) = result {
+        bail!("block 0x{:x}: {} at the end of the body", func.block_addr, e);
+    }
+    Ok(())
+}
+
+/// ", guest instruction 0x... OPCODE" for IR position `i`, if known
+fn origin(func: &WasmFunction, origins: &Origins, i: usize) -> String {
+    let guest = match origins.partition_point(|&(start, _, _)| start <= i) {
+        0 if origins.is_empty() => func.body[..i].iter().rev().find_map(|inst| match *inst {
+            WasmInst::Comment { addr, note: Note::Inst(opcode) } => Some((addr, opcode)),
+            _ => None,
+        }),
+        0 => None,
+        n => Some((origins[n - 1].1, origins[n - 1].2)),
+    };
+    guest.map_or_else(String::new, |(addr, opcode)| format!(", guest instruction 0x{:x} {:?}", addr, opcode))
+}
+
+struct Verifier<'a> {
+    func: &'a WasmFunction,
+    stack: Vec<Ty>,
+    frames: Vec<Frame>,
+}
+
+impl Verifier<'_> {
+    fn pop(&mut self, want: Ty) -> Result<Ty> {
+        let frame = self.frames.last().unwrap();
+        if self.stack.len() == frame.height {
+            if frame.unreachable {
+                return Ok(want);
+            }
+            bail!("stack underflow (wanted {:?})", want);
+        }
+        let got = self.stack.pop().unwrap();
+        if got != want && got != Ty::Any && want != Ty::Any {
+            bail!("type mismatch: expected {:?}, found {:?}", want, got);
+        }
+        Ok(if got == Ty::Any { want } else { got })
+    }
+
+    fn pops(&mut self, want: &[Ty]) -> Result<()> {
+        want.iter().rev().try_for_each(|&ty| self.pop(ty).map(drop))
+    }
+
+    fn end(&mut self) -> Result<()> {
+        let frame = self.frames.pop().unwrap();
+        if self.stack.len() != frame.height {
+            bail!("{} values left on the stack", self.stack.len() - frame.height);
+        }
+        Ok(())
+    }
+
+    /// The rest of the current block is unreachable
+    fn diverge(&mut self) {
+        let frame = self.frames.last_mut().unwrap();
+        self.stack.truncate(frame.height);
+        frame.unreachable = true;
+    }
+
+    /// Values a branch to `label` carries: the function frame returns an i32
+    fn label(&self, label: u32) -> Result<&'static [Ty]> {
+        match (self.frames.len() - 1).checked_sub(label as usize) {
+            Some(0) => Ok(&[Ty::I32]),
+            Some(_) => Ok(&[]),
+            None => bail!("branch to label {} with only {} enclosing blocks", label, self.frames.len()),
+        }
+    }
+
+    fn local(&self, idx: u32) -> Result<Ty> {
+        match idx {
+            0 => Ok(Ty::I32),
+            _ if idx <= self.func.num_locals => Ok(Ty::I64),
+            _ => bail!("local {} out of range ({} locals)", idx, self.func.num_locals),
+        }
+    }
+
+    fn step(&mut self, inst: WasmInst) -> Result<()> {
+        use Ty::*;
+        use WasmInst::*;
+        let (pops, pushes): (&[Ty], &[Ty]) = match inst {
+            Comment { .. } => return Ok(()),
+            Block { .. } | Loop { .. } => {
+                self.frames.push(Frame { height: self.stack.len(), unreachable: false });
+                return Ok(());
+            }
+            End => {
+                if self.frames.len() == 1 {
+                    bail!("End without an open block");
+                }
+                return self.end();
+            }
+            Br { label } => {
+                let carried = self.label(label)?;
+                self.pops(carried)?;
+                self.diverge();
+                return Ok(());
+            }
+            BrIf { label } => {
+                self.pop(I32)?;
+                let carried = self.label(label)?;
+                self.pops(carried)?;
+                self.stack.extend(carried);
+                return Ok(());
+            }
+            BrTable { table, default } => {
+                self.pop(I32)?;
+                let Some(labels) = self.func.br_tables.get(table as usize) else {
+                    bail!("missing br_table {}", table);
+                };
+                let mut carried = self.label(default)?;
+                for &label in labels {
+                    if self.label(label)? != carried {
+                        bail!("br_table targets disagree on the values they take");
+                    }
+                    carried = self.label(label)?;
+                }
+                self.pops(carried)?;
+                self.diverge();
+                return Ok(());
+            }
+            Return => {
+                self.pop(I32)?;
+                self.diverge();
+                return Ok(());
+            }
+            Unreachable => {
+                self.diverge();
+                return Ok(());
+            }
+            Drop => {
+                self.pop(Any)?;
+                return Ok(());
+            }
+            Select => {
+                self.pop(I32)?;
+                let ty = self.pop(Any)?;
+                let ty = self.pop(ty)?;
+                self.stack.push(ty);
+                return Ok(());
+            }
+            LocalGet { idx } => {
+                let ty = self.local(idx)?;
+                self.stack.push(ty);
+                return Ok(());
+            }
+            LocalSet { idx } => {
+                let ty = self.local(idx)?;
+                self.pop(ty)?;
+                return Ok(());
+            }
+            LocalTee { idx } => {
+                let ty = self.local(idx)?;
+                self.pop(ty)?;
+                self.stack.push(ty);
+                return Ok(());
+            }
+            Call { func_idx: YIELD_FUNC } => (&[], &[]),
+            Call { func_idx: CRYPTO_FUNC } => (&[I32, I64, I64], &[I64]),
+            Call { func_idx: WATCH_FUNC } => (&[I32, I32, I32, I32], &[]),
+            Call { func_idx: BREAKPOINT_FUNC } => (&[I32], &[I32]),
+            CallHost { .. } => (&[I64; 8], &[I64]),
+            Call { func_idx } => bail!("call to function {}, which block functions do not import", func_idx),
+            CallIndirect { .. } => bail!("call_indirect in a block function"),
+
+            I32Const { .. } => (&[], &[I32]),
+            I64Const { .. } => (&[], &[I64]),
+            F32Const { .. } => (&[], &[F32]),
+            F64Const { .. } => (&[], &[F64]),
+
+            I32Load { .. } | I32Load8S { .. } | I32Load8U { .. } | I32Load16S { .. } | I32Load16U { .. }
+            | I32AtomicLoad { .. } => (&[I32], &[I32]),
+            I64Load { .. } | I64Load8S { .. } | I64Load8U { .. } | I64Load16S { .. } | I64Load16U { .. }
+            | I64Load32S { .. } | I64Load32U { .. } | I64AtomicLoad { .. } | I64AtomicLoad8U { .. }
+            | I64AtomicLoad16U { .. } => (&[I32], &[I64]),
+            F32Load { .. } => (&[I32], &[F32]),
+            F64Load { .. } => (&[I32], &[F64]),
+            I32Store { .. } | I32Store8 { .. } | I32Store16 { .. } | I32AtomicStore { .. } => (&[I32, I32], &[]),
+            I64Store { .. } | I64Store8 { .. } | I64Store16 { .. } | I64Store32 { .. } | I64AtomicStore { .. } => {
+                (&[I32, I64], &[])
+            }
+            F32Store { .. } => (&[I32, F32], &[]),
+            F64Store { .. } => (&[I32, F64], &[]),
+            MemoryFill => (&[I32, I32, I32], &[]),
+            AtomicFence => (&[], &[]),
+            I32AtomicRmwAdd { .. } | I32AtomicRmwAnd { .. } | I32AtomicRmwOr { .. } | I32AtomicRmwXor { .. }
+            | I32AtomicRmwXchg { .. } => (&[I32, I32], &[I32]),
+            I64AtomicRmwAdd { .. } | I64AtomicRmwAnd { .. } | I64AtomicRmwOr { .. } | I64AtomicRmwXor { .. }
+            | I64AtomicRmwXchg { .. } => (&[I32, I64], &[I64]),
+            I32AtomicRmwCmpxchg { .. } => (&[I32, I32, I32], &[I32]),
+            I64AtomicRmwCmpxchg { .. } | I64AtomicRmw8CmpxchgU { .. } | I64AtomicRmw16CmpxchgU { .. } => {
+                (&[I32, I64, I64], &[I64])
+            }
+            MemoryAtomicWait32 { .. } => (&[I32, I32, I64], &[I32]),
+            MemoryAtomicNotify { .. } => (&[I32, I32], &[I32]),
+
+            I64Add | I64Sub | I64Mul | I64DivS | I64DivU | I64RemS | I64RemU | I64And | I64Or | I64Xor
+            | I64Shl | I64ShrS | I64ShrU | I64Rotl | I64Rotr => (&[I64, I64], &[I64]),
+            I64Eq | I64Ne | I64LtS | I64LtU | I64GtS | I64GtU | I64LeS | I64LeU | I64GeS | I64GeU => {
+                (&[I64, I64], &[I32])
+            }
+            I64Clz | I64Ctz | I64Popcnt | I64Extend8S | I64Extend16S => (&[I64], &[I64]),
+            I64Eqz | I32WrapI64 => (&[I64], &[I32]),
+            I32Add | I32Sub | I32Mul | I32DivS | I32DivU | I32RemS | I32RemU | I32And | I32Or | I32Xor
+            | I32Shl | I32ShrS | I32ShrU | I32Rotr | I32Eq | I32Ne | I32LtS | I32LtU | I32GtS | I32GtU
+            | I32LeS | I32LeU | I32GeS | I32GeU => (&[I32, I32], &[I32]),
+            I32Eqz => (&[I32], &[I32]),
+            I64ExtendI32S | I64ExtendI32U => (&[I32], &[I64]),
+
+            F32Add | F32Sub | F32Mul | F32Div | F32Min | F32Max | F32Copysign => (&[F32, F32], &[F32]),
+            F32Eq | F32Ne | F32Lt | F32Gt | F32Le | F32Ge => (&[F32, F32], &[I32]),
+            F32Sqrt | F32Neg | F32Abs | F32Ceil | F32Floor | F32Trunc | F32Nearest => (&[F32], &[F32]),
+            F64Add | F64Sub | F64Mul | F64Div | F64Min | F64Max | F64Copysign => (&[F64, F64], &[F64]),
+            F64RelaxedMadd => (&[F64, F64, F64], &[F64]),
+            F64Eq | F64Ne | F64Lt | F64Gt | F64Le | F64Ge => (&[F64, F64], &[I32]),
+            F64Sqrt | F64Neg | F64Abs | F64Ceil | F64Floor | F64Trunc | F64Nearest => (&[F64], &[F64]),
+
+            F32ConvertI32S | F32ConvertI32U | F32ReinterpretI32 => (&[I32], &[F32]),
+            F32ConvertI64S | F32ConvertI64U => (&[I64], &[F32]),
+            F64ConvertI32S | F64ConvertI32U => (&[I32], &[F64]),
+            F64ConvertI64S | F64ConvertI64U | F64ReinterpretI64 => (&[I64], &[F64]),
+            I32TruncF32S | I32TruncF32U | I32ReinterpretF32 => (&[F32], &[I32]),
+            I32TruncF64S | I32TruncF64U => (&[F64], &[I32]),
+            I64TruncF32S | I64TruncF32U => (&[F32], &[I64]),
+            I64TruncF64S | I64TruncF64U | I64ReinterpretF64 => (&[F64], &[I64]),
+            F32DemoteF64 => (&[F64], &[F32]),
+            F64PromoteF32 => (&[F32], &[F64]),
+        };
+        self.pops(pops)?;
+        self.stack.extend(pushes);
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use WasmInst::*;
+
+    fn func(body: Vec<WasmInst>) -> WasmFunction {
+        WasmFunction { name: String::new(), block_addr: 0x1000, body, num_locals: 4, br_tables: Vec::new() }
+    }
+
+    #[test]
+    fn test_verify() {
+        // x10 = x11 + 1; return 0x1004
+        let mut body = vec![
+            LocalGet { idx: 0 },
+            LocalGet { idx: 0 },
+            I64Load { offset: 88 },
+            I64Const { value: 1 },
+            I64Add,
+            I64Store { offset: 80 },
+            Block { label: 0 },
+            LocalGet { idx: 1 },
+            I64Eqz,
+            BrIf { label: 0 },
+            I32Const { value: -1 },
+            Return,
+            End,
+            I32Const { value: 0x1004 },
+        ];
+        verify(&func(body.clone()), &[]).unwrap();
+
+        // An i64 stored through an i32 store, blamed on the guest instruction
+        body[5] = I32Store { offset: 80 };
+        let err = verify(&func(body.clone()), &[(0, 0x1000, Opcode::ADDI)]).unwrap_err().to_string();
+        assert!(err.contains("block 0x1000") && err.contains("IR #5") && err.contains("0x1000 ADDI"), "{}", err);
+
+        body[5] = I64Store { offset: 80 };
+        body[7] = LocalGet { idx: 5 };
+        assert!(verify(&func(body.clone()), &[]).unwrap_err().to_string().contains("local 5"));
+        body[7] = LocalGet { idx: 1 };
+        body[9] = BrIf { label: 2 };
+        assert!(verify(&func(body.clone()), &[]).unwrap_err().to_string().contains("label 2"));
+        body[9] = BrIf { label: 0 };
+        body.pop();
+        assert!(verify(&func(body), &[]).unwrap_err().to_string().contains("underflow"));
+    }
+}
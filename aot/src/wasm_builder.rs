@@ -1,18 +1,206 @@
 // wasm_builder.rs - Wasm binary generation
 //
 // Converts the intermediate WasmModule to actual Wasm bytecode using wasm-encoder.
-
-use crate::translate::{WasmInst, WasmModule};
-use anyhow::Result;
-use std::collections::BTreeMap;
+//
+// The dispatch table is exported as `table`, together with
+// `set_block(idx, func)`, so code compiled at runtime can replace block
+// functions in place and AOT and JIT blocks call each other directly.
+// `ModuleLayout::table_index` maps a block address to its entry.
+//
+// `get_reg($m, $i)`, `set_reg($m, $i, $value)`, `get_pc($m)` and
+// `set_pc($m, $pc)` read and write a hart's machine state, so hosts poke
+// registers without hardcoding its layout.
+//
+// Imports, memory type and the block map section follow the linking
+// convention in `link.rs`, shared by AOT and JIT modules.
+//
+// Size mode (`CompileOptions::size`, -Os) drops the `block_<addr>` exports
+// for a `block_index(pc) -> table index (or -1)` export, which `run` also
+// dispatches through, encoded whichever way is smallest. Blocks with
+// identical bodies share one function, so table entries may alias.
+//
+// A PC without a block ends the run as a halt would, unless
+// `CompileOptions::unknown_pc` asks for a distinct stop, a trap or a call
+// to `env.unknown_pc`; those record the PC and the last block run first.
+
+use crate::link::{self, IMPORT_MODULE};
+use crate::options::UnknownPc;
+use crate::translate::{block_name, ModuleLayout, WasmFunction, WasmInst, WasmModule};
+use anyhow::{bail, Context, Result};
+use std::collections::{BTreeMap, HashMap};
+use std::io::{Seek, SeekFrom, Write};
 use wasm_encoder::{
-    CodeSection, ConstExpr, ElementSection, Elements, EntityType, ExportKind, ExportSection,
-    Function, FunctionSection, ImportSection, Instruction, MemorySection, MemoryType, Module,
-    TableSection, TableType, TypeSection, ValType,
+    CodeSection, ConstExpr, DataCountSection, DataSection, ElementSection, Elements, Encode,
+    EntityType, ExportKind, ExportSection, Function, FunctionSection, GlobalSection, GlobalType,
+    ImportSection, Instruction, MemoryType, Module, Section, SectionId, TableSection, TableType,
+    TagKind, TagSection, TagType, TypeSection, ValType,
 };
-
 /// Build the final Wasm binary
 pub fn build(module: &WasmModule) -> Result<Vec<u8>> {
+    let layout = module.layout();
+    if layout.is_split() {
+        bail!("Split layouts are built with build_split");
+    }
+    let (bodies, table) = block_functions(&module.functions, layout.size)?;
+    let mut wasm = header(&layout, &table);
+
+    // ==========================================================================
+    // Code section
+    // ==========================================================================
+    let mut codes = CodeSection::new();
+    codes.function(&build_dispatch_function(&layout, bodies.len() as u32));
+
+    // Block functions
+    for body in &bodies {
+        codes.function(body);
+    }
+
+    for helper in helper_functions(&layout, bodies.len() as u32) {
+        codes.function(&helper);
+    }
+
+    wasm.section(&codes);
+    if let Some(data) = data_section(&layout) {
+        wasm.section(&data);
+    }
+    wasm.section(&link::block_map_section(&layout.block_addrs));
+
+    Ok(wasm.finish())
+}
+
+/// Build split output: the dispatcher module, then one code module per
+/// group of `module.groups` (see `split.rs` for how they link)
+pub fn build_split(module: &WasmModule) -> Result<(Vec<u8>, Vec<Vec<u8>>)> {
+    let layout = module.layout();
+    if !layout.is_split() {
+        bail!("Layout has no code modules; use build");
+    }
+    let mut wasm = header(&layout, &[]);
+    let mut codes = CodeSection::new();
+    codes.function(&build_dispatch_function(&layout, 0));
+    for helper in helper_functions(&layout, 0) {
+        codes.function(&helper);
+    }
+    wasm.section(&codes);
+    if let Some(data) = data_section(&layout) {
+        wasm.section(&data);
+    }
+    wasm.section(&link::block_map_section(&layout.block_addrs));
+
+    let code_modules = (0..layout.groups.len())
+        .map(|g| build_code_module(&layout, g, &module.functions[layout.group_range(g)]))
+        .collect::<Result<_>>()?;
+    Ok((wasm.finish(), code_modules))
+}
+
+/// Type and function indices that depend on the layout
+struct Indices {
+    /// Guest function wrapper type
+    export_type: u32,
+    /// `env.load_block` import type (split mode)
+    load_block_type: u32,
+    /// `set_block` type
+    set_block_type: u32,
+    /// `env.crypto` import type
+    crypto_type: u32,
+    /// `guest_trap` tag type (trap exceptions)
+    trap_tag_type: u32,
+    /// `env.watch` import type (debug builds)
+    watch_type: u32,
+    /// `get_reg`, then the `set_reg`, `get_pc` and `set_pc` types
+    get_reg_type: u32,
+    /// `reset` type, after the accessors'
+    reset_type: u32,
+    /// Interposed function import type, after `reset`'s
+    host_type: u32,
+    /// `env.load_block` import (split mode)
+    load_block: u32,
+    /// `env.compile_missing` import (lazy compilation)
+    compile_missing: Option<u32>,
+    /// `env.unknown_pc` import (`UnknownPc::Import`)
+    unknown_pc: Option<u32>,
+    dispatch: u32,
+    /// First block function (none in split mode)
+    first_block: u32,
+    /// First function after the block functions
+    helpers: u32,
+    /// `block_index` (size mode), after the other helpers
+    block_index: u32,
+}
+
+impl Indices {
+    /// Indices for `module` with `blocks` block functions (0 in split mode)
+    fn new(module: &ModuleLayout, blocks: u32) -> Self {
+        let export_type = if module.threads { 6 } else { 4 };
+        let load_block_type = export_type + !module.exports.is_empty() as u32;
+        let set_block_type = load_block_type + module.is_split() as u32;
+        // Imports: syscall, yield, crypto, then watch and breakpoint in
+        // debug builds, the interposed functions, load_block in split mode,
+        // compile_missing in lazy mode and unknown_pc if imported
+        let load_block = module.imported_funcs();
+        let compile_missing = module.lazy_compile.then_some(load_block + module.is_split() as u32);
+        let unknown_pc_import = module.unknown_pc == UnknownPc::Import;
+        let unknown_pc =
+            unknown_pc_import.then_some(load_block + module.is_split() as u32 + module.lazy_compile as u32);
+        let dispatch =
+            load_block + module.is_split() as u32 + module.lazy_compile as u32 + unknown_pc_import as u32;
+        let helpers = dispatch + 1 + blocks;
+        // call_block, thread helpers, wrappers, data initializers, set_block
+        // and the register accessors
+        let other_helpers = module.is_split() as u32
+            + 3 * module.threads as u32
+            + module.exports.len() as u32
+            + 2 * !module.data.is_empty() as u32
+            + 2
+            + ACCESSORS.len() as u32;
+        Self {
+            export_type,
+            load_block_type,
+            set_block_type,
+            crypto_type: set_block_type + 1,
+            trap_tag_type: set_block_type + 2,
+            watch_type: set_block_type + 2 + module.trap_exceptions as u32,
+            get_reg_type: set_block_type + 2 + module.trap_exceptions as u32 + module.debug_imports() as u32,
+            reset_type: set_block_type + 2 + module.trap_exceptions as u32 + module.debug_imports() as u32 + 4,
+            host_type: set_block_type + 7 + module.trap_exceptions as u32 + module.debug_imports() as u32,
+            load_block,
+            compile_missing,
+            unknown_pc,
+            dispatch,
+            first_block: dispatch + 1,
+            helpers,
+            block_index: helpers + other_helpers,
+        }
+    }
+}
+
+/// The syscall import: `env.syscall`, or `env.syscall_async` in JSPI mode
+fn syscall_import(module: &ModuleLayout) -> &'static str {
+    if module.jspi {
+        link::SYSCALL_ASYNC
+    } else {
+        link::SYSCALL
+    }
+}
+
+/// ($m, $pc), and $cause in JSPI mode
+fn syscall_params(module: &ModuleLayout) -> Vec<ValType> {
+    vec![ValType::I32; 2 + module.jspi as usize]
+}
+
+/// `env.<name>` of type `ty` for each interposed function import
+fn import_interposed(imports: &mut ImportSection, names: &[String], ty: u32) {
+    for name in names {
+        imports.import(IMPORT_MODULE, name, EntityType::Function(ty));
+    }
+}
+
+/// Every section before the code section; these depend only on the layout
+/// and `table`, the block function (offset from the first) behind each
+/// dispatch table entry (empty in split mode)
+fn header(module: &ModuleLayout, table: &[u32]) -> Module {
+    let blocks = table.iter().max().map_or(0, |&last| last + 1);
+    let idx = Indices::new(module, blocks);
     let mut wasm = Module::new();
 
     // ==========================================================================
@@ -26,8 +214,65 @@ pub fn build(module: &WasmModule) -> Result<Vec<u8>> {
     // Type 1: Dispatch function (param $m i32, $pc i32) (result i32)
     types.function(vec![ValType::I32, ValType::I32], vec![ValType::I32]);
 
-    // Type 2: Syscall handler (param $m i32, $pc i32) (result i32)
-    types.function(vec![ValType::I32, ValType::I32], vec![ValType::I32]);
+    // Type 2: Syscall handler (param $m i32, $pc i32) (result i32), with a
+    // third param $cause i32 in JSPI mode
+    types.function(syscall_params(module), vec![ValType::I32]);
+
+    // Type 3: yield hint, init_memory ()
+    types.function(vec![], vec![]);
+
+    if module.threads {
+        // Type 4: futex_wait (param $addr i32, $expected i32, $timeout i64) (result i32)
+        types.function(vec![ValType::I32, ValType::I32, ValType::I64], vec![ValType::I32]);
+        // Type 5: thread_init (param $child i32, $parent i32, $stack i64, $tls i64)
+        types.function(
+            vec![ValType::I32, ValType::I32, ValType::I64, ValType::I64],
+            vec![],
+        );
+    }
+
+    // Guest function wrapper: (param $m i32, a0-a7 i64) (result a0 i64, a1 i64)
+    if !module.exports.is_empty() {
+        let mut params = vec![ValType::I32];
+        params.extend([ValType::I64; 8]);
+        types.function(params, vec![ValType::I64, ValType::I64]);
+    }
+
+    // load_block (param $table_idx i32)
+    if module.is_split() {
+        types.function(vec![ValType::I32], vec![]);
+    }
+
+    // set_block (param $idx i32, $func funcref)
+    types.function(vec![ValType::I32, ValType::Ref(wasm_encoder::RefType::FUNCREF)], vec![]);
+
+    // crypto (param $op i32, $rs1 i64, $rs2 i64) (result i64)
+    types.function(vec![ValType::I32, ValType::I64, ValType::I64], vec![ValType::I64]);
+
+    // guest_trap tag (param $cause i32, $pc i64, $addr i64)
+    if module.trap_exceptions {
+        types.function(vec![ValType::I32, ValType::I64, ValType::I64], vec![]);
+    }
+
+    // watch (param $addr i32, $size i32, $is_write i32, $pc i32)
+    if module.debug_imports() {
+        types.function(vec![ValType::I32; 4], vec![]);
+    }
+
+    // get_reg ($m i32, $i i32) -> i64, set_reg ($m i32, $i i32, $value i64),
+    // get_pc ($m i32) -> i64, set_pc ($m i32, $pc i64)
+    types.function(vec![ValType::I32, ValType::I32], vec![ValType::I64]);
+    types.function(vec![ValType::I32, ValType::I32, ValType::I64], vec![]);
+    types.function(vec![ValType::I32], vec![ValType::I64]);
+    types.function(vec![ValType::I32, ValType::I64], vec![]);
+
+    // reset ($m i32)
+    types.function(vec![ValType::I32], vec![]);
+
+    // Interposed functions (a0-a7 i64) -> i64
+    if !module.interpose.is_empty() {
+        types.function(vec![ValType::I64; 8], vec![ValType::I64]);
+    }
 
     wasm.section(&types);
 
@@ -36,20 +281,36 @@ pub fn build(module: &WasmModule) -> Result<Vec<u8>> {
     // ==========================================================================
     let mut imports = ImportSection::new();
 
-    // Import memory from environment
-    imports.import(
-        "env",
-        "memory",
-        MemoryType {
-            minimum: module.memory_pages as u64,
-            maximum: Some((module.memory_pages * 4) as u64),
-            memory64: false,
-            shared: false,
-        },
-    );
+    // Import memory from environment (shared between workers in threads mode)
+    imports.import(IMPORT_MODULE, link::MEMORY, memory_type(module));
+
+    // Import syscall handler, the spin-wait hint and the AES helper
+    imports.import(IMPORT_MODULE, syscall_import(module), EntityType::Function(2));
+    imports.import(IMPORT_MODULE, link::YIELD, EntityType::Function(3));
+    imports.import(IMPORT_MODULE, link::CRYPTO, EntityType::Function(idx.crypto_type));
+
+    // Debug builds: watched accesses and breakpoints are reported first
+    // (breakpoint shares the block function type)
+    if module.debug_imports() {
+        imports.import(IMPORT_MODULE, link::WATCH, EntityType::Function(idx.watch_type));
+        imports.import(IMPORT_MODULE, link::BREAKPOINT, EntityType::Function(0));
+    }
+    import_interposed(&mut imports, &module.interpose, idx.host_type);
+
+    // Split mode: the host instantiates the code module holding a table index
+    if module.is_split() {
+        imports.import(IMPORT_MODULE, "load_block", EntityType::Function(idx.load_block_type));
+    }
+
+    // Lazy mode: the host compiles code for unknown PCs (pc) -> table index
+    if module.lazy_compile {
+        imports.import(IMPORT_MODULE, link::COMPILE_MISSING, EntityType::Function(0));
+    }
 
-    // Import syscall handler
-    imports.import("env", "syscall", EntityType::Function(2));
+    // The host decides where an unknown PC goes: (pc) -> next pc
+    if module.unknown_pc == UnknownPc::Import {
+        imports.import(IMPORT_MODULE, link::UNKNOWN_PC, EntityType::Function(0));
+    }
 
     wasm.section(&imports);
 
@@ -58,11 +319,45 @@ pub fn build(module: &WasmModule) -> Result<Vec<u8>> {
     // ==========================================================================
     let mut functions = FunctionSection::new();
 
-    // Dispatch function (index 1 after import)
+    // Dispatch function (first after the imports)
     functions.function(1);
 
-    // Block functions (type 0)
-    for _ in &module.functions {
+    if module.is_split() {
+        // call_block($m, idx), loading the code module on first use
+        functions.function(1);
+    } else {
+        // Block functions (type 0)
+        for _ in 0..blocks {
+            functions.function(0);
+        }
+    }
+
+    // Thread helpers follow the block functions
+    if module.threads {
+        functions.function(4); // futex_wait
+        functions.function(1); // futex_wake (addr, count) -> woken
+        functions.function(5); // thread_init
+    }
+
+    // Then guest function wrappers
+    for _ in &module.exports {
+        functions.function(idx.export_type);
+    }
+
+    // Then data initializers and reset
+    if !module.data.is_empty() {
+        functions.function(0); // init_data(addr) -> copied
+        functions.function(3); // init_memory()
+    }
+    functions.function(idx.reset_type); // reset($m)
+
+    // set_block and the register accessors come last, but for
+    // block_index(pc) in size mode
+    functions.function(idx.set_block_type);
+    for i in 0..ACCESSORS.len() as u32 {
+        functions.function(idx.get_reg_type + i);
+    }
+    if module.size {
         functions.function(0);
     }
 
@@ -74,11 +369,7 @@ pub fn build(module: &WasmModule) -> Result<Vec<u8>> {
     let mut tables = TableSection::new();
 
     // Table for block dispatch
-    tables.table(TableType {
-        element_type: wasm_encoder::RefType::FUNCREF,
-        minimum: module.functions.len() as u32,
-        maximum: Some(module.functions.len() as u32),
-    });
+    tables.table(table_type(module));
 
     wasm.section(&tables);
 
@@ -88,24 +379,24 @@ pub fn build(module: &WasmModule) -> Result<Vec<u8>> {
     // Memory is imported, so skip this
 
     // ==========================================================================
-    // Element section (populate function table for call_indirect)
+    // Tag section: the exception traps throw
     // ==========================================================================
-    let mut elements = ElementSection::new();
-
-    // Build function reference list: indices 2, 3, 4, ... (block functions)
-    // Index 0 = imported syscall, Index 1 = dispatch, Index 2+ = block functions
-    let func_indices: Vec<u32> = (0..module.functions.len())
-        .map(|i| (i + 2) as u32)
-        .collect();
-
-    // Active element segment at table index 0, offset 0
-    elements.active(
-        Some(0),                           // table index
-        &ConstExpr::i32_const(0),          // offset
-        Elements::Functions(&func_indices),
-    );
+    if module.trap_exceptions {
+        let mut tags = TagSection::new();
+        tags.tag(TagType { kind: TagKind::Exception, func_type_idx: idx.trap_tag_type });
+        wasm.section(&tags);
+    }
 
-    wasm.section(&elements);
+    // ==========================================================================
+    // Global section: one "page initialized" flag per data segment, then
+    // the sp of the first run (see `boot_sp`)
+    // ==========================================================================
+    let mut globals = GlobalSection::new();
+    for _ in module.data.iter() {
+        globals.global(GlobalType { val_type: ValType::I32, mutable: true }, &ConstExpr::i32_const(0));
+    }
+    globals.global(GlobalType { val_type: ValType::I64, mutable: true }, &ConstExpr::i64_const(0));
+    wasm.section(&globals);
 
     // ==========================================================================
     // Export section
@@ -113,71 +404,736 @@ pub fn build(module: &WasmModule) -> Result<Vec<u8>> {
     let mut exports = ExportSection::new();
 
     // Export dispatch function
-    exports.export("run", ExportKind::Func, 1);
+    exports.export("run", ExportKind::Func, idx.dispatch);
 
-    // Export individual block functions for debugging
-    for (idx, func) in module.functions.iter().enumerate() {
-        exports.export(&func.name, ExportKind::Func, (idx + 2) as u32);
+    // Code modules (split mode) and runtime-compiled code install their
+    // block functions through the table
+    exports.export(link::TABLE, ExportKind::Table, 0);
+
+    if module.trap_exceptions {
+        exports.export(link::GUEST_TRAP, ExportKind::Tag, 0);
+    }
+
+    let mut helpers_base = idx.helpers;
+    if module.is_split() {
+        helpers_base += 1; // call_block
+    } else {
+        // Export individual block functions for debugging
+        for (&addr, &func) in module.block_addrs.iter().zip(table) {
+            if module.exports_block(addr) {
+                exports.export(&block_name(addr), ExportKind::Func, idx.first_block + func);
+            }
+        }
+    }
+
+    if module.threads {
+        exports.export("futex_wait", ExportKind::Func, helpers_base);
+        exports.export("futex_wake", ExportKind::Func, helpers_base + 1);
+        exports.export("thread_init", ExportKind::Func, helpers_base + 2);
+        helpers_base += 3;
+    }
+
+    for (i, (name, _)) in module.exports.iter().enumerate() {
+        exports.export(name, ExportKind::Func, helpers_base + i as u32);
+    }
+    helpers_base += module.exports.len() as u32;
+
+    if !module.data.is_empty() {
+        exports.export("init_data", ExportKind::Func, helpers_base);
+        exports.export("init_memory", ExportKind::Func, helpers_base + 1);
+        helpers_base += 2;
+    }
+    exports.export("reset", ExportKind::Func, helpers_base);
+    helpers_base += 1;
+
+    exports.export("set_block", ExportKind::Func, helpers_base);
+    for (i, name) in ACCESSORS.iter().enumerate() {
+        exports.export(name, ExportKind::Func, helpers_base + 1 + i as u32);
+    }
+    if module.size {
+        exports.export("block_index", ExportKind::Func, idx.block_index);
     }
 
     wasm.section(&exports);
 
     // ==========================================================================
-    // Code section
+    // Element section (populate function table for call_indirect)
     // ==========================================================================
-    let mut codes = CodeSection::new();
+    // In split mode the code modules fill the table as they are loaded
+    if !module.is_split() {
+        let mut elements = ElementSection::new();
+
+        // Build function reference list: indices 2, 3, 4, ... (block functions)
+        // Index 0 = imported syscall, Index 1 = dispatch, Index 2+ = block functions
+        let func_indices: Vec<u32> = table.iter().map(|&func| idx.first_block + func).collect();
+
+        // Active element segment at table index 0, offset 0
+        elements.active(
+            Some(0),                           // table index
+            &ConstExpr::i32_const(0),          // offset
+            Elements::Functions(&func_indices),
+        );
+
+        wasm.section(&elements);
+    }
 
-    // Build address-to-table-index mapping for dispatch
-    // Table index = position in func_indices (0, 1, 2, ...)
-    let addr_to_table_idx: BTreeMap<u64, u32> = module
-        .functions
-        .iter()
-        .enumerate()
-        .map(|(i, f)| (f.block_addr, i as u32))
-        .collect();
+    // memory.init and data.drop need the segment count ahead of the code
+    if !module.data.is_empty() {
+        wasm.section(&DataCountSection { count: module.data.len() as u32 });
+    }
 
-    // Dispatch function
-    let dispatch_func = build_dispatch_function(module, &addr_to_table_idx);
-    codes.function(&dispatch_func);
+    wasm
+}
 
-    // Block functions
-    for func in &module.functions {
-        let wasm_func = build_block_function(func)?;
-        codes.function(&wasm_func);
+/// Passive segments with the embedded memory image, in `module.data` order
+fn data_section(module: &ModuleLayout) -> Option<DataSection> {
+    if module.data.is_empty() {
+        return None;
     }
+    let mut data = DataSection::new();
+    for chunk in module.data.iter() {
+        data.passive(chunk.bytes.iter().copied());
+    }
+    Some(data)
+}
+
+/// Guest memory, imported by every module (shared between workers in threads mode)
+fn memory_type(module: &ModuleLayout) -> MemoryType {
+    let memory = link::memory_type(module.memory_pages as u64, module.threads);
+    MemoryType { maximum: module.max_pages.map(u64::from).or(memory.maximum), ..memory }
+}
+
+/// The dispatch table: one entry per block function, in function order,
+/// then (lazy mode) whatever the host adds
+fn table_type(module: &ModuleLayout) -> TableType {
+    TableType {
+        element_type: wasm_encoder::RefType::FUNCREF,
+        minimum: module.block_addrs.len() as u32,
+        maximum: (!module.lazy_compile).then_some(module.block_addrs.len() as u32),
+    }
+}
+
+/// Functions that follow the `blocks` block functions in the code section
+fn helper_functions(module: &ModuleLayout, blocks: u32) -> Vec<Function> {
+    let idx = Indices::new(module, blocks);
+    let mut helpers = Vec::new();
+    if module.is_split() {
+        helpers.push(build_call_block(idx.load_block));
+    }
+    if module.threads {
+        helpers.extend([build_futex_wait(module), build_futex_wake(module), build_thread_init()]);
+    }
+    helpers.extend(module.exports.iter().map(|&(_, entry)| build_export_wrapper(entry, idx.dispatch)));
+    if !module.data.is_empty() {
+        let init_data = idx.helpers + helpers.len() as u32;
+        helpers.extend([build_init_data(module), build_init_memory(module, init_data)]);
+    }
+    helpers.push(build_reset(module));
+    helpers.push(build_set_block());
+    helpers.extend([build_get_reg(), build_set_reg(), build_get_pc(), build_set_pc()]);
+    if module.size {
+        helpers.push(build_block_index(&module.block_addrs));
+    }
+    helpers
+}
+
+/// Encoded block functions and the one behind each dispatch table entry,
+/// as an offset from the first block function. With `dedup`, blocks whose
+/// encoded bodies are identical share one function.
+fn block_functions(functions: &[WasmFunction], dedup: bool) -> Result<(Vec<Function>, Vec<u32>)> {
+    let mut bodies = Vec::new();
+    let mut table = Vec::with_capacity(functions.len());
+    let mut seen: HashMap<Vec<u8>, u32> = HashMap::new();
+    for func in functions {
+        let body = build_block_function(func)?;
+        if dedup {
+            let mut bytes = Vec::new();
+            body.encode(&mut bytes);
+            let next = bodies.len() as u32;
+            let first = *seen.entry(bytes).or_insert(next);
+            if first != next {
+                table.push(first);
+                continue;
+            }
+        }
+        table.push(bodies.len() as u32);
+        bodies.push(body);
+    }
+    Ok((bodies, table))
+}
+
+/// Code module `g` of a split layout: `functions` installed into the
+/// imported dispatch table at their indices
+fn build_code_module(module: &ModuleLayout, g: usize, functions: &[WasmFunction]) -> Result<Vec<u8>> {
+    build_table_module(module, module.groups[g] as u32, functions)
+}
+
+/// A module that replaces the dispatch table entry of `func`'s block with
+/// `func` when instantiated against the module laid out as `module` (its
+/// memory and exported `table`): how a host swaps in a block recompiled
+/// with breakpoints, say. `module` must import the debug hooks if `func`
+/// calls them.
+pub fn build_patch(module: &ModuleLayout, func: &WasmFunction) -> Result<Vec<u8>> {
+    let index = module.table_index(func.block_addr).with_context(|| {
+        format!("No dispatch table entry for block 0x{:x}", func.block_addr)
+    })?;
+    build_table_module(module, index, std::slice::from_ref(func))
+}
+
+/// `functions`, installed into the imported dispatch table from `at` on
+fn build_table_module(module: &ModuleLayout, at: u32, functions: &[WasmFunction]) -> Result<Vec<u8>> {
+    let mut wasm = Module::new();
+
+    // Block, syscall, yield, crypto, watch and interposed function types
+    let mut types = TypeSection::new();
+    types.function(vec![ValType::I32], vec![ValType::I32]);
+    types.function(syscall_params(module), vec![ValType::I32]);
+    types.function(vec![], vec![]);
+    types.function(vec![ValType::I32, ValType::I64, ValType::I64], vec![ValType::I64]);
+    types.function(vec![ValType::I32; 4], vec![]);
+    types.function(vec![ValType::I64; 8], vec![ValType::I64]);
+    wasm.section(&types);
+
+    let mut imports = ImportSection::new();
+    imports.import(IMPORT_MODULE, link::MEMORY, memory_type(module));
+    imports.import(IMPORT_MODULE, link::TABLE, table_type(module));
+    imports.import(IMPORT_MODULE, syscall_import(module), EntityType::Function(1));
+    imports.import(IMPORT_MODULE, link::YIELD, EntityType::Function(2));
+    imports.import(IMPORT_MODULE, link::CRYPTO, EntityType::Function(3));
+    if module.debug_imports() {
+        imports.import(IMPORT_MODULE, link::WATCH, EntityType::Function(4));
+        imports.import(IMPORT_MODULE, link::BREAKPOINT, EntityType::Function(0));
+    }
+    import_interposed(&mut imports, &module.interpose, 5);
+    wasm.section(&imports);
 
+    let first = module.imported_funcs();
+    let mut decls = FunctionSection::new();
+    let mut exports = ExportSection::new();
+    for (i, func) in functions.iter().enumerate() {
+        decls.function(0);
+        exports.export(&block_name(func.block_addr), ExportKind::Func, first + i as u32);
+    }
+    wasm.section(&decls);
+    wasm.section(&exports);
+
+    let mut elements = ElementSection::new();
+    let func_indices: Vec<u32> = (first..first + functions.len() as u32).collect();
+    elements.active(
+        Some(0),
+        &ConstExpr::i32_const(at as i32),
+        Elements::Functions(&func_indices),
+    );
+    wasm.section(&elements);
+
+    let mut codes = CodeSection::new();
+    for func in functions {
+        codes.function(&build_block_function(func)?);
+    }
     wasm.section(&codes);
 
     Ok(wasm.finish())
 }
 
+/// Writes a module to `out` one block function at a time.
+///
+/// The header and dispatch function are written by `new`; the code section
+/// size is unknown until the last block, so a padded placeholder is written
+/// and patched by `finish` (which is why `out` must be seekable). Block
+/// functions must be pushed in layout order.
+pub struct StreamingBuilder<W: Write + Seek> {
+    out: W,
+    block_addrs: Vec<u64>,
+    helpers: Vec<Function>,
+    data: Option<DataSection>,
+    /// Appended after the block map
+    custom: Vec<wasm_encoder::CustomSection<'static>>,
+    next: usize,
+    size_pos: u64,
+}
+
+impl<W: Write + Seek> StreamingBuilder<W> {
+    pub fn new(mut out: W, layout: &ModuleLayout) -> Result<Self> {
+        if layout.is_split() {
+            bail!("Split layouts are built with build_split");
+        }
+        if layout.size {
+            bail!("Size mode merges identical blocks, which needs them all up front; use build");
+        }
+        let blocks = layout.block_addrs.len() as u32;
+        out.write_all(header(layout, &(0..blocks).collect::<Vec<_>>()).as_slice())?;
+
+        // Code section: id, padded size (patched in finish), count, dispatch
+        let helpers = helper_functions(layout, blocks);
+        out.write_all(&[SectionId::Code as u8])?;
+        let size_pos = out.stream_position()?;
+        out.write_all(&padded_leb(0))?;
+        let mut bytes = Vec::new();
+        ((layout.block_addrs.len() + helpers.len() + 1) as u32).encode(&mut bytes);
+        build_dispatch_function(layout, blocks).encode(&mut bytes);
+        out.write_all(&bytes)?;
+
+        Ok(Self {
+            out,
+            block_addrs: layout.block_addrs.clone(),
+            helpers,
+            data: data_section(layout),
+            custom: Vec::new(),
+            next: 0,
+            size_pos,
+        })
+    }
+
+    /// Encode and write the next block function
+    pub fn push(&mut self, func: &WasmFunction) -> Result<()> {
+        self.push_encoded(func.block_addr, &encode_block(func)?)
+    }
+
+    /// Write the next block function, the block at `addr`, already encoded
+    /// by `encode_block`
+    pub fn push_encoded(&mut self, addr: u64, bytes: &[u8]) -> Result<()> {
+        match self.block_addrs.get(self.next) {
+            Some(&expected) if expected == addr => {}
+            Some(&expected) => bail!("Expected block 0x{:x}, got 0x{:x}", expected, addr),
+            None => bail!("Unexpected block 0x{:x} after the last one", addr),
+        }
+        self.out.write_all(bytes)?;
+        self.next += 1;
+        Ok(())
+    }
+
+    /// Append `section` to the module after the block map
+    pub fn add_custom_section(&mut self, section: wasm_encoder::CustomSection<'static>) {
+        self.custom.push(section);
+    }
+
+    /// Write the helpers, patch the code section size, append the data,
+    /// block map and added custom sections and return the writer
+    pub fn finish(mut self) -> Result<W> {
+        if self.next != self.block_addrs.len() {
+            bail!("Only {} of {} block functions were written", self.next, self.block_addrs.len());
+        }
+        let mut bytes = Vec::new();
+        for helper in &self.helpers {
+            helper.encode(&mut bytes);
+        }
+        self.out.write_all(&bytes)?;
+
+        let end = self.out.stream_position()?;
+        let size = u32::try_from(end - self.size_pos - 5).context("Code section exceeds 4GB")?;
+        self.out.seek(SeekFrom::Start(self.size_pos))?;
+        self.out.write_all(&padded_leb(size))?;
+        self.out.seek(SeekFrom::Start(end))?;
+
+        let mut bytes = Vec::new();
+        if let Some(data) = &self.data {
+            data.append_to(&mut bytes);
+        }
+        link::block_map_section(&self.block_addrs).append_to(&mut bytes);
+        for section in &self.custom {
+            section.append_to(&mut bytes);
+        }
+        self.out.write_all(&bytes)?;
+        Ok(self.out)
+    }
+}
+
+/// A block function as it appears in the code section (size-prefixed body)
+pub fn encode_block(func: &WasmFunction) -> Result<Vec<u8>> {
+    let mut bytes = Vec::new();
+    build_block_function(func)?.encode(&mut bytes);
+    Ok(bytes)
+}
+
+/// `value` as a 5-byte LEB128, so it can be overwritten in place
+fn padded_leb(value: u32) -> [u8; 5] {
+    let mut bytes = [0u8; 5];
+    for (i, b) in bytes.iter_mut().enumerate() {
+        *b = ((value >> (7 * i)) & 0x7f) as u8 | if i < 4 { 0x80 } else { 0 };
+    }
+    bytes
+}
+
+/// futex_wait(addr, expected, timeout_ns) -> 0 (woken), 1 (value mismatch), 2 (timed out).
+/// `addr` is a guest address. A negative timeout waits forever. Must be
+/// called from a worker, not the browser main thread, where
+/// `memory.atomic.wait32` traps.
+fn build_futex_wait(module: &ModuleLayout) -> Function {
+    let mut f = Function::new(vec![]);
+    f.instruction(&Instruction::LocalGet(0));
+    f.instruction(&Instruction::LocalGet(1));
+    f.instruction(&Instruction::LocalGet(2));
+    f.instruction(&Instruction::MemoryAtomicWait32(wasm_encoder::MemArg {
+        offset: module.memory_offset as u64,
+        align: 2,
+        memory_index: 0,
+    }));
+    f.instruction(&Instruction::End);
+    f
+}
+
+/// futex_wake(addr, count) -> number of waiters woken
+fn build_futex_wake(module: &ModuleLayout) -> Function {
+    let mut f = Function::new(vec![]);
+    f.instruction(&Instruction::LocalGet(0));
+    f.instruction(&Instruction::LocalGet(1));
+    f.instruction(&Instruction::MemoryAtomicNotify(wasm_encoder::MemArg {
+        offset: module.memory_offset as u64,
+        align: 2,
+        memory_index: 0,
+    }));
+    f.instruction(&Instruction::End);
+    f
+}
+
+/// thread_init(child_m, parent_m, stack, tls): set up the machine state of a
+/// clone(CLONE_THREAD) child. Copies the parent's registers, then applies the
+/// clone semantics: sp = stack (if nonzero), tp = tls (if CLONE_SETTLS passed
+/// a nonzero value), a0 = 0 in the child, and no LR reservation.
+fn build_thread_init() -> Function {
+    use crate::translate::{MACHINE_STATE_SIZE, RESERVATION_ADDR_OFFSET};
+
+    let mem = |offset: u32| wasm_encoder::MemArg {
+        offset: offset as u64,
+        align: 3,
+        memory_index: 0,
+    };
+    let mut f = Function::new(vec![]);
+
+    // memcpy(child, parent, MACHINE_STATE_SIZE)
+    f.instruction(&Instruction::LocalGet(0));
+    f.instruction(&Instruction::LocalGet(1));
+    f.instruction(&Instruction::I32Const(MACHINE_STATE_SIZE as i32));
+    f.instruction(&Instruction::MemoryCopy { src_mem: 0, dst_mem: 0 });
+
+    // Optional overrides: x2 (sp) and x4 (tp)
+    for (local, reg) in [(2u32, 2u32), (3, 4)] {
+        f.instruction(&Instruction::LocalGet(local));
+        f.instruction(&Instruction::I64Const(0));
+        f.instruction(&Instruction::I64Ne);
+        f.instruction(&Instruction::If(wasm_encoder::BlockType::Empty));
+        f.instruction(&Instruction::LocalGet(0));
+        f.instruction(&Instruction::LocalGet(local));
+        f.instruction(&Instruction::I64Store(mem(reg * 8)));
+        f.instruction(&Instruction::End);
+    }
+
+    // a0 = 0: clone() returns 0 in the child
+    f.instruction(&Instruction::LocalGet(0));
+    f.instruction(&Instruction::I64Const(0));
+    f.instruction(&Instruction::I64Store(mem(10 * 8)));
+
+    // Fresh hart: no reservation
+    f.instruction(&Instruction::LocalGet(0));
+    f.instruction(&Instruction::I64Const(-1));
+    f.instruction(&Instruction::I64Store(mem(RESERVATION_ADDR_OFFSET)));
+
+    f.instruction(&Instruction::End);
+    f
+}
+
+/// call_block($m, idx): call dispatch table entry `idx`, first asking the
+/// host (`env.load_block`, import `load_block`) to load its code module if
+/// it is null
+fn build_call_block(load_block: u32) -> Function {
+    let mut f = Function::new(vec![]);
+    f.instruction(&Instruction::LocalGet(1));
+    f.instruction(&Instruction::TableGet(0));
+    f.instruction(&Instruction::RefIsNull);
+    f.instruction(&Instruction::If(wasm_encoder::BlockType::Empty));
+    f.instruction(&Instruction::LocalGet(1));
+    f.instruction(&Instruction::Call(load_block));
+    f.instruction(&Instruction::End);
+    f.instruction(&Instruction::LocalGet(0));
+    f.instruction(&Instruction::LocalGet(1));
+    f.instruction(&Instruction::CallIndirect { ty: 0, table: 0 });
+    f.instruction(&Instruction::End);
+    f
+}
+
+/// set_block(idx, func): install `func` as dispatch table entry `idx`
+fn build_set_block() -> Function {
+    let mut f = Function::new(vec![]);
+    f.instruction(&Instruction::LocalGet(0));
+    f.instruction(&Instruction::LocalGet(1));
+    f.instruction(&Instruction::TableSet(0));
+    f.instruction(&Instruction::End);
+    f
+}
+
+/// Register accessors exported after `set_block`, so hosts need not know
+/// the machine-state layout to read or poke a hart
+pub const ACCESSORS: [&str; 4] = ["get_reg", "set_reg", "get_pc", "set_pc"];
+
+fn state_arg(offset: u32) -> wasm_encoder::MemArg {
+    wasm_encoder::MemArg { offset: offset as u64, align: 3, memory_index: 0 }
+}
+
+/// `$m` plus the offset of x`$i` (taken mod 32)
+fn emit_reg_addr(f: &mut Function) {
+    f.instruction(&Instruction::LocalGet(0));
+    f.instruction(&Instruction::LocalGet(1));
+    f.instruction(&Instruction::I32Const(31));
+    f.instruction(&Instruction::I32And);
+    f.instruction(&Instruction::I32Const(3));
+    f.instruction(&Instruction::I32Shl);
+    f.instruction(&Instruction::I32Add);
+}
+
+/// get_reg($m, $i) -> x$i
+fn build_get_reg() -> Function {
+    let mut f = Function::new(vec![]);
+    emit_reg_addr(&mut f);
+    f.instruction(&Instruction::I64Load(state_arg(0)));
+    f.instruction(&Instruction::End);
+    f
+}
+
+/// set_reg($m, $i, $value): x$i = $value; writes to x0 are dropped
+fn build_set_reg() -> Function {
+    let mut f = Function::new(vec![]);
+    f.instruction(&Instruction::LocalGet(1));
+    f.instruction(&Instruction::I32Const(31));
+    f.instruction(&Instruction::I32And);
+    f.instruction(&Instruction::If(wasm_encoder::BlockType::Empty));
+    emit_reg_addr(&mut f);
+    f.instruction(&Instruction::LocalGet(2));
+    f.instruction(&Instruction::I64Store(state_arg(0)));
+    f.instruction(&Instruction::End);
+    f.instruction(&Instruction::End);
+    f
+}
+
+/// get_pc($m) -> the PC at `PC_OFFSET`, with the flags the dispatch loop
+/// stored it with decoded: a trap's faulting PC, or the PC of an ECALL or
+/// EBREAK
+fn build_get_pc() -> Function {
+    use crate::abi::{EBREAK_FLAG, ECALL_FLAG, PAGE_FAULT};
+    use crate::translate::{PC_OFFSET, TRAP_PC_OFFSET};
+    use wasm_encoder::BlockType;
+
+    // Local 1: the stored value
+    let mut f = Function::new(vec![(1, ValType::I64)]);
+    f.instruction(&Instruction::LocalGet(0));
+    f.instruction(&Instruction::I64Load(state_arg(PC_OFFSET)));
+    f.instruction(&Instruction::LocalTee(1));
+    f.instruction(&Instruction::I64Const(ECALL_FLAG as i64));
+    f.instruction(&Instruction::I64And);
+    f.instruction(&Instruction::I64Eqz);
+    f.instruction(&Instruction::If(BlockType::Result(ValType::I64)));
+    f.instruction(&Instruction::LocalGet(1));
+    f.instruction(&Instruction::Else);
+    f.instruction(&Instruction::LocalGet(1));
+    f.instruction(&Instruction::I64Const(PAGE_FAULT as i64));
+    f.instruction(&Instruction::I64GeU);
+    f.instruction(&Instruction::If(BlockType::Result(ValType::I64)));
+    f.instruction(&Instruction::LocalGet(0));
+    f.instruction(&Instruction::I64Load(state_arg(TRAP_PC_OFFSET)));
+    f.instruction(&Instruction::Else);
+    f.instruction(&Instruction::LocalGet(1));
+    f.instruction(&Instruction::I64Const(!EBREAK_FLAG as i64));
+    f.instruction(&Instruction::I64And);
+    f.instruction(&Instruction::End);
+    f.instruction(&Instruction::End);
+    f.instruction(&Instruction::End);
+    f
+}
+
+/// set_pc($m, $pc): store `$pc` at `PC_OFFSET`
+fn build_set_pc() -> Function {
+    let mut f = Function::new(vec![]);
+    f.instruction(&Instruction::LocalGet(0));
+    f.instruction(&Instruction::LocalGet(1));
+    f.instruction(&Instruction::I64Store(state_arg(crate::translate::PC_OFFSET)));
+    f.instruction(&Instruction::End);
+    f
+}
+
+/// Wrapper that calls the guest function at `entry` with the standard ABI:
+/// a0-a7 from the parameters, ra = `RETURN_ADDR` so its return ends the
+/// dispatch loop, then (a0, a1) as the result. The caller must have set up
+/// the rest of the machine state (sp, gp, tp) beforehand.
+fn build_export_wrapper(entry: u64, dispatch: u32) -> Function {
+    use crate::translate::RETURN_ADDR;
+
+    let mem = |reg: u32| wasm_encoder::MemArg {
+        offset: reg as u64 * 8,
+        align: 3,
+        memory_index: 0,
+    };
+    let mut f = Function::new(vec![]);
+
+    // x10-x17 = params 1-8
+    for i in 0..8 {
+        f.instruction(&Instruction::LocalGet(0));
+        f.instruction(&Instruction::LocalGet(1 + i));
+        f.instruction(&Instruction::I64Store(mem(10 + i)));
+    }
+    f.instruction(&Instruction::LocalGet(0));
+    f.instruction(&Instruction::I64Const(RETURN_ADDR as i64));
+    f.instruction(&Instruction::I64Store(mem(1)));
+
+    // run($m, entry)
+    f.instruction(&Instruction::LocalGet(0));
+    f.instruction(&Instruction::I32Const(entry as i32));
+    f.instruction(&Instruction::Call(dispatch));
+    f.instruction(&Instruction::Drop);
+
+    f.instruction(&Instruction::LocalGet(0));
+    f.instruction(&Instruction::I64Load(mem(10)));
+    f.instruction(&Instruction::LocalGet(0));
+    f.instruction(&Instruction::I64Load(mem(11)));
+    f.instruction(&Instruction::End);
+    f
+}
+
+/// init_data(addr) -> 1 if it copied in the data segment of the page holding
+/// `addr`, 0 if that page has none or it was already initialized. Segment
+/// `k` is guarded by global `k`; segments are kept for `reset`.
+fn build_init_data(module: &ModuleLayout) -> Function {
+    use crate::data::CHUNK_SIZE;
+
+    let mut f = Function::new(vec![(1, ValType::I32)]);
+    f.instruction(&Instruction::LocalGet(0));
+    f.instruction(&Instruction::I32Const(CHUNK_SIZE.trailing_zeros() as i32));
+    f.instruction(&Instruction::I32ShrU);
+    f.instruction(&Instruction::LocalSet(1));
+
+    for (k, chunk) in module.data.iter().enumerate() {
+        let k = k as u32;
+        f.instruction(&Instruction::LocalGet(1));
+        f.instruction(&Instruction::I32Const(chunk.page() as i32));
+        f.instruction(&Instruction::I32Eq);
+        f.instruction(&Instruction::GlobalGet(k));
+        f.instruction(&Instruction::I32Eqz);
+        f.instruction(&Instruction::I32And);
+        f.instruction(&Instruction::If(wasm_encoder::BlockType::Empty));
+        f.instruction(&Instruction::I32Const(chunk.addr as i32 + module.memory_offset as i32));
+        f.instruction(&Instruction::I32Const(0));
+        f.instruction(&Instruction::I32Const(chunk.bytes.len() as i32));
+        f.instruction(&Instruction::MemoryInit { mem: 0, data_index: k });
+        f.instruction(&Instruction::I32Const(1));
+        f.instruction(&Instruction::GlobalSet(k));
+        f.instruction(&Instruction::I32Const(1));
+        f.instruction(&Instruction::Return);
+        f.instruction(&Instruction::End);
+    }
+
+    f.instruction(&Instruction::I32Const(0));
+    f.instruction(&Instruction::End);
+    f
+}
+
+/// init_memory(): copy in every data segment not yet initialized
+fn build_init_memory(module: &ModuleLayout, init_data: u32) -> Function {
+    let mut f = Function::new(vec![]);
+    for chunk in module.data.iter() {
+        f.instruction(&Instruction::I32Const(chunk.addr as i32));
+        f.instruction(&Instruction::Call(init_data));
+        f.instruction(&Instruction::Drop);
+    }
+    f.instruction(&Instruction::End);
+    f
+}
+
+/// Global holding the sp of the first run, for `reset`; it follows the
+/// data segments' flags
+fn boot_sp(module: &ModuleLayout) -> u32 {
+    module.data.len() as u32
+}
+
+/// reset($m): put the hart at `$m` back where the host started it: zeros,
+/// no reservation, the same hart id, the entry PC and the sp of the first
+/// run. With embedded data, memory goes back to the initial image too: the
+/// loaded segments and bss are zeroed and every data segment copied in
+/// again. A host can then run the guest again without reinstantiating.
+fn build_reset(module: &ModuleLayout) -> Function {
+    use crate::translate::{HART_ID_OFFSET, MACHINE_STATE_SIZE, PC_OFFSET, RESERVATION_ADDR_OFFSET};
+
+    // Local 1 = the hart id across the state fill
+    let mut f = Function::new(vec![(1, ValType::I64)]);
+    if !module.data.is_empty() {
+        // Without the data the host's image cannot be restored, so memory
+        // is left alone
+        for range in &module.image {
+            f.instruction(&Instruction::I32Const((range.start + module.memory_offset as u64) as i32));
+            f.instruction(&Instruction::I32Const(0));
+            f.instruction(&Instruction::I32Const((range.end - range.start) as i32));
+            f.instruction(&Instruction::MemoryFill(0));
+        }
+        for (k, chunk) in module.data.iter().enumerate() {
+            f.instruction(&Instruction::I32Const(chunk.addr as i32 + module.memory_offset as i32));
+            f.instruction(&Instruction::I32Const(0));
+            f.instruction(&Instruction::I32Const(chunk.bytes.len() as i32));
+            f.instruction(&Instruction::MemoryInit { mem: 0, data_index: k as u32 });
+            f.instruction(&Instruction::I32Const(1));
+            f.instruction(&Instruction::GlobalSet(k as u32));
+        }
+    }
+
+    // The state may live below memory_offset, with the host's data
+    f.instruction(&Instruction::LocalGet(0));
+    f.instruction(&Instruction::I64Load(state_arg(HART_ID_OFFSET)));
+    f.instruction(&Instruction::LocalSet(1));
+    f.instruction(&Instruction::LocalGet(0));
+    f.instruction(&Instruction::I32Const(0));
+    f.instruction(&Instruction::I32Const(MACHINE_STATE_SIZE as i32));
+    f.instruction(&Instruction::MemoryFill(0));
+    f.instruction(&Instruction::LocalGet(0));
+    f.instruction(&Instruction::I64Const(-1));
+    f.instruction(&Instruction::I64Store(state_arg(RESERVATION_ADDR_OFFSET)));
+    f.instruction(&Instruction::LocalGet(0));
+    f.instruction(&Instruction::LocalGet(1));
+    f.instruction(&Instruction::I64Store(state_arg(HART_ID_OFFSET)));
+    f.instruction(&Instruction::LocalGet(0));
+    f.instruction(&Instruction::I64Const(module.entry as i64));
+    f.instruction(&Instruction::I64Store(state_arg(PC_OFFSET)));
+    f.instruction(&Instruction::LocalGet(0));
+    f.instruction(&Instruction::GlobalGet(boot_sp(module)));
+    f.instruction(&Instruction::I64Store(state_arg(2 * 8)));
+    f.instruction(&Instruction::End);
+    f
+}
+
 /// Build a JIT Wasm module — simpler than AOT:
-/// - Imports shared memory from "env"/"memory"
-/// - No dispatch function — JS manages block dispatch
+/// - Imports shared memory and the `link.rs` helper functions
+/// - No dispatch function — the host manages block dispatch
 /// - Each block function exported by name (block_XXXXXXXX)
 /// - No table or element sections needed
 /// - Syscalls returned via high-bit convention (same as AOT)
 pub fn build_jit(module: &WasmModule) -> Result<Vec<u8>> {
     let mut wasm = Module::new();
 
-    // Type section: block function (param $m i32) (result i32)
+    // Types: block function (param $m i32) (result i32), the syscall
+    // handler (param $m i32, $pc i32) (result i32), the yield hint (), the
+    // AES helper (param $op i32, $rs1 i64, $rs2 i64) (result i64), the
+    // watchpoint hook (param $addr i32, $size i32, $is_write i32, $pc i32),
+    // interposed functions (a0-a7 i64) (result i64); the breakpoint hook
+    // has the block function type
     let mut types = TypeSection::new();
     types.function(vec![ValType::I32], vec![ValType::I32]);
+    types.function(vec![ValType::I32, ValType::I32], vec![ValType::I32]);
+    types.function(vec![], vec![]);
+    types.function(vec![ValType::I32, ValType::I64, ValType::I64], vec![ValType::I64]);
+    types.function(vec![ValType::I32; 4], vec![]);
+    types.function(vec![ValType::I64; 8], vec![ValType::I64]);
     wasm.section(&types);
 
-    // Import section: shared memory
+    // Imports: shared memory (pages negotiated with the host), syscall,
+    // yield, crypto, then watch and breakpoint in debug builds and the
+    // interposed functions
     let mut imports = ImportSection::new();
-    imports.import(
-        "env",
-        "memory",
-        MemoryType {
-            minimum: 256, // negotiated with runtime (16MB min)
-            maximum: Some(65536), // 4GB max
-            memory64: false,
-            shared: true,
-        },
-    );
+    imports.import(IMPORT_MODULE, link::MEMORY, link::memory_type(module.memory_pages as u64, true));
+    imports.import(IMPORT_MODULE, link::SYSCALL, EntityType::Function(1));
+    imports.import(IMPORT_MODULE, link::YIELD, EntityType::Function(2));
+    imports.import(IMPORT_MODULE, link::CRYPTO, EntityType::Function(3));
+    if module.debug_imports() {
+        imports.import(IMPORT_MODULE, link::WATCH, EntityType::Function(4));
+        imports.import(IMPORT_MODULE, link::BREAKPOINT, EntityType::Function(0));
+    }
+    import_interposed(&mut imports, &module.interpose, 5);
     wasm.section(&imports);
+    let first = module.imported_funcs();
 
     // Function section
     let mut functions = FunctionSection::new();
@@ -186,10 +1142,10 @@ pub fn build_jit(module: &WasmModule) -> Result<Vec<u8>> {
     }
     wasm.section(&functions);
 
-    // Export section: each block function exported by name
+    // Export section: each block function exported by name, after the imports
     let mut exports = ExportSection::new();
     for (idx, func) in module.functions.iter().enumerate() {
-        exports.export(&func.name, ExportKind::Func, idx as u32);
+        exports.export(&func.name, ExportKind::Func, first + idx as u32);
     }
     wasm.section(&exports);
 
@@ -201,53 +1157,249 @@ pub fn build_jit(module: &WasmModule) -> Result<Vec<u8>> {
     }
     wasm.section(&codes);
 
+    let block_addrs: Vec<u64> = module.functions.iter().map(|f| f.block_addr).collect();
+    wasm.section(&link::block_map_section(&block_addrs));
+
     Ok(wasm.finish())
 }
 
+/// JSPI mode: replace the flagged PC on the stack with the plain PC and
+/// the `abi::CAUSE_*` it stood for, so the suspending import gets both as
+/// arguments. Uses local 3 (the table index) as scratch.
+fn emit_syscall_cause(func: &mut Function) {
+    use crate::abi::{CAUSE_EBREAK, CAUSE_ECALL, CAUSE_ILLEGAL, EBREAK_FLAG, ILLEGAL_TRAP, PAGE_FAULT};
+    use wasm_encoder::BlockType;
+
+    func.instruction(&Instruction::LocalSet(3));
+    // Traps: cause ILLEGAL, MISALIGNED or PAGE_FAULT in the order of their
+    // codes, downward from ILLEGAL_TRAP; the PC is in the machine state
+    func.instruction(&Instruction::LocalGet(3));
+    func.instruction(&Instruction::I32Const(PAGE_FAULT as i32));
+    func.instruction(&Instruction::I32GeU);
+    func.instruction(&Instruction::If(BlockType::Result(ValType::I32)));
+    func.instruction(&Instruction::LocalGet(0));
+    func.instruction(&Instruction::I64Load(wasm_encoder::MemArg {
+        offset: crate::translate::TRAP_PC_OFFSET as u64,
+        align: 3,
+        memory_index: 0,
+    }));
+    func.instruction(&Instruction::I32WrapI64);
+    func.instruction(&Instruction::I32Const(ILLEGAL_TRAP as i32 + CAUSE_ILLEGAL));
+    func.instruction(&Instruction::LocalGet(3));
+    func.instruction(&Instruction::I32Sub);
+    func.instruction(&Instruction::LocalSet(3));
+    func.instruction(&Instruction::Else);
+    func.instruction(&Instruction::LocalGet(3));
+    func.instruction(&Instruction::I32Const(EBREAK_FLAG as i32));
+    func.instruction(&Instruction::I32And);
+    func.instruction(&Instruction::I32Const(EBREAK_FLAG as i32));
+    func.instruction(&Instruction::I32Eq);
+    func.instruction(&Instruction::If(BlockType::Result(ValType::I32)));
+    func.instruction(&Instruction::LocalGet(3));
+    func.instruction(&Instruction::I32Const(!EBREAK_FLAG as i32));
+    func.instruction(&Instruction::I32And);
+    func.instruction(&Instruction::I32Const(CAUSE_EBREAK));
+    func.instruction(&Instruction::LocalSet(3));
+    func.instruction(&Instruction::Else);
+    func.instruction(&Instruction::LocalGet(3));
+    func.instruction(&Instruction::I32Const(!crate::abi::ECALL_FLAG as i32));
+    func.instruction(&Instruction::I32And);
+    func.instruction(&Instruction::I32Const(CAUSE_ECALL));
+    func.instruction(&Instruction::LocalSet(3));
+    func.instruction(&Instruction::End);
+    func.instruction(&Instruction::End);
+    func.instruction(&Instruction::LocalGet(3));
+}
+
+/// Trap exceptions: if $pc (local 2) is a trap code, throw `guest_trap`
+/// with its `abi::CAUSE_*`, the faulting PC and the faulting address (0
+/// for an illegal instruction). Uses local 3 (the table index) as scratch.
+fn emit_throw_trap(func: &mut Function) {
+    use crate::abi::{CAUSE_ILLEGAL, ILLEGAL_TRAP, PAGE_FAULT};
+    use crate::translate::{TRAP_PC_OFFSET, TRAP_VALUE_OFFSET};
+    let load = |offset: u32| {
+        Instruction::I64Load(wasm_encoder::MemArg { offset: offset as u64, align: 3, memory_index: 0 })
+    };
+
+    func.instruction(&Instruction::LocalGet(2));
+    func.instruction(&Instruction::I32Const(PAGE_FAULT as i32));
+    func.instruction(&Instruction::I32GeU);
+    func.instruction(&Instruction::If(wasm_encoder::BlockType::Empty));
+    // The trap codes count down from ILLEGAL_TRAP as the causes count up
+    func.instruction(&Instruction::I32Const(ILLEGAL_TRAP as i32 + CAUSE_ILLEGAL));
+    func.instruction(&Instruction::LocalGet(2));
+    func.instruction(&Instruction::I32Sub);
+    func.instruction(&Instruction::LocalTee(3));
+    func.instruction(&Instruction::LocalGet(0));
+    func.instruction(&load(TRAP_PC_OFFSET));
+    func.instruction(&Instruction::LocalGet(0));
+    func.instruction(&load(TRAP_VALUE_OFFSET));
+    func.instruction(&Instruction::I64Const(0));
+    func.instruction(&Instruction::LocalGet(3));
+    func.instruction(&Instruction::I32Const(CAUSE_ILLEGAL));
+    func.instruction(&Instruction::I32Ne);
+    func.instruction(&Instruction::Select);
+    func.instruction(&Instruction::Throw(0));
+    func.instruction(&Instruction::End);
+}
+
 /// Build the main dispatch function with O(1) block lookup via call_indirect
-fn build_dispatch_function(module: &WasmModule, addr_to_table_idx: &BTreeMap<u64, u32>) -> Function {
-    // Locals: param 0 = $m (i32), param 1 = $start_pc (i32), local 2 = $pc (i32)
-    let mut func = Function::new(vec![(1, ValType::I32)]); // 1 local for pc
+/// (through `call_block` in split mode, which loads missing code first, and
+/// `block_index` in size mode)
+fn build_dispatch_function(layout: &ModuleLayout, blocks: u32) -> Function {
+    let block_addrs = &layout.block_addrs[..];
+    let idx = Indices::new(layout, blocks);
+    let call = if layout.is_split() {
+        Instruction::Call(idx.helpers)
+    } else {
+        Instruction::CallIndirect { ty: 0, table: 0 }
+    };
+    let fallback = Fallback { compile_missing: idx.compile_missing, policy: layout.unknown_pc, import: idx.unknown_pc };
+
+    // Table index = position in function order (0, 1, 2, ...)
+    let addr_to_table_idx: BTreeMap<u64, u32> = block_addrs
+        .iter()
+        .enumerate()
+        .map(|(i, &addr)| (addr, i as u32))
+        .collect();
+
+    // Locals: param 0 = $m (i32), param 1 = $start_pc (i32), local 2 = $pc
+    // (i32), local 3 = table index (i32), and unless unknown PCs halt,
+    // local 4 = $last (the last block run) and local 5 = $this (the block
+    // about to run)
+    let tracked = layout.unknown_pc != UnknownPc::Halt;
+    let mut func = Function::new(vec![(2 + 2 * tracked as u32, ValType::I32)]);
 
     // Initialize $pc from parameter
     func.instruction(&Instruction::LocalGet(1));
     func.instruction(&Instruction::LocalSet(2));
 
+    // The first run with an sp set records it for `reset`
+    func.instruction(&Instruction::GlobalGet(boot_sp(layout)));
+    func.instruction(&Instruction::I64Eqz);
+    func.instruction(&Instruction::If(wasm_encoder::BlockType::Empty));
+    func.instruction(&Instruction::LocalGet(0));
+    func.instruction(&Instruction::I64Load(state_arg(2 * 8)));
+    func.instruction(&Instruction::GlobalSet(boot_sp(layout)));
+    func.instruction(&Instruction::End);
+
     // Main dispatch loop
     func.instruction(&Instruction::Loop(wasm_encoder::BlockType::Empty));
 
-    // Check for halt (-1)
+    // Check for halt (-1) or a return from an export wrapper (-2)
     func.instruction(&Instruction::LocalGet(2));
-    func.instruction(&Instruction::I32Const(-1));
-    func.instruction(&Instruction::I32Eq);
+    func.instruction(&Instruction::I32Const(-2));
+    func.instruction(&Instruction::I32GeU);
     func.instruction(&Instruction::If(wasm_encoder::BlockType::Empty));
     func.instruction(&Instruction::I32Const(0));
     func.instruction(&Instruction::Return);
     func.instruction(&Instruction::End);
 
+    // Metered builds: a block short of budget ends the run
+    if layout.meter {
+        func.instruction(&Instruction::LocalGet(2));
+        func.instruction(&Instruction::I32Const(crate::abi::STOP_BUDGET as i32));
+        func.instruction(&Instruction::I32Eq);
+        func.instruction(&Instruction::If(wasm_encoder::BlockType::Empty));
+        func.instruction(&Instruction::LocalGet(2));
+        func.instruction(&Instruction::Return);
+        func.instruction(&Instruction::End);
+    }
+
     // Check for syscall (high bit set = 0x80000000)
     func.instruction(&Instruction::LocalGet(2));
-    func.instruction(&Instruction::I32Const(0x80000000u32 as i32));
+    func.instruction(&Instruction::I32Const(crate::abi::ECALL_FLAG as i32));
     func.instruction(&Instruction::I32And);
     func.instruction(&Instruction::If(wasm_encoder::BlockType::Empty));
+    // Leave the flagged PC for `get_pc`
+    func.instruction(&Instruction::LocalGet(0));
+    func.instruction(&Instruction::LocalGet(2));
+    func.instruction(&Instruction::I64ExtendI32U);
+    func.instruction(&Instruction::I64Store(state_arg(crate::translate::PC_OFFSET)));
+    if layout.trap_exceptions {
+        emit_throw_trap(&mut func);
+    }
     func.instruction(&Instruction::LocalGet(0)); // $m
     func.instruction(&Instruction::LocalGet(2)); // $pc with flags
+    if layout.jspi {
+        emit_syscall_cause(&mut func);
+    }
     func.instruction(&Instruction::Call(0)); // syscall handler (import index 0)
-    func.instruction(&Instruction::LocalSet(2));
+    func.instruction(&Instruction::LocalTee(2));
+    // A stop code (exit, signal, trap) ends the run and is its result
+    func.instruction(&Instruction::I32Const(0xFFFF_0000u32 as i32));
+    func.instruction(&Instruction::I32And);
+    func.instruction(&Instruction::I32Const(crate::abi::STOP as i32));
+    func.instruction(&Instruction::I32Eq);
+    func.instruction(&Instruction::If(wasm_encoder::BlockType::Empty));
+    func.instruction(&Instruction::LocalGet(2));
+    func.instruction(&Instruction::Return);
+    func.instruction(&Instruction::End);
     func.instruction(&Instruction::Br(1)); // Continue loop
     func.instruction(&Instruction::End);
 
+    if tracked {
+        func.instruction(&Instruction::LocalGet(5));
+        func.instruction(&Instruction::LocalSet(4));
+        func.instruction(&Instruction::LocalGet(2));
+        func.instruction(&Instruction::LocalSet(5));
+    }
+
     // Dispatch to block via call_indirect
     // We need to convert PC address to table index
     // Strategy: Use computed index if addresses are dense, else if-else chain
 
-    if module.functions.is_empty() {
+    if block_addrs.is_empty() && fallback.checked() {
+        emit_unknown_pc(&mut func, fallback);
+    } else if block_addrs.is_empty() {
         // No blocks - just return
         func.instruction(&Instruction::I32Const(0));
         func.instruction(&Instruction::Return);
-    } else if can_use_dense_table(module) {
+    } else if layout.size {
+        // Size mode: $pc = table[block_index($pc)]($m), halting on -1
+        func.instruction(&Instruction::LocalGet(2));
+        func.instruction(&Instruction::Call(idx.block_index));
+        func.instruction(&Instruction::LocalTee(3));
+        func.instruction(&Instruction::I32Const(0));
+        func.instruction(&Instruction::I32LtS);
+        func.instruction(&Instruction::If(wasm_encoder::BlockType::Empty));
+        emit_unknown_pc(&mut func, fallback);
+        func.instruction(&Instruction::Else);
+        func.instruction(&Instruction::LocalGet(0));
+        func.instruction(&Instruction::LocalGet(3));
+        func.instruction(&call);
+        func.instruction(&Instruction::LocalSet(2));
+        func.instruction(&Instruction::End);
+    } else if can_use_dense_table(block_addrs) && fallback.checked() {
+        // Dense table, falling back to compile_missing or the unknown-PC
+        // policy off the table
+        func.instruction(&Instruction::Block(wasm_encoder::BlockType::Empty));
+        func.instruction(&Instruction::Block(wasm_encoder::BlockType::Empty));
+        func.instruction(&Instruction::LocalGet(2));
+        func.instruction(&Instruction::I32Const(block_addrs[0] as i32));
+        func.instruction(&Instruction::I32Sub);
+        func.instruction(&Instruction::LocalTee(3));
+        func.instruction(&Instruction::I32Const(3));
+        func.instruction(&Instruction::I32And);
+        func.instruction(&Instruction::BrIf(0));
+        func.instruction(&Instruction::LocalGet(3));
+        func.instruction(&Instruction::I32Const(2));
+        func.instruction(&Instruction::I32ShrU);
+        func.instruction(&Instruction::LocalTee(3));
+        func.instruction(&Instruction::I32Const(block_addrs.len() as i32));
+        func.instruction(&Instruction::I32GeU);
+        func.instruction(&Instruction::BrIf(0));
+        func.instruction(&Instruction::LocalGet(0));
+        func.instruction(&Instruction::LocalGet(3));
+        func.instruction(&call);
+        func.instruction(&Instruction::LocalSet(2));
+        func.instruction(&Instruction::Br(1));
+        func.instruction(&Instruction::End);
+        emit_unknown_pc(&mut func, fallback);
+        func.instruction(&Instruction::End);
+    } else if can_use_dense_table(block_addrs) {
         // Dense table: (pc - base_addr) / 4 gives table index
-        let base_addr = module.functions.first().map(|f| f.block_addr).unwrap_or(0);
+        let base_addr = block_addrs[0];
 
         // Push $m for call_indirect param
         func.instruction(&Instruction::LocalGet(0));
@@ -260,16 +1412,13 @@ fn build_dispatch_function(module: &WasmModule, addr_to_table_idx: &BTreeMap<u64
         func.instruction(&Instruction::I32ShrU);
 
         // call_indirect with type 0 (block function signature)
-        func.instruction(&Instruction::CallIndirect {
-            ty: 0,
-            table: 0,
-        });
+        func.instruction(&call);
 
         func.instruction(&Instruction::LocalSet(2));
     } else {
         // Sparse addresses: use br_table with block nesting
         // Generate a block per address with nested blocks for br_table targets
-        emit_sparse_dispatch(&mut func, module, addr_to_table_idx);
+        emit_sparse_dispatch(&mut func, &addr_to_table_idx, &call, fallback);
     }
 
     func.instruction(&Instruction::Br(0)); // Continue loop
@@ -281,29 +1430,116 @@ fn build_dispatch_function(module: &WasmModule, addr_to_table_idx: &BTreeMap<u64
     func
 }
 
-/// Check if block addresses are dense enough for (pc - base) / 4 indexing
-fn can_use_dense_table(module: &WasmModule) -> bool {
-    if module.functions.len() <= 1 {
-        return true;
+/// What the dispatch loop does with a `$pc` it has no block for
+#[derive(Clone, Copy)]
+struct Fallback {
+    /// `env.compile_missing` import (lazy compilation), tried first
+    compile_missing: Option<u32>,
+    policy: UnknownPc,
+    /// `env.unknown_pc` import (`UnknownPc::Import`)
+    import: Option<u32>,
+}
+
+impl Fallback {
+    /// Whether the dispatch must tell unknown PCs from blocks, rather than
+    /// only halting on them
+    fn checked(&self) -> bool {
+        self.compile_missing.is_some() || self.policy != UnknownPc::Halt
     }
+}
 
-    let addrs: Vec<u64> = module.functions.iter().map(|f| f.block_addr).collect();
-    let min_addr = *addrs.iter().min().unwrap();
-    let max_addr = *addrs.iter().max().unwrap();
+/// Default dispatch case, for a `$pc` without a block: the unknown-PC
+/// policy, or (lazy mode) `$pc = table[compile_missing($pc)]($m)`, applying
+/// the policy if that returns -1
+fn emit_unknown_pc(func: &mut Function, fallback: Fallback) {
+    let Some(compile_missing) = fallback.compile_missing else {
+        emit_no_code(func, fallback);
+        return;
+    };
+    func.instruction(&Instruction::LocalGet(2));
+    func.instruction(&Instruction::Call(compile_missing));
+    func.instruction(&Instruction::LocalTee(3));
+    func.instruction(&Instruction::I32Const(0));
+    func.instruction(&Instruction::I32LtS);
+    func.instruction(&Instruction::If(wasm_encoder::BlockType::Empty));
+    emit_no_code(func, fallback);
+    func.instruction(&Instruction::Else);
+    func.instruction(&Instruction::LocalGet(0));
+    func.instruction(&Instruction::LocalGet(3));
+    func.instruction(&Instruction::CallIndirect { ty: 0, table: 0 });
+    func.instruction(&Instruction::LocalSet(2));
+    func.instruction(&Instruction::End);
+}
+
+/// The unknown-PC policy for `$pc`. Except when halting, the PC goes to
+/// `TRAP_PC_OFFSET` (and `PC_OFFSET`, for `get_pc`) and the last block run,
+/// `$last`, to `TRAP_VALUE_OFFSET` first.
+fn emit_no_code(func: &mut Function, fallback: Fallback) {
+    use crate::translate::{PC_OFFSET, TRAP_PC_OFFSET, TRAP_VALUE_OFFSET};
+    if fallback.policy == UnknownPc::Halt {
+        func.instruction(&Instruction::I32Const(-1));
+        func.instruction(&Instruction::LocalSet(2));
+        return;
+    }
+    for (offset, local) in [(TRAP_PC_OFFSET, 2), (PC_OFFSET, 2), (TRAP_VALUE_OFFSET, 4)] {
+        func.instruction(&Instruction::LocalGet(0));
+        func.instruction(&Instruction::LocalGet(local));
+        func.instruction(&Instruction::I64ExtendI32U);
+        func.instruction(&Instruction::I64Store(state_arg(offset)));
+    }
+    match (fallback.policy, fallback.import) {
+        (UnknownPc::Import, Some(import)) => {
+            func.instruction(&Instruction::LocalGet(2));
+            func.instruction(&Instruction::Call(import));
+            func.instruction(&Instruction::LocalTee(2));
+            // A stop code ends the run, as from the syscall handler
+            func.instruction(&Instruction::I32Const(0xFFFF_0000u32 as i32));
+            func.instruction(&Instruction::I32And);
+            func.instruction(&Instruction::I32Const(crate::abi::STOP as i32));
+            func.instruction(&Instruction::I32Eq);
+            func.instruction(&Instruction::If(wasm_encoder::BlockType::Empty));
+            func.instruction(&Instruction::LocalGet(2));
+            func.instruction(&Instruction::Return);
+            func.instruction(&Instruction::End);
+            // No block ran, so the last one is still the last
+            func.instruction(&Instruction::LocalGet(4));
+            func.instruction(&Instruction::LocalSet(5));
+        }
+        (UnknownPc::Trap, _) => {
+            func.instruction(&Instruction::Unreachable);
+        }
+        _ => {
+            func.instruction(&Instruction::I32Const(crate::abi::STOP_UNKNOWN_PC as i32));
+            func.instruction(&Instruction::Return);
+        }
+    }
+}
 
-    // Dense if span / 4 roughly equals number of blocks (allow 2x overhead)
-    let span = (max_addr - min_addr) / 4 + 1;
-    span <= (module.functions.len() as u64 * 2)
+/// Check if (pc - base) / 4 is exactly each block's table index, i.e. the
+/// blocks sit at consecutive 4-byte slots. Anything with gaps must go
+/// through the br_table dispatch, which maps holes to the default case.
+fn can_use_dense_table(block_addrs: &[u64]) -> bool {
+    let Some(&base) = block_addrs.first() else {
+        return true;
+    };
+    block_addrs
+        .iter()
+        .enumerate()
+        .all(|(i, &addr)| addr == base + i as u64 * 4)
 }
 
 /// Emit sparse dispatch using br_table with dense index mapping, or if-else fallback
-fn emit_sparse_dispatch(func: &mut Function, module: &WasmModule, addr_to_table_idx: &BTreeMap<u64, u32>) {
+fn emit_sparse_dispatch(
+    func: &mut Function,
+    addr_to_table_idx: &BTreeMap<u64, u32>,
+    call: &Instruction,
+    fallback: Fallback,
+) {
     let sorted_addrs: Vec<(u64, u32)> = addr_to_table_idx.iter().map(|(&a, &t)| (a, t)).collect();
     let n = sorted_addrs.len(); // number of real blocks
 
     if n == 0 {
-        func.instruction(&Instruction::I32Const(-1));
-        func.instruction(&Instruction::LocalSet(2));
+        emit_unknown_pc(func, fallback);
         return;
     }
 
@@ -320,10 +1556,10 @@ fn emit_sparse_dispatch(func: &mut Function, module: &WasmModule, addr_to_table_
 
     // Use br_table for O(1) dispatch when table fits in memory
     if table_size <= 65536 {
-        emit_br_table_dispatch(func, &sorted_addrs, base_addr, alignment, table_size, n);
+        emit_br_table_dispatch(func, &sorted_addrs, base_addr, alignment, table_size, call, fallback);
     } else {
         // Fallback: if-else chain for extremely sparse address spaces
-        emit_if_else_dispatch(func, &sorted_addrs);
+        emit_if_else_dispatch(func, &sorted_addrs, call, fallback);
     }
 }
 
@@ -355,7 +1591,7 @@ fn gcd_u64(a: u64, b: u64) -> u64 {
 ///        ;; compute index = (pc - base) / alignment
 ///        br_table [targets...] default
 ///       end               ;; → DEFAULT handler
-///       DEFAULT: pc = -1; br n  (exits $outer)
+///       DEFAULT: pc = -1 (or compile_missing); br n  (exits $outer)
 ///      end                ;; → CASE 0 handler
 ///      CASE 0: call block_0; br (n-1)
 ///     end                 ;; → CASE 1 handler
@@ -369,8 +1605,10 @@ fn emit_br_table_dispatch(
     base_addr: u64,
     alignment: u64,
     table_size: usize,
-    n: usize,
+    call: &Instruction,
+    fallback: Fallback,
 ) {
+    let n = sorted_addrs.len();
     // Build address → case number mapping
     let mut addr_to_case: std::collections::HashMap<u64, usize> = std::collections::HashMap::new();
     for (case_num, &(addr, _)) in sorted_addrs.iter().enumerate() {
@@ -414,9 +1652,8 @@ fn emit_br_table_dispatch(
 
     // End $default block
     func.instruction(&Instruction::End);
-    // DEFAULT handler: unknown PC, halt
-    func.instruction(&Instruction::I32Const(-1));
-    func.instruction(&Instruction::LocalSet(2));
+    // DEFAULT handler: unknown PC
+    emit_unknown_pc(func, fallback);
     func.instruction(&Instruction::Br(n as u32)); // exit $outer
 
     // Emit case handlers (one per real block, in sorted address order)
@@ -426,7 +1663,7 @@ fn emit_br_table_dispatch(
         // Call block function via call_indirect
         func.instruction(&Instruction::LocalGet(0)); // $m
         func.instruction(&Instruction::I32Const(table_idx as i32));
-        func.instruction(&Instruction::CallIndirect { ty: 0, table: 0 });
+        func.instruction(call);
         func.instruction(&Instruction::LocalSet(2));
 
         // Break to $outer
@@ -437,7 +1674,7 @@ fn emit_br_table_dispatch(
 }
 
 /// Fallback: if-else chain dispatch for extremely sparse address spaces
-fn emit_if_else_dispatch(func: &mut Function, sorted_addrs: &[(u64, u32)]) {
+fn emit_if_else_dispatch(func: &mut Function, sorted_addrs: &[(u64, u32)], call: &Instruction, fallback: Fallback) {
     for &(addr, table_idx) in sorted_addrs {
         func.instruction(&Instruction::LocalGet(2)); // $pc
         func.instruction(&Instruction::I32Const(addr as i32));
@@ -446,24 +1683,148 @@ fn emit_if_else_dispatch(func: &mut Function, sorted_addrs: &[(u64, u32)]) {
 
         func.instruction(&Instruction::LocalGet(0)); // $m
         func.instruction(&Instruction::I32Const(table_idx as i32));
-        func.instruction(&Instruction::CallIndirect { ty: 0, table: 0 });
+        func.instruction(call);
         func.instruction(&Instruction::LocalSet(2));
         func.instruction(&Instruction::Br(1)); // break to loop continue
 
         func.instruction(&Instruction::End);
     }
 
-    // Default: unknown PC, halt
-    func.instruction(&Instruction::I32Const(-1));
-    func.instruction(&Instruction::LocalSet(2));
+    // Default: unknown PC
+    emit_unknown_pc(func, fallback);
+}
+
+/// block_index(pc): the dispatch table index of the block at `pc`, or -1.
+/// Built as a dense computation, a br_table and a compare chain where each
+/// applies, keeping the smallest encoding.
+fn build_block_index(block_addrs: &[u64]) -> Function {
+    let mut sorted: Vec<(u64, u32)> = block_addrs.iter().enumerate().map(|(i, &a)| (a, i as u32)).collect();
+    sorted.sort_unstable();
+
+    let mut candidates = vec![lookup_chain(&sorted)];
+    if let Some(&base) = block_addrs.first().filter(|_| can_use_dense_table(block_addrs)) {
+        candidates.push(lookup_dense(base, block_addrs.len() as u32));
+    }
+    candidates.extend(lookup_br_table(&sorted));
+    candidates
+        .into_iter()
+        .min_by_key(|f| {
+            let mut bytes = Vec::new();
+            f.encode(&mut bytes);
+            bytes.len()
+        })
+        .unwrap()
+}
+
+/// Blocks at consecutive 4-byte slots from `base`: index (pc - base) / 4
+/// if that is aligned and below `n`
+fn lookup_dense(base: u64, n: u32) -> Function {
+    let mut f = Function::new(vec![(1, ValType::I32)]);
+    f.instruction(&Instruction::LocalGet(0));
+    f.instruction(&Instruction::I32Const(base as i32));
+    f.instruction(&Instruction::I32Sub);
+    f.instruction(&Instruction::LocalTee(1));
+    f.instruction(&Instruction::I32Const(2));
+    f.instruction(&Instruction::I32ShrU);
+    f.instruction(&Instruction::I32Const(-1));
+    f.instruction(&Instruction::LocalGet(1));
+    f.instruction(&Instruction::I32Const(3));
+    f.instruction(&Instruction::I32And);
+    f.instruction(&Instruction::I32Eqz);
+    f.instruction(&Instruction::LocalGet(1));
+    f.instruction(&Instruction::I32Const(2));
+    f.instruction(&Instruction::I32ShrU);
+    f.instruction(&Instruction::I32Const(n as i32));
+    f.instruction(&Instruction::I32LtU);
+    f.instruction(&Instruction::I32And);
+    f.instruction(&Instruction::Select);
+    f.instruction(&Instruction::End);
+    f
+}
+
+/// br_table over (pc - base) / alignment, like the sparse dispatch, with a
+/// case returning each index. None if the table would be too large.
+fn lookup_br_table(sorted_addrs: &[(u64, u32)]) -> Option<Function> {
+    let (&(base, _), &(last, _)) = (sorted_addrs.first()?, sorted_addrs.last()?);
+    let alignment = compute_addr_alignment(sorted_addrs);
+    let table_size = ((last - base) / alignment + 1) as usize;
+    if table_size > 65536 {
+        return None;
+    }
+    let n = sorted_addrs.len();
+
+    let mut f = Function::new(vec![(1, ValType::I32)]);
+    for _ in 0..=n {
+        f.instruction(&Instruction::Block(wasm_encoder::BlockType::Empty)); // $case_j, then $default
+    }
+    f.instruction(&Instruction::LocalGet(0));
+    f.instruction(&Instruction::I32Const(base as i32));
+    f.instruction(&Instruction::I32Sub);
+    f.instruction(&Instruction::LocalSet(1));
+    // Off-grid PCs go to the default case
+    f.instruction(&Instruction::LocalGet(1));
+    if alignment.is_power_of_two() {
+        f.instruction(&Instruction::I32Const(alignment as i32 - 1));
+        f.instruction(&Instruction::I32And);
+    } else {
+        f.instruction(&Instruction::I32Const(alignment as i32));
+        f.instruction(&Instruction::I32RemU);
+    }
+    f.instruction(&Instruction::BrIf(0));
+    f.instruction(&Instruction::LocalGet(1));
+    if alignment.is_power_of_two() {
+        f.instruction(&Instruction::I32Const(alignment.trailing_zeros() as i32));
+        f.instruction(&Instruction::I32ShrU);
+    } else {
+        f.instruction(&Instruction::I32Const(alignment as i32));
+        f.instruction(&Instruction::I32DivU);
+    }
+
+    let cases: HashMap<u64, u32> =
+        sorted_addrs.iter().enumerate().map(|(case, &(a, _))| (a, case as u32 + 1)).collect();
+    let targets: Vec<u32> =
+        (0..table_size as u64).map(|i| cases.get(&(base + i * alignment)).copied().unwrap_or(0)).collect();
+    f.instruction(&Instruction::BrTable(targets.into(), 0));
+    f.instruction(&Instruction::End);
+    f.instruction(&Instruction::I32Const(-1));
+    f.instruction(&Instruction::Return);
+    for &(_, table_idx) in sorted_addrs {
+        f.instruction(&Instruction::End);
+        f.instruction(&Instruction::I32Const(table_idx as i32));
+        f.instruction(&Instruction::Return);
+    }
+    f.instruction(&Instruction::End);
+    Some(f)
+}
+
+/// One compare per block
+fn lookup_chain(sorted_addrs: &[(u64, u32)]) -> Function {
+    let mut f = Function::new(vec![]);
+    for &(addr, table_idx) in sorted_addrs {
+        f.instruction(&Instruction::LocalGet(0));
+        f.instruction(&Instruction::I32Const(addr as i32));
+        f.instruction(&Instruction::I32Eq);
+        f.instruction(&Instruction::If(wasm_encoder::BlockType::Empty));
+        f.instruction(&Instruction::I32Const(table_idx as i32));
+        f.instruction(&Instruction::Return);
+        f.instruction(&Instruction::End);
+    }
+    f.instruction(&Instruction::I32Const(-1));
+    f.instruction(&Instruction::End);
+    f
 }
 
 /// Build a block function from our IR
-fn build_block_function(func: &crate::translate::WasmFunction) -> Result<Function> {
-    let mut wasm_func = Function::new(vec![(func.num_locals, ValType::I64)]);
+fn build_block_function(func: &WasmFunction) -> Result<Function> {
+    // Two f64 locals after the i64 ones for `F64RelaxedMadd`
+    let mut locals = vec![(func.num_locals, ValType::I64)];
+    if func.body.contains(&WasmInst::F64RelaxedMadd) {
+        locals.push((2, ValType::F64));
+    }
+    let mut wasm_func = Function::new(locals);
 
     for inst in &func.body {
-        emit_instruction(&mut wasm_func, inst)?;
+        emit_instruction(&mut wasm_func, inst, &func.br_tables, func.num_locals + 1)?;
     }
 
     wasm_func.instruction(&Instruction::End);
@@ -471,8 +1832,18 @@ fn build_block_function(func: &crate::translate::WasmFunction) -> Result<Functio
     Ok(wasm_func)
 }
 
-/// Emit a single instruction
-fn emit_instruction(func: &mut Function, inst: &WasmInst) -> Result<()> {
+/// Memory argument for an atomic access of `1 << align` bytes
+fn atomic_memarg(offset: u32, align: u32) -> wasm_encoder::MemArg {
+    wasm_encoder::MemArg {
+        offset: offset as u64,
+        align,
+        memory_index: 0,
+    }
+}
+
+/// Emit a single instruction; `br_tables` are the owning function's label
+/// lists and `f64_locals` the first of its f64 locals
+fn emit_instruction(func: &mut Function, inst: &WasmInst, br_tables: &[Vec<u32>], f64_locals: u32) -> Result<()> {
     match inst {
         // Control flow
         WasmInst::Block { label: _ } => {
@@ -490,13 +1861,14 @@ fn emit_instruction(func: &mut Function, inst: &WasmInst) -> Result<()> {
         WasmInst::BrIf { label } => {
             func.instruction(&Instruction::BrIf(*label));
         }
-        WasmInst::BrTable { labels, default } => {
-            func.instruction(&Instruction::BrTable(labels.clone().into(), *default));
+        WasmInst::BrTable { table, default } => {
+            let labels = br_tables.get(*table as usize).context("BrTable references a missing label table")?;
+            func.instruction(&Instruction::BrTable(labels.into(), *default));
         }
         WasmInst::Return => {
             func.instruction(&Instruction::Return);
         }
-        WasmInst::Call { func_idx } => {
+        WasmInst::Call { func_idx } | WasmInst::CallHost { func_idx } => {
             func.instruction(&Instruction::Call(*func_idx));
         }
         WasmInst::CallIndirect { type_idx } => {
@@ -619,6 +1991,9 @@ fn emit_instruction(func: &mut Function, inst: &WasmInst) -> Result<()> {
                 memory_index: 0,
             }));
         }
+        WasmInst::MemoryFill => {
+            func.instruction(&Instruction::MemoryFill(0));
+        }
 
         // i64 arithmetic
         WasmInst::I64Add => {
@@ -767,6 +2142,9 @@ fn emit_instruction(func: &mut Function, inst: &WasmInst) -> Result<()> {
         WasmInst::I32ShrU => {
             func.instruction(&Instruction::I32ShrU);
         }
+        WasmInst::I32Rotr => {
+            func.instruction(&Instruction::I32Rotr);
+        }
         WasmInst::I32Eqz => {
             func.instruction(&Instruction::I32Eqz);
         }
@@ -808,10 +2186,87 @@ fn emit_instruction(func: &mut Function, inst: &WasmInst) -> Result<()> {
         WasmInst::I64ExtendI32S => {
             func.instruction(&Instruction::I64ExtendI32S);
         }
+        WasmInst::I64Extend8S => {
+            func.instruction(&Instruction::I64Extend8S);
+        }
+        WasmInst::I64Extend16S => {
+            func.instruction(&Instruction::I64Extend16S);
+        }
         WasmInst::I64ExtendI32U => {
             func.instruction(&Instruction::I64ExtendI32U);
         }
 
+        // Atomics (natural alignment is mandatory)
+        WasmInst::AtomicFence => {
+            func.instruction(&Instruction::AtomicFence);
+        }
+        WasmInst::I32AtomicLoad { offset } => {
+            func.instruction(&Instruction::I32AtomicLoad(atomic_memarg(*offset, 2)));
+        }
+        WasmInst::I64AtomicLoad { offset } => {
+            func.instruction(&Instruction::I64AtomicLoad(atomic_memarg(*offset, 3)));
+        }
+        WasmInst::I64AtomicLoad8U { offset } => {
+            func.instruction(&Instruction::I64AtomicLoad8U(atomic_memarg(*offset, 0)));
+        }
+        WasmInst::I64AtomicLoad16U { offset } => {
+            func.instruction(&Instruction::I64AtomicLoad16U(atomic_memarg(*offset, 1)));
+        }
+        WasmInst::I32AtomicStore { offset } => {
+            func.instruction(&Instruction::I32AtomicStore(atomic_memarg(*offset, 2)));
+        }
+        WasmInst::I64AtomicStore { offset } => {
+            func.instruction(&Instruction::I64AtomicStore(atomic_memarg(*offset, 3)));
+        }
+        WasmInst::I32AtomicRmwAdd { offset } => {
+            func.instruction(&Instruction::I32AtomicRmwAdd(atomic_memarg(*offset, 2)));
+        }
+        WasmInst::I64AtomicRmwAdd { offset } => {
+            func.instruction(&Instruction::I64AtomicRmwAdd(atomic_memarg(*offset, 3)));
+        }
+        WasmInst::I32AtomicRmwAnd { offset } => {
+            func.instruction(&Instruction::I32AtomicRmwAnd(atomic_memarg(*offset, 2)));
+        }
+        WasmInst::I64AtomicRmwAnd { offset } => {
+            func.instruction(&Instruction::I64AtomicRmwAnd(atomic_memarg(*offset, 3)));
+        }
+        WasmInst::I32AtomicRmwOr { offset } => {
+            func.instruction(&Instruction::I32AtomicRmwOr(atomic_memarg(*offset, 2)));
+        }
+        WasmInst::I64AtomicRmwOr { offset } => {
+            func.instruction(&Instruction::I64AtomicRmwOr(atomic_memarg(*offset, 3)));
+        }
+        WasmInst::I32AtomicRmwXor { offset } => {
+            func.instruction(&Instruction::I32AtomicRmwXor(atomic_memarg(*offset, 2)));
+        }
+        WasmInst::I64AtomicRmwXor { offset } => {
+            func.instruction(&Instruction::I64AtomicRmwXor(atomic_memarg(*offset, 3)));
+        }
+        WasmInst::I32AtomicRmwXchg { offset } => {
+            func.instruction(&Instruction::I32AtomicRmwXchg(atomic_memarg(*offset, 2)));
+        }
+        WasmInst::I64AtomicRmwXchg { offset } => {
+            func.instruction(&Instruction::I64AtomicRmwXchg(atomic_memarg(*offset, 3)));
+        }
+        WasmInst::I32AtomicRmwCmpxchg { offset } => {
+            func.instruction(&Instruction::I32AtomicRmwCmpxchg(atomic_memarg(*offset, 2)));
+        }
+        WasmInst::I64AtomicRmwCmpxchg { offset } => {
+            func.instruction(&Instruction::I64AtomicRmwCmpxchg(atomic_memarg(*offset, 3)));
+        }
+        WasmInst::I64AtomicRmw8CmpxchgU { offset } => {
+            func.instruction(&Instruction::I64AtomicRmw8CmpxchgU(atomic_memarg(*offset, 0)));
+        }
+        WasmInst::I64AtomicRmw16CmpxchgU { offset } => {
+            func.instruction(&Instruction::I64AtomicRmw16CmpxchgU(atomic_memarg(*offset, 1)));
+        }
+        WasmInst::MemoryAtomicWait32 { offset } => {
+            func.instruction(&Instruction::MemoryAtomicWait32(atomic_memarg(*offset, 2)));
+        }
+        WasmInst::MemoryAtomicNotify { offset } => {
+            func.instruction(&Instruction::MemoryAtomicNotify(atomic_memarg(*offset, 2)));
+        }
+
         // Stack
         WasmInst::Drop => {
             func.instruction(&Instruction::Drop);
@@ -977,6 +2432,18 @@ fn emit_instruction(func: &mut Function, inst: &WasmInst) -> Result<()> {
         WasmInst::F64Copysign => {
             func.instruction(&Instruction::F64Copysign);
         }
+        WasmInst::F64RelaxedMadd => {
+            // Splat a, b and c (b and c set aside meanwhile), then take lane 0
+            func.instruction(&Instruction::LocalSet(f64_locals + 1));
+            func.instruction(&Instruction::LocalSet(f64_locals));
+            func.instruction(&Instruction::F64x2Splat);
+            func.instruction(&Instruction::LocalGet(f64_locals));
+            func.instruction(&Instruction::F64x2Splat);
+            func.instruction(&Instruction::LocalGet(f64_locals + 1));
+            func.instruction(&Instruction::F64x2Splat);
+            func.instruction(&Instruction::F64x2RelaxedMadd);
+            func.instruction(&Instruction::F64x2ExtractLane(0));
+        }
 
         // FP conversions
         WasmInst::F32ConvertI32S => {
@@ -1079,6 +2546,7 @@ fn emit_instruction(func: &mut Function, inst: &WasmInst) -> Result<()> {
 mod tests {
     use super::*;
     use crate::translate::{WasmFunction, WasmModule};
+    use crate::test_util::{elf_info, inst};
 
     /// Helper: create a minimal WasmModule with block functions at the given addresses
     fn make_module(addrs: &[u64]) -> WasmModule {
@@ -1094,6 +2562,7 @@ mod tests {
                     WasmInst::I32Const { value: -1 }, // halt after this block
                 ],
                 num_locals: 0,
+                br_tables: Vec::new(),
             });
         }
         WasmModule {
@@ -1101,9 +2570,688 @@ mod tests {
             memory_pages: 8,
             entry: addrs.first().copied().unwrap_or(0),
             block_to_func,
+            threads: false,
+            exports: Vec::new(),
+            groups: Vec::new(),
+            data: Default::default(),
+            size: false,
+            block_exports: None,
+            memory_offset: 0,
+            soft_mmu: None,
+            time_page: None,
+            lazy_compile: false,
+            unknown_pc: UnknownPc::Halt,
+            jspi: false,
+            trap_exceptions: false,
+            harts: 1,
+            watch: None,
+            breakpoints: false,
+            interpose: Vec::new(),
+            meter: false,
+            max_pages: None,
+            image: Vec::new(),
+        }
+    }
+
+    #[test]
+    fn test_build_threads_module_validates() {
+        use crate::disasm::Opcode;
+        use crate::options::CompileOptions;
+
+        let insts = vec![
+            inst(0x1000, Opcode::LR_W, 5, 10, 0, 0),
+            inst(0x1004, Opcode::SC_W, 6, 10, 11, 0),
+            inst(0x1008, Opcode::AMOADD_D, 0, 10, 11, 0),
+            inst(0x100c, Opcode::AMOMAXU_W, 7, 10, 11, 0),
+            inst(0x1010, Opcode::AMOCAS_W, 7, 10, 11, 0),
+            inst(0x1014, Opcode::AMOMIN_B, 7, 10, 11, 0),
+            inst(0x1018, Opcode::AMOCAS_H, 7, 10, 11, 0),
+            inst(0x101c, Opcode::FENCE, 0, 0, 0, 0),
+            inst(0x1020, Opcode::PAUSE, 0, 0, 0, 0),
+            inst(0x1024, Opcode::WRS_NTO, 0, 0, 0, 0),
+            inst(0x1028, Opcode::ECALL, 0, 0, 0, 0),
+        ];
+        let cfg = crate::cfg::build(&insts, 0x1000).unwrap();
+        let elf_info = elf_info();
+        let options = CompileOptions { threads: true, ..Default::default() };
+        let module = crate::translate::translate(&cfg, &elf_info, &options).unwrap();
+        let bytes = build(&module).unwrap();
+
+        let mut validator = wasmparser::Validator::new_with_features(wasmparser::WasmFeatures {
+            threads: true,
+            ..Default::default()
+        });
+        validator.validate_all(&bytes).unwrap();
+
+        let exports: Vec<String> = wasmparser::Parser::new(0)
+            .parse_all(&bytes)
+            .filter_map(|p| match p.unwrap() {
+                wasmparser::Payload::ExportSection(r) => {
+                    Some(r.into_iter().map(|e| e.unwrap().name.to_string()).collect::<Vec<_>>())
+                }
+                _ => None,
+            })
+            .flatten()
+            .collect();
+        for name in ["run", "futex_wait", "futex_wake", "thread_init"] {
+            assert!(exports.iter().any(|e| e == name), "missing export {name}");
         }
     }
 
+    #[test]
+    fn test_breakpoint_patch() {
+        use crate::disasm::Opcode;
+        use crate::options::CompileOptions;
+
+        // addi a0, a0, 1; lui a1, 0x5; addi a1, a1, 0x23; ecall
+        let insts = vec![
+            inst(0x1000, Opcode::ADDI, 10, 10, 0, 1),
+            inst(0x1004, Opcode::LUI, 11, 0, 0, 0x5000),
+            inst(0x1008, Opcode::ADDI, 11, 11, 0, 0x23),
+            inst(0x100c, Opcode::ECALL, 0, 0, 0, 0),
+        ];
+        let cfg = crate::cfg::build(&insts, 0x1000).unwrap();
+        let elf_info = elf_info();
+        let options = CompileOptions { breakpoints: [0x1008].into(), ..Default::default() };
+        let mut functions = Vec::new();
+        crate::translate::translate_only(&cfg, &options, &[0x1000], |f| {
+            functions.push(f);
+            Ok(())
+        })
+        .unwrap();
+
+        // Stopped before the ADDI, which no longer folds into the LUI
+        let mut memory = vec![0u8; 0x100];
+        let ret = crate::eval::eval(&functions[0], &mut memory, 0).unwrap() as u32;
+        assert_eq!(ret, crate::abi::EBREAK_FLAG | 0x1008);
+        assert_eq!(memory[80..88], 1u64.to_le_bytes());
+        assert_eq!(memory[88..96], 0x5000u64.to_le_bytes());
+
+        let layout = crate::translate::layout(&cfg, &elf_info, &options);
+        let patch = build_patch(&layout, &functions[0]).unwrap();
+        wasmparser::Validator::new().validate_all(&patch).unwrap();
+        let imports: Vec<String> = wasmparser::Parser::new(0)
+            .parse_all(&patch)
+            .filter_map(|p| match p.unwrap() {
+                wasmparser::Payload::ImportSection(r) => {
+                    Some(r.into_iter().map(|i| i.unwrap().name.to_string()).collect::<Vec<_>>())
+                }
+                _ => None,
+            })
+            .flatten()
+            .collect();
+        assert_eq!(imports, ["memory", "table", "syscall", "yield", "crypto", "watch", "breakpoint"]);
+    }
+
+    #[test]
+    fn test_fma_modes_validate() {
+        use crate::disasm::{Instruction as RvInst, Opcode};
+        use crate::options::{CompileOptions, FmaMode};
+
+        // fmadd.d f0, f1, f2, f3; fnmsub.s f4, f5, f6, f3; ebreak
+        let insts = vec![
+            // rs3 = f3 is read from the encoding
+            RvInst { bytes: 3 << 27, ..inst(0x1000, Opcode::FMADD_D, 0, 1, 2, 0) },
+            RvInst { bytes: 3 << 27, ..inst(0x1004, Opcode::FNMSUB_S, 4, 5, 6, 0) },
+            inst(0x1008, Opcode::EBREAK, 0, 0, 0, 0),
+        ];
+        let cfg = crate::cfg::build(&insts, 0x1000).unwrap();
+        let elf_info = elf_info();
+        for fma in [FmaMode::Unfused, FmaMode::Relaxed, FmaMode::Exact] {
+            let options = CompileOptions { fma, ..Default::default() };
+            let module = crate::translate::translate(&cfg, &elf_info, &options).unwrap();
+            let relaxed = module.functions[0].body.contains(&WasmInst::F64RelaxedMadd);
+            assert_eq!(relaxed, fma == FmaMode::Relaxed);
+            let bytes = build(&module).unwrap();
+            let mut validator = wasmparser::Validator::new_with_features(wasmparser::WasmFeatures {
+                relaxed_simd: true,
+                ..Default::default()
+            });
+            validator.validate_all(&bytes).unwrap();
+        }
+    }
+
+    #[test]
+    fn test_spin_wait_calls_yield() {
+        use crate::disasm::Opcode;
+
+        let insts = vec![
+            inst(0x1000, Opcode::PAUSE, 0, 0, 0, 0),
+            inst(0x1004, Opcode::WRS_STO, 0, 0, 0, 0),
+            inst(0x1008, Opcode::ECALL, 0, 0, 0, 0),
+        ];
+        let cfg = crate::cfg::build(&insts, 0x1000).unwrap();
+        let elf_info = elf_info();
+        let module = crate::translate::translate(&cfg, &elf_info, &Default::default()).unwrap();
+        let yields = module.functions[0]
+            .body
+            .iter()
+            .filter(|i| **i == WasmInst::Call { func_idx: link::YIELD_FUNC })
+            .count();
+        assert_eq!(yields, 2);
+        wasmparser::Validator::new().validate_all(&build(&module).unwrap()).unwrap();
+    }
+
+    #[test]
+    fn test_meter_charges_blocks() {
+        use crate::disasm::Opcode;
+        use crate::options::CompileOptions;
+        use crate::translate::{BUDGET_OFFSET, PC_OFFSET};
+
+        // addi a0, a0, 1 ; addi a0, a0, 1 ; ecall
+        let insts = vec![
+            inst(0x1000, Opcode::ADDI, 10, 10, 0, 1),
+            inst(0x1004, Opcode::ADDI, 10, 10, 0, 1),
+            inst(0x1008, Opcode::ECALL, 10, 10, 0, 1),
+        ];
+        let cfg = crate::cfg::build(&insts, 0x1000).unwrap();
+        let elf_info = elf_info();
+        let options = CompileOptions { meter: true, max_pages: Some(16), ..Default::default() };
+        let module = crate::translate::translate(&cfg, &elf_info, &options).unwrap();
+        let mut memory = vec![0u8; 0x1000];
+        let word = |memory: &[u8], offset: u32| u64::from_le_bytes(memory[offset as usize..][..8].try_into().unwrap());
+
+        // Enough budget: the block runs and pays for its three instructions
+        memory[BUDGET_OFFSET as usize..][..8].copy_from_slice(&5u64.to_le_bytes());
+        let ecall = crate::abi::ECALL_FLAG as i32 | 0x1008;
+        assert_eq!(crate::eval::eval(&module.functions[0], &mut memory, 0).unwrap(), ecall);
+        assert_eq!((word(&memory, 80), word(&memory, BUDGET_OFFSET)), (2, 2));
+
+        // Too little: it stops before running, at its own PC
+        assert_eq!(crate::eval::eval(&module.functions[0], &mut memory, 0).unwrap(), crate::abi::STOP_BUDGET as i32);
+        assert_eq!((word(&memory, 80), word(&memory, BUDGET_OFFSET), word(&memory, PC_OFFSET)), (2, 2, 0x1000));
+
+        let wasm = build(&module).unwrap();
+        wasmparser::Validator::new().validate_all(&wasm).unwrap();
+        let memory_max = wasmparser::Parser::new(0).parse_all(&wasm).find_map(|payload| match payload.unwrap() {
+            wasmparser::Payload::ImportSection(r) => r.into_iter().find_map(|i| match i.unwrap().ty {
+                wasmparser::TypeRef::Memory(ty) => ty.maximum,
+                _ => None,
+            }),
+            _ => None,
+        });
+        assert_eq!(memory_max, Some(16));
+    }
+
+    #[test]
+    fn test_memory_offset_moves_guest_accesses_only() {
+        use crate::disasm::Opcode;
+        use crate::options::CompileOptions;
+
+        let insts = vec![
+            inst(0x1000, Opcode::LD, 5, 10, 11, 8),
+            inst(0x1004, Opcode::SW, 5, 10, 11, 8),
+            inst(0x1008, Opcode::LR_D, 5, 10, 11, 8),
+            inst(0x100c, Opcode::SC_D, 5, 10, 11, 8),
+            inst(0x1010, Opcode::AMOADD_W, 5, 10, 11, 8),
+            inst(0x1014, Opcode::FLD, 5, 10, 11, 8),
+            inst(0x1018, Opcode::ECALL, 5, 10, 11, 8),
+        ];
+        let cfg = crate::cfg::build(&insts, 0x1000).unwrap();
+        let elf_info = elf_info();
+        for threads in [false, true] {
+            let options = CompileOptions { threads, ..Default::default() };
+            let plain = crate::translate::translate(&cfg, &elf_info, &options).unwrap();
+            let options = CompileOptions { memory_offset: 0x10000, ..options };
+            let moved = crate::translate::translate(&cfg, &elf_info, &options).unwrap();
+
+            // Same code, with exactly the guest accesses offset
+            let (mut a, mut b) = (plain.functions[0].body.clone(), moved.functions[0].body.clone());
+            assert_eq!(a.len(), b.len());
+            let mut guest = 0;
+            for (a, b) in a.iter_mut().zip(b.iter_mut()) {
+                if let (Some(x), Some(y)) = (a.memarg_offset_mut(), b.memarg_offset_mut()) {
+                    if *x != *y {
+                        assert_eq!(*y, *x + 0x10000);
+                        *y = *x;
+                        guest += 1;
+                    }
+                }
+                assert_eq!(a, b);
+            }
+            // Single-threaded AMOs and SC are plain loads and stores
+            assert_eq!(guest, if threads { 6 } else { 8 });
+
+            let features = wasmparser::WasmFeatures { threads, ..Default::default() };
+            wasmparser::Validator::new_with_features(features).validate_all(&build(&moved).unwrap()).unwrap();
+        }
+    }
+
+    #[test]
+    fn test_x0_reads_are_constants() {
+        use crate::disasm::Opcode;
+        use crate::options::CompileOptions;
+
+        // li a0, 5; sub a1, zero, a0; beq a1, zero, 0x20 -- bltu a0, zero, 0x20 -- ecall
+        let insts = vec![
+            inst(0x1000, Opcode::ADDI, 10, 0, 0, 5),
+            inst(0x1004, Opcode::SUB, 11, 0, 10, 0),
+            inst(0x1008, Opcode::BEQ, 0, 11, 0, 0x18),
+            inst(0x100c, Opcode::BLTU, 0, 10, 0, 0x14),
+            inst(0x1010, Opcode::ECALL, 0, 0, 0, 0),
+            inst(0x1020, Opcode::ECALL, 0, 0, 0, 0),
+        ];
+        let cfg = crate::cfg::build(&insts, 0x1000).unwrap();
+        let elf_info = elf_info();
+        let options = CompileOptions { opt_level: 0, ..Default::default() };
+        let module = crate::translate::translate(&cfg, &elf_info, &options).unwrap();
+        let body = |addr: u64| &module.functions.iter().find(|f| f.block_addr == addr).unwrap().body;
+
+        use WasmInst::*;
+        let first = body(0x1000);
+        assert!(first.windows(2).all(|w| w != [LocalGet { idx: 0 }, I64Load { offset: 0 }]));
+        assert!(first.starts_with(&[LocalGet { idx: 0 }, I64Const { value: 5 }, I64Store { offset: 80 }]));
+        assert!(first.ends_with(&[LocalGet { idx: 0 }, I64Load { offset: 88 }, I64Eqz, Select, Return]));
+        // Unsigned below zero never holds
+        assert_eq!(body(0x100c)[..], [I32Const { value: 0x1010 }, Return]);
+    }
+
+    #[test]
+    fn test_constant_pairs_fuse() {
+        use crate::disasm::Opcode;
+        use crate::options::CompileOptions;
+
+        // li a0, 0x12344fff; auipc t0, 0x7ffff; addiw a1, t0, 0x7ff
+        let code = [
+            inst(0x1000, Opcode::LUI, 10, 0, 0, 0x12345000),
+            inst(0x1004, Opcode::ADDI, 10, 10, 0, -1),
+            inst(0x1008, Opcode::AUIPC, 5, 0, 0, 0x7fff_f000),
+            inst(0x100c, Opcode::ADDIW, 11, 5, 0, 0x7ff),
+        ];
+        let cfg = crate::cfg::build(&code, 0x1000).unwrap();
+        let elf_info = elf_info();
+        let options = CompileOptions { opt_level: 0, ..Default::default() };
+        let module = crate::translate::translate(&cfg, &elf_info, &options).unwrap();
+        use WasmInst::*;
+        assert_eq!(
+            module.functions[0].body[..9],
+            [
+                LocalGet { idx: 0 },
+                I64Const { value: 0x12344fff },
+                I64Store { offset: 80 },
+                LocalGet { idx: 0 },
+                I64Const { value: 0x8000_0008 },
+                I64Store { offset: 40 },
+                LocalGet { idx: 0 },
+                I64Const { value: (0x8000_0807u32 as i32) as i64 },
+                I64Store { offset: 88 },
+            ]
+        );
+    }
+
+    #[test]
+    fn test_address_immediates_fold_into_offsets() {
+        use crate::disasm::Opcode;
+        use crate::options::CompileOptions;
+
+        let insts = vec![
+            inst(0x1000, Opcode::LD, 5, 10, 11, 16),
+            inst(0x1004, Opcode::SB, 5, 10, 11, -16),
+            inst(0x1008, Opcode::ECALL, 5, 10, 11, 0),
+        ];
+        let cfg = crate::cfg::build(&insts, 0x1000).unwrap();
+        let elf_info = elf_info();
+        let options = CompileOptions { memory_offset: 0x10000, opt_level: 0, ..Default::default() };
+        let module = crate::translate::translate(&cfg, &elf_info, &options).unwrap();
+        let body = &module.functions[0].body;
+
+        // ld: x10 is the address, 16 rides in the offset with the memory offset
+        let accesses = crate::translate::guest_accesses(body).unwrap();
+        assert_eq!(accesses.len(), 2);
+        let (access, pusher) = accesses[0];
+        assert_eq!(body[access], WasmInst::I64Load { offset: 0x10010 });
+        assert_eq!(body[pusher - 1..access], [WasmInst::I64Load { offset: 80 }, WasmInst::I32WrapI64]);
+        // sb: a negative immediate is still added
+        let (access, pusher) = accesses[1];
+        assert_eq!(body[access], WasmInst::I64Store8 { offset: 0x10000 });
+        let add = [WasmInst::I64Const { value: -16 }, WasmInst::I64Add, WasmInst::I32WrapI64];
+        assert_eq!(body[pusher - 3..=pusher], [&[WasmInst::I64Load { offset: 80 }][..], &add].concat());
+    }
+
+    #[test]
+    fn test_soft_mmu_checks_every_guest_access() {
+        use crate::disasm::Opcode;
+        use crate::options::CompileOptions;
+
+        let insts = vec![
+            inst(0x1000, Opcode::LD, 5, 10, 11, 8),
+            inst(0x1004, Opcode::SW, 5, 10, 11, 8),
+            inst(0x1008, Opcode::AMOADD_W, 5, 10, 11, 8),
+            inst(0x100c, Opcode::CBO_ZERO, 5, 10, 11, 8),
+            inst(0x1010, Opcode::ECALL, 5, 10, 11, 8),
+        ];
+        let cfg = crate::cfg::build(&insts, 0x1000).unwrap();
+        let elf_info = elf_info();
+        for threads in [false, true] {
+            let options = CompileOptions { threads, soft_mmu: Some(0x10000), ..Default::default() };
+            let module = crate::translate::translate(&cfg, &elf_info, &options).unwrap();
+            let body = &module.functions[0].body;
+            // One root-table lookup and one fault return per guest access
+            let faults: Vec<usize> = body
+                .iter()
+                .enumerate()
+                .filter(|(_, i)| **i == WasmInst::I32Const { value: crate::abi::PAGE_FAULT as i32 })
+                .map(|(at, _)| at)
+                .collect();
+            let roots = body.iter().filter(|i| **i == WasmInst::I32Load { offset: 0x10000 }).count();
+            // Single-threaded AMOs are plain loads and a store
+            assert_eq!(faults.len(), if threads { 4 } else { 6 });
+            assert_eq!(roots, faults.len());
+            // Faults report their instruction
+            assert!(body[..faults[0]].contains(&WasmInst::I64Const { value: 0x1000 }));
+
+            let features = wasmparser::WasmFeatures { threads, bulk_memory: true, ..Default::default() };
+            wasmparser::Validator::new_with_features(features).validate_all(&build(&module).unwrap()).unwrap();
+        }
+    }
+
+    #[test]
+    fn test_guest_function_exports() {
+        use crate::disasm::Opcode;
+        use crate::elf::Symbol;
+        use crate::options::CompileOptions;
+
+        // add: add a0, a0, a1; ret -- then an unexported add
+        let insts = vec![
+            inst(0x1000, Opcode::ADD, 10, 10, 11, 0),
+            inst(0x1004, Opcode::JALR, 0, 1, 0, 0),
+            inst(0x1008, Opcode::ADD, 10, 10, 11, 0),
+        ];
+        let cfg = crate::cfg::build(&insts, 0x1000).unwrap();
+        let elf_info = elf_info();
+        let sym = |name: &str, addr| Symbol { name: name.into(), addr, size: 8 };
+        let options = CompileOptions {
+            exports: vec![sym("add", 0x1000), sym("unreached", 0x2000)],
+            ..Default::default()
+        };
+        let module = crate::translate::translate(&cfg, &elf_info, &options).unwrap();
+        assert_eq!(module.exports, [("guest_add".to_string(), 0x1000)]);
+
+        let bytes = build(&module).unwrap();
+        wasmparser::Validator::new().validate_all(&bytes).unwrap();
+        let export = wasmparser::Parser::new(0).parse_all(&bytes).find_map(|p| match p.unwrap() {
+            wasmparser::Payload::ExportSection(r) => {
+                r.into_iter().map(|e| e.unwrap()).find(|e| e.name == "guest_add").map(|e| e.index)
+            }
+            _ => None,
+        });
+        // Imported syscall, yield and crypto, dispatch, then two block functions
+        assert_eq!(export, Some(6));
+
+        // Selected blocks only; addresses that start no block are ignored
+        let options = CompileOptions { export_blocks: Some(vec![0x1008, 0x3000]), ..options };
+        let module = crate::translate::translate(&cfg, &elf_info, &options).unwrap();
+        let bytes = build(&module).unwrap();
+        wasmparser::Validator::new().validate_all(&bytes).unwrap();
+        let blocks: Vec<String> = wasmparser::Parser::new(0)
+            .parse_all(&bytes)
+            .filter_map(|p| match p.unwrap() {
+                wasmparser::Payload::ExportSection(r) => Some(r.into_iter().map(|e| e.unwrap().name.to_string())),
+                _ => None,
+            })
+            .flatten()
+            .filter(|name| name.starts_with("block_"))
+            .collect();
+        assert_eq!(blocks, ["block_1008"]);
+    }
+
+    #[test]
+    fn test_build_split_modules_validate() {
+        use crate::disasm::Opcode;
+        use crate::options::CompileOptions;
+
+        // main: addi a0, a0, 1; jal f; ecall    f: add a0, a0, a0; ret
+        let insts = vec![
+            inst(0x1000, Opcode::ADDI, 10, 10, 10, 1),
+            inst(0x1004, Opcode::JAL, 1, 0, 10, 8),
+            inst(0x1008, Opcode::ECALL, 0, 0, 10, 0),
+            inst(0x100c, Opcode::ADD, 10, 10, 10, 0),
+            inst(0x1010, Opcode::JALR, 0, 1, 0, 0),
+        ];
+        let cfg = crate::cfg::build(&insts, 0x1000).unwrap();
+        let elf_info = elf_info();
+        let options = CompileOptions { split: 1, ..Default::default() };
+        let module = crate::translate::translate(&cfg, &elf_info, &options).unwrap();
+        assert_eq!(module.layout().block_addrs, [0x1000, 0x1008, 0x100c]);
+        assert_eq!(module.groups, [0, 2]);
+        assert!(build(&module).is_err());
+
+        let (dispatcher, code_modules) = build_split(&module).unwrap();
+        assert_eq!(code_modules.len(), 2);
+        for bytes in std::iter::once(&dispatcher).chain(&code_modules) {
+            wasmparser::Validator::new().validate_all(bytes).unwrap();
+        }
+        let imports: Vec<String> = wasmparser::Parser::new(0)
+            .parse_all(&dispatcher)
+            .filter_map(|p| match p.unwrap() {
+                wasmparser::Payload::ImportSection(r) => {
+                    Some(r.into_iter().map(|i| i.unwrap().name.to_string()).collect::<Vec<_>>())
+                }
+                _ => None,
+            })
+            .flatten()
+            .collect();
+        assert_eq!(imports, ["memory", "syscall", "yield", "crypto", "load_block"]);
+    }
+
+    #[test]
+    fn test_build_lazy_data() {
+        use crate::data::DataChunk;
+
+        let mut module = make_module(&[0x1000, 0x1004]);
+        module.data = vec![
+            DataChunk { addr: 0x2000, bytes: vec![1, 2, 3] },
+            DataChunk { addr: 0x30010, bytes: vec![4] },
+        ]
+        .into();
+        let bytes = build(&module).unwrap();
+        wasmparser::Validator::new().validate_all(&bytes).unwrap();
+
+        let mut passive = 0;
+        for payload in wasmparser::Parser::new(0).parse_all(&bytes) {
+            match payload.unwrap() {
+                wasmparser::Payload::DataCountSection { count, .. } => assert_eq!(count, 2),
+                wasmparser::Payload::DataSection(r) => {
+                    for data in r {
+                        assert!(matches!(data.unwrap().kind, wasmparser::DataKind::Passive));
+                        passive += 1;
+                    }
+                }
+                wasmparser::Payload::ExportSection(r) => {
+                    let names: Vec<_> = r.into_iter().map(|e| e.unwrap().name).collect();
+                    assert!(names.contains(&"init_data") && names.contains(&"init_memory") && names.contains(&"reset"));
+                }
+                _ => {}
+            }
+        }
+        assert_eq!(passive, 2);
+
+        let mut builder = StreamingBuilder::new(std::io::Cursor::new(Vec::new()), &module.layout()).unwrap();
+        for func in &module.functions {
+            builder.push(func).unwrap();
+        }
+        let streamed = builder.finish().unwrap().into_inner();
+        wasmparser::Validator::new().validate_all(&streamed).unwrap();
+        assert!(streamed.ends_with(&bytes[bytes.len() - 11..]));
+    }
+
+    /// Instantiate `bytes` against a fresh `env.memory` of `pages` pages,
+    /// every other import trapping
+    fn instantiate(bytes: &[u8], pages: u32) -> (wasmtime::Store<()>, wasmtime::Instance, wasmtime::Memory) {
+        instantiate_with(bytes, pages, (), |_| {})
+    }
+
+    /// `instantiate` with store data `data` and the imports `define` adds
+    fn instantiate_with<T>(
+        bytes: &[u8],
+        pages: u32,
+        data: T,
+        define: impl FnOnce(&mut wasmtime::Linker<T>),
+    ) -> (wasmtime::Store<T>, wasmtime::Instance, wasmtime::Memory) {
+        let engine = wasmtime::Engine::default();
+        let module = wasmtime::Module::new(&engine, bytes).unwrap();
+        let mut store = wasmtime::Store::new(&engine, data);
+        let memory = wasmtime::Memory::new(&mut store, wasmtime::MemoryType::new(pages, Some(link::MAX_PAGES as u32))).unwrap();
+        let mut linker = wasmtime::Linker::new(&engine);
+        linker.define(&store, "env", "memory", memory).unwrap();
+        define(&mut linker);
+        linker.define_unknown_imports_as_traps(&module).unwrap();
+        let instance = linker.instantiate(&mut store, &module).unwrap();
+        (store, instance, memory)
+    }
+
+    #[test]
+    fn test_register_accessors_round_trip() {
+        use crate::translate::{PC_OFFSET, TRAP_PC_OFFSET};
+
+        let (mut store, instance, memory) = instantiate(&build(&make_module(&[0x1000])).unwrap(), 8);
+        let get_reg = instance.get_typed_func::<(i32, i32), i64>(&mut store, "get_reg").unwrap();
+        let set_reg = instance.get_typed_func::<(i32, i32, i64), ()>(&mut store, "set_reg").unwrap();
+        let get_pc = instance.get_typed_func::<i32, i64>(&mut store, "get_pc").unwrap();
+        let set_pc = instance.get_typed_func::<(i32, i64), ()>(&mut store, "set_pc").unwrap();
+        let m = 0x10000;
+        let mut word = [0u8; 8];
+
+        set_reg.call(&mut store, (m, 10, 0x1234_5678_9abc_def0)).unwrap();
+        assert_eq!(get_reg.call(&mut store, (m, 10)).unwrap(), 0x1234_5678_9abc_def0);
+        memory.read(&store, m as usize + 10 * 8, &mut word).unwrap();
+        assert_eq!(u64::from_le_bytes(word), 0x1234_5678_9abc_def0);
+        // Indices wrap mod 32 and x0 stays zero
+        assert_eq!(get_reg.call(&mut store, (m, 42)).unwrap(), 0x1234_5678_9abc_def0);
+        set_reg.call(&mut store, (m, 0, 7)).unwrap();
+        assert_eq!(get_reg.call(&mut store, (m, 0)).unwrap(), 0);
+
+        set_pc.call(&mut store, (m, 0x1004)).unwrap();
+        assert_eq!(get_pc.call(&mut store, m).unwrap(), 0x1004);
+        memory.read(&store, m as usize + PC_OFFSET as usize, &mut word).unwrap();
+        assert_eq!(u64::from_le_bytes(word), 0x1004);
+        // A trap's stop code reads back as the faulting PC
+        set_pc.call(&mut store, (m, crate::abi::PAGE_FAULT as i64)).unwrap();
+        memory.write(&mut store, m as usize + TRAP_PC_OFFSET as usize, &0x2000u64.to_le_bytes()).unwrap();
+        assert_eq!(get_pc.call(&mut store, m).unwrap(), 0x2000);
+    }
+
+    #[test]
+    fn test_reset_restores_initial_state() {
+        use crate::data::DataChunk;
+        use crate::translate::{HART_ID_OFFSET, RESERVATION_ADDR_OFFSET};
+
+        let mut module = make_module(&[0x1000, 0x1004]);
+        module.data = vec![DataChunk { addr: 0x2000, bytes: vec![1, 2, 3] }].into();
+        module.image = vec![0x1000..0x1008, 0x2000..0x2100];
+        let (mut store, instance, memory) = instantiate(&build(&module).unwrap(), 8);
+        let run = instance.get_typed_func::<(i32, i32), i32>(&mut store, "run").unwrap();
+        let reset = instance.get_typed_func::<i32, ()>(&mut store, "reset").unwrap();
+        let init_memory = instance.get_typed_func::<(), ()>(&mut store, "init_memory").unwrap();
+        let get_reg = instance.get_typed_func::<(i32, i32), i64>(&mut store, "get_reg").unwrap();
+        let set_reg = instance.get_typed_func::<(i32, i32, i64), ()>(&mut store, "set_reg").unwrap();
+        let get_pc = instance.get_typed_func::<i32, i64>(&mut store, "get_pc").unwrap();
+        let set_pc = instance.get_typed_func::<(i32, i64), ()>(&mut store, "set_pc").unwrap();
+
+        // The host boots hart 3 with an sp, then the guest runs
+        let m = 0x10000;
+        init_memory.call(&mut store, ()).unwrap();
+        memory.write(&mut store, m as usize + HART_ID_OFFSET as usize, &3u64.to_le_bytes()).unwrap();
+        set_reg.call(&mut store, (m, 2, 0x7000)).unwrap();
+        assert_eq!(run.call(&mut store, (m, 0x1000)).unwrap(), 0);
+
+        // Dirty the image, the memory past it and the registers
+        memory.write(&mut store, 0x2000, &[0xaa; 0x101]).unwrap();
+        set_reg.call(&mut store, (m, 2, 0x1234)).unwrap();
+        set_reg.call(&mut store, (m, 5, 42)).unwrap();
+        set_pc.call(&mut store, (m, 0x1004)).unwrap();
+        memory.write(&mut store, m as usize + RESERVATION_ADDR_OFFSET as usize, &0x2000u64.to_le_bytes()).unwrap();
+
+        reset.call(&mut store, m).unwrap();
+        let mut image = [0u8; 0x101];
+        memory.read(&store, 0x2000, &mut image).unwrap();
+        assert_eq!(image[..3], [1, 2, 3]);
+        assert!(image[3..0x100].iter().all(|&b| b == 0));
+        assert_eq!(image[0x100], 0xaa, "memory outside the image is left alone");
+        assert_eq!(get_reg.call(&mut store, (m, 2)).unwrap(), 0x7000);
+        assert_eq!(get_reg.call(&mut store, (m, 5)).unwrap(), 0);
+        assert_eq!(get_pc.call(&mut store, m).unwrap(), 0x1000);
+        let mut word = [0u8; 8];
+        memory.read(&store, m as usize + HART_ID_OFFSET as usize, &mut word).unwrap();
+        assert_eq!(u64::from_le_bytes(word), 3);
+        memory.read(&store, m as usize + RESERVATION_ADDR_OFFSET as usize, &mut word).unwrap();
+        assert_eq!(i64::from_le_bytes(word), -1);
+
+        // Without embedded data only the hart is reset
+        let module = make_module(&[0x1000]);
+        let (mut store, instance, memory) = instantiate(&build(&module).unwrap(), 8);
+        let reset = instance.get_typed_func::<i32, ()>(&mut store, "reset").unwrap();
+        let get_pc = instance.get_typed_func::<i32, i64>(&mut store, "get_pc").unwrap();
+        memory.write(&mut store, 0x2000, &[0xaa]).unwrap();
+        memory.write(&mut store, m as usize, &[0xbb; 8]).unwrap();
+        reset.call(&mut store, m).unwrap();
+        memory.read(&store, 0x2000, &mut word[..1]).unwrap();
+        assert_eq!(word[0], 0xaa);
+        memory.read(&store, m as usize, &mut word).unwrap();
+        assert_eq!(word, [0; 8]);
+        assert_eq!(get_pc.call(&mut store, m).unwrap(), 0x1000);
+    }
+
+    #[test]
+    fn test_table_export_and_set_block() {
+        let module = make_module(&[0x1000, 0x1008, 0x1004]);
+        let bytes = build(&module).unwrap();
+        wasmparser::Validator::new().validate_all(&bytes).unwrap();
+        let exports: Vec<(String, wasmparser::ExternalKind, u32)> = wasmparser::Parser::new(0)
+            .parse_all(&bytes)
+            .filter_map(|p| match p.unwrap() {
+                wasmparser::Payload::ExportSection(r) => Some(
+                    r.into_iter()
+                        .map(|e| e.unwrap())
+                        .map(|e| (e.name.to_string(), e.kind, e.index))
+                        .collect::<Vec<_>>(),
+                ),
+                _ => None,
+            })
+            .flatten()
+            .collect();
+        assert!(exports.contains(&("table".into(), wasmparser::ExternalKind::Table, 0)));
+        // Imported syscall, yield and crypto, dispatch, three blocks, reset, then set_block
+        assert!(exports.contains(&("reset".into(), wasmparser::ExternalKind::Func, 7)));
+        assert!(exports.contains(&("set_block".into(), wasmparser::ExternalKind::Func, 8)));
+        assert!(exports.contains(&("get_reg".into(), wasmparser::ExternalKind::Func, 9)));
+        assert!(exports.contains(&("set_pc".into(), wasmparser::ExternalKind::Func, 12)));
+        assert_eq!(module.layout().table_index(0x1004), Some(2));
+        assert_eq!(module.layout().table_index(0x100c), None);
+    }
+
+    #[test]
+    fn test_size_mode_merges_blocks_and_drops_exports() {
+        // make_module gives every block the same body
+        let mut module = make_module(&[0x1000, 0x1008, 0x1004]);
+        module.size = true;
+        module.block_exports = Some(Default::default());
+        let bytes = build(&module).unwrap();
+        wasmparser::Validator::new().validate_all(&bytes).unwrap();
+        let mut exports = Vec::new();
+        let mut funcs = 0;
+        for payload in wasmparser::Parser::new(0).parse_all(&bytes) {
+            match payload.unwrap() {
+                wasmparser::Payload::ExportSection(r) => {
+                    exports.extend(r.into_iter().map(|e| e.unwrap().name.to_string()))
+                }
+                wasmparser::Payload::FunctionSection(r) => funcs = r.count(),
+                _ => {}
+            }
+        }
+        assert_eq!(
+            exports,
+            ["run", "table", "reset", "set_block", "get_reg", "set_reg", "get_pc", "set_pc", "block_index"]
+        );
+        // dispatch, one block body, reset, set_block, the accessors, block_index
+        assert_eq!(funcs, 9);
+
+        module.block_exports = None;
+        let bytes = build(&module).unwrap();
+        wasmparser::Validator::new().validate_all(&bytes).unwrap();
+        assert!(StreamingBuilder::new(std::io::Cursor::new(Vec::new()), &module.layout()).is_err());
+    }
+
     #[test]
     fn test_build_empty_module() {
         let module = make_module(&[]);
@@ -1135,12 +3283,28 @@ mod tests {
         let addrs: Vec<u64> = (0..20).map(|i| 0x10000 + i * 0x100).collect();
         let module = make_module(&addrs);
         // Verify it's actually sparse (not dense)
-        assert!(!can_use_dense_table(&module));
+        assert!(!can_use_dense_table(&addrs));
         let bytes = build(&module).unwrap();
         assert_eq!(&bytes[0..4], b"\0asm");
         assert!(bytes.len() > 100);
     }
 
+    #[test]
+    fn test_jalr_reads_target_before_linking() {
+        use crate::disasm::Opcode;
+        use crate::options::CompileOptions;
+
+        // jalr ra, 0(ra): the target is the old ra, not the link address
+        let insts = vec![inst(0x1000, Opcode::JALR, 1, 1, 0, 0)];
+        let cfg = crate::cfg::build(&insts, 0x1000).unwrap();
+        let elf_info = elf_info();
+        let module = crate::translate::translate(&cfg, &elf_info, &CompileOptions::default()).unwrap();
+        let body = &module.functions[0].body;
+        let load = body.iter().position(|i| matches!(i, WasmInst::I64Load { offset: 8 })).unwrap();
+        let store = body.iter().position(|i| matches!(i, WasmInst::I64Store { offset: 8 })).unwrap();
+        assert!(load < store, "ra written before the jump target was read");
+    }
+
     #[test]
     fn test_build_sparse_blocks_mixed_alignment() {
         // Addresses with mixed 2-byte and 4-byte offsets (simulating C extension mixing)
@@ -1152,6 +3316,215 @@ mod tests {
         assert_eq!(&bytes[0..4], b"\0asm");
     }
 
+    #[test]
+    fn test_lazy_compile_imports_hook_for_every_dispatch() {
+        let dense = vec![0x1000, 0x1004, 0x1008];
+        let sparse: Vec<u64> = (0..20).map(|i| 0x10000 + i * 0x100).collect();
+        for (addrs, size) in [(&dense, false), (&sparse, false), (&dense, true), (&Vec::new(), false)] {
+            let mut module = make_module(addrs);
+            module.lazy_compile = true;
+            module.size = size;
+            let bytes = build(&module).unwrap();
+            wasmparser::Validator::new().validate_all(&bytes).unwrap();
+
+            let mut hook = None;
+            let mut table_max = Some(0);
+            for payload in wasmparser::Parser::new(0).parse_all(&bytes) {
+                match payload.unwrap() {
+                    wasmparser::Payload::ImportSection(r) => {
+                        hook = r.into_iter().map(|i| i.unwrap().name).position(|n| n == link::COMPILE_MISSING);
+                    }
+                    wasmparser::Payload::TableSection(r) => {
+                        table_max = r.into_iter().next().unwrap().unwrap().ty.maximum;
+                    }
+                    _ => {}
+                }
+            }
+            // After memory, syscall, yield and crypto; the host grows the table
+            assert_eq!(hook, Some(4));
+            assert_eq!(table_max, None);
+        }
+    }
+
+    #[test]
+    fn test_unknown_pc_policies_in_an_instance() {
+        use crate::abi::{STOP, STOP_UNKNOWN_PC};
+        use crate::translate::{PC_OFFSET, TRAP_PC_OFFSET, TRAP_VALUE_OFFSET};
+
+        // 0x1000 jumps to the unmapped 0x5000, 0x1004 to the unmapped 0x6000
+        let mut module = make_module(&[0x1000, 0x1004]);
+        module.functions[0].body = vec![WasmInst::I32Const { value: 0x5000 }];
+        module.functions[1].body = vec![WasmInst::I32Const { value: 0x6000 }];
+        let m = 0x10000;
+        let word = |store: &wasmtime::Store<_>, memory: wasmtime::Memory, offset: u32| {
+            let mut bytes = [0u8; 8];
+            memory.read(store, (m + offset) as usize, &mut bytes).unwrap();
+            u64::from_le_bytes(bytes)
+        };
+
+        // Stop: the run ends with the stop code, the PC left for the host
+        module.unknown_pc = UnknownPc::Stop;
+        let (mut store, instance, memory) = instantiate(&build(&module).unwrap(), 8);
+        let run = instance.get_typed_func::<(i32, i32), i32>(&mut store, "run").unwrap();
+        assert_eq!(run.call(&mut store, (m as i32, 0x1000)).unwrap(), STOP_UNKNOWN_PC as i32);
+        assert_eq!(word(&store, memory, TRAP_PC_OFFSET), 0x5000);
+        assert_eq!(word(&store, memory, PC_OFFSET), 0x5000);
+        assert_eq!(word(&store, memory, TRAP_VALUE_OFFSET), 0x1000);
+
+        // Trap: the instance traps with the same state stored
+        module.unknown_pc = UnknownPc::Trap;
+        let (mut store, instance, memory) = instantiate(&build(&module).unwrap(), 8);
+        let run = instance.get_typed_func::<(i32, i32), i32>(&mut store, "run").unwrap();
+        let trap = run.call(&mut store, (m as i32, 0x1000)).unwrap_err();
+        assert_eq!(trap.downcast_ref::<wasmtime::Trap>(), Some(&wasmtime::Trap::UnreachableCodeReached));
+        assert_eq!(word(&store, memory, TRAP_PC_OFFSET), 0x5000);
+        assert_eq!(word(&store, memory, PC_OFFSET), 0x5000);
+
+        // Import: the run resumes at the PC env.unknown_pc returns, and
+        // ends with the stop code it returns
+        module.unknown_pc = UnknownPc::Import;
+        let (mut store, instance, _) = instantiate_with(&build(&module).unwrap(), 8, Vec::new(), |linker| {
+            linker
+                .func_wrap("env", link::UNKNOWN_PC, |mut caller: wasmtime::Caller<'_, Vec<i32>>, pc: i32| {
+                    caller.data_mut().push(pc);
+                    if pc == 0x5000 { 0x1004 } else { (STOP | 0x42) as i32 }
+                })
+                .unwrap();
+        });
+        let run = instance.get_typed_func::<(i32, i32), i32>(&mut store, "run").unwrap();
+        assert_eq!(run.call(&mut store, (m as i32, 0x1000)).unwrap(), (STOP | 0x42) as i32);
+        assert_eq!(store.data()[..], [0x5000, 0x6000]);
+    }
+
+    #[test]
+    fn test_unknown_pc_policies() {
+        let dense = vec![0x1000, 0x1004, 0x1008];
+        let sparse: Vec<u64> = (0..20).map(|i| 0x10000 + i * 0x100).collect();
+        let shapes = [(&dense, false, false), (&sparse, false, true), (&dense, true, false), (&Vec::new(), false, false)];
+        for policy in [UnknownPc::Halt, UnknownPc::Stop, UnknownPc::Trap, UnknownPc::Import] {
+            for (addrs, size, lazy) in shapes {
+                let mut module = make_module(addrs);
+                module.unknown_pc = policy;
+                module.size = size;
+                module.lazy_compile = lazy;
+                let bytes = build(&module).unwrap();
+                wasmparser::Validator::new().validate_all(&bytes).unwrap();
+
+                // What the dispatch function (the first defined) does with
+                // an unknown PC
+                let mut imports = Vec::new();
+                let (mut stops, mut traps, mut calls) = (false, false, false);
+                let mut dispatch = true;
+                for payload in wasmparser::Parser::new(0).parse_all(&bytes) {
+                    match payload.unwrap() {
+                        wasmparser::Payload::ImportSection(r) => {
+                            imports = r.into_iter().map(|i| i.unwrap().name.to_string()).collect();
+                        }
+                        wasmparser::Payload::CodeSectionEntry(body) if dispatch => {
+                            dispatch = false;
+                            // The memory import is not a function
+                            let hook = imports.iter().position(|n| n == link::UNKNOWN_PC).map(|i| i as u32 - 1);
+                            for op in body.get_operators_reader().unwrap() {
+                                match op.unwrap() {
+                                    wasmparser::Operator::I32Const { value } => {
+                                        stops |= value == crate::abi::STOP_UNKNOWN_PC as i32;
+                                    }
+                                    wasmparser::Operator::Unreachable => traps = true,
+                                    wasmparser::Operator::Call { function_index } => {
+                                        calls |= Some(function_index) == hook;
+                                    }
+                                    _ => {}
+                                }
+                            }
+                        }
+                        _ => {}
+                    }
+                }
+                let imported = policy == UnknownPc::Import;
+                assert_eq!(imports.last().map(|n| n == link::UNKNOWN_PC), Some(imported));
+                assert_eq!((stops, traps, calls), (policy == UnknownPc::Stop, policy == UnknownPc::Trap, imported));
+            }
+        }
+    }
+
+    #[test]
+    fn test_jspi_imports_async_syscall() {
+        let mut module = make_module(&[0x1000, 0x1004]);
+        module.jspi = true;
+        module.groups = vec![0, 1];
+        let (dispatcher, code_modules) = build_split(&module).unwrap();
+        module.groups.clear();
+        for bytes in [build(&module).unwrap(), dispatcher].into_iter().chain(code_modules) {
+            wasmparser::Validator::new().validate_all(&bytes).unwrap();
+            let mut types = Vec::new();
+            let mut syscall = None;
+            for payload in wasmparser::Parser::new(0).parse_all(&bytes) {
+                match payload.unwrap() {
+                    wasmparser::Payload::TypeSection(r) => {
+                        types = r.into_iter_err_on_gc_types().map(|t| t.unwrap().params().len()).collect();
+                    }
+                    wasmparser::Payload::ImportSection(r) => {
+                        syscall = r.into_iter().map(|i| i.unwrap()).find_map(|i| match i.ty {
+                            wasmparser::TypeRef::Func(ty) if i.name == link::SYSCALL_ASYNC => Some(ty),
+                            _ => None,
+                        });
+                    }
+                    _ => {}
+                }
+            }
+            // ($m, $pc, $cause)
+            assert_eq!(syscall.map(|ty| types[ty as usize]), Some(3));
+        }
+    }
+
+    #[test]
+    fn test_trap_exceptions_export_tag() {
+        let mut module = make_module(&[0x1000, 0x1004]);
+        module.trap_exceptions = true;
+        module.jspi = true;
+        let single = build(&module).unwrap();
+        module.groups = vec![0, 1];
+        for bytes in [single, build_split(&module).unwrap().0] {
+            let features = wasmparser::WasmFeatures { exceptions: true, ..Default::default() };
+            wasmparser::Validator::new_with_features(features).validate_all(&bytes).unwrap();
+            let mut tag = None;
+            for payload in wasmparser::Parser::new(0).parse_all(&bytes) {
+                if let wasmparser::Payload::ExportSection(r) = payload.unwrap() {
+                    tag = r.into_iter().map(|e| e.unwrap()).find(|e| e.name == link::GUEST_TRAP).map(|e| e.kind);
+                }
+            }
+            assert_eq!(tag, Some(wasmparser::ExternalKind::Tag));
+        }
+    }
+
+    #[test]
+    fn test_streaming_matches_build() {
+        let exports = |bytes: &[u8]| -> Vec<String> {
+            wasmparser::Validator::new().validate_all(bytes).unwrap();
+            wasmparser::Parser::new(0)
+                .parse_all(bytes)
+                .filter_map(|p| match p.unwrap() {
+                    wasmparser::Payload::ExportSection(r) => {
+                        Some(r.into_iter().map(|e| e.unwrap().name.to_string()).collect::<Vec<_>>())
+                    }
+                    _ => None,
+                })
+                .flatten()
+                .collect()
+        };
+        let addrs: Vec<u64> = (0..20).map(|i| 0x10000 + i * 0x100).collect();
+        let module = make_module(&addrs);
+
+        let mut builder = StreamingBuilder::new(std::io::Cursor::new(Vec::new()), &module.layout()).unwrap();
+        assert!(builder.push(&module.functions[1]).is_err(), "out-of-order block accepted");
+        for func in &module.functions {
+            builder.push(func).unwrap();
+        }
+        let streamed = builder.finish().unwrap().into_inner();
+
+        assert_eq!(exports(&streamed), exports(&build(&module).unwrap()));
+    }
+
     #[test]
     fn test_compute_addr_alignment_power_of_two() {
         let addrs = vec![(0x1000u64, 0u32), (0x1004, 1), (0x1008, 2), (0x100c, 3)];
@@ -0,0 +1,165 @@
+// watch.rs - Memory watchpoints
+//
+// With `CompileOptions::watch` every guest load and store is preceded by a
+// range check: an access overlapping the watched guest range calls the
+// imported `env.watch($addr i32, $size i32, $is_write i32, $pc i32)` before
+// it happens, so a host can log it or throw to stop the guest with the old
+// value still in memory. Finding what corrupts a variable then takes a
+// compile, not an interpreter run.
+//
+// Read-modify-write accesses (AMOs) report as writes; CBO.ZERO as a write
+// of its whole cache block. The check costs two compares per access, and
+// the optimizer does not reorder across the call, so a watched build is a
+// debugging build.
+
+use crate::link::WATCH_FUNC;
+use crate::translate::{guest_accesses, WasmInst};
+use anyhow::Result;
+use std::ops::Range;
+
+/// Scratch local holding the guest address, past those of `fma.rs` and
+/// `mmu.rs` (whose lookup runs between the capture and the check)
+const ADDR_LOCAL: u32 = crate::fma::NUM_LOCALS + 1;
+/// Locals a block function needs when its accesses are watched
+pub const NUM_LOCALS: u32 = ADDR_LOCAL;
+
+/// Bytes accessed by `inst`, and whether it writes
+fn access(inst: &WasmInst, cache_block: u32) -> (u32, bool) {
+    use WasmInst::*;
+    match inst {
+        I32Load8S { .. } | I32Load8U { .. } | I64Load8S { .. } | I64Load8U { .. } | I64AtomicLoad8U { .. } => {
+            (1, false)
+        }
+        I32Load16S { .. } | I32Load16U { .. } | I64Load16S { .. } | I64Load16U { .. } | I64AtomicLoad16U { .. } => {
+            (2, false)
+        }
+        I32Load { .. } | I64Load32S { .. } | I64Load32U { .. } | F32Load { .. } | I32AtomicLoad { .. }
+        | MemoryAtomicWait32 { .. } | MemoryAtomicNotify { .. } => (4, false),
+        I64Load { .. } | F64Load { .. } | I64AtomicLoad { .. } => (8, false),
+        I32Store8 { .. } | I64Store8 { .. } | I64AtomicRmw8CmpxchgU { .. } => (1, true),
+        I32Store16 { .. } | I64Store16 { .. } | I64AtomicRmw16CmpxchgU { .. } => (2, true),
+        I32Store { .. } | I64Store32 { .. } | F32Store { .. } | I32AtomicStore { .. } | I32AtomicRmwAdd { .. }
+        | I32AtomicRmwAnd { .. } | I32AtomicRmwOr { .. } | I32AtomicRmwXor { .. } | I32AtomicRmwXchg { .. }
+        | I32AtomicRmwCmpxchg { .. } => (4, true),
+        MemoryFill => (cache_block, true),
+        _ => (8, true),
+    }
+}
+
+/// Check the guest accesses of one instruction's code, `body[start..]`,
+/// against `range`, reporting PC `pc`. `memory_offset` has already been
+/// added to CBO.ZERO's address (but not yet to static offsets).
+pub(crate) fn instrument_accesses(
+    body: &mut Vec<WasmInst>,
+    start: usize,
+    pc: u64,
+    range: &Range<u32>,
+    memory_offset: u32,
+) -> Result<()> {
+    let mut checks = Vec::new();
+    for (at, pusher) in guest_accesses(&body[start..])? {
+        let inst = &mut body[start + at];
+        let (size, is_write) = access(inst, crate::translate::CACHE_BLOCK_SIZE as u32);
+        let offset = match inst.memarg_offset_mut() {
+            Some(offset) => *offset as i64,
+            None => -(memory_offset as i64),
+        };
+        checks.push((start + pusher, check(range, offset, size, is_write, pc)));
+    }
+    if checks.is_empty() {
+        return Ok(());
+    }
+
+    checks.sort_unstable_by_key(|&(pusher, _)| pusher);
+    let tail = body.split_off(start);
+    let mut checks = checks.into_iter().peekable();
+    for (i, inst) in tail.into_iter().enumerate() {
+        body.push(inst);
+        if let Some((_, code)) = checks.next_if(|&(pusher, _)| pusher == start + i) {
+            body.extend(code);
+        }
+    }
+    Ok(())
+}
+
+/// Code that leaves the i32 address on the stack as it is, calling
+/// `env.watch` if `[address + offset, + size)` overlaps `range`
+fn check(range: &Range<u32>, offset: i64, size: u32, is_write: bool, pc: u64) -> Vec<WasmInst> {
+    use WasmInst::*;
+    // addr + offset < end && addr + offset + size > start, with the
+    // constants folded; the address is zero-extended, so no overflow
+    let below = range.end as i64 - offset;
+    let above = range.start as i64 - offset - size as i64;
+    vec![
+        I64ExtendI32U,
+        LocalTee { idx: ADDR_LOCAL },
+        I32WrapI64,
+        Block { label: 0 },
+        LocalGet { idx: ADDR_LOCAL },
+        I64Const { value: below },
+        I64GeS,
+        BrIf { label: 0 },
+        LocalGet { idx: ADDR_LOCAL },
+        I64Const { value: above },
+        I64LeS,
+        BrIf { label: 0 },
+        LocalGet { idx: ADDR_LOCAL },
+        I64Const { value: offset },
+        I64Add,
+        I32WrapI64,
+        I32Const { value: size as i32 },
+        I32Const { value: is_write as i32 },
+        I32Const { value: pc as i32 },
+        Call { func_idx: WATCH_FUNC },
+        End,
+    ]
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::disasm::Opcode;
+    use crate::options::CompileOptions;
+    use crate::test_util::inst;
+
+    #[test]
+    fn test_watch_hits() {
+        // sd a1, 8(a0) ; lw a2, 0(a0) ; sb a1, 20(a0) ; ecall
+        let code = [
+            inst(0x1000, Opcode::SD, 0, 10, 11, 8),
+            inst(0x1004, Opcode::LW, 12, 10, 0, 0),
+            inst(0x1008, Opcode::SB, 0, 10, 11, 20),
+            inst(0x100c, Opcode::ECALL, 0, 10, 0, 0),
+        ];
+        let cfg = crate::cfg::build(&code, 0x1000).unwrap();
+        // Watch 0x180c..0x1814: the doubleword at 0x1808 reaches into it,
+        // the word at 0x1800 does not, the byte at 0x1814 is just past it
+        let options = CompileOptions { watch: Some(0x180c..0x1814), ..Default::default() };
+        let mut funcs = Vec::new();
+        crate::translate::translate_only(&cfg, &options, &[0x1000], |f| {
+            funcs.push(f);
+            Ok(())
+        })
+        .unwrap();
+
+        let mut memory = vec![0u8; 0x3000];
+        memory[80..88].copy_from_slice(&0x1800u64.to_le_bytes());
+        memory[88..96].copy_from_slice(&0xabu64.to_le_bytes());
+        let (_, hits) = crate::eval::eval_watched(&funcs[0], &mut memory, 0).unwrap();
+        assert_eq!(hits, [[0x1808, 8, 1, 0x1000]]);
+        assert_eq!(memory[0x1814], 0xab);
+
+        // Under a memory offset the range is still in guest addresses: with
+        // a0 = 0x180c only the word load hits
+        let options = CompileOptions { memory_offset: 0x1000, ..options };
+        let mut funcs = Vec::new();
+        crate::translate::translate_only(&cfg, &options, &[0x1000], |f| {
+            funcs.push(f);
+            Ok(())
+        })
+        .unwrap();
+        memory[80..88].copy_from_slice(&0x180cu64.to_le_bytes());
+        let (_, hits) = crate::eval::eval_watched(&funcs[0], &mut memory, 0).unwrap();
+        assert_eq!(hits, [[0x180c, 4, 0, 0x1004]]);
+        assert_eq!(memory[0x2820], 0xab);
+    }
+}
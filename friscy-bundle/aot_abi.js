@@ -0,0 +1,32 @@
+// aot_abi.js - Machine-state layout from an rv2wasm ABI descriptor
+//
+// rv2wasm writes `<output>.abi.json` next to every module it emits
+// (aot/src/abi.rs). The hosts in aot_threads.js and aot_jspi.js take that
+// descriptor, parsed, as `abi` and read the machine-state offsets from it
+// rather than hardcoding them, so a layout change in the translator is
+// picked up, and a descriptor from an incompatible rv2wasm is refused.
+//
+// Only those two embedder-facing hosts use it; the emulator in worker.js
+// has its own state layout.
+
+// The abi.rs ABI_VERSION these hosts speak
+export const ABI_VERSION = 8;
+
+/**
+ * The machine-state layout of `abi` (byte offsets of the fields and the
+ * state's size), after checking its version
+ */
+export function machineState(abi) {
+    if (abi?.abi_version !== ABI_VERSION) {
+        throw new Error(`rv2wasm ABI version ${abi?.abi_version} is not ${ABI_VERSION}`);
+    }
+    return abi.machine_state;
+}
+
+/**
+ * Bytes between consecutive machine states of `abi` in guest memory: its
+ * size, rounded up to a 64-byte line so harts do not share one
+ */
+export function stateStride(abi) {
+    return Math.ceil(machineState(abi).size / 64) * 64;
+}
@@ -0,0 +1,64 @@
+// aot_jspi.js - JSPI host for rv2wasm --jspi modules
+//
+// A module compiled with `rv2wasm --jspi` calls
+// env.syscall_async(m, pc, cause) -> nextPc instead of env.syscall(m,
+// flaggedPc): the PC comes unflagged and `cause` says why the guest
+// stopped (CAUSE_* below; for traps the PC is the faulting one). The import
+// is wrapped in WebAssembly.Suspending and `run` in WebAssembly.promising,
+// so an async handler (a network read, a terminal waiting for a key) parks
+// the guest's Wasm stack and resumes it when its promise settles, on the
+// browser main thread and without SharedArrayBuffer.
+//
+// The handler gets `{ m, pc, cause, nr, memory }` (`nr` is a7 for ECALLs)
+// and may return, or resolve to, the PC to resume at or a stop code (see
+// aot_threads.js). undefined resumes after an ECALL and ends the run with
+// the trap's signal otherwise. The machine-state offsets come from the
+// module's parsed ABI descriptor, passed as `abi` (aot_abi.js).
+//
+// Like aot_threads.js, this is for embedders running their own rv2wasm
+// output: the bundle's page does not load AOT modules and never imports
+// it.
+
+import { machineState } from './aot_abi.js';
+import { crypto as zknCrypto } from './zkn.js';
+
+// Why the guest left the dispatch loop (abi.rs CAUSE_*)
+export const CAUSE_ECALL = 0;
+export const CAUSE_EBREAK = 1;
+export const CAUSE_ILLEGAL = 2;
+export const CAUSE_MISALIGNED = 3;
+export const CAUSE_PAGE_FAULT = 4;
+
+// abi.rs STOP_TRAPPED, and the signal for each trap cause
+const STOP_TRAPPED = (0xfffe0000 | 0) | 0x300;
+const TRAP_SIGNALS = [0, 5, 4, 7, 11]; // -, SIGTRAP, SIGILL, SIGBUS, SIGSEGV
+
+export function jspiSupported() {
+    return typeof WebAssembly.Suspending === 'function' && typeof WebAssembly.promising === 'function';
+}
+
+/**
+ * Instantiate `module` against `memory` and run it from `pc` with machine
+ * state at `m`, laid out as `abi` describes. Resolves with `run`'s result
+ * once the guest stops.
+ */
+export async function runSuspending({ module, abi, memory, m, pc, handler }) {
+    if (!jspiSupported()) {
+        throw new Error('JSPI (WebAssembly.Suspending) is not available');
+    }
+    const state = machineState(abi);
+    const syscall = new WebAssembly.Suspending(async (m, pc, cause) => {
+        const a7 = () => Number(new DataView(memory.buffer).getBigInt64(m + 17 * 8, true));
+        const nr = cause === CAUSE_ECALL ? a7() : undefined;
+        const result = await handler({ m, pc, cause, nr, memory });
+        if (result !== undefined) return result;
+        return cause === CAUSE_ECALL ? pc + 4 : STOP_TRAPPED | TRAP_SIGNALS[cause];
+    });
+    const instance = await WebAssembly.instantiate(module, {
+        env: { memory, syscall_async: syscall, yield: () => {}, crypto: zknCrypto },
+    });
+    // No LR/SC reservation
+    new DataView(memory.buffer).setBigInt64(m + state.reservation_addr, -1n, true);
+    const run = WebAssembly.promising(instance.exports.run);
+    return run(m, pc);
+}
@@ -0,0 +1,363 @@
+// aot_threads.js - Worker-based threading host for rv2wasm --threads modules
+//
+// A module compiled with `rv2wasm --threads` imports a shared memory and
+// exports futex_wait / futex_wake / thread_init next to the `run` dispatch
+// loop. This host turns those into Linux threads:
+//
+//   clone(CLONE_VM|CLONE_THREAD)  -> new Worker running run(child_m, pc + 4)
+//   futex(FUTEX_WAIT / FUTEX_WAKE) -> memory.atomic.wait32 / notify
+//   exit (thread)                  -> CLONE_CHILD_CLEARTID wake, worker exits
+//   exit_group                     -> every hart stops, run returns the status
+//
+// Every other syscall is forwarded to the handler module named by
+// `handlerUrl`, whose default export is `(ctx) => nextPc` (return
+// undefined to resume after the ECALL). Illegal-instruction traps reach
+// the handler too, with `trap: 'illegal'`, misaligned atomics with
+// `trap: 'misaligned'` and the faulting `addr`, and software-MMU page faults
+// with `trap: 'pagefault'` and the guest `addr` (undefined ends the process
+// with the trap's signal). Modules built with `--trap-exceptions` throw
+// their traps as the exported `guest_trap` exception instead; `run` catches
+// it and hands it to the handler the same way.
+//
+// The process ends when a thread calls exit_group, the last thread exits or
+// a trap goes unhandled. The handler sees exit_group before it takes effect
+// (to flush host-side output); by then the guest has run its atexit
+// handlers and destructors, which happen before the syscall. Every hart
+// stops at its next syscall, and `runMainThread` resolves with the status
+// as a stop code (`runStatus` decodes it), even if the main thread itself
+// called exit early and left the others running.
+//
+// Machine states for child harts live in guest memory at
+// stateBase + slot * stateStride(abi), where `abi` is the module's parsed
+// ABI descriptor (aot_abi.js), which also gives the offsets of the fields
+// this host touches. Must not be used on the browser main thread: futex
+// waits block.
+//
+// SMP guests (`rv2wasm --harts N`) start with N harts instead of one: hart
+// 0 on the calling worker, the rest on workers of their own, each with a
+// copy of hart 0's registers, a0 and mhartid set to its hart id, all at the
+// entry PC. They share the process like threads do (tids 1..N).
+//
+// Nothing in this bundle imports it: index.html and worker.js run the
+// libriscv emulator, with jit_manager.js for hot regions. It is an entry
+// point for embedders that ship an rv2wasm --threads module instead;
+// import it from their own worker next to the module and its .abi.json.
+
+import { machineState, stateStride } from './aot_abi.js';
+import { crypto as zknCrypto } from './zkn.js';
+
+export { stateStride };
+
+// Block returns for a privileged instruction, a misaligned atomic and a
+// page fault; the PC (and address) are in the machine state at
+// trap_pc and trap_value
+const ILLEGAL_TRAP = -3;
+const MISALIGNED_TRAP = -4;
+const PAGE_FAULT = -5;
+
+// Syscall returns that end the run (abi.rs STOP_*): how in bits 8-15, the
+// exit status or signal in bits 0-7
+const STOP = 0xfffe0000 | 0;
+const STOP_EXITED = STOP | 0x100;
+const STOP_SIGNALED = STOP | 0x200;
+const STOP_TRAPPED = STOP | 0x300;
+const STOP_BUDGET = STOP | 0x400;
+
+const SIGILL = 4;
+const SIGBUS = 7;
+const SIGSEGV = 11;
+
+// The handler's name for each trap, by guest_trap cause (abi.rs CAUSE_*),
+// and the signal it raises
+const TRAP_NAMES = { 2: 'illegal', 3: 'misaligned', 4: 'pagefault' };
+const TRAP_SIGNALS = { illegal: SIGILL, misaligned: SIGBUS, pagefault: SIGSEGV };
+
+const SYS_futex = 98;
+const SYS_exit = 93;
+const SYS_exit_group = 94;
+const SYS_set_tid_address = 96;
+const SYS_gettid = 178;
+const SYS_clone = 220;
+
+const CLONE_THREAD = 0x00010000;
+const CLONE_PARENT_SETTID = 0x00100000;
+const CLONE_CHILD_CLEARTID = 0x00200000;
+
+const FUTEX_WAIT = 0;
+const FUTEX_WAKE = 1;
+const FUTEX_CMD_MASK = 0x7f; // strips FUTEX_PRIVATE_FLAG / FUTEX_CLOCK_REALTIME
+
+const EAGAIN = 11;
+const ENOSYS = 38;
+const ETIMEDOUT = 110;
+
+// Control block (Int32Array over a SharedArrayBuffer)
+const CTL_NEXT_TID = 0;
+const CTL_NEXT_SLOT = 1;
+const CTL_LIVE_THREADS = 2;
+const CTL_EXIT_STATUS = 3; // the stop code once the process has ended, else 0
+
+/**
+ * Decode `runMainThread`'s result: `{ exited: status }`, `{ signaled: sig }`,
+ * `{ trapped: sig }`, `{ outOfBudget: true }` for a metered module whose
+ * instruction budget ran out, or `{ halted: true }` if the handler halted the loop
+ */
+export function runStatus(code) {
+    switch (code & ~0xff) {
+    case STOP_EXITED: return { exited: code & 0xff };
+    case STOP_SIGNALED: return { signaled: code & 0xff };
+    case STOP_TRAPPED: return { trapped: code & 0xff };
+    case STOP_BUDGET: return { outOfBudget: true };
+    default: return { halted: true };
+    }
+}
+
+/**
+ * Per-worker view of one hart. `ctl` is shared by every worker.
+ */
+class Hart {
+    constructor({ module, abi, memory, handler, ctl, stateBase, workerUrl, handlerUrl }, m, tid) {
+        this.abi = abi;
+        this.state = machineState(abi);
+        this.stride = stateStride(abi);
+        this.memory = memory;
+        this.handler = handler;
+        this.ctl = ctl;
+        this.stateBase = stateBase;
+        this.workerUrl = workerUrl;
+        this.handlerUrl = handlerUrl;
+        this.module = module;
+        this.m = m;
+        this.tid = tid;
+        this.clearTid = 0;
+        this.exited = false;
+        this.instance = new WebAssembly.Instance(module, {
+            // Threads builds wait in-module on PAUSE / WRS.*; env.yield is
+            // only called by single-threaded builds
+            env: { memory, syscall: (m, pc) => this.syscall(m, pc), yield: () => {}, crypto: zknCrypto },
+        });
+    }
+
+    view() {
+        return new DataView(this.memory.buffer);
+    }
+
+    reg(i) {
+        return this.instance.exports.get_reg(this.m, i);
+    }
+
+    setReg(i, value) {
+        this.instance.exports.set_reg(this.m, i, BigInt(value));
+    }
+
+    run(pc) {
+        const tag = this.instance.exports.guest_trap;
+        for (;;) {
+            try {
+                return this.instance.exports.run(this.m, pc);
+            } catch (e) {
+                if (!tag || !(e instanceof WebAssembly.Exception) || !e.is(tag)) throw e;
+                const trap = TRAP_NAMES[e.getArg(tag, 0)];
+                const addr = trap === 'illegal' ? undefined : e.getArg(tag, 2);
+                pc = this.trap(this.m, trap, Number(e.getArg(tag, 1)), addr);
+                if ((pc & 0xffff0000) === STOP) return pc;
+            }
+        }
+    }
+
+    /**
+     * Hand a trap to the handler, which returns a PC to resume at; by
+     * default the process ends with the trap's signal
+     */
+    trap(m, trap, pc, addr) {
+        const result = this.handler({ hart: this, m, pc, addr, trap });
+        return result === undefined ? this.exitProcess(STOP_TRAPPED | TRAP_SIGNALS[trap]) : result;
+    }
+
+    syscall(m, flaggedPc) {
+        if (flaggedPc === ILLEGAL_TRAP || flaggedPc === MISALIGNED_TRAP || flaggedPc === PAGE_FAULT) {
+            const view = this.view();
+            const pc = Number(view.getBigInt64(m + this.state.trap_pc, true));
+            const addr = view.getBigInt64(m + this.state.trap_value, true);
+            if (flaggedPc === ILLEGAL_TRAP) return this.trap(m, 'illegal', pc);
+            return this.trap(m, flaggedPc === MISALIGNED_TRAP ? 'misaligned' : 'pagefault', pc, addr);
+        }
+        // Another thread ended the process
+        const status = Atomics.load(this.ctl, CTL_EXIT_STATUS);
+        if (status !== 0) return status;
+
+        const pc = flaggedPc & 0x7fffffff;
+        const next = pc + 4;
+        const nr = Number(this.reg(17));
+
+        switch (nr) {
+        case SYS_clone: {
+            const flags = Number(this.reg(10) & 0xffffffffn);
+            if (!(flags & CLONE_THREAD)) break; // fork: leave to the handler
+            this.setReg(10, this.spawn(flags, next));
+            return next;
+        }
+        case SYS_futex:
+            this.setReg(10, this.futex());
+            return next;
+        case SYS_set_tid_address:
+            this.clearTid = Number(this.reg(10));
+            this.setReg(10, this.tid);
+            return next;
+        case SYS_gettid:
+            this.setReg(10, this.tid);
+            return next;
+        case SYS_exit:
+            // The main thread too: the process lives while any thread does
+            this.exitThread();
+            if (Atomics.load(this.ctl, CTL_LIVE_THREADS) === 0) {
+                this.handler({ hart: this, m, pc, nr });
+                return this.exitProcess(STOP_EXITED | Number(this.reg(10) & 0xffn));
+            }
+            return -1; // halt this hart's dispatch loop
+        case SYS_exit_group: {
+            const result = this.handler({ hart: this, m, pc, nr });
+            if (result !== undefined) return result;
+            return this.exitProcess(STOP_EXITED | Number(this.reg(10) & 0xffn));
+        }
+        }
+
+        const result = this.handler({ hart: this, m, pc, nr });
+        return result === undefined ? next : result;
+    }
+
+    futex() {
+        const addr = Number(this.reg(10));
+        const op = Number(this.reg(11)) & FUTEX_CMD_MASK;
+        const val = Number(this.reg(12) & 0xffffffffn) | 0;
+        const exports = this.instance.exports;
+
+        if (op === FUTEX_WAIT) {
+            let timeout = -1n;
+            const ts = Number(this.reg(13));
+            if (ts !== 0) {
+                const v = this.view();
+                timeout = v.getBigInt64(ts, true) * 1000000000n + v.getBigInt64(ts + 8, true);
+            }
+            const r = exports.futex_wait(addr, val, timeout);
+            return r === 0 ? 0 : r === 1 ? -EAGAIN : -ETIMEDOUT;
+        }
+        if (op === FUTEX_WAKE) {
+            return exports.futex_wake(addr, val);
+        }
+        return -ENOSYS;
+    }
+
+    spawn(flags, pc) {
+        const tid = Atomics.add(this.ctl, CTL_NEXT_TID, 1);
+        const slot = Atomics.add(this.ctl, CTL_NEXT_SLOT, 1);
+        const child = this.stateBase + slot * this.stride;
+
+        this.instance.exports.thread_init(child, this.m, this.reg(11), this.reg(13));
+        if (flags & CLONE_PARENT_SETTID) {
+            this.view().setInt32(Number(this.reg(12)), tid, true);
+        }
+        const clearTid = flags & CLONE_CHILD_CLEARTID ? Number(this.reg(14)) : 0;
+
+        Atomics.add(this.ctl, CTL_LIVE_THREADS, 1);
+        this.start(child, pc, tid, clearTid);
+        return tid;
+    }
+
+    /** Run the hart whose state is at `m` from `pc` on a new worker */
+    start(m, pc, tid, clearTid) {
+        const worker = new Worker(this.workerUrl, { type: 'module' });
+        worker.postMessage({
+            type: 'aot-thread',
+            module: this.module,
+            abi: this.abi,
+            memory: this.memory,
+            ctl: this.ctl,
+            stateBase: this.stateBase,
+            workerUrl: this.workerUrl,
+            handlerUrl: this.handlerUrl,
+            m,
+            pc,
+            tid,
+            clearTid,
+        });
+    }
+
+    /**
+     * Start harts 1..harts-1 of an SMP guest at `pc`, each in a fresh state
+     * slot copied from this one (hart 0)
+     */
+    startHarts(harts, pc) {
+        for (let id = 1; id < harts; id++) {
+            const tid = Atomics.add(this.ctl, CTL_NEXT_TID, 1);
+            const slot = Atomics.add(this.ctl, CTL_NEXT_SLOT, 1);
+            const m = this.stateBase + slot * this.stride;
+            this.instance.exports.thread_init(m, this.m, 0n, 0n);
+            const view = this.view();
+            view.setBigInt64(m + 10 * 8, BigInt(id), true);
+            view.setBigInt64(m + this.state.hart_id, BigInt(id), true);
+            this.start(m, pc, tid, 0);
+        }
+    }
+
+    exitThread() {
+        if (this.clearTid !== 0) {
+            this.view().setInt32(this.clearTid, 0, true);
+            this.instance.exports.futex_wake(this.clearTid, 0x7fffffff);
+        }
+        Atomics.sub(this.ctl, CTL_LIVE_THREADS, 1);
+        this.exited = true;
+    }
+
+    /**
+     * End the process with stop code `code`, unless another thread already
+     * has; returns the code that stands, which stops this hart's loop
+     */
+    exitProcess(code) {
+        const previous = Atomics.compareExchange(this.ctl, CTL_EXIT_STATUS, 0, code);
+        Atomics.notify(this.ctl, CTL_EXIT_STATUS);
+        return previous === 0 ? code : previous;
+    }
+}
+
+/**
+ * Start the main hart (tid 1) on the current worker, and for an SMP guest
+ * the other `harts - 1` on workers of their own. `abi` is the module's
+ * parsed `<output>.abi.json`.
+ * Resolves with the process's stop code once it ends (see `runStatus`),
+ * or 0 if the handler halted the main thread.
+ */
+export async function runMainThread({ module, abi, memory, m, pc, stateBase, workerUrl, handlerUrl, harts = 1 }) {
+    const ctl = new Int32Array(new SharedArrayBuffer(16));
+    ctl[CTL_NEXT_TID] = 2;
+    ctl[CTL_LIVE_THREADS] = harts;
+    const { default: handler } = await import(handlerUrl);
+    const hart = new Hart({ module, abi, memory, handler, ctl, stateBase, workerUrl, handlerUrl }, m, 1);
+    const view = hart.view();
+    view.setBigInt64(m + hart.state.reservation_addr, -1n, true);
+    if (harts > 1) {
+        view.setBigInt64(m + 10 * 8, 0n, true);
+        view.setBigInt64(m + hart.state.hart_id, 0n, true);
+        hart.startHarts(harts, pc);
+    }
+    const result = hart.run(pc);
+    if (!hart.exited) return result;
+    // The main thread exited on its own; wait for the rest of the process
+    while (Atomics.load(ctl, CTL_EXIT_STATUS) === 0) {
+        await Atomics.waitAsync(ctl, CTL_EXIT_STATUS, 0).value;
+    }
+    return Atomics.load(ctl, CTL_EXIT_STATUS);
+}
+
+// Worker entry: child harts are started by Hart.spawn()
+if (typeof WorkerGlobalScope !== 'undefined' && self instanceof WorkerGlobalScope) {
+    self.addEventListener('message', async (e) => {
+        if (e.data?.type !== 'aot-thread') return;
+        const { m, pc, tid, clearTid, handlerUrl } = e.data;
+        const { default: handler } = await import(handlerUrl);
+        const hart = new Hart({ ...e.data, handler }, m, tid);
+        hart.clearTid = clearTid;
+        hart.run(pc);
+        self.close();
+    });
+}
@@ -15,6 +15,26 @@
 //   - Return < 0x80000000: next PC to execute
 //   - Return >= 0x80000000: syscall (pass to friscy_resume with high bit)
 //   - Return == -1 (0xFFFFFFFF): halt
+//
+// Breakpoints (setBreakpoint) recompile the region holding the PC with a
+// call to env.breakpoint before it and swap its blocks in, so guest code is
+// never patched; a block that stops returns the EBREAK marker for the PC.
+
+import { crypto as zknCrypto } from './zkn.js';
+
+// env.yield: guest PAUSE / WRS.* (spin-wait). Sleep briefly where the
+// thread may block (workers); the main thread cannot, so it just returns.
+const backoffCell = typeof SharedArrayBuffer !== 'undefined'
+    ? new Int32Array(new SharedArrayBuffer(4))
+    : null;
+function spinBackoff() {
+    if (!backoffCell) return;
+    try {
+        Atomics.wait(backoffCell, 0, 0, 0.05);
+    } catch (e) {
+        // Atomics.wait is not allowed on this thread
+    }
+}
 
 class JITManager {
     constructor() {
@@ -52,6 +72,12 @@ class JITManager {
         // Invalidation bitmap (1 bit per 4KB page)
         // When a page is written via mprotect(PROT_WRITE), its JIT'd code is invalidated
         this.dirtyPages = new Set();
+
+        // Guest PCs with breakpoints, the one that stopped the last block,
+        // and the one being continued from (whose hook lets the block pass)
+        this.breakpoints = new Set();
+        this.breakpointHit = null;
+        this.resumePC = null;
     }
 
     /**
@@ -70,9 +96,10 @@ class JITManager {
 
         this.jitCompilerLoading = (async () => {
             try {
-                const { default: init, compile_region, version } = await import('./rv2wasm_jit.js');
+                const { default: init, compile_region, compileRegionWithBreakpoints, version } =
+                    await import('./rv2wasm_jit.js');
                 await init(url);
-                this.jitCompiler = { compile_region, version };
+                this.jitCompiler = { compile_region, compileRegionWithBreakpoints, version };
                 console.log(`[JIT] Compiler loaded: ${version()}`);
             } catch (e) {
                 console.warn('[JIT] Failed to load compiler:', e.message);
@@ -131,7 +158,9 @@ class JITManager {
 
     /**
      * Execute a JIT'd function for the given PC.
-     * Returns { nextPC, isSyscall, isHalt }
+     * Returns { nextPC, isSyscall, isHalt, isIllegal, isMisaligned }; for
+     * these traps the faulting PC (and address) are in the machine state
+     * (rv2wasm abi.rs)
      */
     execute(pc, machineStatePtr) {
         const func = this.getCompiledFunction(pc);
@@ -142,6 +171,16 @@ class JITManager {
         if (result === -1 || result === 0xFFFFFFFF) {
             return { nextPC: 0, isSyscall: false, isHalt: true };
         }
+        if (result === -3) {
+            return { nextPC: 0, isSyscall: false, isHalt: false, isIllegal: true };
+        }
+        if (result === -4) {
+            return { nextPC: 0, isSyscall: false, isHalt: false, isMisaligned: true };
+        }
+        if (((result & 0xC0000000) >>> 0) === 0xC0000000 && this.breakpointHit === (result & 0x3FFFFFFF)) {
+            this.breakpointHit = null;
+            return { nextPC: result & 0x3FFFFFFF, isSyscall: false, isHalt: false, isBreakpoint: true };
+        }
         if ((result & 0x80000000) !== 0) {
             return { nextPC: result, isSyscall: true, isHalt: false };
         }
@@ -162,19 +201,30 @@ class JITManager {
         const regionEnd = Math.min(regionStart + this.regionSize, memBuffer.length);
         const codeBytes = memBuffer.slice(regionStart, regionEnd);
 
-        // Compile to Wasm via rv2wasm
+        // Compile to Wasm via rv2wasm, with hooks at the region's breakpoints
+        const breakpoints = [...this.breakpoints].filter(pc => pc >= regionStart && pc < regionEnd);
         let wasmBytes;
         try {
-            wasmBytes = this.jitCompiler.compile_region(codeBytes, regionStart);
+            wasmBytes = breakpoints.length > 0
+                ? this.jitCompiler.compileRegionWithBreakpoints(codeBytes, regionStart, new Uint32Array(breakpoints))
+                : this.jitCompiler.compile_region(codeBytes, regionStart);
         } catch (e) {
             // Compilation can fail for regions with unsupported instructions
             return;
         }
 
-        // Instantiate the compiled Wasm module with shared memory
+        // Instantiate with the standard imports (rv2wasm link.rs). JIT blocks
+        // return the syscall marker instead of calling env.syscall.
         const importObject = {
             env: {
                 memory: this.wasmMemory,
+                syscall: () => {
+                    throw new Error('[JIT] block called env.syscall');
+                },
+                yield: spinBackoff,
+                crypto: zknCrypto,
+                watch: () => {},
+                breakpoint: (pc) => this.breakpointHook(pc >>> 0),
             },
         };
 
@@ -204,6 +254,52 @@ class JITManager {
         );
     }
 
+    /**
+     * env.breakpoint: stop unless continuing from this very breakpoint
+     */
+    breakpointHook(pc) {
+        if (this.resumePC === pc) {
+            this.resumePC = null;
+            return 0;
+        }
+        this.breakpointHit = pc;
+        return 1;
+    }
+
+    /**
+     * Continue from a breakpoint at `pc` without stopping at it again.
+     */
+    resumeFrom(pc) {
+        this.resumePC = pc >>> 0;
+    }
+
+    /**
+     * Stop before executing `pc`: recompile the compiled region holding it
+     * with a breakpoint hook and swap its blocks in.
+     */
+    async setBreakpoint(pc) {
+        this.breakpoints.add(pc >>> 0);
+        await this.recompileRegionsAt(pc >>> 0);
+    }
+
+    async clearBreakpoint(pc) {
+        if (this.breakpoints.delete(pc >>> 0)) {
+            await this.recompileRegionsAt(pc >>> 0);
+        }
+    }
+
+    async recompileRegionsAt(pc) {
+        const regions = new Set();
+        for (const entry of this.compiledBlocks.values()) {
+            if (pc >= entry.regionStart && pc < entry.regionStart + this.regionSize) {
+                regions.add(entry.regionStart);
+            }
+        }
+        for (const regionStart of regions) {
+            await this.compileRegion(regionStart);
+        }
+    }
+
     /**
      * Mark a page as dirty (code was modified via mprotect/mmap).
      * Called from the emulator's mprotect syscall handler.
@@ -258,6 +354,8 @@ class JITManager {
         this.compiledBlocks.clear();
         this.pageHitCounts.clear();
         this.dirtyPages.clear();
+        this.breakpointHit = null;
+        this.resumePC = null;
         this.stats = {
             regionsCompiled: 0,
             jitHits: 0,
@@ -0,0 +1,81 @@
+// zkn.js - env.crypto for rv2wasm modules (scalar AES, Zkne/Zknd)
+//
+// Block functions evaluate aes64es/esm/ds/dsm/im/ks1i by calling
+// env.crypto(op, rs1, rs2) -> rd with i64 operands (BigInt here). Mirrors
+// rv2wasm's crypto.rs; for aes64ks1i, rs2 is the round number.
+
+const MASK64 = (1n << 64n) - 1n;
+
+function gfMul(a, b) {
+    let p = 0;
+    while (b) {
+        if (b & 1) p ^= a;
+        a = ((a << 1) ^ (a & 0x80 ? 0x1b : 0)) & 0xff;
+        b >>= 1;
+    }
+    return p;
+}
+
+const SBOX = new Uint8Array(256);
+const INV_SBOX = new Uint8Array(256);
+for (let x = 0; x < 256; x++) {
+    let inv = 1;
+    for (let i = 0; i < 254; i++) inv = gfMul(inv, x);
+    const b = x === 0 ? 0 : inv;
+    const rotl = (v, n) => ((v << n) | (v >> (8 - n))) & 0xff;
+    SBOX[x] = b ^ rotl(b, 1) ^ rotl(b, 2) ^ rotl(b, 3) ^ rotl(b, 4) ^ 0x63;
+    INV_SBOX[SBOX[x]] = x;
+}
+
+const MIX = [2, 3, 1, 1];
+const INV_MIX = [14, 11, 13, 9];
+const RCON = [0x01, 0x02, 0x04, 0x08, 0x10, 0x20, 0x40, 0x80, 0x1b, 0x36];
+
+const toBytes = (v, n) => Array.from({ length: n }, (_, i) => Number((v >> BigInt(8 * i)) & 0xffn));
+const fromBytes = (bytes) => bytes.reduceRight((acc, b) => (acc << 8n) | BigInt(b), 0n);
+
+function mix(v, m) {
+    const b = toBytes(v, 8);
+    const out = [];
+    for (let col = 0; col < 2; col++) {
+        for (let r = 0; r < 4; r++) {
+            let acc = 0;
+            for (let c = 0; c < 4; c++) acc ^= gfMul(b[4 * col + c], m[(c + 4 - r) % 4]);
+            out.push(acc);
+        }
+    }
+    return fromBytes(out);
+}
+
+function round(rs1, rs2, decrypt) {
+    const state = toBytes(rs1 | (rs2 << 64n), 16);
+    const out = [];
+    for (let i = 0; i < 8; i++) {
+        const row = i % 4, col = Math.floor(i / 4);
+        const src = decrypt ? (col + 4 - row) % 4 : (col + row) % 4;
+        const b = state[row + 4 * src];
+        out.push(decrypt ? INV_SBOX[b] : SBOX[b]);
+    }
+    return fromBytes(out);
+}
+
+export function crypto(op, rs1, rs2) {
+    rs1 &= MASK64;
+    rs2 &= MASK64;
+    let rd;
+    switch (op) {
+        case 0: rd = round(rs1, rs2, false); break;
+        case 1: rd = mix(round(rs1, rs2, false), MIX); break;
+        case 2: rd = round(rs1, rs2, true); break;
+        case 3: rd = mix(round(rs1, rs2, true), INV_MIX); break;
+        case 4: rd = mix(rs1, INV_MIX); break;
+        default: {
+            // aes64ks1i: SubWord(RotWord(w)) ^ rcon, except round 10
+            let word = toBytes(rs1 >> 32n, 4);
+            if (rs2 !== 0xan) word = [...word.slice(1), word[0]];
+            const w = fromBytes(word.map((b) => SBOX[b])) ^ BigInt(RCON[Number(rs2)] ?? 0);
+            rd = (w << 32n) | w;
+        }
+    }
+    return BigInt.asIntN(64, rd);
+}
@@ -0,0 +1,293 @@
+// friscy-py: Python bindings for rv2wasm
+//
+// `import friscy` gives Python pipelines the compiler without shelling out
+// to the CLI: `compile()` and `compile_region()` return module bytes, and
+// `disassemble()`, `cfg()` and `translate()` return the decoded
+// instructions, control flow graph and per-block Wasm IR as plain objects.
+// The objects are snapshots (copied out of the Rust structures), so they
+// stay valid after the input bytes go away.
+//
+// Inputs are ELF images, or raw machine code when `base_addr` is given.
+// Errors raise ValueError with the compiler's message. Build with maturin
+// (`maturin develop` here); `cargo test` embeds an interpreter instead.
+
+use pyo3::exceptions::PyValueError;
+use pyo3::prelude::*;
+use pyo3::types::PyBytes;
+use rv2wasm::{CompileOptions, ElfFile};
+use std::collections::BTreeMap;
+
+/// A decoded instruction
+#[pyclass(name = "Instruction", module = "friscy", frozen, get_all)]
+#[derive(Clone)]
+struct PyInstruction {
+    addr: u64,
+    /// Encoding (2 or 4 bytes)
+    bytes: u32,
+    len: u8,
+    /// Opcode name, e.g. "ADDI"
+    opcode: String,
+    rd: Option<u8>,
+    rs1: Option<u8>,
+    rs2: Option<u8>,
+    imm: Option<i64>,
+}
+
+#[pymethods]
+impl PyInstruction {
+    fn __repr__(&self) -> String {
+        let mut fields = vec![format!("0x{:x}", self.addr), self.opcode.clone()];
+        for (name, reg) in [("rd", self.rd), ("rs1", self.rs1), ("rs2", self.rs2)] {
+            if let Some(reg) = reg {
+                fields.push(format!("{}=x{}", name, reg));
+            }
+        }
+        if let Some(imm) = self.imm {
+            fields.push(format!("imm={}", imm));
+        }
+        format!("Instruction({})", fields.join(", "))
+    }
+}
+
+impl From<&rv2wasm::Instruction> for PyInstruction {
+    fn from(inst: &rv2wasm::Instruction) -> Self {
+        Self {
+            addr: inst.addr,
+            bytes: inst.bytes,
+            len: inst.len,
+            opcode: format!("{:?}", inst.opcode),
+            rd: inst.rd,
+            rs1: inst.rs1,
+            rs2: inst.rs2,
+            imm: inst.imm,
+        }
+    }
+}
+
+/// A basic block
+#[pyclass(name = "BasicBlock", module = "friscy", frozen, get_all)]
+#[derive(Clone)]
+struct PyBasicBlock {
+    start_addr: u64,
+    /// Address after the last instruction
+    end_addr: u64,
+    instructions: Vec<PyInstruction>,
+    successors: Vec<u64>,
+    is_function_entry: bool,
+}
+
+#[pymethods]
+impl PyBasicBlock {
+    fn __repr__(&self) -> String {
+        format!("BasicBlock(0x{:x}..0x{:x}, {} instructions)", self.start_addr, self.end_addr, self.instructions.len())
+    }
+}
+
+/// A function: its entry and the start addresses of its blocks
+#[pyclass(name = "Function", module = "friscy", frozen, get_all)]
+#[derive(Clone)]
+struct PyFunction {
+    entry: u64,
+    name: String,
+    blocks: Vec<u64>,
+}
+
+#[pymethods]
+impl PyFunction {
+    fn __repr__(&self) -> String {
+        format!("Function({}, 0x{:x}, {} blocks)", self.name, self.entry, self.blocks.len())
+    }
+}
+
+/// The control flow graph: blocks by start address, and functions
+#[pyclass(name = "ControlFlowGraph", module = "friscy", frozen, get_all)]
+struct PyControlFlowGraph {
+    entry: u64,
+    blocks: BTreeMap<u64, PyBasicBlock>,
+    functions: Vec<PyFunction>,
+}
+
+#[pymethods]
+impl PyControlFlowGraph {
+    fn __repr__(&self) -> String {
+        format!("ControlFlowGraph({} blocks, {} functions)", self.blocks.len(), self.functions.len())
+    }
+}
+
+/// The Wasm IR of one block function. `body` has one entry per WasmInst,
+/// in rv2wasm's debug notation (e.g. "I64Const(1)").
+#[pyclass(name = "WasmFunction", module = "friscy", frozen, get_all)]
+#[derive(Clone)]
+struct PyWasmFunction {
+    name: String,
+    block_addr: u64,
+    num_locals: u32,
+    body: Vec<String>,
+}
+
+#[pymethods]
+impl PyWasmFunction {
+    fn __repr__(&self) -> String {
+        format!("WasmFunction({}, {} instructions)", self.name, self.body.len())
+    }
+}
+
+/// Compile an ELF image to a Wasm module
+#[pyfunction]
+#[pyo3(signature = (elf, opt_level=2, debug=false, threads=false, size=false))]
+fn compile<'py>(
+    py: Python<'py>,
+    elf: &[u8],
+    opt_level: u8,
+    debug: bool,
+    threads: bool,
+    size: bool,
+) -> PyResult<Bound<'py, PyBytes>> {
+    let options = CompileOptions { opt_level, debug, threads, size, ..Default::default() };
+    let wasm = py.allow_threads(|| rv2wasm::compile_with_options(elf, &options)).map_err(value_error)?;
+    Ok(PyBytes::new(py, &wasm))
+}
+
+/// Compile raw machine code loaded at `base_addr` to a JIT module
+#[pyfunction]
+fn compile_region<'py>(py: Python<'py>, code: &[u8], base_addr: u64) -> PyResult<Bound<'py, PyBytes>> {
+    let wasm = py.allow_threads(|| rv2wasm::compile_region(code, base_addr)).map_err(value_error)?;
+    Ok(PyBytes::new(py, &wasm))
+}
+
+/// Decode the code sections of an ELF image, or `data` as code at `base_addr`
+#[pyfunction]
+#[pyo3(signature = (data, base_addr=None))]
+fn disassemble(data: &[u8], base_addr: Option<u64>) -> PyResult<Vec<PyInstruction>> {
+    let (instructions, _) = decode(data, base_addr).map_err(value_error)?;
+    Ok(instructions.iter().map(PyInstruction::from).collect())
+}
+
+/// The control flow graph of an ELF image, or of `data` as code at `base_addr`
+#[pyfunction]
+#[pyo3(name = "cfg", signature = (data, base_addr=None))]
+fn build_cfg(data: &[u8], base_addr: Option<u64>) -> PyResult<PyControlFlowGraph> {
+    let (instructions, entry) = decode(data, base_addr).map_err(value_error)?;
+    let cfg = rv2wasm::cfg::build(&instructions, entry).map_err(value_error)?;
+    let blocks = cfg
+        .blocks
+        .iter()
+        .map(|(&addr, block)| {
+            let block = PyBasicBlock {
+                start_addr: block.start_addr,
+                end_addr: block.end_addr,
+                instructions: block.instructions.iter().map(PyInstruction::from).collect(),
+                successors: block.successors.clone(),
+                is_function_entry: block.is_function_entry,
+            };
+            (addr, block)
+        })
+        .collect();
+    let functions = cfg
+        .functions
+        .iter()
+        .map(|f| PyFunction { entry: f.entry, name: f.name.clone(), blocks: f.blocks.clone() })
+        .collect();
+    Ok(PyControlFlowGraph { entry: cfg.entry, blocks, functions })
+}
+
+/// The Wasm IR of every block of an ELF image, as `compile` would emit it
+#[pyfunction]
+#[pyo3(signature = (elf, opt_level=2))]
+fn translate(elf: &[u8], opt_level: u8) -> PyResult<Vec<PyWasmFunction>> {
+    let translated = || -> anyhow::Result<_> {
+        let file = ElfFile::parse(elf)?;
+        let (instructions, entry) = decode(elf, None)?;
+        let mut cfg = rv2wasm::cfg::build(&instructions, entry)?;
+        rv2wasm::plt::bind(&mut cfg, &file);
+        rv2wasm::startup::apply(&mut cfg, &file);
+        let options = CompileOptions { opt_level, ..Default::default() };
+        rv2wasm::translate::translate(&cfg, file.info(), &options)
+    };
+    let module = translated().map_err(value_error)?;
+    Ok(module
+        .functions
+        .iter()
+        .map(|f| PyWasmFunction {
+            name: f.name.clone(),
+            block_addr: f.block_addr,
+            num_locals: f.num_locals,
+            body: f.body.iter().map(|inst| format!("{:?}", inst)).collect(),
+        })
+        .collect())
+}
+
+/// Instructions and entry point of an ELF image, or of raw code at `base_addr`
+fn decode(data: &[u8], base_addr: Option<u64>) -> anyhow::Result<(Vec<rv2wasm::Instruction>, u64)> {
+    let Some(base_addr) = base_addr else {
+        let file = ElfFile::parse(data)?;
+        let mut instructions = Vec::new();
+        for section in &file.code_sections() {
+            instructions.extend(rv2wasm::disasm::disassemble(section)?);
+        }
+        return Ok((instructions, file.info().entry));
+    };
+    let section = rv2wasm::CodeSection { vaddr: base_addr, data: data.to_vec(), name: "code".into() };
+    Ok((rv2wasm::disasm::disassemble(&section)?, base_addr))
+}
+
+fn value_error(e: anyhow::Error) -> PyErr {
+    PyValueError::new_err(format!("{:#}", e))
+}
+
+#[pymodule]
+fn friscy(m: &Bound<'_, PyModule>) -> PyResult<()> {
+    m.add("__version__", env!("CARGO_PKG_VERSION"))?;
+    m.add("ABI_VERSION", rv2wasm::abi::ABI_VERSION)?;
+    m.add_function(wrap_pyfunction!(compile, m)?)?;
+    m.add_function(wrap_pyfunction!(compile_region, m)?)?;
+    m.add_function(wrap_pyfunction!(disassemble, m)?)?;
+    m.add_function(wrap_pyfunction!(build_cfg, m)?)?;
+    m.add_function(wrap_pyfunction!(translate, m)?)?;
+    m.add_class::<PyInstruction>()?;
+    m.add_class::<PyBasicBlock>()?;
+    m.add_class::<PyFunction>()?;
+    m.add_class::<PyControlFlowGraph>()?;
+    m.add_class::<PyWasmFunction>()?;
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_module_from_python() {
+        pyo3::append_to_inittab!(friscy);
+        pyo3::prepare_freethreaded_python();
+        Python::with_gil(|py| {
+            // addi a0, zero, 1 ; beq a0, zero, +8 ; ecall ; ecall
+            let code = PyBytes::new(py, &[
+                0x13, 0x05, 0x10, 0x00, 0x63, 0x04, 0x05, 0x00, 0x73, 0x00, 0x00, 0x00, 0x73, 0x00, 0x00, 0x00,
+            ]);
+            let locals = pyo3::types::PyDict::new(py);
+            locals.set_item("code", code).unwrap();
+            py.run(
+                pyo3::ffi::c_str!(
+                    r#"
+import friscy
+insts = friscy.disassemble(code, base_addr=0x1000)
+assert [i.opcode for i in insts] == ["ADDI", "BEQ", "ECALL", "ECALL"], insts
+assert (insts[0].rd, insts[0].rs1, insts[0].imm) == (10, 0, 1), insts[0]
+g = friscy.cfg(code, base_addr=0x1000)
+assert sorted(g.blocks[0x1000].successors) == [0x1008, 0x100c], g.blocks
+assert friscy.compile_region(code, 0x1000)[:4] == b"\0asm"
+try:
+    friscy.compile(b"not an elf")
+    assert False
+except ValueError as e:
+    assert "ELF" in str(e), e
+"#
+                ),
+                None,
+                Some(&locals),
+            )
+            .unwrap();
+        });
+    }
+}
@@ -0,0 +1,26 @@
+#!/usr/bin/env bash
+# fetch-riscv-tests.sh — download and build the riscv-tests ISA binaries
+# used by the rv2wasm conformance harness (aot-isa-tests).
+#
+# Needs a bare-metal RISC-V toolchain (riscv64-unknown-elf-gcc) in PATH.
+# Prints the directory to export as RISCV_TESTS_DIR.
+
+set -euo pipefail
+
+REPO_ROOT="$(cd "$(dirname "$0")/.." && pwd)"
+DEST="${1:-$REPO_ROOT/target/riscv-tests}"
+
+if [[ ! -d "$DEST/.git" ]]; then
+  git clone --depth 1 --recurse-submodules --shallow-submodules \
+    https://github.com/riscv-software-src/riscv-tests.git "$DEST"
+fi
+
+cd "$DEST"
+autoconf
+./configure --prefix="$DEST/install"
+make -C isa -j"$(nproc)" rv64ui rv64um rv64ua rv64uf rv64ud
+
+echo
+echo "Built riscv-tests. Run the harness with:"
+echo "  RISCV_TESTS_DIR=$DEST/isa cargo test -p rv2wasm-isa-tests -- --ignored"
+echo "  cargo run -p rv2wasm-isa-tests -- $DEST/isa"